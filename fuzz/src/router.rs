@@ -235,6 +235,8 @@ pub fn do_test<Out: test_logger::Output>(data: &[u8], out: Out) {
 								inbound_htlc_minimum_msat: None,
 								inbound_htlc_maximum_msat: None,
 								config: None,
+								is_on_probation: false,
+								is_shutting_down: false,
 							});
 						}
 						Some(&first_hops_vec[..])
@@ -19,13 +19,15 @@ use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
 use lightning::chain::chainmonitor::{ChainMonitor, Persist};
 use lightning::chain::keysinterface::{Sign, KeysInterface};
 use lightning::ln::channelmanager::ChannelManager;
-use lightning::ln::msgs::{ChannelMessageHandler, OnionMessageHandler, RoutingMessageHandler};
+use lightning::ln::msgs::{ChannelMessageHandler, DecodeError, OnionMessageHandler, RoutingMessageHandler};
 use lightning::ln::peer_handler::{CustomMessageHandler, PeerManager, SocketDescriptor};
+use lightning::routing::bundle::RoutingStateBundle;
 use lightning::routing::gossip::{NetworkGraph, P2PGossipSync};
 use lightning::routing::scoring::WriteableScore;
 use lightning::util::events::{Event, EventHandler, EventsProvider};
 use lightning::util::logger::Logger;
-use lightning::util::persist::Persister;
+use lightning::util::persist::{KVStorePersister, Persister};
+use lightning::util::ser::{ReadableArgs, Writeable};
 use lightning_rapid_gossip_sync::RapidGossipSync;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -241,6 +243,11 @@ impl BackgroundProcessor {
 	/// [`GossipSync`] is supplied. See [`NetworkGraph::write`] for writing out a [`NetworkGraph`].
 	/// See the `lightning-persister` crate for LDK's provided implementation.
 	///
+	/// [`Persister::persist_scorer`] is responsible for writing out the scorer to disk, if given.
+	/// Passing a [`RoutingStateBundle`] wrapped in a `Mutex` as the `scorer` persists the whole
+	/// bundle, i.e. the graph, scorer and routing preferences together, which can later be read
+	/// back via [`read_routing_state_bundle`].
+	///
 	/// Typically, users should either implement [`Persister::persist_manager`] to never return an
 	/// error or call [`join`] and handle any error that may arise. For the latter case,
 	/// `BackgroundProcessor` must be restarted by calling `start` again after handling the error.
@@ -265,6 +272,7 @@ impl BackgroundProcessor {
 	/// [`ChannelManager::write`]: lightning::ln::channelmanager::ChannelManager#impl-Writeable
 	/// [`Persister::persist_manager`]: lightning::util::persist::Persister::persist_manager
 	/// [`Persister::persist_graph`]: lightning::util::persist::Persister::persist_graph
+	/// [`Persister::persist_scorer`]: lightning::util::persist::Persister::persist_scorer
 	/// [`NetworkGraph`]: lightning::routing::gossip::NetworkGraph
 	/// [`NetworkGraph::write`]: lightning::routing::gossip::NetworkGraph#impl-Writeable
 	pub fn start<
@@ -489,6 +497,29 @@ impl Drop for BackgroundProcessor {
 	}
 }
 
+/// The key under which [`persist_routing_state_bundle`] stores a [`RoutingStateBundle`].
+pub const ROUTING_STATE_BUNDLE_KEY: &str = "routing_state_bundle";
+
+/// Persists the given [`RoutingStateBundle`] under [`ROUTING_STATE_BUNDLE_KEY`] in one call,
+/// allowing the routing intelligence of a node to be restored via [`read_routing_state_bundle`]
+/// after a reinstall or on another device.
+pub fn persist_routing_state_bundle<K: KVStorePersister, G: Deref<Target = NetworkGraph<L>>, L: Deref, S: Writeable>(
+	persister: &K, bundle: &RoutingStateBundle<G, L, S>
+) -> Result<(), std::io::Error> where L::Target: Logger {
+	persister.persist(ROUTING_STATE_BUNDLE_KEY, bundle)
+}
+
+/// Reads a [`RoutingStateBundle`] previously written by [`persist_routing_state_bundle`], or by a
+/// [`BackgroundProcessor`] which was given the bundle as its scorer.
+///
+/// `scorer_args` is given the freshly-read [`NetworkGraph`] and must return the arguments needed
+/// to read the scorer.
+pub fn read_routing_state_bundle<R: std::io::Read, L: Deref, S: ReadableArgs<A>, A, F: FnOnce(Arc<NetworkGraph<L>>) -> A>(
+	reader: &mut R, logger: L, scorer_args: F
+) -> Result<RoutingStateBundle<Arc<NetworkGraph<L>>, L, S>, DecodeError> where L::Target: Logger {
+	RoutingStateBundle::read(reader, (logger, scorer_args))
+}
+
 #[cfg(test)]
 mod tests {
 	use bitcoin::blockdata::block::BlockHeader;
@@ -523,7 +554,8 @@ mod tests {
 	use bitcoin::TxMerkleNode;
 	use lightning::routing::scoring::{FixedPenaltyScorer};
 	use lightning_rapid_gossip_sync::RapidGossipSync;
-	use super::{BackgroundProcessor, GossipSync, FRESHNESS_TIMER};
+	use lightning::routing::bundle::RoutingStateBundle;
+	use super::{BackgroundProcessor, GossipSync, FRESHNESS_TIMER, ROUTING_STATE_BUNDLE_KEY, persist_routing_state_bundle, read_routing_state_bundle};
 
 	const EVENT_DEADLINE: u64 = 5 * FRESHNESS_TIMER;
 
@@ -952,6 +984,55 @@ mod tests {
 		assert!(bg_processor.stop().is_ok());
 	}
 
+	#[test]
+	fn test_routing_state_bundle_persistence() {
+		let nodes = create_nodes(1, "test_routing_state_bundle_persistence".to_string());
+		let data_dir = nodes[0].persister.get_data_dir();
+		let network_graph = Arc::new(NetworkGraph::new(genesis_block(Network::Testnet).header.block_hash(), nodes[0].logger.clone()));
+		let bundle = RoutingStateBundle {
+			network_graph,
+			scorer: FixedPenaltyScorer::with_penalty(0),
+			blacklisted_channels: vec![42],
+			blacklisted_nodes: Vec::new(),
+			route_hint_cache: Vec::new(),
+		};
+		persist_routing_state_bundle(&*nodes[0].persister, &bundle).unwrap();
+
+		let filepath = get_full_filepath(data_dir, ROUTING_STATE_BUNDLE_KEY.to_string());
+		let bytes = fs::read(filepath).unwrap();
+		let read_bundle: RoutingStateBundle<_, _, FixedPenaltyScorer> =
+			read_routing_state_bundle(&mut &bytes[..], nodes[0].logger.clone(), |_| 0).unwrap();
+		assert_eq!(read_bundle.blacklisted_channels, vec![42]);
+		assert_eq!(read_bundle.encode(), bundle.encode());
+	}
+
+	#[test]
+	fn test_background_processor_persists_routing_state_bundle() {
+		// Test that a `RoutingStateBundle` given to the `BackgroundProcessor` in place of a scorer
+		// is persisted in full, and can be read back via `read_routing_state_bundle`.
+		let nodes = create_nodes(1, "test_background_processor_persists_routing_state_bundle".to_string());
+		let data_dir = nodes[0].persister.get_data_dir();
+		let persister = Arc::new(Persister::new(data_dir.clone()));
+		let network_graph = Arc::new(NetworkGraph::new(genesis_block(Network::Testnet).header.block_hash(), nodes[0].logger.clone()));
+		let bundle = Arc::new(Mutex::new(RoutingStateBundle {
+			network_graph,
+			scorer: FixedPenaltyScorer::with_penalty(0),
+			blacklisted_channels: vec![42],
+			blacklisted_nodes: Vec::new(),
+			route_hint_cache: Vec::new(),
+		}));
+		let event_handler = |_: &_| {};
+		let bg_processor = BackgroundProcessor::start(persister, event_handler, nodes[0].chain_monitor.clone(), nodes[0].node.clone(), nodes[0].no_gossip_sync(), nodes[0].peer_manager.clone(), nodes[0].logger.clone(), Some(bundle.clone()));
+		assert!(bg_processor.stop().is_ok());
+
+		let filepath = get_full_filepath(data_dir, "scorer".to_string());
+		let bytes = fs::read(filepath).unwrap();
+		let read_bundle: RoutingStateBundle<_, _, FixedPenaltyScorer> =
+			read_routing_state_bundle(&mut &bytes[..], nodes[0].logger.clone(), |_| 0).unwrap();
+		assert_eq!(read_bundle.blacklisted_channels, vec![42]);
+		assert_eq!(read_bundle.encode(), bundle.lock().unwrap().encode());
+	}
+
 	#[test]
 	fn test_scorer_persistence() {
 		let nodes = create_nodes(2, "test_scorer_persistence".to_string());
@@ -24,7 +24,7 @@ use secp256k1::PublicKey;
 
 use super::{Invoice, Sha256, TaggedField, ExpiryTime, MinFinalCltvExpiry, Fallback, PayeePubKey, InvoiceSignature, PositiveTimestamp,
 	SemanticError, PrivateRoute, ParseError, ParseOrSemanticError, Description, RawTaggedField, Currency, RawHrp, SiPrefix, RawInvoice,
-	constants, SignedRawInvoice, RawDataPart, InvoiceFeatures};
+	constants, SignedRawInvoice, UnsignedInvoice, RawDataPart, InvoiceFeatures};
 
 use self::hrp_sm::parse_hrp;
 
@@ -296,6 +296,25 @@ impl FromStr for SignedRawInvoice {
 	}
 }
 
+impl FromStr for UnsignedInvoice {
+	type Err = ParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (hrp, data, var) = bech32::decode(s)?;
+
+		if var == bech32::Variant::Bech32m {
+			return Err(ParseError::Bech32Error(bech32::Error::InvalidChecksum));
+		}
+
+		Ok(UnsignedInvoice {
+			raw_invoice: RawInvoice {
+				hrp: hrp.parse()?,
+				data: RawDataPart::from_base32(&data)?,
+			},
+		})
+	}
+}
+
 impl FromStr for RawHrp {
 	type Err = ParseError;
 
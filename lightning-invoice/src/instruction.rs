@@ -0,0 +1,349 @@
+//! Parsing of the various string formats used to request a Lightning payment into a single
+//! [`PaymentInstruction`], which may be paid using [`InvoicePayer::pay_instruction`].
+//!
+//! The following formats are supported, optionally prefixed with a `lightning:` URI scheme:
+//!  * BOLT 11 invoices,
+//!  * `node_id` or `node_id@host:port` strings, which are paid via keysend,
+//!  * [BIP 21] `bitcoin:` URIs, including those carrying a BOLT 11 invoice in a `lightning`
+//!    parameter.
+//!
+//! BOLT 12 offers and refunds are recognized but are not yet supported, resulting in
+//! [`PaymentInstructionError::UnsupportedBolt12`].
+//!
+//! [`InvoicePayer::pay_instruction`]: crate::payment::InvoicePayerUsingTime::pay_instruction
+//! [BIP 21]: https://github.com/bitcoin/bips/blob/master/bip-0021.mediawiki
+
+use {Invoice, ParseOrSemanticError};
+
+use crate::prelude::*;
+use lightning::ln::msgs::NetAddress;
+use lightning::util::ser::Hostname;
+use secp256k1::PublicKey;
+
+use core::convert::TryFrom;
+use core::fmt::{self, Display, Formatter};
+use core::str::FromStr;
+
+/// The port assumed for a node's address when none is given.
+const DEFAULT_PORT: u16 = 9735;
+
+/// The number of millisatoshis in one bitcoin, as used to interpret BIP 21 amounts.
+const MSATS_PER_BTC: u64 = 100_000_000_000;
+
+/// A parsed request for a payment, as given by a payee in one of several formats.
+///
+/// Created by parsing a string with [`str::parse`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaymentInstruction {
+	/// A BOLT 11 invoice.
+	Bolt11(Invoice),
+	/// A spontaneous payment to the given node.
+	Keysend {
+		/// The node to pay.
+		node_id: PublicKey,
+		/// The address at which the node may be reached, if given.
+		address: Option<NetAddress>,
+	},
+	/// A [BIP 21] URI, which may include a BOLT 11 invoice to pay instead of the on-chain address.
+	///
+	/// [BIP 21]: https://github.com/bitcoin/bips/blob/master/bip-0021.mediawiki
+	Bip21 {
+		/// The on-chain address to pay, if given. This is not validated.
+		address: Option<String>,
+		/// The amount requested, if any.
+		amount_msats: Option<u64>,
+		/// The invoice given in the `lightning` parameter, if any.
+		invoice: Option<Invoice>,
+	},
+}
+
+/// An error when parsing a [`PaymentInstruction`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaymentInstructionError {
+	/// The string could not be recognized as any supported format.
+	UnknownFormat,
+	/// The string is a BOLT 12 offer or refund, which is not yet supported.
+	UnsupportedBolt12,
+	/// The BOLT 11 invoice could not be parsed.
+	Invoice(ParseOrSemanticError),
+	/// The node id is not a valid public key.
+	InvalidNodeId,
+	/// The host or port following the node id is invalid.
+	InvalidNetAddress,
+	/// The BIP 21 URI is malformed or has a required parameter which we don't understand.
+	InvalidBip21Uri,
+	/// The BIP 21 amount is malformed or out of range.
+	InvalidAmount,
+}
+
+impl Display for PaymentInstructionError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match *self {
+			PaymentInstructionError::UnknownFormat => f.write_str("unrecognized payment instruction"),
+			PaymentInstructionError::UnsupportedBolt12 => f.write_str("BOLT 12 offers and refunds are not supported"),
+			PaymentInstructionError::Invoice(ref err) => err.fmt(f),
+			PaymentInstructionError::InvalidNodeId => f.write_str("invalid node id"),
+			PaymentInstructionError::InvalidNetAddress => f.write_str("invalid node address"),
+			PaymentInstructionError::InvalidBip21Uri => f.write_str("invalid BIP 21 URI"),
+			PaymentInstructionError::InvalidAmount => f.write_str("invalid BIP 21 amount"),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PaymentInstructionError {}
+
+/// Strips `prefix` from the start of `s`, ignoring case.
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+	if s.len() >= prefix.len() && s.is_char_boundary(prefix.len())
+		&& s[..prefix.len()].eq_ignore_ascii_case(prefix) {
+		Some(&s[prefix.len()..])
+	} else {
+		None
+	}
+}
+
+impl FromStr for PaymentInstruction {
+	type Err = PaymentInstructionError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		if let Some(uri) = strip_prefix_ignore_case(s, "bitcoin:") {
+			return parse_bip21(uri);
+		}
+		let s = strip_prefix_ignore_case(s, "lightning:").unwrap_or(s);
+		if strip_prefix_ignore_case(s, "lno1").is_some() || strip_prefix_ignore_case(s, "lnr1").is_some() {
+			return Err(PaymentInstructionError::UnsupportedBolt12);
+		}
+		if strip_prefix_ignore_case(s, "ln").is_some() {
+			return Invoice::from_str(s)
+				.map(|invoice| PaymentInstruction::Bolt11(invoice))
+				.map_err(|e| PaymentInstructionError::Invoice(e));
+		}
+		parse_keysend(s)
+	}
+}
+
+fn parse_keysend(s: &str) -> Result<PaymentInstruction, PaymentInstructionError> {
+	let mut parts = s.splitn(2, '@');
+	let node_id = parts.next().unwrap();
+	if node_id.len() != 66 || !node_id.chars().all(|c| c.is_ascii_hexdigit()) {
+		return Err(PaymentInstructionError::UnknownFormat);
+	}
+	let node_id = PublicKey::from_str(node_id).map_err(|_| PaymentInstructionError::InvalidNodeId)?;
+	let address = match parts.next() {
+		Some(host) => Some(parse_net_address(host)?),
+		None => None,
+	};
+	Ok(PaymentInstruction::Keysend { node_id, address })
+}
+
+/// Parses a `host[:port]` string, where IPv6 hosts must be enclosed in brackets.
+fn parse_net_address(s: &str) -> Result<NetAddress, PaymentInstructionError> {
+	let (host, port) = if s.starts_with('[') {
+		let end = s.find(']').ok_or(PaymentInstructionError::InvalidNetAddress)?;
+		let port = match &s[end + 1..] {
+			"" => None,
+			rest if rest.starts_with(':') => Some(&rest[1..]),
+			_ => return Err(PaymentInstructionError::InvalidNetAddress),
+		};
+		(&s[..end + 1], port)
+	} else {
+		let mut parts = s.splitn(2, ':');
+		(parts.next().unwrap(), parts.next())
+	};
+	let port = match port {
+		Some(port) => u16::from_str(port).map_err(|_| PaymentInstructionError::InvalidNetAddress)?,
+		None => DEFAULT_PORT,
+	};
+	if host.is_empty() {
+		return Err(PaymentInstructionError::InvalidNetAddress);
+	}
+
+	if host.starts_with('[') {
+		return parse_ipv6_address(&host[1..host.len() - 1], port);
+	}
+
+	let octets: Vec<&str> = host.split('.').collect();
+	if octets.len() == 4 && octets.iter().all(|o| !o.is_empty() && o.chars().all(|c| c.is_ascii_digit())) {
+		let mut addr = [0; 4];
+		for (byte, octet) in addr.iter_mut().zip(octets.iter()) {
+			*byte = u8::from_str(octet).map_err(|_| PaymentInstructionError::InvalidNetAddress)?;
+		}
+		return Ok(NetAddress::IPv4 { addr, port });
+	}
+
+	let hostname = Hostname::try_from(host.to_string())
+		.map_err(|_| PaymentInstructionError::InvalidNetAddress)?;
+	Ok(NetAddress::Hostname { hostname, port })
+}
+
+#[cfg(feature = "std")]
+fn parse_ipv6_address(host: &str, port: u16) -> Result<NetAddress, PaymentInstructionError> {
+	let addr = std::net::Ipv6Addr::from_str(host).map_err(|_| PaymentInstructionError::InvalidNetAddress)?;
+	Ok(NetAddress::IPv6 { addr: addr.octets(), port })
+}
+
+/// IPv6 addresses can only be parsed with the `std` feature.
+#[cfg(not(feature = "std"))]
+fn parse_ipv6_address(_host: &str, _port: u16) -> Result<NetAddress, PaymentInstructionError> {
+	Err(PaymentInstructionError::InvalidNetAddress)
+}
+
+/// Parses the part of a BIP 21 URI following the `bitcoin:` scheme.
+fn parse_bip21(uri: &str) -> Result<PaymentInstruction, PaymentInstructionError> {
+	let mut parts = uri.splitn(2, '?');
+	let address = parts.next().unwrap();
+	let address = if address.is_empty() { None } else { Some(address.to_string()) };
+
+	let mut amount_msats = None;
+	let mut invoice = None;
+	for param in parts.next().unwrap_or("").split('&').filter(|param| !param.is_empty()) {
+		let mut kv = param.splitn(2, '=');
+		let key = kv.next().unwrap();
+		let value = kv.next().ok_or(PaymentInstructionError::InvalidBip21Uri)?;
+		if key.eq_ignore_ascii_case("amount") {
+			if amount_msats.is_some() {
+				return Err(PaymentInstructionError::InvalidBip21Uri);
+			}
+			amount_msats = Some(parse_btc_amount(value)?);
+		} else if key.eq_ignore_ascii_case("lightning") {
+			if invoice.is_some() {
+				return Err(PaymentInstructionError::InvalidBip21Uri);
+			}
+			match PaymentInstruction::from_str(value)? {
+				PaymentInstruction::Bolt11(bolt11) => invoice = Some(bolt11),
+				_ => return Err(PaymentInstructionError::InvalidBip21Uri),
+			}
+		} else if strip_prefix_ignore_case(key, "req-").is_some() {
+			// BIP 21 requires us to reject URIs with required parameters we don't understand.
+			return Err(PaymentInstructionError::InvalidBip21Uri);
+		}
+	}
+
+	if address.is_none() && invoice.is_none() {
+		return Err(PaymentInstructionError::InvalidBip21Uri);
+	}
+	Ok(PaymentInstruction::Bip21 { address, amount_msats, invoice })
+}
+
+/// Parses a decimal amount of bitcoin, as used by BIP 21, into millisatoshis.
+fn parse_btc_amount(s: &str) -> Result<u64, PaymentInstructionError> {
+	let mut parts = s.splitn(2, '.');
+	let whole = parts.next().unwrap();
+	let fraction = parts.next().unwrap_or("");
+	if (whole.is_empty() && fraction.is_empty()) || fraction.len() > 8
+		|| !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+		return Err(PaymentInstructionError::InvalidAmount);
+	}
+
+	let whole = if whole.is_empty() { 0 } else {
+		u64::from_str(whole).map_err(|_| PaymentInstructionError::InvalidAmount)?
+	};
+	let mut fraction_msats = 0;
+	let mut unit = MSATS_PER_BTC;
+	for digit in fraction.chars() {
+		unit /= 10;
+		fraction_msats += (digit as u64 - '0' as u64) * unit;
+	}
+	whole.checked_mul(MSATS_PER_BTC)
+		.and_then(|msats| msats.checked_add(fraction_msats))
+		.ok_or(PaymentInstructionError::InvalidAmount)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use {Currency, InvoiceBuilder};
+	use bitcoin_hashes::Hash;
+	use bitcoin_hashes::sha256;
+	use lightning::ln::PaymentSecret;
+	use secp256k1::{Secp256k1, SecretKey};
+	use core::time::Duration;
+
+	const NODE_ID: &str = "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619";
+
+	fn invoice() -> Invoice {
+		let private_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		InvoiceBuilder::new(Currency::Bitcoin)
+			.description("test".into())
+			.payment_hash(sha256::Hash::from_slice(&[1; 32]).unwrap())
+			.payment_secret(PaymentSecret([0; 32]))
+			.duration_since_epoch(Duration::from_secs(1234567))
+			.min_final_cltv_expiry(144)
+			.amount_milli_satoshis(128)
+			.build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &private_key))
+			.unwrap()
+	}
+
+	#[test]
+	fn parses_bolt11_invoices() {
+		let invoice = invoice();
+		let expected = Ok(PaymentInstruction::Bolt11(invoice.clone()));
+		assert_eq!(PaymentInstruction::from_str(&invoice.to_string()), expected);
+		assert_eq!(PaymentInstruction::from_str(&format!("lightning:{}", invoice)), expected);
+		assert_eq!(PaymentInstruction::from_str(&format!("LIGHTNING:{}", invoice.to_string().to_uppercase())), expected);
+
+		match PaymentInstruction::from_str("lnbc1invalid") {
+			Err(PaymentInstructionError::Invoice(_)) => {},
+			res => panic!("unexpected result {:?}", res),
+		}
+		assert_eq!(PaymentInstruction::from_str("lno1qcp4256ypq"), Err(PaymentInstructionError::UnsupportedBolt12));
+		assert_eq!(PaymentInstruction::from_str("LNR1QQSQ"), Err(PaymentInstructionError::UnsupportedBolt12));
+		assert_eq!(PaymentInstruction::from_str("hello"), Err(PaymentInstructionError::UnknownFormat));
+	}
+
+	#[test]
+	fn parses_keysend_node_ids() {
+		let node_id = PublicKey::from_str(NODE_ID).unwrap();
+		assert_eq!(PaymentInstruction::from_str(NODE_ID),
+			Ok(PaymentInstruction::Keysend { node_id, address: None }));
+		assert_eq!(PaymentInstruction::from_str(&format!("{}@1.2.3.4", NODE_ID)),
+			Ok(PaymentInstruction::Keysend { node_id, address: Some(NetAddress::IPv4 { addr: [1, 2, 3, 4], port: 9735 }) }));
+		assert_eq!(PaymentInstruction::from_str(&format!("{}@example.com:9736", NODE_ID)),
+			Ok(PaymentInstruction::Keysend { node_id, address: Some(NetAddress::Hostname {
+				hostname: Hostname::try_from("example.com".to_string()).unwrap(), port: 9736 }) }));
+		#[cfg(feature = "std")]
+		assert_eq!(PaymentInstruction::from_str(&format!("{}@[::1]:9737", NODE_ID)),
+			Ok(PaymentInstruction::Keysend { node_id, address: Some(NetAddress::IPv6 {
+				addr: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], port: 9737 }) }));
+
+		let invalid_node_id = format!("04{}", &NODE_ID[2..]);
+		assert_eq!(PaymentInstruction::from_str(&invalid_node_id), Err(PaymentInstructionError::InvalidNodeId));
+		assert_eq!(PaymentInstruction::from_str(&format!("{}@1.2.3.4:99999", NODE_ID)),
+			Err(PaymentInstructionError::InvalidNetAddress));
+		assert_eq!(PaymentInstruction::from_str(&format!("{}@", NODE_ID)),
+			Err(PaymentInstructionError::InvalidNetAddress));
+		assert_eq!(PaymentInstruction::from_str(&format!("{}@[::1", NODE_ID)),
+			Err(PaymentInstructionError::InvalidNetAddress));
+	}
+
+	#[test]
+	fn parses_bip21_uris() {
+		let address = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:{}", address)),
+			Ok(PaymentInstruction::Bip21 { address: Some(address.to_string()), amount_msats: None, invoice: None }));
+		assert_eq!(PaymentInstruction::from_str(&format!("BITCOIN:{}?amount=0.0001&label=coffee", address)),
+			Ok(PaymentInstruction::Bip21 { address: Some(address.to_string()), amount_msats: Some(10_000_000), invoice: None }));
+
+		let invoice = invoice();
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:{}?amount=1.5&lightning={}", address, invoice)),
+			Ok(PaymentInstruction::Bip21 {
+				address: Some(address.to_string()), amount_msats: Some(150_000_000_000), invoice: Some(invoice.clone())
+			}));
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:?lightning={}", invoice)),
+			Ok(PaymentInstruction::Bip21 { address: None, amount_msats: None, invoice: Some(invoice) }));
+
+		assert_eq!(PaymentInstruction::from_str("bitcoin:"), Err(PaymentInstructionError::InvalidBip21Uri));
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:{}?req-somethingnew=1", address)),
+			Err(PaymentInstructionError::InvalidBip21Uri));
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:{}?lightning={}", address, NODE_ID)),
+			Err(PaymentInstructionError::InvalidBip21Uri));
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:{}?amount=0.000000001", address)),
+			Err(PaymentInstructionError::InvalidAmount));
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:{}?amount=1e3", address)),
+			Err(PaymentInstructionError::InvalidAmount));
+		assert_eq!(PaymentInstruction::from_str(&format!("bitcoin:{}?amount=.", address)),
+			Err(PaymentInstructionError::InvalidAmount));
+	}
+}
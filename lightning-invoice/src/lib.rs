@@ -25,6 +25,7 @@
 #[cfg(not(any(feature = "std", feature = "no-std")))]
 compile_error!("at least one of the `std` or `no-std` features must be enabled");
 
+pub mod instruction;
 pub mod payment;
 pub mod utils;
 
@@ -52,6 +53,7 @@ use lightning::ln::features::InvoiceFeatures;
 #[cfg(any(doc, test))]
 use lightning::routing::gossip::RoutingFees;
 use lightning::routing::router::RouteHint;
+use lightning::util::errors::{ErrorClassification, Retryability};
 use lightning::util::invoice::construct_invoice_preimage;
 
 use secp256k1::PublicKey;
@@ -258,6 +260,20 @@ pub enum InvoiceDescription<'f> {
 	Hash(&'f Sha256),
 }
 
+/// A `RawInvoice` which is ready to be signed, allowing an invoice to be built on one host and
+/// signed on another, e.g. when our node's key is held by a remote signer.
+///
+/// The parts to sign are those expected by [`KeysInterface::sign_invoice`]. The invoice may also
+/// be exported via its `Display` implementation, which encodes it as a BOLT11 invoice without a
+/// signature, and parsed wherever the key lives. Once a signature is available, the [`Invoice`]
+/// is assembled via [`UnsignedInvoice::assemble`].
+///
+/// [`KeysInterface::sign_invoice`]: lightning::chain::keysinterface::KeysInterface::sign_invoice
+#[derive(Eq, PartialEq, Debug, Clone, Hash)]
+pub struct UnsignedInvoice {
+	raw_invoice: RawInvoice,
+}
+
 /// Represents a signed `RawInvoice` with cached hash. The signature is not checked and may be
 /// invalid.
 ///
@@ -684,6 +700,11 @@ impl<D: tb::Bool, H: tb::Bool, T: tb::Bool, C: tb::Bool> InvoiceBuilder<D, H, T,
 }
 
 impl InvoiceBuilder<tb::True, tb::True, tb::True, tb::True, tb::True> {
+	/// Builds an [`UnsignedInvoice`] to be signed separately.
+	pub fn build_unsigned(self) -> Result<UnsignedInvoice, CreationError> {
+		Ok(UnsignedInvoice { raw_invoice: self.build_raw()? })
+	}
+
 	/// Builds and signs an invoice using the supplied `sign_function`. This function MAY NOT fail
 	/// and MUST produce a recoverable signature valid for the given hash and if applicable also for
 	/// the included payee public key.
@@ -799,6 +820,41 @@ impl SignedRawInvoice {
 	}
 }
 
+impl UnsignedInvoice {
+	/// The `RawInvoice` to be signed.
+	pub fn raw_invoice(&self) -> &RawInvoice {
+		&self.raw_invoice
+	}
+
+	/// The human readable part of the invoice, as passed to [`KeysInterface::sign_invoice`].
+	///
+	/// [`KeysInterface::sign_invoice`]: lightning::chain::keysinterface::KeysInterface::sign_invoice
+	pub fn hrp_bytes(&self) -> Vec<u8> {
+		self.raw_invoice.hrp.to_string().into_bytes()
+	}
+
+	/// The data part of the invoice, as passed to [`KeysInterface::sign_invoice`].
+	///
+	/// [`KeysInterface::sign_invoice`]: lightning::chain::keysinterface::KeysInterface::sign_invoice
+	pub fn data_without_signature(&self) -> Vec<u5> {
+		use bech32::ToBase32;
+		self.raw_invoice.data.to_base32()
+	}
+
+	/// The hash which must be signed.
+	pub fn signable_hash(&self) -> [u8; 32] {
+		self.raw_invoice.hash()
+	}
+
+	/// Assembles the [`Invoice`] from the given signature over [`Self::signable_hash`], failing if
+	/// the signature is not valid for the invoice.
+	pub fn assemble(self, signature: RecoverableSignature) -> Result<Invoice, SemanticError> {
+		let signed_invoice = self.raw_invoice.sign::<_, ()>(|_| Ok(signature))
+			.expect("our signing function never fails");
+		Invoice::from_signed(signed_invoice)
+	}
+}
+
 /// Finds the first element of an enum stream of a given variant and extracts one member of the
 /// variant. If no element was found `None` gets returned.
 ///
@@ -1531,6 +1587,46 @@ impl<S> Display for SignOrCreationError<S> {
 	}
 }
 
+impl<S> ErrorClassification for SignOrCreationError<S> {
+	type Cause = SignOrCreationErrorCause;
+
+	/// Returns one of the following codes:
+	///  * 301: [`SignOrCreationError::SignError`]
+	///  * 302: [`SignOrCreationError::CreationError`]
+	fn error_code(&self) -> u16 {
+		match self {
+			SignOrCreationError::SignError(_) => 301,
+			SignOrCreationError::CreationError(_) => 302,
+		}
+	}
+
+	/// Signing errors are [`Retryability::Later`], as they are often transient, e.g. when signing
+	/// remotely. Creation errors are always [`Retryability::Never`].
+	fn retryability(&self) -> Retryability {
+		match self {
+			SignOrCreationError::SignError(_) => Retryability::Later,
+			SignOrCreationError::CreationError(_) => Retryability::Never,
+		}
+	}
+
+	fn cause(&self) -> SignOrCreationErrorCause {
+		match self {
+			SignOrCreationError::SignError(_) => SignOrCreationErrorCause::Signing,
+			SignOrCreationError::CreationError(err) => SignOrCreationErrorCause::Creation(err.clone()),
+		}
+	}
+}
+
+/// The cause of a [`SignOrCreationError`], as returned by [`ErrorClassification::cause`].
+#[non_exhaustive]
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum SignOrCreationErrorCause {
+	/// See [`SignOrCreationError::SignError`].
+	Signing,
+	/// See [`SignOrCreationError::CreationError`].
+	Creation(CreationError),
+}
+
 #[cfg(feature = "serde")]
 impl Serialize for Invoice {
 	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
@@ -1950,6 +2046,45 @@ mod test {
 		assert_eq!(raw_invoice, *invoice.into_signed_raw().raw_invoice())
 	}
 
+	#[test]
+	fn test_builder_unsigned() {
+		use ::*;
+		use secp256k1::Secp256k1;
+		use secp256k1::{SecretKey, PublicKey};
+		use std::time::Duration;
+
+		let secp_ctx = Secp256k1::new();
+		let private_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let public_key = PublicKey::from_secret_key(&secp_ctx, &private_key);
+
+		let builder = InvoiceBuilder::new(Currency::BitcoinTestnet)
+			.amount_milli_satoshis(123)
+			.duration_since_epoch(Duration::from_secs(1234567))
+			.payee_pub_key(public_key)
+			.min_final_cltv_expiry(144)
+			.description("test".into())
+			.payment_hash(sha256::Hash::from_slice(&[21;32][..]).unwrap())
+			.payment_secret(PaymentSecret([42; 32]));
+		let unsigned_invoice = builder.clone().build_unsigned().unwrap();
+
+		// The unsigned invoice survives being exported to wherever our key lives.
+		let exported = unsigned_invoice.to_string();
+		assert!(exported.parse::<SignedRawInvoice>().is_err());
+		let unsigned_invoice: UnsignedInvoice = exported.parse().unwrap();
+
+		// Signing the parts as a `KeysInterface` would gives the same invoice as signing directly.
+		let preimage = construct_invoice_preimage(&unsigned_invoice.hrp_bytes(), &unsigned_invoice.data_without_signature());
+		let hash = Message::from_slice(&sha256::Hash::hash(&preimage)).unwrap();
+		assert_eq!(hash, Message::from_slice(&unsigned_invoice.signable_hash()).unwrap());
+		let signature = secp_ctx.sign_ecdsa_recoverable(&hash, &private_key);
+		let invoice = unsigned_invoice.clone().assemble(signature).unwrap();
+		assert_eq!(invoice, builder.build_signed(|hash| secp_ctx.sign_ecdsa_recoverable(hash, &private_key)).unwrap());
+
+		// A signature by the wrong key is rejected.
+		let signature = secp_ctx.sign_ecdsa_recoverable(&hash, &SecretKey::from_slice(&[43; 32]).unwrap());
+		assert_eq!(unsigned_invoice.assemble(signature), Err(SemanticError::InvalidSignature));
+	}
+
 	#[test]
 	fn test_default_values() {
 		use ::*;
@@ -129,25 +129,34 @@
 //! # }
 //! ```
 //!
+//! # On-chain Fallbacks
+//!
+//! Invoices may include on-chain fallback addresses to be used if the payment cannot be made over
+//! the Lightning Network. [`InvoicePayer::pay_invoice_with_fallback`] attempts the payment within
+//! a fee and retry budget and, if it definitively fails, provides an [`OnChainFallback`] describing
+//! the on-chain payment to make instead.
+//!
 //! # Note
 //!
 //! The [`Route`] is computed before each payment attempt. Any updates affecting path finding such
 //! as updates to the network graph or changes to channel scores should be applied prior to
 //! retries, typically by way of composing [`EventHandler`]s accordingly.
 
-use crate::Invoice;
+use crate::{Fallback, Invoice};
+use instruction::PaymentInstruction;
 
 use bitcoin_hashes::Hash;
 use bitcoin_hashes::sha256::Hash as Sha256;
 
 use crate::prelude::*;
 use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
-use lightning::ln::channelmanager::{ChannelDetails, PaymentId, PaymentSendFailure};
-use lightning::ln::msgs::LightningError;
+use lightning::ln::channelmanager::{ChannelDetails, PaymentId, PaymentSendFailure, PaymentSendFailureCause};
+use lightning::ln::msgs::{ErrorAction, LightningError, LightningErrorCause};
 use lightning::routing::gossip::NodeId;
 use lightning::routing::scoring::{ChannelUsage, LockableScore, Score};
 use lightning::routing::router::{PaymentParameters, Route, RouteHop, RouteParameters};
-use lightning::util::errors::APIError;
+use lightning::util::config::CltvPolicy;
+use lightning::util::errors::{APIError, ErrorClassification, Retryability};
 use lightning::util::events::{Event, EventHandler};
 use lightning::util::logger::Logger;
 use time_utils::Time;
@@ -191,6 +200,8 @@ where
 	/// Caches the overall attempts at making a payment, which is updated prior to retrying.
 	payment_cache: Mutex<HashMap<PaymentHash, PaymentInfo<T>>>,
 	retry: Retry,
+	/// On-chain fallbacks for payments which have failed, see [`Self::get_and_clear_on_chain_fallbacks`].
+	pending_on_chain_fallbacks: Mutex<Vec<OnChainFallback>>,
 }
 
 /// Used by [`InvoicePayerUsingTime::payment_cache`] to track the payments that are either
@@ -198,6 +209,12 @@ where
 struct PaymentInfo<T: Time> {
 	attempts: PaymentAttempts<T>,
 	paths: Vec<Vec<RouteHop>>,
+	/// Overrides [`InvoicePayerUsingTime::retry`] for this payment.
+	retry: Option<Retry>,
+	/// The maximum fee we'll pay on any single route for this payment.
+	max_fee_msat: Option<u64>,
+	/// What to pay on-chain if this payment fails.
+	on_chain_fallback: Option<OnChainFallback>,
 }
 
 impl<T: Time> PaymentInfo<T> {
@@ -205,6 +222,9 @@ impl<T: Time> PaymentInfo<T> {
 		PaymentInfo {
 			attempts: PaymentAttempts::new(),
 			paths: vec![],
+			retry: None,
+			max_fee_msat: None,
+			on_chain_fallback: None,
 		}
 	}
 }
@@ -309,6 +329,13 @@ pub trait Payer {
 
 	/// Signals that no further retries for the given payment will occur.
 	fn abandon_payment(&self, payment_id: PaymentId);
+
+	/// Returns the CLTV limits to apply when building routes.
+	///
+	/// Defaults to [`CltvPolicy::default`].
+	fn cltv_policy(&self) -> CltvPolicy {
+		CltvPolicy::default()
+	}
 }
 
 /// A trait defining behavior for routing an [`Invoice`] payment.
@@ -318,6 +345,20 @@ pub trait Router {
 		&self, payer: &PublicKey, route_params: &RouteParameters, payment_hash: &PaymentHash,
 		first_hops: Option<&[&ChannelDetails]>, scorer: &S
 	) -> Result<Route, LightningError>;
+
+	/// Finds [`Route`]s between `payer` and each of the payees in `route_params`, e.g. for probing
+	/// campaigns or batch payouts, returning one result per entry in the same order.
+	///
+	/// The default implementation simply calls [`Router::find_route`] for each entry, but
+	/// implementations may compute the batch more efficiently.
+	fn find_routes_batch<S: Score>(
+		&self, payer: &PublicKey, route_params: &[(RouteParameters, PaymentHash)],
+		first_hops: Option<&[&ChannelDetails]>, scorer: &S
+	) -> Vec<Result<Route, LightningError>> {
+		route_params.iter()
+			.map(|(params, payment_hash)| self.find_route(payer, params, payment_hash, first_hops, scorer))
+			.collect()
+	}
 }
 
 /// Strategies available to retry payment path failures for an [`Invoice`].
@@ -357,6 +398,113 @@ pub enum PaymentError {
 	Routing(LightningError),
 	/// An error occurring when sending a payment.
 	Sending(PaymentSendFailure),
+	/// A payment made via [`InvoicePayer::pay_invoice_with_fallback`] failed before any HTLCs were
+	/// sent, and should instead be paid on-chain as described.
+	OnChainFallback(OnChainFallback),
+}
+
+impl ErrorClassification for PaymentError {
+	type Cause = PaymentErrorCause;
+
+	/// Returns one of the following codes:
+	///  * 401: [`PaymentError::Invoice`]
+	///  * 402: [`PaymentError::Routing`]
+	///  * 403: [`PaymentError::Sending`]
+	///  * 404: [`PaymentError::OnChainFallback`]
+	///
+	/// The codes of the underlying [`LightningError`] and [`PaymentSendFailure`] are available via
+	/// their own implementations.
+	fn error_code(&self) -> u16 {
+		match self {
+			PaymentError::Invoice(_) => 401,
+			PaymentError::Routing(_) => 402,
+			PaymentError::Sending(_) => 403,
+			PaymentError::OnChainFallback(_) => 404,
+		}
+	}
+
+	fn retryability(&self) -> Retryability {
+		match self {
+			PaymentError::Invoice(_) => Retryability::Never,
+			PaymentError::Routing(err) => err.retryability(),
+			PaymentError::Sending(err) => err.retryability(),
+			PaymentError::OnChainFallback(_) => Retryability::Never,
+		}
+	}
+
+	fn cause(&self) -> PaymentErrorCause {
+		match self {
+			PaymentError::Invoice(err) => PaymentErrorCause::Invoice(match *err {
+				"amount missing" => InvoiceErrorCause::AmountMissing,
+				"amount unexpected" => InvoiceErrorCause::AmountUnexpected,
+				"payment pending" => InvoiceErrorCause::PaymentPending,
+				"Invoice expired prior to send" => InvoiceErrorCause::Expired,
+				"no fallback addresses" => InvoiceErrorCause::NoFallbackAddresses,
+				"no lightning invoice to pay" => InvoiceErrorCause::NoLightningInvoice,
+				"payment preimage missing" => InvoiceErrorCause::PaymentPreimageMissing,
+				_ => InvoiceErrorCause::Other,
+			}),
+			PaymentError::Routing(err) => PaymentErrorCause::Routing(err.cause()),
+			PaymentError::Sending(err) => PaymentErrorCause::Sending(err.cause()),
+			PaymentError::OnChainFallback(_) => PaymentErrorCause::OnChainFallback,
+		}
+	}
+}
+
+/// The cause of a [`PaymentError`], as returned by [`ErrorClassification::cause`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PaymentErrorCause {
+	/// See [`PaymentError::Invoice`].
+	Invoice(InvoiceErrorCause),
+	/// See [`PaymentError::Routing`], with the cause of its [`LightningError`].
+	Routing(LightningErrorCause),
+	/// See [`PaymentError::Sending`], with the cause of its [`PaymentSendFailure`].
+	Sending(PaymentSendFailureCause),
+	/// See [`PaymentError::OnChainFallback`].
+	OnChainFallback,
+}
+
+/// The cause of a [`PaymentError::Invoice`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InvoiceErrorCause {
+	/// The invoice has no amount, and none was provided.
+	AmountMissing,
+	/// An amount was provided for an invoice which already has one.
+	AmountUnexpected,
+	/// A payment for the same payment hash is already pending.
+	PaymentPending,
+	/// The invoice expired before the payment could be sent.
+	Expired,
+	/// The invoice has no fallback addresses to pay on-chain instead.
+	NoFallbackAddresses,
+	/// No invoice was provided where one is required.
+	NoLightningInvoice,
+	/// No payment preimage was provided for a spontaneous payment.
+	PaymentPreimageMissing,
+	/// Any other error resulting from the provided [`Invoice`] or payment hash.
+	Other,
+}
+
+/// An on-chain payment to make in place of an [`Invoice`] payment which could not be completed
+/// over the Lightning Network, see [`InvoicePayer::pay_invoice_with_fallback`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OnChainFallback {
+	/// The hash of the failed payment.
+	pub payment_hash: PaymentHash,
+	/// The invoice's fallback addresses, in the order the payee listed them. Any one of them may
+	/// be paid.
+	pub fallbacks: Vec<Fallback>,
+	/// The amount to pay, in millisatoshis.
+	pub amount_msat: u64,
+}
+
+impl OnChainFallback {
+	/// The amount to pay, rounded up to the next whole satoshi.
+	pub fn amount_sats(&self) -> u64 {
+		(self.amount_msat + 999) / 1000
+	}
 }
 
 impl<P: Deref, R: Router, S: Deref, L: Deref, E: EventHandler, T: Time> InvoicePayerUsingTime<P, R, S, L, E, T>
@@ -380,6 +528,7 @@ where
 			event_handler,
 			payment_cache: Mutex::new(HashMap::new()),
 			retry,
+			pending_on_chain_fallbacks: Mutex::new(Vec::new()),
 		}
 	}
 
@@ -392,10 +541,60 @@ where
 		if invoice.amount_milli_satoshis().is_none() {
 			Err(PaymentError::Invoice("amount missing"))
 		} else {
-			self.pay_invoice_using_amount(invoice, None)
+			self.pay_invoice_using_amount(invoice, None, PaymentInfo::new())
 		}
 	}
 
+	/// Pays the given [`Invoice`] as in [`Self::pay_invoice`], falling back to paying one of the
+	/// invoice's on-chain fallback addresses if the payment fails.
+	///
+	/// Routes whose fees exceed `max_fee_msat` are not used, and the payment is retried according
+	/// to `retry` if set, or the [`Retry`] this payer was created with otherwise. Note that the fee
+	/// budget applies to each route separately, including those used to retry failed paths.
+	///
+	/// If the payment fails before any HTLCs are sent, [`PaymentError::OnChainFallback`] is
+	/// returned. If it instead fails later, the [`OnChainFallback`] is available via
+	/// [`Self::get_and_clear_on_chain_fallbacks`] before the corresponding [`Event::PaymentFailed`]
+	/// is passed to the decorated event handler.
+	///
+	/// Fails with [`PaymentError::Invoice`] if the invoice has no amount or no fallback addresses.
+	pub fn pay_invoice_with_fallback(
+		&self, invoice: &Invoice, max_fee_msat: Option<u64>, retry: Option<Retry>
+	) -> Result<PaymentId, PaymentError> {
+		let amount_msat = match invoice.amount_milli_satoshis() {
+			Some(amount_msat) => amount_msat,
+			None => return Err(PaymentError::Invoice("amount missing")),
+		};
+		let fallbacks: Vec<Fallback> = invoice.fallbacks().into_iter().cloned().collect();
+		if fallbacks.is_empty() {
+			return Err(PaymentError::Invoice("no fallback addresses"));
+		}
+		let on_chain_fallback = OnChainFallback {
+			payment_hash: PaymentHash(invoice.payment_hash().clone().into_inner()),
+			fallbacks,
+			amount_msat,
+		};
+		let payment_info = PaymentInfo {
+			retry,
+			max_fee_msat,
+			on_chain_fallback: Some(on_chain_fallback.clone()),
+			..PaymentInfo::new()
+		};
+		self.pay_invoice_using_amount(invoice, None, payment_info).map_err(|e| match e {
+			PaymentError::Invoice("payment pending") => e,
+			_ => {
+				log_trace!(self.logger, "Payment {} failed, falling back to on-chain: {:?}", log_bytes!(on_chain_fallback.payment_hash.0), e);
+				PaymentError::OnChainFallback(on_chain_fallback)
+			},
+		})
+	}
+
+	/// Returns the [`OnChainFallback`]s for payments made via [`Self::pay_invoice_with_fallback`]
+	/// which have failed since the last call, clearing them.
+	pub fn get_and_clear_on_chain_fallbacks(&self) -> Vec<OnChainFallback> {
+		core::mem::take(&mut *self.pending_on_chain_fallbacks.lock().unwrap())
+	}
+
 	/// Pays the given zero-value [`Invoice`] using the given amount, caching it for later use in
 	/// case a retry is needed.
 	///
@@ -408,25 +607,26 @@ where
 		if invoice.amount_milli_satoshis().is_some() {
 			Err(PaymentError::Invoice("amount unexpected"))
 		} else {
-			self.pay_invoice_using_amount(invoice, Some(amount_msats))
+			self.pay_invoice_using_amount(invoice, Some(amount_msats), PaymentInfo::new())
 		}
 	}
 
 	fn pay_invoice_using_amount(
-		&self, invoice: &Invoice, amount_msats: Option<u64>
+		&self, invoice: &Invoice, amount_msats: Option<u64>, payment_info: PaymentInfo<T>
 	) -> Result<PaymentId, PaymentError> {
 		debug_assert!(invoice.amount_milli_satoshis().is_some() ^ amount_msats.is_some());
 
 		let payment_hash = PaymentHash(invoice.payment_hash().clone().into_inner());
 		match self.payment_cache.lock().unwrap().entry(payment_hash) {
 			hash_map::Entry::Occupied(_) => return Err(PaymentError::Invoice("payment pending")),
-			hash_map::Entry::Vacant(entry) => entry.insert(PaymentInfo::new()),
+			hash_map::Entry::Vacant(entry) => entry.insert(payment_info),
 		};
 
 		let payment_secret = Some(invoice.payment_secret().clone());
 		let mut payment_params = PaymentParameters::from_node_id(invoice.recover_payee_pub_key())
 			.with_expiry_time(expiry_time_from_unix_epoch(&invoice).as_secs())
-			.with_route_hints(invoice.route_hints());
+			.with_route_hints(invoice.route_hints())
+			.with_max_total_cltv_expiry_delta(self.payer.cltv_policy().max_total_cltv_expiry_delta);
 		if let Some(features) = invoice.features() {
 			payment_params = payment_params.with_features(features.clone());
 		}
@@ -460,7 +660,8 @@ where
 		};
 
 		let route_params = RouteParameters {
-			payment_params: PaymentParameters::for_keysend(pubkey),
+			payment_params: PaymentParameters::for_keysend(pubkey)
+				.with_max_total_cltv_expiry_delta(self.payer.cltv_policy().max_total_cltv_expiry_delta),
 			final_value_msat: amount_msats,
 			final_cltv_expiry_delta,
 		};
@@ -472,6 +673,57 @@ where
 			.map_err(|e| { self.payment_cache.lock().unwrap().remove(&payment_hash); e })
 	}
 
+	/// Pays the given [`PaymentInstruction`], dispatching to [`Self::pay_invoice`],
+	/// [`Self::pay_zero_value_invoice`] or [`Self::pay_pubkey`] as appropriate.
+	///
+	/// `amount_msats` is required for instructions which don't specify an amount, and otherwise
+	/// must either be `None` or match the amount requested. For a [`PaymentInstruction::Bip21`]
+	/// URI, the amount in the URI is used if its invoice doesn't have one. URIs without an invoice
+	/// must be paid on-chain instead, resulting in a [`PaymentError::Invoice`].
+	///
+	/// `payment_preimage` is required for [`PaymentInstruction::Keysend`], and is subject to the
+	/// same uniqueness requirements as in [`Self::pay_pubkey`]. Keysend payments use the
+	/// [`Payer::cltv_policy`]'s [`CltvPolicy::min_final_cltv_expiry`] as their final CLTV expiry
+	/// delta.
+	pub fn pay_instruction(
+		&self, instruction: &PaymentInstruction, amount_msats: Option<u64>,
+		payment_preimage: Option<PaymentPreimage>
+	) -> Result<PaymentId, PaymentError> {
+		match *instruction {
+			PaymentInstruction::Bolt11(ref invoice) => self.pay_invoice_instruction(invoice, amount_msats),
+			PaymentInstruction::Bip21 { invoice: Some(ref invoice), amount_msats: uri_amount_msats, .. } => {
+				let amount_msats = match invoice.amount_milli_satoshis() {
+					Some(_) => amount_msats,
+					None => amount_msats.or(uri_amount_msats),
+				};
+				self.pay_invoice_instruction(invoice, amount_msats)
+			},
+			PaymentInstruction::Bip21 { invoice: None, .. } => {
+				Err(PaymentError::Invoice("no lightning invoice to pay"))
+			},
+			PaymentInstruction::Keysend { node_id, .. } => {
+				let amount_msats = amount_msats.ok_or(PaymentError::Invoice("amount missing"))?;
+				let payment_preimage =
+					payment_preimage.ok_or(PaymentError::Invoice("payment preimage missing"))?;
+				let final_cltv_expiry_delta = self.payer.cltv_policy().min_final_cltv_expiry;
+				self.pay_pubkey(node_id, payment_preimage, amount_msats, final_cltv_expiry_delta)
+			},
+		}
+	}
+
+	fn pay_invoice_instruction(
+		&self, invoice: &Invoice, amount_msats: Option<u64>
+	) -> Result<PaymentId, PaymentError> {
+		match (invoice.amount_milli_satoshis(), amount_msats) {
+			(Some(invoice_amount_msats), Some(amount_msats)) if invoice_amount_msats != amount_msats => {
+				Err(PaymentError::Invoice("amount unexpected"))
+			},
+			(Some(_), _) => self.pay_invoice(invoice),
+			(None, Some(amount_msats)) => self.pay_zero_value_invoice(invoice, amount_msats),
+			(None, None) => Err(PaymentError::Invoice("amount missing")),
+		}
+	}
+
 	fn pay_internal<F: FnOnce(&Route) -> Result<PaymentId, PaymentSendFailure> + Copy>(
 		&self, params: &RouteParameters, payment_hash: PaymentHash, send_payment: F,
 	) -> Result<PaymentId, PaymentError> {
@@ -488,7 +740,7 @@ where
 		let route = self.router.find_route(
 			&payer, &params, &payment_hash, Some(&first_hops.iter().collect::<Vec<_>>()),
 			&AccountForInFlightHtlcs { scorer: &mut self.scorer.lock(), inflight_htlcs }
-		).map_err(|e| PaymentError::Routing(e))?;
+		).and_then(|route| self.check_fee_budget(payment_hash, route)).map_err(|e| PaymentError::Routing(e))?;
 
 		match send_payment(&route) {
 			Ok(payment_id) => {
@@ -504,7 +756,7 @@ where
 					let mut payment_cache = self.payment_cache.lock().unwrap();
 					let payment_info = payment_cache.get_mut(&payment_hash).unwrap();
 					payment_info.attempts.count += 1;
-					if self.retry.is_retryable_now(&payment_info.attempts) {
+					if payment_info.retry.unwrap_or(self.retry).is_retryable_now(&payment_info.attempts) {
 						core::mem::drop(payment_cache);
 						Ok(self.pay_internal(params, payment_hash, send_payment)?)
 					} else {
@@ -543,6 +795,22 @@ where
 		}.map_err(|e| PaymentError::Sending(e))
 	}
 
+	// Fails if the given route's fees exceed the budget set for the payment, if any.
+	fn check_fee_budget(&self, payment_hash: PaymentHash, route: Route) -> Result<Route, LightningError> {
+		let max_fee_msat = self.payment_cache.lock().unwrap().get(&payment_hash)
+			.and_then(|payment_info| payment_info.max_fee_msat);
+		match max_fee_msat {
+			Some(max_fee_msat) if route.get_total_fees() > max_fee_msat => {
+				log_trace!(self.logger, "Route fees of {}msat for payment {} exceed the budget of {}msat", route.get_total_fees(), log_bytes!(payment_hash.0), max_fee_msat);
+				Err(LightningError {
+					err: "Route fees exceed the payment's fee budget".to_string(),
+					action: ErrorAction::IgnoreError,
+				})
+			},
+			_ => Ok(route),
+		}
+	}
+
 	// Takes in a path to have its information stored in `payment_cache`. This is done for paths
 	// that are pending retry.
 	fn process_path_inflight_htlcs(&self, payment_hash: PaymentHash, path: Vec<RouteHop>) {
@@ -564,17 +832,21 @@ where
 	fn retry_payment(
 		&self, payment_id: PaymentId, payment_hash: PaymentHash, params: &RouteParameters
 	) -> Result<(), ()> {
-		let attempts = self.payment_cache.lock().unwrap().entry(payment_hash)
-			.and_modify(|info| info.attempts.count += 1 )
-			.or_insert_with(|| PaymentInfo {
-				attempts: PaymentAttempts {
-					count: 1,
-					first_attempted_at: T::now(),
-				},
-				paths: vec![],
-			}).attempts;
+		let (attempts, retry) = {
+			let mut payment_cache = self.payment_cache.lock().unwrap();
+			let payment_info = payment_cache.entry(payment_hash)
+				.and_modify(|info| info.attempts.count += 1 )
+				.or_insert_with(|| PaymentInfo {
+					attempts: PaymentAttempts {
+						count: 1,
+						first_attempted_at: T::now(),
+					},
+					..PaymentInfo::new()
+				});
+			(payment_info.attempts, payment_info.retry.unwrap_or(self.retry))
+		};
 
-		if !self.retry.is_retryable_now(&attempts) {
+		if !retry.is_retryable_now(&attempts) {
 			log_trace!(self.logger, "Payment {} exceeded maximum attempts; not retrying ({})", log_bytes!(payment_hash.0), attempts);
 			return Err(());
 		}
@@ -593,7 +865,7 @@ where
 		let route = self.router.find_route(
 			&payer, &params, &payment_hash, Some(&first_hops.iter().collect::<Vec<_>>()),
 			&AccountForInFlightHtlcs { scorer: &mut self.scorer.lock(), inflight_htlcs }
-		);
+		).and_then(|route| self.check_fee_budget(payment_hash, route));
 
 		if route.is_err() {
 			log_trace!(self.logger, "Failed to find a route for payment {}; not retrying ({:})", log_bytes!(payment_hash.0), attempts);
@@ -740,7 +1012,11 @@ where
 				}
 			},
 			Event::PaymentFailed { payment_hash, .. } => {
-				self.remove_cached_payment(&payment_hash);
+				let payment_info = self.payment_cache.lock().unwrap().remove(payment_hash);
+				if let Some(on_chain_fallback) = payment_info.and_then(|info| info.on_chain_fallback) {
+					log_trace!(self.logger, "Payment {} failed, falling back to on-chain", log_bytes!(payment_hash.0));
+					self.pending_on_chain_fallbacks.lock().unwrap().push(on_chain_fallback);
+				}
 			},
 			Event::PaymentPathSuccessful { path, .. } => {
 				let path = path.iter().collect::<Vec<_>>();
@@ -780,6 +1056,7 @@ mod tests {
 	use utils::create_invoice_from_channelmanager_and_duration_since_epoch;
 	use bitcoin_hashes::sha256::Hash as Sha256;
 	use lightning::ln::PaymentPreimage;
+	use lightning::ln::channelmanager::MIN_FINAL_CLTV_EXPIRY;
 	use lightning::ln::features::{ChannelFeatures, NodeFeatures, InitFeatures};
 	use lightning::ln::functional_test_utils::*;
 	use lightning::ln::msgs::{ChannelMessageHandler, ErrorAction, LightningError};
@@ -787,7 +1064,7 @@ mod tests {
 	use lightning::routing::router::{PaymentParameters, Route, RouteHop};
 	use lightning::routing::scoring::ChannelUsage;
 	use lightning::util::test_utils::TestLogger;
-	use lightning::util::errors::APIError;
+	use lightning::util::errors::{APIError, APIErrorCause};
 	use lightning::util::events::{Event, EventsProvider, MessageSendEvent, MessageSendEventsProvider};
 	use secp256k1::{SecretKey, PublicKey, Secp256k1};
 	use std::cell::RefCell;
@@ -1271,6 +1548,73 @@ mod tests {
 		assert_eq!(*payer.attempts.borrow(), 1);
 	}
 
+	#[test]
+	fn falls_back_to_on_chain_payment() {
+		let invoice_with_fallback = |payment_preimage: PaymentPreimage| {
+			InvoiceBuilder::new(Currency::Bitcoin)
+				.description("test".into())
+				.payment_hash(Sha256::hash(&payment_preimage.0))
+				.payment_secret(PaymentSecret([0; 32]))
+				.duration_since_epoch(duration_since_epoch())
+				.min_final_cltv_expiry(144)
+				.amount_milli_satoshis(128_500)
+				.fallback(Fallback::PubKeyHash([1; 20]))
+				.build_signed(|hash| {
+					Secp256k1::new().sign_ecdsa_recoverable(hash, &SecretKey::from_slice(&[42; 32]).unwrap())
+				})
+				.unwrap()
+		};
+		let event_handled = core::cell::RefCell::new(false);
+		let event_handler = |_: &_| { *event_handled.borrow_mut() = true; };
+
+		let payer = TestPayer::new().expect_send(Amount::ForInvoice(128_500));
+		let router = TestRouter {};
+		let scorer = RefCell::new(TestScorer::new());
+		let logger = TestLogger::new();
+		let invoice_payer =
+			InvoicePayer::new(&payer, router, &scorer, &logger, event_handler, Retry::Attempts(2));
+
+		// Invoices without fallback addresses can't be paid this way.
+		match invoice_payer.pay_invoice_with_fallback(&invoice(PaymentPreimage([1; 32])), None, None) {
+			Err(PaymentError::Invoice("no fallback addresses")) => {},
+			_ => panic!("expected invoice error"),
+		}
+
+		// A route exceeding our fee budget (of 40msat here) has us fall back immediately.
+		let invoice = invoice_with_fallback(PaymentPreimage([2; 32]));
+		let payment_hash = PaymentHash(invoice.payment_hash().clone().into_inner());
+		let expected_fallback = OnChainFallback {
+			payment_hash, fallbacks: vec![Fallback::PubKeyHash([1; 20])], amount_msat: 128_500,
+		};
+		match invoice_payer.pay_invoice_with_fallback(&invoice, Some(39), None) {
+			Err(PaymentError::OnChainFallback(fallback)) => assert_eq!(fallback, expected_fallback),
+			_ => panic!("expected on-chain fallback"),
+		}
+		assert_eq!(expected_fallback.amount_sats(), 129);
+		assert_eq!(*payer.attempts.borrow(), 0);
+
+		// Once a payment we did send fails, its fallback is available to the event handler.
+		let payment_id = Some(invoice_payer.pay_invoice_with_fallback(&invoice, Some(40), Some(Retry::Attempts(0))).unwrap());
+		assert_eq!(*payer.attempts.borrow(), 1);
+		invoice_payer.handle_event(&Event::PaymentPathFailed {
+			payment_id,
+			payment_hash,
+			network_update: None,
+			rejected_by_dest: false,
+			all_paths_failed: true,
+			path: TestRouter::path_for_value(128_500),
+			short_channel_id: None,
+			retry: Some(TestRouter::retry_for_invoice(&invoice)),
+		});
+		assert_eq!(*event_handled.borrow(), true);
+		assert_eq!(*payer.attempts.borrow(), 1);
+		assert!(invoice_payer.get_and_clear_on_chain_fallbacks().is_empty());
+
+		invoice_payer.handle_event(&Event::PaymentFailed { payment_id: payment_id.unwrap(), payment_hash });
+		assert_eq!(invoice_payer.get_and_clear_on_chain_fallbacks(), vec![expected_fallback]);
+		assert!(invoice_payer.get_and_clear_on_chain_fallbacks().is_empty());
+	}
+
 	#[test]
 	fn fails_repaying_invoice_with_pending_payment() {
 		let event_handled = core::cell::RefCell::new(false);
@@ -1359,6 +1703,42 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn classifies_payment_errors() {
+		let payment_preimage = PaymentPreimage([1; 32]);
+		let invoice = invoice(payment_preimage);
+		let final_value_msat = invoice.amount_milli_satoshis().unwrap();
+		let scorer = RefCell::new(TestScorer::new());
+		let logger = TestLogger::new();
+
+		let payer = TestPayer::new();
+		let invoice_payer =
+			InvoicePayer::new(&payer, FailingRouter {}, &scorer, &logger, |_: &_| {}, Retry::Attempts(0));
+		let err = invoice_payer.pay_invoice(&invoice).unwrap_err();
+		assert_eq!(err.error_code(), 402);
+		assert_eq!(err.retryability(), Retryability::Later);
+		assert_eq!(err.cause(), PaymentErrorCause::Routing(LightningErrorCause::Ignored));
+
+		let payer = TestPayer::new()
+			.fails_on_attempt(1)
+			.expect_send(Amount::ForInvoice(final_value_msat));
+		let invoice_payer =
+			InvoicePayer::new(&payer, TestRouter {}, &scorer, &logger, |_: &_| {}, Retry::Attempts(0));
+		let err = invoice_payer.pay_invoice(&invoice).unwrap_err();
+		assert_eq!(err.error_code(), 403);
+		assert_eq!(err.retryability(), Retryability::InProgress);
+		assert_eq!(err.cause(), PaymentErrorCause::Sending(
+			PaymentSendFailureCause::InvalidParameter(APIErrorCause::MonitorUpdateFailed)));
+
+		let payer = TestPayer::new();
+		let invoice_payer =
+			InvoicePayer::new(&payer, TestRouter {}, &scorer, &logger, |_: &_| {}, Retry::Attempts(0));
+		let err = invoice_payer.pay_zero_value_invoice(&invoice, 100).unwrap_err();
+		assert_eq!(err.error_code(), 401);
+		assert_eq!(err.retryability(), Retryability::Never);
+		assert_eq!(err.cause(), PaymentErrorCause::Invoice(InvoiceErrorCause::AmountUnexpected));
+	}
+
 	#[test]
 	fn pays_zero_value_invoice_using_amount() {
 		let event_handled = core::cell::RefCell::new(false);
@@ -1410,6 +1790,73 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn pays_payment_instructions() {
+		let event_handler = |_: &_| {};
+		let payment_preimage = PaymentPreimage([1; 32]);
+		let bip21 = |invoice: Invoice, amount_msats| PaymentInstruction::Bip21 {
+			address: None, amount_msats: Some(amount_msats), invoice: Some(invoice)
+		};
+
+		let payer = TestPayer::new()
+			.expect_send(Amount::ForInvoice(128))
+			.expect_send(Amount::ForInvoice(100))
+			.expect_send(Amount::Spontaneous(200));
+		let router = TestRouter {};
+		let scorer = RefCell::new(TestScorer::new());
+		let logger = TestLogger::new();
+		let invoice_payer =
+			InvoicePayer::new(&payer, router, &scorer, &logger, event_handler, Retry::Attempts(0));
+
+		// The URI amount is ignored in favor of the invoice's, which must match any given amount.
+		let instruction = bip21(invoice(payment_preimage), 100);
+		match invoice_payer.pay_instruction(&instruction, Some(100), None) {
+			Err(PaymentError::Invoice("amount unexpected")) => {},
+			_ => panic!("expected invoice error"),
+		}
+		assert!(invoice_payer.pay_instruction(&instruction, None, None).is_ok());
+
+		// Zero-value invoices use the URI amount if none is given.
+		let instruction = PaymentInstruction::Bolt11(zero_value_invoice(PaymentPreimage([2; 32])));
+		match invoice_payer.pay_instruction(&instruction, None, None) {
+			Err(PaymentError::Invoice("amount missing")) => {},
+			_ => panic!("expected invoice error"),
+		}
+		let instruction = bip21(zero_value_invoice(PaymentPreimage([2; 32])), 100);
+		assert!(invoice_payer.pay_instruction(&instruction, None, None).is_ok());
+
+		let instruction = PaymentInstruction::Bip21 { address: Some("address".to_string()), amount_msats: None, invoice: None };
+		match invoice_payer.pay_instruction(&instruction, Some(100), None) {
+			Err(PaymentError::Invoice("no lightning invoice to pay")) => {},
+			_ => panic!("expected invoice error"),
+		}
+
+		let instruction = PaymentInstruction::Keysend { node_id: pubkey(), address: None };
+		match invoice_payer.pay_instruction(&instruction, Some(200), None) {
+			Err(PaymentError::Invoice("payment preimage missing")) => {},
+			_ => panic!("expected invoice error"),
+		}
+		assert!(invoice_payer.pay_instruction(&instruction, Some(200), Some(PaymentPreimage([3; 32]))).is_ok());
+		assert_eq!(*payer.attempts.borrow(), 3);
+	}
+
+	#[test]
+	fn pays_keysend_instruction_with_min_final_cltv_expiry_from_policy() {
+		let min_final_cltv_expiry = MIN_FINAL_CLTV_EXPIRY + 10;
+		let payer = TestPayer::new()
+			.with_cltv_policy(CltvPolicy { min_final_cltv_expiry, ..CltvPolicy::default() })
+			.expect_send(Amount::Spontaneous(200));
+		let router = FinalCltvCheckingRouter(min_final_cltv_expiry);
+		let scorer = RefCell::new(TestScorer::new());
+		let logger = TestLogger::new();
+		let invoice_payer =
+			InvoicePayer::new(&payer, router, &scorer, &logger, |_: &_| {}, Retry::Attempts(0));
+
+		let instruction = PaymentInstruction::Keysend { node_id: pubkey(), address: None };
+		assert!(invoice_payer.pay_instruction(&instruction, Some(200), Some(PaymentPreimage([3; 32]))).is_ok());
+		assert_eq!(*payer.attempts.borrow(), 1);
+	}
+
 	#[test]
 	fn pays_pubkey_with_amount() {
 		let event_handled = core::cell::RefCell::new(false);
@@ -1874,6 +2321,18 @@ mod tests {
 		}
 	}
 
+	struct FinalCltvCheckingRouter(u32);
+
+	impl Router for FinalCltvCheckingRouter {
+		fn find_route<S: Score>(
+			&self, payer: &PublicKey, params: &RouteParameters, payment_hash: &PaymentHash,
+			first_hops: Option<&[&ChannelDetails]>, scorer: &S
+		) -> Result<Route, LightningError> {
+			assert_eq!(params.final_cltv_expiry_delta, self.0);
+			TestRouter {}.find_route(payer, params, payment_hash, first_hops, scorer)
+		}
+	}
+
 	struct FailingRouter;
 
 	impl Router for FailingRouter {
@@ -2017,6 +2476,7 @@ mod tests {
 		expectations: core::cell::RefCell<VecDeque<Amount>>,
 		attempts: core::cell::RefCell<usize>,
 		failing_on_attempt: core::cell::RefCell<HashMap<usize, PaymentSendFailure>>,
+		cltv_policy: CltvPolicy,
 	}
 
 	#[derive(Clone, Debug, PartialEq, Eq)]
@@ -2034,9 +2494,15 @@ mod tests {
 				expectations: core::cell::RefCell::new(VecDeque::new()),
 				attempts: core::cell::RefCell::new(0),
 				failing_on_attempt: core::cell::RefCell::new(HashMap::new()),
+				cltv_policy: CltvPolicy::default(),
 			}
 		}
 
+		fn with_cltv_policy(mut self, cltv_policy: CltvPolicy) -> Self {
+			self.cltv_policy = cltv_policy;
+			self
+		}
+
 		fn expect_send(self, value_msat: Amount) -> Self {
 			self.expectations.borrow_mut().push_back(value_msat);
 			self
@@ -2125,6 +2591,10 @@ mod tests {
 		}
 
 		fn abandon_payment(&self, _payment_id: PaymentId) { }
+
+		fn cltv_policy(&self) -> CltvPolicy {
+			self.cltv_policy
+		}
 	}
 
 	// *** Full Featured Functional Tests with a Real ChannelManager ***
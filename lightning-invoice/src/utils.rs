@@ -1,6 +1,6 @@
 //! Convenient utilities to create an invoice.
 
-use {CreationError, Currency, Invoice, InvoiceBuilder, SignOrCreationError};
+use {CreationError, Currency, Invoice, InvoiceBuilder, SignOrCreationError, UnsignedInvoice};
 use payment::{Payer, Router};
 
 use crate::{prelude::*, Description, InvoiceDescription, Sha256};
@@ -10,16 +10,18 @@ use lightning::chain;
 use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
 use lightning::chain::keysinterface::{Recipient, KeysInterface, Sign};
 use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
-use lightning::ln::channelmanager::{ChannelDetails, ChannelManager, PaymentId, PaymentSendFailure, MIN_FINAL_CLTV_EXPIRY};
+use lightning::ln::channelmanager::{ChannelDetails, ChannelManager, InboundPaymentLimits, PaymentId, PaymentSendFailure};
 #[cfg(feature = "std")]
-use lightning::ln::channelmanager::{PhantomRouteHints, MIN_CLTV_EXPIRY_DELTA};
+use lightning::ln::channelmanager::PhantomRouteHints;
 use lightning::ln::inbound_payment::{create, create_from_hash, ExpandedKey};
 use lightning::ln::msgs::LightningError;
 use lightning::routing::gossip::{NetworkGraph, RoutingFees};
-use lightning::routing::router::{Route, RouteHint, RouteHintHop, RouteParameters, find_route};
+use lightning::routing::router::{Route, RouteHint, RouteHintHop, RouteParameters, find_route, find_routes_batch};
 use lightning::routing::scoring::Score;
+use lightning::util::config::CltvPolicy;
 use lightning::util::logger::Logger;
 use secp256k1::PublicKey;
+use core::cmp;
 use core::ops::Deref;
 use core::time::Duration;
 use sync::Mutex;
@@ -157,17 +159,19 @@ fn _create_phantom_invoice<Signer: Sign, K: Deref>(
 		.map_err(|_| SignOrCreationError::CreationError(CreationError::InvalidAmount))?
 	};
 
+	// Whichever node the payment ends up at must accept its final CLTV expiry.
+	let min_final_cltv_expiry = phantom_route_hints.iter().map(|hints| hints.min_final_cltv_expiry).max().unwrap();
 	let mut invoice = invoice
 		.current_timestamp()
 		.payment_hash(Hash::from_slice(&payment_hash.0).unwrap())
 		.payment_secret(payment_secret)
-		.min_final_cltv_expiry(MIN_FINAL_CLTV_EXPIRY.into())
+		.min_final_cltv_expiry(min_final_cltv_expiry.into())
 		.expiry_time(Duration::from_secs(invoice_expiry_delta_secs.into()));
 	if let Some(amt) = amt_msat {
 		invoice = invoice.amount_milli_satoshis(amt);
 	}
 
-	for PhantomRouteHints { channels, phantom_scid, real_node_pubkey } in phantom_route_hints {
+	for PhantomRouteHints { channels, phantom_scid, real_node_pubkey, min_cltv_expiry_delta, .. } in phantom_route_hints {
 		let mut route_hints = filter_channels(channels, amt_msat);
 
 		// If we have any public channel, the route hints from `filter_channels` will be empty.
@@ -185,7 +189,7 @@ fn _create_phantom_invoice<Signer: Sign, K: Deref>(
 					base_msat: 0,
 					proportional_millionths: 0,
 				},
-				cltv_expiry_delta: MIN_CLTV_EXPIRY_DELTA,
+				cltv_expiry_delta: min_cltv_expiry_delta,
 				htlc_minimum_msat: None,
 				htlc_maximum_msat: None,});
 			invoice = invoice.private_route(route_hint.clone());
@@ -286,7 +290,7 @@ where
 	_create_invoice_from_channelmanager_and_duration_since_epoch(
 		channelmanager, keys_manager, network, amt_msat,
 		InvoiceDescription::Hash(&description_hash),
-		duration_since_epoch, invoice_expiry_delta_secs
+		duration_since_epoch, invoice_expiry_delta_secs, None
 	)
 }
 
@@ -310,15 +314,80 @@ where
 		InvoiceDescription::Direct(
 			&Description::new(description).map_err(SignOrCreationError::CreationError)?,
 		),
-		duration_since_epoch, invoice_expiry_delta_secs
+		duration_since_epoch, invoice_expiry_delta_secs, None
+	)
+}
+
+/// See [`create_invoice_from_channelmanager_and_duration_since_epoch`]
+/// This version additionally registers `limits` on the HTLCs the `ChannelManager` will accept
+/// when the invoice is paid, via [`ChannelManager::create_inbound_payment_with_limits`].
+pub fn create_invoice_from_channelmanager_with_limits_and_duration_since_epoch<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(
+	channelmanager: &ChannelManager<Signer, M, T, K, F, L>, keys_manager: K, network: Currency,
+	amt_msat: Option<u64>, description: String, duration_since_epoch: Duration,
+	invoice_expiry_delta_secs: u32, limits: InboundPaymentLimits
+) -> Result<Invoice, SignOrCreationError<()>>
+where
+	M::Target: chain::Watch<Signer>,
+	T::Target: BroadcasterInterface,
+	K::Target: KeysInterface<Signer = Signer>,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	_create_invoice_from_channelmanager_and_duration_since_epoch(
+		channelmanager, keys_manager, network, amt_msat,
+		InvoiceDescription::Direct(
+			&Description::new(description).map_err(SignOrCreationError::CreationError)?,
+		),
+		duration_since_epoch, invoice_expiry_delta_secs, Some(limits)
 	)
 }
 
 fn _create_invoice_from_channelmanager_and_duration_since_epoch<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(
 	channelmanager: &ChannelManager<Signer, M, T, K, F, L>, keys_manager: K, network: Currency,
 	amt_msat: Option<u64>, description: InvoiceDescription, duration_since_epoch: Duration,
-	invoice_expiry_delta_secs: u32
+	invoice_expiry_delta_secs: u32, limits: Option<InboundPaymentLimits>
 ) -> Result<Invoice, SignOrCreationError<()>>
+where
+	M::Target: chain::Watch<Signer>,
+	T::Target: BroadcasterInterface,
+	K::Target: KeysInterface<Signer = Signer>,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	let unsigned_invoice = _create_unsigned_invoice_from_channelmanager_and_duration_since_epoch(
+		channelmanager, network, amt_msat, description, duration_since_epoch, invoice_expiry_delta_secs, limits
+	).map_err(SignOrCreationError::CreationError)?;
+	let signature = keys_manager.sign_invoice(&unsigned_invoice.hrp_bytes(), &unsigned_invoice.data_without_signature(), Recipient::Node)
+		.map_err(SignOrCreationError::SignError)?;
+	Ok(unsigned_invoice.assemble(signature).unwrap())
+}
+
+/// Constructs an invoice as in [`create_invoice_from_channelmanager_and_duration_since_epoch`],
+/// but returns it unsigned, e.g. to be signed via [`KeysInterface::sign_invoice`] on a remote
+/// signer holding our node's key. See [`UnsignedInvoice`] for details.
+pub fn create_unsigned_invoice_from_channelmanager_and_duration_since_epoch<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(
+	channelmanager: &ChannelManager<Signer, M, T, K, F, L>, network: Currency,
+	amt_msat: Option<u64>, description: String, duration_since_epoch: Duration,
+	invoice_expiry_delta_secs: u32
+) -> Result<UnsignedInvoice, CreationError>
+where
+	M::Target: chain::Watch<Signer>,
+	T::Target: BroadcasterInterface,
+	K::Target: KeysInterface<Signer = Signer>,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	_create_unsigned_invoice_from_channelmanager_and_duration_since_epoch(
+		channelmanager, network, amt_msat, InvoiceDescription::Direct(&Description::new(description)?),
+		duration_since_epoch, invoice_expiry_delta_secs, None
+	)
+}
+
+fn _create_unsigned_invoice_from_channelmanager_and_duration_since_epoch<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(
+	channelmanager: &ChannelManager<Signer, M, T, K, F, L>, network: Currency,
+	amt_msat: Option<u64>, description: InvoiceDescription, duration_since_epoch: Duration,
+	invoice_expiry_delta_secs: u32, limits: Option<InboundPaymentLimits>
+) -> Result<UnsignedInvoice, CreationError>
 where
 	M::Target: chain::Watch<Signer>,
 	T::Target: BroadcasterInterface,
@@ -329,10 +398,11 @@ where
 	let route_hints = filter_channels(channelmanager.list_usable_channels(), amt_msat);
 
 	// `create_inbound_payment` only returns an error if the amount is greater than the total bitcoin
-	// supply.
-	let (payment_hash, payment_secret) = channelmanager
-		.create_inbound_payment(amt_msat, invoice_expiry_delta_secs)
-		.map_err(|()| SignOrCreationError::CreationError(CreationError::InvalidAmount))?;
+	// supply, or, with limits, if the amount is greater than the limits allow.
+	let (payment_hash, payment_secret) = match limits {
+		Some(limits) => channelmanager.create_inbound_payment_with_limits(amt_msat, invoice_expiry_delta_secs, limits),
+		None => channelmanager.create_inbound_payment(amt_msat, invoice_expiry_delta_secs),
+	}.map_err(|()| CreationError::InvalidAmount)?;
 	let our_node_pubkey = channelmanager.get_our_node_id();
 
 	let invoice = match description {
@@ -348,7 +418,7 @@ where
 		.payment_hash(Hash::from_slice(&payment_hash.0).unwrap())
 		.payment_secret(payment_secret)
 		.basic_mpp()
-		.min_final_cltv_expiry(MIN_FINAL_CLTV_EXPIRY.into())
+		.min_final_cltv_expiry(channelmanager.get_current_default_configuration().cltv_policy.min_final_cltv_expiry.into())
 		.expiry_time(Duration::from_secs(invoice_expiry_delta_secs.into()));
 	if let Some(amt) = amt_msat {
 		invoice = invoice.amount_milli_satoshis(amt);
@@ -357,18 +427,7 @@ where
 		invoice = invoice.private_route(hint);
 	}
 
-	let raw_invoice = match invoice.build_raw() {
-		Ok(inv) => inv,
-		Err(e) => return Err(SignOrCreationError::CreationError(e))
-	};
-	let hrp_str = raw_invoice.hrp.to_string();
-	let hrp_bytes = hrp_str.as_bytes();
-	let data_without_signature = raw_invoice.data.to_base32();
-	let signed_raw_invoice = raw_invoice.sign(|_| keys_manager.sign_invoice(hrp_bytes, &data_without_signature, Recipient::Node));
-	match signed_raw_invoice {
-		Ok(inv) => Ok(Invoice::from_signed(inv).unwrap()),
-		Err(e) => Err(SignOrCreationError::SignError(e))
-	}
+	invoice.build_unsigned()
 }
 
 /// Filters the `channels` for an invoice, and returns the corresponding `RouteHint`s to include
@@ -381,6 +440,7 @@ where
 /// channel with a higher or equal inbound capacity than `min_inbound_capacity_msat` exists
 /// * If any public channel exists, the returned `RouteHint`s will be empty, and the sender will
 /// need to find the path by looking at the public channels instead
+/// * Each hint's `htlc_maximum_msat` is limited to the channel's current inbound capacity
 fn filter_channels(channels: Vec<ChannelDetails>, min_inbound_capacity_msat: Option<u64>) -> Vec<RouteHint>{
 	let mut filtered_channels: HashMap<PublicKey, &ChannelDetails> = HashMap::new();
 	let min_inbound_capacity = min_inbound_capacity_msat.unwrap_or(0);
@@ -425,7 +485,10 @@ fn filter_channels(channels: Vec<ChannelDetails>, min_inbound_capacity_msat: Opt
 			},
 			cltv_expiry_delta: forwarding_info.cltv_expiry_delta,
 			htlc_minimum_msat: channel.inbound_htlc_minimum_msat,
-			htlc_maximum_msat: channel.inbound_htlc_maximum_msat,}])
+			// The counterparty can't send us more than our inbound capacity, so let the sender know
+			// up front rather than having it learn so from a failed attempt.
+			htlc_maximum_msat: Some(channel.inbound_htlc_maximum_msat.map_or(channel.inbound_capacity_msat,
+				|htlc_max| cmp::min(htlc_max, channel.inbound_capacity_msat))),}])
 	};
 	// If all channels are private, return the route hint for the highest inbound capacity channel
 	// per counterparty node. If channels with an higher inbound capacity than the
@@ -468,6 +531,19 @@ where L::Target: Logger {
 		};
 		find_route(payer, params, &self.network_graph, first_hops, &*self.logger, scorer, &random_seed_bytes)
 	}
+
+	fn find_routes_batch<S: Score>(
+		&self, payer: &PublicKey, route_params: &[(RouteParameters, PaymentHash)],
+		first_hops: Option<&[&ChannelDetails]>, scorer: &S
+	) -> Vec<Result<Route, LightningError>> {
+		let random_seed_bytes = {
+			let mut locked_random_seed_bytes = self.random_seed_bytes.lock().unwrap();
+			*locked_random_seed_bytes = sha256::Hash::hash(&*locked_random_seed_bytes).into_inner();
+			*locked_random_seed_bytes
+		};
+		let params = route_params.iter().map(|(params, _)| params).collect::<Vec<_>>();
+		find_routes_batch(payer, &params, &self.network_graph, first_hops, &*self.logger, scorer, &random_seed_bytes)
+	}
 }
 
 impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> Payer for ChannelManager<Signer, M, T, K, F, L>
@@ -508,17 +584,22 @@ where
 	fn abandon_payment(&self, payment_id: PaymentId) {
 		self.abandon_payment(payment_id)
 	}
+
+	fn cltv_policy(&self) -> CltvPolicy {
+		self.get_current_default_configuration().cltv_policy
+	}
 }
 
 #[cfg(test)]
 mod test {
+	use core::cmp;
 	use core::time::Duration;
-	use {Currency, Description, InvoiceDescription};
+	use {CreationError, Currency, Description, InvoiceDescription, SignOrCreationError};
 	use bitcoin_hashes::Hash;
 	use bitcoin_hashes::sha256::Hash as Sha256;
 	use lightning::chain::keysinterface::PhantomKeysManager;
 	use lightning::ln::{PaymentPreimage, PaymentHash};
-	use lightning::ln::channelmanager::{PhantomRouteHints, MIN_FINAL_CLTV_EXPIRY};
+	use lightning::ln::channelmanager::{InboundPaymentLimits, PhantomRouteHints, MIN_FINAL_CLTV_EXPIRY};
 	use lightning::ln::functional_test_utils::*;
 	use lightning::ln::features::InitFeatures;
 	use lightning::ln::msgs::ChannelMessageHandler;
@@ -528,7 +609,9 @@ mod test {
 	use lightning::util::test_utils;
 	use lightning::util::config::UserConfig;
 	use lightning::chain::keysinterface::KeysInterface;
-	use utils::create_invoice_from_channelmanager_and_duration_since_epoch;
+	use utils::{create_invoice_from_channelmanager_and_duration_since_epoch, create_invoice_from_channelmanager_with_limits_and_duration_since_epoch, create_unsigned_invoice_from_channelmanager_and_duration_since_epoch};
+	use UnsignedInvoice;
+	use lightning::chain::keysinterface::Recipient;
 	use std::collections::HashSet;
 
 	#[test]
@@ -555,7 +638,8 @@ mod test {
 		assert_eq!(invoice.route_hints()[0].0[0].short_channel_id, chan.inbound_scid_alias.unwrap());
 
 		assert_eq!(invoice.route_hints()[0].0[0].htlc_minimum_msat, chan.inbound_htlc_minimum_msat);
-		assert_eq!(invoice.route_hints()[0].0[0].htlc_maximum_msat, chan.inbound_htlc_maximum_msat);
+		assert_eq!(invoice.route_hints()[0].0[0].htlc_maximum_msat,
+			Some(cmp::min(chan.inbound_htlc_maximum_msat.unwrap(), chan.inbound_capacity_msat)));
 
 		let payment_params = PaymentParameters::from_node_id(invoice.recover_payee_pub_key())
 			.with_features(invoice.features().unwrap().clone())
@@ -597,6 +681,52 @@ mod test {
 		assert_eq!(events.len(), 2);
 	}
 
+	#[test]
+	fn test_unsigned_from_channelmanager() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		create_unannounced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 10001, InitFeatures::known(), InitFeatures::known());
+
+		let unsigned_invoice = create_unsigned_invoice_from_channelmanager_and_duration_since_epoch(
+			&nodes[1].node, Currency::BitcoinTestnet, Some(10_000), "test".to_string(),
+			Duration::from_secs(1234567), 3600).unwrap();
+
+		// Sign the exported invoice as a remote signer would, then assemble it.
+		let exported: UnsignedInvoice = unsigned_invoice.to_string().parse().unwrap();
+		let signature = nodes[1].keys_manager.sign_invoice(
+			&exported.hrp_bytes(), &exported.data_without_signature(), Recipient::Node).unwrap();
+		let invoice = unsigned_invoice.assemble(signature).unwrap();
+		assert_eq!(invoice.recover_payee_pub_key(), nodes[1].node.get_our_node_id());
+		assert_eq!(invoice.amount_milli_satoshis(), Some(10_000));
+		assert_eq!(invoice.route_hints().len(), 1);
+	}
+
+	#[test]
+	fn test_from_channelmanager_with_limits() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		create_unannounced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 10001, InitFeatures::known(), InitFeatures::known());
+
+		let limits = InboundPaymentLimits { max_total_msat: Some(20_000), max_parts: Some(2) };
+		let invoice = create_invoice_from_channelmanager_with_limits_and_duration_since_epoch(
+			&nodes[1].node, nodes[1].keys_manager, Currency::BitcoinTestnet, Some(10_000), "test".to_string(),
+			Duration::from_secs(1234567), 3600, limits).unwrap();
+		assert_eq!(invoice.amount_milli_satoshis(), Some(10_000));
+
+		// An invoice for more than the limits allow can't be created.
+		match create_invoice_from_channelmanager_with_limits_and_duration_since_epoch(
+			&nodes[1].node, nodes[1].keys_manager, Currency::BitcoinTestnet, Some(30_000), "test".to_string(),
+			Duration::from_secs(1234567), 3600, limits)
+		{
+			Err(SignOrCreationError::CreationError(CreationError::InvalidAmount)) => {},
+			_ => panic!(),
+		}
+	}
+
 	#[test]
 	fn test_create_invoice_with_description_hash() {
 		let chanmon_cfgs = create_chanmon_cfgs(2);
@@ -759,6 +889,26 @@ mod test {
 		match_invoice_routes(None, &nodes[0], scid_aliases_no_specified_amount);
 	}
 
+	#[test]
+	fn test_hints_htlc_maximum_limited_to_inbound_capacity() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		// With most of the channel's balance pushed to nodes[0], the counterparty can no longer send
+		// as much as the channel's HTLC maximum would otherwise allow.
+		create_unannounced_chan_between_nodes_with_value(&nodes, 1, 0, 100_000, 95_000_000, InitFeatures::known(), InitFeatures::known());
+
+		let chan = &nodes[0].node.list_usable_channels()[0];
+		assert!(chan.inbound_capacity_msat < chan.inbound_htlc_maximum_msat.unwrap());
+
+		let invoice = create_invoice_from_channelmanager_and_duration_since_epoch(
+			&nodes[0].node, nodes[0].keys_manager, Currency::BitcoinTestnet, None, "test".to_string(),
+			Duration::from_secs(1234567), 3600).unwrap();
+		assert_eq!(invoice.route_hints().len(), 1);
+		assert_eq!(invoice.route_hints()[0].0[0].htlc_maximum_msat, Some(chan.inbound_capacity_msat));
+	}
+
 	fn match_invoice_routes<'a, 'b: 'a, 'c: 'b>(
 		invoice_amt: Option<u64>,
 		invoice_node: &Node<'a, 'b, 'c>,
@@ -929,11 +1079,41 @@ mod test {
 
 		let chan_0_1 = &nodes[1].node.list_usable_channels()[0];
 		assert_eq!(invoice.route_hints()[0].0[0].htlc_minimum_msat, chan_0_1.inbound_htlc_minimum_msat);
-		assert_eq!(invoice.route_hints()[0].0[0].htlc_maximum_msat, chan_0_1.inbound_htlc_maximum_msat);
+		assert_eq!(invoice.route_hints()[0].0[0].htlc_maximum_msat,
+			Some(cmp::min(chan_0_1.inbound_htlc_maximum_msat.unwrap(), chan_0_1.inbound_capacity_msat)));
 
 		let chan_0_2 = &nodes[2].node.list_usable_channels()[0];
 		assert_eq!(invoice.route_hints()[1].0[0].htlc_minimum_msat, chan_0_2.inbound_htlc_minimum_msat);
-		assert_eq!(invoice.route_hints()[1].0[0].htlc_maximum_msat, chan_0_2.inbound_htlc_maximum_msat);
+		assert_eq!(invoice.route_hints()[1].0[0].htlc_maximum_msat,
+			Some(cmp::min(chan_0_2.inbound_htlc_maximum_msat.unwrap(), chan_0_2.inbound_capacity_msat)));
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn test_multi_node_invoice_uses_cltv_policies() {
+		let chanmon_cfgs = create_chanmon_cfgs(3);
+		let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+		let mut config_1 = test_default_channel_config();
+		config_1.cltv_policy.min_cltv_expiry_delta = 50;
+		config_1.cltv_policy.min_final_cltv_expiry = 40;
+		let mut config_2 = test_default_channel_config();
+		config_2.cltv_policy.min_cltv_expiry_delta = 60;
+		config_2.cltv_policy.min_final_cltv_expiry = 30;
+		let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(config_1), Some(config_2)]);
+		let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+		let route_hints = vec![
+			nodes[1].node.get_phantom_route_hints(),
+			nodes[2].node.get_phantom_route_hints(),
+		];
+		let invoice = ::utils::create_phantom_invoice::<EnforcingSigner, &test_utils::TestKeysInterface>(Some(20_000), None, "test".to_string(), 3600, route_hints, &nodes[1].keys_manager, Currency::BitcoinTestnet).unwrap();
+
+		// The invoice's final CLTV expiry must satisfy every participating node, while each phantom
+		// hop uses the delta its real node requires.
+		assert_eq!(invoice.min_final_cltv_expiry(), 40);
+		assert_eq!(invoice.route_hints().len(), 2);
+		assert_eq!(invoice.route_hints()[0].0.last().unwrap().cltv_expiry_delta, 50);
+		assert_eq!(invoice.route_hints()[1].0.last().unwrap().cltv_expiry_delta, 60);
 	}
 
 	#[test]
@@ -21,8 +21,8 @@ use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::hashes::hex::FromHex;
 use lightning::chain::channelmonitor::ChannelMonitor;
 use lightning::chain::keysinterface::{Sign, KeysInterface};
-use lightning::util::ser::{ReadableArgs, Writeable};
-use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable};
+use lightning::util::persist::{FencingTokenStore, KVStorePersister};
 use std::fs;
 use std::io::Cursor;
 use std::ops::Deref;
@@ -130,6 +130,26 @@ impl KVStorePersister for FilesystemPersister {
 	}
 }
 
+impl FencingTokenStore for FilesystemPersister {
+	fn read_fencing_token(&self) -> std::io::Result<Option<u64>> {
+		let mut path = PathBuf::from(&self.path_to_channel_data);
+		path.push("fencing_token");
+		let contents = match fs::read(&path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(e),
+		};
+		u64::read(&mut Cursor::new(&contents)).map(Some).map_err(|_| std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"Invalid fencing token",
+		))
+	}
+
+	fn persist_fencing_token(&self, token: u64) -> std::io::Result<()> {
+		self.persist("fencing_token", &token)
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	extern crate lightning;
@@ -145,6 +165,7 @@ mod tests {
 	use lightning::ln::features::InitFeatures;
 	use lightning::ln::functional_test_utils::*;
 	use lightning::util::events::{ClosureReason, MessageSendEventsProvider};
+	use lightning::util::persist::FencedPersister;
 	use lightning::util::test_utils;
 	use std::fs;
 	use bitcoin::hashes::Hash;
@@ -278,6 +299,44 @@ mod tests {
 		added_monitors.clear();
 	}
 
+	// Test that once a second instance has started on the same directory, writes from the first
+	// are refused.
+	#[test]
+	fn test_fenced_persister_refuses_stale_writes() {
+		let stale = FencedPersister::new(FilesystemPersister::new("test_fenced_persister_refuses_stale_writes".to_string())).unwrap();
+		assert_eq!(stale.fencing_token(), 1);
+		stale.persist("manager", &42u64).unwrap();
+
+		let current = FencedPersister::new(FilesystemPersister::new("test_fenced_persister_refuses_stale_writes".to_string())).unwrap();
+		assert_eq!(current.fencing_token(), 2);
+		assert!(stale.persist("manager", &43u64).is_err());
+		current.persist("manager", &44u64).unwrap();
+
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		nodes[1].node.force_close_broadcasting_latest_txn(&chan.2, &nodes[0].node.get_our_node_id()).unwrap();
+		check_closed_event!(nodes[1], 1, ClosureReason::HolderForceClosed);
+		let mut added_monitors = nodes[1].chain_monitor.added_monitors.lock().unwrap();
+		let update_map = nodes[1].chain_monitor.latest_monitor_update_id.lock().unwrap();
+		let update_id = update_map.get(&added_monitors[0].0.to_channel_id()).unwrap();
+
+		let test_txo = OutPoint {
+			txid: Txid::from_hex("8984484a580b825b9972d7adb15050b3ab624ccd731946b3eeddb92f4e7ef6be").unwrap(),
+			index: 0
+		};
+		match stale.persist_new_channel(test_txo, &added_monitors[0].1, update_id.2) {
+			Err(ChannelMonitorUpdateErr::TemporaryFailure) => {},
+			_ => panic!("unexpected result from persisting new channel with a stale instance")
+		}
+		current.persist_new_channel(test_txo, &added_monitors[0].1, update_id.2).unwrap();
+
+		nodes[1].node.get_and_clear_pending_msg_events();
+		added_monitors.clear();
+	}
+
 	// Test that if a persister's directory name is invalid, monitor persistence
 	// will fail.
 	#[cfg(target_os = "windows")]
@@ -17,15 +17,55 @@ use core::{cmp, ops::Deref};
 
 use bitcoin::blockdata::transaction::Transaction;
 
+use prelude::*;
+
+/// Why a transaction was rejected by the Bitcoin network, see [`BroadcastResult::Rejected`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BroadcastRejectReason {
+	/// The transaction's feerate was too low to be accepted, e.g. because it didn't meet the
+	/// minimum relay fee or didn't pay enough to replace a conflicting transaction. We'll
+	/// immediately try again at a higher feerate where possible.
+	InsufficientFee,
+	/// The transaction was rejected for another reason, described in the contained string.
+	Other(String),
+}
+
+/// The result of broadcasting a transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BroadcastResult {
+	/// The transaction was accepted into the mempool.
+	Accepted,
+	/// The transaction is already in the mempool or chain.
+	AlreadyKnown,
+	/// The transaction was rejected.
+	Rejected(BroadcastRejectReason),
+}
+
 /// An interface to send a transaction to the Bitcoin network.
 pub trait BroadcasterInterface {
 	/// Sends a transaction out to (hopefully) be mined.
 	fn broadcast_transaction(&self, tx: &Transaction);
+
+	/// Sends a transaction out to (hopefully) be mined, returning the result of the broadcast if
+	/// it is available immediately.
+	///
+	/// This is used when broadcasting on-chain claims, allowing us to react to a claim being
+	/// rejected, e.g. by immediately bumping its feerate. Broadcasters which learn of the result
+	/// asynchronously should return `None` and later provide it via
+	/// [`ChainMonitor::provide_broadcast_result`].
+	///
+	/// The default implementation calls [`Self::broadcast_transaction`] and returns `None`.
+	///
+	/// [`ChainMonitor::provide_broadcast_result`]: crate::chain::chainmonitor::ChainMonitor::provide_broadcast_result
+	fn broadcast_transaction_with_result(&self, tx: &Transaction) -> Option<BroadcastResult> {
+		self.broadcast_transaction(tx);
+		None
+	}
 }
 
 /// An enum that represents the speed at which we want a transaction to confirm used for feerate
 /// estimation.
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConfirmationTarget {
 	/// We are happy with this transaction confirming slowly when feerate drops some.
 	Background,
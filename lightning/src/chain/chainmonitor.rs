@@ -24,14 +24,19 @@
 //! servicing [`ChannelMonitor`] updates from the client.
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
 use bitcoin::hash_types::Txid;
 
 use chain;
 use chain::{ChannelMonitorUpdateErr, Filter, WatchedOutput};
-use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
-use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, Balance, MonitorEvent, TransactionOutputs, LATENCY_GRACE_PERIOD_BLOCKS};
+use chain::chaininterface::{BroadcasterInterface, BroadcastResult, FeeEstimator};
+use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, Balance, MonitorEvent, TransactionOutputs, ANTI_REORG_DELAY, LATENCY_GRACE_PERIOD_BLOCKS};
 use chain::transaction::{OutPoint, TransactionData};
 use chain::keysinterface::Sign;
+#[cfg(any(test, feature = "unsafe_revoked_tx_signing"))]
+use chain::channelmonitor::EmergencyCloseBundle;
+#[cfg(any(test, feature = "unsafe_revoked_tx_signing"))]
+use chain::keysinterface::SpendableOutputDescriptor;
 use util::atomic_counter::AtomicCounter;
 use util::logger::Logger;
 use util::errors::APIError;
@@ -208,6 +213,52 @@ impl<ChannelSigner: Sign> Deref for LockedChannelMonitor<'_, ChannelSigner> {
 	}
 }
 
+/// Where the funds in a [`TimelockedBalance`] come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimelockedBalanceSource {
+	/// Our balance in a channel which is being cooperatively closed, spendable once the closing
+	/// transaction confirms.
+	CooperativeClose,
+	/// Our balance in a channel which has been closed but for which no commitment transaction has
+	/// confirmed yet. The height given assumes our own commitment transaction confirms in the next
+	/// block.
+	UnconfirmedClose,
+	/// An output from a confirmed closing or commitment transaction which is awaiting
+	/// confirmations or the expiry of a relative timelock.
+	ConfirmedCloseOutput,
+	/// An outbound HTLC which we can claim back once it times out.
+	HTLCTimeoutClaim,
+	/// An inbound HTLC for which we have the preimage and which we can claim on-chain.
+	HTLCPreimageClaim,
+	/// An output from a revoked counterparty commitment transaction which we can claim.
+	RevokedOutputClaim,
+}
+
+/// An amount which becomes spendable by us on-chain at a given height, as returned by
+/// [`ChainMonitor::get_timelocked_balances`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimelockedBalance {
+	/// The height at which the funds become spendable.
+	pub spendable_height: u32,
+	/// The amount, in satoshis, which becomes spendable, excluding the on-chain fees which will be
+	/// required to claim it.
+	pub amount_satoshis: u64,
+	/// Where the funds come from.
+	pub source: TimelockedBalanceSource,
+	/// The funding outpoint of the channel the funds come from.
+	pub funding_txo: OutPoint,
+}
+
+/// The scripts and outpoints the application has asked a [`ChainMonitor`] to watch on its behalf,
+/// see [`ChainMonitor::watch_script`].
+struct AppWatchState {
+	scripts: HashSet<Script>,
+	outpoints: HashSet<OutPoint>,
+	/// The transactions we've generated events for, and the height they were confirmed at.
+	confirmed_txids: HashMap<Txid, u32>,
+	pending_events: Vec<events::Event>,
+}
+
 /// An implementation of [`chain::Watch`] for monitoring channels.
 ///
 /// Connected and disconnected blocks must be provided to `ChainMonitor` as documented by
@@ -239,6 +290,7 @@ pub struct ChainMonitor<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: De
 	pending_monitor_events: Mutex<Vec<(OutPoint, Vec<MonitorEvent>, Option<PublicKey>)>>,
 	/// The best block height seen, used as a proxy for the passage of time.
 	highest_chain_height: AtomicUsize,
+	app_watch_state: Mutex<AppWatchState>,
 }
 
 impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> ChainMonitor<ChannelSigner, C, T, F, L, P>
@@ -344,6 +396,152 @@ where C::Target: chain::Filter,
 			persister,
 			pending_monitor_events: Mutex::new(Vec::new()),
 			highest_chain_height: AtomicUsize::new(0),
+			app_watch_state: Mutex::new(AppWatchState {
+				scripts: HashSet::new(),
+				outpoints: HashSet::new(),
+				confirmed_txids: HashMap::new(),
+				pending_events: Vec::new(),
+			}),
+		}
+	}
+
+	/// Watches for outputs paying to `script_pubkey` on behalf of the application, registering it
+	/// with the chain source, if any.
+	///
+	/// Each such output results in an [`Event::WatchedOutputConfirmed`] once confirmed, after
+	/// which its spends are watched for as if registered via [`Self::watch_outpoint`]. This allows
+	/// the application to track outputs of interest (e.g. of on-chain swaps) using the same chain
+	/// data provided for channels.
+	///
+	/// The set of watched scripts and outpoints is not persisted, and thus must be registered again
+	/// after restarting, prior to syncing the chain. Outputs confirmed while the scripts were not
+	/// registered will not be reported.
+	///
+	/// [`Event::WatchedOutputConfirmed`]: events::Event::WatchedOutputConfirmed
+	pub fn watch_script(&self, script_pubkey: Script) {
+		if let Some(ref chain_source) = self.chain_source {
+			chain_source.register_script(&script_pubkey);
+		}
+		self.app_watch_state.lock().unwrap().scripts.insert(script_pubkey);
+	}
+
+	/// Stops watching for outputs paying to `script_pubkey`. Outputs which were already confirmed
+	/// continue to be watched for spends until passed to [`Self::unwatch_outpoint`].
+	pub fn unwatch_script(&self, script_pubkey: &Script) {
+		self.app_watch_state.lock().unwrap().scripts.remove(script_pubkey);
+	}
+
+	/// Watches for spends of `outpoint`, which pays to `script_pubkey`, on behalf of the
+	/// application, registering it with the chain source, if any.
+	///
+	/// Each confirmed spend results in an [`Event::WatchedOutputSpent`]. As with
+	/// [`Self::watch_script`], watched outpoints are not persisted.
+	///
+	/// [`Event::WatchedOutputSpent`]: events::Event::WatchedOutputSpent
+	pub fn watch_outpoint(&self, outpoint: OutPoint, script_pubkey: Script) {
+		if let Some(ref chain_source) = self.chain_source {
+			chain_source.register_output(WatchedOutput { block_hash: None, outpoint, script_pubkey });
+		}
+		self.app_watch_state.lock().unwrap().outpoints.insert(outpoint);
+	}
+
+	/// Stops watching for spends of `outpoint`.
+	pub fn unwatch_outpoint(&self, outpoint: &OutPoint) {
+		self.app_watch_state.lock().unwrap().outpoints.remove(outpoint);
+	}
+
+	/// Checks the given transactions against the scripts and outpoints watched on behalf of the
+	/// application, generating events for any matches.
+	fn process_app_watched_txdata(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let block_hash = header.block_hash();
+		let mut new_outputs = Vec::new();
+		{
+			let mut state = self.app_watch_state.lock().unwrap();
+			if state.scripts.is_empty() && state.outpoints.is_empty() { return; }
+			for &(_, tx) in txdata.iter() {
+				let txid = tx.txid();
+				if state.confirmed_txids.contains_key(&txid) { continue; }
+				let mut relevant = false;
+				for input in tx.input.iter() {
+					// Outputs beyond the range of our `OutPoint`s can never be watched.
+					if input.previous_output.vout >= 1 << 16 { continue; }
+					let outpoint = OutPoint { txid: input.previous_output.txid, index: input.previous_output.vout as u16 };
+					if state.outpoints.contains(&outpoint) {
+						relevant = true;
+						state.pending_events.push(events::Event::WatchedOutputSpent {
+							outpoint, spending_tx: (*tx).clone(), block_hash, height,
+						});
+					}
+				}
+				for (idx, output) in tx.output.iter().enumerate().take(1 << 16) {
+					if state.scripts.contains(&output.script_pubkey) {
+						relevant = true;
+						let outpoint = OutPoint { txid, index: idx as u16 };
+						// Insert the output immediately so that in-block spends are detected.
+						state.outpoints.insert(outpoint);
+						new_outputs.push(WatchedOutput { block_hash: Some(block_hash), outpoint, script_pubkey: output.script_pubkey.clone() });
+						state.pending_events.push(events::Event::WatchedOutputConfirmed {
+							outpoint, output: output.clone(), block_hash, height,
+						});
+					}
+				}
+				if relevant {
+					log_debug!(self.logger, "Transaction {} matched a watched script or outpoint at height {}", txid, height);
+					state.confirmed_txids.insert(txid, height);
+				}
+			}
+		}
+		if let Some(ref chain_source) = self.chain_source {
+			for output in new_outputs.drain(..) {
+				chain_source.register_output(output);
+			}
+		}
+	}
+
+	/// Generates events for any transactions we've reported on behalf of the application which
+	/// match `is_unconfirmed`.
+	fn app_watched_txn_unconfirmed<FN: Fn(&Txid, u32) -> bool>(&self, is_unconfirmed: FN) {
+		let mut state = self.app_watch_state.lock().unwrap();
+		let mut unconfirmed_txids = Vec::new();
+		state.confirmed_txids.retain(|txid, height| {
+			if is_unconfirmed(txid, *height) {
+				unconfirmed_txids.push(*txid);
+				false
+			} else { true }
+		});
+		for txid in unconfirmed_txids.drain(..) {
+			state.pending_events.push(events::Event::WatchedTransactionUnconfirmed { txid });
+		}
+	}
+
+	/// Provides the result of broadcasting the transaction with the given `txid`, for broadcasters
+	/// which learn of it asynchronously, to whichever [`ChannelMonitor`] broadcast it. See
+	/// [`BroadcasterInterface::broadcast_transaction_with_result`] for details.
+	pub fn provide_broadcast_result(&self, txid: &Txid, result: BroadcastResult) {
+		let monitor_states = self.monitors.read().unwrap();
+		for monitor_state in monitor_states.values() {
+			monitor_state.monitor.provide_broadcast_result(
+				txid, result.clone(), &*self.broadcaster, &*self.fee_estimator, &*self.logger);
+		}
+	}
+
+	/// Gets the txids of the unconfirmed on-chain claim transactions broadcast by the contained
+	/// [`ChannelMonitor`]s. See [`ChannelMonitor::get_unconfirmed_claim_txids`].
+	pub fn get_unconfirmed_claim_txids(&self) -> Vec<Txid> {
+		let mut txids = Vec::new();
+		for monitor_state in self.monitors.read().unwrap().values() {
+			txids.append(&mut monitor_state.monitor.get_unconfirmed_claim_txids());
+		}
+		txids
+	}
+
+	/// Informs the contained [`ChannelMonitor`]s which of the transactions returned by
+	/// [`Self::get_unconfirmed_claim_txids`] are currently in the mempool, rebroadcasting those
+	/// which have been evicted. See [`ChannelMonitor::update_mempool_status`].
+	pub fn update_mempool_status(&self, mempool_txids: &[Txid]) {
+		for monitor_state in self.monitors.read().unwrap().values() {
+			monitor_state.monitor.update_mempool_status(
+				mempool_txids, &*self.broadcaster, &*self.fee_estimator, &*self.logger);
 		}
 	}
 
@@ -372,6 +570,59 @@ where C::Target: chain::Filter,
 		ret
 	}
 
+	/// Gets a timeline of when the funds from closed or closing channels become spendable, sorted
+	/// by the height at which they do.
+	///
+	/// `open_channels` should be the result of [`ChannelManager::list_channels`]. Balances from
+	/// channels which are open and not being cooperatively closed are not included, as they aren't
+	/// spendable on-chain until the channel is closed. Neither are HTLCs which we can only claim
+	/// if we learn their preimage, see [`Balance::MaybePreimageClaimableHTLC`].
+	///
+	/// Where the height at which funds become spendable depends on transactions which have yet to
+	/// confirm, it is estimated based on our current best block, assuming the transactions
+	/// confirm in the next block. For channels which have been closed but whose commitment or
+	/// closing transaction has not yet confirmed, we assume our own commitment transaction
+	/// confirms, giving the latest height at which the funds may become spendable.
+	///
+	/// [`ChannelManager::list_channels`]: crate::ln::channelmanager::ChannelManager::list_channels
+	pub fn get_timelocked_balances(&self, open_channels: &[&ChannelDetails]) -> Vec<TimelockedBalance> {
+		let mut ret = Vec::new();
+		let monitor_states = self.monitors.read().unwrap();
+		for (funding_txo, monitor_state) in monitor_states.iter() {
+			let open_channel = open_channels.iter().find(|chan| chan.funding_txo.as_ref() == Some(funding_txo));
+			if let Some(chan) = open_channel {
+				if !chan.is_shutting_down { continue; }
+			}
+			let monitor = &monitor_state.monitor;
+			let best_height = monitor.current_best_block().height();
+			let confirmed_height = best_height + ANTI_REORG_DELAY;
+			for balance in monitor.get_claimable_balances() {
+				let (spendable_height, amount_satoshis, source) = match balance {
+					Balance::ClaimableOnChannelClose { claimable_amount_satoshis } => {
+						if open_channel.is_some() {
+							(confirmed_height, claimable_amount_satoshis, TimelockedBalanceSource::CooperativeClose)
+						} else {
+							(confirmed_height + monitor.get_holder_to_self_delay() as u32, claimable_amount_satoshis,
+								TimelockedBalanceSource::UnconfirmedClose)
+						}
+					},
+					Balance::ClaimableAwaitingConfirmations { claimable_amount_satoshis, confirmation_height } =>
+						(confirmation_height, claimable_amount_satoshis, TimelockedBalanceSource::ConfirmedCloseOutput),
+					Balance::ContentiousClaimable { claimable_amount_satoshis, .. } =>
+						(confirmed_height, claimable_amount_satoshis, TimelockedBalanceSource::HTLCPreimageClaim),
+					Balance::MaybeTimeoutClaimableHTLC { claimable_amount_satoshis, claimable_height } =>
+						(claimable_height, claimable_amount_satoshis, TimelockedBalanceSource::HTLCTimeoutClaim),
+					Balance::MaybePreimageClaimableHTLC { .. } => continue,
+					Balance::CounterpartyRevokedOutputClaimable { claimable_amount_satoshis } =>
+						(confirmed_height, claimable_amount_satoshis, TimelockedBalanceSource::RevokedOutputClaim),
+				};
+				ret.push(TimelockedBalance { spendable_height, amount_satoshis, source, funding_txo: *funding_txo });
+			}
+		}
+		ret.sort_by_key(|balance| balance.spendable_height);
+		ret
+	}
+
 	/// Gets the [`LockedChannelMonitor`] for a given funding outpoint, returning an `Err` if no
 	/// such [`ChannelMonitor`] is currently being monitored for.
 	///
@@ -394,11 +645,53 @@ where C::Target: chain::Filter,
 		self.monitors.read().unwrap().keys().map(|outpoint| *outpoint).collect()
 	}
 
+	/// Gets an [`EmergencyCloseBundle`] for each channel whose funding output has not yet been
+	/// spent, keyed by funding outpoint. See [`ChannelMonitor::get_emergency_close_bundle`] for
+	/// details on `sign_sweep`.
+	///
+	/// As each bundle is only safe to broadcast until its channel's state advances, you likely
+	/// want to call this after each [`Persist`] call completes and replace any previously-exported
+	/// bundles.
+	#[cfg(any(test, feature = "unsafe_revoked_tx_signing"))]
+	pub fn get_emergency_close_bundles<S>(&self, mut sign_sweep: S) -> Vec<(OutPoint, EmergencyCloseBundle)>
+	where S: FnMut(&SpendableOutputDescriptor) -> Result<bitcoin::Transaction, ()> {
+		let monitors = self.monitors.read().unwrap();
+		monitors.iter().filter_map(|(funding_txo, monitor_state)| {
+			monitor_state.monitor.get_emergency_close_bundle(&mut sign_sweep, &self.logger)
+				.map(|bundle| (*funding_txo, bundle))
+		}).collect()
+	}
+
 	#[cfg(test)]
 	pub fn remove_monitor(&self, funding_txo: &OutPoint) -> ChannelMonitor<ChannelSigner> {
 		self.monitors.write().unwrap().remove(funding_txo).unwrap().monitor
 	}
 
+	/// Replaces the [`ChannelMonitor`] for the given `funding_txo`, persisting the new monitor as
+	/// if it were new. Used by a [`WatchingService`] to resync a diverged copy.
+	///
+	/// [`WatchingService`]: crate::chain::replication::WatchingService
+	pub(crate) fn replace_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor<ChannelSigner>) -> Result<(), ChannelMonitorUpdateErr> {
+		let mut monitors = self.monitors.write().unwrap();
+		let update_id = MonitorUpdateId::from_new_monitor(&monitor);
+		let persist_res = self.persister.persist_new_channel(funding_txo, &monitor, update_id);
+		if persist_res == Err(ChannelMonitorUpdateErr::PermanentFailure) {
+			log_error!(self.logger, "Failed to persist replacement ChannelMonitor for channel {}", log_funding_info!(monitor));
+			return persist_res;
+		}
+		log_trace!(self.logger, "Replaced ChannelMonitor for channel {}", log_funding_info!(monitor));
+		if let Some(ref chain_source) = self.chain_source {
+			monitor.load_outputs_to_watch(chain_source);
+		}
+		monitors.insert(funding_txo, MonitorHolder {
+			monitor,
+			pending_monitor_updates: Mutex::new(if persist_res.is_err() { vec![update_id] } else { Vec::new() }),
+			channel_perm_failed: AtomicBool::new(false),
+			last_chain_persist_height: AtomicUsize::new(self.highest_chain_height.load(Ordering::Acquire)),
+		});
+		persist_res
+	}
+
 	/// Indicates the persistence of a [`ChannelMonitor`] has completed after
 	/// [`ChannelMonitorUpdateErr::TemporaryFailure`] was returned from an update operation.
 	///
@@ -491,6 +784,7 @@ where
 {
 	fn filtered_block_connected(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
 		log_debug!(self.logger, "New best block {} at height {} provided via block_connected", header.block_hash(), height);
+		self.process_app_watched_txdata(header, txdata, height);
 		self.process_chain_data(header, Some(height), &txdata, |monitor, txdata| {
 			monitor.block_connected(
 				header, txdata, height, &*self.broadcaster, &*self.fee_estimator, &*self.logger)
@@ -500,6 +794,7 @@ where
 	fn block_disconnected(&self, header: &BlockHeader, height: u32) {
 		let monitor_states = self.monitors.read().unwrap();
 		log_debug!(self.logger, "Latest block {} at height {} removed via block_disconnected", header.block_hash(), height);
+		self.app_watched_txn_unconfirmed(|_, conf_height| conf_height >= height);
 		for monitor_state in monitor_states.values() {
 			monitor_state.monitor.block_disconnected(
 				header, height, &*self.broadcaster, &*self.fee_estimator, &*self.logger);
@@ -518,6 +813,7 @@ where
 {
 	fn transactions_confirmed(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
 		log_debug!(self.logger, "{} provided transactions confirmed at height {} in block {}", txdata.len(), height, header.block_hash());
+		self.process_app_watched_txdata(header, txdata, height);
 		self.process_chain_data(header, None, txdata, |monitor, txdata| {
 			monitor.transactions_confirmed(
 				header, txdata, height, &*self.broadcaster, &*self.fee_estimator, &*self.logger)
@@ -526,6 +822,7 @@ where
 
 	fn transaction_unconfirmed(&self, txid: &Txid) {
 		log_debug!(self.logger, "Transaction {} reorganized out of chain", txid);
+		self.app_watched_txn_unconfirmed(|unconf_txid, _| unconf_txid == txid);
 		let monitor_states = self.monitors.read().unwrap();
 		for monitor_state in monitor_states.values() {
 			monitor_state.monitor.transaction_unconfirmed(txid, &*self.broadcaster, &*self.fee_estimator, &*self.logger);
@@ -534,6 +831,8 @@ where
 
 	fn best_block_updated(&self, header: &BlockHeader, height: u32) {
 		log_debug!(self.logger, "New best block {} at height {} provided via best_block_updated", header.block_hash(), height);
+		// A lower best block height implies any transactions confirmed above it were reorganized out.
+		self.app_watched_txn_unconfirmed(|_, conf_height| conf_height > height);
 		self.process_chain_data(header, Some(height), &[], |monitor, txdata| {
 			// While in practice there shouldn't be any recursive calls when given empty txdata,
 			// it's still possible if a chain::Filter implementation returns a transaction.
@@ -549,6 +848,7 @@ where
 		for monitor_state in monitor_states.values() {
 			txids.append(&mut monitor_state.monitor.get_relevant_txids());
 		}
+		txids.extend(self.app_watch_state.lock().unwrap().confirmed_txids.keys());
 
 		txids.sort_unstable();
 		txids.dedup();
@@ -699,7 +999,9 @@ impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> even
 	      L::Target: Logger,
 	      P::Target: Persist<ChannelSigner>,
 {
-	/// Processes [`SpendableOutputs`] events produced from each [`ChannelMonitor`] upon maturity.
+	/// Processes [`SpendableOutputs`] events produced from each [`ChannelMonitor`] upon maturity,
+	/// as well as events for scripts and outpoints watched on behalf of the application via
+	/// [`ChainMonitor::watch_script`] and [`ChainMonitor::watch_outpoint`].
 	///
 	/// An [`EventHandler`] may safely call back to the provider, though this shouldn't be needed in
 	/// order to handle these events.
@@ -710,6 +1012,7 @@ impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> even
 		for monitor_state in self.monitors.read().unwrap().values() {
 			pending_events.append(&mut monitor_state.monitor.get_and_clear_pending_events());
 		}
+		pending_events.append(&mut self.app_watch_state.lock().unwrap().pending_events);
 		for event in pending_events.drain(..) {
 			handler.handle_event(&event);
 		}
@@ -718,19 +1021,24 @@ impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> even
 
 #[cfg(test)]
 mod tests {
-	use bitcoin::{BlockHeader, TxMerkleNode};
+	use bitcoin::{BlockHeader, PackedLockTime, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Witness};
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
+	use bitcoin::hash_types::Txid;
 	use bitcoin::hashes::Hash;
 	use ::{check_added_monitors, check_closed_broadcast, check_closed_event};
 	use ::{expect_payment_sent, expect_payment_claimed, expect_payment_sent_without_paths, expect_payment_path_successful, get_event_msg};
 	use ::{get_htlc_update_msgs, get_local_commitment_txn, get_revoke_commit_msgs, get_route_and_payment_hash, unwrap_send_err};
 	use chain::{ChannelMonitorUpdateErr, Confirm, Watch};
 	use chain::channelmonitor::LATENCY_GRACE_PERIOD_BLOCKS;
+	use chain::transaction::OutPoint;
 	use ln::channelmanager::PaymentSendFailure;
 	use ln::features::InitFeatures;
 	use ln::functional_test_utils::*;
 	use ln::msgs::ChannelMessageHandler;
 	use util::errors::APIError;
-	use util::events::{ClosureReason, MessageSendEvent, MessageSendEventsProvider};
+	use util::events::{ClosureReason, Event, MessageSendEvent, MessageSendEventsProvider};
 
 	#[test]
 	fn test_async_ooo_offchain_updates() {
@@ -910,4 +1218,93 @@ mod tests {
 		check_closed_event!(nodes[0], 1, ClosureReason::ProcessingError { err: "Failed to persist ChannelMonitor update during chain sync".to_string() });
 		check_added_monitors!(nodes[0], 1);
 	}
+
+	#[test]
+	fn app_watched_scripts_and_outpoints() {
+		// Test that scripts and outpoints watched on behalf of the application generate events as
+		// matching transactions are confirmed, spent and reorganized out of the chain.
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let chain_monitor = &nodes[0].chain_monitor.chain_monitor;
+
+		let script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_1).into_script();
+		chain_monitor.watch_script(script.clone());
+		assert!(chanmon_cfgs[0].chain_source.watched_scripts.lock().unwrap().contains(&script));
+
+		let spend_input = |outpoint: BitcoinOutPoint| TxIn {
+			previous_output: outpoint, script_sig: Script::new(), sequence: Sequence::MAX, witness: Witness::new(),
+		};
+		let funding_tx = Transaction {
+			version: 2, lock_time: PackedLockTime::ZERO,
+			input: vec![spend_input(BitcoinOutPoint { txid: Txid::all_zeros(), vout: 0 })],
+			output: vec![
+				TxOut { value: 1000, script_pubkey: Script::new() },
+				TxOut { value: 2000, script_pubkey: script.clone() },
+			],
+		};
+		let watched_outpoint = OutPoint { txid: funding_tx.txid(), index: 1 };
+		mine_transaction(&nodes[0], &funding_tx);
+		let funding_height = nodes[0].best_block_info().1;
+		match &chain_monitor.get_and_clear_pending_events()[..] {
+			[Event::WatchedOutputConfirmed { outpoint, output, height, .. }] => {
+				assert_eq!(*outpoint, watched_outpoint);
+				assert_eq!(*output, funding_tx.output[1]);
+				assert_eq!(*height, funding_height);
+			},
+			events => panic!("Unexpected events {:?}", events),
+		}
+		// The confirmed output is now watched for spends.
+		assert!(chanmon_cfgs[0].chain_source.watched_outputs.lock().unwrap().contains(&(watched_outpoint, script.clone())));
+		assert!(chain_monitor.get_relevant_txids().contains(&funding_tx.txid()));
+
+		let spending_tx = Transaction {
+			version: 2, lock_time: PackedLockTime::ZERO,
+			input: vec![spend_input(watched_outpoint.into_bitcoin_outpoint())],
+			output: vec![TxOut { value: 1500, script_pubkey: Script::new() }],
+		};
+		mine_transaction(&nodes[0], &spending_tx);
+		let expect_spent = || match &chain_monitor.get_and_clear_pending_events()[..] {
+			[Event::WatchedOutputSpent { outpoint, spending_tx: tx, .. }] => {
+				assert_eq!(*outpoint, watched_outpoint);
+				assert_eq!(*tx, spending_tx);
+			},
+			events => panic!("Unexpected events {:?}", events),
+		};
+		expect_spent();
+
+		// Reorganizing the spend out of the chain is reported, and the spend is reported again once
+		// it is re-confirmed.
+		disconnect_blocks(&nodes[0], 1);
+		match &chain_monitor.get_and_clear_pending_events()[..] {
+			[Event::WatchedTransactionUnconfirmed { txid }] => assert_eq!(*txid, spending_tx.txid()),
+			events => panic!("Unexpected events {:?}", events),
+		}
+		mine_transaction(&nodes[0], &spending_tx);
+		expect_spent();
+
+		// Once unwatched, neither new outputs nor spends are reported.
+		chain_monitor.unwatch_script(&script);
+		chain_monitor.unwatch_outpoint(&watched_outpoint);
+		let mut second_tx = funding_tx.clone();
+		second_tx.input[0].previous_output.vout = 1;
+		mine_transaction(&nodes[0], &second_tx);
+		assert!(chain_monitor.get_and_clear_pending_events().is_empty());
+
+		// Outpoints may also be watched directly.
+		let direct_outpoint = OutPoint { txid: second_tx.txid(), index: 0 };
+		chain_monitor.watch_outpoint(direct_outpoint, Script::new());
+		let mut second_spend = spending_tx.clone();
+		second_spend.input[0].previous_output = direct_outpoint.into_bitcoin_outpoint();
+		mine_transaction(&nodes[0], &second_spend);
+		match &chain_monitor.get_and_clear_pending_events()[..] {
+			[Event::WatchedOutputSpent { outpoint, .. }] => assert_eq!(*outpoint, direct_outpoint),
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		// Application-watched outputs are not persisted, so aren't registered by a reloaded
+		// ChainMonitor, which the test framework checks for on drop.
+		chanmon_cfgs[0].chain_source.watched_outputs.lock().unwrap().clear();
+	}
 }
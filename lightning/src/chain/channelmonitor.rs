@@ -40,16 +40,18 @@ use ln::chan_utils;
 use ln::chan_utils::{CounterpartyCommitmentSecrets, HTLCOutputInCommitment, HTLCType, ChannelTransactionParameters, HolderCommitmentTransaction};
 use ln::channelmanager::HTLCSource;
 use chain;
-use chain::{BestBlock, WatchedOutput};
-use chain::chaininterface::{BroadcasterInterface, FeeEstimator, LowerBoundedFeeEstimator};
+use chain::{BestBlock, BlockProvider, WatchedOutput};
+use chain::chaininterface::{BroadcasterInterface, BroadcastResult, FeeEstimator, LowerBoundedFeeEstimator};
 use chain::transaction::{OutPoint, TransactionData};
 use chain::keysinterface::{SpendableOutputDescriptor, StaticPaymentOutputDescriptor, DelayedPaymentOutputDescriptor, Sign, KeysInterface};
-use chain::onchaintx::OnchainTxHandler;
+use chain::onchaintx::{MAX_IMMEDIATE_FEE_BUMPS, OnchainTxHandler};
 use chain::package::{CounterpartyOfferedHTLCOutput, CounterpartyReceivedHTLCOutput, HolderFundingOutput, HolderHTLCOutput, PackageSolvingData, PackageTemplate, RevokedOutput, RevokedHTLCOutput};
 use chain::Filter;
 use util::logger::Logger;
 use util::ser::{Readable, ReadableArgs, MaybeReadable, Writer, Writeable, U48, OptionDeserWrapper};
 use util::byte_utils;
+use util::config::ClaimFeeBudget;
+use util::compression::{self, SerializedForm};
 use util::events::Event;
 
 use prelude::*;
@@ -94,6 +96,17 @@ pub struct ChannelMonitorUpdate {
 /// force-close.
 pub const CLOSED_CHANNEL_UPDATE_ID: u64 = core::u64::MAX;
 
+impl ChannelMonitorUpdate {
+	/// Serializes this update compressed, which may substantially reduce the size of updates
+	/// containing many HTLCs.
+	///
+	/// Compressed updates are read transparently by [`ChannelMonitorUpdate`]'s [`Readable`]
+	/// implementation, but cannot be read by versions of LDK prior to 0.0.111.
+	pub fn encode_compressed(&self) -> Vec<u8> {
+		compression::encode_compressed(self)
+	}
+}
+
 impl Writeable for ChannelMonitorUpdate {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
 		write_ver_prefix!(w, SERIALIZATION_VERSION, MIN_SERIALIZATION_VERSION);
@@ -108,7 +121,10 @@ impl Writeable for ChannelMonitorUpdate {
 }
 impl Readable for ChannelMonitorUpdate {
 	fn read<R: io::Read>(r: &mut R) -> Result<Self, DecodeError> {
-		let _ver = read_ver_prefix!(r, SERIALIZATION_VERSION);
+		let _ver = match compression::read_ver_prefix_or_decompress(r, SERIALIZATION_VERSION)? {
+			SerializedForm::Uncompressed(ver) => ver,
+			SerializedForm::Compressed(data) => return <Self as Readable>::read(&mut io::Cursor::new(&data)),
+		};
 		let update_id: u64 = Readable::read(r)?;
 		let len: u64 = Readable::read(r)?;
 		let mut updates = Vec::with_capacity(cmp::min(len as usize, MAX_ALLOC_SIZE / ::core::mem::size_of::<ChannelMonitorUpdateStep>()));
@@ -634,6 +650,89 @@ impl_writeable_tlv_based!(IrrevocablyResolvedHTLC, {
 	(2, payment_preimage, option),
 });
 
+/// An HTLC-Timeout or HTLC-Success transaction spending an HTLC output of our current commitment
+/// transaction, as returned in [`CommitmentTransactionsSnapshot::holder_htlc_txn`].
+#[derive(Clone, PartialEq)]
+pub struct HolderHTLCTransaction {
+	/// The HTLC whose output the transaction spends.
+	pub htlc: HTLCOutputInCommitment,
+	/// The unsigned HTLC transaction. HTLC-Success transactions additionally require the HTLC's
+	/// payment preimage before they can be broadcast.
+	pub transaction: Transaction,
+	/// Our counterparty's signature on the transaction, if signatures were requested.
+	pub counterparty_sig: Option<Signature>,
+}
+
+/// The transactions which would be broadcast if a channel were force-closed now, as well as what
+/// we know about the commitment transaction our counterparty could broadcast, as returned by
+/// [`ChannelMonitor::get_commitment_transactions_snapshot`].
+///
+/// Our own signatures are never included, as signing our latest commitment transaction prevents
+/// the [`ChannelMonitor`] from accepting any further updates. Transactions are thus returned with
+/// empty witnesses, though their txids match those which will eventually hit the chain.
+#[derive(Clone, PartialEq)]
+pub struct CommitmentTransactionsSnapshot {
+	/// Our current (unsigned) commitment transaction.
+	pub holder_commitment_tx: Transaction,
+	/// Our counterparty's signature on [`Self::holder_commitment_tx`], if signatures were
+	/// requested.
+	pub holder_commitment_counterparty_sig: Option<Signature>,
+	/// The transactions spending each non-dust HTLC output of [`Self::holder_commitment_tx`].
+	pub holder_htlc_txn: Vec<HolderHTLCTransaction>,
+	/// The txid of the latest commitment transaction we've signed for our counterparty, if any.
+	pub counterparty_commitment_txid: Option<Txid>,
+	/// The commitment number of the latest commitment transaction we've signed for our
+	/// counterparty. Note that LDK counts commitment numbers down from 2^48 - 1.
+	pub counterparty_commitment_number: u64,
+	/// The HTLCs included in the latest commitment transaction we've signed for our counterparty.
+	pub counterparty_htlcs: Vec<HTLCOutputInCommitment>,
+}
+
+/// Fully-signed transactions which force-close a channel using our current commitment
+/// transaction and sweep our funds to a separate (e.g. cold) destination, as returned by
+/// [`ChannelMonitor::get_emergency_close_bundle`].
+///
+/// A bundle may be stored on an offline machine and broadcast if the node itself is lost or
+/// compromised. However, it is only safe to broadcast until the channel's state advances - once
+/// our counterparty holds the revocation secret for [`Self::commitment_tx`], broadcasting it
+/// allows them to claim the entire channel balance. Thus, a fresh bundle must be exported
+/// whenever the [`ChannelMonitor`] is updated and any older bundles destroyed.
+#[cfg(any(test, feature = "unsafe_revoked_tx_signing"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmergencyCloseBundle {
+	/// The commitment number of [`Self::commitment_tx`]. Note that LDK counts commitment numbers
+	/// down from 2^48 - 1, so a bundle with a lower commitment number supersedes one with a higher.
+	pub commitment_number: u64,
+	/// Our current commitment transaction, signed by both parties.
+	pub commitment_tx: Transaction,
+	/// The HTLC-Timeout and HTLC-Success transactions spending the HTLC outputs of
+	/// [`Self::commitment_tx`]. HTLC-Success transactions are only included for HTLCs whose
+	/// payment preimage we know, and HTLC-Timeout transactions may not be broadcast until the
+	/// HTLC has expired.
+	pub htlc_txn: Vec<Transaction>,
+	/// Transactions sweeping our delayed outputs of [`Self::commitment_tx`] and of each of
+	/// [`Self::htlc_txn`], which may only be broadcast once the channel's `to_self_delay` has
+	/// passed after the transaction they spend confirms. Outputs the sweep signer refused to sign
+	/// for are omitted.
+	pub sweep_txn: Vec<Transaction>,
+}
+
+/// An on-chain claim which we're waiting on a timelock to broadcast, as returned by
+/// [`ChannelMonitor::get_scheduled_claims`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledClaim {
+	/// The height of the best block at which the claim will be broadcast, i.e. one below the
+	/// height at which the outputs it spends may first be included in a block.
+	pub broadcast_height: u32,
+	/// The outputs the claim spends.
+	pub outpoints: Vec<BitcoinOutPoint>,
+	/// The claim transaction as currently computed, if we were able to compute it.
+	///
+	/// Claims which we are able to fee-bump are re-computed at the then-current feerate when they
+	/// are broadcast, so their final transaction may differ from this one.
+	pub transaction: Option<Transaction>,
+}
+
 /// A ChannelMonitor handles chain events (blocks connected and disconnected) and generates
 /// on-chain transactions to ensure no loss of funds occurs.
 ///
@@ -853,8 +952,8 @@ impl<Signer: Sign> Writeable for ChannelMonitor<Signer> {
 }
 
 // These are also used for ChannelMonitorUpdate, above.
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+pub(crate) const SERIALIZATION_VERSION: u8 = 1;
+pub(crate) const MIN_SERIALIZATION_VERSION: u8 = 1;
 
 impl<Signer: Sign> Writeable for ChannelMonitorImpl<Signer> {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
@@ -1216,6 +1315,15 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 		self.inner.lock().unwrap().get_latest_update_id()
 	}
 
+	/// Serializes this monitor compressed, which may substantially reduce the size of monitors
+	/// for channels which have seen many HTLCs.
+	///
+	/// Compressed monitors are read transparently, exactly like monitors written via
+	/// [`Writeable::write`], but cannot be read by versions of LDK prior to 0.0.111.
+	pub fn encode_compressed(&self) -> Vec<u8> {
+		compression::encode_compressed(self)
+	}
+
 	/// Gets the funding transaction outpoint of the channel this ChannelMonitor is monitoring for.
 	pub fn get_funding_txo(&self) -> (OutPoint, Script) {
 		self.inner.lock().unwrap().get_funding_txo().clone()
@@ -1282,6 +1390,12 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 		self.inner.lock().unwrap().counterparty_node_id
 	}
 
+	/// Gets the number of blocks we have to wait after our own commitment transaction confirms
+	/// before we can claim our funds from it.
+	pub(crate) fn get_holder_to_self_delay(&self) -> u16 {
+		self.inner.lock().unwrap().on_holder_tx_csv
+	}
+
 	/// Used by ChannelManager deserialization to broadcast the latest holder state if its copy of
 	/// the Channel was out-of-date. You may use it to get a broadcastable holder toxic tx in case of
 	/// fallen-behind, i.e when receiving a channel_reestablish with a proof that our counterparty side knows
@@ -1305,6 +1419,37 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 		self.inner.lock().unwrap().unsafe_get_latest_holder_commitment_txn(logger)
 	}
 
+	/// Gets the transactions which would be broadcast if this channel were force-closed now, along
+	/// with what we know about our counterparty's latest commitment transaction. This is intended
+	/// for debugging and for pre-computing the fees required to bump a force-close, see
+	/// [`CommitmentTransactionsSnapshot`] for details.
+	///
+	/// If `include_signatures` is false, our counterparty's signatures are redacted from the
+	/// snapshot.
+	pub fn get_commitment_transactions_snapshot(&self, include_signatures: bool) -> CommitmentTransactionsSnapshot {
+		self.inner.lock().unwrap().get_commitment_transactions_snapshot(include_signatures)
+	}
+
+	/// Gets an [`EmergencyCloseBundle`] which force-closes this channel with our current commitment
+	/// transaction and sweeps our resulting funds, or `None` if the funding output has already
+	/// been spent.
+	///
+	/// `sign_sweep` is called once for each of our delayed outputs and should return a signed
+	/// transaction spending it to the desired destination, e.g. via
+	/// [`KeysManager::spend_spendable_outputs`]. It may return `Err` to omit the sweep, e.g. if its
+	/// signing policy does not allow spending to the destination.
+	///
+	/// Unlike [`Self::get_latest_holder_commitment_txn`], this does not prevent further updates to
+	/// the channel. This is why it is only available with the `unsafe_revoked_tx_signing` feature
+	/// - see [`EmergencyCloseBundle`] for when a bundle becomes unsafe to broadcast.
+	///
+	/// [`KeysManager::spend_spendable_outputs`]: crate::chain::keysinterface::KeysManager::spend_spendable_outputs
+	#[cfg(any(test, feature = "unsafe_revoked_tx_signing"))]
+	pub fn get_emergency_close_bundle<F, L: Deref>(&self, sign_sweep: F, logger: &L) -> Option<EmergencyCloseBundle>
+	where F: FnMut(&SpendableOutputDescriptor) -> Result<Transaction, ()>, L::Target: Logger {
+		self.inner.lock().unwrap().get_emergency_close_bundle(sign_sweep, logger)
+	}
+
 	/// Processes transactions in a newly connected block, which may result in any of the following:
 	/// - update the monitor's state against resolved HTLCs
 	/// - punish the counterparty in the case of seeing a revoked commitment transaction
@@ -1425,6 +1570,86 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 			header, height, broadcaster, &bounded_fee_estimator, logger)
 	}
 
+	/// Provides the result of broadcasting the transaction with the given `txid`, for broadcasters
+	/// which learn of it asynchronously. See
+	/// [`BroadcasterInterface::broadcast_transaction_with_result`] for details.
+	///
+	/// Results for transactions which aren't one of our pending on-chain claims are ignored.
+	pub fn provide_broadcast_result<B: Deref, F: Deref, L: Deref>(
+		&self,
+		txid: &Txid,
+		result: BroadcastResult,
+		broadcaster: B,
+		fee_estimator: F,
+		logger: L,
+	) where
+		B::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let bounded_fee_estimator = LowerBoundedFeeEstimator::new(fee_estimator);
+		let mut inner = self.inner.lock().unwrap();
+		let cur_height = inner.best_block.height();
+		inner.onchain_tx_handler.handle_broadcast_result(
+			txid, result, cur_height, &broadcaster, &bounded_fee_estimator, &logger, MAX_IMMEDIATE_FEE_BUMPS);
+	}
+
+	/// Sets the limit on the total fee we'll pay to claim this channel's outputs on-chain, or
+	/// removes it if `claim_fee_budget` is `None`. See [`ClaimFeeBudget`] for details.
+	///
+	/// The budget is stored as a part of the monitor, and thus only survives a restart once the
+	/// monitor has been persisted again after this call.
+	pub fn set_claim_fee_budget(&self, claim_fee_budget: Option<ClaimFeeBudget>) {
+		self.inner.lock().unwrap().onchain_tx_handler.set_claim_fee_budget(claim_fee_budget);
+	}
+
+	/// Gets the limit on the total fee we'll pay to claim this channel's outputs on-chain, if any.
+	pub fn get_claim_fee_budget(&self) -> Option<ClaimFeeBudget> {
+		self.inner.lock().unwrap().onchain_tx_handler.get_claim_fee_budget()
+	}
+
+	/// Gets the on-chain claims we're waiting on a CLTV or CSV timelock to broadcast, ordered by
+	/// the height at which they'll be broadcast. Claims which can be broadcast immediately are not
+	/// included.
+	pub fn get_scheduled_claims(&self) -> Vec<ScheduledClaim> {
+		self.inner.lock().unwrap().onchain_tx_handler.get_scheduled_claims().into_iter()
+			.map(|(broadcast_height, outpoints, transaction)| ScheduledClaim { broadcast_height, outpoints, transaction })
+			.collect()
+	}
+
+	/// Gets the txids of the on-chain claim transactions we've broadcast which have not yet
+	/// confirmed, i.e. those which should currently be in the mempool. See
+	/// [`Self::update_mempool_status`].
+	pub fn get_unconfirmed_claim_txids(&self) -> Vec<Txid> {
+		self.inner.lock().unwrap().onchain_tx_handler.get_unconfirmed_claim_txids()
+	}
+
+	/// Informs the monitor which of the transactions returned by
+	/// [`Self::get_unconfirmed_claim_txids`] are currently in the mempool, allowing it to
+	/// rebroadcast those which have been evicted rather than waiting to bump them at their next
+	/// timer. A claim transaction which is repeatedly evicted has its feerate bumped instead of
+	/// being rebroadcast as-is.
+	///
+	/// This should be called periodically by clients which can query the mempool of their chain
+	/// source, e.g. whenever syncing new blocks.
+	pub fn update_mempool_status<B: Deref, F: Deref, L: Deref>(
+		&self,
+		mempool_txids: &[Txid],
+		broadcaster: B,
+		fee_estimator: F,
+		logger: L,
+	) where
+		B::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let bounded_fee_estimator = LowerBoundedFeeEstimator::new(fee_estimator);
+		let mut inner = self.inner.lock().unwrap();
+		let cur_height = inner.best_block.height();
+		inner.onchain_tx_handler.update_mempool_status(
+			mempool_txids, cur_height, &broadcaster, &bounded_fee_estimator, &logger);
+	}
+
 	/// Returns the set of txids that should be monitored for re-organization out of the chain.
 	pub fn get_relevant_txids(&self) -> Vec<Txid> {
 		let inner = self.inner.lock().unwrap();
@@ -1443,6 +1668,61 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 	pub fn current_best_block(&self) -> BestBlock {
 		self.inner.lock().unwrap().best_block.clone()
 	}
+
+	/// Rewinds the monitor to just before the block at `height` and replays every block from
+	/// `height` up to the chain tip of `block_provider`, returning new outputs to watch. See
+	/// [`block_connected`] for details.
+	///
+	/// This is intended for recovery after a chain source fed the monitor invalid chain data, e.g.
+	/// confirming transactions which were never mined or skipping ones which were. Any on-chain
+	/// events and claims which were tracked as confirmed at or above `height` are dropped before
+	/// the correct blocks are replayed. Events which have already reached [`ANTI_REORG_DELAY`]
+	/// confirmations have been acted upon and are not reverted, so `height` should be chosen such
+	/// that the invalid data was seen within the last few blocks.
+	///
+	/// Returns `Err(())` without modifying the monitor if `height` is zero or above our current
+	/// best block, or if `block_provider` does not have the block at `height`.
+	///
+	/// [`block_connected`]: Self::block_connected
+	pub fn replay_from_height<P: Deref, B: Deref, F: Deref, L: Deref>(
+		&self,
+		height: u32,
+		block_provider: P,
+		broadcaster: B,
+		fee_estimator: F,
+		logger: L,
+	) -> Result<Vec<TransactionOutputs>, ()>
+	where
+		P::Target: BlockProvider,
+		B::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let mut inner = self.inner.lock().unwrap();
+		if height == 0 || height > inner.best_block.height() { return Err(()); }
+		let mut block = match block_provider.get_block(height) {
+			Some(block) => block,
+			None => return Err(()),
+		};
+		log_info!(logger, "Replaying chain data from height {} for channel {}", height, log_funding_info!(inner));
+
+		// Disconnecting the block at `height` drops everything we saw at or above it.
+		inner.block_disconnected(&block.header, height, &*broadcaster, &*fee_estimator, &*logger);
+
+		let mut watch_outputs = Vec::new();
+		let mut block_height = height;
+		loop {
+			let txdata: Vec<_> = block.txdata.iter().enumerate().collect();
+			watch_outputs.append(&mut inner.block_connected(
+				&block.header, &txdata, block_height, &*broadcaster, &*fee_estimator, &*logger));
+			block_height += 1;
+			block = match block_provider.get_block(block_height) {
+				Some(block) => block,
+				None => break,
+			};
+		}
+		Ok(watch_outputs)
+	}
 }
 
 impl<Signer: Sign> ChannelMonitorImpl<Signer> {
@@ -2266,6 +2546,12 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 	}
 
 	pub fn get_and_clear_pending_events(&mut self) -> Vec<Event> {
+		if let Some((total_fee_satoshis, attempted_total_fee_satoshis, budget_satoshis)) = self.onchain_tx_handler.take_claim_fee_budget_event() {
+			self.pending_events.push(Event::ClaimFeeBudgetExhausted {
+				channel_id: self.funding_info.0.to_channel_id(),
+				total_fee_satoshis, attempted_total_fee_satoshis, budget_satoshis,
+			});
+		}
 		let mut ret = Vec::new();
 		mem::swap(&mut ret, &mut self.pending_events);
 		ret
@@ -2648,6 +2934,35 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 		holder_transactions
 	}
 
+	fn get_commitment_transactions_snapshot(&self, include_signatures: bool) -> CommitmentTransactionsSnapshot {
+		let holder_commitment = self.onchain_tx_handler.current_holder_commitment_tx();
+		let trusted_tx = holder_commitment.trust();
+		let keys = trusted_tx.keys();
+		let contest_delay = self.onchain_tx_handler.channel_transaction_parameters.as_holder_broadcastable().contest_delay();
+		let holder_htlc_txn = holder_commitment.htlcs().iter().enumerate().map(|(idx, htlc)| {
+			HolderHTLCTransaction {
+				htlc: htlc.clone(),
+				transaction: chan_utils::build_htlc_transaction(&trusted_tx.txid(), holder_commitment.feerate_per_kw(),
+					contest_delay, htlc, trusted_tx.opt_anchors(), &keys.broadcaster_delayed_payment_key, &keys.revocation_key),
+				counterparty_sig: if include_signatures { Some(holder_commitment.counterparty_htlc_sigs[idx]) } else { None },
+			}
+		}).collect();
+
+		let counterparty_htlcs = self.current_counterparty_commitment_txid
+			.and_then(|txid| self.counterparty_claimable_outpoints.get(&txid))
+			.map(|htlcs| htlcs.iter().map(|(htlc, _)| htlc.clone()).collect())
+			.unwrap_or_default();
+
+		CommitmentTransactionsSnapshot {
+			holder_commitment_tx: trusted_tx.built_transaction().transaction.clone(),
+			holder_commitment_counterparty_sig: if include_signatures { Some(holder_commitment.counterparty_sig) } else { None },
+			holder_htlc_txn,
+			counterparty_commitment_txid: self.current_counterparty_commitment_txid,
+			counterparty_commitment_number: self.current_counterparty_commitment_number,
+			counterparty_htlcs,
+		}
+	}
+
 	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
 	/// Note that this includes possibly-locktimed-in-the-future transactions!
 	fn unsafe_get_latest_holder_commitment_txn<L: Deref>(&mut self, logger: &L) -> Vec<Transaction> where L::Target: Logger {
@@ -2672,6 +2987,50 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 		holder_transactions
 	}
 
+	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+	fn get_emergency_close_bundle<F, L: Deref>(&mut self, mut sign_sweep: F, logger: &L) -> Option<EmergencyCloseBundle>
+	where F: FnMut(&SpendableOutputDescriptor) -> Result<Transaction, ()>, L::Target: Logger {
+		if self.funding_spend_seen {
+			return None;
+		}
+		let mut txn = self.unsafe_get_latest_holder_commitment_txn(logger);
+		let commitment_tx = txn.remove(0);
+
+		let (per_commitment_point, revocation_pubkey, revokeable_script) = {
+			let holder_commitment = self.onchain_tx_handler.current_holder_commitment_tx();
+			let trusted_tx = holder_commitment.trust();
+			let keys = trusted_tx.keys();
+			(keys.per_commitment_point, keys.revocation_key,
+				chan_utils::get_revokeable_redeemscript(&keys.revocation_key, self.on_holder_tx_csv, &keys.broadcaster_delayed_payment_key).to_v0_p2wsh())
+		};
+		let mut sweep_txn = Vec::new();
+		for tx in core::iter::once(&commitment_tx).chain(txn.iter()) {
+			for (idx, output) in tx.output.iter().enumerate() {
+				if output.script_pubkey != revokeable_script { continue; }
+				let descriptor = SpendableOutputDescriptor::DelayedPaymentOutput(DelayedPaymentOutputDescriptor {
+					outpoint: OutPoint { txid: tx.txid(), index: idx as u16 },
+					per_commitment_point,
+					to_self_delay: self.on_holder_tx_csv,
+					output: output.clone(),
+					revocation_pubkey,
+					channel_keys_id: self.channel_keys_id,
+					channel_value_satoshis: self.channel_value_satoshis,
+				});
+				match sign_sweep(&descriptor) {
+					Ok(sweep_tx) => sweep_txn.push(sweep_tx),
+					Err(()) => { log_info!(logger, "Omitting sweep of {} from emergency close bundle as it was not signed", log_spendable!(descriptor)); },
+				}
+			}
+		}
+
+		Some(EmergencyCloseBundle {
+			commitment_number: self.current_holder_commitment_number,
+			commitment_tx,
+			htlc_txn: txn,
+			sweep_txn,
+		})
+	}
+
 	pub fn block_connected<B: Deref, F: Deref, L: Deref>(&mut self, header: &BlockHeader, txdata: &TransactionData, height: u32, broadcaster: B, fee_estimator: F, logger: L) -> Vec<TransactionOutputs>
 		where B::Target: BroadcasterInterface,
 		      F::Target: FeeEstimator,
@@ -3428,7 +3787,10 @@ impl<'a, Signer: Sign, K: KeysInterface<Signer = Signer>> ReadableArgs<&'a K>
 			}
 		}
 
-		let _ver = read_ver_prefix!(reader, SERIALIZATION_VERSION);
+		let _ver = match compression::read_ver_prefix_or_decompress(reader, SERIALIZATION_VERSION)? {
+			SerializedForm::Uncompressed(ver) => ver,
+			SerializedForm::Compressed(data) => return Self::read(&mut io::Cursor::new(&data), keys_manager),
+		};
 
 		let latest_update_id: u64 = Readable::read(reader)?;
 		let commitment_transaction_number_obscure_factor = <U48 as Readable>::read(reader)?.0;
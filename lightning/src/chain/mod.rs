@@ -25,6 +25,8 @@ use prelude::*;
 
 pub mod chaininterface;
 pub mod chainmonitor;
+pub mod quorum;
+pub mod replication;
 pub mod channelmonitor;
 pub mod transaction;
 pub mod keysinterface;
@@ -187,6 +189,14 @@ pub trait Confirm {
 	fn get_relevant_txids(&self) -> Vec<Txid>;
 }
 
+/// A source of blocks on the best chain, looked up by height, used to replay chain data into a
+/// [`ChannelMonitor`] via [`ChannelMonitor::replay_from_height`].
+pub trait BlockProvider {
+	/// Gets the block at the given height on the best chain, or `None` if `height` is above the
+	/// current chain tip.
+	fn get_block(&self, height: u32) -> Option<Block>;
+}
+
 /// An error enum representing a failure to persist a channel monitor update.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ChannelMonitorUpdateErr {
@@ -338,6 +348,15 @@ pub trait Filter {
 	/// handled, e.g., by re-scanning the block in question whenever new outputs have been
 	/// registered mid-processing.
 	fn register_output(&self, output: WatchedOutput);
+
+	/// Registers interest in any transaction having an output with `script_pubkey` as a spending
+	/// condition.
+	///
+	/// This is not used for channels, whose transactions are always known in advance, but may be
+	/// called on behalf of the application via [`ChainMonitor::watch_script`].
+	///
+	/// [`ChainMonitor::watch_script`]: chainmonitor::ChainMonitor::watch_script
+	fn register_script(&self, script_pubkey: &Script);
 }
 
 /// A transaction output watched by a [`ChannelMonitor`] for spends on-chain.
@@ -24,10 +24,11 @@ use bitcoin::secp256k1;
 use ln::msgs::DecodeError;
 use ln::PaymentPreimage;
 use ln::chan_utils::{ChannelTransactionParameters, HolderCommitmentTransaction};
-use chain::chaininterface::{FeeEstimator, BroadcasterInterface, LowerBoundedFeeEstimator};
+use chain::chaininterface::{FeeEstimator, BroadcasterInterface, BroadcastRejectReason, BroadcastResult, LowerBoundedFeeEstimator};
 use chain::channelmonitor::{ANTI_REORG_DELAY, CLTV_SHARED_CLAIM_BUFFER};
 use chain::keysinterface::{Sign, KeysInterface};
 use chain::package::PackageTemplate;
+use util::config::ClaimFeeBudget;
 use util::logger::Logger;
 use util::ser::{Readable, ReadableArgs, MaybeReadable, Writer, Writeable, VecWriter};
 use util::byte_utils;
@@ -42,6 +43,10 @@ use bitcoin::hashes::Hash;
 
 const MAX_ALLOC_SIZE: usize = 64*1024;
 
+/// The maximum number of times we'll immediately bump a claim transaction's feerate in a row
+/// because our broadcaster told us its feerate was insufficient.
+pub(crate) const MAX_IMMEDIATE_FEE_BUMPS: u8 = 3;
+
 /// An entry for an [`OnchainEvent`], stating the block height when the event was observed and the
 /// transaction causing it.
 ///
@@ -207,8 +212,38 @@ pub struct OnchainTxHandler<ChannelSigner: Sign> {
 
 	locktimed_packages: BTreeMap<u32, Vec<PackageTemplate>>,
 
+	// Claim transactions pre-computed for the packages in locktimed_packages, keyed by the first
+	// outpoint each package spends. Untractable packages are broadcast from this cache once their
+	// timelock matures, whereas malleable ones are regenerated at the then-current feerate. Not
+	// persisted, as we'll re-compute any missing entries at the next block.
+	locktimed_package_txn: HashMap<BitcoinOutPoint, Transaction>,
+
 	onchain_events_awaiting_threshold_conf: Vec<OnchainEventEntry>,
 
+	// Used to link the claim transactions we've broadcast to their pending claim request, so that
+	// we can react to the result of a broadcast. Key is the txid of the broadcast transaction,
+	// value is the pending claim request identifier. Not persisted, as we only care about the
+	// results of recent broadcasts.
+	broadcast_claim_txids: HashMap<Txid, Txid>,
+
+	// Used to rebroadcast claim transactions which were evicted from the mempool. Key is the
+	// pending claim request identifier, value is the latest claim transaction we broadcast for it
+	// and the number of times in a row we've seen it missing from the mempool. Not persisted, as
+	// we'll broadcast fresh claim transactions at the next height timer after a restart.
+	latest_claim_txn: HashMap<Txid, (Transaction, u8)>,
+
+	// The limit on the total fee we'll pay to claim this channel's outputs, if any.
+	claim_fee_budget: Option<ClaimFeeBudget>,
+	// Used to enforce claim_fee_budget. Key is the pending claim request identifier, value is the
+	// fee of the latest claim transaction we broadcast for it and the value of the outputs it
+	// claims. Entries for matured claim requests are summed into resolved_claim_fees.
+	claim_fees: HashMap<Txid, (u64, u64)>,
+	resolved_claim_fees: (u64, u64),
+	// Set once we've refused to bump a claim due to claim_fee_budget, so that we only generate one
+	// event until the budget is changed. Not persisted, as the event isn't either.
+	claim_fee_budget_exhausted: bool,
+	pending_claim_fee_budget_event: Option<(u64, u64, u64)>,
+
 	pub(super) secp_ctx: Secp256k1<secp256k1::All>,
 }
 
@@ -261,7 +296,13 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 			entry.write(writer)?;
 		}
 
-		write_tlv_fields!(writer, {});
+		let claim_fees: Vec<(Txid, u64, u64)> = self.claim_fees.iter()
+			.map(|(first_claim_txid, (fee, value))| (*first_claim_txid, *fee, *value)).collect();
+		write_tlv_fields!(writer, {
+			(1, self.claim_fee_budget, option),
+			(3, claim_fees, vec_type),
+			(5, self.resolved_claim_fees, required),
+		});
 		Ok(())
 	}
 }
@@ -325,7 +366,16 @@ impl<'a, K: KeysInterface> ReadableArgs<&'a K> for OnchainTxHandler<K::Signer> {
 			}
 		}
 
-		read_tlv_fields!(reader, {});
+		let mut claim_fee_budget = None;
+		let mut claim_fees_vec: Option<Vec<(Txid, u64, u64)>> = Some(Vec::new());
+		let mut resolved_claim_fees = None;
+		read_tlv_fields!(reader, {
+			(1, claim_fee_budget, option),
+			(3, claim_fees_vec, vec_type),
+			(5, resolved_claim_fees, option),
+		});
+		let claim_fees = claim_fees_vec.unwrap().into_iter()
+			.map(|(first_claim_txid, fee, value)| (first_claim_txid, (fee, value))).collect();
 
 		let mut secp_ctx = Secp256k1::new();
 		secp_ctx.seeded_randomize(&keys_manager.get_secure_random_bytes());
@@ -340,8 +390,16 @@ impl<'a, K: KeysInterface> ReadableArgs<&'a K> for OnchainTxHandler<K::Signer> {
 			channel_transaction_parameters: channel_parameters,
 			claimable_outpoints,
 			locktimed_packages,
+			locktimed_package_txn: HashMap::new(),
 			pending_claim_requests,
 			onchain_events_awaiting_threshold_conf,
+			broadcast_claim_txids: HashMap::new(),
+			latest_claim_txn: HashMap::new(),
+			claim_fee_budget,
+			claim_fees,
+			resolved_claim_fees: resolved_claim_fees.unwrap_or((0, 0)),
+			claim_fee_budget_exhausted: false,
+			pending_claim_fee_budget_event: None,
 			secp_ctx,
 		})
 	}
@@ -360,7 +418,15 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 			pending_claim_requests: HashMap::new(),
 			claimable_outpoints: HashMap::new(),
 			locktimed_packages: BTreeMap::new(),
+			locktimed_package_txn: HashMap::new(),
 			onchain_events_awaiting_threshold_conf: Vec::new(),
+			broadcast_claim_txids: HashMap::new(),
+			latest_claim_txn: HashMap::new(),
+			claim_fee_budget: None,
+			claim_fees: HashMap::new(),
+			resolved_claim_fees: (0, 0),
+			claim_fee_budget_exhausted: false,
+			pending_claim_fee_budget_event: None,
 
 			secp_ctx,
 		}
@@ -374,11 +440,19 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 		self.holder_commitment.to_broadcaster_value_sat()
 	}
 
+	pub(crate) fn current_holder_commitment_tx(&self) -> &HolderCommitmentTransaction {
+		&self.holder_commitment
+	}
+
 	/// Lightning security model (i.e being able to redeem/timeout HTLC or penalize coutnerparty onchain) lays on the assumption of claim transactions getting confirmed before timelock expiration
 	/// (CSV or CLTV following cases). In case of high-fee spikes, claim tx may stuck in the mempool, so you need to bump its feerate quickly using Replace-By-Fee or Child-Pay-For-Parent.
 	/// Panics if there are signing errors, because signing operations in reaction to on-chain events
 	/// are not expected to fail, and if they do, we may lose funds.
-	fn generate_claim_tx<F: Deref, L: Deref>(&mut self, cur_height: u32, cached_request: &PackageTemplate, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L) -> Option<(Option<u32>, u64, Transaction)>
+	///
+	/// `first_claim_txid` identifies the pending claim request if we've already broadcast a claim
+	/// transaction for it, in which case a fee bump is refused if it would exceed our
+	/// [`ClaimFeeBudget`].
+	fn generate_claim_tx<F: Deref, L: Deref>(&mut self, cur_height: u32, cached_request: &PackageTemplate, first_claim_txid: Option<&Txid>, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L) -> Option<(Option<u32>, u64, Transaction)>
 		where F::Target: FeeEstimator,
 					L::Target: Logger,
 	{
@@ -393,6 +467,13 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 					cached_request.compute_package_output(predicted_weight, self.destination_script.dust_value().to_sat(), fee_estimator, logger) {
 				assert!(new_feerate != 0);
 
+				if let Some(first_claim_txid) = first_claim_txid {
+					let claimed_value = cached_request.package_amount();
+					if !self.within_claim_fee_budget(first_claim_txid, claimed_value - output_value, claimed_value, logger) {
+						return None;
+					}
+				}
+
 				let transaction = cached_request.finalize_package(self, output_value, self.destination_script.clone(), logger).unwrap();
 				log_trace!(logger, "...with timer {} and feerate {}", new_timer.unwrap(), new_feerate);
 				assert!(predicted_weight >= transaction.weight());
@@ -427,7 +508,9 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 
 		// Try to aggregate outputs if their timelock expiration isn't imminent (package timelock
 		// <= CLTV_SHARED_CLAIM_BUFFER) and they don't require an immediate nLockTime (aggregable).
-		for req in requests {
+		// Packages spending outputs which mature at differing heights are first split so that each
+		// part can be scheduled for broadcast at its own timelock.
+		for req in requests.into_iter().flat_map(|req| req.split_by_timelock()) {
 			// Don't claim a outpoint twice that would be bad for privacy and may uselessly lock a CPFP input for a while
 			if let Some(_) = self.claimable_outpoints.get(req.outpoints()[0]) {
 				log_info!(logger, "Ignoring second claim for outpoint {}:{}, already registered its claiming request", req.outpoints()[0].txid, req.outpoints()[0].vout);
@@ -471,11 +554,16 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 			preprocessed_requests.append(&mut entry);
 		}
 		self.locktimed_packages = remaining_locked_packages;
+		self.cache_locktimed_package_txn(cur_height, fee_estimator, logger);
 
 		// Generate claim transactions and track them to bump if necessary at
 		// height timer expiration (i.e in how many blocks we're going to take action).
 		for mut req in preprocessed_requests {
-			if let Some((new_timer, new_feerate, tx)) = self.generate_claim_tx(cur_height, &req, &*fee_estimator, &*logger) {
+			let claim = match self.locktimed_package_txn.remove(req.outpoints()[0]) {
+				Some(tx) if !req.is_malleable() => Some((None, 0, tx)),
+				_ => self.generate_claim_tx(cur_height, &req, None, &*fee_estimator, &*logger),
+			};
+			if let Some((new_timer, new_feerate, tx)) = claim {
 				req.set_timer(new_timer);
 				req.set_feerate(new_feerate);
 				let txid = tx.txid();
@@ -485,7 +573,7 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 				}
 				self.pending_claim_requests.insert(txid, req);
 				log_info!(logger, "Broadcasting onchain {}", log_tx!(tx));
-				broadcaster.broadcast_transaction(&tx);
+				self.broadcast_claim_tx(txid, &tx, cur_height, broadcaster, fee_estimator, logger, MAX_IMMEDIATE_FEE_BUMPS);
 			}
 		}
 
@@ -579,6 +667,10 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 								self.claimable_outpoints.remove(&outpoint);
 							}
 						}
+						if let Some((fee, value)) = self.claim_fees.remove(&claim_request) {
+							self.resolved_claim_fees.0 += fee;
+							self.resolved_claim_fees.1 += value;
+						}
 					},
 					OnchainEvent::ContentiousOutpoint { package } => {
 						log_debug!(logger, "Removing claim tracking due to maturation of claim tx for outpoints:");
@@ -603,15 +695,229 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 		// Build, bump and rebroadcast tx accordingly
 		log_trace!(logger, "Bumping {} candidates", bump_candidates.len());
 		for (first_claim_txid, request) in bump_candidates.iter() {
-			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(cur_height, &request, &*fee_estimator, &*logger) {
-				log_info!(logger, "Broadcasting RBF-bumped onchain {}", log_tx!(bump_tx));
-				broadcaster.broadcast_transaction(&bump_tx);
+			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(cur_height, &request, Some(first_claim_txid), &*fee_estimator, &*logger) {
 				if let Some(request) = self.pending_claim_requests.get_mut(first_claim_txid) {
 					request.set_timer(new_timer);
 					request.set_feerate(new_feerate);
 				}
+				log_info!(logger, "Broadcasting RBF-bumped onchain {}", log_tx!(bump_tx));
+				self.broadcast_claim_tx(*first_claim_txid, &bump_tx, cur_height, broadcaster, fee_estimator, logger, MAX_IMMEDIATE_FEE_BUMPS);
 			}
 		}
+
+		let pending_claim_requests = &self.pending_claim_requests;
+		self.broadcast_claim_txids.retain(|_, first_claim_txid| pending_claim_requests.contains_key(first_claim_txid));
+		self.latest_claim_txn.retain(|first_claim_txid, _| pending_claim_requests.contains_key(first_claim_txid));
+	}
+
+	/// Gets the txids of the latest claim transaction we broadcast for each pending claim request
+	/// which has not yet confirmed.
+	pub(crate) fn get_unconfirmed_claim_txids(&self) -> Vec<Txid> {
+		self.latest_claim_txn.values()
+			.map(|(tx, _)| tx.txid())
+			.filter(|txid| !self.onchain_events_awaiting_threshold_conf.iter().any(|entry| entry.txid == *txid))
+			.collect()
+	}
+
+	/// Rebroadcasts any unconfirmed claim transaction which is not in `mempool_txids`, the set of
+	/// our claim transactions currently known to be in the mempool. If a claim transaction has been
+	/// evicted repeatedly, we bump its feerate instead of rebroadcasting it as-is.
+	pub(crate) fn update_mempool_status<B: Deref, F: Deref, L: Deref>(&mut self, mempool_txids: &[Txid], cur_height: u32, broadcaster: &B, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L)
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let mut evicted_claims = Vec::new();
+		let onchain_events_awaiting_threshold_conf = &self.onchain_events_awaiting_threshold_conf;
+		let claimable_outpoints = &self.claimable_outpoints;
+		for (first_claim_txid, (tx, evictions)) in self.latest_claim_txn.iter_mut() {
+			let txid = tx.txid();
+			if mempool_txids.contains(&txid) {
+				*evictions = 0;
+				continue;
+			}
+			if onchain_events_awaiting_threshold_conf.iter().any(|entry| entry.txid == txid) { continue; }
+			// If some of the outpoints were claimed by another transaction, the claim transaction is
+			// outdated and we'll generate a new one when we see the conflicting transaction confirm.
+			let claims_pending = tx.input.iter().all(|input| claimable_outpoints.get(&input.previous_output)
+				.map(|(claim_txid, _)| claim_txid == first_claim_txid).unwrap_or(false));
+			if !claims_pending { continue; }
+			*evictions = evictions.saturating_add(1);
+			evicted_claims.push((*first_claim_txid, tx.clone(), *evictions));
+		}
+
+		for (first_claim_txid, tx, evictions) in evicted_claims {
+			if evictions > 1 {
+				let request = match self.pending_claim_requests.get(&first_claim_txid) {
+					Some(request) if request.is_malleable() => request.clone(),
+					_ => {
+						log_info!(logger, "Rebroadcasting onchain {} after it was evicted from the mempool {} times", log_tx!(tx), evictions);
+						self.broadcast_claim_tx(first_claim_txid, &tx, cur_height, broadcaster, fee_estimator, logger, MAX_IMMEDIATE_FEE_BUMPS);
+						continue;
+					},
+				};
+				if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(cur_height, &request, Some(&first_claim_txid), fee_estimator, logger) {
+					if let Some(request) = self.pending_claim_requests.get_mut(&first_claim_txid) {
+						request.set_timer(new_timer);
+						request.set_feerate(new_feerate);
+					}
+					log_info!(logger, "Broadcasting RBF-bumped onchain {} after the previous claim was evicted from the mempool {} times", log_tx!(bump_tx), evictions);
+					self.broadcast_claim_tx(first_claim_txid, &bump_tx, cur_height, broadcaster, fee_estimator, logger, MAX_IMMEDIATE_FEE_BUMPS);
+					continue;
+				}
+			}
+			log_info!(logger, "Rebroadcasting onchain {} after it was evicted from the mempool", log_tx!(tx));
+			self.broadcast_claim_tx(first_claim_txid, &tx, cur_height, broadcaster, fee_estimator, logger, MAX_IMMEDIATE_FEE_BUMPS);
+		}
+	}
+
+	/// Broadcasts `tx`, the latest claim transaction for the pending claim request identified by
+	/// `first_claim_txid`, handling the result of the broadcast if our broadcaster provides it.
+	fn broadcast_claim_tx<B: Deref, F: Deref, L: Deref>(&mut self, first_claim_txid: Txid, tx: &Transaction, cur_height: u32, broadcaster: &B, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L, remaining_fee_bumps: u8)
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let txid = tx.txid();
+		self.broadcast_claim_txids.insert(txid, first_claim_txid);
+		let evictions = match self.latest_claim_txn.get(&first_claim_txid) {
+			Some((prev_tx, evictions)) if prev_tx.txid() == txid => *evictions,
+			_ => 0,
+		};
+		self.latest_claim_txn.insert(first_claim_txid, (tx.clone(), evictions));
+		if let Some(request) = self.pending_claim_requests.get(&first_claim_txid) {
+			if request.is_malleable() {
+				let claimed_value = request.package_amount();
+				let fee = claimed_value.saturating_sub(tx.output.iter().map(|output| output.value).sum());
+				self.claim_fees.insert(first_claim_txid, (fee, claimed_value));
+			}
+		}
+		if let Some(result) = broadcaster.broadcast_transaction_with_result(tx) {
+			self.handle_broadcast_result(&txid, result, cur_height, broadcaster, fee_estimator, logger, remaining_fee_bumps);
+		}
+	}
+
+	/// Handles the result of broadcasting the transaction with the given `txid`, bumping its
+	/// feerate if it was rejected for paying too little fee. Results for transactions which aren't
+	/// one of our pending claims are ignored.
+	pub(crate) fn handle_broadcast_result<B: Deref, F: Deref, L: Deref>(&mut self, txid: &Txid, result: BroadcastResult, cur_height: u32, broadcaster: &B, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L, remaining_fee_bumps: u8)
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let first_claim_txid = match self.broadcast_claim_txids.get(txid) {
+			Some(first_claim_txid) => *first_claim_txid,
+			None => return,
+		};
+		let request = match self.pending_claim_requests.get(&first_claim_txid) {
+			Some(request) => request.clone(),
+			None => return,
+		};
+		match result {
+			BroadcastResult::Accepted|BroadcastResult::AlreadyKnown => {
+				log_trace!(logger, "Claim transaction {} was accepted by the network", txid);
+				if let Some((latest_tx, evictions)) = self.latest_claim_txn.get_mut(&first_claim_txid) {
+					if latest_tx.txid() == *txid { *evictions = 0; }
+				}
+			},
+			BroadcastResult::Rejected(BroadcastRejectReason::Other(reason)) => {
+				log_error!(logger, "Claim transaction {} was rejected by the network: {}", txid, reason);
+			},
+			BroadcastResult::Rejected(BroadcastRejectReason::InsufficientFee) => {
+				if !request.is_malleable() {
+					log_error!(logger, "Claim transaction {} was rejected for insufficient fee, but we cannot bump its feerate", txid);
+					return;
+				}
+				if remaining_fee_bumps == 0 {
+					log_error!(logger, "Claim transaction {} was rejected for insufficient fee, bumping its feerate again at its next timer", txid);
+					return;
+				}
+				if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(cur_height, &request, Some(&first_claim_txid), fee_estimator, logger) {
+					if let Some(request) = self.pending_claim_requests.get_mut(&first_claim_txid) {
+						request.set_timer(new_timer);
+						request.set_feerate(new_feerate);
+					}
+					log_info!(logger, "Claim transaction {} was rejected for insufficient fee, broadcasting RBF-bumped onchain {}", txid, log_tx!(bump_tx));
+					self.broadcast_claim_tx(first_claim_txid, &bump_tx, cur_height, broadcaster, fee_estimator, logger, remaining_fee_bumps - 1);
+				}
+			},
+		}
+	}
+
+	/// Checks whether replacing the latest claim transaction for the given pending claim request
+	/// with one paying `new_fee` for outputs worth `claimed_value` keeps the total fee of our claims
+	/// within our [`ClaimFeeBudget`], queueing an event if not.
+	fn within_claim_fee_budget<L: Deref>(&mut self, first_claim_txid: &Txid, new_fee: u64, claimed_value: u64, logger: &L) -> bool
+		where L::Target: Logger,
+	{
+		let budget = match self.claim_fee_budget {
+			Some(budget) => budget,
+			None => return true,
+		};
+		let (prev_fee, prev_value) = self.claim_fees.get(first_claim_txid).cloned().unwrap_or((0, 0));
+		if new_fee <= prev_fee { return true; }
+		let (mut total_fee, mut total_value) = self.resolved_claim_fees;
+		for (fee, value) in self.claim_fees.values() {
+			total_fee += fee;
+			total_value += value;
+		}
+		let attempted_total_fee = total_fee - prev_fee + new_fee;
+		let budget_sat = budget.budget_satoshis(total_value - prev_value + claimed_value);
+		if attempted_total_fee <= budget_sat { return true; }
+		log_error!(logger, "Not bumping the fee of claim request {} to {} sat as it would bring the total fee of our claims to {} sat, over our budget of {} sat",
+			first_claim_txid, new_fee, attempted_total_fee, budget_sat);
+		if !self.claim_fee_budget_exhausted {
+			self.claim_fee_budget_exhausted = true;
+			self.pending_claim_fee_budget_event = Some((total_fee, attempted_total_fee, budget_sat));
+		}
+		false
+	}
+
+	/// Pre-computes the claim transactions for any packages we're waiting on a timelock for which
+	/// aren't yet in `locktimed_package_txn`.
+	fn cache_locktimed_package_txn<F: Deref, L: Deref>(&mut self, cur_height: u32, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L)
+		where F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let uncached_packages: Vec<PackageTemplate> = self.locktimed_packages.values().flatten()
+			.filter(|package| !self.locktimed_package_txn.contains_key(package.outpoints()[0]))
+			.cloned().collect();
+		for package in uncached_packages {
+			if let Some((_, _, tx)) = self.generate_claim_tx(cur_height, &package, None, fee_estimator, logger) {
+				self.locktimed_package_txn.insert(*package.outpoints()[0], tx);
+			}
+		}
+	}
+
+	/// Gets the claims we're waiting on a timelock to broadcast, as `(broadcast height, outpoints
+	/// spent, pre-computed claim transaction)`, ordered by broadcast height.
+	pub(crate) fn get_scheduled_claims(&self) -> Vec<(u32, Vec<BitcoinOutPoint>, Option<Transaction>)> {
+		let mut scheduled_claims = Vec::new();
+		for (locktime, packages) in self.locktimed_packages.iter() {
+			for package in packages.iter() {
+				let outpoints: Vec<BitcoinOutPoint> = package.outpoints().into_iter().cloned().collect();
+				let tx = self.locktimed_package_txn.get(&outpoints[0]).cloned();
+				// Packages are claimed once they may be included in the next block.
+				scheduled_claims.push((locktime.saturating_sub(1), outpoints, tx));
+			}
+		}
+		scheduled_claims
+	}
+
+	pub(crate) fn set_claim_fee_budget(&mut self, claim_fee_budget: Option<ClaimFeeBudget>) {
+		self.claim_fee_budget = claim_fee_budget;
+		self.claim_fee_budget_exhausted = false;
+		self.pending_claim_fee_budget_event = None;
+	}
+
+	pub(crate) fn get_claim_fee_budget(&self) -> Option<ClaimFeeBudget> {
+		self.claim_fee_budget
+	}
+
+	/// Takes the total fee of our claims, the total a refused fee bump would have brought it to,
+	/// and our budget, if we've refused a fee bump since this was last called.
+	pub(crate) fn take_claim_fee_budget_event(&mut self) -> Option<(u64, u64, u64)> {
+		self.pending_claim_fee_budget_event.take()
 	}
 
 	pub(crate) fn transaction_unconfirmed<B: Deref, F: Deref, L: Deref>(
@@ -667,17 +973,21 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 				self.onchain_events_awaiting_threshold_conf.push(entry);
 			}
 		}
-		for (_, request) in bump_candidates.iter_mut() {
-			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(height, &request, fee_estimator, &&*logger) {
+		let mut bump_txn = Vec::with_capacity(bump_candidates.len());
+		for (ancestor_claim_txid, request) in bump_candidates.iter_mut() {
+			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(height, &request, Some(&ancestor_claim_txid.0), fee_estimator, &&*logger) {
 				request.set_timer(new_timer);
 				request.set_feerate(new_feerate);
-				log_info!(logger, "Broadcasting onchain {}", log_tx!(bump_tx));
-				broadcaster.broadcast_transaction(&bump_tx);
+				bump_txn.push((ancestor_claim_txid.0, bump_tx));
 			}
 		}
 		for (ancestor_claim_txid, request) in bump_candidates.drain() {
 			self.pending_claim_requests.insert(ancestor_claim_txid.0, request);
 		}
+		for (ancestor_claim_txid, bump_tx) in bump_txn {
+			log_info!(logger, "Broadcasting onchain {}", log_tx!(bump_tx));
+			self.broadcast_claim_tx(ancestor_claim_txid, &bump_tx, height, &broadcaster, fee_estimator, &logger, MAX_IMMEDIATE_FEE_BUMPS);
+		}
 		//TODO: if we implement cross-block aggregated claim transaction we need to refresh set of outpoints and regenerate tx but
 		// right now if one of the outpoint get disconnected, just erase whole pending claim request.
 		let mut remove_request = Vec::new();
@@ -688,6 +998,7 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 			} else { true });
 		for req in remove_request {
 			self.pending_claim_requests.remove(&req);
+			self.claim_fees.remove(&req);
 		}
 	}
 
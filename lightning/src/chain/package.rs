@@ -33,6 +33,7 @@ use util::ser::{Readable, Writer, Writeable};
 
 use io;
 use prelude::*;
+use alloc::collections::BTreeMap;
 use core::cmp;
 use core::mem;
 use core::ops::Deref;
@@ -556,6 +557,30 @@ impl PackageTemplate {
 			}
 		}
 	}
+	/// Splits a malleable package whose outputs become spendable at differing heights into one
+	/// package per [`Self::package_timelock`], so that earlier-maturing outputs can be claimed
+	/// without waiting on the later ones. Untractable packages are returned as-is.
+	pub(crate) fn split_by_timelock(self) -> Vec<PackageTemplate> {
+		if self.malleability == PackageMalleability::Untractable { return vec![self]; }
+		let height_original = self.height_original;
+		let mut inputs_by_timelock: BTreeMap<u32, Vec<(BitcoinOutPoint, PackageSolvingData)>> = BTreeMap::new();
+		for (outpoint, outp) in self.inputs.iter() {
+			inputs_by_timelock.entry(outp.absolute_tx_timelock(height_original)).or_insert(Vec::new())
+				.push((*outpoint, outp.clone()));
+		}
+		if inputs_by_timelock.len() <= 1 { return vec![self]; }
+		inputs_by_timelock.into_iter().map(|(_, inputs)| {
+			PackageTemplate {
+				inputs,
+				malleability: PackageMalleability::Malleable,
+				soonest_conf_deadline: self.soonest_conf_deadline,
+				aggregable: self.aggregable,
+				feerate_previous: self.feerate_previous,
+				height_timer: self.height_timer,
+				height_original,
+			}
+		}).collect()
+	}
 	pub(crate) fn merge_package(&mut self, mut merge_from: PackageTemplate) {
 		assert_eq!(self.height_original, merge_from.height_original);
 		if self.malleability == PackageMalleability::Untractable || merge_from.malleability == PackageMalleability::Untractable {
@@ -583,7 +608,7 @@ impl PackageTemplate {
 	}
 	/// Gets the amount of all outptus being spent by this package, only valid for malleable
 	/// packages.
-	fn package_amount(&self) -> u64 {
+	pub(crate) fn package_amount(&self) -> u64 {
 		let mut amounts = 0;
 		for (_, outp) in self.inputs.iter() {
 			amounts += outp.amount();
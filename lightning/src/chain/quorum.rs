@@ -0,0 +1,316 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Logic to only act on chain data which a quorum of independent chain sources agree on.
+//!
+//! A [`ConfirmQuorum`] wraps a [`chain::Confirm`] implementation, such as a [`ChainMonitor`], and
+//! hands out one [`ConfirmQuorumSource`] per chain source. Each chain source (e.g. an Electrum or
+//! Esplora server) is synced against its own [`ConfirmQuorumSource`] as if it were the wrapped
+//! [`chain::Confirm`] implementation. Transaction confirmations and new best blocks are only
+//! passed on once a quorum of sources has reported them, protecting against a single
+//! compromised or buggy chain source feeding us an invalid chain. Whenever sources disagree, a
+//! [`ChainSourceDivergence`] is recorded for the user to inspect.
+//!
+//! [`ChainMonitor`]: crate::chain::chainmonitor::ChainMonitor
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::hash_types::{BlockHash, Txid};
+
+use chain;
+use chain::Confirm;
+use chain::transaction::TransactionData;
+
+use prelude::*;
+use sync::Mutex;
+use core::ops::Deref;
+
+/// A disagreement between the chain sources of a [`ConfirmQuorum`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainSourceDivergence {
+	/// Chain sources reported different best blocks at the same height.
+	BestBlock {
+		/// The height at which the sources disagree.
+		height: u32,
+		/// The index of each source whose best block is at `height`, along with the hash of that
+		/// block.
+		block_hashes: Vec<(usize, BlockHash)>,
+	},
+	/// Chain sources reported the same transaction as confirmed in different blocks.
+	TransactionConfirmation {
+		/// The ID of the transaction.
+		txid: Txid,
+		/// The index of each source which reported the transaction as confirmed, along with the
+		/// hash and height of the block it reported the transaction as confirmed in.
+		confirmations: Vec<(usize, BlockHash, u32)>,
+	},
+}
+
+struct SourceState {
+	best_block: Option<(BlockHash, u32)>,
+	confirmed_txids: HashMap<Txid, (BlockHash, u32)>,
+}
+
+struct QuorumState {
+	sources: Vec<SourceState>,
+	/// The best block we last passed on to the wrapped [`chain::Confirm`].
+	best_block: Option<(BlockHash, u32)>,
+	/// The transactions we've passed on as confirmed, along with the block they confirmed in.
+	confirmed_txids: HashMap<Txid, BlockHash>,
+	divergences: Vec<ChainSourceDivergence>,
+}
+
+impl QuorumState {
+	fn best_block_votes(&self, best_block: (BlockHash, u32)) -> usize {
+		self.sources.iter().filter(|source| source.best_block == Some(best_block)).count()
+	}
+
+	fn confirmation_votes(&self, txid: &Txid, block_hash: &BlockHash) -> usize {
+		self.sources.iter()
+			.filter(|source| source.confirmed_txids.get(txid).map(|(hash, _)| hash == block_hash).unwrap_or(false))
+			.count()
+	}
+}
+
+/// Wraps a [`chain::Confirm`] implementation, only passing on the chain data which a quorum of
+/// chain sources agree on. See the [module-level documentation] for details.
+///
+/// Note that best blocks are only passed on once a quorum of sources report the very same best
+/// block, so sources which are briefly out of sync will delay the processing of new blocks until
+/// they catch up.
+///
+/// [module-level documentation]: crate::chain::quorum
+pub struct ConfirmQuorum<C: Deref> where C::Target: chain::Confirm {
+	inner: C,
+	quorum: usize,
+	state: Mutex<QuorumState>,
+}
+
+impl<C: Deref> ConfirmQuorum<C> where C::Target: chain::Confirm {
+	/// Creates a new `ConfirmQuorum` wrapping `inner`, fed by `num_sources` chain sources of which
+	/// `quorum` have to agree on any piece of chain data before it is passed on.
+	///
+	/// Panics if `quorum` is zero or greater than `num_sources`.
+	pub fn new(inner: C, num_sources: usize, quorum: usize) -> Self {
+		assert!(quorum > 0 && quorum <= num_sources, "quorum must be between 1 and the number of sources");
+		let mut sources = Vec::with_capacity(num_sources);
+		for _ in 0..num_sources {
+			sources.push(SourceState { best_block: None, confirmed_txids: HashMap::new() });
+		}
+		Self {
+			inner,
+			quorum,
+			state: Mutex::new(QuorumState {
+				sources,
+				best_block: None,
+				confirmed_txids: HashMap::new(),
+				divergences: Vec::new(),
+			}),
+		}
+	}
+
+	/// Gets the [`chain::Confirm`] interface through which the chain source with the given index
+	/// should be synced.
+	///
+	/// Panics if `index` is not less than the number of sources given in [`Self::new`].
+	pub fn source(&self, index: usize) -> ConfirmQuorumSource<'_, C> {
+		assert!(index < self.state.lock().unwrap().sources.len(), "No chain source with the given index");
+		ConfirmQuorumSource { quorum: self, index }
+	}
+
+	/// Gets the disagreements between chain sources seen since this was last called.
+	pub fn get_and_clear_divergences(&self) -> Vec<ChainSourceDivergence> {
+		let mut state = self.state.lock().unwrap();
+		core::mem::take(&mut state.divergences)
+	}
+
+	fn transactions_confirmed(&self, index: usize, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let block_hash = header.block_hash();
+		let mut state = self.state.lock().unwrap();
+		let mut newly_confirmed = Vec::new();
+		for &(tx_index, tx) in txdata.iter() {
+			let txid = tx.txid();
+			state.sources[index].confirmed_txids.insert(txid, (block_hash, height));
+
+			let confirmations: Vec<(usize, BlockHash, u32)> = state.sources.iter().enumerate()
+				.filter_map(|(idx, source)| source.confirmed_txids.get(&txid).map(|&(hash, height)| (idx, hash, height)))
+				.collect();
+			if confirmations.iter().any(|&(_, hash, _)| hash != block_hash) {
+				state.divergences.push(ChainSourceDivergence::TransactionConfirmation { txid, confirmations });
+			}
+
+			if state.confirmation_votes(&txid, &block_hash) < self.quorum { continue; }
+			match state.confirmed_txids.get(&txid) {
+				Some(hash) if *hash == block_hash => continue,
+				Some(_) => self.inner.transaction_unconfirmed(&txid),
+				None => {},
+			}
+			state.confirmed_txids.insert(txid, block_hash);
+			newly_confirmed.push((tx_index, tx));
+		}
+		if !newly_confirmed.is_empty() {
+			self.inner.transactions_confirmed(header, &newly_confirmed, height);
+		}
+	}
+
+	fn transaction_unconfirmed(&self, index: usize, txid: &Txid) {
+		let mut state = self.state.lock().unwrap();
+		state.sources[index].confirmed_txids.remove(txid);
+		let block_hash = match state.confirmed_txids.get(txid) {
+			Some(hash) => *hash,
+			None => return,
+		};
+		if state.confirmation_votes(txid, &block_hash) < self.quorum {
+			state.confirmed_txids.remove(txid);
+			self.inner.transaction_unconfirmed(txid);
+		}
+	}
+
+	fn best_block_updated(&self, index: usize, header: &BlockHeader, height: u32) {
+		let best_block = (header.block_hash(), height);
+		let mut state = self.state.lock().unwrap();
+		state.sources[index].best_block = Some(best_block);
+
+		let block_hashes: Vec<(usize, BlockHash)> = state.sources.iter().enumerate()
+			.filter_map(|(idx, source)| match source.best_block {
+				Some((hash, source_height)) if source_height == height => Some((idx, hash)),
+				_ => None,
+			})
+			.collect();
+		if block_hashes.iter().any(|&(_, hash)| hash != best_block.0) {
+			state.divergences.push(ChainSourceDivergence::BestBlock { height, block_hashes });
+		}
+
+		if state.best_block != Some(best_block) && state.best_block_votes(best_block) >= self.quorum {
+			state.best_block = Some(best_block);
+			self.inner.best_block_updated(header, height);
+		}
+	}
+
+	fn get_relevant_txids(&self, index: usize) -> Vec<Txid> {
+		let mut txids = self.inner.get_relevant_txids();
+		let state = self.state.lock().unwrap();
+		// Sources also need to tell us if transactions which haven't reached a quorum yet are
+		// reorged out.
+		for txid in state.sources[index].confirmed_txids.keys() {
+			if !txids.contains(txid) {
+				txids.push(*txid);
+			}
+		}
+		txids
+	}
+}
+
+/// The [`chain::Confirm`] interface of a single chain source feeding a [`ConfirmQuorum`], as
+/// returned by [`ConfirmQuorum::source`].
+pub struct ConfirmQuorumSource<'a, C: Deref> where C::Target: chain::Confirm {
+	quorum: &'a ConfirmQuorum<C>,
+	index: usize,
+}
+
+impl<'a, C: Deref> chain::Confirm for ConfirmQuorumSource<'a, C> where C::Target: chain::Confirm {
+	fn transactions_confirmed(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		self.quorum.transactions_confirmed(self.index, header, txdata, height)
+	}
+
+	fn transaction_unconfirmed(&self, txid: &Txid) {
+		self.quorum.transaction_unconfirmed(self.index, txid)
+	}
+
+	fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+		self.quorum.best_block_updated(self.index, header, height)
+	}
+
+	fn get_relevant_txids(&self) -> Vec<Txid> {
+		self.quorum.get_relevant_txids(self.index)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::{BlockHeader, PackedLockTime, Transaction, TxMerkleNode, Txid};
+	use bitcoin::hashes::Hash;
+	use chain::Confirm;
+	use chain::transaction::TransactionData;
+	use super::{ChainSourceDivergence, ConfirmQuorum};
+
+	use prelude::*;
+	use sync::Mutex;
+
+	#[derive(Debug, PartialEq)]
+	enum ConfirmCall {
+		Confirmed(Vec<Txid>, u32),
+		Unconfirmed(Txid),
+		BestBlock(u32),
+	}
+
+	struct TestConfirm {
+		calls: Mutex<Vec<ConfirmCall>>,
+	}
+
+	impl TestConfirm {
+		fn take_calls(&self) -> Vec<ConfirmCall> {
+			core::mem::take(&mut *self.calls.lock().unwrap())
+		}
+	}
+
+	impl Confirm for TestConfirm {
+		fn transactions_confirmed(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) {
+			self.calls.lock().unwrap().push(ConfirmCall::Confirmed(txdata.iter().map(|(_, tx)| tx.txid()).collect(), height));
+		}
+		fn transaction_unconfirmed(&self, txid: &Txid) {
+			self.calls.lock().unwrap().push(ConfirmCall::Unconfirmed(*txid));
+		}
+		fn best_block_updated(&self, _header: &BlockHeader, height: u32) {
+			self.calls.lock().unwrap().push(ConfirmCall::BestBlock(height));
+		}
+		fn get_relevant_txids(&self) -> Vec<Txid> { Vec::new() }
+	}
+
+	fn header(nonce: u32) -> BlockHeader {
+		BlockHeader { version: 0x20000000, prev_blockhash: Hash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(), time: 42, bits: 42, nonce }
+	}
+
+	#[test]
+	fn test_confirm_quorum() {
+		let inner = TestConfirm { calls: Mutex::new(Vec::new()) };
+		let quorum = ConfirmQuorum::new(&inner, 3, 2);
+		let tx = Transaction { version: 2, lock_time: PackedLockTime::ZERO, input: Vec::new(), output: Vec::new() };
+		let txid = tx.txid();
+		let (good_header, bad_header) = (header(1), header(2));
+
+		// A single source can't confirm a transaction or move the best block on its own.
+		quorum.source(0).transactions_confirmed(&good_header, &[(0, &tx)], 100);
+		quorum.source(0).best_block_updated(&good_header, 100);
+		assert!(inner.take_calls().is_empty());
+		assert_eq!(quorum.source(0).get_relevant_txids(), vec![txid]);
+		assert!(quorum.source(1).get_relevant_txids().is_empty());
+
+		// A compromised source reporting a different chain is recorded as diverging.
+		quorum.source(2).transactions_confirmed(&bad_header, &[(0, &tx)], 100);
+		quorum.source(2).best_block_updated(&bad_header, 100);
+		assert!(inner.take_calls().is_empty());
+		assert_eq!(quorum.get_and_clear_divergences(), vec![
+			ChainSourceDivergence::TransactionConfirmation { txid, confirmations: vec![(0, good_header.block_hash(), 100), (2, bad_header.block_hash(), 100)] },
+			ChainSourceDivergence::BestBlock { height: 100, block_hashes: vec![(0, good_header.block_hash()), (2, bad_header.block_hash())] },
+		]);
+		assert!(quorum.get_and_clear_divergences().is_empty());
+
+		// Once a second source agrees, the data is passed on exactly once.
+		quorum.source(1).transactions_confirmed(&good_header, &[(0, &tx)], 100);
+		quorum.source(1).best_block_updated(&good_header, 100);
+		assert_eq!(inner.take_calls(), vec![ConfirmCall::Confirmed(vec![txid], 100), ConfirmCall::BestBlock(100)]);
+		quorum.source(1).best_block_updated(&good_header, 100);
+		assert!(inner.take_calls().is_empty());
+
+		// The transaction is only unconfirmed once the quorum no longer holds.
+		quorum.source(2).transaction_unconfirmed(&txid);
+		quorum.source(0).transaction_unconfirmed(&txid);
+		assert_eq!(inner.take_calls(), vec![ConfirmCall::Unconfirmed(txid)]);
+	}
+}
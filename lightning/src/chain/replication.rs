@@ -0,0 +1,550 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Logic to replicate [`ChannelMonitor`]s to a standalone watching service.
+//!
+//! A node replicates its monitors by wrapping its [`Persist`] implementation in a
+//! [`ReplicatingPersister`], which sends each new monitor and monitor update over a user-provided
+//! [`MonitorReplicationTransport`].
+//!
+//! On the other end, a [`WatchingService`] runs only a [`ChainMonitor`], with no
+//! `ChannelManager` or `PeerManager`. Messages received from the transport are passed to
+//! [`WatchingService::handle_message`], which keeps its copies of the monitors current and detects
+//! when they have diverged from the node's. Whether the service broadcasts the transactions its
+//! monitors generate, e.g. to punish a counterparty broadcasting a revoked state, is determined by
+//! its [`ClaimAuthority`].
+//!
+//! Each message carries a sequence number, allowing the [`WatchingService`] to detect lost
+//! messages as a [`ReplicationError::SequenceGap`]. The node then catches the service up by
+//! sending the full copy of each of its monitors via [`ReplicatingPersister::catch_up`].
+//!
+//! A [`WatchingService`] may also back a standby `ChannelManager` (see [`NodeRole::Standby`]),
+//! which takes over from the node once it has stopped. The node announces that it has stopped
+//! via [`ReplicatingPersister::step_down`], or an operator confirms it via
+//! [`WatchingService::confirm_node_stopped`], after which the standby may be promoted via
+//! [`ChannelManager::promote`] with the [`WatchingService`] as its [`LeadershipFence`]. Doing so
+//! fences off the node, rejecting any further messages from it.
+//!
+//! [`NodeRole::Standby`]: crate::ln::channelmanager::NodeRole::Standby
+//! [`ChannelManager::promote`]: crate::ln::channelmanager::ChannelManager::promote
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::hash_types::BlockHash;
+
+use chain;
+use chain::{ChannelMonitorUpdateErr, Watch};
+use chain::chaininterface::{BroadcasterInterface, BroadcastResult, FeeEstimator};
+use chain::chainmonitor::{ChainMonitor, MonitorUpdateId, Persist};
+use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, CLOSED_CHANNEL_UPDATE_ID};
+use chain::keysinterface::{KeysInterface, Sign};
+use chain::transaction::OutPoint;
+use ln::channelmanager::LeadershipFence;
+use ln::msgs::DecodeError;
+use util::logger::Logger;
+use util::ser::{ReadableArgs, Writeable};
+
+use io;
+use prelude::*;
+use sync::{Arc, Mutex};
+use core::ops::Deref;
+
+/// A message sent from a node to a [`WatchingService`] to keep its [`ChannelMonitor`]s current.
+#[derive(Clone)]
+pub enum MonitorReplicationMessage {
+	/// A full copy of a channel's [`ChannelMonitor`], sent when the channel is first watched or to
+	/// resync a copy which has diverged.
+	Monitor {
+		/// The funding outpoint of the channel.
+		funding_txo: OutPoint,
+		/// The serialized [`ChannelMonitor`].
+		serialized_monitor: Vec<u8>,
+		/// The sequence number of this message.
+		sequence_number: u64,
+	},
+	/// An update to a channel's [`ChannelMonitor`].
+	Update {
+		/// The funding outpoint of the channel.
+		funding_txo: OutPoint,
+		/// The update to apply.
+		update: ChannelMonitorUpdate,
+		/// The sequence number of this message.
+		sequence_number: u64,
+	},
+	/// Sent after the full copy of each of the node's [`ChannelMonitor`]s in response to a
+	/// [`ReplicationError::SequenceGap`], indicating that the [`WatchingService`] is current.
+	CatchUpComplete {
+		/// The sequence number of this message.
+		sequence_number: u64,
+	},
+	/// Sent once the node has stopped operating, allowing a standby to take over from it.
+	StepDown {
+		/// The sequence number of this message.
+		sequence_number: u64,
+	},
+}
+
+impl MonitorReplicationMessage {
+	/// Gets the sequence number of this message.
+	///
+	/// Sequence numbers increase by one with each message a [`ReplicatingPersister`] sends,
+	/// starting from 0.
+	pub fn sequence_number(&self) -> u64 {
+		match self {
+			MonitorReplicationMessage::Monitor { sequence_number, .. } => *sequence_number,
+			MonitorReplicationMessage::Update { sequence_number, .. } => *sequence_number,
+			MonitorReplicationMessage::CatchUpComplete { sequence_number } => *sequence_number,
+			MonitorReplicationMessage::StepDown { sequence_number } => *sequence_number,
+		}
+	}
+}
+
+impl_writeable_tlv_based_enum!(MonitorReplicationMessage,
+	(0, Monitor) => {
+		(0, funding_txo, required),
+		(2, serialized_monitor, vec_type),
+		(4, sequence_number, required),
+	},
+	(2, Update) => {
+		(0, funding_txo, required),
+		(2, update, required),
+		(4, sequence_number, required),
+	},
+	(4, CatchUpComplete) => {
+		(0, sequence_number, required),
+	},
+	(6, StepDown) => {
+		(0, sequence_number, required),
+	};
+);
+
+/// A transport over which a [`ReplicatingPersister`] sends [`MonitorReplicationMessage`]s to a
+/// [`WatchingService`].
+///
+/// Messages should be delivered in order. Lost or reordered messages are detected by the
+/// [`WatchingService`] as a divergence, which may be resolved by resyncing the affected monitor
+/// via [`ReplicatingPersister::resync_monitor`].
+pub trait MonitorReplicationTransport {
+	/// Sends the given message to the [`WatchingService`].
+	///
+	/// Returns `Err(())` if the message could not be sent, in which case the full
+	/// [`ChannelMonitor`] is sent in place of the next update to the same channel.
+	fn send_message(&self, message: &MonitorReplicationMessage) -> Result<(), ()>;
+}
+
+/// A [`Persist`] implementation which wraps another, additionally replicating each new
+/// [`ChannelMonitor`] and [`ChannelMonitorUpdate`] to a [`WatchingService`] over a
+/// [`MonitorReplicationTransport`].
+///
+/// Replication is best-effort: failing to send a message does not fail persistence, so a
+/// watching service which is unreachable never prevents the node from operating.
+pub struct ReplicatingPersister<P: Deref, T: Deref> where T::Target: MonitorReplicationTransport {
+	persister: P,
+	transport: T,
+	/// Channels whose last message failed to send, which will be sent a full monitor next.
+	pending_resyncs: Mutex<HashSet<OutPoint>>,
+	/// The sequence number of the next message we send. Only incremented once a message is sent
+	/// successfully, so that messages which fail to send do not appear as a gap.
+	next_sequence_number: Mutex<u64>,
+}
+
+impl<P: Deref, T: Deref> ReplicatingPersister<P, T> where T::Target: MonitorReplicationTransport {
+	/// Creates a new persister wrapping `persister` and replicating over `transport`.
+	pub fn new(persister: P, transport: T) -> Self {
+		ReplicatingPersister {
+			persister, transport,
+			pending_resyncs: Mutex::new(HashSet::new()),
+			next_sequence_number: Mutex::new(0),
+		}
+	}
+
+	/// Sends a full copy of the given [`ChannelMonitor`], e.g. in response to a
+	/// [`ReplicationError::Diverged`] from the [`WatchingService`].
+	pub fn resync_monitor<ChannelSigner: Sign>(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> Result<(), ()> {
+		let serialized_monitor = monitor.encode();
+		self.send(Some(funding_txo), |sequence_number| {
+			MonitorReplicationMessage::Monitor { funding_txo, serialized_monitor, sequence_number }
+		})
+	}
+
+	/// Sends a full copy of each of the node's [`ChannelMonitor`]s followed by a
+	/// [`MonitorReplicationMessage::CatchUpComplete`], in response to a
+	/// [`ReplicationError::SequenceGap`] from the [`WatchingService`].
+	///
+	/// `monitors` should contain every monitor the node is watching, e.g. as listed by
+	/// [`ChainMonitor::list_monitors`]. Returns `Err(())` if any message could not be sent, in
+	/// which case the catch-up should be retried.
+	pub fn catch_up<ChannelSigner: Sign>(&self, monitors: &[(OutPoint, &ChannelMonitor<ChannelSigner>)]) -> Result<(), ()> {
+		let mut res = Ok(());
+		for (funding_txo, monitor) in monitors.iter() {
+			if self.resync_monitor(*funding_txo, monitor).is_err() { res = Err(()); }
+		}
+		res?;
+		self.send(None, |sequence_number| MonitorReplicationMessage::CatchUpComplete { sequence_number })
+	}
+
+	/// Sends a [`MonitorReplicationMessage::StepDown`], telling the [`WatchingService`] that the
+	/// node has stopped operating, e.g. after [`ChannelManager::step_down`], such that a standby
+	/// may take over from it.
+	///
+	/// Any message we send afterwards indicates that the node is operating again.
+	///
+	/// [`ChannelManager::step_down`]: crate::ln::channelmanager::ChannelManager::step_down
+	pub fn step_down(&self) -> Result<(), ()> {
+		self.send(None, |sequence_number| MonitorReplicationMessage::StepDown { sequence_number })
+	}
+
+	/// Gets the sequence number of the next message we'll send.
+	pub fn next_sequence_number(&self) -> u64 {
+		*self.next_sequence_number.lock().unwrap()
+	}
+
+	fn send<M: FnOnce(u64) -> MonitorReplicationMessage>(&self, funding_txo: Option<OutPoint>, build_message: M) -> Result<(), ()> {
+		let mut next_sequence_number = self.next_sequence_number.lock().unwrap();
+		let res = self.transport.send_message(&build_message(*next_sequence_number));
+		if res.is_ok() { *next_sequence_number += 1; }
+		if let Some(funding_txo) = funding_txo {
+			let mut pending_resyncs = self.pending_resyncs.lock().unwrap();
+			if res.is_ok() {
+				pending_resyncs.remove(&funding_txo);
+			} else {
+				pending_resyncs.insert(funding_txo);
+			}
+		}
+		res
+	}
+}
+
+impl<ChannelSigner: Sign, P: Deref, T: Deref> Persist<ChannelSigner> for ReplicatingPersister<P, T>
+	where P::Target: Persist<ChannelSigner>,
+		T::Target: MonitorReplicationTransport,
+{
+	fn persist_new_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), ChannelMonitorUpdateErr> {
+		let res = self.persister.persist_new_channel(funding_txo, monitor, update_id);
+		let _ = self.resync_monitor(funding_txo, monitor);
+		res
+	}
+
+	fn update_persisted_channel(&self, funding_txo: OutPoint, update: &Option<ChannelMonitorUpdate>, monitor: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), ChannelMonitorUpdateErr> {
+		let res = self.persister.update_persisted_channel(funding_txo, update, monitor, update_id);
+		let needs_resync = self.pending_resyncs.lock().unwrap().contains(&funding_txo);
+		match update {
+			Some(update) if !needs_resync => {
+				let _ = self.send(Some(funding_txo), |sequence_number| {
+					MonitorReplicationMessage::Update { funding_txo, update: update.clone(), sequence_number }
+				});
+			},
+			// Updates without a `ChannelMonitorUpdate` only reflect chain data, which the watching
+			// service learns of itself.
+			None if !needs_resync => {},
+			_ => { let _ = self.resync_monitor(funding_txo, monitor); },
+		}
+		res
+	}
+}
+
+/// Whether a [`WatchingService`] may broadcast the transactions generated by its
+/// [`ChannelMonitor`]s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaimAuthority {
+	/// Transactions are broadcast, so the service punishes revoked commitment transactions, claims
+	/// HTLCs and broadcasts commitment transactions on behalf of the node.
+	Full,
+	/// Transactions are never broadcast, and are instead available via
+	/// [`WatchingService::take_withheld_transactions`], e.g. to be reviewed by an operator or
+	/// forwarded to the node.
+	WatchOnly,
+}
+
+/// A [`BroadcasterInterface`] which broadcasts via another only if permitted by its
+/// [`ClaimAuthority`].
+pub struct ClaimAuthorityBroadcaster<T: Deref> where T::Target: BroadcasterInterface {
+	broadcaster: T,
+	claim_authority: Mutex<ClaimAuthority>,
+	withheld_transactions: Mutex<Vec<Transaction>>,
+}
+
+impl<T: Deref> BroadcasterInterface for ClaimAuthorityBroadcaster<T> where T::Target: BroadcasterInterface {
+	fn broadcast_transaction(&self, tx: &Transaction) {
+		match *self.claim_authority.lock().unwrap() {
+			ClaimAuthority::Full => self.broadcaster.broadcast_transaction(tx),
+			ClaimAuthority::WatchOnly => self.withheld_transactions.lock().unwrap().push(tx.clone()),
+		}
+	}
+
+	fn broadcast_transaction_with_result(&self, tx: &Transaction) -> Option<BroadcastResult> {
+		match *self.claim_authority.lock().unwrap() {
+			ClaimAuthority::Full => self.broadcaster.broadcast_transaction_with_result(tx),
+			ClaimAuthority::WatchOnly => {
+				self.withheld_transactions.lock().unwrap().push(tx.clone());
+				None
+			},
+		}
+	}
+}
+
+/// An error returned by [`WatchingService::handle_message`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplicationError {
+	/// The message contained a [`ChannelMonitor`] which could not be read or which is for a
+	/// different channel than the message indicated.
+	InvalidMonitor(DecodeError),
+	/// An update was received for a channel we do not have a [`ChannelMonitor`] for.
+	UnknownChannel {
+		/// The funding outpoint of the channel.
+		funding_txo: OutPoint,
+	},
+	/// Our copy of a [`ChannelMonitor`] has diverged from the node's, as we received an update
+	/// which does not directly follow our latest one, or a full monitor older than ours. The node
+	/// should resync the monitor via [`ReplicatingPersister::resync_monitor`].
+	Diverged {
+		/// The funding outpoint of the channel.
+		funding_txo: OutPoint,
+		/// The latest update ID of our copy of the monitor.
+		local_update_id: u64,
+		/// The update ID of the received update or monitor.
+		remote_update_id: u64,
+	},
+	/// Applying or persisting the update or monitor failed.
+	UpdateFailed {
+		/// The funding outpoint of the channel.
+		funding_txo: OutPoint,
+	},
+	/// We received a message with a later sequence number than we expected, so at least one
+	/// message has been lost, or the node's [`ReplicatingPersister`] was restarted. The message was
+	/// not applied, and the node should send its monitors via [`ReplicatingPersister::catch_up`].
+	///
+	/// Until the catch-up completes, further messages are applied as usual, but no further gaps
+	/// are reported.
+	SequenceGap {
+		/// The sequence number we expected.
+		expected_sequence_number: u64,
+		/// The sequence number of the received message.
+		received_sequence_number: u64,
+	},
+	/// We've fenced off the node as a standby has taken over from it, see
+	/// [`LeadershipFence::fence_previous_active`]. The message was not applied, and no further
+	/// messages will be.
+	Fenced,
+}
+
+struct ReplicationStreamState {
+	/// The sequence number of the next message we expect, if we've received any.
+	next_sequence_number: Option<u64>,
+	/// Whether we've reported a [`ReplicationError::SequenceGap`] and are waiting on a
+	/// [`MonitorReplicationMessage::CatchUpComplete`].
+	catching_up: bool,
+	/// Whether the node has stopped operating, as indicated by a
+	/// [`MonitorReplicationMessage::StepDown`] or [`WatchingService::confirm_node_stopped`] not
+	/// followed by any further messages.
+	node_stopped: bool,
+	/// Whether we've fenced off the node via [`LeadershipFence::fence_previous_active`].
+	fenced: bool,
+}
+
+/// A standalone service watching the chain for a node's channels, using copies of its
+/// [`ChannelMonitor`]s received via a [`ReplicatingPersister`]. See the [module-level
+/// documentation] for details.
+///
+/// The wrapped [`ChainMonitor`], available via [`WatchingService::chain_monitor`], must be fed
+/// chain data via [`chain::Listen`] or [`chain::Confirm`] and have its events processed, as with
+/// any [`ChainMonitor`].
+///
+/// [module-level documentation]: crate::chain::replication
+pub struct WatchingService<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref, K: Deref>
+	where C::Target: chain::Filter,
+		T::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+		P::Target: Persist<ChannelSigner>,
+		K::Target: KeysInterface<Signer = ChannelSigner> + Sized,
+{
+	chain_monitor: ChainMonitor<ChannelSigner, C, Arc<ClaimAuthorityBroadcaster<T>>, F, L, P>,
+	broadcaster: Arc<ClaimAuthorityBroadcaster<T>>,
+	keys_manager: K,
+	stream_state: Mutex<ReplicationStreamState>,
+}
+
+impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref, K: Deref>
+	WatchingService<ChannelSigner, C, T, F, L, P, K>
+	where C::Target: chain::Filter,
+		T::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+		P::Target: Persist<ChannelSigner>,
+		K::Target: KeysInterface<Signer = ChannelSigner> + Sized,
+{
+	/// Creates a new watching service. See [`ChainMonitor::new`] for details on the parameters.
+	///
+	/// `keys_manager` is used to read the received [`ChannelMonitor`]s, and must thus be able to
+	/// read the node's signers.
+	pub fn new(chain_source: Option<C>, broadcaster: T, logger: L, feeest: F, persister: P, keys_manager: K, claim_authority: ClaimAuthority) -> Self {
+		let broadcaster = Arc::new(ClaimAuthorityBroadcaster {
+			broadcaster,
+			claim_authority: Mutex::new(claim_authority),
+			withheld_transactions: Mutex::new(Vec::new()),
+		});
+		WatchingService {
+			chain_monitor: ChainMonitor::new(chain_source, Arc::clone(&broadcaster), logger, feeest, persister),
+			broadcaster,
+			keys_manager,
+			stream_state: Mutex::new(ReplicationStreamState {
+				next_sequence_number: None, catching_up: false, node_stopped: false, fenced: false,
+			}),
+		}
+	}
+
+	/// Gets the [`ChainMonitor`] holding our copies of the node's [`ChannelMonitor`]s.
+	pub fn chain_monitor(&self) -> &ChainMonitor<ChannelSigner, C, Arc<ClaimAuthorityBroadcaster<T>>, F, L, P> {
+		&self.chain_monitor
+	}
+
+	/// Gets the current [`ClaimAuthority`].
+	pub fn claim_authority(&self) -> ClaimAuthority {
+		*self.broadcaster.claim_authority.lock().unwrap()
+	}
+
+	/// Changes the [`ClaimAuthority`], e.g. to take over claiming if the node has failed.
+	///
+	/// Note that transactions which were previously withheld are not broadcast, though they will
+	/// generally be regenerated and broadcast with the next block.
+	pub fn set_claim_authority(&self, claim_authority: ClaimAuthority) {
+		*self.broadcaster.claim_authority.lock().unwrap() = claim_authority;
+	}
+
+	/// Gets and clears the transactions which were withheld under [`ClaimAuthority::WatchOnly`].
+	pub fn take_withheld_transactions(&self) -> Vec<Transaction> {
+		core::mem::take(&mut *self.broadcaster.withheld_transactions.lock().unwrap())
+	}
+
+	/// Gets the funding outpoint and latest update ID of each of our [`ChannelMonitor`]s, allowing
+	/// them to be compared against the node's.
+	pub fn list_monitor_update_ids(&self) -> Vec<(OutPoint, u64)> {
+		self.chain_monitor.list_monitors().drain(..).filter_map(|funding_txo| {
+			self.chain_monitor.get_monitor(funding_txo).ok()
+				.map(|monitor| (funding_txo, monitor.get_latest_update_id()))
+		}).collect()
+	}
+
+	/// Returns true if we've reported a [`ReplicationError::SequenceGap`] and have not yet received
+	/// the end of the node's [`ReplicatingPersister::catch_up`].
+	pub fn is_catching_up(&self) -> bool {
+		self.stream_state.lock().unwrap().catching_up
+	}
+
+	/// Records that the node is known to have stopped operating, e.g. because an operator has shut
+	/// it down, as if it had sent a [`MonitorReplicationMessage::StepDown`]. This allows a standby
+	/// to take over from a node which failed without stepping down.
+	///
+	/// Any message we receive from the node afterwards indicates that it is operating again.
+	pub fn confirm_node_stopped(&self) {
+		self.stream_state.lock().unwrap().node_stopped = true;
+	}
+
+	/// Applies a message received from the node's [`ReplicatingPersister`].
+	///
+	/// Messages with a sequence number we've already seen, updates which we've already applied and
+	/// copies of monitors identical to ours are ignored, allowing messages to be redelivered
+	/// safely. As a [`ReplicatingPersister`] numbers its messages from 0, a message with sequence
+	/// number 0 is always taken to indicate that the node has restarted.
+	pub fn handle_message(&self, message: MonitorReplicationMessage) -> Result<(), ReplicationError> {
+		let received_sequence_number = message.sequence_number();
+		{
+			let mut stream_state = self.stream_state.lock().unwrap();
+			if stream_state.fenced { return Err(ReplicationError::Fenced); }
+			if let Some(expected_sequence_number) = stream_state.next_sequence_number {
+				if received_sequence_number < expected_sequence_number && received_sequence_number != 0 {
+					return Ok(());
+				}
+				stream_state.next_sequence_number = Some(received_sequence_number + 1);
+				if received_sequence_number != expected_sequence_number && !stream_state.catching_up {
+					stream_state.catching_up = true;
+					return Err(ReplicationError::SequenceGap { expected_sequence_number, received_sequence_number });
+				}
+			} else {
+				stream_state.next_sequence_number = Some(received_sequence_number + 1);
+			}
+			stream_state.node_stopped = false;
+		}
+		match message {
+			MonitorReplicationMessage::CatchUpComplete { .. } => {
+				self.stream_state.lock().unwrap().catching_up = false;
+				Ok(())
+			},
+			MonitorReplicationMessage::StepDown { .. } => {
+				self.stream_state.lock().unwrap().node_stopped = true;
+				Ok(())
+			},
+			MonitorReplicationMessage::Monitor { funding_txo, serialized_monitor, .. } => {
+				let (_, monitor) = <(BlockHash, ChannelMonitor<ChannelSigner>)>::read(
+					&mut io::Cursor::new(&serialized_monitor), &*self.keys_manager)
+					.map_err(ReplicationError::InvalidMonitor)?;
+				if monitor.get_funding_txo().0 != funding_txo {
+					return Err(ReplicationError::InvalidMonitor(DecodeError::InvalidValue));
+				}
+				let remote_update_id = monitor.get_latest_update_id();
+				let local_update_id = self.chain_monitor.get_monitor(funding_txo).ok()
+					.map(|monitor| monitor.get_latest_update_id());
+				let res = match local_update_id {
+					None => self.chain_monitor.watch_channel(funding_txo, monitor),
+					Some(local_update_id) if local_update_id > remote_update_id => {
+						return Err(ReplicationError::Diverged { funding_txo, local_update_id, remote_update_id });
+					},
+					Some(local_update_id) if local_update_id == remote_update_id => return Ok(()),
+					Some(_) => self.chain_monitor.replace_monitor(funding_txo, monitor),
+				};
+				match res {
+					Err(ChannelMonitorUpdateErr::PermanentFailure) => Err(ReplicationError::UpdateFailed { funding_txo }),
+					_ => Ok(()),
+				}
+			},
+			MonitorReplicationMessage::Update { funding_txo, update, .. } => {
+				let local_update_id = match self.chain_monitor.get_monitor(funding_txo) {
+					Ok(monitor) => monitor.get_latest_update_id(),
+					Err(()) => return Err(ReplicationError::UnknownChannel { funding_txo }),
+				};
+				if update.update_id != CLOSED_CHANNEL_UPDATE_ID {
+					if update.update_id <= local_update_id { return Ok(()); }
+					if update.update_id != local_update_id + 1 {
+						return Err(ReplicationError::Diverged { funding_txo, local_update_id, remote_update_id: update.update_id });
+					}
+				}
+				match self.chain_monitor.update_channel(funding_txo, update) {
+					Err(ChannelMonitorUpdateErr::PermanentFailure) => Err(ReplicationError::UpdateFailed { funding_txo }),
+					_ => Ok(()),
+				}
+			},
+		}
+	}
+}
+
+impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref, K: Deref> LeadershipFence
+	for WatchingService<ChannelSigner, C, T, F, L, P, K>
+	where C::Target: chain::Filter,
+		T::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+		P::Target: Persist<ChannelSigner>,
+		K::Target: KeysInterface<Signer = ChannelSigner> + Sized,
+{
+	/// Fences off the node if it has stopped, as indicated by a
+	/// [`MonitorReplicationMessage::StepDown`] or [`WatchingService::confirm_node_stopped`], and
+	/// we're not catching up on messages we've missed from it.
+	///
+	/// Once fenced, all further messages from the node are rejected with
+	/// [`ReplicationError::Fenced`], and we take over claiming on its behalf with
+	/// [`ClaimAuthority::Full`], as our [`ChainMonitor`] now backs the promoted standby.
+	fn fence_previous_active(&self) -> bool {
+		let mut stream_state = self.stream_state.lock().unwrap();
+		if stream_state.fenced { return true; }
+		if !stream_state.node_stopped || stream_state.catching_up { return false; }
+		stream_state.fenced = true;
+		self.set_claim_authority(ClaimAuthority::Full);
+		true
+	}
+}
@@ -0,0 +1,338 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities for forwarding payments within blinded routes live here.
+//!
+//! A node within a blinded route learns how to forward an HTLC from the `encrypted_recipient_data`
+//! in its onion payload, which the recipient encrypted to it using a secret shared with the
+//! route's blinding point. Nodes after the introduction node receive that blinding point in the
+//! `update_add_htlc`, and their onion is encrypted to a blinded version of their node id.
+
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::secp256k1::{self, PublicKey, Scalar, Secp256k1, SecretKey};
+
+use ln::msgs::DecodeError;
+use ln::onion_utils;
+use util::chacha20poly1305rfc::ChaChaPolyReadAdapter;
+use util::ser::{FixedLengthReader, HighZeroBytesDroppedBigSize, LengthReadableArgs, Readable, Writeable, Writer};
+
+use core::convert::TryFrom;
+use io::{self, Cursor, Read};
+use prelude::*;
+
+/// The failure code for any failure of an HTLC within a blinded route, which is returned in place
+/// of the actual failure so as not to reveal anything about the route to the sender.
+pub(crate) const INVALID_ONION_BLINDING: u16 = 0x8000 | 0x4000 | 24;
+
+/// The parameters with which a node within a blinded route relays payments, which are set by the
+/// recipient from the forwarding node's advertised channel parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PaymentRelay {
+	/// The CLTV delta to subtract from the incoming HTLC's expiry.
+	pub(crate) cltv_expiry_delta: u16,
+	/// The proportional fee charged on the forwarded amount.
+	pub(crate) fee_proportional_millionths: u32,
+	/// The base fee charged for the forward.
+	pub(crate) fee_base_msat: u32,
+}
+
+/// Constraints on the HTLCs a node within a blinded route accepts, limiting the use of the route
+/// to payments the recipient expects.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PaymentConstraints {
+	/// The maximum CLTV expiry of HTLCs the route may be used for.
+	pub(crate) max_cltv_expiry: u32,
+	/// The minimum amount of HTLCs the route may be used for.
+	pub(crate) htlc_minimum_msat: u64,
+}
+
+/// The TLVs a recipient encrypts for a node it wants to forward payments within its blinded route.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ForwardTlvs {
+	/// The channel over which to forward the payment.
+	pub(crate) short_channel_id: u64,
+	/// How the forwarded HTLC's amount and expiry are derived from the incoming HTLC's.
+	pub(crate) payment_relay: PaymentRelay,
+	/// Constraints the incoming HTLC must satisfy.
+	pub(crate) payment_constraints: PaymentConstraints,
+	/// Set if the blinding point for the next hop should be replaced, e.g. when two blinded routes
+	/// were concatenated.
+	pub(crate) next_blinding_override: Option<PublicKey>,
+}
+
+/// How to forward an HTLC we received within a blinded route, as derived from our
+/// [`ForwardTlvs`].
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BlindedForwardInfo {
+	pub(crate) short_channel_id: u64,
+	pub(crate) amt_to_forward: u64,
+	pub(crate) outgoing_cltv_value: u32,
+	/// The blinding point to relay to the next hop in its `update_add_htlc`.
+	pub(crate) next_blinding_point: PublicKey,
+}
+
+impl Writeable for PaymentRelay {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		self.cltv_expiry_delta.write(w)?;
+		self.fee_proportional_millionths.write(w)?;
+		HighZeroBytesDroppedBigSize(self.fee_base_msat).write(w)
+	}
+}
+
+impl Readable for PaymentRelay {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let cltv_expiry_delta: u16 = Readable::read(r)?;
+		let fee_proportional_millionths: u32 = Readable::read(r)?;
+		let fee_base_msat: HighZeroBytesDroppedBigSize<u32> = Readable::read(r)?;
+		Ok(PaymentRelay { cltv_expiry_delta, fee_proportional_millionths, fee_base_msat: fee_base_msat.0 })
+	}
+}
+
+impl Writeable for PaymentConstraints {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		self.max_cltv_expiry.write(w)?;
+		HighZeroBytesDroppedBigSize(self.htlc_minimum_msat).write(w)
+	}
+}
+
+impl Readable for PaymentConstraints {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let max_cltv_expiry: u32 = Readable::read(r)?;
+		let htlc_minimum_msat: HighZeroBytesDroppedBigSize<u64> = Readable::read(r)?;
+		Ok(PaymentConstraints { max_cltv_expiry, htlc_minimum_msat: htlc_minimum_msat.0 })
+	}
+}
+
+impl Writeable for ForwardTlvs {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		encode_tlv_stream!(w, {
+			(2, self.short_channel_id, required),
+			(8, self.next_blinding_override, option),
+			(10, self.payment_relay, required),
+			(12, self.payment_constraints, required),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for ForwardTlvs {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
+		let mut _padding: Option<Vec<u8>> = None;
+		let mut short_channel_id: Option<u64> = None;
+		let mut next_blinding_override: Option<PublicKey> = None;
+		let mut payment_relay: Option<PaymentRelay> = None;
+		let mut payment_constraints: Option<PaymentConstraints> = None;
+		let mut allowed_features: Option<Vec<u8>> = None;
+		decode_tlv_stream!(&mut r, {
+			(1, _padding, vec_type),
+			(2, short_channel_id, option),
+			(8, next_blinding_override, option),
+			(10, payment_relay, option),
+			(12, payment_constraints, option),
+			(14, allowed_features, vec_type),
+		});
+
+		// We don't support any features within blinded routes yet.
+		if allowed_features.map_or(false, |features| features.iter().any(|byte| *byte != 0)) {
+			return Err(DecodeError::UnknownRequiredFeature);
+		}
+		// Without a short_channel_id, these are a recipient's TLVs, and we don't support receiving
+		// over blinded routes.
+		match (short_channel_id, payment_relay, payment_constraints) {
+			(Some(short_channel_id), Some(payment_relay), Some(payment_constraints)) => Ok(ForwardTlvs {
+				short_channel_id, payment_relay, payment_constraints, next_blinding_override,
+			}),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+/// Computes the factor by which our node secret is tweaked to get the blinded node id the sender
+/// encrypted our onion to, given the secret we share with the HTLC's blinding point.
+fn blinded_node_id_factor(blinding_ss: &[u8; 32]) -> Scalar {
+	let mut hmac = HmacEngine::<Sha256>::new(b"blinded_node_id");
+	hmac.input(blinding_ss);
+	Scalar::from_be_bytes(Hmac::from_engine(hmac).into_inner()).expect("HMAC output is a valid scalar w.h.p.")
+}
+
+/// Returns the secret key with which to decrypt our onion layer of an HTLC we received from a
+/// previous node within a blinded route.
+pub(crate) fn blinded_onion_key(node_secret: &SecretKey, blinding_ss: &[u8; 32]) -> Result<SecretKey, secp256k1::Error> {
+	node_secret.mul_tweak(&blinded_node_id_factor(blinding_ss))
+}
+
+/// Returns the blinding point for the next hop within a blinded route.
+pub(crate) fn next_blinding_point<T: secp256k1::Verification>(
+	secp_ctx: &Secp256k1<T>, blinding_point: &PublicKey, blinding_ss: &[u8; 32]
+) -> Result<PublicKey, secp256k1::Error> {
+	let mut sha = Sha256::engine();
+	sha.input(&blinding_point.serialize()[..]);
+	sha.input(blinding_ss);
+	let blinding_factor = Sha256::from_engine(sha).into_inner();
+	blinding_point.mul_tweak(secp_ctx, &Scalar::from_be_bytes(blinding_factor).unwrap())
+}
+
+/// Decrypts the `encrypted_recipient_data` in our onion payload with the secret we share with the
+/// HTLC's blinding point.
+fn decrypt_forward_tlvs(encrypted_tlvs: &[u8], blinding_ss: &[u8; 32]) -> Result<ForwardTlvs, DecodeError> {
+	let rho = onion_utils::gen_rho_from_shared_secret(blinding_ss);
+	let mut reader = FixedLengthReader::new(Cursor::new(encrypted_tlvs), encrypted_tlvs.len() as u64);
+	let read_adapter: ChaChaPolyReadAdapter<ForwardTlvs> = LengthReadableArgs::read(&mut reader, rho)?;
+	Ok(read_adapter.readable)
+}
+
+/// Computes the amount to forward such that the HTLC we received pays exactly our fee per
+/// `payment_relay`, rounding in our favor. Returns `None` if the HTLC doesn't even cover our base
+/// fee.
+fn amt_to_forward_msat(inbound_amt_msat: u64, payment_relay: &PaymentRelay) -> Option<u64> {
+	let inbound_amt = inbound_amt_msat as u128;
+	let base = payment_relay.fee_base_msat as u128;
+	let prop = payment_relay.fee_proportional_millionths as u128;
+
+	let post_base_fee_inbound_amt = inbound_amt.checked_sub(base)?;
+	let mut amt_to_forward = (post_base_fee_inbound_amt * 1_000_000 + 1_000_000 + prop - 1) / (prop + 1_000_000);
+	let fee = ((amt_to_forward * prop) / 1_000_000) + base;
+	if inbound_amt - fee < amt_to_forward {
+		// Rounding the forwarded amount up left us short of our fee, so forward 1 msat less.
+		amt_to_forward -= 1;
+	}
+	u64::try_from(amt_to_forward).ok()
+}
+
+/// Processes our layer of an HTLC we're to forward within a blinded route, given the
+/// `encrypted_recipient_data` from our onion payload and the HTLC's blinding point, with which we
+/// share `blinding_ss`.
+///
+/// On failure, returns a description of the failure, which must be returned to the sender as
+/// [`INVALID_ONION_BLINDING`].
+pub(crate) fn process_blinded_forward<T: secp256k1::Verification>(
+	secp_ctx: &Secp256k1<T>, encrypted_tlvs: &[u8], blinding_point: &PublicKey, blinding_ss: &[u8; 32],
+	inbound_amt_msat: u64, inbound_cltv_expiry: u32
+) -> Result<BlindedForwardInfo, &'static str> {
+	let ForwardTlvs { short_channel_id, payment_relay, payment_constraints, next_blinding_override } =
+		decrypt_forward_tlvs(encrypted_tlvs, blinding_ss)
+			.map_err(|_| "Failed to decrypt or decode the encrypted data of a blinded forward")?;
+
+	if inbound_cltv_expiry > payment_constraints.max_cltv_expiry {
+		return Err("Blinded HTLC expires after the route's max_cltv_expiry");
+	}
+	if inbound_amt_msat < payment_constraints.htlc_minimum_msat {
+		return Err("Blinded HTLC amount was below the route's htlc_minimum_msat");
+	}
+
+	let amt_to_forward = amt_to_forward_msat(inbound_amt_msat, &payment_relay)
+		.ok_or("Blinded HTLC amount was below our base fee")?;
+	let outgoing_cltv_value = inbound_cltv_expiry.checked_sub(payment_relay.cltv_expiry_delta as u32)
+		.ok_or("Blinded HTLC expiry was below our cltv_expiry_delta")?;
+
+	let next_blinding_point = match next_blinding_override {
+		Some(blinding_point) => blinding_point,
+		None => next_blinding_point(secp_ctx, blinding_point, blinding_ss)
+			.map_err(|_| "Failed to compute the next blinding point")?,
+	};
+
+	Ok(BlindedForwardInfo { short_channel_id, amt_to_forward, outgoing_cltv_value, next_blinding_point })
+}
+
+/// Blinds the given hops, each of which is paired with its serialized encrypted TLVs, using
+/// `session_priv` as the initial blinding secret. Returns the route's blinding point along with
+/// each hop's blinded node id and encrypted TLVs.
+#[cfg(test)]
+pub(crate) fn blind_hops<T: secp256k1::Signing + secp256k1::Verification>(
+	secp_ctx: &Secp256k1<T>, session_priv: &SecretKey, hops: &[(PublicKey, Vec<u8>)]
+) -> (PublicKey, Vec<(PublicKey, Vec<u8>)>) {
+	use bitcoin::secp256k1::ecdh::SharedSecret;
+	use util::chacha20poly1305rfc::ChaChaPolyWriteAdapter;
+	use util::ser::VecWriter;
+
+	struct RawTlvs<'a>(&'a [u8]);
+	impl<'a> Writeable for RawTlvs<'a> {
+		fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+			w.write_all(self.0)
+		}
+	}
+
+	let first_blinding_point = PublicKey::from_secret_key(secp_ctx, session_priv);
+	let mut blinding_priv = session_priv.clone();
+	let mut blinding_point = first_blinding_point;
+	let mut blinded_hops = Vec::with_capacity(hops.len());
+	for (node_id, tlvs) in hops {
+		let blinding_ss = SharedSecret::new(node_id, &blinding_priv).secret_bytes();
+		let blinded_node_id = node_id.mul_tweak(secp_ctx, &blinded_node_id_factor(&blinding_ss)).unwrap();
+		let mut writer = VecWriter(Vec::new());
+		let rho = onion_utils::gen_rho_from_shared_secret(&blinding_ss);
+		ChaChaPolyWriteAdapter::new(rho, &RawTlvs(&tlvs[..])).write(&mut writer).unwrap();
+		blinded_hops.push((blinded_node_id, writer.0));
+
+		let mut sha = Sha256::engine();
+		sha.input(&blinding_point.serialize()[..]);
+		sha.input(&blinding_ss);
+		blinding_priv = blinding_priv.mul_tweak(&Scalar::from_be_bytes(Sha256::from_engine(sha).into_inner()).unwrap()).unwrap();
+		blinding_point = PublicKey::from_secret_key(secp_ctx, &blinding_priv);
+	}
+	(first_blinding_point, blinded_hops)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{amt_to_forward_msat, blind_hops, blinded_onion_key, process_blinded_forward, ForwardTlvs, PaymentConstraints, PaymentRelay};
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+	use bitcoin::secp256k1::ecdh::SharedSecret;
+	use util::ser::Writeable;
+
+	#[test]
+	fn computes_amt_to_forward() {
+		let relay = PaymentRelay { cltv_expiry_delta: 0, fee_proportional_millionths: 1_000, fee_base_msat: 1_000 };
+		// 100_000 msat forwarded pays 1_000 base + 100 proportional.
+		assert_eq!(amt_to_forward_msat(101_100, &relay), Some(100_000));
+		assert_eq!(amt_to_forward_msat(101_101, &relay), Some(100_001));
+		// Forwarding 100_000 msat would leave us 1 msat short of our fee.
+		assert_eq!(amt_to_forward_msat(101_099, &relay), Some(99_999));
+		assert_eq!(amt_to_forward_msat(999, &relay), None);
+		for inbound in 1_000..3_000 {
+			let fwd = amt_to_forward_msat(inbound, &relay).unwrap();
+			assert!(fwd + fwd * 1_000 / 1_000_000 + 1_000 <= inbound);
+		}
+	}
+
+	#[test]
+	fn processes_blinded_forward() {
+		let secp_ctx = Secp256k1::new();
+		let node_secret = SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &node_secret);
+		let session_priv = SecretKey::from_slice(&[43; 32]).unwrap();
+		let tlvs = ForwardTlvs {
+			short_channel_id: 42,
+			payment_relay: PaymentRelay { cltv_expiry_delta: 144, fee_proportional_millionths: 0, fee_base_msat: 1_000 },
+			payment_constraints: PaymentConstraints { max_cltv_expiry: 1_000, htlc_minimum_msat: 5_000 },
+			next_blinding_override: None,
+		};
+		let (blinding_point, hops) = blind_hops(&secp_ctx, &session_priv, &[(node_id, tlvs.encode())]);
+		let blinding_ss = SharedSecret::new(&blinding_point, &node_secret).secret_bytes();
+
+		// Our onion is encrypted to the blinded node id, which our blinded onion key matches.
+		let onion_key = blinded_onion_key(&node_secret, &blinding_ss).unwrap();
+		assert_eq!(PublicKey::from_secret_key(&secp_ctx, &onion_key), hops[0].0);
+
+		let info = process_blinded_forward(&secp_ctx, &hops[0].1, &blinding_point, &blinding_ss, 11_000, 900).unwrap();
+		assert_eq!(info.short_channel_id, 42);
+		assert_eq!(info.amt_to_forward, 10_000);
+		assert_eq!(info.outgoing_cltv_value, 900 - 144);
+		assert_ne!(info.next_blinding_point, blinding_point);
+
+		// The route's constraints are enforced.
+		assert!(process_blinded_forward(&secp_ctx, &hops[0].1, &blinding_point, &blinding_ss, 11_000, 1_001).is_err());
+		assert!(process_blinded_forward(&secp_ctx, &hops[0].1, &blinding_point, &blinding_ss, 4_999, 900).is_err());
+		// As is the encryption of the TLVs.
+		let other_ss = [0; 32];
+		assert!(process_blinded_forward(&secp_ctx, &hops[0].1, &blinding_point, &other_ss, 11_000, 900).is_err());
+	}
+}
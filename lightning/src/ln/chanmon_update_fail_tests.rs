@@ -19,7 +19,7 @@ use bitcoin::network::constants::Network;
 use chain::channelmonitor::{ANTI_REORG_DELAY, ChannelMonitor};
 use chain::transaction::OutPoint;
 use chain::{ChannelMonitorUpdateErr, Listen, Watch};
-use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, RAACommitmentOrder, PaymentSendFailure};
+use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, NodeRole, RAACommitmentOrder, PaymentSendFailure};
 use ln::channel::AnnouncementSigsState;
 use ln::features::InitFeatures;
 use ln::msgs;
@@ -200,7 +200,7 @@ fn do_test_simple_monitor_temporary_update_fail(disconnect: bool) {
 	let events_3 = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events_3.len(), 1);
 	match events_3[0] {
-		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(payment_hash_1, *payment_hash);
 			assert_eq!(amount_msat, 1_000_000);
 			match &purpose {
@@ -568,7 +568,7 @@ fn do_test_monitor_temporary_update_fail(disconnect_count: usize) {
 	let events_5 = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events_5.len(), 1);
 	match events_5[0] {
-		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(payment_hash_2, *payment_hash);
 			assert_eq!(amount_msat, 1_000_000);
 			match &purpose {
@@ -685,7 +685,7 @@ fn test_monitor_update_fail_cs() {
 	let events = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentReceived { payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(payment_hash, our_payment_hash);
 			assert_eq!(amount_msat, 1_000_000);
 			match &purpose {
@@ -1659,7 +1659,7 @@ fn test_monitor_update_fail_claim() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 2);
 	match events[0] {
-		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(payment_hash_2, *payment_hash);
 			assert_eq!(1_000_000, amount_msat);
 			match &purpose {
@@ -1673,7 +1673,7 @@ fn test_monitor_update_fail_claim() {
 		_ => panic!("Unexpected event"),
 	}
 	match events[1] {
-		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(payment_hash_3, *payment_hash);
 			assert_eq!(1_000_000, amount_msat);
 			match &purpose {
@@ -2311,6 +2311,7 @@ fn do_channel_holding_cell_serialize(disconnect: bool, reload_a: bool) {
 					tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 					logger: nodes[0].logger,
 					channel_monitors,
+					role: NodeRole::Active,
 				}).unwrap().1
 			};
 			nodes[0].node = &nodes_0_deserialized;
@@ -9,6 +9,7 @@
 
 use bitcoin::blockdata::script::{Script,Builder};
 use bitcoin::blockdata::transaction::{Transaction, EcdsaSighashType};
+use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
 use bitcoin::util::sighash;
 use bitcoin::consensus::encode;
 
@@ -27,11 +28,14 @@ use ln::features::{ChannelFeatures, ChannelTypeFeatures, InitFeatures};
 use ln::msgs;
 use ln::msgs::{DecodeError, OptionalField, DataLossProtect};
 use ln::script::{self, ShutdownScript};
-use ln::channelmanager::{CounterpartyForwardingInfo, PendingHTLCStatus, HTLCSource, HTLCFailReason, HTLCFailureMsg, PendingHTLCInfo, RAACommitmentOrder, BREAKDOWN_TIMEOUT, MIN_CLTV_EXPIRY_DELTA, MAX_LOCAL_BREAKDOWN_TIMEOUT};
+use ln::channelmanager::{AbandonedShardState, ChannelConfigExposure, ConfigLimitViolation, CounterpartyForwardingInfo, ObservedHTLC, PendingHTLCStatus, HTLCSource, HTLCFailReason, HTLCFailureMsg, PendingHTLCInfo, PaymentId, RAACommitmentOrder, ReestablishPlan, BREAKDOWN_TIMEOUT, MAX_LOCAL_BREAKDOWN_TIMEOUT};
+#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+use ln::channelmanager::ReestablishOverrides;
 use ln::chan_utils::{CounterpartyCommitmentSecrets, TxCreationKeys, HTLCOutputInCommitment, htlc_success_tx_weight, htlc_timeout_tx_weight, make_funding_redeemscript, ChannelPublicKeys, CommitmentTransaction, HolderCommitmentTransaction, ChannelTransactionParameters, CounterpartyChannelTransactionParameters, MAX_HTLCS, get_commitment_transaction_number_obscure_factor, ClosingTransaction};
 use ln::chan_utils;
 use chain::BestBlock;
-use chain::chaininterface::{FeeEstimator, ConfirmationTarget, LowerBoundedFeeEstimator};
+use routing::router::RouteHop;
+use chain::chaininterface::{FeeEstimator, ConfirmationTarget, LowerBoundedFeeEstimator, FEERATE_FLOOR_SATS_PER_KW};
 use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateStep, LATENCY_GRACE_PERIOD_BLOCKS};
 use chain::transaction::{OutPoint, TransactionData};
 use chain::keysinterface::{Sign, KeysInterface};
@@ -39,8 +43,9 @@ use util::events::ClosureReason;
 use util::ser::{Readable, ReadableArgs, Writeable, Writer, VecWriter};
 use util::logger::Logger;
 use util::errors::APIError;
-use util::config::{UserConfig, ChannelConfig, LegacyChannelConfig, ChannelHandshakeConfig, ChannelHandshakeLimits};
+use util::config::{UserConfig, ChannelConfig, LegacyChannelConfig, ChannelHandshakeConfig, ChannelHandshakeLimits, ChannelProbationConfig};
 use util::scid_utils::scid_from_parts;
+use util::extensions::ExtensionData;
 
 use io;
 use prelude::*;
@@ -219,6 +224,9 @@ struct OutboundHTLCOutput {
 	payment_hash: PaymentHash,
 	state: OutboundHTLCState,
 	source: HTLCSource,
+	/// The blinding point to send to our counterparty along with the HTLC, if we're forwarding it
+	/// within a blinded route.
+	blinding_point: Option<PublicKey>,
 }
 
 /// See AwaitingRemoteRevoke ChannelState for more info
@@ -230,6 +238,7 @@ enum HTLCUpdateAwaitingACK {
 		payment_hash: PaymentHash,
 		source: HTLCSource,
 		onion_routing_packet: msgs::OnionPacket,
+		blinding_point: Option<PublicKey>,
 	},
 	ClaimHTLC {
 		payment_preimage: PaymentPreimage,
@@ -239,6 +248,11 @@ enum HTLCUpdateAwaitingACK {
 		htlc_id: u64,
 		err_packet: msgs::OnionErrorPacket,
 	},
+	FailMalformedHTLC {
+		htlc_id: u64,
+		failure_code: u16,
+		sha256_of_onion: [u8; 32],
+	},
 }
 
 /// There are a few "states" and then a number of flags which can be applied:
@@ -316,6 +330,27 @@ pub(super) enum ChannelUpdateStatus {
 	Disabled,
 }
 
+/// The kinds of counterparty misbehavior counted towards placing a channel on probation, see
+/// [`ChannelProbationConfig`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum ProbationStrike {
+	/// The counterparty failed an HTLC we offered them.
+	HTLCFailure,
+	/// The counterparty disconnected from us.
+	Disconnect,
+	/// The counterparty funded the channel and has let its feerate go stale.
+	StaleFeerate,
+}
+
+/// The strikes counted against a channel in the current [`ChannelProbationConfig::window_ticks`].
+#[derive(Clone, Copy, Default)]
+struct ProbationStrikes {
+	htlc_failures: u32,
+	disconnects: u32,
+	stale_feerate_ticks: u32,
+	window_ticks_elapsed: u32,
+}
+
 /// We track when we sent an `AnnouncementSignatures` to our peer in a few states, described here.
 #[derive(PartialEq)]
 pub enum AnnouncementSigsState {
@@ -628,6 +663,9 @@ pub(super) struct Channel<Signer: Sign> {
 	/// serialized if it was serialized by versions prior to 0.0.103.
 	/// We use this to close if funding is never broadcasted.
 	channel_creation_height: u32,
+	/// For outbound channels, the number of blocks after `channel_creation_height` we'll wait for
+	/// the funding transaction to confirm before giving up on the channel.
+	outbound_funding_timeout_blocks: Option<u32>,
 
 	counterparty_dust_limit_satoshis: u64,
 
@@ -677,6 +715,15 @@ pub(super) struct Channel<Signer: Sign> {
 	commitment_secrets: CounterpartyCommitmentSecrets,
 
 	channel_update_status: ChannelUpdateStatus,
+	/// Strikes counted towards placing this channel on probation. These are reset on
+	/// deserialization, as the misbehavior they track is typically transient.
+	probation_strikes: ProbationStrikes,
+	/// If we've placed this channel on probation, the number of timer ticks until it ends.
+	probation_ticks_remaining: Option<u32>,
+	/// Opaque data stored alongside this channel by extensions, see [`util::extensions`].
+	///
+	/// [`util::extensions`]: crate::util::extensions
+	extension_data: ExtensionData,
 	/// Once we reach `closing_negotiation_ready`, we set this, indicating if closing_signed does
 	/// not complete within a single timer tick (one minute), we should force-close the channel.
 	/// This prevents us from keeping unusable channels around forever if our counterparty wishes
@@ -707,6 +754,12 @@ pub(super) struct Channel<Signer: Sign> {
 	/// See-also <https://github.com/lightningnetwork/lnd/issues/4006>
 	pub workaround_lnd_bug_4006: Option<msgs::ChannelReady>,
 
+	// The retransmissions we settled on in response to the last channel_reestablish we received,
+	// kept around to debug reconnection issues with other implementations. Not persisted.
+	last_reestablish_plan: Option<ReestablishPlan>,
+	#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+	reestablish_overrides: Option<ReestablishOverrides>,
+
 	#[cfg(any(test, fuzzing))]
 	// When we receive an HTLC fulfill on an outbound path, we may immediately fulfill the
 	// corresponding HTLC on the inbound path. If, then, the outbound path channel is
@@ -756,7 +809,7 @@ pub(crate) fn commitment_tx_base_weight(opt_anchors: bool) -> u64 {
 }
 
 #[cfg(not(test))]
-const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
+pub(crate) const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
 #[cfg(test)]
 pub const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
 
@@ -852,8 +905,10 @@ impl<Signer: Sign> Channel<Signer> {
 	/// Guaranteed to return a value no larger than channel_value_satoshis
 	///
 	/// This is used both for outbound and inbound channels and has lower bound
-	/// of `MIN_THEIR_CHAN_RESERVE_SATOSHIS`.
+	/// of `MIN_THEIR_CHAN_RESERVE_SATOSHIS`, unless
+	/// [`ChannelHandshakeConfig::their_channel_reserve_zero`] is set, in which case it is 0.
 	pub(crate) fn get_holder_selected_channel_reserve_satoshis(channel_value_satoshis: u64, config: &UserConfig) -> u64 {
+		if config.channel_handshake_config.their_channel_reserve_zero { return 0; }
 		let calculated_reserve = channel_value_satoshis.saturating_mul(config.channel_handshake_config.their_channel_reserve_proportional_millionths as u64) / 1_000_000;
 		cmp::min(channel_value_satoshis, cmp::max(calculated_reserve, MIN_THEIR_CHAN_RESERVE_SATOSHIS))
 	}
@@ -867,6 +922,16 @@ impl<Signer: Sign> Channel<Signer> {
 		cmp::min(channel_value_satoshis, cmp::max(q, 1000))
 	}
 
+	/// Gets the feerate we propose for the commitment transactions of a new outbound channel.
+	pub(crate) fn get_outbound_feerate<F: Deref>(fee_estimator: &LowerBoundedFeeEstimator<F>, config: &UserConfig) -> u32
+	where F::Target: FeeEstimator
+	{
+		match config.channel_handshake_config.funding_feerate_sat_per_1000_weight {
+			Some(feerate) => cmp::max(feerate, FEERATE_FLOOR_SATS_PER_KW),
+			None => fee_estimator.bounded_sat_per_1000_weight(config.channel_handshake_config.funding_feerate_confirmation_target),
+		}
+	}
+
 	pub(crate) fn opt_anchors(&self) -> bool {
 		self.channel_transaction_parameters.opt_anchors.is_some()
 	}
@@ -925,13 +990,13 @@ impl<Signer: Sign> Channel<Signer> {
 			return Err(APIError::APIMisuseError {err: format!("Configured with an unreasonable our_to_self_delay ({}) putting user funds at risks", holder_selected_contest_delay)});
 		}
 		let holder_selected_channel_reserve_satoshis = Channel::<Signer>::get_holder_selected_channel_reserve_satoshis(channel_value_satoshis, config);
-		if holder_selected_channel_reserve_satoshis < MIN_CHAN_DUST_LIMIT_SATOSHIS {
+		if holder_selected_channel_reserve_satoshis < MIN_CHAN_DUST_LIMIT_SATOSHIS && !config.channel_handshake_config.their_channel_reserve_zero {
 			// Protocol level safety check in place, although it should never happen because
 			// of `MIN_THEIR_CHAN_RESERVE_SATOSHIS`
 			return Err(APIError::APIMisuseError { err: format!("Holder selected channel  reserve below implemention limit dust_limit_satoshis {}", holder_selected_channel_reserve_satoshis) });
 		}
 
-		let feerate = fee_estimator.bounded_sat_per_1000_weight(ConfirmationTarget::Normal);
+		let feerate = Self::get_outbound_feerate(fee_estimator, config);
 
 		let value_to_self_msat = channel_value_satoshis * 1000 - push_msat;
 		let commitment_tx_fee = Self::commit_tx_fee_msat(feerate, MIN_AFFORDABLE_HTLC_COUNT, opt_anchors);
@@ -1015,6 +1080,7 @@ impl<Signer: Sign> Channel<Signer> {
 			funding_tx_confirmation_height: 0,
 			short_channel_id: None,
 			channel_creation_height: current_chain_height,
+			outbound_funding_timeout_blocks: config.channel_handshake_config.outbound_funding_timeout_blocks,
 
 			feerate_per_kw: feerate,
 			counterparty_dust_limit_satoshis: 0,
@@ -1049,6 +1115,9 @@ impl<Signer: Sign> Channel<Signer> {
 			commitment_secrets: CounterpartyCommitmentSecrets::new(),
 
 			channel_update_status: ChannelUpdateStatus::Enabled,
+			probation_strikes: ProbationStrikes::default(),
+			probation_ticks_remaining: None,
+			extension_data: ExtensionData::default(),
 			closing_signed_in_flight: false,
 
 			announcement_sigs: None,
@@ -1059,6 +1128,9 @@ impl<Signer: Sign> Channel<Signer> {
 			next_remote_commitment_tx_fee_info_cached: Mutex::new(None),
 
 			workaround_lnd_bug_4006: None,
+			last_reestablish_plan: None,
+			#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+			reestablish_overrides: None,
 
 			latest_inbound_scid_alias: None,
 			outbound_scid_alias,
@@ -1070,7 +1142,7 @@ impl<Signer: Sign> Channel<Signer> {
 		})
 	}
 
-	fn check_remote_fee<F: Deref>(fee_estimator: &LowerBoundedFeeEstimator<F>, feerate_per_kw: u32) -> Result<(), ChannelError>
+	pub(super) fn check_remote_fee<F: Deref>(fee_estimator: &LowerBoundedFeeEstimator<F>, feerate_per_kw: u32) -> Result<(), ChannelError>
 		where F::Target: FeeEstimator
 	{
 		// We only bound the fee updates on the upper side to prevent completely absurd feerates,
@@ -1219,7 +1291,8 @@ impl<Signer: Sign> Channel<Signer> {
 		}
 
 		let holder_selected_channel_reserve_satoshis = Channel::<Signer>::get_holder_selected_channel_reserve_satoshis(msg.funding_satoshis, config);
-		if holder_selected_channel_reserve_satoshis < MIN_CHAN_DUST_LIMIT_SATOSHIS {
+		let zero_reserve = config.channel_handshake_config.their_channel_reserve_zero;
+		if holder_selected_channel_reserve_satoshis < MIN_CHAN_DUST_LIMIT_SATOSHIS && !zero_reserve {
 			// Protocol level safety check in place, although it should never happen because
 			// of `MIN_THEIR_CHAN_RESERVE_SATOSHIS`
 			return Err(ChannelError::Close(format!("Suitable channel reserve not found. remote_channel_reserve was ({}). dust_limit_satoshis is ({}).", holder_selected_channel_reserve_satoshis, MIN_CHAN_DUST_LIMIT_SATOSHIS)));
@@ -1231,7 +1304,8 @@ impl<Signer: Sign> Channel<Signer> {
 			log_debug!(logger, "channel_reserve_satoshis ({}) is smaller than our dust limit ({}). We can broadcast stale states without any risk, implying this channel is very insecure for our counterparty.",
 				msg.channel_reserve_satoshis, MIN_CHAN_DUST_LIMIT_SATOSHIS);
 		}
-		if holder_selected_channel_reserve_satoshis < msg.dust_limit_satoshis {
+		// With a zero reserve we accept that the counterparty's output may be trimmed to dust.
+		if holder_selected_channel_reserve_satoshis < msg.dust_limit_satoshis && !zero_reserve {
 			return Err(ChannelError::Close(format!("Dust limit ({}) too high for the channel reserve we require the remote to keep ({})", msg.dust_limit_satoshis, holder_selected_channel_reserve_satoshis)));
 		}
 
@@ -1345,6 +1419,7 @@ impl<Signer: Sign> Channel<Signer> {
 			funding_tx_confirmation_height: 0,
 			short_channel_id: None,
 			channel_creation_height: current_chain_height,
+			outbound_funding_timeout_blocks: None,
 
 			feerate_per_kw: msg.feerate_per_kw,
 			channel_value_satoshis: msg.funding_satoshis,
@@ -1383,6 +1458,9 @@ impl<Signer: Sign> Channel<Signer> {
 			commitment_secrets: CounterpartyCommitmentSecrets::new(),
 
 			channel_update_status: ChannelUpdateStatus::Enabled,
+			probation_strikes: ProbationStrikes::default(),
+			probation_ticks_remaining: None,
+			extension_data: ExtensionData::default(),
 			closing_signed_in_flight: false,
 
 			announcement_sigs: None,
@@ -1393,6 +1471,9 @@ impl<Signer: Sign> Channel<Signer> {
 			next_remote_commitment_tx_fee_info_cached: Mutex::new(None),
 
 			workaround_lnd_bug_4006: None,
+			last_reestablish_plan: None,
+			#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+			reestablish_overrides: None,
 
 			latest_inbound_scid_alias: None,
 			outbound_scid_alias,
@@ -1923,6 +2004,34 @@ impl<Signer: Sign> Channel<Signer> {
 	/// If we do fail twice, we debug_assert!(false) and return Ok(None). Thus, will always return
 	/// Ok(_) if debug assertions are turned on or preconditions are met.
 	pub fn get_update_fail_htlc<L: Deref>(&mut self, htlc_id_arg: u64, err_packet: msgs::OnionErrorPacket, logger: &L) -> Result<Option<msgs::UpdateFailHTLC>, ChannelError> where L::Target: Logger {
+		let failed = self.fail_htlc(htlc_id_arg, InboundHTLCRemovalReason::FailRelay(err_packet.clone()), logger)?;
+		Ok(if failed {
+			Some(msgs::UpdateFailHTLC {
+				channel_id: self.channel_id(),
+				htlc_id: htlc_id_arg,
+				reason: err_packet
+			})
+		} else { None })
+	}
+
+	/// Like [`Self::get_update_fail_htlc`], but fails the HTLC with an
+	/// `update_fail_malformed_htlc`, as we do for HTLCs we received within a blinded route.
+	pub fn get_update_fail_malformed_htlc<L: Deref>(&mut self, htlc_id_arg: u64, failure_code: u16, sha256_of_onion: [u8; 32], logger: &L) -> Result<Option<msgs::UpdateFailMalformedHTLC>, ChannelError> where L::Target: Logger {
+		let failed = self.fail_htlc(htlc_id_arg, InboundHTLCRemovalReason::FailMalformed((sha256_of_onion, failure_code)), logger)?;
+		Ok(if failed {
+			Some(msgs::UpdateFailMalformedHTLC {
+				channel_id: self.channel_id(),
+				htlc_id: htlc_id_arg,
+				sha256_of_onion,
+				failure_code,
+			})
+		} else { None })
+	}
+
+	/// Fails the inbound HTLC with the given id for the given `reason`, which must be a failure.
+	/// Returns whether the failure is to be sent to our counterparty now, rather than from the
+	/// holding cell or not at all.
+	fn fail_htlc<L: Deref>(&mut self, htlc_id_arg: u64, reason: InboundHTLCRemovalReason, logger: &L) -> Result<bool, ChannelError> where L::Target: Logger {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			panic!("Was asked to fail an HTLC when channel was not in an operational state");
 		}
@@ -1942,7 +2051,7 @@ impl<Signer: Sign> Channel<Signer> {
 						} else {
 							debug_assert!(false, "Tried to fail an HTLC that was already failed");
 						}
-						return Ok(None);
+						return Ok(false);
 					},
 					_ => {
 						debug_assert!(false, "Have an inbound HTLC we tried to claim before it was fully committed to");
@@ -1957,7 +2066,7 @@ impl<Signer: Sign> Channel<Signer> {
 			// If we failed to find an HTLC to fail, make sure it was previously fulfilled and this
 			// is simply a duplicate fail, not previously failed and we failed-back too early.
 			debug_assert!(self.historical_inbound_htlc_fulfills.contains(&htlc_id_arg));
-			return Ok(None);
+			return Ok(false);
 		}
 
 		// Now update local state:
@@ -1968,10 +2077,11 @@ impl<Signer: Sign> Channel<Signer> {
 						if htlc_id_arg == htlc_id {
 							#[cfg(any(test, fuzzing))]
 							debug_assert!(self.historical_inbound_htlc_fulfills.contains(&htlc_id_arg));
-							return Ok(None);
+							return Ok(false);
 						}
 					},
-					&HTLCUpdateAwaitingACK::FailHTLC { htlc_id, .. } => {
+					&HTLCUpdateAwaitingACK::FailHTLC { htlc_id, .. } |
+					&HTLCUpdateAwaitingACK::FailMalformedHTLC { htlc_id, .. } => {
 						if htlc_id_arg == htlc_id {
 							debug_assert!(false, "Tried to fail an HTLC that was already failed");
 							return Err(ChannelError::Ignore("Unable to find a pending HTLC which matched the given HTLC ID".to_owned()));
@@ -1981,24 +2091,30 @@ impl<Signer: Sign> Channel<Signer> {
 				}
 			}
 			log_trace!(logger, "Placing failure for HTLC ID {} in holding cell in channel {}.", htlc_id_arg, log_bytes!(self.channel_id()));
-			self.holding_cell_htlc_updates.push(HTLCUpdateAwaitingACK::FailHTLC {
-				htlc_id: htlc_id_arg,
-				err_packet,
+			self.holding_cell_htlc_updates.push(match reason {
+				InboundHTLCRemovalReason::FailRelay(err_packet) => HTLCUpdateAwaitingACK::FailHTLC {
+					htlc_id: htlc_id_arg,
+					err_packet,
+				},
+				InboundHTLCRemovalReason::FailMalformed((sha256_of_onion, failure_code)) => HTLCUpdateAwaitingACK::FailMalformedHTLC {
+					htlc_id: htlc_id_arg,
+					failure_code,
+					sha256_of_onion,
+				},
+				InboundHTLCRemovalReason::Fulfill(_) => unreachable!(),
 			});
-			return Ok(None);
+			return Ok(false);
 		}
 
-		log_trace!(logger, "Failing HTLC ID {} back with a update_fail_htlc message in channel {}.", htlc_id_arg, log_bytes!(self.channel_id()));
+		log_trace!(logger, "Failing HTLC ID {} back with a {} message in channel {}.", htlc_id_arg,
+			if let InboundHTLCRemovalReason::FailMalformed(_) = reason { "update_fail_malformed_htlc" } else { "update_fail_htlc" },
+			log_bytes!(self.channel_id()));
 		{
 			let htlc = &mut self.pending_inbound_htlcs[pending_idx];
-			htlc.state = InboundHTLCState::LocalRemoved(InboundHTLCRemovalReason::FailRelay(err_packet.clone()));
+			htlc.state = InboundHTLCState::LocalRemoved(reason);
 		}
 
-		Ok(Some(msgs::UpdateFailHTLC {
-			channel_id: self.channel_id(),
-			htlc_id: htlc_id_arg,
-			reason: err_packet
-		}))
+		Ok(true)
 	}
 
 	// Message handlers:
@@ -2019,7 +2135,9 @@ impl<Signer: Sign> Channel<Signer> {
 		if msg.channel_reserve_satoshis > self.channel_value_satoshis {
 			return Err(ChannelError::Close(format!("Bogus channel_reserve_satoshis ({}). Must not be greater than ({})", msg.channel_reserve_satoshis, self.channel_value_satoshis)));
 		}
-		if msg.dust_limit_satoshis > self.holder_selected_channel_reserve_satoshis {
+		// A zero reserve can only have been selected if we were configured to not require one, in
+		// which case we accept that the counterparty's output may be trimmed to dust.
+		if msg.dust_limit_satoshis > self.holder_selected_channel_reserve_satoshis && self.holder_selected_channel_reserve_satoshis != 0 {
 			return Err(ChannelError::Close(format!("Dust limit ({}) is bigger than our channel reserve ({})", msg.dust_limit_satoshis, self.holder_selected_channel_reserve_satoshis)));
 		}
 		if msg.channel_reserve_satoshis > self.channel_value_satoshis - self.holder_selected_channel_reserve_satoshis {
@@ -2100,10 +2218,11 @@ impl<Signer: Sign> Channel<Signer> {
 		self.counterparty_htlc_minimum_msat = msg.htlc_minimum_msat;
 		self.counterparty_max_accepted_htlcs = msg.max_accepted_htlcs;
 
+		let minimum_depth = cmp::max(msg.minimum_depth, peer_limits.min_minimum_depth);
 		if peer_limits.trust_own_funding_0conf {
-			self.minimum_depth = Some(msg.minimum_depth);
+			self.minimum_depth = Some(minimum_depth);
 		} else {
-			self.minimum_depth = Some(cmp::max(1, msg.minimum_depth));
+			self.minimum_depth = Some(cmp::max(1, minimum_depth));
 		}
 
 		let counterparty_pubkeys = ChannelPublicKeys {
@@ -2376,6 +2495,14 @@ impl<Signer: Sign> Channel<Signer> {
 		Ok(self.get_announcement_sigs(node_pk, genesis_block_hash, best_block.height(), logger))
 	}
 
+	/// Returns the outpoints spent by our funding transaction, if we still have a copy of it.
+	pub fn get_funding_tx_inputs(&self) -> Vec<BitcoinOutPoint> {
+		match self.funding_transaction {
+			Some(ref tx) => tx.input.iter().map(|input| input.previous_output).collect(),
+			None => Vec::new(),
+		}
+	}
+
 	/// Returns transaction if there is pending funding transaction that is yet to broadcast
 	pub fn unbroadcasted_funding(&self) -> Option<Transaction> {
 		if self.channel_state & (ChannelState::FundingCreated as u32) != 0 {
@@ -2471,6 +2598,17 @@ impl<Signer: Sign> Channel<Signer> {
 				- outbound_stats.pending_htlcs_value_msat as i64
 				- self.counterparty_selected_channel_reserve_satoshis.unwrap_or(0) as i64 * 1000,
 			0) as u64;
+
+		// The next HTLC must also leave room for the commitment transaction fee it adds, as checked
+		// in `send_htlc`. This is what limits spending in channels without a channel reserve.
+		let mut available_capacity_msat = outbound_capacity_msat;
+		if self.is_outbound() {
+			// We pay the fee, including the fee spike buffer.
+			let real_dust_limit_timeout_sat = (self.feerate_per_kw as u64 * htlc_timeout_tx_weight(self.opt_anchors()) / 1000) + self.holder_dust_limit_satoshis;
+			let htlc_above_dust = HTLCCandidate::new(real_dust_limit_timeout_sat * 1000, HTLCInitiator::LocalOffered);
+			let max_reserved_commit_tx_fee_msat = FEE_SPIKE_BUFFER_FEE_INCREASE_MULTIPLE * self.next_local_commit_tx_fee_msat(htlc_above_dust, Some(()));
+			available_capacity_msat = available_capacity_msat.saturating_sub(max_reserved_commit_tx_fee_msat);
+		}
 		AvailableBalances {
 			inbound_capacity_msat: cmp::max(self.channel_value_satoshis as i64 * 1000
 					- self.value_to_self_msat as i64
@@ -2478,7 +2616,7 @@ impl<Signer: Sign> Channel<Signer> {
 					- self.holder_selected_channel_reserve_satoshis as i64 * 1000,
 				0) as u64,
 			outbound_capacity_msat,
-			next_outbound_htlc_limit_msat: cmp::max(cmp::min(outbound_capacity_msat as i64,
+			next_outbound_htlc_limit_msat: cmp::max(cmp::min(available_capacity_msat as i64,
 					self.counterparty_max_htlc_value_in_flight_msat as i64
 						- outbound_stats.pending_htlcs_value_msat as i64),
 				0) as u64,
@@ -3105,6 +3243,7 @@ impl<Signer: Sign> Channel<Signer> {
 			let mut update_add_htlcs = Vec::with_capacity(htlc_updates.len());
 			let mut update_fulfill_htlcs = Vec::with_capacity(htlc_updates.len());
 			let mut update_fail_htlcs = Vec::with_capacity(htlc_updates.len());
+			let mut update_fail_malformed_htlcs = Vec::new();
 			let mut htlcs_to_fail = Vec::new();
 			for htlc_update in htlc_updates.drain(..) {
 				// Note that this *can* fail, though it should be due to rather-rare conditions on
@@ -3113,8 +3252,8 @@ impl<Signer: Sign> Channel<Signer> {
 				// handling this case better and maybe fulfilling some of the HTLCs while attempting
 				// to rebalance channels.
 				match &htlc_update {
-					&HTLCUpdateAwaitingACK::AddHTLC {amount_msat, cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, ..} => {
-						match self.send_htlc(amount_msat, *payment_hash, cltv_expiry, source.clone(), onion_routing_packet.clone(), logger) {
+					&HTLCUpdateAwaitingACK::AddHTLC {amount_msat, cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, blinding_point, ..} => {
+						match self.send_htlc(amount_msat, *payment_hash, cltv_expiry, source.clone(), onion_routing_packet.clone(), blinding_point, logger) {
 							Ok(update_add_msg_option) => update_add_htlcs.push(update_add_msg_option.unwrap()),
 							Err(e) => {
 								match e {
@@ -3167,9 +3306,21 @@ impl<Signer: Sign> Channel<Signer> {
 							}
 						}
 					},
+					&HTLCUpdateAwaitingACK::FailMalformedHTLC { htlc_id, failure_code, sha256_of_onion } => {
+						match self.get_update_fail_malformed_htlc(htlc_id, failure_code, sha256_of_onion, logger) {
+							// As with `FailHTLC` above, generating the fail message must not fail.
+							Ok(update_fail_malformed_msg_option) => update_fail_malformed_htlcs.push(update_fail_malformed_msg_option.unwrap()),
+							Err(e) => {
+								if let ChannelError::Ignore(_) = e {}
+								else {
+									panic!("Got a non-IgnoreError action trying to fail holding cell HTLC");
+								}
+							}
+						}
+					},
 				}
 			}
-			if update_add_htlcs.is_empty() && update_fulfill_htlcs.is_empty() && update_fail_htlcs.is_empty() && self.holding_cell_update_fee.is_none() {
+			if update_add_htlcs.is_empty() && update_fulfill_htlcs.is_empty() && update_fail_htlcs.is_empty() && update_fail_malformed_htlcs.is_empty() && self.holding_cell_update_fee.is_none() {
 				return Ok((None, htlcs_to_fail));
 			}
 			let update_fee = if let Some(feerate) = self.holding_cell_update_fee.take() {
@@ -3186,13 +3337,13 @@ impl<Signer: Sign> Channel<Signer> {
 
 			log_debug!(logger, "Freeing holding cell in channel {} resulted in {}{} HTLCs added, {} HTLCs fulfilled, and {} HTLCs failed.",
 				log_bytes!(self.channel_id()), if update_fee.is_some() { "a fee update, " } else { "" },
-				update_add_htlcs.len(), update_fulfill_htlcs.len(), update_fail_htlcs.len());
+				update_add_htlcs.len(), update_fulfill_htlcs.len(), update_fail_htlcs.len() + update_fail_malformed_htlcs.len());
 
 			Ok((Some((msgs::CommitmentUpdate {
 				update_add_htlcs,
 				update_fulfill_htlcs,
 				update_fail_htlcs,
-				update_fail_malformed_htlcs: Vec::new(),
+				update_fail_malformed_htlcs,
 				update_fee,
 				commitment_signed,
 			}, monitor_update)), htlcs_to_fail))
@@ -3703,7 +3854,9 @@ impl<Signer: Sign> Channel<Signer> {
 		}
 	}
 
-	pub fn update_fee<F: Deref>(&mut self, fee_estimator: &LowerBoundedFeeEstimator<F>, msg: &msgs::UpdateFee) -> Result<(), ChannelError>
+	/// Handles an update_fee from our counterparty. If `enforce_feerate_bounds` is false, the new
+	/// feerate is accepted even if it is far outside of what our fee estimator expects.
+	pub fn update_fee<F: Deref>(&mut self, fee_estimator: &LowerBoundedFeeEstimator<F>, msg: &msgs::UpdateFee, enforce_feerate_bounds: bool) -> Result<(), ChannelError>
 		where F::Target: FeeEstimator
 	{
 		if self.is_outbound() {
@@ -3712,7 +3865,9 @@ impl<Signer: Sign> Channel<Signer> {
 		if self.channel_state & (ChannelState::PeerDisconnected as u32) == ChannelState::PeerDisconnected as u32 {
 			return Err(ChannelError::Close("Peer sent update_fee when we needed a channel_reestablish".to_owned()));
 		}
-		Channel::<Signer>::check_remote_fee(fee_estimator, msg.feerate_per_kw)?;
+		if enforce_feerate_bounds {
+			Channel::<Signer>::check_remote_fee(fee_estimator, msg.feerate_per_kw)?;
+		}
 		let feerate_over_dust_buffer = msg.feerate_per_kw > self.get_dust_buffer_feerate(None);
 
 		self.pending_update_fee = Some((msg.feerate_per_kw, FeeUpdateState::RemoteAnnounced));
@@ -3762,6 +3917,7 @@ impl<Signer: Sign> Channel<Signer> {
 					payment_hash: htlc.payment_hash,
 					cltv_expiry: htlc.cltv_expiry,
 					onion_routing_packet: (**onion_packet).clone(),
+					blinding_point: htlc.blinding_point,
 				});
 			}
 		}
@@ -3820,6 +3976,32 @@ impl<Signer: Sign> Channel<Signer> {
 	/// [`super::channelmanager::ChannelManager::force_close_all_channels_without_broadcasting_txn`].
 	pub fn channel_reestablish<L: Deref>(&mut self, msg: &msgs::ChannelReestablish, logger: &L,
 		node_pk: PublicKey, genesis_block_hash: BlockHash, best_block: &BestBlock)
+	-> Result<ReestablishResponses, ChannelError> where L::Target: Logger {
+		#[allow(unused_mut)]
+		let mut responses = self.get_reestablish_responses(msg, logger, node_pk, genesis_block_hash, best_block)?;
+		#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+		self.apply_reestablish_overrides(&mut responses, logger);
+		self.last_reestablish_plan = Some(ReestablishPlan {
+			counterparty_next_local_commitment_number: msg.next_local_commitment_number,
+			counterparty_next_remote_commitment_number: msg.next_remote_commitment_number,
+			resend_channel_ready: responses.channel_ready.is_some(),
+			resend_revoke_and_ack: responses.raa.is_some(),
+			resend_commitment_update: responses.commitment_update.is_some(),
+			resent_htlc_updates: responses.commitment_update.as_ref().map_or(0, |upd|
+				upd.update_add_htlcs.len() + upd.update_fulfill_htlcs.len() +
+				upd.update_fail_htlcs.len() + upd.update_fail_malformed_htlcs.len()),
+			revoke_and_ack_first: responses.order == RAACommitmentOrder::RevokeAndACKFirst,
+			awaiting_monitor_update: self.channel_state & (ChannelState::MonitorUpdateFailed as u32) != 0,
+			resend_shutdown: responses.shutdown_msg.is_some(),
+			send_announcement_signatures: responses.announcement_sigs.is_some(),
+		});
+		Ok(responses)
+	}
+
+	/// Gets the retransmissions we'd like to make in response to our counterparty's
+	/// `channel_reestablish`, before any [`ReestablishOverrides`] are applied.
+	fn get_reestablish_responses<L: Deref>(&mut self, msg: &msgs::ChannelReestablish, logger: &L,
+		node_pk: PublicKey, genesis_block_hash: BlockHash, best_block: &BestBlock)
 	-> Result<ReestablishResponses, ChannelError> where L::Target: Logger {
 		if self.channel_state & (ChannelState::PeerDisconnected as u32) == 0 {
 			// While BOLT 2 doesn't indicate explicitly we should error this channel here, it
@@ -4026,6 +4208,51 @@ impl<Signer: Sign> Channel<Signer> {
 		}
 	}
 
+	/// Applies any [`ReestablishOverrides`] set on this channel to the retransmissions we
+	/// computed in response to our counterparty's `channel_reestablish`.
+	///
+	/// Forced retransmissions are only made when we have a message to resend at all, i.e. if
+	/// we've revoked a commitment transaction or are awaiting a revoke_and_ack for one we sent, and
+	/// are never made while a monitor update is pending.
+	#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+	fn apply_reestablish_overrides<L: Deref>(&self, responses: &mut ReestablishResponses, logger: &L) where L::Target: Logger {
+		let overrides = match self.reestablish_overrides { Some(ref overrides) => overrides, None => return };
+		let monitor_update_failed = self.channel_state & (ChannelState::MonitorUpdateFailed as u32) != 0;
+		if overrides.force_revoke_and_ack && responses.raa.is_none() && !monitor_update_failed &&
+			self.cur_holder_commitment_transaction_number <= INITIAL_COMMITMENT_NUMBER - 2
+		{
+			log_debug!(logger, "Forcing re-send of revoke_and_ack on channel {}", log_bytes!(self.channel_id()));
+			responses.raa = Some(self.get_last_revoke_and_ack());
+		}
+		if overrides.force_commitment_update && responses.commitment_update.is_none() && !monitor_update_failed &&
+			self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32) != 0
+		{
+			log_debug!(logger, "Forcing re-send of commitment update on channel {}", log_bytes!(self.channel_id()));
+			responses.commitment_update = Some(self.get_last_commitment_update(logger));
+		}
+		if overrides.suppress_channel_ready && responses.channel_ready.take().is_some() {
+			log_debug!(logger, "Suppressing re-send of channel_ready on channel {}", log_bytes!(self.channel_id()));
+		}
+		if overrides.suppress_revoke_and_ack && responses.raa.take().is_some() {
+			log_debug!(logger, "Suppressing re-send of revoke_and_ack on channel {}", log_bytes!(self.channel_id()));
+		}
+		if overrides.suppress_commitment_update && responses.commitment_update.take().is_some() {
+			log_debug!(logger, "Suppressing re-send of commitment update on channel {}", log_bytes!(self.channel_id()));
+		}
+	}
+
+	/// Gets the retransmissions we made in response to the last `channel_reestablish` we
+	/// received, if any since this channel was loaded.
+	pub fn get_last_reestablish_plan(&self) -> Option<ReestablishPlan> {
+		self.last_reestablish_plan.clone()
+	}
+
+	/// Sets (or clears) the overrides applied when we next reestablish this channel.
+	#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+	pub fn set_reestablish_overrides(&mut self, overrides: Option<ReestablishOverrides>) {
+		self.reestablish_overrides = overrides;
+	}
+
 	/// Calculates and returns our minimum and maximum closing transaction fee amounts, in whole
 	/// satoshis. The amounts remain consistent unless a peer disconnects/reconnects or we restart,
 	/// at which point they will be recalculated.
@@ -4533,8 +4760,12 @@ impl<Signer: Sign> Channel<Signer> {
 		self.config.options.forwarding_fee_proportional_millionths
 	}
 
-	pub fn get_cltv_expiry_delta(&self) -> u16 {
-		cmp::max(self.config.options.cltv_expiry_delta, MIN_CLTV_EXPIRY_DELTA)
+	/// Gets the CLTV expiry delta we require for HTLCs forwarded over this channel, which is never
+	/// below the given `min_cltv_expiry_delta` of our [`CltvPolicy`].
+	///
+	/// [`CltvPolicy`]: crate::util::config::CltvPolicy
+	pub fn get_cltv_expiry_delta(&self, min_cltv_expiry_delta: u16) -> u16 {
+		cmp::max(self.config.options.cltv_expiry_delta, min_cltv_expiry_delta)
 	}
 
 	pub fn get_max_dust_htlc_exposure_msat(&self) -> u64 {
@@ -4560,6 +4791,51 @@ impl<Signer: Sign> Channel<Signer> {
 		}
 	}
 
+	/// Gets the HTLCs pending in this channel, excluding those in the holding cell.
+	pub fn get_observed_htlcs(&self) -> Vec<ObservedHTLC> {
+		let inbound = self.pending_inbound_htlcs.iter().map(|htlc| ObservedHTLC {
+			channel_id: self.channel_id, inbound: true, htlc_id: htlc.htlc_id, payment_hash: htlc.payment_hash,
+			amount_msat: htlc.amount_msat, cltv_expiry: htlc.cltv_expiry,
+		});
+		let outbound = self.pending_outbound_htlcs.iter().map(|htlc| ObservedHTLC {
+			channel_id: self.channel_id, inbound: false, htlc_id: htlc.htlc_id, payment_hash: htlc.payment_hash,
+			amount_msat: htlc.amount_msat, cltv_expiry: htlc.cltv_expiry,
+		});
+		inbound.chain(outbound).collect()
+	}
+
+	/// Gets the path, session private key and state of each HTLC we sent over this channel as part
+	/// of the outbound payment with the given `payment_id`.
+	pub fn get_outbound_payment_htlcs(&self, payment_id: PaymentId) -> Vec<(&Vec<RouteHop>, &SecretKey, AbandonedShardState)> {
+		let mut res = Vec::new();
+		for htlc in self.pending_outbound_htlcs.iter() {
+			if let HTLCSource::OutboundRoute { ref path, ref session_priv, payment_id: htlc_payment_id, .. } = htlc.source {
+				if htlc_payment_id != payment_id { continue; }
+				let state = match htlc.state {
+					OutboundHTLCState::LocalAnnounced(_) => AbandonedShardState::Offered,
+					OutboundHTLCState::Committed => AbandonedShardState::Committed,
+					OutboundHTLCState::RemoteRemoved(OutboundHTLCOutcome::Success(_)) |
+					OutboundHTLCState::AwaitingRemoteRevokeToRemove(OutboundHTLCOutcome::Success(_)) |
+					OutboundHTLCState::AwaitingRemovedRemoteRevoke(OutboundHTLCOutcome::Success(_)) =>
+						AbandonedShardState::Fulfilling,
+					OutboundHTLCState::RemoteRemoved(OutboundHTLCOutcome::Failure(_)) |
+					OutboundHTLCState::AwaitingRemoteRevokeToRemove(OutboundHTLCOutcome::Failure(_)) |
+					OutboundHTLCState::AwaitingRemovedRemoteRevoke(OutboundHTLCOutcome::Failure(_)) =>
+						AbandonedShardState::Failing,
+				};
+				res.push((path, session_priv, state));
+			}
+		}
+		for update in self.holding_cell_htlc_updates.iter() {
+			if let &HTLCUpdateAwaitingACK::AddHTLC { source: HTLCSource::OutboundRoute { ref path, ref session_priv, payment_id: htlc_payment_id, .. }, .. } = update {
+				if htlc_payment_id == payment_id {
+					res.push((path, session_priv, AbandonedShardState::HoldingCell));
+				}
+			}
+		}
+		res
+	}
+
 	/// Returns the current [`ChannelConfig`] applied to the channel.
 	pub fn config(&self) -> ChannelConfig {
 		self.config.options
@@ -4623,6 +4899,48 @@ impl<Signer: Sign> Channel<Signer> {
 		self.feerate_per_kw
 	}
 
+	/// Computes the worst-case exposure of our pending HTLCs under `config`, assuming our feerate
+	/// rises to `feerate_per_kw`, if set.
+	pub fn simulate_config_exposure(&self, config: &UserConfig, feerate_per_kw: Option<u32>) -> ChannelConfigExposure {
+		let inbound_stats = self.get_inbound_pending_htlc_stats(feerate_per_kw);
+		let outbound_stats = self.get_outbound_pending_htlc_stats(feerate_per_kw);
+		let on_holder_tx_dust_exposure_msat = inbound_stats.on_holder_tx_dust_exposure_msat + outbound_stats.on_holder_tx_dust_exposure_msat;
+		let on_counterparty_tx_dust_exposure_msat = inbound_stats.on_counterparty_tx_dust_exposure_msat + outbound_stats.on_counterparty_tx_dust_exposure_msat;
+
+		let mut violations = Vec::new();
+		let dust_exposure_msat = cmp::max(on_holder_tx_dust_exposure_msat, on_counterparty_tx_dust_exposure_msat);
+		if dust_exposure_msat > config.channel_config.max_dust_htlc_exposure_msat {
+			violations.push(ConfigLimitViolation::DustHTLCExposure {
+				exposure_msat: dust_exposure_msat,
+				limit_msat: config.channel_config.max_dust_htlc_exposure_msat,
+			});
+		}
+		let max_inbound_in_flight_msat = Self::get_holder_max_htlc_value_in_flight_msat(self.channel_value_satoshis, &config.channel_handshake_config);
+		if inbound_stats.pending_htlcs_value_msat > max_inbound_in_flight_msat {
+			violations.push(ConfigLimitViolation::InboundHTLCValueInFlight {
+				value_msat: inbound_stats.pending_htlcs_value_msat,
+				limit_msat: max_inbound_in_flight_msat,
+			});
+		}
+		// The counterparty's limit is only known once they've accepted or opened the channel.
+		if self.counterparty_max_accepted_htlcs != 0 && self.counterparty_max_accepted_htlcs < config.channel_handshake_limits.min_max_accepted_htlcs {
+			violations.push(ConfigLimitViolation::CounterpartyMaxAcceptedHTLCs {
+				counterparty_max_accepted_htlcs: self.counterparty_max_accepted_htlcs,
+				min_max_accepted_htlcs: config.channel_handshake_limits.min_max_accepted_htlcs,
+			});
+		}
+
+		ChannelConfigExposure {
+			channel_id: self.channel_id,
+			counterparty_node_id: self.counterparty_node_id,
+			pending_htlcs: inbound_stats.pending_htlcs + outbound_stats.pending_htlcs,
+			on_holder_tx_dust_exposure_msat,
+			on_counterparty_tx_dust_exposure_msat,
+			inbound_htlc_value_in_flight_msat: inbound_stats.pending_htlcs_value_msat,
+			violations,
+		}
+	}
+
 	pub fn get_dust_buffer_feerate(&self, outbound_feerate_update: Option<u32>) -> u32 {
 		// When calculating our exposure to dust HTLCs, we assume that the channel feerate
 		// may, at any point, increase by at least 10 sat/vB (i.e 2530 sat/kWU) or 25%,
@@ -4688,6 +5006,12 @@ impl<Signer: Sign> Channel<Signer> {
 		self.update_time_counter
 	}
 
+	/// Bumps the timestamp used in our channel_updates so that the next one we generate supersedes
+	/// any we've sent before, moving it up to `current_time` if that is further along.
+	pub fn refresh_update_time_counter(&mut self, current_time: u32) {
+		self.update_time_counter = cmp::max(self.update_time_counter + 1, current_time);
+	}
+
 	pub fn get_latest_monitor_update_id(&self) -> u64 {
 		self.latest_monitor_update_id
 	}
@@ -4770,6 +5094,76 @@ impl<Signer: Sign> Channel<Signer> {
 		self.channel_update_status = status;
 	}
 
+	/// Returns true if we've placed this channel on probation, and thus shouldn't forward any new
+	/// HTLCs through it.
+	pub fn is_on_probation(&self) -> bool {
+		self.probation_ticks_remaining.is_some()
+	}
+
+	/// Counts `strike` against this channel, placing it on probation if doing so reaches the
+	/// relevant threshold in `config`. Returns true if the channel was newly placed on probation.
+	pub(super) fn add_probation_strike(&mut self, strike: ProbationStrike, config: &ChannelProbationConfig) -> bool {
+		let (count, threshold) = match strike {
+			ProbationStrike::HTLCFailure => (&mut self.probation_strikes.htlc_failures, config.max_htlc_failures),
+			ProbationStrike::Disconnect => (&mut self.probation_strikes.disconnects, config.max_disconnects),
+			ProbationStrike::StaleFeerate => (&mut self.probation_strikes.stale_feerate_ticks, config.max_stale_feerate_ticks),
+		};
+		let threshold = match threshold {
+			Some(threshold) => threshold,
+			None => return false,
+		};
+		*count = count.saturating_add(1);
+		if *count < threshold || self.is_on_probation() { return false; }
+
+		self.probation_ticks_remaining = Some(config.probation_ticks);
+		self.probation_strikes = ProbationStrikes::default();
+		true
+	}
+
+	/// Advances the probation window and, if the channel is on probation, its remaining duration.
+	/// Returns true if the channel's probation ended.
+	pub(super) fn probation_timer_tick(&mut self, config: &ChannelProbationConfig) -> bool {
+		self.probation_strikes.window_ticks_elapsed += 1;
+		if self.probation_strikes.window_ticks_elapsed >= config.window_ticks {
+			self.probation_strikes = ProbationStrikes::default();
+		}
+		match self.probation_ticks_remaining {
+			Some(ticks) if ticks <= 1 => {
+				self.probation_ticks_remaining = None;
+				true
+			},
+			Some(ticks) => {
+				self.probation_ticks_remaining = Some(ticks - 1);
+				false
+			},
+			None => false,
+		}
+	}
+
+	/// Places this channel on probation for `ticks` timer ticks, unless it already is. Returns true
+	/// if the channel was newly placed on probation.
+	pub(super) fn start_probation(&mut self, ticks: u32) -> bool {
+		if self.is_on_probation() { return false; }
+		self.probation_ticks_remaining = Some(ticks);
+		true
+	}
+
+	/// Takes this channel off probation, if it was on it. Returns true if it was.
+	pub(super) fn end_probation(&mut self) -> bool {
+		self.probation_strikes = ProbationStrikes::default();
+		self.probation_ticks_remaining.take().is_some()
+	}
+
+	/// Gets the opaque data stored alongside this channel by extensions.
+	pub(super) fn extension_data(&self) -> &ExtensionData {
+		&self.extension_data
+	}
+
+	/// Gets the opaque data stored alongside this channel by extensions, for modification.
+	pub(super) fn extension_data_mut(&mut self) -> &mut ExtensionData {
+		&mut self.extension_data
+	}
+
 	fn check_get_channel_ready(&mut self, height: u32) -> Option<msgs::ChannelReady> {
 		if self.funding_tx_confirmation_height == 0 && self.minimum_depth != Some(0) {
 			return None;
@@ -4939,6 +5333,10 @@ impl<Signer: Sign> Channel<Signer> {
 			return Ok((Some(channel_ready), timed_out_htlcs, announcement_sigs));
 		}
 
+		let outbound_funding_timed_out = match self.outbound_funding_timeout_blocks {
+			Some(timeout_blocks) => height >= self.channel_creation_height.saturating_add(timeout_blocks),
+			None => false,
+		};
 		let non_shutdown_state = self.channel_state & (!MULTI_STATE_FLAGS);
 		if non_shutdown_state >= ChannelState::ChannelFunded as u32 ||
 		   (non_shutdown_state & ChannelState::OurChannelReady as u32) == ChannelState::OurChannelReady as u32 {
@@ -4971,6 +5369,11 @@ impl<Signer: Sign> Channel<Signer> {
 			assert!(non_shutdown_state <= ChannelState::ChannelFunded as u32);
 			assert_eq!(non_shutdown_state & ChannelState::OurChannelReady as u32, 0);
 			return Err(ClosureReason::FundingTimedOut);
+		} else if self.is_outbound() && self.funding_tx_confirmed_in.is_none() && outbound_funding_timed_out {
+			log_info!(logger, "Closing outbound channel {} as its funding transaction failed to confirm in time", log_bytes!(self.channel_id));
+			assert!(non_shutdown_state <= ChannelState::ChannelFunded as u32);
+			assert_eq!(non_shutdown_state & ChannelState::OurChannelReady as u32, 0);
+			return Err(ClosureReason::FundingTimedOut);
 		}
 
 		let announcement_sigs = if let Some((genesis_block_hash, node_pk)) = genesis_node_pk {
@@ -5350,7 +5753,8 @@ impl<Signer: Sign> Channel<Signer> {
 				my_current_per_commitment_point: dummy_pubkey,
 			})
 		};
-		msgs::ChannelReestablish {
+		#[allow(unused_mut)]
+		let mut msg = msgs::ChannelReestablish {
 			channel_id: self.channel_id(),
 			// The protocol has two different commitment number concepts - the "commitment
 			// transaction number", which starts from 0 and counts up, and the "revocation key
@@ -5371,7 +5775,16 @@ impl<Signer: Sign> Channel<Signer> {
 			// overflow here.
 			next_remote_commitment_number: INITIAL_COMMITMENT_NUMBER - self.cur_counterparty_commitment_transaction_number - 1,
 			data_loss_protect,
+		};
+		#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+		if let Some(ref overrides) = self.reestablish_overrides {
+			fn offset_by(num: u64, offset: i64) -> u64 {
+				if offset < 0 { num.saturating_sub(offset.wrapping_neg() as u64) } else { num.saturating_add(offset as u64) }
+			}
+			msg.next_local_commitment_number = offset_by(msg.next_local_commitment_number, overrides.next_local_commitment_number_offset);
+			msg.next_remote_commitment_number = offset_by(msg.next_remote_commitment_number, overrides.next_remote_commitment_number_offset);
 		}
+		msg
 	}
 
 
@@ -5391,8 +5804,11 @@ impl<Signer: Sign> Channel<Signer> {
 	///
 	/// You MUST call send_commitment prior to calling any other methods on this Channel!
 	///
+	/// `blinding_point` should be set if we are forwarding an HTLC within a blinded route, and
+	/// will be relayed to our counterparty in the `update_add_htlc`.
+	///
 	/// If an Err is returned, it's a ChannelError::Ignore!
-	pub fn send_htlc<L: Deref>(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, logger: &L) -> Result<Option<msgs::UpdateAddHTLC>, ChannelError> where L::Target: Logger {
+	pub fn send_htlc<L: Deref>(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, blinding_point: Option<PublicKey>, logger: &L) -> Result<Option<msgs::UpdateAddHTLC>, ChannelError> where L::Target: Logger {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32 | BOTH_SIDES_SHUTDOWN_MASK)) != (ChannelState::ChannelFunded as u32) {
 			return Err(ChannelError::Ignore("Cannot send HTLC until channel is fully established and we haven't started shutting down".to_owned()));
 		}
@@ -5488,6 +5904,7 @@ impl<Signer: Sign> Channel<Signer> {
 				cltv_expiry,
 				source,
 				onion_routing_packet,
+				blinding_point,
 			});
 			return Ok(None);
 		}
@@ -5499,6 +5916,7 @@ impl<Signer: Sign> Channel<Signer> {
 			cltv_expiry,
 			state: OutboundHTLCState::LocalAnnounced(Box::new(onion_routing_packet.clone())),
 			source,
+			blinding_point,
 		});
 
 		let res = msgs::UpdateAddHTLC {
@@ -5508,6 +5926,7 @@ impl<Signer: Sign> Channel<Signer> {
 			payment_hash,
 			cltv_expiry,
 			onion_routing_packet,
+			blinding_point,
 		};
 		self.next_holder_htlc_id += 1;
 
@@ -5674,7 +6093,7 @@ impl<Signer: Sign> Channel<Signer> {
 	/// Shorthand for calling send_htlc() followed by send_commitment(), see docs on those for
 	/// more info.
 	pub fn send_htlc_and_commit<L: Deref>(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, logger: &L) -> Result<Option<(msgs::UpdateAddHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, ChannelError> where L::Target: Logger {
-		match self.send_htlc(amount_msat, payment_hash, cltv_expiry, source, onion_routing_packet, logger)? {
+		match self.send_htlc(amount_msat, payment_hash, cltv_expiry, source, onion_routing_packet, None, logger)? {
 			Some(update_add_htlc) => {
 				let (commitment_signed, monitor_update) = self.send_commitment_no_status_check(logger)?;
 				Ok(Some((update_add_htlc, commitment_signed, monitor_update)))
@@ -5703,7 +6122,8 @@ impl<Signer: Sign> Channel<Signer> {
 
 	/// Begins the shutdown process, getting a message for the remote peer and returning all
 	/// holding cell HTLCs for payment failure.
-	pub fn get_shutdown<K: Deref>(&mut self, keys_provider: &K, their_features: &InitFeatures, target_feerate_sats_per_kw: Option<u32>)
+	pub fn get_shutdown<K: Deref>(&mut self, keys_provider: &K, their_features: &InitFeatures, target_feerate_sats_per_kw: Option<u32>,
+		override_shutdown_script: Option<ShutdownScript>)
 	-> Result<(msgs::Shutdown, Option<ChannelMonitorUpdate>, Vec<(HTLCSource, PaymentHash)>), APIError>
 	where K::Target: KeysInterface<Signer = Signer> {
 		for htlc in self.pending_outbound_htlcs.iter() {
@@ -5724,10 +6144,19 @@ impl<Signer: Sign> Channel<Signer> {
 			return Err(APIError::ChannelUnavailable{err: "Cannot begin shutdown while peer is disconnected or we're waiting on a monitor update, maybe force-close instead?".to_owned()});
 		}
 
+		// If we've already committed to a shutdown script (via upfront_shutdown_script), we can't
+		// pay out to a different one on close.
+		if self.shutdown_scriptpubkey.is_some() && override_shutdown_script.is_some() {
+			return Err(APIError::APIMisuseError{err: "Cannot override shutdown script for a channel with one already set".to_owned()});
+		}
+
 		let update_shutdown_script = match self.shutdown_scriptpubkey {
 			Some(_) => false,
 			None => {
-				let shutdown_scriptpubkey = keys_provider.get_shutdown_scriptpubkey();
+				let shutdown_scriptpubkey = match override_shutdown_script {
+					Some(shutdown_scriptpubkey) => shutdown_scriptpubkey,
+					None => keys_provider.get_shutdown_scriptpubkey(),
+				};
 				if !shutdown_scriptpubkey.is_compatible(their_features) {
 					return Err(APIError::IncompatibleShutdownScript { script: shutdown_scriptpubkey.clone() });
 				}
@@ -5995,10 +6424,14 @@ impl<Signer: Sign> Writeable for Channel<Signer> {
 			}
 		}
 
+		// Versions which don't know about `FailMalformedHTLC` holding cell updates read them as a
+		// `FailHTLC` with an empty error packet, with the malformed failure details written in an
+		// odd TLV below.
+		let mut holding_cell_malformed_htlcs: Vec<(u64, u16, [u8; 32])> = Vec::new();
 		(self.holding_cell_htlc_updates.len() as u64).write(writer)?;
 		for update in self.holding_cell_htlc_updates.iter() {
 			match update {
-				&HTLCUpdateAwaitingACK::AddHTLC { ref amount_msat, ref cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet } => {
+				&HTLCUpdateAwaitingACK::AddHTLC { ref amount_msat, ref cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, .. } => {
 					0u8.write(writer)?;
 					amount_msat.write(writer)?;
 					cltv_expiry.write(writer)?;
@@ -6015,6 +6448,12 @@ impl<Signer: Sign> Writeable for Channel<Signer> {
 					2u8.write(writer)?;
 					htlc_id.write(writer)?;
 					err_packet.write(writer)?;
+				},
+				&HTLCUpdateAwaitingACK::FailMalformedHTLC { htlc_id, failure_code, sha256_of_onion } => {
+					2u8.write(writer)?;
+					htlc_id.write(writer)?;
+					msgs::OnionErrorPacket { data: Vec::new() }.write(writer)?;
+					holding_cell_malformed_htlcs.push((htlc_id, failure_code, sha256_of_onion));
 				}
 			}
 		}
@@ -6132,6 +6571,23 @@ impl<Signer: Sign> Writeable for Channel<Signer> {
 			if self.holder_max_htlc_value_in_flight_msat != Self::get_holder_max_htlc_value_in_flight_msat(self.channel_value_satoshis, &old_max_in_flight_percent_config)
 			{ Some(self.holder_max_htlc_value_in_flight_msat) } else { None };
 
+		let extension_data = if self.extension_data.is_empty() { None } else { Some(&self.extension_data) };
+
+		// Blinding points are only set for HTLCs we forward within a blinded route, so we only
+		// write them out if any are present.
+		let mut pending_outbound_blinding_points: Vec<Option<PublicKey>> = Vec::new();
+		if self.pending_outbound_htlcs.iter().any(|htlc| htlc.blinding_point.is_some()) {
+			pending_outbound_blinding_points = self.pending_outbound_htlcs.iter().map(|htlc| htlc.blinding_point).collect();
+		}
+		let mut holding_cell_blinding_points: Vec<Option<PublicKey>> = Vec::new();
+		let holding_cell_blinding_point = |update: &HTLCUpdateAwaitingACK| match update {
+			HTLCUpdateAwaitingACK::AddHTLC { blinding_point, .. } => *blinding_point,
+			_ => None,
+		};
+		if self.holding_cell_htlc_updates.iter().any(|update| holding_cell_blinding_point(update).is_some()) {
+			holding_cell_blinding_points = self.holding_cell_htlc_updates.iter().map(holding_cell_blinding_point).collect();
+		}
+
 		write_tlv_fields!(writer, {
 			(0, self.announcement_sigs, option),
 			// minimum_depth and counterparty_selected_channel_reserve_satoshis used to have a
@@ -6154,6 +6610,12 @@ impl<Signer: Sign> Writeable for Channel<Signer> {
 			(17, self.announcement_sigs_state, required),
 			(19, self.latest_inbound_scid_alias, option),
 			(21, self.outbound_scid_alias, required),
+			(23, self.outbound_funding_timeout_blocks, option),
+			(25, self.probation_ticks_remaining, option),
+			(27, extension_data, option),
+			(29, pending_outbound_blinding_points, vec_type),
+			(31, holding_cell_blinding_points, vec_type),
+			(33, holding_cell_malformed_htlcs, vec_type),
 		});
 
 		Ok(())
@@ -6236,6 +6698,7 @@ impl<'a, Signer: Sign, K: Deref> ReadableArgs<(&'a K, u32)> for Channel<Signer>
 				cltv_expiry: Readable::read(reader)?,
 				payment_hash: Readable::read(reader)?,
 				source: Readable::read(reader)?,
+				blinding_point: None,
 				state: match <u8 as Readable>::read(reader)? {
 					0 => OutboundHTLCState::LocalAnnounced(Box::new(Readable::read(reader)?)),
 					1 => OutboundHTLCState::Committed,
@@ -6266,6 +6729,7 @@ impl<'a, Signer: Sign, K: Deref> ReadableArgs<(&'a K, u32)> for Channel<Signer>
 					payment_hash: Readable::read(reader)?,
 					source: Readable::read(reader)?,
 					onion_routing_packet: Readable::read(reader)?,
+					blinding_point: None,
 				},
 				1 => HTLCUpdateAwaitingACK::ClaimHTLC {
 					payment_preimage: Readable::read(reader)?,
@@ -6411,6 +6875,12 @@ impl<'a, Signer: Sign, K: Deref> ReadableArgs<(&'a K, u32)> for Channel<Signer>
 		let mut announcement_sigs_state = Some(AnnouncementSigsState::NotSent);
 		let mut latest_inbound_scid_alias = None;
 		let mut outbound_scid_alias = None;
+		let mut outbound_funding_timeout_blocks = None;
+		let mut probation_ticks_remaining = None;
+		let mut extension_data: Option<ExtensionData> = None;
+		let mut pending_outbound_blinding_points_opt: Option<Vec<Option<PublicKey>>> = None;
+		let mut holding_cell_blinding_points_opt: Option<Vec<Option<PublicKey>>> = None;
+		let mut holding_cell_malformed_htlcs: Option<Vec<(u64, u16, [u8; 32])>> = None;
 
 		read_tlv_fields!(reader, {
 			(0, announcement_sigs, option),
@@ -6428,8 +6898,48 @@ impl<'a, Signer: Sign, K: Deref> ReadableArgs<(&'a K, u32)> for Channel<Signer>
 			(17, announcement_sigs_state, option),
 			(19, latest_inbound_scid_alias, option),
 			(21, outbound_scid_alias, option),
+			(23, outbound_funding_timeout_blocks, option),
+			(25, probation_ticks_remaining, option),
+			(27, extension_data, option),
+			(29, pending_outbound_blinding_points_opt, vec_type),
+			(31, holding_cell_blinding_points_opt, vec_type),
+			(33, holding_cell_malformed_htlcs, vec_type),
 		});
 
+		if let Some(blinding_points) = pending_outbound_blinding_points_opt {
+			if !blinding_points.is_empty() {
+				if blinding_points.len() != pending_outbound_htlcs.len() {
+					return Err(DecodeError::InvalidValue);
+				}
+				for (htlc, blinding_point) in pending_outbound_htlcs.iter_mut().zip(blinding_points) {
+					htlc.blinding_point = blinding_point;
+				}
+			}
+		}
+		if let Some(blinding_points) = holding_cell_blinding_points_opt {
+			if !blinding_points.is_empty() {
+				if blinding_points.len() != holding_cell_htlc_updates.len() {
+					return Err(DecodeError::InvalidValue);
+				}
+				for (update, blinding_point_read) in holding_cell_htlc_updates.iter_mut().zip(blinding_points) {
+					if let HTLCUpdateAwaitingACK::AddHTLC { ref mut blinding_point, .. } = update {
+						*blinding_point = blinding_point_read;
+					}
+				}
+			}
+		}
+		if let Some(malformed_htlcs) = holding_cell_malformed_htlcs {
+			for (malformed_htlc_id, failure_code, sha256_of_onion) in malformed_htlcs {
+				let update = holding_cell_htlc_updates.iter_mut().find(|update| match update {
+					HTLCUpdateAwaitingACK::FailHTLC { htlc_id, .. } => *htlc_id == malformed_htlc_id,
+					_ => false,
+				}).ok_or(DecodeError::InvalidValue)?;
+				*update = HTLCUpdateAwaitingACK::FailMalformedHTLC {
+					htlc_id: malformed_htlc_id, failure_code, sha256_of_onion,
+				};
+			}
+		}
+
 		if let Some(preimages) = preimages_opt {
 			let mut iter = preimages.into_iter();
 			for htlc in pending_outbound_htlcs.iter_mut() {
@@ -6527,6 +7037,7 @@ impl<'a, Signer: Sign, K: Deref> ReadableArgs<(&'a K, u32)> for Channel<Signer>
 			funding_tx_confirmation_height,
 			short_channel_id,
 			channel_creation_height: channel_creation_height.unwrap(),
+			outbound_funding_timeout_blocks,
 
 			counterparty_dust_limit_satoshis,
 			holder_dust_limit_satoshis,
@@ -6553,6 +7064,9 @@ impl<'a, Signer: Sign, K: Deref> ReadableArgs<(&'a K, u32)> for Channel<Signer>
 			commitment_secrets,
 
 			channel_update_status,
+			probation_strikes: ProbationStrikes::default(),
+			probation_ticks_remaining,
+			extension_data: extension_data.unwrap_or_default(),
 			closing_signed_in_flight: false,
 
 			announcement_sigs,
@@ -6563,6 +7077,9 @@ impl<'a, Signer: Sign, K: Deref> ReadableArgs<(&'a K, u32)> for Channel<Signer>
 			next_remote_commitment_tx_fee_info_cached: Mutex::new(None),
 
 			workaround_lnd_bug_4006: None,
+			last_reestablish_plan: None,
+			#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+			reestablish_overrides: None,
 
 			latest_inbound_scid_alias,
 			// Later in the ChannelManager deserialization phase we scan for channels and assign scid aliases if its missing
@@ -6587,10 +7104,10 @@ mod tests {
 	use hex;
 	use ln::PaymentHash;
 	use ln::channelmanager::{HTLCSource, PaymentId};
-	use ln::channel::{Channel, InboundHTLCOutput, OutboundHTLCOutput, InboundHTLCState, OutboundHTLCState, HTLCCandidate, HTLCInitiator};
+	use ln::channel::{Channel, InboundHTLCOutput, OutboundHTLCOutput, InboundHTLCState, OutboundHTLCState, HTLCCandidate, HTLCInitiator, HTLCUpdateAwaitingACK};
 	use ln::channel::{MAX_FUNDING_SATOSHIS_NO_WUMBO, TOTAL_BITCOIN_SUPPLY_SATOSHIS, MIN_THEIR_CHAN_RESERVE_SATOSHIS};
 	use ln::features::{InitFeatures, ChannelTypeFeatures};
-	use ln::msgs::{ChannelUpdate, DataLossProtect, DecodeError, OptionalField, UnsignedChannelUpdate, MAX_VALUE_MSAT};
+	use ln::msgs::{ChannelUpdate, DataLossProtect, DecodeError, OnionErrorPacket, OptionalField, UnsignedChannelUpdate, MAX_VALUE_MSAT};
 	use ln::script::ShutdownScript;
 	use ln::chan_utils;
 	use ln::chan_utils::{htlc_success_tx_weight, htlc_timeout_tx_weight};
@@ -6601,6 +7118,7 @@ mod tests {
 	use util::config::UserConfig;
 	use util::enforcing_trait_impls::EnforcingSigner;
 	use util::errors::APIError;
+	use util::ser::{ReadableArgs, Writeable};
 	use util::test_utils;
 	use util::test_utils::OnGetShutdownScriptpubkey;
 	use bitcoin::secp256k1::{Secp256k1, ecdsa::Signature, Scalar};
@@ -6771,6 +7289,7 @@ mod tests {
 			payment_hash: PaymentHash(Sha256::hash(&[43; 32]).into_inner()),
 			cltv_expiry: 200000000,
 			state: OutboundHTLCState::Committed,
+			blinding_point: None,
 			source: HTLCSource::OutboundRoute {
 				path: Vec::new(),
 				session_priv: SecretKey::from_slice(&hex::decode("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").unwrap()[..]).unwrap(),
@@ -6910,6 +7429,42 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn holding_cell_fail_malformed_htlc_serialization() {
+		// Malformed failures in the holding cell are written as legacy `FailHTLC`s, with the
+		// failure details in an odd TLV, so that we can still downgrade. Check they round-trip.
+		let feeest = LowerBoundedFeeEstimator::new(&TestFeeEstimator{fee_est: 15000});
+		let secp_ctx = Secp256k1::new();
+		let seed = [42; 32];
+		let network = Network::Testnet;
+		let keys_provider = test_utils::TestKeysInterface::new(&seed, network);
+		let node_b_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		let config = UserConfig::default();
+		let mut chan = Channel::<EnforcingSigner>::new_outbound(&feeest, &&keys_provider, node_b_node_id, &InitFeatures::known(), 10000000, 100000, 42, &config, 0, 42).unwrap();
+
+		chan.holding_cell_htlc_updates.push(HTLCUpdateAwaitingACK::FailHTLC {
+			htlc_id: 0, err_packet: OnionErrorPacket { data: vec![1; 32] },
+		});
+		chan.holding_cell_htlc_updates.push(HTLCUpdateAwaitingACK::FailMalformedHTLC {
+			htlc_id: 1, failure_code: 0x8000 | 0x4000 | 24, sha256_of_onion: [2; 32],
+		});
+
+		let encoded_chan = chan.encode();
+		let decoded_chan = Channel::<EnforcingSigner>::read(&mut &encoded_chan[..], (&&keys_provider, 0)).unwrap();
+		assert_eq!(decoded_chan.holding_cell_htlc_updates.len(), 2);
+		match decoded_chan.holding_cell_htlc_updates[0] {
+			HTLCUpdateAwaitingACK::FailHTLC { htlc_id: 0, ref err_packet } => assert_eq!(err_packet.data, vec![1; 32]),
+			_ => panic!("Unexpected holding cell update"),
+		}
+		match decoded_chan.holding_cell_htlc_updates[1] {
+			HTLCUpdateAwaitingACK::FailMalformedHTLC { htlc_id: 1, failure_code, sha256_of_onion } => {
+				assert_eq!(failure_code, 0x8000 | 0x4000 | 24);
+				assert_eq!(sha256_of_onion, [2; 32]);
+			},
+			_ => panic!("Unexpected holding cell update"),
+		}
+	}
+
 	#[test]
 	fn test_configured_holder_max_htlc_value_in_flight() {
 		let feeest = LowerBoundedFeeEstimator::new(&TestFeeEstimator{fee_est: 15000});
@@ -0,0 +1,857 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Server-side support for selling inbound liquidity, allowing clients to purchase channels from
+//! us in the style of LSPS1.
+//!
+//! Clients communicate with a [`ChannelRequestServer`] over custom messages, which should be
+//! wired into the [`PeerManager`] as its [`CustomMessageHandler`]:
+//!  * A [`GetInfoRequest`] is answered with our [`ChannelRequestOptions`], describing the channels
+//!    we're willing to sell and what we charge for them.
+//!  * A [`CreateOrderRequest`] creates a [`ChannelOrder`] for a channel of a given size, answered
+//!    with an [`OrderResponse`] describing how to pay for it.
+//!  * A [`GetOrderRequest`] is answered with the current state of an order.
+//!
+//! Payment details for each order are provided by a [`ChannelOrderProvider`], which is also
+//! responsible for opening the channel once the order is paid. The application must tell the
+//! server about payments it receives via [`ChannelRequestServer::lightning_payment_received`] or
+//! [`ChannelRequestServer::onchain_payment_received`].
+//!
+//! Orders are persisted via a [`KVStorePersister`] under [`CHANNEL_ORDERS_KEY`] whenever they
+//! change, and may be restored after a restart with [`ChannelRequestServer::from_orders`]. Orders
+//! which were paid but whose channel could not yet be opened are retried on each call to
+//! [`ChannelRequestServer::timer_tick_occurred`].
+//!
+//! [`PeerManager`]: crate::ln::peer_handler::PeerManager
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::secp256k1::PublicKey;
+
+use chain::keysinterface::KeysInterface;
+use ln::PaymentHash;
+use ln::msgs::{DecodeError, ErrorAction, LightningError};
+use ln::peer_handler::CustomMessageHandler;
+use ln::wire::{CustomMessageReader, Type};
+use util::errors::{APIError, ErrorClassification, Retryability};
+use util::logger::Logger;
+use util::persist::KVStorePersister;
+use util::ser::{Readable, Writeable, Writer};
+
+use io;
+use prelude::*;
+use sync::Mutex;
+use core::ops::Deref;
+
+/// The key under which the [`ChannelOrders`] of a [`ChannelRequestServer`] are persisted.
+pub const CHANNEL_ORDERS_KEY: &str = "channel_orders";
+
+/// The custom message type of a [`GetInfoRequest`].
+pub const GET_INFO_REQUEST_TYPE: u16 = 37901;
+/// The custom message type of a [`GetInfoResponse`].
+pub const GET_INFO_RESPONSE_TYPE: u16 = 37903;
+/// The custom message type of a [`CreateOrderRequest`].
+pub const CREATE_ORDER_REQUEST_TYPE: u16 = 37905;
+/// The custom message type of a [`GetOrderRequest`].
+pub const GET_ORDER_REQUEST_TYPE: u16 = 37907;
+/// The custom message type of an [`OrderResponse`].
+pub const ORDER_RESPONSE_TYPE: u16 = 37909;
+/// The custom message type of a [`ChannelRequestError`].
+pub const CHANNEL_REQUEST_ERROR_TYPE: u16 = 37911;
+
+/// The [`ChannelRequestError::code`] when a request's parameters are outside of our
+/// [`ChannelRequestOptions`].
+pub const ERROR_CODE_INVALID_PARAMS: u16 = 1;
+/// The [`ChannelRequestError::code`] when a requested order doesn't exist.
+pub const ERROR_CODE_UNKNOWN_ORDER: u16 = 2;
+/// The [`ChannelRequestError::code`] when we failed to create an order for reasons unrelated to
+/// the request.
+pub const ERROR_CODE_INTERNAL: u16 = 3;
+
+/// The channels a [`ChannelRequestServer`] is willing to sell, and at what price.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelRequestOptions {
+	/// The smallest channel we're willing to open, in satoshis.
+	pub min_channel_size_sat: u64,
+	/// The largest channel we're willing to open, in satoshis.
+	pub max_channel_size_sat: u64,
+	/// The fixed part of the fee for a channel, in satoshis.
+	pub base_fee_sat: u64,
+	/// The part of the fee for a channel proportional to its size, in millionths of a satoshi per
+	/// satoshi.
+	pub fee_proportional_millionths: u32,
+	/// Whether we're willing to open zero-confirmation channels, allowing the client to use the
+	/// channel before the funding transaction confirms.
+	pub zero_conf_supported: bool,
+	/// How long an order may remain unpaid before it expires, in seconds.
+	pub order_expiry_secs: u32,
+}
+
+impl_writeable_tlv_based!(ChannelRequestOptions, {
+	(0, min_channel_size_sat, required),
+	(2, max_channel_size_sat, required),
+	(4, base_fee_sat, required),
+	(6, fee_proportional_millionths, required),
+	(8, zero_conf_supported, required),
+	(10, order_expiry_secs, required),
+});
+
+impl ChannelRequestOptions {
+	/// The fee we charge for a channel of the given size, in millisatoshis, or `None` on overflow.
+	pub fn fee_msat(&self, channel_size_sat: u64) -> Option<u64> {
+		let proportional_fee_msat = channel_size_sat.checked_mul(self.fee_proportional_millionths as u64)? / 1000;
+		self.base_fee_sat.checked_mul(1000)?.checked_add(proportional_fee_msat)
+	}
+}
+
+/// How a client may pay for a [`ChannelOrder`], as provided by
+/// [`ChannelOrderProvider::get_payment_details`]. At least one means of payment must be given.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrderPaymentDetails {
+	/// A BOLT 11 invoice for the order's fee, if it may be paid over Lightning.
+	pub bolt11_invoice: Option<String>,
+	/// The payment hash of [`Self::bolt11_invoice`], used to match the payment to the order in
+	/// [`ChannelRequestServer::lightning_payment_received`].
+	pub payment_hash: Option<PaymentHash>,
+	/// The on-chain output script to pay the order's fee to, if it may be paid on-chain.
+	pub onchain_script: Option<Script>,
+}
+
+impl_writeable_tlv_based!(OrderPaymentDetails, {
+	(0, bolt11_invoice, option),
+	(2, payment_hash, option),
+	(4, onchain_script, option),
+});
+
+/// The state of a [`ChannelOrder`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OrderState {
+	/// The order is waiting to be paid.
+	AwaitingPayment,
+	/// The order has been paid, but the channel has not yet been opened, e.g. because the client
+	/// is not currently connected. Opening the channel is retried on each call to
+	/// [`ChannelRequestServer::timer_tick_occurred`].
+	Paid,
+	/// The order has been paid and the channel opened.
+	ChannelOpened {
+		/// The temporary channel id returned by [`ChannelOrderProvider::open_channel`].
+		temporary_channel_id: [u8; 32],
+	},
+	/// The order was not paid before it expired.
+	Expired,
+	/// The order was paid but the channel cannot be opened. The client should be refunded.
+	Failed {
+		/// A description of why the channel could not be opened.
+		err: String,
+	},
+}
+
+impl_writeable_tlv_based_enum!(OrderState,
+	(0, AwaitingPayment) => {},
+	(2, Paid) => {},
+	(4, ChannelOpened) => { (0, temporary_channel_id, required) },
+	(6, Expired) => {},
+	(8, Failed) => { (0, err, required) };
+);
+
+/// A client's order for a channel, as communicated to the client in an [`OrderResponse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrderInfo {
+	/// The unique identifier of the order.
+	pub order_id: [u8; 32],
+	/// The size of the channel to open, in satoshis.
+	pub channel_size_sat: u64,
+	/// Whether the channel will be announced to the network.
+	pub announce_channel: bool,
+	/// Whether the channel will be usable before its funding transaction confirms.
+	pub zero_conf: bool,
+	/// The fee to pay for the channel, in millisatoshis.
+	pub fee_msat: u64,
+	/// The time after which the order may no longer be paid, in seconds since the UNIX epoch.
+	pub expires_at: u64,
+	/// How to pay for the order.
+	pub payment: OrderPaymentDetails,
+	/// The current state of the order.
+	pub state: OrderState,
+}
+
+impl_writeable_tlv_based!(OrderInfo, {
+	(0, order_id, required),
+	(2, channel_size_sat, required),
+	(4, announce_channel, required),
+	(6, zero_conf, required),
+	(8, fee_msat, required),
+	(10, expires_at, required),
+	(12, payment, required),
+	(14, state, required),
+});
+
+/// A client's order for a channel, as tracked by a [`ChannelRequestServer`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelOrder {
+	/// The client which placed the order.
+	pub counterparty_node_id: PublicKey,
+	/// The `user_channel_id` given to [`ChannelOrderProvider::open_channel`] for the order's
+	/// channel.
+	pub user_channel_id: u64,
+	/// The order as communicated to the client.
+	pub info: OrderInfo,
+}
+
+impl_writeable_tlv_based!(ChannelOrder, {
+	(0, counterparty_node_id, required),
+	(2, user_channel_id, required),
+	(4, info, required),
+});
+
+/// All orders tracked by a [`ChannelRequestServer`], as persisted under [`CHANNEL_ORDERS_KEY`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelOrders {
+	/// The orders, in no particular order.
+	pub orders: Vec<ChannelOrder>,
+}
+
+impl_writeable_tlv_based!(ChannelOrders, {
+	(0, orders, vec_type),
+});
+
+/// A request for our [`ChannelRequestOptions`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetInfoRequest {
+	/// An identifier chosen by the client, echoed in our response.
+	pub request_id: u64,
+}
+
+impl_writeable_tlv_based!(GetInfoRequest, {
+	(0, request_id, required),
+});
+
+/// A response to a [`GetInfoRequest`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetInfoResponse {
+	/// The identifier of the [`GetInfoRequest`] this is a response to.
+	pub request_id: u64,
+	/// The channels we're willing to sell.
+	pub options: ChannelRequestOptions,
+}
+
+impl_writeable_tlv_based!(GetInfoResponse, {
+	(0, request_id, required),
+	(2, options, required),
+});
+
+/// A request to purchase a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreateOrderRequest {
+	/// An identifier chosen by the client, echoed in our response.
+	pub request_id: u64,
+	/// The size of the channel to open, in satoshis.
+	pub channel_size_sat: u64,
+	/// Whether the channel should be announced to the network.
+	pub announce_channel: bool,
+	/// Whether the channel should be usable before its funding transaction confirms.
+	pub zero_conf: bool,
+}
+
+impl_writeable_tlv_based!(CreateOrderRequest, {
+	(0, request_id, required),
+	(2, channel_size_sat, required),
+	(4, announce_channel, required),
+	(6, zero_conf, required),
+});
+
+/// A request for the current state of an order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetOrderRequest {
+	/// An identifier chosen by the client, echoed in our response.
+	pub request_id: u64,
+	/// The [`OrderInfo::order_id`] of the order.
+	pub order_id: [u8; 32],
+}
+
+impl_writeable_tlv_based!(GetOrderRequest, {
+	(0, request_id, required),
+	(2, order_id, required),
+});
+
+/// The current state of an order, sent in response to a [`CreateOrderRequest`] or
+/// [`GetOrderRequest`], or unprompted when the order's state changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrderResponse {
+	/// The identifier of the request this is a response to, or `None` if unprompted.
+	pub request_id: Option<u64>,
+	/// The order.
+	pub order: OrderInfo,
+}
+
+impl_writeable_tlv_based!(OrderResponse, {
+	(0, request_id, option),
+	(2, order, required),
+});
+
+/// An error in response to a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelRequestError {
+	/// The identifier of the request this is a response to.
+	pub request_id: u64,
+	/// One of the `ERROR_CODE_*` constants in this module.
+	pub code: u16,
+	/// A human-readable description of the error.
+	pub message: String,
+}
+
+impl_writeable_tlv_based!(ChannelRequestError, {
+	(0, request_id, required),
+	(2, code, required),
+	(4, message, required),
+});
+
+/// A message exchanged between a client and a [`ChannelRequestServer`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelRequestMessage {
+	/// See [`GetInfoRequest`].
+	GetInfoRequest(GetInfoRequest),
+	/// See [`GetInfoResponse`].
+	GetInfoResponse(GetInfoResponse),
+	/// See [`CreateOrderRequest`].
+	CreateOrderRequest(CreateOrderRequest),
+	/// See [`GetOrderRequest`].
+	GetOrderRequest(GetOrderRequest),
+	/// See [`OrderResponse`].
+	OrderResponse(OrderResponse),
+	/// See [`ChannelRequestError`].
+	Error(ChannelRequestError),
+}
+
+impl Type for ChannelRequestMessage {
+	fn type_id(&self) -> u16 {
+		match *self {
+			ChannelRequestMessage::GetInfoRequest(_) => GET_INFO_REQUEST_TYPE,
+			ChannelRequestMessage::GetInfoResponse(_) => GET_INFO_RESPONSE_TYPE,
+			ChannelRequestMessage::CreateOrderRequest(_) => CREATE_ORDER_REQUEST_TYPE,
+			ChannelRequestMessage::GetOrderRequest(_) => GET_ORDER_REQUEST_TYPE,
+			ChannelRequestMessage::OrderResponse(_) => ORDER_RESPONSE_TYPE,
+			ChannelRequestMessage::Error(_) => CHANNEL_REQUEST_ERROR_TYPE,
+		}
+	}
+}
+
+impl Writeable for ChannelRequestMessage {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		match *self {
+			ChannelRequestMessage::GetInfoRequest(ref msg) => msg.write(writer),
+			ChannelRequestMessage::GetInfoResponse(ref msg) => msg.write(writer),
+			ChannelRequestMessage::CreateOrderRequest(ref msg) => msg.write(writer),
+			ChannelRequestMessage::GetOrderRequest(ref msg) => msg.write(writer),
+			ChannelRequestMessage::OrderResponse(ref msg) => msg.write(writer),
+			ChannelRequestMessage::Error(ref msg) => msg.write(writer),
+		}
+	}
+}
+
+/// Reads a [`ChannelRequestMessage`] of the given custom message type, or `Ok(None)` if the type
+/// is not one of ours.
+pub fn read_channel_request_message<R: io::Read>(message_type: u16, buffer: &mut R) -> Result<Option<ChannelRequestMessage>, DecodeError> {
+	Ok(Some(match message_type {
+		GET_INFO_REQUEST_TYPE => ChannelRequestMessage::GetInfoRequest(Readable::read(buffer)?),
+		GET_INFO_RESPONSE_TYPE => ChannelRequestMessage::GetInfoResponse(Readable::read(buffer)?),
+		CREATE_ORDER_REQUEST_TYPE => ChannelRequestMessage::CreateOrderRequest(Readable::read(buffer)?),
+		GET_ORDER_REQUEST_TYPE => ChannelRequestMessage::GetOrderRequest(Readable::read(buffer)?),
+		ORDER_RESPONSE_TYPE => ChannelRequestMessage::OrderResponse(Readable::read(buffer)?),
+		CHANNEL_REQUEST_ERROR_TYPE => ChannelRequestMessage::Error(Readable::read(buffer)?),
+		_ => return Ok(None),
+	}))
+}
+
+/// Provides the means of paying for orders and opens the channels purchased from a
+/// [`ChannelRequestServer`].
+pub trait ChannelOrderProvider {
+	/// Gets the means for the client to pay `amount_msat` for the order with the given id within
+	/// `expiry_secs` seconds, e.g. by creating an invoice or deriving a fresh on-chain address.
+	///
+	/// If `Err` is returned, the order is not created.
+	fn get_payment_details(&self, order_id: &[u8; 32], amount_msat: u64, expiry_secs: u32) -> Result<OrderPaymentDetails, ()>;
+
+	/// Opens a channel to the client for a paid order, e.g. via
+	/// [`ChannelManager::create_channel`], returning its temporary channel id.
+	///
+	/// If `zero_conf` is set, the channel should be made usable before its funding transaction
+	/// confirms, and if `announce_channel` is set it should be announced to the network.
+	///
+	/// Errors with a [`Retryability`] of [`Retryability::Never`] fail the order, while others
+	/// cause the channel open to be retried on the next call to
+	/// [`ChannelRequestServer::timer_tick_occurred`].
+	///
+	/// [`ChannelManager::create_channel`]: crate::ln::channelmanager::ChannelManager::create_channel
+	fn open_channel(
+		&self, counterparty_node_id: &PublicKey, channel_value_satoshis: u64, user_channel_id: u64,
+		zero_conf: bool, announce_channel: bool
+	) -> Result<[u8; 32], APIError>;
+}
+
+/// An error when reporting a payment to a [`ChannelRequestServer`], in which case the payment
+/// should be failed back or refunded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OrderPaymentError {
+	/// The payment does not correspond to any order.
+	UnknownOrder,
+	/// The order is not awaiting payment, e.g. because it was already paid or has expired.
+	NotAwaitingPayment,
+	/// The payment is for less than the order's fee.
+	Underpaid,
+}
+
+struct OrderBook {
+	orders: HashMap<[u8; 32], ChannelOrder>,
+	/// The latest time passed to [`ChannelRequestServer::timer_tick_occurred`].
+	now: u64,
+}
+
+/// Sells channels to clients as described in the [module-level documentation].
+///
+/// [module-level documentation]: crate::ln::channel_request
+pub struct ChannelRequestServer<P: Deref, K: Deref, S: Deref, L: Deref>
+where
+	P::Target: ChannelOrderProvider,
+	K::Target: KeysInterface,
+	S::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	options: ChannelRequestOptions,
+	provider: P,
+	keys_manager: K,
+	persister: S,
+	logger: L,
+	order_book: Mutex<OrderBook>,
+	pending_messages: Mutex<Vec<(PublicKey, ChannelRequestMessage)>>,
+}
+
+impl<P: Deref, K: Deref, S: Deref, L: Deref> ChannelRequestServer<P, K, S, L>
+where
+	P::Target: ChannelOrderProvider,
+	K::Target: KeysInterface,
+	S::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	/// Creates a new server, without any orders, selling channels according to `options`.
+	pub fn new(options: ChannelRequestOptions, provider: P, keys_manager: K, persister: S, logger: L) -> Self {
+		Self::from_orders(options, provider, keys_manager, persister, logger, ChannelOrders { orders: Vec::new() })
+	}
+
+	/// Restores a server from the [`ChannelOrders`] it persisted under [`CHANNEL_ORDERS_KEY`].
+	pub fn from_orders(
+		options: ChannelRequestOptions, provider: P, keys_manager: K, persister: S, logger: L,
+		orders: ChannelOrders
+	) -> Self {
+		let orders = orders.orders.into_iter().map(|order| (order.info.order_id, order)).collect();
+		Self {
+			options, provider, keys_manager, persister, logger,
+			order_book: Mutex::new(OrderBook { orders, now: 0 }),
+			pending_messages: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Gets the order with the given id, if any.
+	pub fn get_order(&self, order_id: &[u8; 32]) -> Option<ChannelOrder> {
+		self.order_book.lock().unwrap().orders.get(order_id).cloned()
+	}
+
+	/// Gets all orders we're tracking.
+	pub fn list_orders(&self) -> Vec<ChannelOrder> {
+		self.order_book.lock().unwrap().orders.values().cloned().collect()
+	}
+
+	/// Stops tracking the order with the given id, e.g. once its channel has been opened or the
+	/// client has been refunded, returning it if it existed.
+	pub fn remove_order(&self, order_id: &[u8; 32]) -> Result<Option<ChannelOrder>, io::Error> {
+		let mut order_book = self.order_book.lock().unwrap();
+		let order = order_book.orders.remove(order_id);
+		if order.is_some() {
+			self.persist_orders(&order_book)?;
+		}
+		Ok(order)
+	}
+
+	/// Reports a Lightning payment we received, e.g. via [`Event::PaymentReceived`], returning the
+	/// id of the order it paid for.
+	///
+	/// If `Ok` is returned the payment should be claimed and the order's channel will be opened,
+	/// otherwise it should be failed back.
+	///
+	/// [`Event::PaymentReceived`]: crate::util::events::Event::PaymentReceived
+	pub fn lightning_payment_received(&self, payment_hash: &PaymentHash, amount_msat: u64) -> Result<[u8; 32], OrderPaymentError> {
+		let order_id = self.order_book.lock().unwrap().orders.values()
+			.find(|order| order.info.payment.payment_hash.as_ref() == Some(payment_hash))
+			.map(|order| order.info.order_id)
+			.ok_or(OrderPaymentError::UnknownOrder)?;
+		self.payment_received(&order_id, amount_msat)?;
+		Ok(order_id)
+	}
+
+	/// Reports an on-chain payment of `amount_sat` to the [`OrderPaymentDetails::onchain_script`]
+	/// of the order with the given id, once the application considers it sufficiently confirmed.
+	///
+	/// If `Ok` is returned the order's channel will be opened, otherwise the payment should be
+	/// refunded.
+	pub fn onchain_payment_received(&self, order_id: &[u8; 32], amount_sat: u64) -> Result<(), OrderPaymentError> {
+		match self.order_book.lock().unwrap().orders.get(order_id) {
+			Some(order) if order.info.payment.onchain_script.is_some() => {},
+			_ => return Err(OrderPaymentError::UnknownOrder),
+		}
+		self.payment_received(order_id, amount_sat.saturating_mul(1000))
+	}
+
+	fn payment_received(&self, order_id: &[u8; 32], amount_msat: u64) -> Result<(), OrderPaymentError> {
+		let mut order_book = self.order_book.lock().unwrap();
+		let now = order_book.now;
+		{
+			let order = order_book.orders.get_mut(order_id).ok_or(OrderPaymentError::UnknownOrder)?;
+			if order.info.state != OrderState::AwaitingPayment || order.info.expires_at < now {
+				return Err(OrderPaymentError::NotAwaitingPayment);
+			}
+			if amount_msat < order.info.fee_msat {
+				return Err(OrderPaymentError::Underpaid);
+			}
+			log_info!(self.logger, "Order {} was paid, opening a {} sat channel to {}",
+				log_bytes!(*order_id), order.info.channel_size_sat, log_pubkey!(order.counterparty_node_id));
+			order.info.state = OrderState::Paid;
+		}
+		self.open_channel(&mut order_book, order_id);
+		if let Err(e) = self.persist_orders(&order_book) {
+			log_error!(self.logger, "Failed to persist channel orders: {}", e);
+		}
+		Ok(())
+	}
+
+	/// Attempts to open the channel for the paid order with the given id, notifying the client of
+	/// any change in the order's state.
+	fn open_channel(&self, order_book: &mut OrderBook, order_id: &[u8; 32]) {
+		let order = order_book.orders.get_mut(order_id).unwrap();
+		debug_assert_eq!(order.info.state, OrderState::Paid);
+		match self.provider.open_channel(&order.counterparty_node_id, order.info.channel_size_sat,
+			order.user_channel_id, order.info.zero_conf, order.info.announce_channel)
+		{
+			Ok(temporary_channel_id) => {
+				order.info.state = OrderState::ChannelOpened { temporary_channel_id };
+			},
+			Err(e) => {
+				log_error!(self.logger, "Failed to open channel for order {}: {:?}", log_bytes!(*order_id), e);
+				if e.retryability() == Retryability::Never {
+					order.info.state = OrderState::Failed { err: format!("{:?}", e) };
+				}
+			},
+		}
+		if order.info.state != OrderState::Paid {
+			self.pending_messages.lock().unwrap().push((order.counterparty_node_id,
+				ChannelRequestMessage::OrderResponse(OrderResponse { request_id: None, order: order.info.clone() })));
+		}
+	}
+
+	/// Expires unpaid orders and retries opening channels for paid orders whose channel could not
+	/// yet be opened. `now` is the current time in seconds since the UNIX epoch.
+	///
+	/// Should be called regularly, e.g. once a minute, as well as when a client connects.
+	pub fn timer_tick_occurred(&self, now: u64) {
+		let mut order_book = self.order_book.lock().unwrap();
+		order_book.now = core::cmp::max(order_book.now, now);
+		let mut changed = false;
+		let mut paid_orders = Vec::new();
+		for order in order_book.orders.values_mut() {
+			match order.info.state {
+				OrderState::AwaitingPayment if order.info.expires_at < now => {
+					log_debug!(self.logger, "Order {} expired without being paid", log_bytes!(order.info.order_id));
+					order.info.state = OrderState::Expired;
+					self.pending_messages.lock().unwrap().push((order.counterparty_node_id,
+						ChannelRequestMessage::OrderResponse(OrderResponse { request_id: None, order: order.info.clone() })));
+					changed = true;
+				},
+				OrderState::Paid => paid_orders.push(order.info.order_id),
+				_ => {},
+			}
+		}
+		for order_id in paid_orders.iter() {
+			self.open_channel(&mut order_book, order_id);
+			changed |= order_book.orders.get(order_id).unwrap().info.state != OrderState::Paid;
+		}
+		if changed {
+			if let Err(e) = self.persist_orders(&order_book) {
+				log_error!(self.logger, "Failed to persist channel orders: {}", e);
+			}
+		}
+	}
+
+	fn persist_orders(&self, order_book: &OrderBook) -> Result<(), io::Error> {
+		let orders = ChannelOrders { orders: order_book.orders.values().cloned().collect() };
+		self.persister.persist(CHANNEL_ORDERS_KEY, &orders)
+	}
+
+	fn create_order(&self, counterparty_node_id: &PublicKey, msg: &CreateOrderRequest) -> Result<OrderInfo, ChannelRequestError> {
+		let invalid_params = |message: &str| ChannelRequestError {
+			request_id: msg.request_id, code: ERROR_CODE_INVALID_PARAMS, message: message.to_owned(),
+		};
+		let internal_error = |message: &str| ChannelRequestError {
+			request_id: msg.request_id, code: ERROR_CODE_INTERNAL, message: message.to_owned(),
+		};
+		if msg.channel_size_sat < self.options.min_channel_size_sat || msg.channel_size_sat > self.options.max_channel_size_sat {
+			return Err(invalid_params("Channel size is out of range"));
+		}
+		if msg.zero_conf && !self.options.zero_conf_supported {
+			return Err(invalid_params("Zero-conf channels are not supported"));
+		}
+		let fee_msat = self.options.fee_msat(msg.channel_size_sat).ok_or(invalid_params("Channel size is out of range"))?;
+
+		let order_id = self.keys_manager.get_secure_random_bytes();
+		let payment = self.provider.get_payment_details(&order_id, fee_msat, self.options.order_expiry_secs)
+			.map_err(|_| internal_error("Failed to create payment details"))?;
+		if payment.bolt11_invoice.is_none() && payment.onchain_script.is_none() {
+			return Err(internal_error("No means of payment available"));
+		}
+
+		let mut order_book = self.order_book.lock().unwrap();
+		let mut user_channel_id_bytes = [0; 8];
+		user_channel_id_bytes.copy_from_slice(&order_id[..8]);
+		let order = ChannelOrder {
+			counterparty_node_id: *counterparty_node_id,
+			user_channel_id: u64::from_be_bytes(user_channel_id_bytes),
+			info: OrderInfo {
+				order_id,
+				channel_size_sat: msg.channel_size_sat,
+				announce_channel: msg.announce_channel,
+				zero_conf: msg.zero_conf,
+				fee_msat,
+				expires_at: order_book.now.saturating_add(self.options.order_expiry_secs as u64),
+				payment,
+				state: OrderState::AwaitingPayment,
+			},
+		};
+		order_book.orders.insert(order_id, order.clone());
+		if let Err(e) = self.persist_orders(&order_book) {
+			log_error!(self.logger, "Failed to persist new channel order: {}", e);
+			order_book.orders.remove(&order_id);
+			return Err(internal_error("Failed to persist order"));
+		}
+		log_info!(self.logger, "Created order {} for a {} sat channel to {}",
+			log_bytes!(order_id), msg.channel_size_sat, log_pubkey!(counterparty_node_id));
+		Ok(order.info)
+	}
+}
+
+impl<P: Deref, K: Deref, S: Deref, L: Deref> CustomMessageReader for ChannelRequestServer<P, K, S, L>
+where
+	P::Target: ChannelOrderProvider,
+	K::Target: KeysInterface,
+	S::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	type CustomMessage = ChannelRequestMessage;
+
+	fn read<R: io::Read>(&self, message_type: u16, buffer: &mut R) -> Result<Option<ChannelRequestMessage>, DecodeError> {
+		read_channel_request_message(message_type, buffer)
+	}
+}
+
+impl<P: Deref, K: Deref, S: Deref, L: Deref> CustomMessageHandler for ChannelRequestServer<P, K, S, L>
+where
+	P::Target: ChannelOrderProvider,
+	K::Target: KeysInterface,
+	S::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	fn handle_custom_message(&self, msg: ChannelRequestMessage, sender_node_id: &PublicKey) -> Result<(), LightningError> {
+		let response = match msg {
+			ChannelRequestMessage::GetInfoRequest(msg) => {
+				ChannelRequestMessage::GetInfoResponse(GetInfoResponse { request_id: msg.request_id, options: self.options.clone() })
+			},
+			ChannelRequestMessage::CreateOrderRequest(msg) => {
+				match self.create_order(sender_node_id, &msg) {
+					Ok(order) => ChannelRequestMessage::OrderResponse(OrderResponse { request_id: Some(msg.request_id), order }),
+					Err(err) => ChannelRequestMessage::Error(err),
+				}
+			},
+			ChannelRequestMessage::GetOrderRequest(msg) => {
+				match self.get_order(&msg.order_id) {
+					Some(ref order) if order.counterparty_node_id == *sender_node_id => {
+						ChannelRequestMessage::OrderResponse(OrderResponse { request_id: Some(msg.request_id), order: order.info.clone() })
+					},
+					_ => ChannelRequestMessage::Error(ChannelRequestError {
+						request_id: msg.request_id, code: ERROR_CODE_UNKNOWN_ORDER, message: "Unknown order".to_owned(),
+					}),
+				}
+			},
+			_ => return Err(LightningError {
+				err: "Received a channel request response as a server".to_owned(),
+				action: ErrorAction::IgnoreAndLog(::util::logger::Level::Debug),
+			}),
+		};
+		self.pending_messages.lock().unwrap().push((*sender_node_id, response));
+		Ok(())
+	}
+
+	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, ChannelRequestMessage)> {
+		core::mem::take(&mut *self.pending_messages.lock().unwrap())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use util::ser::Readable;
+	use util::test_utils::{TestKeysInterface, TestLogger};
+	use io::Cursor;
+	use core::cell::RefCell;
+
+	struct TestStore {
+		entries: Mutex<HashMap<String, Vec<u8>>>,
+	}
+
+	impl KVStorePersister for TestStore {
+		fn persist<W: Writeable>(&self, key: &str, object: &W) -> io::Result<()> {
+			self.entries.lock().unwrap().insert(key.to_string(), object.encode());
+			Ok(())
+		}
+	}
+
+	struct TestProvider {
+		opened_channels: Mutex<Vec<(PublicKey, u64, bool)>>,
+		open_result: Mutex<Result<[u8; 32], APIError>>,
+		next_payment_hash: RefCell<u8>,
+	}
+
+	impl ChannelOrderProvider for TestProvider {
+		fn get_payment_details(&self, _order_id: &[u8; 32], amount_msat: u64, _expiry_secs: u32) -> Result<OrderPaymentDetails, ()> {
+			let mut next_payment_hash = self.next_payment_hash.borrow_mut();
+			*next_payment_hash += 1;
+			Ok(OrderPaymentDetails {
+				bolt11_invoice: Some(format!("lnbc{}", amount_msat)),
+				payment_hash: Some(PaymentHash([*next_payment_hash; 32])),
+				onchain_script: Some(Script::new()),
+			})
+		}
+
+		fn open_channel(
+			&self, counterparty_node_id: &PublicKey, channel_value_satoshis: u64, _user_channel_id: u64,
+			zero_conf: bool, _announce_channel: bool
+		) -> Result<[u8; 32], APIError> {
+			let res = self.open_result.lock().unwrap().clone();
+			if res.is_ok() {
+				self.opened_channels.lock().unwrap().push((*counterparty_node_id, channel_value_satoshis, zero_conf));
+			}
+			res
+		}
+	}
+
+	fn options() -> ChannelRequestOptions {
+		ChannelRequestOptions {
+			min_channel_size_sat: 100_000,
+			max_channel_size_sat: 1_000_000,
+			base_fee_sat: 1_000,
+			fee_proportional_millionths: 10_000,
+			zero_conf_supported: true,
+			order_expiry_secs: 3600,
+		}
+	}
+
+	fn order_response(msgs: Vec<(PublicKey, ChannelRequestMessage)>) -> OrderInfo {
+		assert_eq!(msgs.len(), 1);
+		match msgs[0].1 {
+			ChannelRequestMessage::OrderResponse(ref response) => response.order.clone(),
+			ref msg => panic!("Unexpected message {:?}", msg),
+		}
+	}
+
+	#[test]
+	fn test_message_serialization() {
+		let msg = ChannelRequestMessage::CreateOrderRequest(CreateOrderRequest {
+			request_id: 42, channel_size_sat: 500_000, announce_channel: true, zero_conf: false,
+		});
+		let encoded = msg.encode();
+		assert_eq!(read_channel_request_message(msg.type_id(), &mut Cursor::new(&encoded)).unwrap(), Some(msg));
+		assert_eq!(read_channel_request_message(37913, &mut Cursor::new(&encoded)).unwrap(), None);
+		assert_eq!(options().fee_msat(500_000), Some(1_000_000 + 5_000_000));
+	}
+
+	#[test]
+	fn test_channel_orders() {
+		let provider = TestProvider {
+			opened_channels: Mutex::new(Vec::new()),
+			open_result: Mutex::new(Err(APIError::ChannelUnavailable { err: "Peer disconnected".to_owned() })),
+			next_payment_hash: RefCell::new(0),
+		};
+		let keys_manager = TestKeysInterface::new(&[42; 32], Network::Testnet);
+		let store = TestStore { entries: Mutex::new(HashMap::new()) };
+		let logger = TestLogger::new();
+		let server = ChannelRequestServer::new(options(), &provider, &keys_manager, &store, &logger);
+		server.timer_tick_occurred(1000);
+
+		let client = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+		server.handle_custom_message(ChannelRequestMessage::GetInfoRequest(GetInfoRequest { request_id: 1 }), &client).unwrap();
+		assert_eq!(server.get_and_clear_pending_msg(), vec![(client,
+			ChannelRequestMessage::GetInfoResponse(GetInfoResponse { request_id: 1, options: options() }))]);
+
+		// Orders outside of our options are rejected.
+		let create_order = |request_id, channel_size_sat| ChannelRequestMessage::CreateOrderRequest(CreateOrderRequest {
+			request_id, channel_size_sat, announce_channel: false, zero_conf: true,
+		});
+		server.handle_custom_message(create_order(2, 10_000_000), &client).unwrap();
+		match server.get_and_clear_pending_msg()[0].1 {
+			ChannelRequestMessage::Error(ref err) => assert_eq!((err.request_id, err.code), (2, ERROR_CODE_INVALID_PARAMS)),
+			ref msg => panic!("Unexpected message {:?}", msg),
+		}
+
+		server.handle_custom_message(create_order(3, 500_000), &client).unwrap();
+		let order = order_response(server.get_and_clear_pending_msg());
+		assert_eq!(order.fee_msat, 6_000_000);
+		assert_eq!(order.expires_at, 4600);
+		assert_eq!(order.state, OrderState::AwaitingPayment);
+		let payment_hash = order.payment.payment_hash.unwrap();
+
+		// Other clients can't see the order.
+		let other_client = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[2; 32]).unwrap());
+		server.handle_custom_message(ChannelRequestMessage::GetOrderRequest(GetOrderRequest { request_id: 4, order_id: order.order_id }), &other_client).unwrap();
+		match server.get_and_clear_pending_msg()[0].1 {
+			ChannelRequestMessage::Error(ref err) => assert_eq!(err.code, ERROR_CODE_UNKNOWN_ORDER),
+			ref msg => panic!("Unexpected message {:?}", msg),
+		}
+
+		assert_eq!(server.lightning_payment_received(&PaymentHash([0; 32]), 6_000_000), Err(OrderPaymentError::UnknownOrder));
+		assert_eq!(server.lightning_payment_received(&payment_hash, 5_999_999), Err(OrderPaymentError::Underpaid));
+		assert_eq!(server.lightning_payment_received(&payment_hash, 6_000_000), Ok(order.order_id));
+		assert_eq!(server.lightning_payment_received(&payment_hash, 6_000_000), Err(OrderPaymentError::NotAwaitingPayment));
+
+		// The client is disconnected so the channel can't be opened yet, but the order is paid.
+		assert_eq!(server.get_order(&order.order_id).unwrap().info.state, OrderState::Paid);
+		assert!(server.get_and_clear_pending_msg().is_empty());
+
+		// A second order expires without being paid.
+		server.handle_custom_message(create_order(5, 100_000), &client).unwrap();
+		let expiring_order = order_response(server.get_and_clear_pending_msg());
+		server.timer_tick_occurred(5000);
+		let expired_order = order_response(server.get_and_clear_pending_msg());
+		assert_eq!(expired_order.order_id, expiring_order.order_id);
+		assert_eq!(expired_order.state, OrderState::Expired);
+		assert_eq!(server.onchain_payment_received(&expired_order.order_id, 2_000), Err(OrderPaymentError::NotAwaitingPayment));
+
+		// After restarting, the paid order's channel is opened once the client is connected.
+		let orders: ChannelOrders = Readable::read(&mut Cursor::new(store.entries.lock().unwrap().get(CHANNEL_ORDERS_KEY).unwrap())).unwrap();
+		assert_eq!(orders.orders.len(), 2);
+		let server = ChannelRequestServer::from_orders(options(), &provider, &keys_manager, &store, &logger, orders);
+		assert_eq!(server.get_order(&order.order_id).unwrap().info.state, OrderState::Paid);
+		*provider.open_result.lock().unwrap() = Ok([42; 32]);
+		server.timer_tick_occurred(6000);
+		let opened_order = order_response(server.get_and_clear_pending_msg());
+		assert_eq!(opened_order.state, OrderState::ChannelOpened { temporary_channel_id: [42; 32] });
+		assert_eq!(*provider.opened_channels.lock().unwrap(), vec![(client, 500_000, true)]);
+
+		let orders: ChannelOrders = Readable::read(&mut Cursor::new(store.entries.lock().unwrap().get(CHANNEL_ORDERS_KEY).unwrap())).unwrap();
+		assert!(orders.orders.iter().any(|o| o.info == opened_order));
+		assert_eq!(server.remove_order(&expired_order.order_id).unwrap().unwrap().info, expired_order);
+		assert_eq!(server.list_orders().len(), 1);
+	}
+}
@@ -22,6 +22,7 @@
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Script;
 use bitcoin::network::constants::Network;
 
 use bitcoin::hashes::Hash;
@@ -36,28 +37,32 @@ use bitcoin::{LockTime, secp256k1, Sequence};
 
 use chain;
 use chain::{Confirm, ChannelMonitorUpdateErr, Watch, BestBlock};
-use chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator, LowerBoundedFeeEstimator};
+use chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator, LowerBoundedFeeEstimator, FEERATE_FLOOR_SATS_PER_KW};
 use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateStep, HTLC_FAIL_BACK_BUFFER, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ANTI_REORG_DELAY, MonitorEvent, CLOSED_CHANNEL_UPDATE_ID};
 use chain::transaction::{OutPoint, TransactionData};
 // Since this struct is returned in `list_channels` methods, expose it here in case users want to
 // construct one themselves.
 use ln::{inbound_payment, PaymentHash, PaymentPreimage, PaymentSecret};
-use ln::channel::{Channel, ChannelError, ChannelUpdateStatus, UpdateFulfillCommitFetch};
+use ln::channel::{Channel, ChannelError, ChannelUpdateStatus, ProbationStrike, UpdateFulfillCommitFetch, COMMITMENT_TX_WEIGHT_PER_HTLC, commitment_tx_base_weight};
 use ln::features::{ChannelTypeFeatures, InitFeatures, NodeFeatures};
-use routing::router::{PaymentParameters, Route, RouteHop, RoutePath, RouteParameters};
+use routing::gossip::{NodeId, ReadOnlyNetworkGraph};
+use routing::router::{PaymentParameters, Route, RouteHop, RoutePath, RouteParameters, RoutingFeeLimit};
 use ln::msgs;
 use ln::msgs::NetAddress;
 use ln::onion_utils;
+use ln::blinded_payment::{self, INVALID_ONION_BLINDING};
+use ln::script::ShutdownScript;
 use ln::msgs::{ChannelMessageHandler, DecodeError, LightningError, MAX_VALUE_MSAT};
 use ln::wire::Encode;
 use chain::keysinterface::{Sign, KeysInterface, KeysManager, InMemorySigner, Recipient};
-use util::config::{UserConfig, ChannelConfig};
+use util::extensions::{ExtensionData, ExtensionRegistry};
+use util::config::{UserConfig, ChannelConfig, EventQueueOverflowPolicy, InboundVolumeConfig, OverpaymentPolicy, PeerConfigOverrides};
 use util::events::{EventHandler, EventsProvider, MessageSendEvent, MessageSendEventsProvider, ClosureReason, HTLCDestination};
 use util::{byte_utils, events};
 use util::scid_utils::fake_scid;
-use util::ser::{BigSize, FixedLengthReader, Readable, ReadableArgs, MaybeReadable, Writeable, Writer, VecWriter};
+use util::ser::{BigSize, FixedLengthReader, LengthReadable, Readable, ReadableArgs, MaybeReadable, Writeable, Writer, VecWriter};
 use util::logger::{Level, Logger};
-use util::errors::APIError;
+use util::errors::{APIError, APIErrorCause, ErrorClassification, Retryability};
 
 use io;
 use prelude::*;
@@ -65,7 +70,7 @@ use core::{cmp, mem};
 use core::cell::RefCell;
 use io::Read;
 use sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::time::Duration;
 use core::ops::Deref;
 
@@ -97,6 +102,10 @@ pub(super) enum PendingHTLCRouting {
 		/// The SCID from the onion that we should forward to. This could be a "real" SCID, an
 		/// outbound SCID alias, or a phantom node SCID.
 		short_channel_id: u64, // This should be NonZero<u64> eventually when we bump MSRV
+		/// Set if this HTLC is being forwarded within a blinded route.
+		blinded: Option<BlindedForward>,
+		/// Custom TLVs the sender included in our onion, see [`msgs::OnionHopDataFormat::NonFinalNode`].
+		custom_tlvs: Vec<(u64, Vec<u8>)>,
 	},
 	Receive {
 		payment_data: msgs::FinalOnionHopData,
@@ -106,7 +115,64 @@ pub(super) enum PendingHTLCRouting {
 	ReceiveKeysend {
 		payment_preimage: PaymentPreimage,
 		incoming_cltv_expiry: u32, // Used to track when we should expire pending HTLCs that go unclaimed
+		/// Custom TLVs the payer included in the onion, see [`msgs::OnionHopDataFormat::FinalNode`].
+		custom_tlvs: Vec<(u64, Vec<u8>)>,
 	},
+	ReceiveTrampoline {
+		payment_data: msgs::FinalOnionHopData,
+		incoming_cltv_expiry: u32, // Used to track when we should expire pending HTLCs that go unforwarded
+		forward_info: TrampolineForwardInfo,
+	},
+}
+
+/// Information used to forward an HTLC within a blinded route.
+#[derive(Clone, PartialEq)]
+pub(super) struct BlindedForward {
+	/// The blinding point to relay to the next hop in its `update_add_htlc`.
+	next_blinding_point: PublicKey,
+	/// How to fail the HTLC backwards if it fails downstream.
+	failure: BlindedFailure,
+}
+
+/// How an HTLC we received within a blinded route is failed backwards, so that the sender learns
+/// nothing about the route beyond that it failed.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) enum BlindedFailure {
+	/// We're the introduction node, so we fail the HTLC with an `update_fail_htlc` containing
+	/// `invalid_onion_blinding`, which the sender will attribute to us.
+	FromIntroductionNode,
+	/// We're further within the blinded route, so we fail the HTLC with an
+	/// `update_fail_malformed_htlc`, which the introduction node converts to a failure from itself.
+	FromBlindedNode,
+}
+
+/// Builds the message with which we immediately fail an HTLC we received within a blinded route.
+fn blinded_failure_msg(msg: &msgs::UpdateAddHTLC, failure: BlindedFailure, incoming_shared_secret: &[u8; 32]) -> HTLCFailureMsg {
+	match failure {
+		BlindedFailure::FromIntroductionNode => HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
+			channel_id: msg.channel_id,
+			htlc_id: msg.htlc_id,
+			reason: onion_utils::build_first_hop_failure_packet(incoming_shared_secret, INVALID_ONION_BLINDING, &[0; 32]),
+		}),
+		BlindedFailure::FromBlindedNode => HTLCFailureMsg::Malformed(msgs::UpdateFailMalformedHTLC {
+			channel_id: msg.channel_id,
+			htlc_id: msg.htlc_id,
+			sha256_of_onion: [0; 32],
+			failure_code: INVALID_ONION_BLINDING,
+		}),
+	}
+}
+
+/// What we learned from peeling the trampoline onion of a trampoline payment we're to relay.
+#[derive(Clone, PartialEq)]
+pub(super) struct TrampolineForwardInfo {
+	next_node_id: PublicKey,
+	amt_to_forward: u64,
+	outgoing_cltv_value: u32,
+	/// Set if `next_node_id` is the payment's final recipient, which we pay with it.
+	payment_data: Option<msgs::FinalOnionHopData>,
+	/// Set if `next_node_id` is another trampoline node, which peels this next.
+	next_packet: Option<msgs::TrampolineOnionPacket>,
 }
 
 #[derive(Clone)] // See Channel::revoke_and_ack for why, tl;dr: Rust bug
@@ -114,6 +180,9 @@ pub(super) struct PendingHTLCInfo {
 	pub(super) routing: PendingHTLCRouting,
 	pub(super) incoming_shared_secret: [u8; 32],
 	payment_hash: PaymentHash,
+	/// The value of the HTLC we received, for HTLCs we're to forward. `None` for HTLCs we're to
+	/// receive and for forwards received before we recorded it.
+	pub(super) incoming_amt_msat: Option<u64>,
 	pub(super) amt_to_forward: u64,
 	pub(super) outgoing_cltv_value: u32,
 }
@@ -149,6 +218,11 @@ pub(super) enum HTLCForwardInfo {
 		htlc_id: u64,
 		err_packet: msgs::OnionErrorPacket,
 	},
+	FailMalformedHTLC {
+		htlc_id: u64,
+		failure_code: u16,
+		sha256_of_onion: [u8; 32],
+	},
 }
 
 /// Tracks the inbound corresponding to an outbound HTLC
@@ -159,6 +233,9 @@ pub(crate) struct HTLCPreviousHopData {
 	htlc_id: u64,
 	incoming_packet_shared_secret: [u8; 32],
 	phantom_shared_secret: Option<[u8; 32]>,
+	/// Set if we received the HTLC within a blinded route, and thus must fail it backwards as
+	/// `invalid_onion_blinding`.
+	blinded_failure: Option<BlindedFailure>,
 
 	// This field is consumed by `claim_funds_from_hop()` when updating a force-closed backwards
 	// channel with a preimage provided by the forward channel.
@@ -188,6 +265,24 @@ struct ClaimableHTLC {
 	total_msat: u64,
 }
 
+/// The HTLCs of a trampoline payment we're to relay, which we claim once the payment we relay it
+/// with is claimed.
+struct PendingTrampolineForward {
+	payment_hash: PaymentHash,
+	htlcs: Vec<ClaimableHTLC>,
+	forward_info: TrampolineForwardInfo,
+	/// The payment we relay this with and the total value, including routing fees, we sent with
+	/// it, once we've forwarded it.
+	outbound_payment_id: Option<PaymentId>,
+	outbound_amount_msat: Option<u64>,
+}
+
+impl PendingTrampolineForward {
+	fn total_received_msat(&self) -> u64 {
+		self.htlcs.iter().map(|htlc| htlc.value).sum()
+	}
+}
+
 /// A payment identifier used to uniquely identify a payment to LDK.
 /// (C-not exported) as we just use [u8; 32] directly
 #[derive(Hash, Copy, Clone, PartialEq, Eq, Debug)]
@@ -281,6 +376,46 @@ enum ClaimFundsFromHop {
 	DuplicateClaim,
 }
 
+/// The value of payments claimed within the current [`InboundVolumeConfig::window_ticks`].
+struct InboundVolumeWindow {
+	received_msat: u64,
+	payment_count: u64,
+	ticks_elapsed: u32,
+}
+
+impl InboundVolumeWindow {
+	fn new() -> Self {
+		InboundVolumeWindow { received_msat: 0, payment_count: 0, ticks_elapsed: 0 }
+	}
+
+	/// Adds a claimed payment to the window, returning an
+	/// [`events::Event::InboundVolumeThresholdReached`] for each threshold it crossed.
+	fn payment_claimed(&mut self, amount_msat: u64, config: &InboundVolumeConfig) -> Vec<events::Event> {
+		let prev_received_msat = self.received_msat;
+		self.received_msat = self.received_msat.saturating_add(amount_msat);
+		self.payment_count += 1;
+		let mut crossed: Vec<u64> = config.thresholds_msat.iter().filter_map(|t| *t)
+			.filter(|threshold_msat| prev_received_msat < *threshold_msat && *threshold_msat <= self.received_msat)
+			.collect();
+		crossed.sort_unstable();
+		crossed.dedup();
+		crossed.drain(..).map(|threshold_msat| events::Event::InboundVolumeThresholdReached {
+			threshold_msat,
+			received_msat: self.received_msat,
+			payment_count: self.payment_count,
+			window_ticks: config.window_ticks,
+		}).collect()
+	}
+
+	/// Resets the window once it has lasted [`InboundVolumeConfig::window_ticks`].
+	fn timer_tick(&mut self, config: &InboundVolumeConfig) {
+		self.ticks_elapsed += 1;
+		if self.ticks_elapsed >= config.window_ticks {
+			*self = InboundVolumeWindow::new();
+		}
+	}
+}
+
 type ShutdownResult = (Option<(OutPoint, ChannelMonitorUpdate)>, Vec<(HTLCSource, PaymentHash, PublicKey, [u8; 32])>);
 
 /// Error type returned across the channel_state mutex boundary. When an Err is generated for a
@@ -420,6 +555,8 @@ pub(super) struct ChannelHolder<Signer: Sign> {
 	/// guarantees are made about the channels given here actually existing anymore by the time you
 	/// go to read them!
 	claimable_htlcs: HashMap<PaymentHash, (events::PaymentPurpose, Vec<ClaimableHTLC>)>,
+	/// Map from payment hash to the HTLCs of trampoline payments we're to relay.
+	trampoline_forwards: HashMap<PaymentHash, PendingTrampolineForward>,
 	/// Messages to send to peers - pushed to in the same lock that they are generated in (except
 	/// for broadcast messages, where ordering isn't as strict).
 	pub(super) pending_msg_events: Vec<MessageSendEvent>,
@@ -461,6 +598,82 @@ struct PendingInboundPayment {
 	min_value_msat: Option<u64>,
 }
 
+/// Limits on the HTLCs we accept when receiving a payment, registered via
+/// [`ChannelManager::create_inbound_payment_with_limits`] or
+/// [`ChannelManager::create_inbound_payment_for_hash_with_limits`].
+///
+/// HTLCs which would cause a payment to exceed these limits are failed back as if the payment
+/// were unknown, protecting against payments split into many tiny parts against a published
+/// invoice.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InboundPaymentLimits {
+	/// The maximum total value we will accept for the payment, including the value the sender
+	/// claims to be sending in total.
+	pub max_total_msat: Option<u64>,
+	/// The maximum number of HTLCs (i.e. MPP parts) we will accept for the payment.
+	pub max_parts: Option<usize>,
+}
+
+impl InboundPaymentLimits {
+	fn is_exceeded_by(&self, total_msat: u64, parts: usize) -> bool {
+		self.max_total_msat.map(|max| total_msat > max).unwrap_or(false) ||
+			self.max_parts.map(|max| parts > max).unwrap_or(false)
+	}
+}
+
+/// The [`InboundPaymentLimits`] registered for a payment hash, which are removed at the same time
+/// a [`PendingInboundPayment`] with the same expiry would be.
+struct RegisteredInboundPaymentLimits {
+	payment_hash: PaymentHash,
+	max_total_msat: Option<u64>,
+	max_parts: Option<u64>,
+	expiry_time: u64,
+}
+
+impl RegisteredInboundPaymentLimits {
+	fn limits(&self) -> InboundPaymentLimits {
+		InboundPaymentLimits {
+			max_total_msat: self.max_total_msat,
+			max_parts: self.max_parts.map(|max| max as usize),
+		}
+	}
+}
+
+impl_writeable_tlv_based!(RegisteredInboundPaymentLimits, {
+	(0, payment_hash, required),
+	(2, max_total_msat, option),
+	(4, max_parts, option),
+	(6, expiry_time, required),
+});
+
+/// The arguments of the last call to [`ChannelManager::broadcast_node_announcement`], which we
+/// use to re-broadcast our node_announcement before it becomes stale.
+struct NodeAnnouncementInfo {
+	rgb: [u8; 3],
+	alias: [u8; 32],
+	addresses: Vec<NetAddress>,
+	/// The time after which we'll next re-broadcast the node_announcement, or 0 if we have yet to
+	/// broadcast it as we had no public channels.
+	refresh_time: u32,
+}
+
+impl_writeable_tlv_based!(NodeAnnouncementInfo, {
+	(0, rgb, required),
+	(2, alias, required),
+	(4, addresses, vec_type),
+	(6, refresh_time, required),
+});
+
+/// Tracks when we next need to re-broadcast our own gossip, see
+/// [`ChannelManager::force_gossip_refresh`].
+#[derive(Default)]
+struct GossipRefreshState {
+	/// The time after which we'll next re-broadcast the channel_update of each of our public
+	/// channels, by channel_id.
+	channel_update_refresh_times: HashMap<[u8; 32], u32>,
+	node_announcement: Option<NodeAnnouncementInfo>,
+}
+
 /// Stores the session_priv for each part of a payment that is still pending. For versions 0.0.102
 /// and later, also stores information for retrying the payment.
 pub(crate) enum PendingOutboundPayment {
@@ -478,6 +691,10 @@ pub(crate) enum PendingOutboundPayment {
 		total_msat: u64,
 		/// Our best known block height at the time this payment was initiated.
 		starting_block_height: u32,
+		/// The maximum total fee across all paths, as set by the payment's
+		/// [`PaymentParameters::max_total_routing_fee`] for its total amount, which limits the fees
+		/// of retries.
+		max_total_routing_fee_msat: Option<u64>,
 	},
 	/// When a pending payment is fulfilled, we continue tracking it until all pending HTLCs have
 	/// been resolved. This ensures we don't look up pending payments in ChannelMonitors on restart
@@ -524,6 +741,19 @@ impl PendingOutboundPayment {
 			_ => None,
 		}
 	}
+	/// Gets the [`PaymentParameters`] with which to retry this payment's failed paths, limiting the
+	/// fees of the retry to what remains of the payment's fee limit after the fees of its other
+	/// pending paths.
+	fn retry_payment_params(&self, payment_params: &PaymentParameters) -> PaymentParameters {
+		let mut payment_params = payment_params.clone();
+		if let PendingOutboundPayment::Retryable {
+			max_total_routing_fee_msat: Some(max_fee_msat), pending_fee_msat: Some(pending_fee_msat), ..
+		} = self {
+			payment_params.max_total_routing_fee =
+				Some(RoutingFeeLimit::absolute(max_fee_msat.saturating_sub(*pending_fee_msat)));
+		}
+		payment_params
+	}
 
 	fn payment_hash(&self) -> Option<PaymentHash> {
 		match self {
@@ -686,7 +916,7 @@ pub struct ChannelManager<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref,
         F::Target: FeeEstimator,
 				L::Target: Logger,
 {
-	default_configuration: UserConfig,
+	default_configuration: RwLock<UserConfig>,
 	genesis_hash: BlockHash,
 	fee_estimator: LowerBoundedFeeEstimator<F>,
 	chain_monitor: M,
@@ -709,6 +939,14 @@ pub struct ChannelManager<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref,
 	/// after we generate a PaymentReceived upon receipt of all MPP parts or when they time out.
 	/// Locked *after* channel_state.
 	pending_inbound_payments: Mutex<HashMap<PaymentHash, PendingInboundPayment>>,
+	/// The [`InboundPaymentLimits`] of inbound payments created via
+	/// [`ChannelManager::create_inbound_payment_with_limits`] and
+	/// [`ChannelManager::create_inbound_payment_for_hash_with_limits`].
+	inbound_payment_limits: Mutex<HashMap<PaymentHash, RegisteredInboundPaymentLimits>>,
+	/// The [`FundingIntent`]s of channels created via
+	/// [`ChannelManager::create_channel_with_funding_intent`] which have not yet been funded, by
+	/// temporary channel ID. Locked *after* channel_state.
+	funding_intents: Mutex<HashMap<[u8; 32], FundingIntent>>,
 
 	/// The session_priv bytes and retry metadata of outbound payments which are pending resolution.
 	/// The authoritative state of these HTLCs resides either within Channels or ChannelMonitors
@@ -775,6 +1013,64 @@ pub struct ChannelManager<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref,
 	/// very far in the past, and can only ever be up to two hours in the future.
 	highest_seen_timestamp: AtomicUsize,
 
+	/// Whether we've generated an [`events::Event::PendingEventQueueThresholdCrossed`] for the
+	/// `pending_msg_events` queue since it was last below the warning threshold.
+	msg_event_queue_warned: AtomicBool,
+	/// Whether we've generated an [`events::Event::PendingEventQueueThresholdCrossed`] for the
+	/// `pending_events` queue since it was last below the warning threshold.
+	event_queue_warned: AtomicBool,
+
+	/// Whether all channels have been frozen via [`ChannelManager::freeze_all_channels`], in which
+	/// case we reject all new HTLCs and pause fee updates.
+	channels_frozen: AtomicBool,
+
+	/// Whether we're a [`NodeRole::Standby`], in which case we do nothing until promoted via
+	/// [`ChannelManager::promote`].
+	standby: AtomicBool,
+	/// The events we deserialized as a standby, which are surfaced once we're promoted rather than
+	/// being handled by both us and the active instance.
+	standby_held_events: Mutex<Vec<events::Event>>,
+
+	/// The value of payments claimed within the current [`InboundVolumeConfig::window_ticks`],
+	/// used to generate [`events::Event::InboundVolumeThresholdReached`]s.
+	inbound_volume: Mutex<InboundVolumeWindow>,
+
+	/// The overrides of our default configuration registered for specific peers via
+	/// [`ChannelManager::set_peer_config_overrides`].
+	peer_config_overrides: Mutex<HashMap<PublicKey, PeerConfigOverrides>>,
+
+	/// Audit records of payments abandoned via [`ChannelManager::abandon_payment_force`]. Failed
+	/// HTLCs of these payments never result in retryable [`events::Event::PaymentPathFailed`]s.
+	payment_abandonment_records: Mutex<HashMap<PaymentId, PaymentAbandonmentRecord>>,
+
+	/// The extensions registered via [`ChannelManager::register_extension`].
+	extension_registry: Mutex<ExtensionRegistry>,
+	/// Opaque data stored by extensions via [`ChannelManager::set_node_extension_data`].
+	node_extension_data: Mutex<ExtensionData>,
+
+	/// When we next need to re-broadcast our channel_updates and node_announcement.
+	gossip_refresh: Mutex<GossipRefreshState>,
+
+	/// The last [`MAX_REJECTED_FORWARDS`] HTLCs we rejected forwarding, oldest first.
+	rejected_forwards: Mutex<VecDeque<RejectedForward>>,
+
+	/// The handler consulted before force-closing channels for non-security-critical reasons, if
+	/// any, see [`ChannelManager::set_force_close_decision_handler`].
+	force_close_decision_handler: Mutex<Option<Box<dyn ForceCloseDecisionHandler + Send>>>,
+
+	/// The handler consulted before forwarding HTLCs, if any, see
+	/// [`ChannelManager::set_forward_policy_handler`].
+	forward_policy_handler: Mutex<Option<Box<dyn ForwardPolicyHandler + Send>>>,
+	/// The forwards our [`ForwardPolicyHandler`] asked us to hold. Locked after `channel_state`.
+	held_forwards: Mutex<HeldForwards>,
+
+	/// When each of our periodic [`TimerTask`]s is next due.
+	timer_schedule: Mutex<TimerSchedule>,
+	/// The `highest_seen_timestamp` at which we were put in the background via
+	/// [`ChannelManager::prepare_for_background`], if we're in the background, during which our
+	/// timers are stopped.
+	backgrounded_at: Mutex<Option<u32>>,
+
 	/// The bulk of our storage will eventually be here (channels and message queues and the like).
 	/// If we are connected to a peer we always at least have an entry here, even if no channels
 	/// are currently open with that peer.
@@ -893,7 +1189,7 @@ pub const MIN_CLTV_EXPIRY_DELTA: u16 = 6*7;
 // scale them up to suit its security policy. At the network-level, we shouldn't constrain them too much,
 // while avoiding to introduce a DoS vector. Further, a low CTLV_FAR_FAR_AWAY could be a source of
 // routing failure for any HTLC sender picking up an LDK node among the first hops.
-pub(super) const CLTV_FAR_FAR_AWAY: u32 = 14 * 24 * 6;
+pub(crate) const CLTV_FAR_FAR_AWAY: u32 = 14 * 24 * 6;
 
 /// Minimum CLTV difference between the current block height and received inbound payments.
 /// Invoices generated for payment to us must set their `min_final_cltv_expiry` field to at least
@@ -903,6 +1199,11 @@ pub(super) const CLTV_FAR_FAR_AWAY: u32 = 14 * 24 * 6;
 // a payment was being routed, so we add an extra block to be safe.
 pub const MIN_FINAL_CLTV_EXPIRY: u32 = HTLC_FAIL_BACK_BUFFER + 3;
 
+// The number of blocks found while a payment to us was being routed which we tolerate when
+// checking its CLTV against the `min_final_cltv_expiry` we advertised, mirroring the extra block
+// included in MIN_FINAL_CLTV_EXPIRY above.
+const FINAL_CLTV_EXPIRY_IN_FLIGHT_SLACK: u32 = 1;
+
 // Check that our CLTV_EXPIRY is at least CLTV_CLAIM_BUFFER + ANTI_REORG_DELAY + LATENCY_GRACE_PERIOD_BLOCKS,
 // ie that if the next-hop peer fails the HTLC within
 // LATENCY_GRACE_PERIOD_BLOCKS then we'll still have CLTV_CLAIM_BUFFER left to timeout it onchain,
@@ -926,6 +1227,15 @@ pub(crate) const PAYMENT_EXPIRY_BLOCKS: u32 = 3;
 /// The number of ticks of [`ChannelManager::timer_tick_occurred`] until expiry of incomplete MPPs
 pub(crate) const MPP_TIMEOUT_TICKS: u8 = 3;
 
+/// The number of seconds after which we re-broadcast the channel_update of an otherwise-quiet
+/// public channel, or our node_announcement, less up to [`GOSSIP_REFRESH_JITTER_SECS`]. This is
+/// comfortably below the two weeks after which other nodes may prune our gossip as stale.
+pub(crate) const GOSSIP_REFRESH_INTERVAL_SECS: u32 = 60 * 60 * 24 * 11;
+
+/// The maximum random jitter, in seconds, subtracted from [`GOSSIP_REFRESH_INTERVAL_SECS`] so
+/// that our channels' refreshes don't all happen at once.
+pub(crate) const GOSSIP_REFRESH_JITTER_SECS: u32 = 60 * 60 * 24 * 2;
+
 /// Information needed for constructing an invoice route hint for this channel.
 #[derive(Clone, Debug, PartialEq)]
 pub struct CounterpartyForwardingInfo {
@@ -1061,7 +1371,8 @@ pub struct ChannelDetails {
 	pub outbound_capacity_msat: u64,
 	/// The available outbound capacity for sending a single HTLC to the remote peer. This is
 	/// similar to [`ChannelDetails::outbound_capacity_msat`] but it may be further restricted by
-	/// the current state and per-HTLC limit(s). This is intended for use when routing, allowing us
+	/// the current state and per-HTLC limit(s), as well as the commitment transaction fee we have to
+	/// leave room for if we opened the channel. This is intended for use when routing, allowing us
 	/// to use a limit as close as possible to the HTLC limit we can currently send.
 	///
 	/// See also [`ChannelDetails::balance_msat`] and [`ChannelDetails::outbound_capacity_msat`].
@@ -1122,6 +1433,15 @@ pub struct ChannelDetails {
 	///
 	/// This field is only `None` for `ChannelDetails` objects serialized prior to LDK 0.0.109.
 	pub config: Option<ChannelConfig>,
+	/// True if this channel is currently on probation and will not be used to forward HTLCs.
+	///
+	/// See [`ChannelProbationConfig`] for when channels are placed on probation and
+	/// [`ChannelManager::end_channel_probation`] for lifting it early.
+	///
+	/// [`ChannelProbationConfig`]: crate::util::config::ChannelProbationConfig
+	pub is_on_probation: bool,
+	/// True if either we or our counterparty have started cooperatively closing this channel.
+	pub is_shutting_down: bool,
 }
 
 impl ChannelDetails {
@@ -1146,6 +1466,51 @@ impl ChannelDetails {
 	}
 }
 
+/// The weight of a typical funding transaction, spending a single P2WPKH input to the P2WSH
+/// funding output and a P2WPKH change output.
+const TYPICAL_FUNDING_TX_WEIGHT: u64 = 42 + 272 + 172 + 124;
+/// The weight of a cooperative closing transaction paying to two P2WPKH outputs.
+const CLOSING_TX_WEIGHT: u64 = 42 + 384 + 124 * 2;
+/// The weight of a transaction sweeping our delayed `to_self` output from our commitment
+/// transaction to a P2WPKH output.
+const TO_SELF_SWEEP_TX_WEIGHT: u64 = 42 + 317 + 124;
+
+/// An estimate of the on-chain costs of opening, and eventually closing, a channel, as returned by
+/// [`ChannelManager::estimate_channel_costs`].
+///
+/// All fees are estimated using the feerates our [`FeeEstimator`] currently returns and may differ
+/// significantly by the time a channel is actually opened or closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelCostEstimate {
+	/// The feerate, in satoshis per 1000 weight units, we'd propose for the channel's commitment
+	/// transactions, also used to estimate the funding transaction's fee.
+	pub feerate_sat_per_1000_weight: u32,
+	/// The estimated fee for a typical funding transaction, spending a single P2WPKH input and
+	/// paying to the funding output and a P2WPKH change output. Wallets adding more inputs will pay
+	/// more.
+	pub funding_tx_fee_satoshis: u64,
+	/// The fee, paid by us as the channel funder, of the commitment transaction with no HTLCs
+	/// pending. This amount is unspendable while the channel is open.
+	pub commitment_tx_fee_satoshis: u64,
+	/// The additional commitment transaction fee, paid by us as the channel funder, for each
+	/// (non-dust) HTLC pending in the channel.
+	pub commitment_tx_fee_per_htlc_satoshis: u64,
+	/// The reserve we'd require our counterparty to keep in the channel, per our config's
+	/// [`ChannelHandshakeConfig::their_channel_reserve_proportional_millionths`].
+	///
+	/// [`ChannelHandshakeConfig::their_channel_reserve_proportional_millionths`]: crate::util::config::ChannelHandshakeConfig::their_channel_reserve_proportional_millionths
+	pub counterparty_reserve_satoshis: u64,
+	/// The reserve our counterparty is likely to require us to keep in the channel, assuming it
+	/// uses the 1% recommended by the spec. This amount is unspendable while the channel is open.
+	pub expected_holder_reserve_satoshis: u64,
+	/// The estimated fee of cooperatively closing the channel.
+	pub cooperative_close_fee_satoshis: u64,
+	/// The estimated total fee of force-closing the channel with no HTLCs pending, i.e. the
+	/// commitment transaction fee plus the fee to sweep our balance once the `to_self_delay` has
+	/// passed.
+	pub force_close_fee_satoshis: u64,
+}
+
 /// If a payment fails to send, it can be in one of several states. This enum is returned as the
 /// Err() type describing which state the payment is in, see the description of individual enum
 /// states for more.
@@ -1191,6 +1556,103 @@ pub enum PaymentSendFailure {
 	},
 }
 
+impl ErrorClassification for PaymentSendFailure {
+	type Cause = PaymentSendFailureCause;
+
+	/// Returns one of the following codes:
+	///  * 101: [`PaymentSendFailure::ParameterError`]
+	///  * 102: [`PaymentSendFailure::PathParameterError`]
+	///  * 103: [`PaymentSendFailure::AllFailedRetrySafe`]
+	///  * 104: [`PaymentSendFailure::PartialFailure`]
+	///
+	/// The codes of the underlying [`APIError`]s are available via their own implementation.
+	fn error_code(&self) -> u16 {
+		match *self {
+			PaymentSendFailure::ParameterError(_) => 101,
+			PaymentSendFailure::PathParameterError(_) => 102,
+			PaymentSendFailure::AllFailedRetrySafe(_) => 103,
+			PaymentSendFailure::PartialFailure { .. } => 104,
+		}
+	}
+
+	/// A [`PaymentSendFailure::PartialFailure`] is [`Retryability::Later`] only if some paths may be
+	/// retried via [`ChannelManager::retry_payment`], and [`Retryability::InProgress`] otherwise.
+	fn retryability(&self) -> Retryability {
+		match *self {
+			PaymentSendFailure::ParameterError(ref err) => err.retryability(),
+			PaymentSendFailure::PathParameterError(_) => Retryability::Never,
+			PaymentSendFailure::AllFailedRetrySafe(_) => Retryability::Later,
+			PaymentSendFailure::PartialFailure { failed_paths_retry: Some(_), .. } => Retryability::Later,
+			PaymentSendFailure::PartialFailure { failed_paths_retry: None, .. } => Retryability::InProgress,
+		}
+	}
+
+	fn cause(&self) -> PaymentSendFailureCause {
+		match *self {
+			PaymentSendFailure::ParameterError(ref err) => PaymentSendFailureCause::InvalidParameter(err.cause()),
+			PaymentSendFailure::PathParameterError(ref results) => PaymentSendFailureCause::InvalidPathParameter(
+				results.iter().filter_map(|res| res.as_ref().err()).map(|err| err.cause()).next()),
+			PaymentSendFailure::AllFailedRetrySafe(ref errs) =>
+				PaymentSendFailureCause::AllPathsFailed(errs.first().map(|err| err.cause())),
+			PaymentSendFailure::PartialFailure { .. } => PaymentSendFailureCause::PartialFailure,
+		}
+	}
+}
+
+/// The cause of a [`PaymentSendFailure`], as returned by [`ErrorClassification::cause`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PaymentSendFailureCause {
+	/// See [`PaymentSendFailure::ParameterError`], with the cause of its [`APIError`].
+	InvalidParameter(APIErrorCause),
+	/// See [`PaymentSendFailure::PathParameterError`], with the cause of the first path's
+	/// [`APIError`].
+	InvalidPathParameter(Option<APIErrorCause>),
+	/// See [`PaymentSendFailure::AllFailedRetrySafe`], with the cause of the first path's
+	/// [`APIError`].
+	AllPathsFailed(Option<APIErrorCause>),
+	/// See [`PaymentSendFailure::PartialFailure`].
+	PartialFailure,
+}
+
+/// A request for an outbound channel to be funded, created via
+/// [`ChannelManager::create_channel_with_funding_intent`].
+///
+/// This allows the funding transaction to be built out-of-band, e.g. by a custodial backend, some
+/// time after the channel was opened. Once [`FundingIntent::output_script`] is known, a funding
+/// transaction paying [`FundingIntent::channel_value_satoshis`] to it should be provided via
+/// [`ChannelManager::funding_transaction_generated`] before [`FundingIntent::expires_at`], after
+/// which the channel is closed with [`ClosureReason::FundingIntentExpired`].
+///
+/// Note that, as with any channel which has not yet been funded, the channel (and its intent) is
+/// forgotten if the counterparty disconnects or the [`ChannelManager`] is reloaded.
+///
+/// [`ClosureReason::FundingIntentExpired`]: events::ClosureReason::FundingIntentExpired
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FundingIntent {
+	/// The temporary channel ID of the channel to be funded.
+	pub temporary_channel_id: [u8; 32],
+	/// The node ID of our counterparty in the channel.
+	pub counterparty_node_id: PublicKey,
+	/// The `user_channel_id` the channel was created with.
+	pub user_channel_id: u64,
+	/// The value, in satoshis, which the funding output must have.
+	pub channel_value_satoshis: u64,
+	/// The script which the funding output must pay to.
+	///
+	/// This is `None` until our counterparty accepts the channel, at which point an
+	/// [`Event::FundingGenerationReady`] is also generated.
+	///
+	/// [`Event::FundingGenerationReady`]: events::Event::FundingGenerationReady
+	pub output_script: Option<Script>,
+	/// The time, in seconds since the UNIX epoch, after which the channel is closed if it has not
+	/// been funded.
+	///
+	/// This is compared against the highest block header timestamp we've seen, and may thus be
+	/// enforced up to two hours late.
+	pub expires_at: u64,
+}
+
 /// Route hints used in constructing invoices for [phantom node payents].
 ///
 /// [phantom node payments]: crate::chain::keysinterface::PhantomKeysManager
@@ -1203,6 +1665,685 @@ pub struct PhantomRouteHints {
 	pub phantom_scid: u64,
 	/// The pubkey of the real backing node that would ultimately receive the payment.
 	pub real_node_pubkey: PublicKey,
+	/// The CLTV delta the real backing node requires for HTLCs forwarded to the phantom node, per
+	/// its [`CltvPolicy::min_cltv_expiry_delta`].
+	///
+	/// [`CltvPolicy::min_cltv_expiry_delta`]: crate::util::config::CltvPolicy::min_cltv_expiry_delta
+	pub min_cltv_expiry_delta: u16,
+	/// The `min_final_cltv_expiry` the real backing node sets in invoices, per its
+	/// [`CltvPolicy::min_final_cltv_expiry`].
+	///
+	/// [`CltvPolicy::min_final_cltv_expiry`]: crate::util::config::CltvPolicy::min_final_cltv_expiry
+	pub min_final_cltv_expiry: u32,
+}
+
+/// The reasons for which a [`ChannelManager`] may wish to force-close a channel which are not
+/// security-critical, and thus may be overridden by a [`ForceCloseDecisionHandler`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ForceCloseReason {
+	/// Our counterparty set a feerate on our channel which is far outside of what our
+	/// [`FeeEstimator`] expects.
+	///
+	/// [`FeeEstimator`]: crate::chain::chaininterface::FeeEstimator
+	FeerateDisagreement {
+		/// The feerate our counterparty wishes to use, in satoshis per 1000 weight units.
+		counterparty_feerate_sat_per_1000_weight: u32,
+	},
+	/// The `closing_signed` fee negotiation of a cooperative close failed to finish in time.
+	ClosingNegotiationTimedOut,
+	/// The channel's funding transaction failed to confirm in time.
+	FundingTimedOut,
+}
+
+/// What a [`ForceCloseDecisionHandler`] wishes to do about a channel we'd like to force-close.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForceCloseDecision {
+	/// Force-close the channel now.
+	Approve,
+	/// Leave the channel open for now. We'll ask again the next time the condition which led us to
+	/// want to force-close it is checked.
+	Delay,
+	/// Leave the channel open, but stop forwarding HTLCs over it by placing it on probation for
+	/// [`ChannelProbationConfig::probation_ticks`]. As with [`ForceCloseDecision::Delay`], we'll
+	/// ask again later.
+	///
+	/// [`ChannelProbationConfig::probation_ticks`]: crate::util::config::ChannelProbationConfig::probation_ticks
+	DisableAndWait,
+}
+
+/// A handler consulted before a [`ChannelManager`] force-closes a channel for one of the
+/// non-security-critical reasons in [`ForceCloseReason`], allowing the application to keep the
+/// channel open instead. Force-closes required to keep our funds safe, e.g. to claim HTLCs
+/// on-chain before they expire, are never deferred.
+///
+/// Set via [`ChannelManager::set_force_close_decision_handler`]. The handler is called while
+/// internal locks are held, and thus must not call back into the [`ChannelManager`].
+pub trait ForceCloseDecisionHandler {
+	/// Decides what to do about the channel with the given `channel_id`, which we'd like to
+	/// force-close for the given `reason`.
+	fn decide_force_close(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, reason: &ForceCloseReason) -> ForceCloseDecision;
+}
+
+/// An HTLC we've been asked to forward, as passed to a [`ForwardPolicyHandler`].
+///
+/// HTLC endorsement signals are not included, as we don't parse them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardRequest {
+	/// The hash of the payment the HTLC is a part of.
+	pub payment_hash: PaymentHash,
+	/// The channel over which we received the HTLC.
+	pub prev_channel_id: [u8; 32],
+	/// The id of the HTLC in the channel over which we received it. Together with
+	/// [`ForwardRequest::prev_channel_id`], this identifies a held forward in
+	/// [`ChannelManager::resolve_held_forward`].
+	pub prev_htlc_id: u64,
+	/// The node id of the peer which sent us the HTLC, if the channel over which we received it is
+	/// still open.
+	pub prev_counterparty_node_id: Option<PublicKey>,
+	/// The channel over which we'd forward the HTLC.
+	pub next_channel_id: [u8; 32],
+	/// The short channel id (or alias) over which the onion asked us to forward the HTLC. This
+	/// may not be the short channel id of [`ForwardRequest::next_channel_id`] if
+	/// [`UserConfig::nonstrict_forwarding`] is set.
+	pub next_short_channel_id: u64,
+	/// The node id of the peer we'd forward the HTLC to.
+	pub next_counterparty_node_id: PublicKey,
+	/// The value of the HTLC we received, or `None` if we received it before upgrading to a version
+	/// of LDK which records it.
+	pub incoming_amount_msat: Option<u64>,
+	/// The value of the HTLC we'd forward, as set in the onion.
+	pub outgoing_amount_msat: u64,
+	/// The CLTV expiry of the HTLC we'd forward, as set in the onion.
+	pub outgoing_cltv_expiry: u32,
+	/// Any TLVs of an odd type in the custom range (at or above 2^16) the sender included in the
+	/// onion for us, sorted by type. Always empty for HTLCs forwarded within a blinded route.
+	pub custom_tlvs: Vec<(u64, Vec<u8>)>,
+}
+
+/// What a [`ForwardPolicyHandler`] wishes to do with an HTLC we've been asked to forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardPolicyDecision {
+	/// Forward the HTLC now.
+	Allow,
+	/// Fail the HTLC back to the previous hop with a `temporary_channel_failure`.
+	Deny,
+	/// Hold the HTLC until the decision is made asynchronously via
+	/// [`ChannelManager::resolve_held_forward`]. If no decision is made within
+	/// [`ForwardPolicyConfig::max_hold_ticks`], the HTLC is forwarded or failed back as set by
+	/// [`ForwardPolicyConfig::fail_open`].
+	///
+	/// [`ForwardPolicyConfig::max_hold_ticks`]: crate::util::config::ForwardPolicyConfig::max_hold_ticks
+	/// [`ForwardPolicyConfig::fail_open`]: crate::util::config::ForwardPolicyConfig::fail_open
+	Hold,
+}
+
+/// A handler consulted before a [`ChannelManager`] forwards an HTLC, allowing an external policy
+/// engine to allow, deny or hold each forward.
+///
+/// Decisions should be made quickly, as the handler is called while internal locks are held, and
+/// thus also must not call back into the [`ChannelManager`]. Decisions which take longer than
+/// [`ForwardPolicyConfig::max_decision_millis`] are replaced by the fallback set by
+/// [`ForwardPolicyConfig::fail_open`]. Where a decision requires slower work, e.g. a round-trip to
+/// a remote service, return [`ForwardPolicyDecision::Hold`] and complete it asynchronously via
+/// [`ChannelManager::resolve_held_forward`].
+///
+/// Set via [`ChannelManager::set_forward_policy_handler`].
+///
+/// [`ForwardPolicyConfig::max_decision_millis`]: crate::util::config::ForwardPolicyConfig::max_decision_millis
+/// [`ForwardPolicyConfig::fail_open`]: crate::util::config::ForwardPolicyConfig::fail_open
+pub trait ForwardPolicyHandler {
+	/// Decides what to do with the HTLC we've been asked to forward described by `request`.
+	fn decide_forward(&self, request: &ForwardRequest) -> ForwardPolicyDecision;
+}
+
+/// An HTLC forward our [`ForwardPolicyHandler`] asked us to hold.
+struct HeldForward {
+	/// The short channel id the HTLC is to be forwarded over, as keyed in
+	/// [`ChannelHolder::forward_htlcs`].
+	short_channel_id: u64,
+	forward_info: HTLCForwardInfo,
+	/// The number of calls to [`ChannelManager::timer_tick_occurred`] since we started holding the
+	/// forward.
+	ticks_elapsed: u8,
+}
+
+#[derive(Default)]
+struct HeldForwards {
+	/// The forwards awaiting a decision, by the id of the channel over which we received the HTLC
+	/// and the HTLC's id in that channel.
+	held: HashMap<([u8; 32], u64), HeldForward>,
+	/// Held forwards which have been decided on, along with whether they were allowed, awaiting
+	/// the next call to [`ChannelManager::process_pending_htlc_forwards`].
+	decided: Vec<(HeldForward, bool)>,
+}
+
+/// The interval at which each [`TimerTask`] is run by [`ChannelManager::process_timer_tasks`].
+pub const TIMER_TASK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The periodic tasks run by [`ChannelManager::timer_tick_occurred`] and
+/// [`ChannelManager::process_timer_tasks`].
+///
+/// Each task is scheduled independently and runs at most once per [`TIMER_TASK_INTERVAL`],
+/// however often it is polled. Thus, a burst of ticks after a long pause, e.g. when a mobile app
+/// returns from the background, runs each task only once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimerTask {
+	/// Increasing or decreasing the on-chain feerate estimates for our outbound channels, and
+	/// checking that those of our inbound channels are not stale.
+	FeerateUpdates,
+	/// Broadcasting `ChannelUpdate` messages disabling channels whose peer has been disconnected
+	/// for a while and re-enabling them once it reconnects, timing out stalled closing
+	/// negotiation, ending channel probation and expiring previous `ChannelConfig`s.
+	ChannelStatusUpdates,
+	/// Failing back incomplete MPP payments which have not completed within a few runs, and
+	/// forwarding or failing back forwards held for a [`ForwardPolicyHandler`] which has not
+	/// decided on them in time.
+	PaymentTimeouts,
+	/// Re-broadcasting the `ChannelUpdate`s of our public channels and our `NodeAnnouncement`
+	/// before other nodes consider them stale, see [`ChannelManager::force_gossip_refresh`].
+	GossipRefresh,
+	/// Rolling over the window of received payments tracked for
+	/// [`InboundVolumeConfig::window_ticks`].
+	InboundVolumeWindow,
+}
+
+impl TimerTask {
+	const ALL: [TimerTask; 5] = [TimerTask::FeerateUpdates, TimerTask::ChannelStatusUpdates,
+		TimerTask::PaymentTimeouts, TimerTask::GossipRefresh, TimerTask::InboundVolumeWindow];
+}
+
+/// Tracks when each [`TimerTask`] is next due, see [`ChannelManager::process_timer_tasks`].
+struct TimerSchedule {
+	/// The time at which each task is next due. Tasks which have never run are due immediately.
+	deadlines: HashMap<TimerTask, Duration>,
+	/// The clock [`ChannelManager::timer_tick_occurred`] schedules tasks by, which is monotonic
+	/// time since we were created.
+	#[cfg(all(feature = "std", not(test)))]
+	clock_start: Instant,
+	/// Without a clock, [`ChannelManager::timer_tick_occurred`] assumes each call follows the
+	/// previous one by [`TIMER_TASK_INTERVAL`].
+	#[cfg(any(not(feature = "std"), test))]
+	ticks: u32,
+	/// Time which passed without our clock advancing, as reported to
+	/// [`ChannelManager::resume_from_background`].
+	skipped: Duration,
+}
+
+impl TimerSchedule {
+	fn new() -> Self {
+		TimerSchedule {
+			deadlines: HashMap::new(),
+			#[cfg(all(feature = "std", not(test)))]
+			clock_start: Instant::now(),
+			#[cfg(any(not(feature = "std"), test))]
+			ticks: 0,
+			skipped: Duration::from_secs(0),
+		}
+	}
+
+	/// Gets the current time for [`ChannelManager::timer_tick_occurred`].
+	fn tick_time(&mut self) -> Duration {
+		#[cfg(all(feature = "std", not(test)))]
+		{ self.clock_start.elapsed() + self.skipped }
+		#[cfg(any(not(feature = "std"), test))]
+		{
+			self.ticks = self.ticks.saturating_add(1);
+			TIMER_TASK_INTERVAL * self.ticks + self.skipped
+		}
+	}
+
+	/// Gets the tasks which are due at `now`, scheduling each to run again one interval later.
+	fn take_due_tasks(&mut self, now: Duration) -> Vec<TimerTask> {
+		let mut due_tasks = Vec::new();
+		for task in TimerTask::ALL.iter() {
+			let deadline = self.deadlines.entry(*task).or_insert(Duration::from_secs(0));
+			if *deadline <= now {
+				*deadline = now + TIMER_TASK_INTERVAL;
+				due_tasks.push(*task);
+			}
+		}
+		due_tasks
+	}
+
+	fn next_deadline(&self) -> Duration {
+		TimerTask::ALL.iter().map(|task| self.deadlines.get(task).cloned().unwrap_or(Duration::from_secs(0)))
+			.min().unwrap_or(Duration::from_secs(0))
+	}
+}
+
+/// A summary of a [`ChannelManager`]'s state when it was put in the background, as returned by
+/// [`ChannelManager::prepare_for_background`].
+///
+/// It is small enough to be stored alongside a mobile app's scheduled background tasks, and may
+/// be serialized via [`Writeable`] to survive the app being terminated while in the background.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackgroundResumeHint {
+	/// The highest block timestamp we had seen, which approximates the time at which we were put
+	/// in the background. Apps without a clock which survives being terminated may compare this
+	/// to the current time to compute the `elapsed` time given to
+	/// [`ChannelManager::resume_from_background`].
+	pub highest_seen_timestamp: u32,
+	/// The height of the best block we had seen, from which chain sync will resume.
+	pub best_block_height: u32,
+	/// The lowest CLTV expiry of the HTLCs pending in our channels, if any. The app should resume,
+	/// sync the chain and reconnect to peers well before the chain reaches this height, lest our
+	/// counterparties force-close the channels to enforce the HTLCs on-chain.
+	pub earliest_htlc_expiry: Option<u32>,
+}
+
+impl_writeable_tlv_based!(BackgroundResumeHint, {
+	(0, highest_seen_timestamp, required),
+	(2, best_block_height, required),
+	(4, earliest_htlc_expiry, option),
+});
+
+/// The role of a [`ChannelManager`] in a deployment where a standby instance is kept ready to
+/// take over from the active one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeRole {
+	/// The instance operates the node as usual.
+	Active,
+	/// The instance holds a read-only copy of the node's state and does not operate the node. It
+	/// neither talks to peers nor signs anything, ignores chain data and refuses to be persisted
+	/// until promoted via [`ChannelManager::promote`].
+	///
+	/// A standby is created by deserializing a [`ChannelManager`] with
+	/// [`ChannelManagerReadArgs::role`] set to `Standby`, or by stepping down an active instance
+	/// via [`ChannelManager::step_down`].
+	Standby,
+}
+
+impl_writeable_tlv_based_enum!(NodeRole,
+	(0, Active) => {},
+	(2, Standby) => {};
+);
+
+/// Determines whether the node's previously active instance has stopped operating, and thus
+/// whether a standby [`ChannelManager`] may be promoted via [`ChannelManager::promote`] without
+/// both instances signing conflicting channel states.
+///
+/// Implemented for the [`WatchingService`] keeping the standby's [`ChannelMonitor`]s current, as
+/// well as for a [`FencedPersister`].
+///
+/// [`WatchingService`]: crate::chain::replication::WatchingService
+/// [`FencedPersister`]: crate::util::persist::FencedPersister
+pub trait LeadershipFence {
+	/// Returns true if the previously active instance is known to have stopped operating, or can
+	/// no longer persist and thus sign new channel states, in which case it must be prevented from
+	/// resuming. Returns false if it may still be operating.
+	fn fence_previous_active(&self) -> bool;
+}
+
+/// A limit from a hypothetical [`UserConfig`] which a channel's current HTLCs would violate, as
+/// reported by [`ChannelManager::simulate_config_exposure`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigLimitViolation {
+	/// The value of dust HTLCs on either commitment transaction, which would be lost to miners if
+	/// the channel were force-closed, exceeds [`ChannelConfig::max_dust_htlc_exposure_msat`].
+	DustHTLCExposure {
+		/// The worst-case dust exposure across both commitment transactions, in millisatoshis.
+		exposure_msat: u64,
+		/// The limit from the hypothetical config, in millisatoshis.
+		limit_msat: u64,
+	},
+	/// The total value of inbound HTLCs exceeds what we'd allow given
+	/// [`ChannelHandshakeConfig::max_inbound_htlc_value_in_flight_percent_of_channel`].
+	///
+	/// [`ChannelHandshakeConfig::max_inbound_htlc_value_in_flight_percent_of_channel`]: crate::util::config::ChannelHandshakeConfig::max_inbound_htlc_value_in_flight_percent_of_channel
+	InboundHTLCValueInFlight {
+		/// The total value of pending inbound HTLCs, in millisatoshis.
+		value_msat: u64,
+		/// The limit from the hypothetical config, in millisatoshis.
+		limit_msat: u64,
+	},
+	/// Our counterparty accepts fewer HTLCs than
+	/// [`ChannelHandshakeLimits::min_max_accepted_htlcs`] requires.
+	///
+	/// [`ChannelHandshakeLimits::min_max_accepted_htlcs`]: crate::util::config::ChannelHandshakeLimits::min_max_accepted_htlcs
+	CounterpartyMaxAcceptedHTLCs {
+		/// The number of HTLCs our counterparty accepts.
+		counterparty_max_accepted_htlcs: u16,
+		/// The minimum from the hypothetical config.
+		min_max_accepted_htlcs: u16,
+	},
+}
+
+/// The worst-case on-chain exposure of a channel's current HTLCs under a hypothetical
+/// [`UserConfig`] and feerate, as returned by [`ChannelManager::simulate_config_exposure`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelConfigExposure {
+	/// The channel's ID.
+	pub channel_id: [u8; 32],
+	/// The node ID of our counterparty on the channel.
+	pub counterparty_node_id: PublicKey,
+	/// The number of HTLCs pending on the channel, in either direction, including those in our
+	/// holding cell.
+	pub pending_htlcs: u32,
+	/// The value of HTLCs which would be dust on our commitment transaction, in millisatoshis.
+	pub on_holder_tx_dust_exposure_msat: u64,
+	/// The value of HTLCs which would be dust on our counterparty's commitment transaction, in
+	/// millisatoshis.
+	pub on_counterparty_tx_dust_exposure_msat: u64,
+	/// The total value of pending inbound HTLCs, in millisatoshis.
+	pub inbound_htlc_value_in_flight_msat: u64,
+	/// The limits from the hypothetical config which the channel would violate. Empty if the
+	/// channel is within all of them.
+	pub violations: Vec<ConfigLimitViolation>,
+}
+
+/// Whether a channel type or workflow can be used with a given peer, as reported in
+/// [`PeerCapabilities`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerCapabilitySupport {
+	/// Both we and the peer support it.
+	Supported,
+	/// The peer doesn't advertise support for it.
+	UnsupportedByPeer,
+	/// The peer advertises support for it, but LDK doesn't yet support it.
+	UnsupportedByUs,
+	/// Neither the peer nor LDK support it.
+	Unsupported,
+	/// We're not connected to the peer and haven't seen a `node_announcement` from it, so we
+	/// don't know which features it supports.
+	Unknown,
+}
+
+impl PeerCapabilitySupport {
+	fn new(supported_by_peer: Option<bool>, supported_by_us: bool) -> Self {
+		match (supported_by_peer, supported_by_us) {
+			(None, _) => PeerCapabilitySupport::Unknown,
+			(Some(true), true) => PeerCapabilitySupport::Supported,
+			(Some(false), true) => PeerCapabilitySupport::UnsupportedByPeer,
+			(Some(true), false) => PeerCapabilitySupport::UnsupportedByUs,
+			(Some(false), false) => PeerCapabilitySupport::Unsupported,
+		}
+	}
+}
+
+/// The channel types and workflows a peer supports, as returned by
+/// [`ChannelManager::probe_peer_capabilities`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCapabilities {
+	/// The node ID of the peer.
+	pub counterparty_node_id: PublicKey,
+	/// Whether we're currently connected to the peer. If not, the capabilities below are based
+	/// on the peer's `node_announcement`, if any.
+	pub is_connected: bool,
+	/// The features the peer sent us in its `init` message, if we're connected to it.
+	pub init_features: Option<InitFeatures>,
+	/// The features the peer announced in its `node_announcement`, if we've seen one.
+	pub announced_features: Option<NodeFeatures>,
+	/// Whether the peer supports `option_static_remotekey` channels.
+	pub static_remote_key: PeerCapabilitySupport,
+	/// Whether the peer supports `option_anchor_outputs` or `option_anchors_zero_fee_htlc_tx`
+	/// channels.
+	pub anchors: PeerCapabilitySupport,
+	/// Whether the peer supports opening channels with `option_dual_fund`.
+	pub dual_funding: PeerCapabilitySupport,
+	/// Whether the peer supports zero-conf channels. Note that the peer will also have to trust us
+	/// to accept a zero-conf channel.
+	pub zero_conf: PeerCapabilitySupport,
+	/// Whether the peer supports `option_scid_alias` (referred to in LDK as `scid_privacy`)
+	/// channels.
+	pub scid_privacy: PeerCapabilitySupport,
+	/// Whether the peer supports channels larger than 2^24 satoshis (`option_support_large_channel`).
+	pub wumbo: PeerCapabilitySupport,
+	/// Whether the peer supports simple taproot channels.
+	pub taproot: PeerCapabilitySupport,
+	/// The names of the features required by our default config's
+	/// [`UserConfig::peer_feature_requirements`] which the peer does not support, in which case
+	/// [`ChannelManager::create_channel`] will fail. Empty if we're not connected to the peer.
+	pub missing_required_features: Vec<&'static str>,
+}
+
+/// The retransmissions a channel made in response to the last `channel_reestablish` it received,
+/// as returned by [`ChannelManager::get_last_reestablish_plan`].
+///
+/// This is mostly useful to debug reconnection issues with other implementations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReestablishPlan {
+	/// The `next_commitment_number` our counterparty sent, i.e. the number of the next
+	/// `commitment_signed` it expects to receive from us.
+	pub counterparty_next_local_commitment_number: u64,
+	/// The `next_revocation_number` our counterparty sent, i.e. the number of the next
+	/// `revoke_and_ack` it expects to receive from us.
+	pub counterparty_next_remote_commitment_number: u64,
+	/// Whether we re-sent our `channel_ready`.
+	pub resend_channel_ready: bool,
+	/// Whether we re-sent our last `revoke_and_ack`.
+	pub resend_revoke_and_ack: bool,
+	/// Whether we (re-)sent a `commitment_signed`, along with the updates it commits to.
+	///
+	/// Note that this may be a new commitment if we freed HTLCs from our holding cell on
+	/// reconnection.
+	pub resend_commitment_update: bool,
+	/// The number of HTLC updates sent along with the `commitment_signed`, if any.
+	pub resent_htlc_updates: usize,
+	/// Whether the `revoke_and_ack` is sent before the `commitment_signed`, if both are sent.
+	pub revoke_and_ack_first: bool,
+	/// Whether a monitor update was pending at the time, in which case any `revoke_and_ack` or
+	/// `commitment_signed` we owe our counterparty is sent once the update completes instead.
+	pub awaiting_monitor_update: bool,
+	/// Whether we re-sent our `shutdown`.
+	pub resend_shutdown: bool,
+	/// Whether we sent `announcement_signatures`.
+	pub send_announcement_signatures: bool,
+}
+
+/// Overrides for how a channel is reestablished, set with
+/// [`ChannelManager::set_reestablish_overrides`] to reproduce reconnection issues with other
+/// implementations.
+///
+/// Any of these may cause our counterparty to force-close the channel, and thus are only
+/// available with the `unsafe_reestablish_overrides` feature.
+#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReestablishOverrides {
+	/// Added to the `next_commitment_number` in the `channel_reestablish` we send.
+	pub next_local_commitment_number_offset: i64,
+	/// Added to the `next_revocation_number` in the `channel_reestablish` we send.
+	pub next_remote_commitment_number_offset: i64,
+	/// Re-send our last `revoke_and_ack` even if our counterparty indicates it received it.
+	pub force_revoke_and_ack: bool,
+	/// Re-send our last `commitment_signed` (and the updates it commits to) even if our
+	/// counterparty indicates it received it, as long as it hasn't been revoked yet.
+	pub force_commitment_update: bool,
+	/// Don't re-send our `channel_ready`, even if our counterparty needs it.
+	pub suppress_channel_ready: bool,
+	/// Don't re-send our last `revoke_and_ack`, even if our counterparty needs it.
+	pub suppress_revoke_and_ack: bool,
+	/// Don't (re-)send a `commitment_signed`, even if our counterparty needs it.
+	pub suppress_commitment_update: bool,
+}
+
+/// The last-known state of one part of an outbound payment, as recorded in a
+/// [`PaymentAbandonmentRecord`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbandonedShardState {
+	/// The HTLC was waiting in its channel's holding cell and had not yet been offered to our
+	/// counterparty.
+	HoldingCell,
+	/// The HTLC had been offered to our counterparty but was not yet irrevocably committed.
+	Offered,
+	/// The HTLC was irrevocably committed and awaiting resolution downstream.
+	Committed,
+	/// Our counterparty had fulfilled the HTLC, but the removal was not yet irrevocably committed.
+	Fulfilling,
+	/// Our counterparty had failed the HTLC, but the removal was not yet irrevocably committed.
+	Failing,
+	/// The HTLC was not found in any open channel, e.g. because its channel was closed and the
+	/// HTLC is being resolved on-chain.
+	NotInChannel,
+}
+
+impl_writeable_tlv_based_enum!(AbandonedShardState,
+	(0, HoldingCell) => {},
+	(2, Offered) => {},
+	(4, Committed) => {},
+	(6, Fulfilling) => {},
+	(8, Failing) => {},
+	(10, NotInChannel) => {};
+);
+
+/// One part (i.e. one HTLC) of an outbound payment, as recorded in a
+/// [`PaymentAbandonmentRecord`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AbandonedShard {
+	/// The channel the HTLC was sent over, if it was found in an open channel.
+	pub channel_id: Option<[u8; 32]>,
+	/// The path the HTLC was sent along. Empty if the HTLC was not found in an open channel.
+	pub path: Vec<RouteHop>,
+	/// The state of the HTLC when the payment was abandoned.
+	pub state: AbandonedShardState,
+}
+
+impl_writeable_tlv_based!(AbandonedShard, {
+	(0, channel_id, option),
+	(2, path, vec_type),
+	(4, state, required),
+});
+
+/// An audit record of an outbound payment abandoned via
+/// [`ChannelManager::abandon_payment_force`], detailing the last-known state of each of its parts
+/// which was still pending at the time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PaymentAbandonmentRecord {
+	/// The id of the abandoned payment.
+	pub payment_id: PaymentId,
+	/// The hash of the abandoned payment.
+	pub payment_hash: PaymentHash,
+	/// Our best known block height when the payment was abandoned.
+	pub abandoned_at_height: u32,
+	/// The parts of the payment which were still pending when it was abandoned.
+	pub shards: Vec<AbandonedShard>,
+}
+
+impl_writeable_tlv_based!(PaymentAbandonmentRecord, {
+	(0, payment_id, required),
+	(2, payment_hash, required),
+	(4, abandoned_at_height, required),
+	(6, shards, vec_type),
+});
+
+/// The maximum number of [`RejectedForward`]s kept by a [`ChannelManager`], see
+/// [`ChannelManager::list_rejected_forwards`].
+pub const MAX_REJECTED_FORWARDS: usize = 1000;
+
+/// Why we rejected an HTLC we were asked to forward, as recorded in a [`RejectedForward`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardRejectionReason {
+	/// The HTLC didn't pay the fee required by the outbound channel's [`ChannelConfig`].
+	FeeInsufficient,
+	/// The HTLC didn't leave us the CLTV expiry delta required by the outbound channel's
+	/// [`ChannelConfig`] or our [`CltvPolicy`], or its CLTV expiry was too close to or too far
+	/// from the current block height.
+	///
+	/// [`CltvPolicy`]: crate::util::config::CltvPolicy
+	CltvExpiry,
+	/// The HTLC's value was below the outbound channel's `htlc_minimum_msat`.
+	AmountBelowMinimum,
+	/// The outbound channel didn't have the liquidity available to add the HTLC, e.g. as we
+	/// didn't have enough balance on our side or our counterparty's HTLC limits were reached.
+	InsufficientLiquidity,
+	/// Adding the HTLC would have put our exposure to dust HTLCs on the outbound channel over its
+	/// [`ChannelConfig::max_dust_htlc_exposure_msat`].
+	DustExposure,
+	/// The outbound channel's peer was disconnected, or the channel was otherwise not ready to
+	/// forward HTLCs.
+	PeerDisconnected,
+	/// We had no channel with the requested short channel id, or it closed before we could
+	/// forward the HTLC.
+	UnknownNextChannel,
+	/// Our configuration or state forbade the forward, e.g. as the outbound channel was private
+	/// or either channel was on probation.
+	Policy,
+}
+
+/// A record of an HTLC we were asked to forward but rejected, as returned by
+/// [`ChannelManager::list_rejected_forwards`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectedForward {
+	/// The hash of the payment the HTLC was a part of.
+	pub payment_hash: PaymentHash,
+	/// The channel over which we received the HTLC.
+	pub prev_channel_id: [u8; 32],
+	/// The short channel id over which we were asked to forward the HTLC.
+	pub next_short_channel_id: u64,
+	/// The value of the HTLC we received, if it was rejected while being received. `None` if it
+	/// was rejected when we got around to forwarding it.
+	pub incoming_amount_msat: Option<u64>,
+	/// The value of the HTLC we were asked to forward.
+	pub outgoing_amount_msat: u64,
+	/// The CLTV expiry of the HTLC we received, if it was rejected while being received. `None` if
+	/// it was rejected when we got around to forwarding it.
+	pub incoming_cltv_expiry: Option<u32>,
+	/// The CLTV expiry of the HTLC we were asked to forward.
+	pub outgoing_cltv_expiry: u32,
+	/// Why we rejected the HTLC.
+	pub reason: ForwardRejectionReason,
+	/// The BOLT 4 failure code we failed the HTLC back with.
+	pub failure_code: u16,
+	/// A human-readable description of why we rejected the HTLC.
+	pub details: String,
+	/// Our best known block height when we rejected the HTLC.
+	pub rejected_at_height: u32,
+}
+
+/// A group of settings in a [`UserConfig`], as reported in a [`UserConfigUpdate`]. Each variant
+/// corresponds to the [`UserConfig`] field of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserConfigSetting {
+	/// [`UserConfig::channel_handshake_config`].
+	ChannelHandshakeConfig,
+	/// [`UserConfig::channel_handshake_limits`].
+	ChannelHandshakeLimits,
+	/// [`UserConfig::channel_config`].
+	ChannelConfig,
+	/// [`UserConfig::accept_forwards_to_priv_channels`].
+	AcceptForwardsToPrivChannels,
+	/// [`UserConfig::nonstrict_forwarding`].
+	NonstrictForwarding,
+	/// [`UserConfig::accept_inbound_channels`].
+	AcceptInboundChannels,
+	/// [`UserConfig::manually_accept_inbound_channels`].
+	ManuallyAcceptInboundChannels,
+	/// [`UserConfig::accept_keysend_custom_tlvs`].
+	AcceptKeysendCustomTlvs,
+	/// [`UserConfig::cltv_policy`].
+	CltvPolicy,
+	/// [`UserConfig::peer_feature_requirements`].
+	PeerFeatureRequirements,
+	/// [`UserConfig::event_queue_limits`].
+	EventQueueLimits,
+	/// [`UserConfig::channel_probation`].
+	ChannelProbation,
+	/// [`UserConfig::payment_amount_policy`].
+	PaymentAmountPolicy,
+	/// [`UserConfig::inbound_volume`].
+	InboundVolume,
+	/// [`UserConfig::trampoline_forwarding`].
+	TrampolineForwarding,
+	/// [`UserConfig::forward_policy`].
+	ForwardPolicy,
+}
+
+/// A report of which changed settings were applied by [`ChannelManager::apply_user_config`].
+/// Settings which did not change are not included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserConfigUpdate {
+	/// Settings which took effect immediately.
+	///
+	/// For [`UserConfigSetting::ChannelConfig`], this means the new config was applied to all
+	/// existing channels which were still using the previous default, as well as to new channels.
+	/// Channels whose config was changed via [`ChannelManager::update_channel_config`] keep their
+	/// own config.
+	pub applied: Vec<UserConfigSetting>,
+	/// Settings which were applied but only affect channels opened from now on, as they are
+	/// negotiated with our counterparty when a channel is opened.
+	pub new_channels_only: Vec<UserConfigSetting>,
+	/// Settings which were rejected as invalid, along with the reason why. The previous values of
+	/// these settings remain in effect.
+	pub rejected: Vec<(UserConfigSetting, String)>,
 }
 
 macro_rules! handle_error {
@@ -1584,13 +2725,14 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// Users need to notify the new ChannelManager when a new block is connected or
 	/// disconnected using its `block_connected` and `block_disconnected` methods, starting
 	/// from after `params.latest_hash`.
-	pub fn new(fee_est: F, chain_monitor: M, tx_broadcaster: T, logger: L, keys_manager: K, config: UserConfig, params: ChainParameters) -> Self {
+	pub fn new(fee_est: F, chain_monitor: M, tx_broadcaster: T, logger: L, keys_manager: K, mut config: UserConfig, params: ChainParameters) -> Self {
+		config.cltv_policy = config.cltv_policy.sanitized();
 		let mut secp_ctx = Secp256k1::new();
 		secp_ctx.seeded_randomize(&keys_manager.get_secure_random_bytes());
 		let inbound_pmt_key_material = keys_manager.get_inbound_payment_key_material();
 		let expanded_inbound_key = inbound_payment::ExpandedKey::new(&inbound_pmt_key_material);
 		ChannelManager {
-			default_configuration: config.clone(),
+			default_configuration: RwLock::new(config),
 			genesis_hash: genesis_block(params.network).header.block_hash(),
 			fee_estimator: LowerBoundedFeeEstimator::new(fee_est),
 			chain_monitor,
@@ -1603,10 +2745,13 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				short_to_chan_info: HashMap::new(),
 				forward_htlcs: HashMap::new(),
 				claimable_htlcs: HashMap::new(),
+				trampoline_forwards: HashMap::new(),
 				pending_msg_events: Vec::new(),
 			}),
 			outbound_scid_aliases: Mutex::new(HashSet::new()),
 			pending_inbound_payments: Mutex::new(HashMap::new()),
+			inbound_payment_limits: Mutex::new(HashMap::new()),
+			funding_intents: Mutex::new(HashMap::new()),
 			pending_outbound_payments: Mutex::new(HashMap::new()),
 			id_to_peer: Mutex::new(HashMap::new()),
 
@@ -1621,6 +2766,23 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 
 			last_node_announcement_serial: AtomicUsize::new(0),
 			highest_seen_timestamp: AtomicUsize::new(0),
+			msg_event_queue_warned: AtomicBool::new(false),
+			event_queue_warned: AtomicBool::new(false),
+			channels_frozen: AtomicBool::new(false),
+			standby: AtomicBool::new(false),
+			standby_held_events: Mutex::new(Vec::new()),
+			inbound_volume: Mutex::new(InboundVolumeWindow::new()),
+			peer_config_overrides: Mutex::new(HashMap::new()),
+			payment_abandonment_records: Mutex::new(HashMap::new()),
+			extension_registry: Mutex::new(ExtensionRegistry::new()),
+			node_extension_data: Mutex::new(ExtensionData::default()),
+			gossip_refresh: Mutex::new(GossipRefreshState::default()),
+			rejected_forwards: Mutex::new(VecDeque::new()),
+			force_close_decision_handler: Mutex::new(None),
+			forward_policy_handler: Mutex::new(None),
+			held_forwards: Mutex::new(HeldForwards::default()),
+			timer_schedule: Mutex::new(TimerSchedule::new()),
+			backgrounded_at: Mutex::new(None),
 
 			per_peer_state: RwLock::new(HashMap::new()),
 
@@ -1636,48 +2798,298 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	}
 
 	/// Gets the current configuration applied to all new channels.
-	pub fn get_current_default_configuration(&self) -> &UserConfig {
-		&self.default_configuration
+	pub fn get_current_default_configuration(&self) -> UserConfig {
+		*self.default_configuration.read().unwrap()
 	}
 
-	fn create_and_insert_outbound_scid_alias(&self) -> u64 {
-		let height = self.best_block.read().unwrap().height();
-		let mut outbound_scid_alias = 0;
-		let mut i = 0;
-		loop {
-			if cfg!(fuzzing) { // fuzzing chacha20 doesn't use the key at all so we always get the same alias
-				outbound_scid_alias += 1;
+	/// Validates and applies `config` as our new default configuration at runtime, returning a
+	/// report of which changed settings took effect immediately, which only apply to new channels,
+	/// and which were rejected.
+	///
+	/// Settings are validated as they would be elsewhere: the [`UserConfig::cltv_policy`] is
+	/// sanitized as in [`ChannelManager::new`], and a [`ChannelConfig::cltv_expiry_delta`] below its
+	/// [`CltvPolicy::min_cltv_expiry_delta`] or a [`ChannelHandshakeConfig::our_to_self_delay`]
+	/// below [`BREAKDOWN_TIMEOUT`] is rejected. Rejected settings keep their previous values.
+	///
+	/// Changes to the [`ChannelConfig`] of existing channels generate [`BroadcastChannelUpdate`]
+	/// or [`SendChannelUpdate`] messages as with [`ChannelManager::update_channel_config`].
+	///
+	/// Note that the configuration is not persisted by the [`ChannelManager`], and thus must be
+	/// provided again when deserializing it.
+	///
+	/// [`CltvPolicy::min_cltv_expiry_delta`]: crate::util::config::CltvPolicy::min_cltv_expiry_delta
+	/// [`ChannelHandshakeConfig::our_to_self_delay`]: crate::util::config::ChannelHandshakeConfig::our_to_self_delay
+	/// [`BroadcastChannelUpdate`]: events::MessageSendEvent::BroadcastChannelUpdate
+	/// [`SendChannelUpdate`]: events::MessageSendEvent::SendChannelUpdate
+	pub fn apply_user_config(&self, mut config: UserConfig) -> UserConfigUpdate {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let mut update = UserConfigUpdate { applied: Vec::new(), new_channels_only: Vec::new(), rejected: Vec::new() };
+		let prev_config = self.get_current_default_configuration();
+		config.cltv_policy = config.cltv_policy.sanitized();
+
+		if config.channel_handshake_config != prev_config.channel_handshake_config {
+			if config.channel_handshake_config.our_to_self_delay < BREAKDOWN_TIMEOUT {
+				update.rejected.push((UserConfigSetting::ChannelHandshakeConfig,
+					format!("our_to_self_delay must be at least {}", BREAKDOWN_TIMEOUT)));
+				config.channel_handshake_config = prev_config.channel_handshake_config;
 			} else {
-				outbound_scid_alias = fake_scid::Namespace::OutboundAlias.get_fake_scid(height, &self.genesis_hash, &self.fake_scid_rand_bytes, &self.keys_manager);
+				update.new_channels_only.push(UserConfigSetting::ChannelHandshakeConfig);
 			}
-			if outbound_scid_alias != 0 && self.outbound_scid_aliases.lock().unwrap().insert(outbound_scid_alias) {
-				break;
+		}
+		if config.channel_handshake_limits != prev_config.channel_handshake_limits {
+			update.new_channels_only.push(UserConfigSetting::ChannelHandshakeLimits);
+		}
+		if config.channel_config != prev_config.channel_config {
+			if config.channel_config.cltv_expiry_delta < config.cltv_policy.min_cltv_expiry_delta {
+				update.rejected.push((UserConfigSetting::ChannelConfig,
+					format!("cltv_expiry_delta must be at least {}", config.cltv_policy.min_cltv_expiry_delta)));
+				config.channel_config = prev_config.channel_config;
+			} else {
+				update.applied.push(UserConfigSetting::ChannelConfig);
 			}
-			i += 1;
-			if i > 1_000_000 { panic!("Your RNG is busted or we ran out of possible outbound SCID aliases (which should never happen before we run out of memory to store channels"); }
 		}
-		outbound_scid_alias
+		if config.accept_forwards_to_priv_channels != prev_config.accept_forwards_to_priv_channels {
+			update.applied.push(UserConfigSetting::AcceptForwardsToPrivChannels);
+		}
+		if config.nonstrict_forwarding != prev_config.nonstrict_forwarding {
+			update.applied.push(UserConfigSetting::NonstrictForwarding);
+		}
+		if config.accept_inbound_channels != prev_config.accept_inbound_channels {
+			update.applied.push(UserConfigSetting::AcceptInboundChannels);
+		}
+		if config.manually_accept_inbound_channels != prev_config.manually_accept_inbound_channels {
+			update.applied.push(UserConfigSetting::ManuallyAcceptInboundChannels);
+		}
+		if config.accept_keysend_custom_tlvs != prev_config.accept_keysend_custom_tlvs {
+			update.applied.push(UserConfigSetting::AcceptKeysendCustomTlvs);
+		}
+		if config.cltv_policy != prev_config.cltv_policy {
+			update.applied.push(UserConfigSetting::CltvPolicy);
+		}
+		if config.peer_feature_requirements != prev_config.peer_feature_requirements {
+			update.applied.push(UserConfigSetting::PeerFeatureRequirements);
+		}
+		if config.event_queue_limits != prev_config.event_queue_limits {
+			update.applied.push(UserConfigSetting::EventQueueLimits);
+		}
+		if config.channel_probation != prev_config.channel_probation {
+			update.applied.push(UserConfigSetting::ChannelProbation);
+		}
+		if config.payment_amount_policy != prev_config.payment_amount_policy {
+			update.applied.push(UserConfigSetting::PaymentAmountPolicy);
+		}
+		if config.inbound_volume != prev_config.inbound_volume {
+			update.applied.push(UserConfigSetting::InboundVolume);
+		}
+		if config.trampoline_forwarding != prev_config.trampoline_forwarding {
+			update.applied.push(UserConfigSetting::TrampolineForwarding);
+		}
+		if config.forward_policy != prev_config.forward_policy {
+			update.applied.push(UserConfigSetting::ForwardPolicy);
+		}
+
+		*self.default_configuration.write().unwrap() = config;
+
+		if config.channel_config != prev_config.channel_config {
+			let mut channel_state_lock = self.channel_state.lock().unwrap();
+			let channel_state = &mut *channel_state_lock;
+			for channel in channel_state.by_id.values_mut() {
+				if channel.config() != prev_config.channel_config || !channel.update_config(&config.channel_config) {
+					continue;
+				}
+				if let Ok(msg) = self.get_channel_update_for_broadcast(channel) {
+					channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate { msg });
+				} else if let Ok(msg) = self.get_channel_update_for_unicast(channel) {
+					channel_state.pending_msg_events.push(events::MessageSendEvent::SendChannelUpdate {
+						node_id: channel.get_counterparty_node_id(),
+						msg,
+					});
+				}
+			}
+		}
+		log_info!(self.logger, "Applied new default configuration ({} settings applied, {} for new channels only, {} rejected)",
+			update.applied.len(), update.new_channels_only.len(), update.rejected.len());
+		update
 	}
 
-	/// Creates a new outbound channel to the given remote node and with the given value.
-	///
-	/// `user_channel_id` will be provided back as in
-	/// [`Event::FundingGenerationReady::user_channel_id`] to allow tracking of which events
-	/// correspond with which `create_channel` call. Note that the `user_channel_id` defaults to 0
-	/// for inbound channels, so you may wish to avoid using 0 for `user_channel_id` here.
-	/// `user_channel_id` has no meaning inside of LDK, it is simply copied to events and otherwise
-	/// ignored.
+	/// Registers `overrides` of our default configuration which apply to channels with, and HTLCs
+	/// forwarded to, the peer with the given `counterparty_node_id`, replacing any overrides
+	/// previously registered for it.
 	///
-	/// Raises [`APIError::APIMisuseError`] when `channel_value_satoshis` > 2**24 or `push_msat` is
-	/// greater than `channel_value_satoshis * 1k` or `channel_value_satoshis < 1000`.
+	/// If [`PeerConfigOverrides::channel_config`] is set, it is applied immediately to all existing
+	/// channels with the peer which use the previously applicable [`ChannelConfig`], generating
+	/// [`BroadcastChannelUpdate`] or [`SendChannelUpdate`] messages as with
+	/// [`ChannelManager::update_channel_config`].
 	///
-	/// Note that we do not check if you are currently connected to the given peer. If no
-	/// connection is available, the outbound `open_channel` message may fail to send, resulting in
-	/// the channel eventually being silently forgotten (dropped on reload).
+	/// Note that overrides are not persisted by the [`ChannelManager`], and thus must be
+	/// registered again when deserializing it.
 	///
-	/// Returns the new Channel's temporary `channel_id`. This ID will appear as
-	/// [`Event::FundingGenerationReady::temporary_channel_id`] and in
-	/// [`ChannelDetails::channel_id`] until after
+	/// [`BroadcastChannelUpdate`]: events::MessageSendEvent::BroadcastChannelUpdate
+	/// [`SendChannelUpdate`]: events::MessageSendEvent::SendChannelUpdate
+	pub fn set_peer_config_overrides(&self, counterparty_node_id: PublicKey, overrides: PeerConfigOverrides) -> Result<(), APIError> {
+		if let Some(ref channel_config) = overrides.channel_config {
+			let min_cltv_expiry_delta = self.get_current_default_configuration().cltv_policy.min_cltv_expiry_delta;
+			if channel_config.cltv_expiry_delta < min_cltv_expiry_delta {
+				return Err(APIError::APIMisuseError {
+					err: format!("The chosen CLTV expiry delta is below the minimum of {}", min_cltv_expiry_delta),
+				});
+			}
+		}
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let prev_config = self.get_config_for_peer(&counterparty_node_id);
+		self.peer_config_overrides.lock().unwrap().insert(counterparty_node_id, overrides);
+		self.update_peer_channel_configs(&counterparty_node_id, &prev_config.channel_config);
+		Ok(())
+	}
+
+	/// Removes any overrides registered for the peer with the given `counterparty_node_id` via
+	/// [`ChannelManager::set_peer_config_overrides`], returning them.
+	///
+	/// Existing channels with the peer which use the overridden [`ChannelConfig`] revert to our
+	/// default [`UserConfig::channel_config`].
+	pub fn remove_peer_config_overrides(&self, counterparty_node_id: &PublicKey) -> Option<PeerConfigOverrides> {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let prev_config = self.get_config_for_peer(counterparty_node_id);
+		let overrides = self.peer_config_overrides.lock().unwrap().remove(counterparty_node_id);
+		self.update_peer_channel_configs(counterparty_node_id, &prev_config.channel_config);
+		overrides
+	}
+
+	/// Gets the overrides registered for the peer with the given `counterparty_node_id`, if any.
+	pub fn get_peer_config_overrides(&self, counterparty_node_id: &PublicKey) -> Option<PeerConfigOverrides> {
+		self.peer_config_overrides.lock().unwrap().get(counterparty_node_id).cloned()
+	}
+
+	/// Gets our default configuration with any overrides registered for the given peer applied.
+	fn get_config_for_peer(&self, counterparty_node_id: &PublicKey) -> UserConfig {
+		let config = self.get_current_default_configuration();
+		match self.peer_config_overrides.lock().unwrap().get(counterparty_node_id) {
+			Some(overrides) => overrides.apply_to(config),
+			None => config,
+		}
+	}
+
+	/// Moves channels with the given peer which use `prev_channel_config` to the peer's current
+	/// [`ChannelConfig`].
+	fn update_peer_channel_configs(&self, counterparty_node_id: &PublicKey, prev_channel_config: &ChannelConfig) {
+		let channel_config = self.get_config_for_peer(counterparty_node_id).channel_config;
+		if channel_config == *prev_channel_config { return; }
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = &mut *channel_state_lock;
+		for channel in channel_state.by_id.values_mut() {
+			if channel.get_counterparty_node_id() != *counterparty_node_id || channel.config() != *prev_channel_config {
+				continue;
+			}
+			if !channel.update_config(&channel_config) {
+				continue;
+			}
+			if let Ok(msg) = self.get_channel_update_for_broadcast(channel) {
+				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate { msg });
+			} else if let Ok(msg) = self.get_channel_update_for_unicast(channel) {
+				channel_state.pending_msg_events.push(events::MessageSendEvent::SendChannelUpdate {
+					node_id: channel.get_counterparty_node_id(),
+					msg,
+				});
+			}
+		}
+	}
+
+	/// Fails if the peer's [`PeerConfigOverrides::max_pending_channels`] channels whose funding has
+	/// not yet been initiated already exist.
+	fn check_pending_channel_limit(&self, counterparty_node_id: &PublicKey, by_id: &HashMap<[u8; 32], Channel<Signer>>) -> Result<(), String> {
+		let max_pending_channels = match self.peer_config_overrides.lock().unwrap().get(counterparty_node_id) {
+			Some(PeerConfigOverrides { max_pending_channels: Some(max), .. }) => *max,
+			_ => return Ok(()),
+		};
+		let pending_channels = by_id.values()
+			.filter(|chan| chan.get_counterparty_node_id() == *counterparty_node_id && !chan.is_funding_initiated())
+			.count();
+		if pending_channels >= max_pending_channels {
+			return Err(format!("Already have {} pending channels with peer {}", pending_channels, counterparty_node_id));
+		}
+		Ok(())
+	}
+
+	/// Whether we trust the given peer's zero-conf channels via
+	/// [`PeerConfigOverrides::trusted_zero_conf`].
+	fn trusts_peer_0conf(&self, counterparty_node_id: &PublicKey) -> bool {
+		self.peer_config_overrides.lock().unwrap().get(counterparty_node_id)
+			.map(|overrides| overrides.trusted_zero_conf).unwrap_or(false)
+	}
+
+	fn create_and_insert_outbound_scid_alias(&self) -> u64 {
+		let height = self.best_block.read().unwrap().height();
+		let mut outbound_scid_alias = 0;
+		let mut i = 0;
+		loop {
+			if cfg!(fuzzing) { // fuzzing chacha20 doesn't use the key at all so we always get the same alias
+				outbound_scid_alias += 1;
+			} else {
+				outbound_scid_alias = fake_scid::Namespace::OutboundAlias.get_fake_scid(height, &self.genesis_hash, &self.fake_scid_rand_bytes, &self.keys_manager);
+			}
+			if outbound_scid_alias != 0 && self.outbound_scid_aliases.lock().unwrap().insert(outbound_scid_alias) {
+				break;
+			}
+			i += 1;
+			if i > 1_000_000 { panic!("Your RNG is busted or we ran out of possible outbound SCID aliases (which should never happen before we run out of memory to store channels"); }
+		}
+		outbound_scid_alias
+	}
+
+	/// Estimates the on-chain costs of opening an outbound channel with the given value and
+	/// [`ChannelTypeFeatures`] using our default config, as well as those of eventually closing it.
+	///
+	/// This lets wallets display the full cost of a channel before calling
+	/// [`ChannelManager::create_channel`].
+	///
+	/// Raises [`APIError::APIMisuseError`] if `channel_type` requires features we do not support.
+	pub fn estimate_channel_costs(&self, channel_value_satoshis: u64, channel_type: &ChannelTypeFeatures) -> Result<ChannelCostEstimate, APIError> {
+		if channel_type.requires_unknown_bits() {
+			return Err(APIError::APIMisuseError { err: "Cannot estimate the costs of a channel type we do not support".to_owned() });
+		}
+		let opt_anchors = false; // TODO - should be based on channel_type, once we support anchors
+
+		let feerate = Channel::<Signer>::get_outbound_feerate(&self.fee_estimator, &self.get_current_default_configuration());
+		let normal_feerate = self.fee_estimator.bounded_sat_per_1000_weight(ConfirmationTarget::Normal);
+		let background_feerate = self.fee_estimator.bounded_sat_per_1000_weight(ConfirmationTarget::Background);
+		let commitment_tx_fee_satoshis = feerate as u64 * commitment_tx_base_weight(opt_anchors) / 1000;
+
+		Ok(ChannelCostEstimate {
+			feerate_sat_per_1000_weight: feerate,
+			funding_tx_fee_satoshis: feerate as u64 * TYPICAL_FUNDING_TX_WEIGHT / 1000,
+			commitment_tx_fee_satoshis,
+			commitment_tx_fee_per_htlc_satoshis: feerate as u64 * COMMITMENT_TX_WEIGHT_PER_HTLC / 1000,
+			counterparty_reserve_satoshis: Channel::<Signer>::get_holder_selected_channel_reserve_satoshis(
+				channel_value_satoshis, &self.get_current_default_configuration()),
+			expected_holder_reserve_satoshis: Channel::<Signer>::get_legacy_default_holder_selected_channel_reserve_satoshis(
+				channel_value_satoshis),
+			cooperative_close_fee_satoshis: normal_feerate as u64 * CLOSING_TX_WEIGHT / 1000,
+			force_close_fee_satoshis: commitment_tx_fee_satoshis + background_feerate as u64 * TO_SELF_SWEEP_TX_WEIGHT / 1000,
+		})
+	}
+
+	/// Creates a new outbound channel to the given remote node and with the given value.
+	///
+	/// `user_channel_id` will be provided back as in
+	/// [`Event::FundingGenerationReady::user_channel_id`] to allow tracking of which events
+	/// correspond with which `create_channel` call. Note that the `user_channel_id` defaults to 0
+	/// for inbound channels, so you may wish to avoid using 0 for `user_channel_id` here.
+	/// `user_channel_id` has no meaning inside of LDK, it is simply copied to events and otherwise
+	/// ignored.
+	///
+	/// Raises [`APIError::APIMisuseError`] when `channel_value_satoshis` > 2**24 or `push_msat` is
+	/// greater than `channel_value_satoshis * 1k` or `channel_value_satoshis < 1000`.
+	///
+	/// Raises [`APIError::ChannelUnavailable`] if the peer does not support a feature required by
+	/// the config's [`UserConfig::peer_feature_requirements`].
+	///
+	/// Note that we do not check if you are currently connected to the given peer. If no
+	/// connection is available, the outbound `open_channel` message may fail to send, resulting in
+	/// the channel eventually being silently forgotten (dropped on reload).
+	///
+	/// Returns the new Channel's temporary `channel_id`. This ID will appear as
+	/// [`Event::FundingGenerationReady::temporary_channel_id`] and in
+	/// [`ChannelDetails::channel_id`] until after
 	/// [`ChannelManager::funding_transaction_generated`] is called, swapping the Channel's ID for
 	/// one derived from the funding transaction's TXID. If the counterparty rejects the channel
 	/// immediately, this temporary ID will appear in [`Event::ChannelClosed::channel_id`].
@@ -1686,18 +3098,27 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// [`Event::FundingGenerationReady::temporary_channel_id`]: events::Event::FundingGenerationReady::temporary_channel_id
 	/// [`Event::ChannelClosed::channel_id`]: events::Event::ChannelClosed::channel_id
 	pub fn create_channel(&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64, user_channel_id: u64, override_config: Option<UserConfig>) -> Result<[u8; 32], APIError> {
+		self.check_active()?;
 		if channel_value_satoshis < 1000 {
 			return Err(APIError::APIMisuseError { err: format!("Channel value must be at least 1000 satoshis. It was {}", channel_value_satoshis) });
 		}
+		self.check_event_queue_capacity()?;
 
 		let channel = {
 			let per_peer_state = self.per_peer_state.read().unwrap();
 			match per_peer_state.get(&their_network_key) {
 				Some(peer_state) => {
-					let outbound_scid_alias = self.create_and_insert_outbound_scid_alias();
 					let peer_state = peer_state.lock().unwrap();
 					let their_features = &peer_state.latest_features;
-					let config = if override_config.is_some() { override_config.as_ref().unwrap() } else { &self.default_configuration };
+					let default_config = self.get_config_for_peer(&their_network_key);
+					let config = if override_config.is_some() { override_config.as_ref().unwrap() } else { &default_config };
+					let missing_features = config.peer_feature_requirements.missing_features(their_features);
+					if !missing_features.is_empty() {
+						return Err(APIError::ChannelUnavailable {
+							err: format!("Peer {} does not support required features: {}", their_network_key, missing_features.join(", "))
+						});
+					}
+					let outbound_scid_alias = self.create_and_insert_outbound_scid_alias();
 					match Channel::new_outbound(&self.fee_estimator, &self.keys_manager, their_network_key,
 						their_features, channel_value_satoshis, push_msat, user_channel_id, config,
 						self.best_block.read().unwrap().height(), outbound_scid_alias)
@@ -1714,12 +3135,22 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		};
 		let res = channel.get_open_channel(self.genesis_hash.clone());
 
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() {
+			Ok(guard) => guard,
+			Err(e) => {
+				self.outbound_scid_aliases.lock().unwrap().remove(&channel.outbound_scid_alias());
+				return Err(e);
+			},
+		};
 		// We want to make sure the lock is actually acquired by PersistenceNotifierGuard.
 		debug_assert!(&self.total_consistency_lock.try_write().is_err());
 
 		let temporary_channel_id = channel.channel_id();
 		let mut channel_state = self.channel_state.lock().unwrap();
+		if let Err(err) = self.check_pending_channel_limit(&their_network_key, &channel_state.by_id) {
+			self.outbound_scid_aliases.lock().unwrap().remove(&channel.outbound_scid_alias());
+			return Err(APIError::ChannelUnavailable { err });
+		}
 		match channel_state.by_id.entry(temporary_channel_id) {
 			hash_map::Entry::Occupied(_) => {
 				if cfg!(fuzzing) {
@@ -1737,6 +3168,85 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		Ok(temporary_channel_id)
 	}
 
+	/// Creates a new outbound channel, as in [`ChannelManager::create_channel`], to be funded
+	/// out-of-band within `funding_expiry_secs` seconds.
+	///
+	/// Returns a [`FundingIntent`] describing the funding output, which is tracked until the
+	/// channel is funded via [`ChannelManager::funding_transaction_generated`] or closed. Its
+	/// current state is available via [`ChannelManager::get_funding_intent`].
+	///
+	/// Note that the funding output's script is not known until our counterparty accepts the
+	/// channel, and that the channel is closed if the intent expires before it is funded.
+	pub fn create_channel_with_funding_intent(&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64, user_channel_id: u64, override_config: Option<UserConfig>, funding_expiry_secs: u32) -> Result<FundingIntent, APIError> {
+		let temporary_channel_id = self.create_channel(their_network_key, channel_value_satoshis, push_msat, user_channel_id, override_config)?;
+		let intent = FundingIntent {
+			temporary_channel_id,
+			counterparty_node_id: their_network_key,
+			user_channel_id,
+			channel_value_satoshis,
+			output_script: None,
+			expires_at: self.highest_seen_timestamp.load(Ordering::Acquire) as u64 + funding_expiry_secs as u64,
+		};
+		self.funding_intents.lock().unwrap().insert(temporary_channel_id, intent.clone());
+		Ok(intent)
+	}
+
+	/// Gets the [`FundingIntent`] of the channel with the given temporary channel ID, if it was
+	/// created via [`ChannelManager::create_channel_with_funding_intent`] and has not yet been
+	/// funded, closed or expired.
+	pub fn get_funding_intent(&self, temporary_channel_id: &[u8; 32]) -> Option<FundingIntent> {
+		self.funding_intents.lock().unwrap().get(temporary_channel_id).cloned()
+	}
+
+	/// Forgets the pending inbound payments and inbound payment limits which expired before
+	/// `timestamp`.
+	fn expire_inbound_payments(&self, timestamp: u64) {
+		self.pending_inbound_payments.lock().unwrap().retain(|_, inbound_payment| {
+			inbound_payment.expiry_time > timestamp
+		});
+		self.inbound_payment_limits.lock().unwrap().retain(|_, limits| {
+			limits.expiry_time > timestamp
+		});
+	}
+
+	/// Gets all pending [`FundingIntent`]s. See [`ChannelManager::get_funding_intent`].
+	pub fn list_funding_intents(&self) -> Vec<FundingIntent> {
+		self.funding_intents.lock().unwrap().values().cloned().collect()
+	}
+
+	/// Closes the channels of any [`FundingIntent`]s which have expired, and forgets the intents of
+	/// channels which have otherwise been closed.
+	fn expire_funding_intents(&self) {
+		let highest_seen_timestamp = self.highest_seen_timestamp.load(Ordering::Acquire) as u64;
+		let mut expired_channels = Vec::new();
+		{
+			let mut channel_state_lock = self.channel_state.lock().unwrap();
+			let channel_state = &mut *channel_state_lock;
+			self.funding_intents.lock().unwrap().retain(|temporary_channel_id, intent| {
+				match channel_state.by_id.entry(*temporary_channel_id) {
+					hash_map::Entry::Occupied(chan) => {
+						if chan.get().is_funding_initiated() { return false; }
+						if intent.expires_at > highest_seen_timestamp { return true; }
+						log_info!(self.logger, "Closing channel {} as its funding intent expired", log_bytes!(temporary_channel_id[..]));
+						self.issue_channel_close_events(chan.get(), ClosureReason::FundingIntentExpired);
+						channel_state.pending_msg_events.push(events::MessageSendEvent::HandleError {
+							node_id: intent.counterparty_node_id,
+							action: msgs::ErrorAction::SendErrorMessage {
+								msg: msgs::ErrorMessage { channel_id: *temporary_channel_id, data: "Channel funding expired".to_owned() }
+							},
+						});
+						expired_channels.push(remove_channel!(self, channel_state, chan));
+						false
+					},
+					hash_map::Entry::Vacant(_) => false,
+				}
+			});
+		}
+		for mut chan in expired_channels.drain(..) {
+			self.finish_force_close_channel(chan.force_shutdown(false));
+		}
+	}
+
 	fn list_channels_with_filter<Fn: FnMut(&(&[u8; 32], &Channel<Signer>)) -> bool>(&self, f: Fn) -> Vec<ChannelDetails> {
 		let mut res = Vec::new();
 		{
@@ -1785,6 +3295,8 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					inbound_htlc_minimum_msat: Some(channel.get_holder_htlc_minimum_msat()),
 					inbound_htlc_maximum_msat: channel.get_holder_htlc_maximum_msat(),
 					config: Some(channel.config()),
+					is_on_probation: channel.is_on_probation(),
+					is_shutting_down: channel.sent_shutdown() || channel.received_shutdown(),
 				});
 			}
 		}
@@ -1818,6 +3330,84 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		self.list_channels_with_filter(|&(_, ref channel)| channel.is_live())
 	}
 
+	/// Simulates the worst-case on-chain exposure of the HTLCs currently pending on each of our
+	/// channels under `config`, reporting which channels would violate its limits. This allows
+	/// evaluating a config change before applying it, e.g. via [`Self::update_channel_config`].
+	///
+	/// If `feerate_sat_per_1000_weight` is set, dust thresholds are calculated as if the channels'
+	/// feerates had risen to it. Dust thresholds always include the buffer we apply against future
+	/// feerate increases, and a lower feerate than a channel's current one is ignored.
+	///
+	/// Note that limits negotiated when a channel is opened, such as those derived from
+	/// [`UserConfig::channel_handshake_config`] and [`UserConfig::channel_handshake_limits`], are
+	/// not updated on existing channels. For those, the result shows whether the channel's current
+	/// state would have been acceptable had it been opened with `config`.
+	pub fn simulate_config_exposure(&self, config: &UserConfig, feerate_sat_per_1000_weight: Option<u32>) -> Vec<ChannelConfigExposure> {
+		let channel_state = self.channel_state.lock().unwrap();
+		channel_state.by_id.values()
+			.map(|chan| chan.simulate_config_exposure(config, feerate_sat_per_1000_weight))
+			.collect()
+	}
+
+	/// Reports which channel types and workflows the given peer supports, allowing users to check
+	/// a peer's capabilities before committing to opening a channel with it.
+	///
+	/// If we're connected to the peer, this is based on the features it sent us in its `init`
+	/// message. Otherwise, it falls back to the features in the peer's `node_announcement`, if
+	/// `network_graph` is provided and contains one. Note that we never connect to peers
+	/// ourselves, so you should connect to the peer (e.g. via [`PeerManager::new_outbound_connection`])
+	/// first to get the most accurate results. If neither source is available, all capabilities
+	/// are reported as [`PeerCapabilitySupport::Unknown`].
+	///
+	/// [`PeerManager::new_outbound_connection`]: crate::ln::peer_handler::PeerManager::new_outbound_connection
+	pub fn probe_peer_capabilities(&self, counterparty_node_id: &PublicKey, network_graph: Option<&ReadOnlyNetworkGraph>) -> PeerCapabilities {
+		let init_features = self.per_peer_state.read().unwrap().get(counterparty_node_id)
+			.map(|peer_state| peer_state.lock().unwrap().latest_features.clone());
+		let announced_features = network_graph
+			.and_then(|graph| graph.node(&NodeId::from_pubkey(counterparty_node_id)))
+			.and_then(|node| node.announcement_info.as_ref())
+			.map(|announcement_info| announcement_info.features.clone());
+
+		let our_features = InitFeatures::known();
+		let peer_features: Option<NodeFeatures> = match (&init_features, &announced_features) {
+			(Some(init_features), _) => Some(init_features.to_context()),
+			(None, Some(announced_features)) => Some(announced_features.clone()),
+			(None, None) => None,
+		};
+		macro_rules! support {
+			($supported_by_peer: expr, $supported_by_us: expr) => {
+				PeerCapabilitySupport::new(peer_features.as_ref().map($supported_by_peer), $supported_by_us)
+			}
+		}
+		// Features we don't understand are dropped when converting between contexts, so check
+		// for them in the features as we received them.
+		let supports_unknown_feature = |required_bits: &[usize]| -> Option<bool> {
+			match (&init_features, &announced_features) {
+				(Some(init_features), _) => Some(required_bits.iter().any(|bit| init_features.supports_feature_bit_pair(*bit))),
+				(None, Some(announced_features)) => Some(required_bits.iter().any(|bit| announced_features.supports_feature_bit_pair(*bit))),
+				(None, None) => None,
+			}
+		};
+		let missing_required_features = match init_features {
+			Some(ref features) => self.get_current_default_configuration().peer_feature_requirements.missing_features(features),
+			None => Vec::new(),
+		};
+		PeerCapabilities {
+			counterparty_node_id: *counterparty_node_id,
+			is_connected: init_features.is_some(),
+			static_remote_key: support!(|f| f.supports_static_remote_key(), our_features.supports_static_remote_key()),
+			anchors: PeerCapabilitySupport::new(supports_unknown_feature(&[20, 22]), false),
+			dual_funding: PeerCapabilitySupport::new(supports_unknown_feature(&[28]), false),
+			zero_conf: support!(|f| f.supports_zero_conf(), our_features.supports_zero_conf()),
+			scid_privacy: support!(|f| f.supports_scid_privacy(), our_features.supports_scid_privacy()),
+			wumbo: support!(|f| f.supports_wumbo(), our_features.supports_wumbo()),
+			taproot: PeerCapabilitySupport::new(supports_unknown_feature(&[80, 180]), false),
+			missing_required_features,
+			init_features,
+			announced_features,
+		}
+	}
+
 	/// Helper function that issues the channel close events
 	fn issue_channel_close_events(&self, channel: &Channel<Signer>, closure_reason: ClosureReason) {
 		let mut pending_events_lock = self.pending_events.lock().unwrap();
@@ -1827,6 +3417,14 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			},
 			None => {},
 		}
+		if closure_reason == ClosureReason::FundingTimedOut && channel.is_outbound() {
+			pending_events_lock.push(events::Event::FundingTimedOut {
+				channel_id: channel.channel_id(),
+				user_channel_id: channel.get_user_id(),
+				funding_txo: channel.get_funding_txo(),
+				funding_inputs: channel.get_funding_tx_inputs(),
+			});
+		}
 		pending_events_lock.push(events::Event::ChannelClosed {
 			channel_id: channel.channel_id(),
 			user_channel_id: channel.get_user_id(),
@@ -1834,8 +3432,8 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		});
 	}
 
-	fn close_channel_internal(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, target_feerate_sats_per_1000_weight: Option<u32>) -> Result<(), APIError> {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+	fn close_channel_internal(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, target_feerate_sats_per_1000_weight: Option<u32>, override_shutdown_script: Option<ShutdownScript>) -> Result<(), APIError> {
+		let _persistence_guard = self.active_persistence_guard()?;
 
 		let mut failed_htlcs: Vec<(HTLCSource, PaymentHash)>;
 		let result: Result<(), _> = loop {
@@ -1851,7 +3449,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 						Some(peer_state) => {
 							let peer_state = peer_state.lock().unwrap();
 							let their_features = &peer_state.latest_features;
-							chan_entry.get_mut().get_shutdown(&self.keys_manager, their_features, target_feerate_sats_per_1000_weight)?
+							chan_entry.get_mut().get_shutdown(&self.keys_manager, their_features, target_feerate_sats_per_1000_weight, override_shutdown_script)?
 						},
 						None => return Err(APIError::ChannelUnavailable { err: format!("Not connected to node: {}", counterparty_node_id) }),
 					};
@@ -1916,7 +3514,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// [`Background`]: crate::chain::chaininterface::ConfirmationTarget::Background
 	/// [`Normal`]: crate::chain::chaininterface::ConfirmationTarget::Normal
 	pub fn close_channel(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey) -> Result<(), APIError> {
-		self.close_channel_internal(channel_id, counterparty_node_id, None)
+		self.close_channel_internal(channel_id, counterparty_node_id, None, None)
 	}
 
 	/// Begins the process of closing a channel. After this call (plus some timeout), no new HTLCs
@@ -1939,7 +3537,28 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// [`Background`]: crate::chain::chaininterface::ConfirmationTarget::Background
 	/// [`Normal`]: crate::chain::chaininterface::ConfirmationTarget::Normal
 	pub fn close_channel_with_target_feerate(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, target_feerate_sats_per_1000_weight: u32) -> Result<(), APIError> {
-		self.close_channel_internal(channel_id, counterparty_node_id, Some(target_feerate_sats_per_1000_weight))
+		self.close_channel_internal(channel_id, counterparty_node_id, Some(target_feerate_sats_per_1000_weight), None)
+	}
+
+	/// Begins the process of closing a channel, paying our balance out to `shutdown_script` rather
+	/// than the script provided by [`KeysInterface::get_shutdown_scriptpubkey`], e.g. to send the
+	/// funds directly to cold storage. Otherwise behaves as [`Self::close_channel`], or
+	/// [`Self::close_channel_with_target_feerate`] if `target_feerate_sats_per_1000_weight` is set.
+	///
+	/// Note that the cooperative close protocol allows only a single output per side, so our balance
+	/// cannot be split between several scripts on the closing transaction.
+	///
+	/// Fails with an [`APIError::APIMisuseError`] if the channel was opened with an upfront shutdown
+	/// script (see [`ChannelHandshakeConfig::commit_upfront_shutdown_pubkey`]), as we've committed
+	/// to closing to that script, or with an [`APIError::IncompatibleShutdownScript`] if our
+	/// counterparty doesn't support `shutdown_script`.
+	///
+	/// May generate a SendShutdown message event on success, which should be relayed.
+	///
+	/// [`KeysInterface::get_shutdown_scriptpubkey`]: crate::chain::keysinterface::KeysInterface::get_shutdown_scriptpubkey
+	/// [`ChannelHandshakeConfig::commit_upfront_shutdown_pubkey`]: crate::util::config::ChannelHandshakeConfig::commit_upfront_shutdown_pubkey
+	pub fn close_channel_with_shutdown_script(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, target_feerate_sats_per_1000_weight: Option<u32>, shutdown_script: ShutdownScript) -> Result<(), APIError> {
+		self.close_channel_internal(channel_id, counterparty_node_id, target_feerate_sats_per_1000_weight, Some(shutdown_script))
 	}
 
 	#[inline]
@@ -1964,6 +3583,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// user closes, which will be re-exposed as the `ChannelClosed` reason.
 	fn force_close_channel_with_peer(&self, channel_id: &[u8; 32], peer_node_id: &PublicKey, peer_msg: Option<&String>, broadcast: bool)
 	-> Result<PublicKey, APIError> {
+		self.check_active()?;
 		let mut chan = {
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = &mut *channel_state_lock;
@@ -1994,7 +3614,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	}
 
 	fn force_close_sending_error(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, broadcast: bool) -> Result<(), APIError> {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = self.active_persistence_guard()?;
 		match self.force_close_channel_with_peer(channel_id, counterparty_node_id, None, broadcast) {
 			Ok(counterparty_node_id) => {
 				self.channel_state.lock().unwrap().pending_msg_events.push(
@@ -2047,9 +3667,540 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		}
 	}
 
+	/// Freezes all channels, e.g. in response to a suspected key compromise or infrastructure
+	/// issue, without force-closing them.
+	///
+	/// While frozen, we:
+	///  * refuse to send new payments, returning an [`APIError::ChannelUnavailable`],
+	///  * fail back any new HTLCs we receive, whether destined for us or to be forwarded, with a
+	///    `temporary_node_failure`,
+	///  * broadcast `channel_update`s marking all of our public channels as disabled, and
+	///  * stop updating the feerate on our outbound channels.
+	///
+	/// HTLCs which were already accepted continue to be forwarded, claimed or failed as usual, and
+	/// cooperative and force closes are unaffected.
+	///
+	/// Channels remain frozen until [`ChannelManager::unfreeze_all_channels`] is called. Note that
+	/// this state is not persisted, so this must be called again after reloading the
+	/// `ChannelManager` if channels should remain frozen.
+	pub fn freeze_all_channels(&self) {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		if self.channels_frozen.swap(true, Ordering::AcqRel) { return; }
+		log_info!(self.logger, "Freezing all channels, new HTLCs will be rejected until unfrozen");
+
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = &mut *channel_state_lock;
+		for (_, chan) in channel_state.by_id.iter_mut() {
+			if let ChannelUpdateStatus::Disabled = chan.channel_update_status() { continue; }
+			chan.set_channel_update_status(ChannelUpdateStatus::Disabled);
+			if let Ok(update) = self.get_channel_update_for_broadcast(chan) {
+				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+					msg: update
+				});
+			}
+		}
+	}
+
+	/// Unfreezes all channels previously frozen via [`ChannelManager::freeze_all_channels`],
+	/// re-enabling any channels which are live and resuming normal operation.
+	pub fn unfreeze_all_channels(&self) {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		if !self.channels_frozen.swap(false, Ordering::AcqRel) { return; }
+		log_info!(self.logger, "Unfreezing all channels");
+
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = &mut *channel_state_lock;
+		for (_, chan) in channel_state.by_id.iter_mut() {
+			// Channels which aren't live will be re-enabled by `timer_tick_occurred` once they are.
+			if !chan.is_live() { continue; }
+			chan.set_channel_update_status(ChannelUpdateStatus::Enabled);
+			if let Ok(update) = self.get_channel_update_for_broadcast(chan) {
+				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+					msg: update
+				});
+			}
+		}
+	}
+
+	/// Returns whether all channels are currently frozen, see
+	/// [`ChannelManager::freeze_all_channels`].
+	pub fn channels_frozen(&self) -> bool {
+		self.channels_frozen.load(Ordering::Acquire)
+	}
+
+	/// Gets our current [`NodeRole`].
+	pub fn role(&self) -> NodeRole {
+		if self.standby.load(Ordering::Acquire) { NodeRole::Standby } else { NodeRole::Active }
+	}
+
+	/// Fails if we're a standby. As we may become a standby concurrently, this only suffices to
+	/// fail early, see [`Self::active_persistence_guard`].
+	fn check_active(&self) -> Result<(), APIError> {
+		if self.standby.load(Ordering::Acquire) {
+			return Err(APIError::APIMisuseError { err: "A standby ChannelManager must be promoted before it can be used".to_owned() });
+		}
+		Ok(())
+	}
+
+	/// Takes a read lock on `total_consistency_lock` as [`PersistenceNotifierGuard::notify_on_drop`]
+	/// does, failing if we're a standby.
+	///
+	/// [`ChannelManager::step_down`] makes us a standby while holding the write lock, so we only
+	/// check for it once we hold the read lock. Otherwise, a call which was waiting on the lock
+	/// while we stepped down could go on to sign new channel states as a standby.
+	fn active_persistence_guard(&self) -> Result<PersistenceNotifierGuard<'_, impl Fn() -> NotifyOption + '_>, APIError> {
+		// A standby is never persisted, so we don't notify if we fail.
+		let guard = PersistenceNotifierGuard::optionally_notify(&self.total_consistency_lock, &self.persistence_notifier,
+			move || if self.standby.load(Ordering::Acquire) { NotifyOption::SkipPersist } else { NotifyOption::DoPersist });
+		self.check_active()?;
+		Ok(guard)
+	}
+
+	/// Promotes a [`NodeRole::Standby`] to become the node's active instance, after which it may
+	/// be persisted, synced to the chain from [`ChannelManager::current_best_block`] and connected
+	/// to peers as on any startup. Generates an [`Event::RoleChanged`] on success.
+	///
+	/// `channel_monitors` must contain the latest [`ChannelMonitor`] of each of the node's
+	/// channels, e.g. as persisted by a [`WatchingService`]. As on deserialization, these should be
+	/// read from storage rather than borrowed from the [`chain::Watch`], whose locks may not be
+	/// held while calling into the `ChannelManager`. Promotion is refused if any of our channels
+	/// is missing its monitor or is not in sync with it, as our copy of the node's state is then
+	/// stale, in which case we should be re-read from the latest persisted state first. Monitors
+	/// for channels we don't have are handled as on deserialization, broadcasting their latest
+	/// holder commitment transaction.
+	///
+	/// Promotion is also refused if the given `fence` indicates that the previously active
+	/// instance may still be operating, as both instances would otherwise sign conflicting
+	/// channel states. The checks and the promotion happen atomically with respect to other calls
+	/// into the `ChannelManager`.
+	///
+	/// [`Event::RoleChanged`]: events::Event::RoleChanged
+	/// [`WatchingService`]: crate::chain::replication::WatchingService
+	pub fn promote<Fence: Deref>(&self, fence: Fence, channel_monitors: Vec<&ChannelMonitor<Signer>>) -> Result<(), APIError>
+	where Fence::Target: LeadershipFence {
+		let mut stored_preimages = Vec::new();
+		{
+			let _consistency_lock = self.total_consistency_lock.write().unwrap();
+			if !self.standby.load(Ordering::Acquire) {
+				return Err(APIError::APIMisuseError { err: "Only a standby ChannelManager can be promoted".to_owned() });
+			}
+
+			let channel_monitors: HashMap<OutPoint, &ChannelMonitor<Signer>> = channel_monitors.into_iter()
+				.map(|monitor| (monitor.get_funding_txo().0, monitor)).collect();
+			let channel_state = self.channel_state.lock().unwrap();
+			let mut funding_txo_set = HashSet::with_capacity(channel_state.by_id.len());
+			for (channel_id, channel) in channel_state.by_id.iter() {
+				let funding_txo = match channel.get_funding_txo() { Some(funding_txo) => funding_txo, None => continue };
+				funding_txo_set.insert(funding_txo);
+				let monitor = channel_monitors.get(&funding_txo).ok_or_else(|| APIError::APIMisuseError {
+					err: format!("Missing ChannelMonitor for channel {}", log_bytes!(*channel_id)),
+				})?;
+				if channel.get_cur_holder_commitment_transaction_number() != monitor.get_cur_holder_commitment_number() ||
+					channel.get_revoked_counterparty_commitment_transaction_number() != monitor.get_min_seen_secret() ||
+					channel.get_cur_counterparty_commitment_transaction_number() != monitor.get_cur_counterparty_commitment_number() ||
+					channel.get_latest_monitor_update_id() != monitor.get_latest_update_id()
+				{
+					return Err(APIError::APIMisuseError {
+						err: format!("Channel {} is at update_id {} but its ChannelMonitor is at update_id {}",
+							log_bytes!(*channel_id), channel.get_latest_monitor_update_id(), monitor.get_latest_update_id()),
+					});
+				}
+			}
+
+			if !fence.fence_previous_active() {
+				return Err(APIError::APIMisuseError { err: "The previously active instance may still be operating".to_owned() });
+			}
+			log_info!(self.logger, "Promoting standby ChannelManager to active");
+
+			let best_block_height = self.best_block.read().unwrap().height();
+			let mut pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+			for (funding_txo, monitor) in channel_monitors.iter() {
+				if !funding_txo_set.contains(funding_txo) {
+					log_info!(self.logger, "Broadcasting latest holder commitment transaction for closed channel {}", log_bytes!(funding_txo.to_channel_id()));
+					monitor.broadcast_latest_holder_commitment_txn(&self.tx_broadcaster, &self.logger);
+					let _ = track_monitor_pending_outbound_htlcs(&mut pending_outbound_payments, monitor, best_block_height, &self.logger);
+				}
+				for (payment_hash, payment_preimage) in monitor.get_stored_preimages() {
+					if channel_state.claimable_htlcs.contains_key(&payment_hash) {
+						stored_preimages.push(payment_preimage);
+					}
+				}
+			}
+
+			self.standby.store(false, Ordering::Release);
+			let mut pending_events = self.pending_events.lock().unwrap();
+			pending_events.append(&mut *self.standby_held_events.lock().unwrap());
+			pending_events.push(events::Event::RoleChanged { role: NodeRole::Active });
+		}
+		self.persistence_notifier.notify();
+
+		// As on deserialization, re-claim any payments whose preimage we've released to a
+		// ChannelMonitor, which we otherwise may not be told to claim again.
+		for payment_preimage in stored_preimages {
+			self.claim_funds(payment_preimage);
+		}
+		Ok(())
+	}
+
+	/// Steps down from being the node's active instance to become a [`NodeRole::Standby`], e.g.
+	/// to hand over to another instance, generating an [`Event::RoleChanged`]. Does nothing if
+	/// we're already a standby.
+	///
+	/// All peers are disconnected, and from then on we neither talk to peers nor sign anything.
+	/// As a standby may not be persisted, this should be called only after the
+	/// `ChannelManager` has been persisted for the final time, e.g. after a
+	/// `lightning-background-processor` `BackgroundProcessor` has been stopped. Once any monitor
+	/// updates in flight have completed, the standby instance may be told it can take over, e.g.
+	/// via [`ReplicatingPersister::step_down`].
+	///
+	/// [`Event::RoleChanged`]: events::Event::RoleChanged
+	/// [`ReplicatingPersister::step_down`]: crate::chain::replication::ReplicatingPersister::step_down
+	pub fn step_down(&self) {
+		if self.standby.load(Ordering::Acquire) { return; }
+
+		let peers: Vec<PublicKey> = self.per_peer_state.read().unwrap().keys().cloned().collect();
+		for counterparty_node_id in peers.iter() {
+			ChannelMessageHandler::peer_disconnected(self, counterparty_node_id, false);
+		}
+
+		let _consistency_lock = self.total_consistency_lock.write().unwrap();
+		if self.standby.swap(true, Ordering::AcqRel) { return; }
+		log_info!(self.logger, "Stepping down to standby");
+		let mut channel_state = self.channel_state.lock().unwrap();
+		for counterparty_node_id in peers {
+			channel_state.pending_msg_events.push(events::MessageSendEvent::HandleError {
+				node_id: counterparty_node_id,
+				action: msgs::ErrorAction::DisconnectPeer { msg: None },
+			});
+		}
+		self.pending_events.lock().unwrap().push(events::Event::RoleChanged { role: NodeRole::Standby });
+	}
+
+	/// Sets the [`ForceCloseDecisionHandler`] consulted before force-closing channels for
+	/// non-security-critical reasons, replacing any previously set handler. Without a handler,
+	/// such force-closes happen immediately.
+	///
+	/// Note that the handler is not persisted, and thus must be set again after reloading the
+	/// `ChannelManager`.
+	pub fn set_force_close_decision_handler(&self, handler: Box<dyn ForceCloseDecisionHandler + Send>) {
+		*self.force_close_decision_handler.lock().unwrap() = Some(handler);
+	}
+
+	/// Consults our [`ForceCloseDecisionHandler`], if any, about force-closing `chan` for the given
+	/// `reason`, returning true if it should be closed now.
+	fn should_force_close(&self, chan: &mut Channel<Signer>, reason: ForceCloseReason) -> bool {
+		let decision = match *self.force_close_decision_handler.lock().unwrap() {
+			Some(ref handler) => handler.decide_force_close(&chan.channel_id(), &chan.get_counterparty_node_id(), &reason),
+			None => return true,
+		};
+		match decision {
+			ForceCloseDecision::Approve => true,
+			ForceCloseDecision::Delay => {
+				log_info!(self.logger, "Delaying force-close of channel {} ({:?}) as requested", log_bytes!(chan.channel_id()), reason);
+				false
+			},
+			ForceCloseDecision::DisableAndWait => {
+				log_info!(self.logger, "Delaying force-close of channel {} ({:?}) and disabling it as requested", log_bytes!(chan.channel_id()), reason);
+				chan.start_probation(self.get_current_default_configuration().channel_probation.probation_ticks);
+				false
+			},
+		}
+	}
+
+	/// Sets the [`ForwardPolicyHandler`] consulted before forwarding HTLCs, replacing any
+	/// previously set handler. Without a handler, all forwards which pass our own checks are
+	/// allowed.
+	///
+	/// Note that the handler is not persisted, and thus must be set again after reloading the
+	/// `ChannelManager`. Held forwards are persisted, but are passed to the handler again after
+	/// reloading.
+	pub fn set_forward_policy_handler(&self, handler: Box<dyn ForwardPolicyHandler + Send>) {
+		*self.forward_policy_handler.lock().unwrap() = Some(handler);
+	}
+
+	/// Completes the decision on a forward our [`ForwardPolicyHandler`] asked us to hold via
+	/// [`ForwardPolicyDecision::Hold`], forwarding the HTLC if `allow` is set, or failing it back
+	/// otherwise. The forward is identified by the [`ForwardRequest::prev_channel_id`] and
+	/// [`ForwardRequest::prev_htlc_id`] it was requested with.
+	///
+	/// The HTLC is forwarded or failed on the next call to
+	/// [`ChannelManager::process_pending_htlc_forwards`], for which an
+	/// [`Event::PendingHTLCsForwardable`] is generated.
+	///
+	/// Errors if no such forward is held, e.g. as it was already decided on or timed out.
+	///
+	/// [`Event::PendingHTLCsForwardable`]: events::Event::PendingHTLCsForwardable
+	pub fn resolve_held_forward(&self, prev_channel_id: &[u8; 32], prev_htlc_id: u64, allow: bool) -> Result<(), APIError> {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		{
+			let mut held_forwards = self.held_forwards.lock().unwrap();
+			let held_forward = match held_forwards.held.remove(&(*prev_channel_id, prev_htlc_id)) {
+				Some(held_forward) => held_forward,
+				None => return Err(APIError::APIMisuseError { err: "No such forward is held".to_owned() }),
+			};
+			log_debug!(self.logger, "{} held forward of HTLC {} from channel {}", if allow { "Allowing" } else { "Denying" },
+				prev_htlc_id, log_bytes!(*prev_channel_id));
+			held_forwards.decided.push((held_forward, allow));
+		}
+		self.pending_events.lock().unwrap().push(events::Event::PendingHTLCsForwardable {
+			time_forwardable: Duration::from_millis(MIN_HTLC_RELAY_HOLDING_CELL_MILLIS),
+		});
+		Ok(())
+	}
+
+	/// Forwards or fails back, as set by [`ForwardPolicyConfig::fail_open`], the held forwards which
+	/// haven't been decided on within [`ForwardPolicyConfig::max_hold_ticks`], after counting
+	/// another `ticks` ticks against each. Returns whether any timed out.
+	///
+	/// [`ForwardPolicyConfig::fail_open`]: crate::util::config::ForwardPolicyConfig::fail_open
+	/// [`ForwardPolicyConfig::max_hold_ticks`]: crate::util::config::ForwardPolicyConfig::max_hold_ticks
+	fn time_out_held_forwards(&self, ticks: u8) -> bool {
+		let config = self.get_current_default_configuration().forward_policy;
+		{
+			let mut held_forwards = self.held_forwards.lock().unwrap();
+			let mut timed_out = Vec::new();
+			for (id, held_forward) in held_forwards.held.iter_mut() {
+				held_forward.ticks_elapsed = held_forward.ticks_elapsed.saturating_add(ticks);
+				if held_forward.ticks_elapsed >= config.max_hold_ticks { timed_out.push(*id); }
+			}
+			if timed_out.is_empty() { return false; }
+			for (prev_channel_id, prev_htlc_id) in timed_out {
+				log_warn!(self.logger, "Timed out waiting for a decision on held forward of HTLC {} from channel {}, {} it instead",
+					prev_htlc_id, log_bytes!(prev_channel_id), if config.fail_open { "allowing" } else { "denying" });
+				let held_forward = held_forwards.held.remove(&(prev_channel_id, prev_htlc_id)).unwrap();
+				held_forwards.decided.push((held_forward, config.fail_open));
+			}
+		}
+		self.pending_events.lock().unwrap().push(events::Event::PendingHTLCsForwardable {
+			time_forwardable: Duration::from_millis(MIN_HTLC_RELAY_HOLDING_CELL_MILLIS),
+		});
+		true
+	}
+
+	/// Removes the incomplete MPP payments, whether received or to be relayed via trampoline,
+	/// which haven't completed within [`MPP_TIMEOUT_TICKS`], after counting another `ticks` ticks
+	/// against each. Returns the HTLCs of the removed payments, to be failed back.
+	fn time_out_mpp_htlcs(channel_state: &mut ChannelHolder<Signer>, ticks: u8) -> Vec<(HTLCPreviousHopData, PaymentHash)> {
+		let mut timed_out_mpp_htlcs = Vec::new();
+		channel_state.claimable_htlcs.retain(|payment_hash, (_, htlcs)| {
+			if htlcs.is_empty() {
+				// This should be unreachable
+				debug_assert!(false);
+				return false;
+			}
+			if let OnionPayload::Invoice { .. } = htlcs[0].onion_payload {
+				// Check if we've received all the parts we need for an MPP (the value of the parts adds to total_msat).
+				// In this case we're not going to handle any timeouts of the parts here.
+				if htlcs[0].total_msat <= htlcs.iter().fold(0, |total, htlc| total + htlc.value) {
+					return true;
+				} else if htlcs.iter_mut().any(|htlc| {
+					htlc.timer_ticks = htlc.timer_ticks.saturating_add(ticks);
+					htlc.timer_ticks >= MPP_TIMEOUT_TICKS
+				}) {
+					timed_out_mpp_htlcs.extend(htlcs.iter().map(|htlc| (htlc.prev_hop.clone(), *payment_hash)));
+					return false;
+				}
+			}
+			true
+		});
+
+		channel_state.trampoline_forwards.retain(|payment_hash, forward| {
+			if forward.total_received_msat() >= forward.htlcs[0].total_msat {
+				return true;
+			}
+			if forward.htlcs.iter_mut().any(|htlc| {
+				htlc.timer_ticks = htlc.timer_ticks.saturating_add(ticks);
+				htlc.timer_ticks >= MPP_TIMEOUT_TICKS
+			}) {
+				timed_out_mpp_htlcs.extend(forward.htlcs.drain(..).map(|htlc| (htlc.prev_hop, *payment_hash)));
+				return false;
+			}
+			true
+		});
+		timed_out_mpp_htlcs
+	}
+
+	/// Decides what to do with the pending `forward_info` to be forwarded over `next_chan`, either
+	/// from the decision made on it while it was held or by consulting our
+	/// [`ForwardPolicyHandler`], if any.
+	fn forward_policy_decision(
+		&self, forward_info: &HTLCForwardInfo, next_short_channel_id: u64, next_chan: &Channel<Signer>,
+		short_to_chan_info: &HashMap<u64, (PublicKey, [u8; 32])>, held_forward_decisions: &mut HashMap<([u8; 32], u64), bool>
+	) -> ForwardPolicyDecision {
+		let (prev_short_channel_id, prev_htlc_id, prev_funding_outpoint, info) = match forward_info {
+			HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, prev_funding_outpoint, forward_info } =>
+				(*prev_short_channel_id, *prev_htlc_id, *prev_funding_outpoint, forward_info),
+			HTLCForwardInfo::FailHTLC { .. } | HTLCForwardInfo::FailMalformedHTLC { .. } => return ForwardPolicyDecision::Allow,
+		};
+		let prev_channel_id = prev_funding_outpoint.to_channel_id();
+		if let Some(allow) = held_forward_decisions.remove(&(prev_channel_id, prev_htlc_id)) {
+			return if allow { ForwardPolicyDecision::Allow } else { ForwardPolicyDecision::Deny };
+		}
+		let handler_lock = self.forward_policy_handler.lock().unwrap();
+		let handler = match *handler_lock {
+			Some(ref handler) => handler,
+			None => return ForwardPolicyDecision::Allow,
+		};
+		let request = ForwardRequest {
+			payment_hash: info.payment_hash,
+			prev_channel_id,
+			prev_htlc_id,
+			prev_counterparty_node_id: short_to_chan_info.get(&prev_short_channel_id).map(|(cp_id, _)| *cp_id),
+			next_channel_id: next_chan.channel_id(),
+			next_short_channel_id,
+			next_counterparty_node_id: next_chan.get_counterparty_node_id(),
+			incoming_amount_msat: info.incoming_amt_msat,
+			outgoing_amount_msat: info.amt_to_forward,
+			outgoing_cltv_expiry: info.outgoing_cltv_value,
+			custom_tlvs: match info.routing {
+				PendingHTLCRouting::Forward { ref custom_tlvs, .. } => custom_tlvs.clone(),
+				_ => Vec::new(),
+			},
+		};
+		#[cfg(any(test, feature = "std"))]
+		let decision_start = Instant::now();
+		let decision = handler.decide_forward(&request);
+		#[cfg(any(test, feature = "std"))]
+		{
+			let config = self.get_current_default_configuration().forward_policy;
+			if decision_start.elapsed() > Duration::from_millis(config.max_decision_millis as u64) {
+				log_warn!(self.logger, "Forward policy handler took too long to decide on forwarding HTLC {} from channel {}, {} it instead",
+					prev_htlc_id, log_bytes!(prev_channel_id), if config.fail_open { "allowing" } else { "denying" });
+				return if config.fail_open { ForwardPolicyDecision::Allow } else { ForwardPolicyDecision::Deny };
+			}
+		}
+		decision
+	}
+
+	/// Takes a channel off probation before its probation period ends, allowing HTLCs to be
+	/// forwarded through it again. See [`ChannelProbationConfig`] for when channels are placed on
+	/// probation.
+	///
+	/// Does nothing if the channel isn't on probation.
+	///
+	/// [`ChannelProbationConfig`]: crate::util::config::ChannelProbationConfig
+	pub fn end_channel_probation(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey) -> Result<(), APIError> {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		match channel_state_lock.by_id.get_mut(channel_id) {
+			Some(chan) => {
+				if *counterparty_node_id != chan.get_counterparty_node_id() {
+					return Err(APIError::APIMisuseError { err: "The passed counterparty_node_id doesn't match the channel's counterparty node_id".to_owned() });
+				}
+				if chan.end_probation() {
+					log_info!(self.logger, "Channel {} taken off probation", log_bytes!(chan.channel_id()));
+				}
+				Ok(())
+			},
+			None => Err(APIError::ChannelUnavailable { err: "No such channel".to_owned() }),
+		}
+	}
+
+	/// Gets the retransmissions the given channel made in response to the last
+	/// `channel_reestablish` it received, if any since the `ChannelManager` was loaded.
+	pub fn get_last_reestablish_plan(&self, channel_id: &[u8; 32]) -> Option<ReestablishPlan> {
+		let channel_state = self.channel_state.lock().unwrap();
+		channel_state.by_id.get(channel_id).and_then(|chan| chan.get_last_reestablish_plan())
+	}
+
+	/// Sets the [`ReestablishOverrides`] applied each time the given channel is reestablished
+	/// from now on, or clears them if `overrides` is `None`. Overrides are not persisted.
+	///
+	/// This is unsafe to use in production as it may cause our counterparty to force-close the
+	/// channel, and thus requires the `unsafe_reestablish_overrides` feature.
+	#[cfg(any(test, feature = "unsafe_reestablish_overrides"))]
+	pub fn set_reestablish_overrides(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, overrides: Option<ReestablishOverrides>) -> Result<(), APIError> {
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		match channel_state_lock.by_id.get_mut(channel_id) {
+			Some(chan) => {
+				if *counterparty_node_id != chan.get_counterparty_node_id() {
+					return Err(APIError::APIMisuseError { err: "The passed counterparty_node_id doesn't match the channel's counterparty node_id".to_owned() });
+				}
+				chan.set_reestablish_overrides(overrides);
+				Ok(())
+			},
+			None => Err(APIError::ChannelUnavailable { err: "No such channel".to_owned() }),
+		}
+	}
+
+	/// Registers an extension's `extension_type` under `name`, allowing it to store opaque data via
+	/// [`ChannelManager::set_channel_extension_data`] and
+	/// [`ChannelManager::set_node_extension_data`]. See [`util::extensions`] for the rules
+	/// extension types must follow.
+	///
+	/// Fails if `extension_type` is reserved or already registered under a different name.
+	/// Registrations are not persisted, and thus must be made again after reloading the
+	/// `ChannelManager`.
+	///
+	/// [`util::extensions`]: crate::util::extensions
+	pub fn register_extension(&self, extension_type: u64, name: String) -> Result<(), APIError> {
+		self.extension_registry.lock().unwrap().register(extension_type, name)
+	}
+
+	/// Sets the opaque data stored alongside the given channel under the registered
+	/// `extension_type`, or removes it if `data` is `None`.
+	///
+	/// The data is persisted as a part of the `ChannelManager` and dropped once the channel
+	/// closes.
+	pub fn set_channel_extension_data(&self, channel_id: &[u8; 32], counterparty_node_id: &PublicKey, extension_type: u64, data: Option<Vec<u8>>) -> Result<(), APIError> {
+		self.extension_registry.lock().unwrap().check_registered(extension_type)?;
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		match channel_state_lock.by_id.get_mut(channel_id) {
+			Some(chan) => {
+				if *counterparty_node_id != chan.get_counterparty_node_id() {
+					return Err(APIError::APIMisuseError { err: "The passed counterparty_node_id doesn't match the channel's counterparty node_id".to_owned() });
+				}
+				chan.extension_data_mut().set(extension_type, data);
+				Ok(())
+			},
+			None => Err(APIError::ChannelUnavailable { err: "No such channel".to_owned() }),
+		}
+	}
+
+	/// Gets the opaque data stored alongside the given channel under `extension_type`, if any.
+	pub fn get_channel_extension_data(&self, channel_id: &[u8; 32], extension_type: u64) -> Option<Vec<u8>> {
+		let channel_state = self.channel_state.lock().unwrap();
+		channel_state.by_id.get(channel_id).and_then(|chan| chan.extension_data().get(extension_type).cloned())
+	}
+
+	/// Sets the opaque data stored under the registered `extension_type` which isn't specific to
+	/// any channel, or removes it if `data` is `None`.
+	///
+	/// The data is persisted as a part of the `ChannelManager`.
+	pub fn set_node_extension_data(&self, extension_type: u64, data: Option<Vec<u8>>) -> Result<(), APIError> {
+		self.extension_registry.lock().unwrap().check_registered(extension_type)?;
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		self.node_extension_data.lock().unwrap().set(extension_type, data);
+		Ok(())
+	}
+
+	/// Gets the opaque data stored under `extension_type` which isn't specific to any channel, if
+	/// any.
+	pub fn get_node_extension_data(&self, extension_type: u64) -> Option<Vec<u8>> {
+		self.node_extension_data.lock().unwrap().get(extension_type).cloned()
+	}
+
+	/// Counts `strike` against `chan`, placing it on probation if we've seen enough of them.
+	/// Returns true if the channel was newly placed on probation.
+	fn add_probation_strike(&self, chan: &mut Channel<Signer>, strike: ProbationStrike) -> bool {
+		if chan.add_probation_strike(strike, &self.get_current_default_configuration().channel_probation) {
+			log_info!(self.logger, "Placing channel {} on probation after too many strikes ({:?}) against our counterparty",
+				log_bytes!(chan.channel_id()), strike);
+			true
+		} else { false }
+	}
+
 	fn construct_recv_pending_htlc_info(&self, hop_data: msgs::OnionHopData, shared_secret: [u8; 32],
 		payment_hash: PaymentHash, amt_msat: u64, cltv_expiry: u32, phantom_shared_secret: Option<[u8; 32]>) -> Result<PendingHTLCInfo, ReceiveError>
 	{
+		if let msgs::OnionHopDataFormat::Blinded { .. } = hop_data.format {
+			return Err(ReceiveError {
+				err_code: INVALID_ONION_BLINDING,
+				err_data: vec![0; 32],
+				msg: "We don't support receiving payments over blinded routes",
+			});
+		}
 		// final_incorrect_cltv_expiry
 		if hop_data.outgoing_cltv_value != cltv_expiry {
 			return Err(ReceiveError {
@@ -2064,7 +4215,12 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		// Also, ensure that, in the case of an unknown preimage for the received payment hash, our
 		// payment logic has enough time to fail the HTLC backward before our onchain logic triggers a
 		// channel closure (see HTLC_FAIL_BACK_BUFFER rationale).
-		if (hop_data.outgoing_cltv_value as u64) <= self.best_block.read().unwrap().height() as u64 + HTLC_FAIL_BACK_BUFFER as u64 + 1	{
+		// Beyond that, we advertise our CltvPolicy's min_final_cltv_expiry in invoices, so require it
+		// of any payment we receive, less the slack it includes for blocks found in flight.
+		let min_final_cltv_expiry = self.get_current_default_configuration().cltv_policy.min_final_cltv_expiry;
+		let min_final_cltv_delta = cmp::max(HTLC_FAIL_BACK_BUFFER + 2,
+			min_final_cltv_expiry.saturating_sub(FINAL_CLTV_EXPIRY_IN_FLIGHT_SLACK));
+		if (hop_data.outgoing_cltv_value as u64) < self.best_block.read().unwrap().height() as u64 + min_final_cltv_delta as u64 {
 			return Err(ReceiveError {
 				err_code: 17,
 				err_data: Vec::new(),
@@ -2094,7 +4250,47 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					msg: "Got non final data with an HMAC of 0",
 				});
 			},
-			msgs::OnionHopDataFormat::FinalNode { payment_data, keysend_preimage } => {
+			msgs::OnionHopDataFormat::TrampolineForward { .. } => {
+				return Err(ReceiveError {
+					err_code: 0x4000|22,
+					err_data: Vec::new(),
+					msg: "Got trampoline hop data outside of a trampoline onion",
+				});
+			},
+			msgs::OnionHopDataFormat::Blinded { .. } => unreachable!(),
+			msgs::OnionHopDataFormat::TrampolineEntrypoint { payment_data, trampoline_packet } => {
+				let config = self.get_current_default_configuration().trampoline_forwarding;
+				if !config.enabled || phantom_shared_secret.is_some() {
+					return Err(ReceiveError {
+						err_code: 0x4000|0x2000|3,
+						err_data: Vec::new(),
+						msg: "We don't support forwarding trampoline payments",
+					});
+				}
+				let forward_info = self.peel_trampoline_onion(payment_hash, trampoline_packet)?;
+				// trampoline_fee_insufficient
+				if payment_data.total_msat < forward_info.amt_to_forward.saturating_add(config.fee_msat(forward_info.amt_to_forward)) {
+					return Err(ReceiveError {
+						err_code: 0x2000|51,
+						err_data: Vec::new(),
+						msg: "Trampoline payment didn't leave us enough fee",
+					});
+				}
+				// trampoline_expiry_too_soon
+				if (hop_data.outgoing_cltv_value as u64) < forward_info.outgoing_cltv_value as u64 + config.cltv_expiry_delta as u64 {
+					return Err(ReceiveError {
+						err_code: 0x2000|52,
+						err_data: Vec::new(),
+						msg: "Trampoline payment didn't leave us enough CLTV delta",
+					});
+				}
+				PendingHTLCRouting::ReceiveTrampoline {
+					payment_data,
+					incoming_cltv_expiry: hop_data.outgoing_cltv_value,
+					forward_info,
+				}
+			},
+			msgs::OnionHopDataFormat::FinalNode { payment_data, keysend_preimage, custom_tlvs } => {
 				if payment_data.is_some() && keysend_preimage.is_some() {
 					return Err(ReceiveError {
 						err_code: 0x4000|22,
@@ -2121,10 +4317,18 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 							msg: "Payment preimage didn't match payment hash",
 						});
 					}
+					if !custom_tlvs.is_empty() && !self.get_current_default_configuration().accept_keysend_custom_tlvs {
+						return Err(ReceiveError {
+							err_code: 0x4000|22,
+							err_data: Vec::new(),
+							msg: "We don't accept keysend payments with custom TLVs",
+						});
+					}
 
 					PendingHTLCRouting::ReceiveKeysend {
 						payment_preimage,
 						incoming_cltv_expiry: hop_data.outgoing_cltv_value,
+						custom_tlvs,
 					}
 				} else {
 					return Err(ReceiveError {
@@ -2139,21 +4343,74 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			routing,
 			payment_hash,
 			incoming_shared_secret: shared_secret,
+			incoming_amt_msat: None,
 			amt_to_forward: amt_msat,
 			outgoing_cltv_value: hop_data.outgoing_cltv_value,
 		})
 	}
 
+	/// Peels our layer of the trampoline onion of a trampoline payment we're to relay.
+	fn peel_trampoline_onion(&self, payment_hash: PaymentHash, packet: msgs::TrampolineOnionPacket) -> Result<TrampolineForwardInfo, ReceiveError> {
+		macro_rules! return_err {
+			($msg: expr) => {
+				return Err(ReceiveError { err_code: 0x4000|22, err_data: Vec::new(), msg: $msg })
+			}
+		}
+
+		if packet.version != 0 {
+			return_err!("Unknown trampoline onion version byte");
+		}
+		let shared_secret = SharedSecret::new(&packet.public_key, &self.our_network_key).secret_bytes();
+		let (hop_data, next_hop): (msgs::OnionHopData, Option<([u8; 32], Vec<u8>)>) =
+			match onion_utils::decode_next_hop(shared_secret, &packet.hop_data, packet.hmac, payment_hash) {
+				Ok(res) => res,
+				Err(_) => return_err!("Failed to decode trampoline onion"),
+			};
+		let (next_node_id, payment_data) = match hop_data.format {
+			msgs::OnionHopDataFormat::TrampolineForward { outgoing_node_id, payment_data } => (outgoing_node_id, payment_data),
+			_ => return_err!("Got non-trampoline hop data in a trampoline onion"),
+		};
+		let next_packet = match (next_hop, &payment_data) {
+			(Some((hmac, hop_data)), None) => {
+				let public_key = match onion_utils::next_hop_packet_pubkey(&self.secp_ctx, packet.public_key, &shared_secret) {
+					Ok(public_key) => public_key,
+					Err(_) => return_err!("Failed to derive the next trampoline onion's public key"),
+				};
+				Some(msgs::TrampolineOnionPacket { version: 0, public_key, hop_data, hmac })
+			},
+			(None, Some(_)) => None,
+			_ => return_err!("Trampoline hop data was inconsistent with whether it's the last trampoline hop"),
+		};
+		if next_node_id == self.our_network_pubkey {
+			return_err!("Trampoline payment was to be relayed to ourselves");
+		}
+		Ok(TrampolineForwardInfo {
+			next_node_id,
+			amt_to_forward: hop_data.amt_to_forward,
+			outgoing_cltv_value: hop_data.outgoing_cltv_value,
+			payment_data,
+			next_packet,
+		})
+	}
+
 	fn decode_update_add_htlc_onion(&self, msg: &msgs::UpdateAddHTLC) -> PendingHTLCStatus {
+		// If we received the HTLC within a blinded route, all failures are returned as
+		// invalid_onion_blinding so as not to reveal anything about the route to the sender.
+		let mut blinded_failure = msg.blinding_point.map(|_| BlindedFailure::FromBlindedNode);
 		macro_rules! return_malformed_err {
 			($msg: expr, $err_code: expr) => {
 				{
 					log_info!(self.logger, "Failed to accept/forward incoming HTLC: {}", $msg);
+					let (sha256_of_onion, failure_code) = if msg.blinding_point.is_some() {
+						([0; 32], INVALID_ONION_BLINDING)
+					} else {
+						(Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner(), $err_code)
+					};
 					return PendingHTLCStatus::Fail(HTLCFailureMsg::Malformed(msgs::UpdateFailMalformedHTLC {
 						channel_id: msg.channel_id,
 						htlc_id: msg.htlc_id,
-						sha256_of_onion: Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner(),
-						failure_code: $err_code,
+						sha256_of_onion,
+						failure_code,
 					}));
 				}
 			}
@@ -2163,7 +4420,17 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			return_malformed_err!("invalid ephemeral pubkey", 0x8000 | 0x4000 | 6);
 		}
 
-		let shared_secret = SharedSecret::new(&msg.onion_routing_packet.public_key.unwrap(), &self.our_network_key).secret_bytes();
+		// Within a blinded route, the onion is encrypted to our blinded node id, which is derived
+		// from the secret we share with the blinding point.
+		let blinding_ss = msg.blinding_point.map(|blinding_point| SharedSecret::new(&blinding_point, &self.our_network_key).secret_bytes());
+		let onion_key = match blinding_ss {
+			Some(ref blinding_ss) => match blinded_payment::blinded_onion_key(&self.our_network_key, blinding_ss) {
+				Ok(key) => key,
+				Err(_) => return_malformed_err!("Failed to derive our blinded onion key", INVALID_ONION_BLINDING),
+			},
+			None => self.our_network_key,
+		};
+		let shared_secret = SharedSecret::new(&msg.onion_routing_packet.public_key.unwrap(), &onion_key).secret_bytes();
 
 		if msg.onion_routing_packet.version != 0 {
 			//TODO: Spec doesn't indicate if we should only hash hop_data here (and in other
@@ -2178,11 +4445,14 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			($msg: expr, $err_code: expr, $data: expr) => {
 				{
 					log_info!(self.logger, "Failed to accept/forward incoming HTLC: {}", $msg);
-					return PendingHTLCStatus::Fail(HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
-						channel_id: msg.channel_id,
-						htlc_id: msg.htlc_id,
-						reason: onion_utils::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
-					}));
+					return PendingHTLCStatus::Fail(match blinded_failure {
+						Some(failure) => blinded_failure_msg(msg, failure, &shared_secret),
+						None => HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
+							channel_id: msg.channel_id,
+							htlc_id: msg.htlc_id,
+							reason: onion_utils::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
+						}),
+					});
 				}
 			}
 		}
@@ -2197,6 +4467,10 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			},
 		};
 
+		if self.channels_frozen.load(Ordering::Acquire) { // temporary_node_failure
+			return_err!("Refusing to accept or forward new HTLCs while channels are frozen", 0x2000 | 2, &[0; 0]);
+		}
+
 		let pending_forward_info = match next_hop {
 			onion_utils::Hop::Receive(next_hop_data) => {
 				// OUR PAYMENT!
@@ -2220,23 +4494,58 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					hmac: next_hop_hmac.clone(),
 				};
 
-				let short_channel_id = match next_hop_data.format {
-					msgs::OnionHopDataFormat::Legacy { short_channel_id } => short_channel_id,
-					msgs::OnionHopDataFormat::NonFinalNode { short_channel_id } => short_channel_id,
-					msgs::OnionHopDataFormat::FinalNode { .. } => {
+				let (short_channel_id, amt_to_forward, outgoing_cltv_value, blinded, custom_tlvs) = match next_hop_data.format {
+					msgs::OnionHopDataFormat::Legacy { .. } |
+					msgs::OnionHopDataFormat::NonFinalNode { .. } if msg.blinding_point.is_some() => {
+						return_err!("Got unblinded hop data within a blinded route", INVALID_ONION_BLINDING, &[0; 32]);
+					},
+					msgs::OnionHopDataFormat::Legacy { short_channel_id } =>
+						(short_channel_id, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value, None, Vec::new()),
+					msgs::OnionHopDataFormat::NonFinalNode { short_channel_id, custom_tlvs } =>
+						(short_channel_id, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value, None, custom_tlvs),
+					msgs::OnionHopDataFormat::Blinded { encrypted_tlvs, intro_node_blinding_point } => {
+						let (blinding_point, blinding_ss) = match (msg.blinding_point, blinding_ss, intro_node_blinding_point) {
+							(Some(blinding_point), Some(blinding_ss), None) => (blinding_point, blinding_ss),
+							(None, None, Some(blinding_point)) => {
+								// We're the introduction node, so failures are returned to the
+								// sender as from us.
+								blinded_failure = Some(BlindedFailure::FromIntroductionNode);
+								(blinding_point, SharedSecret::new(&blinding_point, &self.our_network_key).secret_bytes())
+							},
+							_ => return_err!("Blinded hop data didn't provide exactly one blinding point", INVALID_ONION_BLINDING, &[0; 32]),
+						};
+						let failure = blinded_failure.unwrap();
+						match blinded_payment::process_blinded_forward(&self.secp_ctx, &encrypted_tlvs,
+							&blinding_point, &blinding_ss, msg.amount_msat, msg.cltv_expiry)
+						{
+							Ok(info) => (info.short_channel_id, info.amt_to_forward, info.outgoing_cltv_value, Some(BlindedForward {
+								next_blinding_point: info.next_blinding_point,
+								failure,
+							}), Vec::new()),
+							Err(err) => return_err!(err, INVALID_ONION_BLINDING, &[0; 32]),
+						}
+					},
+					msgs::OnionHopDataFormat::FinalNode { .. } |
+					msgs::OnionHopDataFormat::TrampolineEntrypoint { .. } => {
 						return_err!("Final Node OnionHopData provided for us as an intermediary node", 0x4000 | 22, &[0;0]);
 					},
+					msgs::OnionHopDataFormat::TrampolineForward { .. } => {
+						return_err!("Got trampoline hop data outside of a trampoline onion", 0x4000 | 22, &[0;0]);
+					},
 				};
 
 				PendingHTLCStatus::Forward(PendingHTLCInfo {
 					routing: PendingHTLCRouting::Forward {
 						onion_packet: outgoing_packet,
 						short_channel_id,
+						blinded,
+						custom_tlvs,
 					},
 					payment_hash: msg.payment_hash.clone(),
 					incoming_shared_secret: shared_secret,
-					amt_to_forward: next_hop_data.amt_to_forward,
-					outgoing_cltv_value: next_hop_data.outgoing_cltv_value,
+					incoming_amt_msat: Some(msg.amount_msat),
+					amt_to_forward,
+					outgoing_cltv_value,
 				})
 			}
 		};
@@ -2246,8 +4555,11 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			// with a short_channel_id of 0. This is important as various things later assume
 			// short_channel_id is non-0 in any ::Forward.
 			if let &PendingHTLCRouting::Forward { ref short_channel_id, .. } = routing {
-				if let Some((err, code, chan_update)) = loop {
+				if let Some((err, code, chan_update, reason)) = loop {
 					let mut channel_state = self.channel_state.lock().unwrap();
+					if channel_state.by_id.get(&msg.channel_id).map(|chan| chan.is_on_probation()).unwrap_or(false) {
+						break Some(("Refusing to forward an HTLC received over a channel on probation.", 0x1000 | 7, None, ForwardRejectionReason::Policy));
+					}
 					let id_option = channel_state.short_to_chan_info.get(&short_channel_id).cloned();
 					let forwarding_id_opt = match id_option {
 						None => { // unknown_next_peer
@@ -2256,47 +4568,65 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 							if fake_scid::is_valid_phantom(&self.fake_scid_rand_bytes, *short_channel_id) {
 								None
 							} else {
-								break Some(("Don't have available channel for forwarding as requested.", 0x4000 | 10, None));
+								break Some(("Don't have available channel for forwarding as requested.", 0x4000 | 10, None, ForwardRejectionReason::UnknownNextChannel));
 							}
 						},
 						Some((_cp_id, chan_id)) => Some(chan_id.clone()),
 					};
 					let chan_update_opt = if let Some(forwarding_id) = forwarding_id_opt {
 						let chan = channel_state.by_id.get_mut(&forwarding_id).unwrap();
-						if !chan.should_announce() && !self.default_configuration.accept_forwards_to_priv_channels {
+						if !chan.should_announce() && !self.get_current_default_configuration().accept_forwards_to_priv_channels {
 							// Note that the behavior here should be identical to the above block - we
 							// should NOT reveal the existence or non-existence of a private channel if
 							// we don't allow forwards outbound over them.
-							break Some(("Refusing to forward to a private channel based on our config.", 0x4000 | 10, None));
+							break Some(("Refusing to forward to a private channel based on our config.", 0x4000 | 10, None, ForwardRejectionReason::Policy));
+						}
+						let peer_requirements = &self.get_current_default_configuration().peer_feature_requirements;
+						if peer_requirements.enforce_on_forwards {
+							let per_peer_state = self.per_peer_state.read().unwrap();
+							if let Some(peer_state) = per_peer_state.get(&chan.get_counterparty_node_id()) {
+								if !peer_requirements.missing_features(&peer_state.lock().unwrap().latest_features).is_empty() {
+									// As above, we pretend we don't have the channel at all.
+									break Some(("Refusing to forward to a peer lacking features required by our config.", 0x4000 | 10, None, ForwardRejectionReason::Policy));
+								}
+							}
 						}
 						if chan.get_channel_type().supports_scid_privacy() && *short_channel_id != chan.outbound_scid_alias() {
 							// `option_scid_alias` (referred to in LDK as `scid_privacy`) means
 							// "refuse to forward unless the SCID alias was used", so we pretend
 							// we don't have the channel here.
-							break Some(("Refusing to forward over real channel SCID as our counterparty requested.", 0x4000 | 10, None));
+							break Some(("Refusing to forward over real channel SCID as our counterparty requested.", 0x4000 | 10, None, ForwardRejectionReason::Policy));
 						}
 						let chan_update_opt = self.get_channel_update_for_onion(*short_channel_id, chan).ok();
 
+						if chan.is_on_probation() { // temporary_channel_failure
+							break Some(("Refusing to forward over a channel on probation.", 0x1000 | 7, chan_update_opt, ForwardRejectionReason::Policy));
+						}
+
 						// Note that we could technically not return an error yet here and just hope
 						// that the connection is reestablished or monitor updated by the time we get
 						// around to doing the actual forward, but better to fail early if we can and
 						// hopefully an attacker trying to path-trace payments cannot make this occur
 						// on a small/per-node/per-channel scale.
 						if !chan.is_live() { // channel_disabled
-							break Some(("Forwarding channel is not in a ready state.", 0x1000 | 20, chan_update_opt));
+							break Some(("Forwarding channel is not in a ready state.", 0x1000 | 20, chan_update_opt, ForwardRejectionReason::PeerDisconnected));
 						}
 						if *amt_to_forward < chan.get_counterparty_htlc_minimum_msat() { // amount_below_minimum
-							break Some(("HTLC amount was below the htlc_minimum_msat", 0x1000 | 11, chan_update_opt));
+							break Some(("HTLC amount was below the htlc_minimum_msat", 0x1000 | 11, chan_update_opt, ForwardRejectionReason::AmountBelowMinimum));
 						}
 						if let Err((err, code)) = chan.htlc_satisfies_config(&msg, *amt_to_forward, *outgoing_cltv_value) {
-							break Some((err, code, chan_update_opt));
+							let reason = if code == 0x1000 | 12 { ForwardRejectionReason::FeeInsufficient } else { ForwardRejectionReason::CltvExpiry };
+							break Some((err, code, chan_update_opt, reason));
+						}
+						if (msg.cltv_expiry as u64) < (*outgoing_cltv_value) as u64 + self.get_current_default_configuration().cltv_policy.min_cltv_expiry_delta as u64 { // incorrect_cltv_expiry
+							break Some(("Forwarding node has tampered with the intended HTLC values or origin node has an obsolete cltv_expiry_delta", 0x1000 | 13, chan_update_opt, ForwardRejectionReason::CltvExpiry));
 						}
 						chan_update_opt
 					} else {
-						if (msg.cltv_expiry as u64) < (*outgoing_cltv_value) as u64 + MIN_CLTV_EXPIRY_DELTA as u64 { // incorrect_cltv_expiry
+						if (msg.cltv_expiry as u64) < (*outgoing_cltv_value) as u64 + self.get_current_default_configuration().cltv_policy.min_cltv_expiry_delta as u64 { // incorrect_cltv_expiry
 							break Some((
 								"Forwarding node has tampered with the intended HTLC values or origin node has an obsolete cltv_expiry_delta",
-								0x1000 | 13, None,
+								0x1000 | 13, None, ForwardRejectionReason::CltvExpiry,
 							));
 						}
 						None
@@ -2307,10 +4637,10 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					// but we want to be robust wrt to counterparty packet sanitization (see
 					// HTLC_FAIL_BACK_BUFFER rationale).
 					if msg.cltv_expiry <= cur_height + HTLC_FAIL_BACK_BUFFER as u32 { // expiry_too_soon
-						break Some(("CLTV expiry is too close", 0x1000 | 14, chan_update_opt));
+						break Some(("CLTV expiry is too close", 0x1000 | 14, chan_update_opt, ForwardRejectionReason::CltvExpiry));
 					}
-					if msg.cltv_expiry > cur_height + CLTV_FAR_FAR_AWAY as u32 { // expiry_too_far
-						break Some(("CLTV expiry is too far in the future", 21, None));
+					if msg.cltv_expiry > cur_height + self.get_current_default_configuration().cltv_policy.max_cltv_expiry_from_now { // expiry_too_far
+						break Some(("CLTV expiry is too far in the future", 21, None, ForwardRejectionReason::CltvExpiry));
 					}
 					// If the HTLC expires ~now, don't bother trying to forward it to our
 					// counterparty. They should fail it anyway, but we don't want to bother with
@@ -2321,12 +4651,25 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					// but there is no need to do that, and since we're a bit conservative with our
 					// risk threshold it just results in failing to forward payments.
 					if (*outgoing_cltv_value) as u64 <= (cur_height + LATENCY_GRACE_PERIOD_BLOCKS) as u64 {
-						break Some(("Outgoing CLTV value is too soon", 0x1000 | 14, chan_update_opt));
+						break Some(("Outgoing CLTV value is too soon", 0x1000 | 14, chan_update_opt, ForwardRejectionReason::CltvExpiry));
 					}
 
 					break None;
 				}
 				{
+					self.record_rejected_forward(RejectedForward {
+						payment_hash: msg.payment_hash,
+						prev_channel_id: msg.channel_id,
+						next_short_channel_id: *short_channel_id,
+						incoming_amount_msat: Some(msg.amount_msat),
+						outgoing_amount_msat: *amt_to_forward,
+						incoming_cltv_expiry: Some(msg.cltv_expiry),
+						outgoing_cltv_expiry: *outgoing_cltv_value,
+						reason,
+						failure_code: code,
+						details: err.to_owned(),
+						rejected_at_height: self.best_block.read().unwrap().height(),
+					});
 					let mut res = VecWriter(Vec::with_capacity(chan_update.serialized_length() + 2 + 8 + 2));
 					if let Some(chan_update) = chan_update {
 						if code == 0x1000 | 11 || code == 0x1000 | 12 {
@@ -2367,7 +4710,18 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			return Err(LightningError{err: "Channel not yet established".to_owned(), action: msgs::ErrorAction::IgnoreError});
 		}
 		log_trace!(self.logger, "Attempting to generate broadcast channel update for channel {}", log_bytes!(chan.channel_id()));
-		self.get_channel_update_for_unicast(chan)
+		let update = self.get_channel_update_for_unicast(chan)?;
+		let refresh_time = self.next_gossip_refresh_time();
+		self.gossip_refresh.lock().unwrap().channel_update_refresh_times.insert(chan.channel_id(), refresh_time);
+		Ok(update)
+	}
+
+	/// Gets the (jittered) time after which gossip we're broadcasting now should be re-broadcast.
+	fn next_gossip_refresh_time(&self) -> u32 {
+		let mut jitter_bytes = [0; 4];
+		jitter_bytes.copy_from_slice(&self.keys_manager.get_secure_random_bytes()[..4]);
+		let jitter = u32::from_be_bytes(jitter_bytes) % GOSSIP_REFRESH_JITTER_SECS;
+		(self.highest_seen_timestamp.load(Ordering::Acquire) as u32).saturating_add(GOSSIP_REFRESH_INTERVAL_SECS - jitter)
 	}
 
 	/// Gets the current channel_update for the given channel. This does not check if the channel
@@ -2392,8 +4746,8 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			chain_hash: self.genesis_hash,
 			short_channel_id,
 			timestamp: chan.get_update_time_counter(),
-			flags: (!were_node_one) as u8 | ((!chan.is_live() as u8) << 1),
-			cltv_expiry_delta: chan.get_cltv_expiry_delta(),
+			flags: (!were_node_one) as u8 | (((!chan.is_live() || self.channels_frozen.load(Ordering::Acquire)) as u8) << 1),
+			cltv_expiry_delta: chan.get_cltv_expiry_delta(self.get_current_default_configuration().cltv_policy.min_cltv_expiry_delta),
 			htlc_minimum_msat: chan.get_counterparty_htlc_minimum_msat(),
 			htlc_maximum_msat: chan.get_announced_htlc_max_msat(),
 			fee_base_msat: chan.get_outbound_forwarding_fee_base_msat(),
@@ -2412,6 +4766,27 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 
 	// Only public for testing, this should otherwise never be called direcly
 	pub(crate) fn send_payment_along_path(&self, path: &Vec<RouteHop>, payment_params: &Option<PaymentParameters>, payment_hash: &PaymentHash, payment_secret: &Option<PaymentSecret>, total_value: u64, cur_height: u32, payment_id: PaymentId, keysend_preimage: &Option<PaymentPreimage>) -> Result<(), APIError> {
+		let trampoline_params = payment_params.as_ref().filter(|params| !params.trampoline_hops.is_empty());
+		let (onion_payloads, htlc_msat, htlc_cltv) = if let Some(params) = trampoline_params {
+			let payment_secret = match (payment_secret, keysend_preimage) {
+				(Some(payment_secret), None) => payment_secret,
+				_ => return Err(APIError::APIMisuseError{err: "Trampoline payments require a payment secret and can't be keysend payments".to_owned()}),
+			};
+			let trampoline_prng_seed = self.keys_manager.get_secure_random_bytes();
+			let trampoline_session_priv = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
+			onion_utils::build_trampoline_onion_payloads(&self.secp_ctx, path, &params.trampoline_hops,
+				params.payee_pubkey, total_value, payment_secret, cur_height, payment_hash,
+				&trampoline_session_priv, trampoline_prng_seed)?
+		} else {
+			onion_utils::build_onion_payloads(path, total_value, payment_secret, cur_height, keysend_preimage)?
+		};
+		self.send_onion_along_path(path, payment_params, payment_hash, payment_secret, total_value, payment_id, onion_payloads, htlc_msat, htlc_cltv)
+	}
+
+	/// Sends an HTLC along the given path carrying the given onion payloads, tracking it as a part
+	/// of the outbound payment with the given `payment_id`.
+	// Only public for testing, this should otherwise never be called direcly
+	pub(crate) fn send_onion_along_path(&self, path: &Vec<RouteHop>, payment_params: &Option<PaymentParameters>, payment_hash: &PaymentHash, payment_secret: &Option<PaymentSecret>, total_value: u64, payment_id: PaymentId, onion_payloads: Vec<msgs::OnionHopData>, htlc_msat: u64, htlc_cltv: u32) -> Result<(), APIError> {
 		log_trace!(self.logger, "Attempting to send payment for path with next hop {}", path.first().unwrap().short_channel_id);
 		let prng_seed = self.keys_manager.get_secure_random_bytes();
 		let session_priv_bytes = self.keys_manager.get_secure_random_bytes();
@@ -2419,13 +4794,12 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 
 		let onion_keys = onion_utils::construct_onion_keys(&self.secp_ctx, &path, &session_priv)
 			.map_err(|_| APIError::RouteError{err: "Pubkey along hop was maliciously selected"})?;
-		let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(path, total_value, payment_secret, cur_height, keysend_preimage)?;
 		if onion_utils::route_size_insane(&onion_payloads) {
 			return Err(APIError::RouteError{err: "Route size too large considering onion data"});
 		}
 		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, prng_seed, payment_hash);
 
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = self.active_persistence_guard()?;
 
 		let err: Result<(), _> = loop {
 			let mut channel_lock = self.channel_state.lock().unwrap();
@@ -2455,6 +4829,9 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 						payment_secret: *payment_secret,
 						starting_block_height: self.best_block.read().unwrap().height(),
 						total_msat: total_value,
+						max_total_routing_fee_msat: payment_params.as_ref()
+							.and_then(|params| params.max_total_routing_fee)
+							.map(|limit| limit.max_fee_msat(total_value)),
 					});
 					assert!(payment.insert(session_priv_bytes, path));
 				}
@@ -2570,6 +4947,11 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		if payment_secret.is_none() && route.paths.len() > 1 {
 			return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError{err: "Payment secret is required for multi-path payments".to_string()}));
 		}
+		self.check_active().map_err(PaymentSendFailure::ParameterError)?;
+		self.check_event_queue_capacity().map_err(PaymentSendFailure::ParameterError)?;
+		if self.channels_frozen.load(Ordering::Acquire) {
+			return Err(PaymentSendFailure::ParameterError(APIError::ChannelUnavailable{err: "Channels are frozen, no new payments may be sent".to_owned()}));
+		}
 		let mut total_value = 0;
 		let our_node_id = self.get_our_node_id();
 		let mut path_errs = Vec::with_capacity(route.paths.len());
@@ -2624,8 +5006,12 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				payment_id,
 				failed_paths_retry: if pending_amt_unsent != 0 {
 					if let Some(payment_params) = &route.payment_params {
+						let outbounds = self.pending_outbound_payments.lock().unwrap();
 						Some(RouteParameters {
-							payment_params: payment_params.clone(),
+							payment_params: match outbounds.get(&payment_id) {
+								Some(payment) => payment.retry_payment_params(payment_params),
+								None => payment_params.clone(),
+							},
 							final_value_msat: pending_amt_unsent,
 							final_cltv_expiry_delta: max_unsent_cltv_delta,
 						})
@@ -2646,14 +5032,15 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	///
 	/// Errors returned are a superset of those returned from [`send_payment`], so see
 	/// [`send_payment`] documentation for more details on errors. This method will also error if the
-	/// retry amount puts the payment more than 10% over the payment's total amount, if the payment
+	/// retry amount puts the payment over the payment's total amount by more than
+	/// [`PaymentAmountPolicy::outbound_mpp_overshoot`] allows, if the payment
 	/// for the given `payment_id` cannot be found (likely due to timeout or success), or if
 	/// further retries have been disabled with [`abandon_payment`].
 	///
 	/// [`send_payment`]: [`ChannelManager::send_payment`]
 	/// [`abandon_payment`]: [`ChannelManager::abandon_payment`]
+	/// [`PaymentAmountPolicy::outbound_mpp_overshoot`]: crate::util::config::PaymentAmountPolicy::outbound_mpp_overshoot
 	pub fn retry_payment(&self, route: &Route, payment_id: PaymentId) -> Result<(), PaymentSendFailure> {
-		const RETRY_OVERFLOW_PERCENTAGE: u64 = 10;
 		for path in route.paths.iter() {
 			if path.len() == 0 {
 				return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError {
@@ -2667,14 +5054,23 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			if let Some(payment) = outbounds.get(&payment_id) {
 				match payment {
 					PendingOutboundPayment::Retryable {
-						total_msat, payment_hash, payment_secret, pending_amt_msat, ..
+						total_msat, payment_hash, payment_secret, pending_amt_msat, pending_fee_msat, max_total_routing_fee_msat, ..
 					} => {
 						let retry_amt_msat: u64 = route.paths.iter().map(|path| path.last().unwrap().fee_msat).sum();
-						if retry_amt_msat + *pending_amt_msat > *total_msat * (100 + RETRY_OVERFLOW_PERCENTAGE) / 100 {
+						let outbound_mpp_overshoot = &self.get_current_default_configuration().payment_amount_policy.outbound_mpp_overshoot;
+						if !outbound_mpp_overshoot.allows(retry_amt_msat + *pending_amt_msat, *total_msat) {
 							return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError {
-								err: format!("retry_amt_msat of {} will put pending_amt_msat (currently: {}) more than 10% over total_payment_amt_msat of {}", retry_amt_msat, pending_amt_msat, total_msat).to_string()
+								err: format!("retry_amt_msat of {} will put pending_amt_msat (currently: {}) too far over total_payment_amt_msat of {} ({:?} overshoot allowed)", retry_amt_msat, pending_amt_msat, total_msat, outbound_mpp_overshoot).to_string()
 							}))
 						}
+						if let (Some(max_fee_msat), Some(pending_fee_msat)) = (max_total_routing_fee_msat, pending_fee_msat) {
+							let retry_fee_msat = route.get_total_fees();
+							if retry_fee_msat + *pending_fee_msat > *max_fee_msat {
+								return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError {
+									err: format!("retry_fee_msat of {} will put pending_fee_msat (currently: {}) over the payment's max_total_routing_fee_msat of {}", retry_fee_msat, pending_fee_msat, max_fee_msat)
+								}))
+							}
+						}
 						(*total_msat, *payment_hash, *payment_secret)
 					},
 					PendingOutboundPayment::Legacy { .. } => {
@@ -2720,6 +5116,91 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
 
 		let mut outbounds = self.pending_outbound_payments.lock().unwrap();
+		self.mark_payment_abandoned(&mut outbounds, payment_id);
+	}
+
+	/// Abandons the given payment as with [`abandon_payment`], additionally recording the
+	/// last-known state of each of its pending parts in a [`PaymentAbandonmentRecord`], which is
+	/// returned.
+	///
+	/// Unlike with [`abandon_payment`], any parts of the payment which later fail back generate
+	/// [`Event::PaymentPathFailed`] events without [`retry`] parameters, ensuring they are not
+	/// retried by an automated payment retrier.
+	///
+	/// The record is persisted with the [`ChannelManager`] and may be fetched again via
+	/// [`get_payment_abandonment_record`] until it is removed via
+	/// [`remove_payment_abandonment_record`].
+	///
+	/// Returns `None` if no such payment is pending or it has already succeeded.
+	///
+	/// [`abandon_payment`]: Self::abandon_payment
+	/// [`Event::PaymentPathFailed`]: events::Event::PaymentPathFailed
+	/// [`retry`]: events::Event::PaymentPathFailed::retry
+	/// [`get_payment_abandonment_record`]: Self::get_payment_abandonment_record
+	/// [`remove_payment_abandonment_record`]: Self::remove_payment_abandonment_record
+	pub fn abandon_payment_force(&self, payment_id: PaymentId) -> Option<PaymentAbandonmentRecord> {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+
+		let channel_state = self.channel_state.lock().unwrap();
+		let mut outbounds = self.pending_outbound_payments.lock().unwrap();
+		let (payment_hash, mut session_privs) = match outbounds.get(&payment_id) {
+			Some(PendingOutboundPayment::Retryable { payment_hash, session_privs, .. }) |
+			Some(PendingOutboundPayment::Abandoned { payment_hash, session_privs }) =>
+				(*payment_hash, session_privs.clone()),
+			_ => return None,
+		};
+
+		let mut shards = Vec::new();
+		for (channel_id, chan) in channel_state.by_id.iter() {
+			for (path, session_priv, state) in chan.get_outbound_payment_htlcs(payment_id) {
+				let mut session_priv_bytes = [0; 32];
+				session_priv_bytes.copy_from_slice(&session_priv[..]);
+				if session_privs.remove(&session_priv_bytes) {
+					shards.push(AbandonedShard { channel_id: Some(*channel_id), path: path.clone(), state });
+				}
+			}
+		}
+		for _ in session_privs.iter() {
+			shards.push(AbandonedShard { channel_id: None, path: Vec::new(), state: AbandonedShardState::NotInChannel });
+		}
+		let record = PaymentAbandonmentRecord {
+			payment_id,
+			payment_hash,
+			abandoned_at_height: self.best_block.read().unwrap().height(),
+			shards,
+		};
+		log_info!(self.logger, "Force-abandoning payment with hash {} with {} pending parts", log_bytes!(payment_hash.0), record.shards.len());
+		self.payment_abandonment_records.lock().unwrap().insert(payment_id, record.clone());
+		self.mark_payment_abandoned(&mut outbounds, payment_id);
+		Some(record)
+	}
+
+	/// Gets the [`PaymentAbandonmentRecord`] of a payment abandoned via
+	/// [`ChannelManager::abandon_payment_force`], if any.
+	pub fn get_payment_abandonment_record(&self, payment_id: PaymentId) -> Option<PaymentAbandonmentRecord> {
+		self.payment_abandonment_records.lock().unwrap().get(&payment_id).cloned()
+	}
+
+	/// Lists the [`PaymentAbandonmentRecord`]s of all payments abandoned via
+	/// [`ChannelManager::abandon_payment_force`] which have not been removed.
+	pub fn list_payment_abandonment_records(&self) -> Vec<PaymentAbandonmentRecord> {
+		self.payment_abandonment_records.lock().unwrap().values().cloned().collect()
+	}
+
+	/// Removes the [`PaymentAbandonmentRecord`] of the given payment, returning it.
+	///
+	/// Any parts of the payment which fail back after this is called may once again generate
+	/// [`Event::PaymentPathFailed`] events with [`retry`] parameters, though the payment itself
+	/// remains abandoned.
+	///
+	/// [`Event::PaymentPathFailed`]: events::Event::PaymentPathFailed
+	/// [`retry`]: events::Event::PaymentPathFailed::retry
+	pub fn remove_payment_abandonment_record(&self, payment_id: PaymentId) -> Option<PaymentAbandonmentRecord> {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		self.payment_abandonment_records.lock().unwrap().remove(&payment_id)
+	}
+
+	fn mark_payment_abandoned(&self, outbounds: &mut HashMap<PaymentId, PendingOutboundPayment>, payment_id: PaymentId) {
 		if let hash_map::Entry::Occupied(mut payment) = outbounds.entry(payment_id) {
 			if let Ok(()) = payment.get_mut().mark_abandoned() {
 				if payment.get().remaining_parts() == 0 {
@@ -2752,32 +5233,187 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			Some(p) => p,
 			None => PaymentPreimage(self.keys_manager.get_secure_random_bytes()),
 		};
-		let payment_hash = PaymentHash(Sha256::hash(&preimage.0).into_inner());
-		match self.send_payment_internal(route, payment_hash, &None, Some(preimage), None, None) {
-			Ok(payment_id) => Ok((payment_hash, payment_id)),
-			Err(e) => Err(e)
+		let payment_hash = PaymentHash(Sha256::hash(&preimage.0).into_inner());
+		match self.send_payment_internal(route, payment_hash, &None, Some(preimage), None, None) {
+			Ok(payment_id) => Ok((payment_hash, payment_id)),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Send a payment that is probing the given route for liquidity. We calculate the
+	/// [`PaymentHash`] of probes based on a static secret and a random [`PaymentId`], which allows
+	/// us to easily discern them from real payments.
+	pub fn send_probe(&self, hops: Vec<RouteHop>) -> Result<(PaymentHash, PaymentId), PaymentSendFailure> {
+		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
+
+		let payment_hash = self.probing_cookie_from_id(&payment_id);
+
+		if hops.len() < 2 {
+			return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError {
+				err: "No need probing a path with less than two hops".to_string()
+			}))
+		}
+
+		let route = Route { paths: vec![hops], payment_params: None };
+
+		match self.send_payment_internal(&route, payment_hash, &None, None, Some(payment_id), None) {
+			Ok(payment_id) => Ok((payment_hash, payment_id)),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Relays a trampoline payment we received all parts of along the given [`Route`], which
+	/// should have been found for the [`route_params`] of the [`Event::TrampolineForwardRequested`]
+	/// generated for it.
+	///
+	/// The route must deliver exactly the requested amount to the next trampoline node, and stay
+	/// within the fee and CLTV budgets reflected in the event's
+	/// [`PaymentParameters::max_total_routing_fee`] and
+	/// [`PaymentParameters::max_total_cltv_expiry_delta`], ensuring we're left with the fee and
+	/// CLTV delta configured in [`TrampolineForwardingConfig`].
+	///
+	/// Once the payment is claimed, the inbound parts are claimed as well and an
+	/// [`Event::TrampolinePaymentForwarded`] is generated in place of [`Event::PaymentSent`]. If
+	/// it fails, the inbound parts are failed back once all outbound parts have failed. Outbound
+	/// parts still generate [`Event::PaymentPathSuccessful`] and [`Event::PaymentPathFailed`], the
+	/// latter without [`retry`] parameters as a trampoline payment may only be forwarded once.
+	///
+	/// See [`send_payment`] for the errors this may return.
+	///
+	/// [`route_params`]: events::Event::TrampolineForwardRequested::route_params
+	/// [`Event::TrampolineForwardRequested`]: events::Event::TrampolineForwardRequested
+	/// [`Event::TrampolinePaymentForwarded`]: events::Event::TrampolinePaymentForwarded
+	/// [`Event::PaymentSent`]: events::Event::PaymentSent
+	/// [`Event::PaymentPathSuccessful`]: events::Event::PaymentPathSuccessful
+	/// [`Event::PaymentPathFailed`]: events::Event::PaymentPathFailed
+	/// [`retry`]: events::Event::PaymentPathFailed::retry
+	/// [`TrampolineForwardingConfig`]: crate::util::config::TrampolineForwardingConfig
+	/// [`send_payment`]: Self::send_payment
+	pub fn forward_trampoline_payment(&self, payment_hash: PaymentHash, route: &Route) -> Result<PaymentId, PaymentSendFailure> {
+		self.check_active().map_err(PaymentSendFailure::ParameterError)?;
+		if self.channels_frozen.load(Ordering::Acquire) {
+			return Err(PaymentSendFailure::ParameterError(APIError::ChannelUnavailable{err: "Channels are frozen, no new payments may be sent".to_owned()}));
+		}
+		let our_node_id = self.get_our_node_id();
+		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
+		let forward_info = {
+			let mut channel_state = self.channel_state.lock().unwrap();
+			let forward = match channel_state.trampoline_forwards.get_mut(&payment_hash) {
+				Some(forward) if forward.total_received_msat() >= forward.htlcs[0].total_msat => forward,
+				_ => return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError {
+					err: format!("No trampoline payment with payment_hash {} is awaiting forwarding", log_bytes!(payment_hash.0)),
+				})),
+			};
+			if forward.outbound_payment_id.is_some() {
+				return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError {
+					err: "Trampoline payment has already been forwarded".to_owned(),
+				}));
+			}
+			if route.paths.is_empty() {
+				return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "There must be at least one path to send over"}));
+			}
+			let mut path_errs = Vec::with_capacity(route.paths.len());
+			for path in route.paths.iter() {
+				if path.is_empty() || path.len() > 20 {
+					path_errs.push(Err(APIError::RouteError{err: "Path didn't go anywhere/had bogus size"}));
+				} else if path.iter().any(|hop| hop.pubkey == our_node_id) {
+					path_errs.push(Err(APIError::RouteError{err: "Path went through us"}));
+				} else if path.last().unwrap().pubkey != forward.forward_info.next_node_id {
+					path_errs.push(Err(APIError::RouteError{err: "Path didn't end at the next trampoline node"}));
+				} else {
+					path_errs.push(Ok(()));
+				}
+			}
+			if path_errs.iter().any(|e| e.is_err()) {
+				return Err(PaymentSendFailure::PathParameterError(path_errs));
+			}
+			let (max_total_routing_fee_msat, max_route_cltv_expiry_delta) = self.trampoline_forward_budget(forward);
+			let forwarded_msat: u64 = route.paths.iter().map(|path| path.last().unwrap().fee_msat).sum();
+			if forwarded_msat != forward.forward_info.amt_to_forward {
+				return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Route doesn't deliver the amount the trampoline payment is to be forwarded with"}));
+			}
+			if route.get_total_fees() > max_total_routing_fee_msat {
+				return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Route fees exceed the trampoline payment's fee budget"}));
+			}
+			for path in route.paths.iter() {
+				let route_cltv_expiry_delta: u32 = path[..path.len() - 1].iter().map(|hop| hop.cltv_expiry_delta).sum();
+				if route_cltv_expiry_delta > max_route_cltv_expiry_delta {
+					return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Route CLTV expiry delta exceeds the trampoline payment's CLTV budget"}));
+				}
+			}
+			forward.outbound_payment_id = Some(payment_id);
+			forward.forward_info.clone()
+		};
+
+		let (payment_secret, total_msat) = match forward_info.payment_data {
+			Some(ref payment_data) => (payment_data.payment_secret, payment_data.total_msat),
+			None => (PaymentSecret(self.keys_manager.get_secure_random_bytes()), forward_info.amt_to_forward),
+		};
+		let mut results = Vec::with_capacity(route.paths.len());
+		for path in route.paths.iter() {
+			results.push(onion_utils::build_trampoline_forward_onion_payloads(path, total_msat, &payment_secret,
+					forward_info.outgoing_cltv_value, forward_info.next_packet.clone())
+				.and_then(|(onion_payloads, htlc_msat, htlc_cltv)| self.send_onion_along_path(path, &None, &payment_hash,
+					&Some(payment_secret), total_msat, payment_id, onion_payloads, htlc_msat, htlc_cltv)));
+		}
+
+		// Track how much we actually sent so that we can tell the fee we earned once claimed. Note
+		// that an HTLC for which the monitor update failed will still go out eventually.
+		let outbound_amount_msat = results.iter().zip(route.paths.iter())
+			.filter(|(res, _)| res.is_ok() || **res == Err(APIError::MonitorUpdateFailed))
+			.map(|(_, path)| path.iter().map(|hop| hop.fee_msat).sum::<u64>())
+			.sum::<u64>();
+		let mut channel_state = self.channel_state.lock().unwrap();
+		if let Some(forward) = channel_state.trampoline_forwards.get_mut(&payment_hash) {
+			if outbound_amount_msat == 0 {
+				// Nothing went out, so allow the user to try again with a different route.
+				forward.outbound_payment_id = None;
+			} else {
+				forward.outbound_amount_msat = Some(outbound_amount_msat);
+			}
+		}
+		if results.iter().all(|res| res.is_ok()) {
+			Ok(payment_id)
+		} else if outbound_amount_msat != 0 {
+			Err(PaymentSendFailure::PartialFailure { results, payment_id, failed_paths_retry: None })
+		} else {
+			Err(PaymentSendFailure::AllFailedRetrySafe(results.drain(..).map(|r| r.unwrap_err()).collect()))
 		}
 	}
 
-	/// Send a payment that is probing the given route for liquidity. We calculate the
-	/// [`PaymentHash`] of probes based on a static secret and a random [`PaymentId`], which allows
-	/// us to easily discern them from real payments.
-	pub fn send_probe(&self, hops: Vec<RouteHop>) -> Result<(PaymentHash, PaymentId), PaymentSendFailure> {
-		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
-
-		let payment_hash = self.probing_cookie_from_id(&payment_id);
+	/// Fails back all parts of a trampoline payment we received but haven't yet forwarded with
+	/// [`ChannelManager::forward_trampoline_payment`], e.g. because no route within its budget
+	/// could be found.
+	///
+	/// Fails if no such payment is pending or if it has already been forwarded.
+	pub fn fail_trampoline_forward(&self, payment_hash: &PaymentHash) -> Result<(), APIError> {
+		let _persistence_guard = self.active_persistence_guard()?;
 
-		if hops.len() < 2 {
-			return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError {
-				err: "No need probing a path with less than two hops".to_string()
-			}))
+		let mut channel_state = self.channel_state.lock().unwrap();
+		match channel_state.trampoline_forwards.get(payment_hash) {
+			None => return Err(APIError::APIMisuseError {
+				err: format!("No trampoline payment with payment_hash {} is pending", log_bytes!(payment_hash.0)),
+			}),
+			Some(forward) if forward.outbound_payment_id.is_some() => return Err(APIError::APIMisuseError {
+				err: "Trampoline payment has already been forwarded".to_owned(),
+			}),
+			Some(_) => {},
 		}
+		let forward = channel_state.trampoline_forwards.remove(payment_hash).unwrap();
+		self.fail_trampoline_htlcs(channel_state, forward, 0x2000 | 25);
+		Ok(())
+	}
 
-		let route = Route { paths: vec![hops], payment_params: None };
-
-		match self.send_payment_internal(&route, payment_hash, &None, None, Some(payment_id), None) {
-			Ok(payment_id) => Ok((payment_hash, payment_id)),
-			Err(e) => Err(e)
+	/// Fails back all inbound HTLCs of the given trampoline payment with `failure_code`.
+	fn fail_trampoline_htlcs(&self, channel_state_lock: MutexGuard<ChannelHolder<Signer>>, forward: PendingTrampolineForward, failure_code: u16) {
+		log_debug!(self.logger, "Failing back trampoline payment with payment_hash {}", log_bytes!(forward.payment_hash.0));
+		let mut channel_state = Some(channel_state_lock);
+		for htlc in forward.htlcs {
+			if channel_state.is_none() { channel_state = Some(self.channel_state.lock().unwrap()); }
+			self.fail_htlc_backwards_internal(channel_state.take().unwrap(),
+				HTLCSource::PreviousHopData(htlc.prev_hop), &forward.payment_hash,
+				HTLCFailReason::Reason { failure_code, data: Vec::new() },
+				HTLCDestination::FailedPayment { payment_hash: forward.payment_hash });
 		}
 	}
 
@@ -2801,9 +5437,11 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	fn funding_transaction_generated_intern<FundingOutput: Fn(&Channel<Signer>, &Transaction) -> Result<OutPoint, APIError>>(
 		&self, temporary_channel_id: &[u8; 32], _counterparty_node_id: &PublicKey, funding_transaction: Transaction, find_funding_output: FundingOutput
 	) -> Result<(), APIError> {
+		self.check_active()?;
 		let (chan, msg) = {
 			let (res, chan) = match self.channel_state.lock().unwrap().by_id.remove(temporary_channel_id) {
 				Some(mut chan) => {
+					self.funding_intents.lock().unwrap().remove(temporary_channel_id);
 					let funding_txo = find_funding_output(&chan, &funding_transaction)?;
 
 					(chan.get_outbound_funding_created(funding_transaction, funding_txo, &self.logger)
@@ -2882,7 +5520,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// [`Event::FundingGenerationReady`]: crate::util::events::Event::FundingGenerationReady
 	/// [`Event::ChannelClosed`]: crate::util::events::Event::ChannelClosed
 	pub fn funding_transaction_generated(&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey, funding_transaction: Transaction) -> Result<(), APIError> {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = self.active_persistence_guard()?;
 
 		for inp in funding_transaction.input.iter() {
 			if inp.witness.is_empty() {
@@ -2971,17 +5609,13 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		// addresses be sorted for future compatibility.
 		addresses.sort_by_key(|addr| addr.get_id());
 
-		let announcement = msgs::UnsignedNodeAnnouncement {
-			features: NodeFeatures::known(),
-			timestamp: self.last_node_announcement_serial.fetch_add(1, Ordering::AcqRel) as u32,
-			node_id: self.get_our_node_id(),
-			rgb, alias, addresses,
-			excess_address_data: Vec::new(),
-			excess_data: Vec::new(),
-		};
-		let msghash = hash_to_message!(&Sha256dHash::hash(&announcement.encode()[..])[..]);
-		let node_announce_sig = sign(&self.secp_ctx, &msghash, &self.our_network_key);
+		self.do_broadcast_node_announcement(NodeAnnouncementInfo { rgb, alias, addresses, refresh_time: 0 });
+	}
 
+	/// Broadcasts a node_announcement built from the given [`NodeAnnouncementInfo`] along with our
+	/// channel_announcements, storing it to be re-broadcast once its refresh time has passed.
+	/// Returns whether the node_announcement was broadcast, i.e. whether we have public channels.
+	fn do_broadcast_node_announcement(&self, mut info: NodeAnnouncementInfo) -> bool {
 		let mut channel_state_lock = self.channel_state.lock().unwrap();
 		let channel_state = &mut *channel_state_lock;
 
@@ -3004,13 +5638,99 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		}
 
 		if announced_chans {
+			let announcement = msgs::UnsignedNodeAnnouncement {
+				features: NodeFeatures::known(),
+				timestamp: self.last_node_announcement_serial.fetch_add(1, Ordering::AcqRel) as u32,
+				node_id: self.get_our_node_id(),
+				rgb: info.rgb, alias: info.alias, addresses: info.addresses.clone(),
+				excess_address_data: Vec::new(),
+				excess_data: Vec::new(),
+			};
+			let msghash = hash_to_message!(&Sha256dHash::hash(&announcement.encode()[..])[..]);
+			let node_announce_sig = sign(&self.secp_ctx, &msghash, &self.our_network_key);
+
 			channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastNodeAnnouncement {
 				msg: msgs::NodeAnnouncement {
 					signature: node_announce_sig,
 					contents: announcement
 				},
 			});
+			info.refresh_time = self.next_gossip_refresh_time();
+		}
+		self.gossip_refresh.lock().unwrap().node_announcement = Some(info);
+		announced_chans
+	}
+
+	/// Re-broadcasts the channel_updates of all of our announced channels, as well as our
+	/// node_announcement if [`ChannelManager::broadcast_node_announcement`] has been called,
+	/// providing them in corresponding events via [`get_and_clear_pending_msg_events`].
+	///
+	/// [`ChannelManager::timer_tick_occurred`] already does this for each piece of gossip some
+	/// (randomized) time between nine and eleven days after it was last broadcast, ensuring other
+	/// nodes don't prune our channels from their network graph as stale even if they've been
+	/// quiet for a long time. This method allows doing so immediately instead, e.g. after a long
+	/// period offline.
+	///
+	/// [`get_and_clear_pending_msg_events`]: MessageSendEventsProvider::get_and_clear_pending_msg_events
+	pub fn force_gossip_refresh(&self) {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+
+		{
+			let mut gossip_refresh = self.gossip_refresh.lock().unwrap();
+			for refresh_time in gossip_refresh.channel_update_refresh_times.values_mut() {
+				*refresh_time = 0;
+			}
+			if let Some(info) = gossip_refresh.node_announcement.as_mut() {
+				info.refresh_time = 0;
+			}
+		}
+		self.refresh_stale_gossip();
+	}
+
+	/// Re-broadcasts the channel_updates of our announced channels and our node_announcement which
+	/// are past their refresh time, returning whether we need to be persisted.
+	///
+	/// Must not be called with channel_state locked.
+	fn refresh_stale_gossip(&self) -> bool {
+		let current_time = self.highest_seen_timestamp.load(Ordering::Acquire) as u32;
+		let mut refreshed = false;
+		{
+			let mut channel_state_lock = self.channel_state.lock().unwrap();
+			let channel_state = &mut *channel_state_lock;
+			let best_block_height = self.best_block.read().unwrap().height();
+			for (chan_id, chan) in channel_state.by_id.iter_mut() {
+				if !chan.is_usable() || !chan.should_announce() { continue; }
+				let refresh_time = self.gossip_refresh.lock().unwrap().channel_update_refresh_times.get(chan_id).cloned();
+				if refresh_time.map(|time| time > current_time).unwrap_or(false) { continue; }
+				// Channels which other nodes may not know about yet have no gossip to keep fresh.
+				if chan.get_signed_channel_announcement(self.get_our_node_id(), self.genesis_hash, best_block_height).is_none() { continue; }
+
+				chan.refresh_update_time_counter(current_time);
+				if let Ok(msg) = self.get_channel_update_for_broadcast(chan) {
+					log_debug!(self.logger, "Re-broadcasting channel_update for channel {} to keep it from going stale", log_bytes!(chan_id[..]));
+					channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate { msg });
+					refreshed = true;
+				}
+			}
+			self.gossip_refresh.lock().unwrap().channel_update_refresh_times.retain(|chan_id, _| channel_state.by_id.contains_key(chan_id));
+		}
+
+		let stale_node_announcement = {
+			let mut gossip_refresh = self.gossip_refresh.lock().unwrap();
+			match gossip_refresh.node_announcement {
+				Some(ref info) if info.refresh_time <= current_time => gossip_refresh.node_announcement.take(),
+				_ => None,
+			}
+		};
+		if let Some(info) = stale_node_announcement {
+			// A refresh time of 0 means we've yet to broadcast the node_announcement at all as we
+			// had no public channels, so we simply retry on each call until we do.
+			if info.refresh_time != 0 {
+				log_debug!(self.logger, "Re-broadcasting our node_announcement to keep it from going stale");
+			}
+			if self.do_broadcast_node_announcement(info) { refreshed = true; }
 		}
+		refreshed
 	}
 
 	/// Atomically updates the [`ChannelConfig`] for the given channels.
@@ -3024,7 +5744,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// `counterparty_node_id` is provided.
 	///
 	/// Returns [`APIMisuseError`] when a [`cltv_expiry_delta`] update is to be applied with a value
-	/// below [`MIN_CLTV_EXPIRY_DELTA`].
+	/// below our [`CltvPolicy::min_cltv_expiry_delta`].
 	///
 	/// If an error is returned, none of the updates should be considered applied.
 	///
@@ -3035,18 +5755,19 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// [`ChannelUpdate`]: msgs::ChannelUpdate
 	/// [`ChannelUnavailable`]: APIError::ChannelUnavailable
 	/// [`APIMisuseError`]: APIError::APIMisuseError
+	/// [`CltvPolicy::min_cltv_expiry_delta`]: crate::util::config::CltvPolicy::min_cltv_expiry_delta
 	pub fn update_channel_config(
 		&self, counterparty_node_id: &PublicKey, channel_ids: &[[u8; 32]], config: &ChannelConfig,
 	) -> Result<(), APIError> {
-		if config.cltv_expiry_delta < MIN_CLTV_EXPIRY_DELTA {
+		self.check_active()?;
+		let min_cltv_expiry_delta = self.get_current_default_configuration().cltv_policy.min_cltv_expiry_delta;
+		if config.cltv_expiry_delta < min_cltv_expiry_delta {
 			return Err(APIError::APIMisuseError {
-				err: format!("The chosen CLTV expiry delta is below the minimum of {}", MIN_CLTV_EXPIRY_DELTA),
+				err: format!("The chosen CLTV expiry delta is below the minimum of {}", min_cltv_expiry_delta),
 			});
 		}
 
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(
-			&self.total_consistency_lock, &self.persistence_notifier,
-		);
+		let _persistence_guard = self.active_persistence_guard()?;
 		{
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = &mut *channel_state_lock;
@@ -3080,12 +5801,34 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		Ok(())
 	}
 
+	/// Lists the HTLCs we were asked to forward but rejected, oldest first, e.g. to diagnose why a
+	/// routing node isn't forwarding (and earning fees on) more payments.
+	///
+	/// Only the last [`MAX_REJECTED_FORWARDS`] rejections are kept, and they are not persisted.
+	/// HTLCs which we failed back because the next hop failed them are not included.
+	pub fn list_rejected_forwards(&self) -> Vec<RejectedForward> {
+		self.rejected_forwards.lock().unwrap().iter().cloned().collect()
+	}
+
+	/// Clears the [`RejectedForward`]s returned by [`ChannelManager::list_rejected_forwards`].
+	pub fn clear_rejected_forwards(&self) {
+		self.rejected_forwards.lock().unwrap().clear();
+	}
+
+	fn record_rejected_forward(&self, rejection: RejectedForward) {
+		let mut rejected_forwards = self.rejected_forwards.lock().unwrap();
+		if rejected_forwards.len() >= MAX_REJECTED_FORWARDS {
+			rejected_forwards.pop_front();
+		}
+		rejected_forwards.push_back(rejection);
+	}
+
 	/// Processes HTLCs which are pending waiting on random forward delay.
 	///
 	/// Should only really ever be called in response to a PendingHTLCsForwardable event.
 	/// Will likely generate further events.
 	pub fn process_pending_htlc_forwards(&self) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 
 		let mut new_events = Vec::new();
 		let mut failed_forwards = Vec::new();
@@ -3095,6 +5838,20 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = &mut *channel_state_lock;
 
+			// Forwards which were held by our forward policy handler and have since been decided on are
+			// processed along with the rest.
+			let mut held_forward_decisions = HashMap::new();
+			for (held_forward, allow) in self.held_forwards.lock().unwrap().decided.drain(..) {
+				if let HTLCForwardInfo::AddHTLC { prev_htlc_id, prev_funding_outpoint, .. } = held_forward.forward_info {
+					held_forward_decisions.insert((prev_funding_outpoint.to_channel_id(), prev_htlc_id), allow);
+				}
+				channel_state.forward_htlcs.entry(held_forward.short_channel_id).or_insert_with(Vec::new).push(held_forward.forward_info);
+			}
+
+			if self.get_current_default_configuration().nonstrict_forwarding {
+				self.select_nonstrict_forward_channels(channel_state);
+			}
+
 			for (short_chan_id, mut pending_forwards) in channel_state.forward_htlcs.drain() {
 				if short_chan_id != 0 {
 					let forward_chan_id = match channel_state.short_to_chan_info.get(&short_chan_id) {
@@ -3103,8 +5860,12 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 							for forward_info in pending_forwards.drain(..) {
 								match forward_info {
 									HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
-										routing, incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value },
+										routing, incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value, incoming_amt_msat, .. },
 										prev_funding_outpoint } => {
+											let blinded_failure = match routing {
+												PendingHTLCRouting::Forward { blinded: Some(ref blinded), .. } => Some(blinded.failure),
+												_ => None,
+											};
 											macro_rules! failure_handler {
 												($msg: expr, $err_code: expr, $err_data: expr, $phantom_ss: expr, $next_hop_unknown: expr) => {
 													log_info!(self.logger, "Failed to accept/forward incoming HTLC: {}", $msg);
@@ -3115,6 +5876,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 														htlc_id: prev_htlc_id,
 														incoming_packet_shared_secret: incoming_shared_secret,
 														phantom_shared_secret: $phantom_ss,
+														blinded_failure,
 													});
 
 													let reason = if $next_hop_unknown {
@@ -3133,6 +5895,19 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 											macro_rules! fail_forward {
 												($msg: expr, $err_code: expr, $err_data: expr, $phantom_ss: expr) => {
 													{
+														self.record_rejected_forward(RejectedForward {
+															payment_hash,
+															prev_channel_id: prev_funding_outpoint.to_channel_id(),
+															next_short_channel_id: short_chan_id,
+															incoming_amount_msat: incoming_amt_msat,
+															outgoing_amount_msat: amt_to_forward,
+															incoming_cltv_expiry: None,
+															outgoing_cltv_expiry: outgoing_cltv_value,
+															reason: ForwardRejectionReason::UnknownNextChannel,
+															failure_code: $err_code,
+															details: $msg.clone(),
+															rejected_at_height: self.best_block.read().unwrap().height(),
+														});
 														failure_handler!($msg, $err_code, $err_data, $phantom_ss, true);
 													}
 												}
@@ -3178,7 +5953,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 												fail_forward!(format!("Unknown short channel id {} for forward HTLC", short_chan_id), 0x4000 | 10, Vec::new(), None);
 											}
 										},
-									HTLCForwardInfo::FailHTLC { .. } => {
+									HTLCForwardInfo::FailHTLC { .. } | HTLCForwardInfo::FailMalformedHTLC { .. } => {
 										// Channel went away before we could fail it. This implies
 										// the channel is now on chain and our counterparty is
 										// trying to broadcast the HTLC-Timeout, but that's their
@@ -3192,12 +5967,24 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					if let hash_map::Entry::Occupied(mut chan) = channel_state.by_id.entry(forward_chan_id) {
 						let mut add_htlc_msgs = Vec::new();
 						let mut fail_htlc_msgs = Vec::new();
+						let mut fail_malformed_htlc_msgs = Vec::new();
 						for forward_info in pending_forwards.drain(..) {
+							let policy_decision = self.forward_policy_decision(&forward_info, short_chan_id, chan.get(),
+								&channel_state.short_to_chan_info, &mut held_forward_decisions);
+							if policy_decision == ForwardPolicyDecision::Hold {
+								if let HTLCForwardInfo::AddHTLC { prev_htlc_id, prev_funding_outpoint, .. } = forward_info {
+									log_debug!(self.logger, "Holding forward of HTLC {} from channel {} as requested by our forward policy handler",
+										prev_htlc_id, log_bytes!(prev_funding_outpoint.to_channel_id()));
+									self.held_forwards.lock().unwrap().held.insert((prev_funding_outpoint.to_channel_id(), prev_htlc_id),
+										HeldForward { short_channel_id: short_chan_id, forward_info, ticks_elapsed: 0 });
+								}
+								continue;
+							}
 							match forward_info {
 								HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
 										routing: PendingHTLCRouting::Forward {
-											onion_packet, ..
-										}, incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value },
+											onion_packet, blinded, ..
+										}, incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value, incoming_amt_msat, .. },
 										prev_funding_outpoint } => {
 									log_trace!(self.logger, "Adding HTLC from short id {} with payment_hash {} to channel with short id {} after delay", prev_short_channel_id, log_bytes!(payment_hash.0), short_chan_id);
 									let htlc_source = HTLCSource::PreviousHopData(HTLCPreviousHopData {
@@ -3207,15 +5994,62 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 										incoming_packet_shared_secret: incoming_shared_secret,
 										// Phantom payments are only PendingHTLCRouting::Receive.
 										phantom_shared_secret: None,
+										blinded_failure: blinded.as_ref().map(|blinded| blinded.failure),
 									});
-									match chan.get_mut().send_htlc(amt_to_forward, payment_hash, outgoing_cltv_value, htlc_source.clone(), onion_packet, &self.logger) {
+									if policy_decision == ForwardPolicyDecision::Deny {
+										log_trace!(self.logger, "Failing HTLC with payment_hash {} as our forward policy handler denied forwarding it", log_bytes!(payment_hash.0));
+										let (failure_code, data) = self.get_htlc_temp_fail_err_and_data(0x1000|7, short_chan_id, chan.get());
+										self.record_rejected_forward(RejectedForward {
+											payment_hash,
+											prev_channel_id: prev_funding_outpoint.to_channel_id(),
+											next_short_channel_id: short_chan_id,
+											incoming_amount_msat: incoming_amt_msat,
+											outgoing_amount_msat: amt_to_forward,
+											incoming_cltv_expiry: None,
+											outgoing_cltv_expiry: outgoing_cltv_value,
+											reason: ForwardRejectionReason::Policy,
+											failure_code,
+											details: "Forward denied by our forward policy handler".to_owned(),
+											rejected_at_height: self.best_block.read().unwrap().height(),
+										});
+										failed_forwards.push((htlc_source, payment_hash,
+											HTLCFailReason::Reason { failure_code, data },
+											HTLCDestination::NextHopChannel { node_id: Some(chan.get().get_counterparty_node_id()), channel_id: forward_chan_id }
+										));
+										continue;
+									}
+									match chan.get_mut().send_htlc(amt_to_forward, payment_hash, outgoing_cltv_value, htlc_source.clone(), onion_packet,
+										blinded.map(|blinded| blinded.next_blinding_point), &self.logger) {
 										Err(e) => {
-											if let ChannelError::Ignore(msg) = e {
+											let msg = if let ChannelError::Ignore(msg) = e {
 												log_trace!(self.logger, "Failed to forward HTLC with payment_hash {}: {}", log_bytes!(payment_hash.0), msg);
+												msg
 											} else {
 												panic!("Stated return value requirements in send_htlc() were not met");
-											}
+											};
 											let (failure_code, data) = self.get_htlc_temp_fail_err_and_data(0x1000|7, short_chan_id, chan.get());
+											// send_htlc doesn't tell us why it failed beyond its error message, so we
+											// classify the failure from the channel's state and that message.
+											let reason = if !chan.get().is_live() {
+												ForwardRejectionReason::PeerDisconnected
+											} else if msg.contains("dust") {
+												ForwardRejectionReason::DustExposure
+											} else {
+												ForwardRejectionReason::InsufficientLiquidity
+											};
+											self.record_rejected_forward(RejectedForward {
+												payment_hash,
+												prev_channel_id: prev_funding_outpoint.to_channel_id(),
+												next_short_channel_id: short_chan_id,
+												incoming_amount_msat: incoming_amt_msat,
+												outgoing_amount_msat: amt_to_forward,
+												incoming_cltv_expiry: None,
+												outgoing_cltv_expiry: outgoing_cltv_value,
+												reason,
+												failure_code,
+												details: msg,
+												rejected_at_height: self.best_block.read().unwrap().height(),
+											});
 											failed_forwards.push((htlc_source, payment_hash,
 												HTLCFailReason::Reason { failure_code, data },
 												HTLCDestination::NextHopChannel { node_id: Some(chan.get().get_counterparty_node_id()), channel_id: forward_chan_id }
@@ -3268,10 +6102,27 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 										}
 									}
 								},
+								HTLCForwardInfo::FailMalformedHTLC { htlc_id, failure_code, sha256_of_onion } => {
+									log_trace!(self.logger, "Failing malformed HTLC back to channel with short id {} (backward HTLC ID {}) after delay", short_chan_id, htlc_id);
+									match chan.get_mut().get_update_fail_malformed_htlc(htlc_id, failure_code, sha256_of_onion, &self.logger) {
+										Err(e) => {
+											if let ChannelError::Ignore(msg) = e {
+												log_trace!(self.logger, "Failed to fail HTLC with ID {} backwards to short_id {}: {}", htlc_id, short_chan_id, msg);
+											} else {
+												panic!("Stated return value requirements in get_update_fail_malformed_htlc() were not met");
+											}
+											// As above, fail-backs are best-effort.
+											continue;
+										},
+										Ok(Some(msg)) => { fail_malformed_htlc_msgs.push(msg); },
+										// As above, the Channel will send the failure when it can.
+										Ok(None) => {},
+									}
+								},
 							}
 						}
 
-						if !add_htlc_msgs.is_empty() || !fail_htlc_msgs.is_empty() {
+						if !add_htlc_msgs.is_empty() || !fail_htlc_msgs.is_empty() || !fail_malformed_htlc_msgs.is_empty() {
 							let (commitment_msg, monitor_update) = match chan.get_mut().send_commitment(&self.logger) {
 								Ok(res) => res,
 								Err(e) => {
@@ -3298,14 +6149,14 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 								continue;
 							}
 							log_debug!(self.logger, "Forwarding HTLCs resulted in a commitment update with {} HTLCs added and {} HTLCs failed for channel {}",
-								add_htlc_msgs.len(), fail_htlc_msgs.len(), log_bytes!(chan.get().channel_id()));
+								add_htlc_msgs.len(), fail_htlc_msgs.len() + fail_malformed_htlc_msgs.len(), log_bytes!(chan.get().channel_id()));
 							channel_state.pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
 								node_id: chan.get().get_counterparty_node_id(),
 								updates: msgs::CommitmentUpdate {
 									update_add_htlcs: add_htlc_msgs,
 									update_fulfill_htlcs: Vec::new(),
 									update_fail_htlcs: fail_htlc_msgs,
-									update_fail_malformed_htlcs: Vec::new(),
+									update_fail_malformed_htlcs: fail_malformed_htlc_msgs,
 									update_fee: None,
 									commitment_signed: commitment_msg,
 								},
@@ -3320,13 +6171,32 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 							HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
 									routing, incoming_shared_secret, payment_hash, amt_to_forward, .. },
 									prev_funding_outpoint } => {
-								let (cltv_expiry, onion_payload, payment_data, phantom_shared_secret) = match routing {
+								let (cltv_expiry, onion_payload, payment_data, phantom_shared_secret, custom_tlvs) = match routing {
 									PendingHTLCRouting::Receive { payment_data, incoming_cltv_expiry, phantom_shared_secret } => {
 										let _legacy_hop_data = Some(payment_data.clone());
-										(incoming_cltv_expiry, OnionPayload::Invoice { _legacy_hop_data }, Some(payment_data), phantom_shared_secret)
+										(incoming_cltv_expiry, OnionPayload::Invoice { _legacy_hop_data }, Some(payment_data), phantom_shared_secret, Vec::new())
+									},
+									PendingHTLCRouting::ReceiveKeysend { payment_preimage, incoming_cltv_expiry, custom_tlvs } =>
+										(incoming_cltv_expiry, OnionPayload::Spontaneous(payment_preimage), None, None, custom_tlvs),
+									PendingHTLCRouting::ReceiveTrampoline { payment_data, incoming_cltv_expiry, forward_info } => {
+										let htlc = ClaimableHTLC {
+											prev_hop: HTLCPreviousHopData {
+												short_channel_id: prev_short_channel_id,
+												outpoint: prev_funding_outpoint,
+												htlc_id: prev_htlc_id,
+												incoming_packet_shared_secret: incoming_shared_secret,
+												phantom_shared_secret: None,
+												blinded_failure: None,
+											},
+											value: amt_to_forward,
+											timer_ticks: 0,
+											total_msat: payment_data.total_msat,
+											cltv_expiry: incoming_cltv_expiry,
+											onion_payload: OnionPayload::Invoice { _legacy_hop_data: Some(payment_data) },
+										};
+										self.receive_trampoline_htlc(&mut channel_state.trampoline_forwards, payment_hash, htlc, forward_info, &mut failed_forwards, &mut new_events);
+										continue;
 									},
-									PendingHTLCRouting::ReceiveKeysend { payment_preimage, incoming_cltv_expiry } =>
-										(incoming_cltv_expiry, OnionPayload::Spontaneous(payment_preimage), None, None),
 									_ => {
 										panic!("short_channel_id == 0 should imply any pending_forward entries are of type Receive");
 									}
@@ -3338,6 +6208,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 										htlc_id: prev_htlc_id,
 										incoming_packet_shared_secret: incoming_shared_secret,
 										phantom_shared_secret,
+										blinded_failure: None,
 									},
 									value: amt_to_forward,
 									timer_ticks: 0,
@@ -3358,6 +6229,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 												htlc_id: $htlc.prev_hop.htlc_id,
 												incoming_packet_shared_secret: $htlc.prev_hop.incoming_packet_shared_secret,
 												phantom_shared_secret,
+												blinded_failure: None,
 											}), payment_hash,
 											HTLCFailReason::Reason { failure_code: 0x4000 | 15, data: htlc_msat_height_data },
 											HTLCDestination::FailedPayment { payment_hash: $payment_hash },
@@ -3384,6 +6256,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 											}
 										}
 										let mut total_value = claimable_htlc.value;
+										let mpp_overshoot = &self.get_current_default_configuration().payment_amount_policy.inbound_mpp_overshoot;
 										for htlc in htlcs.iter() {
 											total_value += htlc.value;
 											match &htlc.onion_payload {
@@ -3398,16 +6271,29 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 												_ => unreachable!(),
 											}
 										}
-										if total_value >= msgs::MAX_VALUE_MSAT || total_value > $payment_data.total_msat {
+										let exceeds_limits = self.inbound_payment_limits.lock().unwrap().get(&payment_hash)
+											.map(|registered| registered.limits().is_exceeded_by(cmp::max(total_value, $payment_data.total_msat), htlcs.len() + 1))
+											.unwrap_or(false);
+										// If we'd already reached the total value, the payment is complete
+										// and we don't accept any further parts.
+										let prior_total_value = total_value - claimable_htlc.value;
+										if exceeds_limits {
+											log_trace!(self.logger, "Failing HTLCs with payment_hash {} as the payment would exceed its limits with {} parts totaling {} (expected {})",
+												log_bytes!(payment_hash.0), htlcs.len() + 1, total_value, $payment_data.total_msat);
+											fail_htlc!(claimable_htlc, payment_hash);
+										} else if total_value >= msgs::MAX_VALUE_MSAT || prior_total_value >= $payment_data.total_msat ||
+											!mpp_overshoot.allows(total_value, $payment_data.total_msat)
+										{
 											log_trace!(self.logger, "Failing HTLCs with payment_hash {} as the total value {} ran over expected value {} (or HTLCs were inconsistent)",
 												log_bytes!(payment_hash.0), total_value, $payment_data.total_msat);
 											fail_htlc!(claimable_htlc, payment_hash);
-										} else if total_value == $payment_data.total_msat {
+										} else if total_value >= $payment_data.total_msat {
 											htlcs.push(claimable_htlc);
 											new_events.push(events::Event::PaymentReceived {
 												payment_hash,
 												purpose: purpose(),
 												amount_msat: total_value,
+												custom_tlvs: Vec::new(),
 											});
 											payment_received_generated = true;
 										} else {
@@ -3432,7 +6318,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 										match claimable_htlc.onion_payload {
 											OnionPayload::Invoice { .. } => {
 												let payment_data = payment_data.unwrap();
-												let payment_preimage = match inbound_payment::verify(payment_hash, &payment_data, self.highest_seen_timestamp.load(Ordering::Acquire) as u64, &self.inbound_payment_key, &self.logger) {
+												let payment_preimage = match inbound_payment::verify(payment_hash, &payment_data, self.highest_seen_timestamp.load(Ordering::Acquire) as u64, &self.inbound_payment_key, &self.get_current_default_configuration().payment_amount_policy.inbound_invoice_overpayment, &self.logger) {
 													Ok(payment_preimage) => payment_preimage,
 													Err(()) => {
 														fail_htlc!(claimable_htlc, payment_hash);
@@ -3450,6 +6336,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 															payment_hash,
 															amount_msat: amt_to_forward,
 															purpose,
+															custom_tlvs,
 														});
 													},
 													hash_map::Entry::Occupied(_) => {
@@ -3474,6 +6361,12 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 											log_trace!(self.logger, "Failing new HTLC with payment_hash {} as it didn't match our minimum value (had {}, needed {}).",
 												log_bytes!(payment_hash.0), payment_data.total_msat, inbound_payment.get().min_value_msat.unwrap());
 											fail_htlc!(claimable_htlc, payment_hash);
+										} else if inbound_payment.get().min_value_msat.map(|min_value_msat| min_value_msat != 0 &&
+											!self.get_current_default_configuration().payment_amount_policy.inbound_invoice_overpayment.allows(payment_data.total_msat, min_value_msat)).unwrap_or(false)
+										{
+											log_trace!(self.logger, "Failing new HTLC with payment_hash {} as it overpaid our expected value (had {}, expected {}).",
+												log_bytes!(payment_hash.0), payment_data.total_msat, inbound_payment.get().min_value_msat.unwrap());
+											fail_htlc!(claimable_htlc, payment_hash);
 										} else {
 											let payment_received_generated = check_total_value!(payment_data, inbound_payment.get().payment_preimage);
 											if payment_received_generated {
@@ -3483,7 +6376,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 									},
 								};
 							},
-							HTLCForwardInfo::FailHTLC { .. } => {
+							HTLCForwardInfo::FailHTLC { .. } | HTLCForwardInfo::FailMalformedHTLC { .. } => {
 								panic!("Got pending fail of our own HTLC");
 							}
 						}
@@ -3501,9 +6394,224 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			let _ = handle_error!(self, err, counterparty_node_id);
 		}
 
-		if new_events.is_empty() { return }
-		let mut events = self.pending_events.lock().unwrap();
-		events.append(&mut new_events);
+		if !new_events.is_empty() {
+			self.pending_events.lock().unwrap().append(&mut new_events);
+		}
+		self.enforce_event_queue_limits();
+	}
+
+	/// Moves HTLCs we're to forward over a channel which can't currently carry them to the channel
+	/// with the same peer which has the most outbound liquidity available, if it can carry them.
+	/// See [`UserConfig::nonstrict_forwarding`].
+	fn select_nonstrict_forward_channels(&self, channel_state: &mut ChannelHolder<Signer>) {
+		// The liquidity of each channel we've yet to commit to HTLCs, by channel_id.
+		let mut available_msat: HashMap<[u8; 32], u64> = HashMap::new();
+		macro_rules! available_msat {
+			($chan: expr) => {
+				*available_msat.entry($chan.channel_id())
+					.or_insert_with(|| $chan.get_available_balances().next_outbound_htlc_limit_msat)
+			}
+		}
+		let mut moved_forwards = Vec::new();
+		for (short_chan_id, pending_forwards) in channel_state.forward_htlcs.iter_mut() {
+			let (counterparty_node_id, forward_chan_id) = match channel_state.short_to_chan_info.get(short_chan_id) {
+				Some(info) => *info,
+				None => continue,
+			};
+			let mut idx = 0;
+			while idx < pending_forwards.len() {
+				let (amt_to_forward, payment_hash) = match pending_forwards[idx] {
+					HTLCForwardInfo::AddHTLC { forward_info: PendingHTLCInfo {
+						routing: PendingHTLCRouting::Forward { .. }, amt_to_forward, payment_hash, .. }, .. } => (amt_to_forward, payment_hash),
+					_ => { idx += 1; continue; },
+				};
+				let can_carry = |chan: &Channel<Signer>, available_msat: u64| {
+					chan.is_live() && !chan.is_on_probation() && available_msat >= amt_to_forward &&
+						amt_to_forward >= chan.get_counterparty_htlc_minimum_msat()
+				};
+				if let Some(chan) = channel_state.by_id.get(&forward_chan_id) {
+					if can_carry(chan, available_msat!(chan)) {
+						*available_msat.get_mut(&forward_chan_id).unwrap() -= amt_to_forward;
+						idx += 1;
+						continue;
+					}
+				}
+				let mut best_chan: Option<(&Channel<Signer>, u64, u64)> = None;
+				for chan in channel_state.by_id.values() {
+					if chan.get_counterparty_node_id() != counterparty_node_id || chan.channel_id() == forward_chan_id {
+						continue;
+					}
+					// Forwards are keyed by an SCID we can look the channel up by, which must not be 0.
+					let scid = chan.get_short_channel_id().unwrap_or(chan.outbound_scid_alias());
+					if scid == 0 { continue; }
+					let chan_available_msat = available_msat!(chan);
+					if can_carry(chan, chan_available_msat) && best_chan.map_or(true, |(_, best_msat, _)| chan_available_msat > best_msat) {
+						best_chan = Some((chan, chan_available_msat, scid));
+					}
+				}
+				if let Some((chan, _, scid)) = best_chan {
+					log_debug!(self.logger, "Forwarding HTLC with payment_hash {} over channel {} instead of the requested short id {}",
+						log_bytes!(payment_hash.0), log_bytes!(chan.channel_id()), short_chan_id);
+					*available_msat.get_mut(&chan.channel_id()).unwrap() -= amt_to_forward;
+					moved_forwards.push((scid, pending_forwards.remove(idx)));
+				} else {
+					idx += 1;
+				}
+			}
+		}
+		for (scid, forward) in moved_forwards {
+			channel_state.forward_htlcs.entry(scid).or_default().push(forward);
+		}
+	}
+
+	/// Adds a received HTLC of a trampoline payment we're to relay to its pending forward, asking
+	/// the user to route the payment once all of its parts have been received.
+	fn receive_trampoline_htlc(&self, trampoline_forwards: &mut HashMap<PaymentHash, PendingTrampolineForward>, payment_hash: PaymentHash,
+		htlc: ClaimableHTLC, forward_info: TrampolineForwardInfo,
+		failed_forwards: &mut Vec<(HTLCSource, PaymentHash, HTLCFailReason, HTLCDestination)>, new_events: &mut Vec<events::Event>
+	) {
+		let forward = match trampoline_forwards.entry(payment_hash) {
+			hash_map::Entry::Vacant(entry) => {
+				entry.insert(PendingTrampolineForward {
+					payment_hash,
+					htlcs: Vec::new(),
+					forward_info,
+					outbound_payment_id: None,
+					outbound_amount_msat: None,
+				})
+			},
+			hash_map::Entry::Occupied(entry) => {
+				let forward = entry.into_mut();
+				if forward.outbound_payment_id.is_some() || forward.forward_info != forward_info ||
+					forward.htlcs[0].total_msat != htlc.total_msat || forward.total_received_msat() >= htlc.total_msat
+				{
+					log_trace!(self.logger, "Failing HTLC with payment_hash {} as it was inconsistent with the other parts of the trampoline payment",
+						log_bytes!(payment_hash.0));
+					let mut htlc_msat_height_data = byte_utils::be64_to_array(htlc.value).to_vec();
+					htlc_msat_height_data.extend_from_slice(&byte_utils::be32_to_array(self.best_block.read().unwrap().height()));
+					failed_forwards.push((HTLCSource::PreviousHopData(htlc.prev_hop), payment_hash,
+						HTLCFailReason::Reason { failure_code: 0x4000 | 15, data: htlc_msat_height_data },
+						HTLCDestination::FailedPayment { payment_hash }));
+					return;
+				}
+				forward
+			},
+		};
+		forward.htlcs.push(htlc);
+		if forward.total_received_msat() >= forward.htlcs[0].total_msat {
+			let (max_total_routing_fee_msat, max_route_cltv_expiry_delta) = self.trampoline_forward_budget(forward);
+			let min_final_cltv_expiry = self.get_current_default_configuration().cltv_policy.min_final_cltv_expiry;
+			log_debug!(self.logger, "Received all parts of trampoline payment with payment_hash {} to relay to {}",
+				log_bytes!(payment_hash.0), log_pubkey!(forward.forward_info.next_node_id));
+			let mut payment_params = PaymentParameters::from_node_id(forward.forward_info.next_node_id)
+				.with_max_total_routing_fee(RoutingFeeLimit::absolute(max_total_routing_fee_msat));
+			payment_params.max_total_cltv_expiry_delta = max_route_cltv_expiry_delta.saturating_add(min_final_cltv_expiry);
+			new_events.push(events::Event::TrampolineForwardRequested {
+				payment_hash,
+				next_node_id: forward.forward_info.next_node_id,
+				route_params: RouteParameters {
+					payment_params,
+					final_value_msat: forward.forward_info.amt_to_forward,
+					final_cltv_expiry_delta: min_final_cltv_expiry,
+				},
+			});
+		}
+	}
+
+	/// Gets the maximum total fee and CLTV delta, excluding the final hop's, the route we relay a
+	/// trampoline payment with may have, after our own fee and CLTV delta.
+	fn trampoline_forward_budget(&self, forward: &PendingTrampolineForward) -> (u64, u32) {
+		let config = self.get_current_default_configuration().trampoline_forwarding;
+		let amt_to_forward = forward.forward_info.amt_to_forward;
+		let max_total_routing_fee_msat = forward.total_received_msat()
+			.saturating_sub(amt_to_forward).saturating_sub(config.fee_msat(amt_to_forward));
+		let min_incoming_cltv_expiry = forward.htlcs.iter().map(|htlc| htlc.cltv_expiry).min().unwrap_or(0);
+		let max_route_cltv_expiry_delta = min_incoming_cltv_expiry.saturating_sub(config.cltv_expiry_delta as u32)
+			.saturating_sub(forward.forward_info.outgoing_cltv_value);
+		(max_total_routing_fee_msat, max_route_cltv_expiry_delta)
+	}
+
+	/// Applies the [`EventQueueLimits`] from our config to our pending event queues, dropping
+	/// queued gossip if so configured and generating
+	/// [`events::Event::PendingEventQueueThresholdCrossed`]s as queues cross the warning threshold.
+	///
+	/// [`EventQueueLimits`]: crate::util::config::EventQueueLimits
+	fn enforce_event_queue_limits(&self) {
+		let limits = &self.get_current_default_configuration().event_queue_limits;
+		let mut channel_state = self.channel_state.lock().unwrap();
+		let pending_msg_events = &mut channel_state.pending_msg_events;
+		if limits.overflow_policy == EventQueueOverflowPolicy::DropGossipFirst &&
+			pending_msg_events.len() >= limits.max_pending_msg_events
+		{
+			// Leave room for at least one more message so that we can keep working.
+			let excess = pending_msg_events.len() - limits.max_pending_msg_events + 1;
+			let mut dropped = 0;
+			pending_msg_events.retain(|event| {
+				if dropped == excess { return true; }
+				match event {
+					&events::MessageSendEvent::BroadcastChannelAnnouncement { .. } |
+					&events::MessageSendEvent::BroadcastNodeAnnouncement { .. } |
+					&events::MessageSendEvent::BroadcastChannelUpdate { .. } => {
+						dropped += 1;
+						false
+					},
+					_ => true,
+				}
+			});
+			log_warn!(self.logger, "Dropped {} queued gossip broadcasts as our message queue is full", dropped);
+		}
+
+		let mut pending_events = self.pending_events.lock().unwrap();
+		if Self::crossed_warning_threshold(&self.msg_event_queue_warned, pending_msg_events.len(),
+			limits.warning_threshold(limits.max_pending_msg_events))
+		{
+			log_warn!(self.logger, "{} message events are pending, approaching the limit of {}",
+				pending_msg_events.len(), limits.max_pending_msg_events);
+			pending_events.push(events::Event::PendingEventQueueThresholdCrossed {
+				queue: events::PendingEventQueue::MessageSendEvents,
+				pending: pending_msg_events.len(),
+				limit: limits.max_pending_msg_events,
+			});
+		}
+		if Self::crossed_warning_threshold(&self.event_queue_warned, pending_events.len(),
+			limits.warning_threshold(limits.max_pending_events))
+		{
+			log_warn!(self.logger, "{} events are pending, approaching the limit of {}",
+				pending_events.len(), limits.max_pending_events);
+			let pending = pending_events.len();
+			pending_events.push(events::Event::PendingEventQueueThresholdCrossed {
+				queue: events::PendingEventQueue::Events,
+				pending,
+				limit: limits.max_pending_events,
+			});
+		}
+	}
+
+	/// Returns true if a queue of length `pending` has just reached `threshold`, tracking whether
+	/// we've already warned for the current crossing in `warned`.
+	fn crossed_warning_threshold(warned: &AtomicBool, pending: usize, threshold: usize) -> bool {
+		if pending >= threshold {
+			!warned.swap(true, Ordering::AcqRel)
+		} else {
+			warned.store(false, Ordering::Release);
+			false
+		}
+	}
+
+	/// Returns an error if either of our pending event queues is at its limit (after dropping any
+	/// gossip we're allowed to), in which case we shouldn't start any new work which would generate
+	/// further events.
+	fn check_event_queue_capacity(&self) -> Result<(), APIError> {
+		self.enforce_event_queue_limits();
+		let limits = &self.get_current_default_configuration().event_queue_limits;
+		let msg_events_full = self.channel_state.lock().unwrap().pending_msg_events.len() >= limits.max_pending_msg_events;
+		let events_full = self.pending_events.lock().unwrap().len() >= limits.max_pending_events;
+		if msg_events_full || events_full {
+			return Err(APIError::ChannelUnavailable {
+				err: "Our pending event queues are full, events must be processed before starting new work".to_owned()
+			});
+		}
+		Ok(())
 	}
 
 	/// Free the background events, generally called from timer_tick_occurred.
@@ -3622,22 +6730,55 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 
 	/// Performs actions which should happen on startup and roughly once per minute thereafter.
 	///
-	/// This currently includes:
+	/// This runs each [`TimerTask`] which is due, see [`ChannelManager::process_timer_tasks`],
+	/// which currently includes:
 	///  * Increasing or decreasing the on-chain feerate estimates for our outbound channels,
 	///  * Broadcasting `ChannelUpdate` messages if we've been disconnected from our peer for more
 	///    than a minute, informing the network that they should no longer attempt to route over
 	///    the channel.
 	///  * Expiring a channel's previous `ChannelConfig` if necessary to only allow forwarding HTLCs
 	///    with the current `ChannelConfig`.
+	///  * Re-broadcasting the `ChannelUpdate`s of our public channels and our `NodeAnnouncement`
+	///    before other nodes consider them stale, see [`ChannelManager::force_gossip_refresh`].
+	///
+	/// With the `std` feature, tasks are scheduled by a monotonic clock, so calling this more
+	/// often than once per [`TIMER_TASK_INTERVAL`], e.g. in a burst after a long pause, is
+	/// harmless. Without it, each call is assumed to follow the previous one by
+	/// [`TIMER_TASK_INTERVAL`]; use [`ChannelManager::process_timer_tasks`] to provide the time
+	/// instead.
 	///
 	/// Note that this may cause reentrancy through `chain::Watch::update_channel` calls or feerate
 	/// estimate fetches.
 	pub fn timer_tick_occurred(&self) {
+		if self.backgrounded_at.lock().unwrap().is_some() { return; }
+		let now = self.timer_schedule.lock().unwrap().tick_time();
+		self.process_timer_tasks(now);
+	}
+
+	/// Runs each [`TimerTask`] which is due at `now`, which is the time elapsed on a monotonic
+	/// clock of the caller's choosing. Each task runs at most once per [`TIMER_TASK_INTERVAL`],
+	/// and tasks which are not yet due are skipped, so this may be called as often as desired,
+	/// e.g. when waking up at [`ChannelManager::next_timer_task_deadline`].
+	///
+	/// This should be used in place of, rather than in addition to,
+	/// [`ChannelManager::timer_tick_occurred`], as the two may use different clocks.
+	pub fn process_timer_tasks(&self, now: Duration) {
+		if self.standby.load(Ordering::Acquire) { return; }
+		if self.backgrounded_at.lock().unwrap().is_some() { return; }
 		PersistenceNotifierGuard::optionally_notify(&self.total_consistency_lock, &self.persistence_notifier, || {
+			// We may have stepped down while waiting on the lock, see `active_persistence_guard`.
+			if self.standby.load(Ordering::Acquire) { return NotifyOption::SkipPersist; }
 			let mut should_persist = NotifyOption::SkipPersist;
 			if self.process_background_events() { should_persist = NotifyOption::DoPersist; }
 
+			let due_tasks = self.timer_schedule.lock().unwrap().take_due_tasks(now);
+			let is_due = |task| due_tasks.contains(&task);
+			if !due_tasks.is_empty() {
+				log_trace!(self.logger, "Running timer tasks {:?}", due_tasks);
+			}
+
 			let new_feerate = self.fee_estimator.bounded_sat_per_1000_weight(ConfirmationTarget::Normal);
+			let channels_frozen = self.channels_frozen.load(Ordering::Acquire);
 
 			let mut handle_errors = Vec::new();
 			let mut timed_out_mpp_htlcs = Vec::new();
@@ -3648,20 +6789,42 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				let short_to_chan_info = &mut channel_state.short_to_chan_info;
 				channel_state.by_id.retain(|chan_id, chan| {
 					let counterparty_node_id = chan.get_counterparty_node_id();
-					let (retain_channel, chan_needs_persist, err) = self.update_channel_fee(short_to_chan_info, pending_msg_events, chan_id, chan, new_feerate);
+					let (retain_channel, chan_needs_persist, err) = if channels_frozen || !is_due(TimerTask::FeerateUpdates) {
+						// Fee updates are paused while channels are frozen.
+						(true, NotifyOption::SkipPersist, Ok(()))
+					} else {
+						self.update_channel_fee(short_to_chan_info, pending_msg_events, chan_id, chan, new_feerate)
+					};
 					if chan_needs_persist == NotifyOption::DoPersist { should_persist = NotifyOption::DoPersist; }
 					if err.is_err() {
 						handle_errors.push((err, counterparty_node_id));
 					}
 					if !retain_channel { return false; }
 
+					if is_due(TimerTask::FeerateUpdates) {
+						let stale_feerate = chan.is_usable() && !chan.is_outbound() && (chan.get_feerate() as u64) * 2 < new_feerate as u64;
+						if stale_feerate && self.add_probation_strike(chan, ProbationStrike::StaleFeerate) {
+							should_persist = NotifyOption::DoPersist;
+						}
+					}
+					if !is_due(TimerTask::ChannelStatusUpdates) { return true; }
+
+					if chan.probation_timer_tick(&self.get_current_default_configuration().channel_probation) {
+						log_info!(self.logger, "Channel {} is no longer on probation", log_bytes!(chan.channel_id()));
+						should_persist = NotifyOption::DoPersist;
+					}
+
 					if let Err(e) = chan.timer_check_closing_negotiation_progress() {
-						let (needs_close, err) = convert_chan_err!(self, e, short_to_chan_info, chan, chan_id);
-						handle_errors.push((Err(err), chan.get_counterparty_node_id()));
-						if needs_close { return false; }
+						if self.should_force_close(chan, ForceCloseReason::ClosingNegotiationTimedOut) {
+							let (needs_close, err) = convert_chan_err!(self, e, short_to_chan_info, chan, chan_id);
+							handle_errors.push((Err(err), chan.get_counterparty_node_id()));
+							if needs_close { return false; }
+						}
 					}
 
 					match chan.channel_update_status() {
+						// Frozen channels stay disabled until unfrozen.
+						_ if channels_frozen => {},
 						ChannelUpdateStatus::Enabled if !chan.is_live() => chan.set_channel_update_status(ChannelUpdateStatus::DisabledStaged),
 						ChannelUpdateStatus::Disabled if chan.is_live() => chan.set_channel_update_status(ChannelUpdateStatus::EnabledStaged),
 						ChannelUpdateStatus::DisabledStaged if chan.is_live() => chan.set_channel_update_status(ChannelUpdateStatus::Enabled),
@@ -3692,29 +6855,19 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					true
 				});
 
-				channel_state.claimable_htlcs.retain(|payment_hash, (_, htlcs)| {
-					if htlcs.is_empty() {
-						// This should be unreachable
-						debug_assert!(false);
-						return false;
-					}
-					if let OnionPayload::Invoice { .. } = htlcs[0].onion_payload {
-						// Check if we've received all the parts we need for an MPP (the value of the parts adds to total_msat).
-						// In this case we're not going to handle any timeouts of the parts here.
-						if htlcs[0].total_msat == htlcs.iter().fold(0, |total, htlc| total + htlc.value) {
-							return true;
-						} else if htlcs.into_iter().any(|htlc| {
-							htlc.timer_ticks += 1;
-							return htlc.timer_ticks >= MPP_TIMEOUT_TICKS
-						}) {
-							timed_out_mpp_htlcs.extend(htlcs.into_iter().map(|htlc| (htlc.prev_hop.clone(), payment_hash.clone())));
-							return false;
-						}
-					}
-					true
-				});
+				if is_due(TimerTask::PaymentTimeouts) {
+					timed_out_mpp_htlcs = Self::time_out_mpp_htlcs(channel_state, 1);
+				}
+			}
+
+			if is_due(TimerTask::InboundVolumeWindow) {
+				self.inbound_volume.lock().unwrap().timer_tick(&self.get_current_default_configuration().inbound_volume);
 			}
 
+			if is_due(TimerTask::GossipRefresh) && self.refresh_stale_gossip() { should_persist = NotifyOption::DoPersist; }
+
+			if is_due(TimerTask::PaymentTimeouts) && self.time_out_held_forwards(1) { should_persist = NotifyOption::DoPersist; }
+
 			for htlc_source in timed_out_mpp_htlcs.drain(..) {
 				let receiver = HTLCDestination::FailedPayment { payment_hash: htlc_source.1 };
 				self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), HTLCSource::PreviousHopData(htlc_source.0.clone()), &htlc_source.1, HTLCFailReason::Reason { failure_code: 23, data: Vec::new() }, receiver );
@@ -3723,10 +6876,98 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			for (err, counterparty_node_id) in handle_errors.drain(..) {
 				let _ = handle_error!(self, err, counterparty_node_id);
 			}
+			self.enforce_event_queue_limits();
 			should_persist
 		});
 	}
 
+	/// Gets the time at which the next [`TimerTask`] is due, on the clock passed to
+	/// [`ChannelManager::process_timer_tasks`]. Tasks which have never run are due at time zero.
+	pub fn next_timer_task_deadline(&self) -> Duration {
+		self.timer_schedule.lock().unwrap().next_deadline()
+	}
+
+	/// Prepares for the app to be suspended in the background, e.g. by iOS or Android, returning
+	/// a [`BackgroundResumeHint`] to be stored until [`ChannelManager::resume_from_background`] is
+	/// called.
+	///
+	/// Any pending background events are processed and the `ChannelManager` is flagged as needing
+	/// persistence, so that a `lightning-background-processor` `BackgroundProcessor` persists it
+	/// right away. Apps which persist the `ChannelManager` themselves should do so after calling
+	/// this. Until resumed, [`ChannelManager::timer_tick_occurred`] and
+	/// [`ChannelManager::process_timer_tasks`] do nothing, so that timers firing during brief
+	/// background execution windows don't count towards timeouts.
+	pub fn prepare_for_background(&self) -> BackgroundResumeHint {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		self.process_background_events();
+
+		let highest_seen_timestamp = self.highest_seen_timestamp.load(Ordering::Acquire) as u32;
+		let earliest_htlc_expiry = self.channel_state.lock().unwrap().by_id.values()
+			.flat_map(|chan| chan.get_observed_htlcs())
+			.map(|htlc| htlc.cltv_expiry)
+			.min();
+		let mut backgrounded_at = self.backgrounded_at.lock().unwrap();
+		if backgrounded_at.is_none() {
+			log_info!(self.logger, "Preparing to be put in the background");
+			*backgrounded_at = Some(highest_seen_timestamp);
+		}
+		BackgroundResumeHint {
+			highest_seen_timestamp,
+			best_block_height: self.best_block.read().unwrap().height(),
+			earliest_htlc_expiry,
+		}
+	}
+
+	/// Resumes from the background after [`ChannelManager::prepare_for_background`], given the
+	/// wall clock time `elapsed` since then. Does nothing if we're not in the background.
+	///
+	/// Rather than replaying the timer ticks missed while suspended, time-based state is caught
+	/// up in one go:
+	///  * incomplete MPP payments and held forwards are timed out as if the missed ticks had
+	///    occurred,
+	///  * inbound payments, including those created via
+	///    [`ChannelManager::create_inbound_payment`], are expired as though we'd seen a block with
+	///    a timestamp `elapsed` after the last one, without waiting for the chain to sync,
+	///  * each [`TimerTask`] becomes due, running once on the next call to
+	///    [`ChannelManager::timer_tick_occurred`].
+	///
+	/// The scorer is not owned by the `ChannelManager`; a [`ProbabilisticScorer`] should be
+	/// caught up via [`ProbabilisticScorer::time_passed`] alongside this.
+	///
+	/// [`ProbabilisticScorer`]: crate::routing::scoring::ProbabilisticScorer
+	/// [`ProbabilisticScorer::time_passed`]: crate::routing::scoring::ProbabilisticScorerUsingTime::time_passed
+	pub fn resume_from_background(&self, elapsed: Duration) {
+		let backgrounded_at = match self.backgrounded_at.lock().unwrap().take() {
+			Some(backgrounded_at) => backgrounded_at,
+			None => return,
+		};
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		log_info!(self.logger, "Resuming from the background after {} seconds", elapsed.as_secs());
+
+		self.timer_schedule.lock().unwrap().skipped += elapsed;
+		let missed_ticks = cmp::min(elapsed.as_secs() / TIMER_TASK_INTERVAL.as_secs(), u8::max_value() as u64) as u8;
+		if missed_ticks > 0 {
+			let timed_out_mpp_htlcs = Self::time_out_mpp_htlcs(&mut self.channel_state.lock().unwrap(), missed_ticks);
+			for (prev_hop, payment_hash) in timed_out_mpp_htlcs {
+				let receiver = HTLCDestination::FailedPayment { payment_hash };
+				self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), HTLCSource::PreviousHopData(prev_hop), &payment_hash, HTLCFailReason::Reason { failure_code: 23, data: Vec::new() }, receiver);
+			}
+			self.time_out_held_forwards(missed_ticks);
+		}
+
+		let resumed_timestamp = (backgrounded_at as u64).saturating_add(elapsed.as_secs());
+		let resumed_timestamp = cmp::min(resumed_timestamp, u32::max_value() as u64) as usize;
+		loop {
+			let old_timestamp = self.highest_seen_timestamp.load(Ordering::Acquire);
+			if old_timestamp >= resumed_timestamp { break; }
+			if self.highest_seen_timestamp.compare_exchange(old_timestamp, resumed_timestamp, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+				break;
+			}
+		}
+		self.expire_funding_intents();
+		self.expire_inbound_payments(self.highest_seen_timestamp.load(Ordering::Acquire) as u64);
+	}
+
 	/// Indicates that the preimage for payment_hash is unknown or the received amount is incorrect
 	/// after a PaymentReceived event, failing the HTLC back to its origin and freeing resources
 	/// along the path (including in our own channel on which we received it).
@@ -3741,7 +6982,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// [`events::Event::PaymentClaimed`] events even for payments you intend to fail, especially on
 	/// startup during which time claims that were in-progress at shutdown may be replayed.
 	pub fn fail_htlc_backwards(&self, payment_hash: &PaymentHash) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 
 		let mut channel_state = Some(self.channel_state.lock().unwrap());
 		let removed_source = channel_state.as_mut().unwrap().claimable_htlcs.remove(payment_hash);
@@ -3837,7 +7078,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 							let retry = if let Some(payment_params_data) = payment_params {
 								let path_last_hop = path.last().expect("Outbound payments must have had a valid path");
 								Some(RouteParameters {
-									payment_params: payment_params_data,
+									payment_params: payment.get().retry_payment_params(&payment_params_data),
 									final_value_msat: path_last_hop.fee_msat,
 									final_cltv_expiry_delta: path_last_hop.cltv_expiry_delta,
 								})
@@ -3895,6 +7136,8 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				let mut outbounds = self.pending_outbound_payments.lock().unwrap();
 				let mut all_paths_failed = false;
 				let mut full_failure_ev = None;
+				let mut failed_trampoline_forward = None;
+				let retry_payment_params;
 				if let hash_map::Entry::Occupied(mut payment) = outbounds.entry(payment_id) {
 					if !payment.get_mut().remove(&session_priv_bytes, Some(&path)) {
 						log_trace!(self.logger, "Received duplicative fail for HTLC with payment_hash {}", log_bytes!(payment_hash.0));
@@ -3904,9 +7147,16 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 						log_trace!(self.logger, "Received failure of HTLC with payment_hash {} after payment completion", log_bytes!(payment_hash.0));
 						return;
 					}
+					retry_payment_params = payment_params.as_ref().map(|params| payment.get().retry_payment_params(params));
 					if payment.get().remaining_parts() == 0 {
 						all_paths_failed = true;
-						if payment.get().abandoned() {
+						let is_trampoline_forward = channel_state_lock.trampoline_forwards.get(payment_hash)
+							.map_or(false, |forward| forward.outbound_payment_id == Some(payment_id));
+						if is_trampoline_forward {
+							// Trampoline payments can't be retried, so fail them back right away.
+							failed_trampoline_forward = channel_state_lock.trampoline_forwards.remove(payment_hash);
+							payment.remove();
+						} else if payment.get().abandoned() {
 							full_failure_ev = Some(events::Event::PaymentFailed {
 								payment_id,
 								payment_hash: payment.get().payment_hash().expect("PendingOutboundPayments::RetriesExceeded always has a payment hash set"),
@@ -3919,10 +7169,11 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					return;
 				}
 				mem::drop(channel_state_lock);
-				let mut retry = if let Some(payment_params_data) = payment_params {
+				let force_abandoned = self.payment_abandonment_records.lock().unwrap().contains_key(&payment_id);
+				let mut retry = if force_abandoned { None } else if let Some(payment_params_data) = retry_payment_params {
 					let path_last_hop = path.last().expect("Outbound payments must have had a valid path");
 					Some(RouteParameters {
-						payment_params: payment_params_data.clone(),
+						payment_params: payment_params_data,
 						final_value_msat: path_last_hop.fee_msat,
 						final_cltv_expiry_delta: path_last_hop.cltv_expiry_delta,
 					})
@@ -4008,8 +7259,28 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				let mut pending_events = self.pending_events.lock().unwrap();
 				pending_events.push(path_failure);
 				if let Some(ev) = full_failure_ev { pending_events.push(ev); }
+				mem::drop(pending_events);
+				mem::drop(outbounds);
+				if let Some(forward) = failed_trampoline_forward {
+					self.fail_trampoline_htlcs(self.channel_state.lock().unwrap(), forward, 0x2000 | 25);
+				}
 			},
-			HTLCSource::PreviousHopData(HTLCPreviousHopData { short_channel_id, htlc_id, incoming_packet_shared_secret, phantom_shared_secret, outpoint }) => {
+			HTLCSource::PreviousHopData(HTLCPreviousHopData { short_channel_id, htlc_id, incoming_packet_shared_secret, phantom_shared_secret, blinded_failure, outpoint }) => {
+				let onion_error = match blinded_failure {
+					// Whatever the actual failure, the sender only learns that the blinded route failed.
+					Some(BlindedFailure::FromIntroductionNode) => {
+						log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards as invalid_onion_blinding as we're the introduction node", log_bytes!(payment_hash.0));
+						HTLCFailReason::Reason { failure_code: INVALID_ONION_BLINDING, data: vec![0; 32] }
+					},
+					Some(BlindedFailure::FromBlindedNode) => {
+						log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards with update_fail_malformed_htlc as we're within a blinded route", log_bytes!(payment_hash.0));
+						self.push_fail_backwards(channel_state_lock, short_channel_id, HTLCForwardInfo::FailMalformedHTLC {
+							htlc_id, failure_code: INVALID_ONION_BLINDING, sha256_of_onion: [0; 32],
+						}, outpoint, destination);
+						return;
+					},
+					None => onion_error,
+				};
 				let err_packet = match onion_error {
 					HTLCFailReason::Reason { failure_code, data } => {
 						log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards from us with code {}", log_bytes!(payment_hash.0), failure_code);
@@ -4028,31 +7299,39 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					}
 				};
 
-				let mut forward_event = None;
-				if channel_state_lock.forward_htlcs.is_empty() {
-					forward_event = Some(Duration::from_millis(MIN_HTLC_RELAY_HOLDING_CELL_MILLIS));
-				}
-				match channel_state_lock.forward_htlcs.entry(short_channel_id) {
-					hash_map::Entry::Occupied(mut entry) => {
-						entry.get_mut().push(HTLCForwardInfo::FailHTLC { htlc_id, err_packet });
-					},
-					hash_map::Entry::Vacant(entry) => {
-						entry.insert(vec!(HTLCForwardInfo::FailHTLC { htlc_id, err_packet }));
-					}
-				}
-				mem::drop(channel_state_lock);
-				let mut pending_events = self.pending_events.lock().unwrap();
-				if let Some(time) = forward_event {
-					pending_events.push(events::Event::PendingHTLCsForwardable {
-						time_forwardable: time
-					});
-				}
-				pending_events.push(events::Event::HTLCHandlingFailed {
-					prev_channel_id: outpoint.to_channel_id(),
-					failed_next_destination: destination
-				});
+				self.push_fail_backwards(channel_state_lock, short_channel_id, HTLCForwardInfo::FailHTLC { htlc_id, err_packet }, outpoint, destination);
+			},
+		}
+	}
+
+	/// Queues the failure of an HTLC we received over the channel with the given
+	/// `short_channel_id`, to be sent on the next call to [`Self::process_pending_htlc_forwards`].
+	fn push_fail_backwards(&self, mut channel_state_lock: MutexGuard<ChannelHolder<Signer>>, short_channel_id: u64,
+		fail: HTLCForwardInfo, outpoint: OutPoint, destination: HTLCDestination)
+	{
+		let mut forward_event = None;
+		if channel_state_lock.forward_htlcs.is_empty() {
+			forward_event = Some(Duration::from_millis(MIN_HTLC_RELAY_HOLDING_CELL_MILLIS));
+		}
+		match channel_state_lock.forward_htlcs.entry(short_channel_id) {
+			hash_map::Entry::Occupied(mut entry) => {
+				entry.get_mut().push(fail);
 			},
+			hash_map::Entry::Vacant(entry) => {
+				entry.insert(vec!(fail));
+			}
+		}
+		mem::drop(channel_state_lock);
+		let mut pending_events = self.pending_events.lock().unwrap();
+		if let Some(time) = forward_event {
+			pending_events.push(events::Event::PendingHTLCsForwardable {
+				time_forwardable: time
+			});
 		}
+		pending_events.push(events::Event::HTLCHandlingFailed {
+			prev_channel_id: outpoint.to_channel_id(),
+			failed_next_destination: destination
+		});
 	}
 
 	/// Provides a payment preimage in response to [`Event::PaymentReceived`], generating any
@@ -4074,10 +7353,9 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// [`create_inbound_payment_for_hash`]: Self::create_inbound_payment_for_hash
 	/// [`get_and_clear_pending_msg_events`]: MessageSendEventsProvider::get_and_clear_pending_msg_events
 	pub fn claim_funds(&self, payment_preimage: PaymentPreimage) {
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
 
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
-
 		let mut channel_state = Some(self.channel_state.lock().unwrap());
 		let removed_source = channel_state.as_mut().unwrap().claimable_htlcs.remove(&payment_hash);
 		if let Some((payment_purpose, mut sources)) = removed_source {
@@ -4126,7 +7404,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				log_info!(self.logger, "Attempted to claim an incomplete payment which no longer had any available HTLCs!");
 				return;
 			}
-			if claimable_amt_msat != expected_amt_msat.unwrap() {
+			if claimable_amt_msat < expected_amt_msat.unwrap() {
 				log_info!(self.logger, "Attempted to claim an incomplete payment, expected {} msat, had {} available to claim.",
 					expected_amt_msat.unwrap(), claimable_amt_msat);
 				return;
@@ -4167,11 +7445,15 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			}
 
 			if claimed_any_htlcs {
-				self.pending_events.lock().unwrap().push(events::Event::PaymentClaimed {
+				let volume_events = self.inbound_volume.lock().unwrap()
+					.payment_claimed(claimable_amt_msat, &self.get_current_default_configuration().inbound_volume);
+				let mut pending_events = self.pending_events.lock().unwrap();
+				pending_events.push(events::Event::PaymentClaimed {
 					payment_hash,
 					purpose: payment_purpose,
 					amount_msat: claimable_amt_msat,
 				});
+				pending_events.extend(volume_events);
 			}
 
 			// Now that we've done the entire above loop in one lock, we can handle any errors
@@ -4275,14 +7557,21 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	fn claim_funds_internal(&self, mut channel_state_lock: MutexGuard<ChannelHolder<Signer>>, source: HTLCSource, payment_preimage: PaymentPreimage, forwarded_htlc_value_msat: Option<u64>, from_onchain: bool, next_channel_id: [u8; 32]) {
 		match source {
 			HTLCSource::OutboundRoute { session_priv, payment_id, path, .. } => {
+				let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
+				let trampoline_forward = match channel_state_lock.trampoline_forwards.get(&payment_hash) {
+					Some(forward) if forward.outbound_payment_id == Some(payment_id) =>
+						channel_state_lock.trampoline_forwards.remove(&payment_hash),
+					_ => None,
+				};
 				mem::drop(channel_state_lock);
 				let mut session_priv_bytes = [0; 32];
 				session_priv_bytes.copy_from_slice(&session_priv[..]);
 				let mut outbounds = self.pending_outbound_payments.lock().unwrap();
 				if let hash_map::Entry::Occupied(mut payment) = outbounds.entry(payment_id) {
 					let mut pending_events = self.pending_events.lock().unwrap();
-					if !payment.get().is_fulfilled() {
-						let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
+					if !payment.get().is_fulfilled() && trampoline_forward.is_some() {
+						payment.get_mut().mark_fulfilled();
+					} else if !payment.get().is_fulfilled() {
 						let fee_paid_msat = payment.get().get_pending_fee_msat();
 						pending_events.push(
 							events::Event::PaymentSent {
@@ -4320,6 +7609,20 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				} else {
 					log_trace!(self.logger, "Received duplicative fulfill for HTLC with payment_preimage {}", log_bytes!(payment_preimage.0));
 				}
+				mem::drop(outbounds);
+
+				if let Some(forward) = trampoline_forward {
+					log_debug!(self.logger, "Claiming the inbound parts of trampoline payment with payment_hash {}", log_bytes!(payment_hash.0));
+					let received_msat = forward.total_received_msat();
+					for htlc in forward.htlcs {
+						self.claim_funds_internal(self.channel_state.lock().unwrap(), HTLCSource::PreviousHopData(htlc.prev_hop),
+							payment_preimage, None, from_onchain, next_channel_id);
+					}
+					self.pending_events.lock().unwrap().push(events::Event::TrampolinePaymentForwarded {
+						payment_hash,
+						fee_earned_msat: received_msat.saturating_sub(forward.outbound_amount_msat.unwrap_or(0)),
+					});
+				}
 			},
 			HTLCSource::PreviousHopData(hop_data) => {
 				let prev_outpoint = hop_data.outpoint;
@@ -4440,7 +7743,8 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	///
 	/// Note that this method will return an error and reject the channel, if it requires support
 	/// for zero confirmations. Instead, `accept_inbound_channel_from_trusted_peer_0conf` must be
-	/// used to accept such channels.
+	/// used to accept such channels, unless the peer is trusted via
+	/// [`PeerConfigOverrides::trusted_zero_conf`], in which case this method behaves identically.
 	///
 	/// [`Event::OpenChannelRequest`]: events::Event::OpenChannelRequest
 	/// [`Event::ChannelClosed::user_channel_id`]: events::Event::ChannelClosed::user_channel_id
@@ -4471,7 +7775,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	}
 
 	fn do_accept_inbound_channel(&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey, accept_0conf: bool, user_channel_id: u64) -> Result<(), APIError> {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = self.active_persistence_guard()?;
 
 		let mut channel_state_lock = self.channel_state.lock().unwrap();
 		let channel_state = &mut *channel_state_lock;
@@ -4483,7 +7787,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				if *counterparty_node_id != channel.get().get_counterparty_node_id() {
 					return Err(APIError::APIMisuseError { err: "The passed counterparty_node_id doesn't match the channel's counterparty node_id".to_owned() });
 				}
-				if accept_0conf {
+				if accept_0conf || self.trusts_peer_0conf(counterparty_node_id) {
 					channel.get_mut().set_0conf();
 				} else if channel.get().get_channel_type().requires_zero_conf() {
 					let send_msg_err_event = events::MessageSendEvent::HandleError {
@@ -4514,13 +7818,25 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 			return Err(MsgHandleErrInternal::send_err_msg_no_close("Unknown genesis block hash".to_owned(), msg.temporary_channel_id.clone()));
 		}
 
-		if !self.default_configuration.accept_inbound_channels {
+		let config = self.get_config_for_peer(counterparty_node_id);
+		if !config.accept_inbound_channels {
 			return Err(MsgHandleErrInternal::send_err_msg_no_close("No inbound channels accepted".to_owned(), msg.temporary_channel_id.clone()));
 		}
 
+		if let Err(APIError::ChannelUnavailable { err }) = self.check_event_queue_capacity() {
+			return Err(MsgHandleErrInternal::send_err_msg_no_close(err, msg.temporary_channel_id));
+		}
+
+		let missing_features = config.peer_feature_requirements.missing_features(&their_features);
+		if !missing_features.is_empty() {
+			return Err(MsgHandleErrInternal::send_err_msg_no_close(
+				format!("No inbound channels accepted from peers without features: {}", missing_features.join(", ")),
+				msg.temporary_channel_id));
+		}
+
 		let outbound_scid_alias = self.create_and_insert_outbound_scid_alias();
 		let mut channel = match Channel::new_from_req(&self.fee_estimator, &self.keys_manager,
-			counterparty_node_id.clone(), &their_features, msg, 0, &self.default_configuration,
+			counterparty_node_id.clone(), &their_features, msg, 0, &config,
 			self.best_block.read().unwrap().height(), &self.logger, outbound_scid_alias)
 		{
 			Err(e) => {
@@ -4531,14 +7847,20 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		};
 		let mut channel_state_lock = self.channel_state.lock().unwrap();
 		let channel_state = &mut *channel_state_lock;
+		if let Err(err) = self.check_pending_channel_limit(counterparty_node_id, &channel_state.by_id) {
+			self.outbound_scid_aliases.lock().unwrap().remove(&outbound_scid_alias);
+			return Err(MsgHandleErrInternal::send_err_msg_no_close(err, msg.temporary_channel_id));
+		}
 		match channel_state.by_id.entry(channel.channel_id()) {
 			hash_map::Entry::Occupied(_) => {
 				self.outbound_scid_aliases.lock().unwrap().remove(&outbound_scid_alias);
 				return Err(MsgHandleErrInternal::send_err_msg_no_close("temporary_channel_id collision!".to_owned(), msg.temporary_channel_id.clone()))
 			},
 			hash_map::Entry::Vacant(entry) => {
-				if !self.default_configuration.manually_accept_inbound_channels {
-					if channel.get_channel_type().requires_zero_conf() {
+				if !config.manually_accept_inbound_channels {
+					if self.trusts_peer_0conf(counterparty_node_id) {
+						channel.set_0conf();
+					} else if channel.get_channel_type().requires_zero_conf() {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("No zero confirmation channels accepted".to_owned(), msg.temporary_channel_id.clone()));
 					}
 					channel_state.pending_msg_events.push(events::MessageSendEvent::SendAcceptChannel {
@@ -4573,8 +7895,12 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					if chan.get().get_counterparty_node_id() != *counterparty_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!".to_owned(), msg.temporary_channel_id));
 					}
-					try_chan_entry!(self, chan.get_mut().accept_channel(&msg, &self.default_configuration.channel_handshake_limits, &their_features), channel_state, chan);
-					(chan.get().get_value_satoshis(), chan.get().get_funding_redeemscript().to_v0_p2wsh(), chan.get().get_user_id())
+					try_chan_entry!(self, chan.get_mut().accept_channel(&msg, &self.get_current_default_configuration().channel_handshake_limits, &their_features), channel_state, chan);
+					let output_script = chan.get().get_funding_redeemscript().to_v0_p2wsh();
+					if let Some(intent) = self.funding_intents.lock().unwrap().get_mut(&msg.temporary_channel_id) {
+						intent.output_script = Some(output_script.clone());
+					}
+					(chan.get().get_value_satoshis(), output_script, chan.get().get_user_id())
 				},
 				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel".to_owned(), msg.temporary_channel_id))
 			}
@@ -4860,7 +8186,10 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					// but if we've sent a shutdown and they haven't acknowledged it yet, we just
 					// want to reject the new HTLC and fail it backwards instead of forwarding.
 					match pending_forward_info {
-						PendingHTLCStatus::Forward(PendingHTLCInfo { ref incoming_shared_secret, .. }) => {
+						PendingHTLCStatus::Forward(PendingHTLCInfo { ref incoming_shared_secret, ref routing, .. }) => {
+							if let PendingHTLCRouting::Forward { blinded: Some(ref blinded), .. } = routing {
+								return PendingHTLCStatus::Fail(blinded_failure_msg(msg, blinded.failure, incoming_shared_secret));
+							}
 							let reason = if (error_code & 0x1000) != 0 {
 								let (real_code, error_data) = self.get_htlc_inbound_temp_fail_err_and_data(error_code, chan);
 								onion_utils::build_first_hop_failure_packet(incoming_shared_secret, real_code, &error_data)
@@ -4911,6 +8240,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!".to_owned(), msg.channel_id));
 				}
 				try_chan_entry!(self, chan.get_mut().update_fail_htlc(&msg, HTLCFailReason::LightningError { err: msg.reason.clone() }), channel_state, chan);
+				self.add_probation_strike(chan.get_mut(), ProbationStrike::HTLCFailure);
 			},
 			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel".to_owned(), msg.channel_id))
 		}
@@ -4930,6 +8260,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					try_chan_entry!(self, Err(chan_err), channel_state, chan);
 				}
 				try_chan_entry!(self, chan.get_mut().update_fail_malformed_htlc(&msg, HTLCFailReason::Reason { failure_code: msg.failure_code, data: Vec::new() }), channel_state, chan);
+				self.add_probation_strike(chan.get_mut(), ProbationStrike::HTLCFailure);
 				Ok(())
 			},
 			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel".to_owned(), msg.channel_id))
@@ -4995,6 +8326,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 							PendingHTLCRouting::Forward { short_channel_id, .. } => short_channel_id,
 							PendingHTLCRouting::Receive { .. } => 0,
 							PendingHTLCRouting::ReceiveKeysend { .. } => 0,
+							PendingHTLCRouting::ReceiveTrampoline { .. } => 0,
 					}) {
 						hash_map::Entry::Occupied(mut entry) => {
 							entry.get_mut().push(HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_funding_outpoint,
@@ -5090,7 +8422,13 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 				if chan.get().get_counterparty_node_id() != *counterparty_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!".to_owned(), msg.channel_id));
 				}
-				try_chan_entry!(self, chan.get_mut().update_fee(&self.fee_estimator, &msg), channel_state, chan);
+				let mut enforce_feerate_bounds = true;
+				if !chan.get().is_outbound() && Channel::<Signer>::check_remote_fee(&self.fee_estimator, msg.feerate_per_kw).is_err() {
+					enforce_feerate_bounds = self.should_force_close(chan.get_mut(), ForceCloseReason::FeerateDisagreement {
+						counterparty_feerate_sat_per_1000_weight: msg.feerate_per_kw,
+					});
+				}
+				try_chan_entry!(self, chan.get_mut().update_fee(&self.fee_estimator, msg, enforce_feerate_bounds), channel_state, chan);
 			},
 			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel".to_owned(), msg.channel_id))
 		}
@@ -5409,18 +8747,20 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// Channel object.
 	fn handle_init_event_channel_failures(&self, mut failed_channels: Vec<ShutdownResult>) {
 		for mut failure in failed_channels.drain(..) {
-			// Either a commitment transactions has been confirmed on-chain or
+			// Either a commitment transactions has been confirmed on-chain,
 			// Channel::block_disconnected detected that the funding transaction has been
-			// reorganized out of the main chain.
+			// reorganized out of the main chain, or we gave up on our own funding transaction
+			// ever confirming.
 			// We cannot broadcast our latest local state via monitor update (as
-			// Channel::force_shutdown tries to make us do) as we may still be in initialization,
-			// so we track the update internally and handle it when the user next calls
-			// timer_tick_occurred, guaranteeing we're running normally.
+			// Channel::force_shutdown tries to make us do in the first two cases) as we may still
+			// be in initialization, so we track the update internally and handle it when the user
+			// next calls timer_tick_occurred, guaranteeing we're running normally.
 			if let Some((funding_txo, update)) = failure.0.take() {
 				assert_eq!(update.updates.len(), 1);
-				if let ChannelMonitorUpdateStep::ChannelForceClosed { should_broadcast } = update.updates[0] {
-					assert!(should_broadcast);
-				} else { unreachable!(); }
+				match update.updates[0] {
+					ChannelMonitorUpdateStep::ChannelForceClosed { .. } => {},
+					_ => unreachable!(),
+				}
 				self.pending_background_events.lock().unwrap().push(BackgroundEvent::ClosingMonitorUpdate((funding_txo, update)));
 			}
 			self.finish_force_close_channel(failure);
@@ -5552,6 +8892,59 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		inbound_payment::create_from_hash(&self.inbound_payment_key, min_value_msat, payment_hash, invoice_expiry_delta_secs, self.highest_seen_timestamp.load(Ordering::Acquire) as u64)
 	}
 
+	/// Gets a payment secret and payment hash for use in an invoice as with
+	/// [`create_inbound_payment`], additionally registering `limits` on the HTLCs we will accept
+	/// for the payment.
+	///
+	/// Unlike the payment itself, the limits are stored in the `ChannelManager` until the invoice
+	/// expires, and are thus lost if an older `ChannelManager` is used to receive the payment.
+	///
+	/// Errors if `min_value_msat` is greater than total bitcoin supply or than
+	/// [`InboundPaymentLimits::max_total_msat`], or if [`InboundPaymentLimits::max_parts`] is zero.
+	///
+	/// [`create_inbound_payment`]: Self::create_inbound_payment
+	pub fn create_inbound_payment_with_limits(&self, min_value_msat: Option<u64>, invoice_expiry_delta_secs: u32, limits: InboundPaymentLimits) -> Result<(PaymentHash, PaymentSecret), ()> {
+		Self::check_inbound_payment_limits(min_value_msat, &limits)?;
+		let (payment_hash, payment_secret) = self.create_inbound_payment(min_value_msat, invoice_expiry_delta_secs)?;
+		self.register_inbound_payment_limits(payment_hash, invoice_expiry_delta_secs, limits);
+		Ok((payment_hash, payment_secret))
+	}
+
+	/// Gets a [`PaymentSecret`] for a given [`PaymentHash`] as with
+	/// [`create_inbound_payment_for_hash`], additionally registering `limits` on the HTLCs we will
+	/// accept for the payment.
+	///
+	/// See [`create_inbound_payment_with_limits`] for more details.
+	///
+	/// [`create_inbound_payment_for_hash`]: Self::create_inbound_payment_for_hash
+	/// [`create_inbound_payment_with_limits`]: Self::create_inbound_payment_with_limits
+	pub fn create_inbound_payment_for_hash_with_limits(&self, payment_hash: PaymentHash, min_value_msat: Option<u64>, invoice_expiry_delta_secs: u32, limits: InboundPaymentLimits) -> Result<PaymentSecret, ()> {
+		Self::check_inbound_payment_limits(min_value_msat, &limits)?;
+		let payment_secret = self.create_inbound_payment_for_hash(payment_hash, min_value_msat, invoice_expiry_delta_secs)?;
+		self.register_inbound_payment_limits(payment_hash, invoice_expiry_delta_secs, limits);
+		Ok(payment_secret)
+	}
+
+	fn check_inbound_payment_limits(min_value_msat: Option<u64>, limits: &InboundPaymentLimits) -> Result<(), ()> {
+		if limits.max_parts == Some(0) { return Err(()); }
+		match (min_value_msat, limits.max_total_msat) {
+			(Some(min), Some(max)) if min > max => Err(()),
+			_ => Ok(()),
+		}
+	}
+
+	fn register_inbound_payment_limits(&self, payment_hash: PaymentHash, invoice_expiry_delta_secs: u32, limits: InboundPaymentLimits) {
+		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		self.inbound_payment_limits.lock().unwrap().insert(payment_hash, RegisteredInboundPaymentLimits {
+			payment_hash,
+			max_total_msat: limits.max_total_msat,
+			max_parts: limits.max_parts.map(|max| max as u64),
+			// As for legacy inbound payments, we add two hours to account for inaccurate block
+			// header timestamps.
+			expiry_time: self.highest_seen_timestamp.load(Ordering::Acquire) as u64 + invoice_expiry_delta_secs as u64 + 7200,
+		});
+	}
+
 	/// Legacy version of [`create_inbound_payment_for_hash`]. Use this method if you wish to share
 	/// serialized state with LDK node(s) running 0.0.103 and earlier.
 	///
@@ -5595,10 +8988,13 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	///
 	/// [phantom node payments]: crate::chain::keysinterface::PhantomKeysManager
 	pub fn get_phantom_route_hints(&self) -> PhantomRouteHints {
+		let cltv_policy = self.get_current_default_configuration().cltv_policy;
 		PhantomRouteHints {
 			channels: self.list_usable_channels(),
 			phantom_scid: self.get_phantom_scid(),
 			real_node_pubkey: self.get_our_node_id(),
+			min_cltv_expiry_delta: cltv_policy.min_cltv_expiry_delta,
+			min_final_cltv_expiry: cltv_policy.min_final_cltv_expiry,
 		}
 	}
 
@@ -5709,6 +9105,8 @@ where
 	L::Target: Logger,
 {
 	fn filtered_block_connected(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		// A standby ignores chain data, and is synced to the chain once promoted.
+		if self.standby.load(Ordering::Acquire) { return; }
 		{
 			let best_block = self.best_block.read().unwrap();
 			assert_eq!(best_block.block_hash(), header.prev_blockhash,
@@ -5722,7 +9120,7 @@ where
 	}
 
 	fn block_disconnected(&self, header: &BlockHeader, height: u32) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let new_height = height - 1;
 		{
 			let mut best_block = self.best_block.write().unwrap();
@@ -5746,6 +9144,7 @@ where
 	L::Target: Logger,
 {
 	fn transactions_confirmed(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		if self.standby.load(Ordering::Acquire) { return; }
 		// Note that we MUST NOT end up calling methods on self.chain_monitor here - we're called
 		// during initialization prior to the chain_monitor being fully configured in some cases.
 		// See the docs for `ChannelManagerReadArgs` for more.
@@ -5753,7 +9152,7 @@ where
 		let block_hash = header.block_hash();
 		log_trace!(self.logger, "{} transactions included in block {} at height {} provided", txdata.len(), block_hash, height);
 
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		self.do_chain_event(Some(height), |channel| channel.transactions_confirmed(&block_hash, height, txdata, self.genesis_hash.clone(), self.get_our_node_id(), &self.logger)
 			.map(|(a, b)| (a, Vec::new(), b)));
 
@@ -5765,6 +9164,7 @@ where
 	}
 
 	fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+		if self.standby.load(Ordering::Acquire) { return; }
 		// Note that we MUST NOT end up calling methods on self.chain_monitor here - we're called
 		// during initialization prior to the chain_monitor being fully configured in some cases.
 		// See the docs for `ChannelManagerReadArgs` for more.
@@ -5772,7 +9172,7 @@ where
 		let block_hash = header.block_hash();
 		log_trace!(self.logger, "New best block: {} at height {}", block_hash, height);
 
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 
 		*self.best_block.write().unwrap() = BestBlock::new(block_hash, height);
 
@@ -5796,10 +9196,8 @@ where
 		}
 		max_time!(self.last_node_announcement_serial);
 		max_time!(self.highest_seen_timestamp);
-		let mut payment_secrets = self.pending_inbound_payments.lock().unwrap();
-		payment_secrets.retain(|_, inbound_payment| {
-			inbound_payment.expiry_time > header.time as u64
-		});
+		self.expire_funding_intents();
+		self.expire_inbound_payments(header.time as u64);
 
 		let mut outbounds = self.pending_outbound_payments.lock().unwrap();
 		let mut pending_events = self.pending_events.lock().unwrap();
@@ -5829,7 +9227,7 @@ where
 	}
 
 	fn transaction_unconfirmed(&self, txid: &Txid) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		self.do_chain_event(None, |channel| {
 			if let Some(funding_txo) = channel.get_funding_txo() {
 				if funding_txo.txid == *txid {
@@ -5919,10 +9317,17 @@ where
 						}
 					}
 				} else if let Err(reason) = res {
+					if reason == ClosureReason::FundingTimedOut && !self.should_force_close(channel, ForceCloseReason::FundingTimedOut) {
+						return true;
+					}
 					update_maps_on_chan_removal!(self, short_to_chan_info, channel);
 					// It looks like our counterparty went on-chain or funding transaction was
 					// reorged out of the main chain. Close the channel.
-					failed_channels.push(channel.force_shutdown(true));
+					// If we're giving up on our own funding transaction, however, it never confirmed
+					// so there's no point in broadcasting a commitment transaction spending it.
+					// Instead, the user will double-spend it (see `Event::FundingTimedOut`).
+					let abandoning_funding = reason == ClosureReason::FundingTimedOut && channel.is_outbound();
+					failed_channels.push(channel.force_shutdown(!abandoning_funding));
 					if let Ok(update) = self.get_channel_update_for_broadcast(&channel) {
 						pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 							msg: update
@@ -5962,6 +9367,22 @@ where
 					});
 					!htlcs.is_empty() // Only retain this entry if htlcs has at least one entry.
 				});
+				// Trampoline payments we've yet to forward are failed back in full, as we can no
+				// longer forward them with the amount the sender asked for.
+				channel_state.trampoline_forwards.retain(|payment_hash, forward| {
+					if forward.outbound_payment_id.is_some() ||
+						!forward.htlcs.iter().any(|htlc| height >= htlc.cltv_expiry - HTLC_FAIL_BACK_BUFFER)
+					{
+						return true;
+					}
+					for htlc in forward.htlcs.drain(..) {
+						timed_out_htlcs.push((HTLCSource::PreviousHopData(htlc.prev_hop), *payment_hash, HTLCFailReason::Reason {
+							failure_code: 0x2000 | 25,
+							data: Vec::new(),
+						}, HTLCDestination::FailedPayment { payment_hash: *payment_hash }));
+					}
+					false
+				});
 			}
 		}
 
@@ -5970,6 +9391,7 @@ where
 		for (source, payment_hash, reason, destination) in timed_out_htlcs.drain(..) {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), source, &payment_hash, reason, destination);
 		}
+		self.enforce_event_queue_limits();
 	}
 
 	/// Blocks until ChannelManager needs to be persisted or a timeout is reached. It returns a bool
@@ -6014,82 +9436,85 @@ impl<Signer: Sign, M: Deref , T: Deref , K: Deref , F: Deref , L: Deref >
         L::Target: Logger,
 {
 	fn handle_open_channel(&self, counterparty_node_id: &PublicKey, their_features: InitFeatures, msg: &msgs::OpenChannel) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_open_channel(counterparty_node_id, their_features, msg), *counterparty_node_id);
 	}
 
 	fn handle_accept_channel(&self, counterparty_node_id: &PublicKey, their_features: InitFeatures, msg: &msgs::AcceptChannel) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_accept_channel(counterparty_node_id, their_features, msg), *counterparty_node_id);
 	}
 
 	fn handle_funding_created(&self, counterparty_node_id: &PublicKey, msg: &msgs::FundingCreated) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_funding_created(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_funding_signed(&self, counterparty_node_id: &PublicKey, msg: &msgs::FundingSigned) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_funding_signed(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_channel_ready(&self, counterparty_node_id: &PublicKey, msg: &msgs::ChannelReady) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_channel_ready(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_shutdown(&self, counterparty_node_id: &PublicKey, their_features: &InitFeatures, msg: &msgs::Shutdown) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_shutdown(counterparty_node_id, their_features, msg), *counterparty_node_id);
 	}
 
 	fn handle_closing_signed(&self, counterparty_node_id: &PublicKey, msg: &msgs::ClosingSigned) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_closing_signed(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_update_add_htlc(&self, counterparty_node_id: &PublicKey, msg: &msgs::UpdateAddHTLC) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_update_add_htlc(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_update_fulfill_htlc(&self, counterparty_node_id: &PublicKey, msg: &msgs::UpdateFulfillHTLC) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_update_fulfill_htlc(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_update_fail_htlc(&self, counterparty_node_id: &PublicKey, msg: &msgs::UpdateFailHTLC) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_update_fail_htlc(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_update_fail_malformed_htlc(&self, counterparty_node_id: &PublicKey, msg: &msgs::UpdateFailMalformedHTLC) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_update_fail_malformed_htlc(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_commitment_signed(&self, counterparty_node_id: &PublicKey, msg: &msgs::CommitmentSigned) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_commitment_signed(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_revoke_and_ack(&self, counterparty_node_id: &PublicKey, msg: &msgs::RevokeAndACK) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_revoke_and_ack(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_update_fee(&self, counterparty_node_id: &PublicKey, msg: &msgs::UpdateFee) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_update_fee(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_announcement_signatures(&self, counterparty_node_id: &PublicKey, msg: &msgs::AnnouncementSignatures) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_announcement_signatures(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn handle_channel_update(&self, counterparty_node_id: &PublicKey, msg: &msgs::ChannelUpdate) {
+		if self.standby.load(Ordering::Acquire) { return; }
 		PersistenceNotifierGuard::optionally_notify(&self.total_consistency_lock, &self.persistence_notifier, || {
+			// We may have stepped down while waiting on the lock, see `active_persistence_guard`.
+			if self.standby.load(Ordering::Acquire) { return NotifyOption::SkipPersist; }
 			if let Ok(persist) = handle_error!(self, self.internal_channel_update(counterparty_node_id, msg), *counterparty_node_id) {
 				persist
 			} else {
@@ -6099,12 +9524,12 @@ impl<Signer: Sign, M: Deref , T: Deref , K: Deref , F: Deref , L: Deref >
 	}
 
 	fn handle_channel_reestablish(&self, counterparty_node_id: &PublicKey, msg: &msgs::ChannelReestablish) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let _ = handle_error!(self, self.internal_channel_reestablish(counterparty_node_id, msg), *counterparty_node_id);
 	}
 
 	fn peer_disconnected(&self, counterparty_node_id: &PublicKey, no_connection_possible: bool) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 		let mut failed_channels = Vec::new();
 		let mut no_channels_remain = true;
 		{
@@ -6117,6 +9542,7 @@ impl<Signer: Sign, M: Deref , T: Deref , K: Deref , F: Deref , L: Deref >
 			channel_state.by_id.retain(|_, chan| {
 				if chan.get_counterparty_node_id() == *counterparty_node_id {
 					chan.remove_uncommitted_htlcs_and_mark_paused(&self.logger);
+					self.add_probation_strike(chan, ProbationStrike::Disconnect);
 					if chan.is_shutdown() {
 						update_maps_on_chan_removal!(self, short_to_chan_info, chan);
 						self.issue_channel_close_events(chan, ClosureReason::DisconnectedPeer);
@@ -6162,10 +9588,19 @@ impl<Signer: Sign, M: Deref , T: Deref , K: Deref , F: Deref , L: Deref >
 	}
 
 	fn peer_connected(&self, counterparty_node_id: &PublicKey, init_msg: &msgs::Init) {
+		let _persistence_guard = match self.active_persistence_guard() {
+			Ok(guard) => guard,
+			Err(_) => {
+				log_debug!(self.logger, "Disconnecting {} as we're a standby", log_pubkey!(counterparty_node_id));
+				self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::HandleError {
+					node_id: *counterparty_node_id,
+					action: msgs::ErrorAction::DisconnectPeer { msg: None },
+				});
+				return;
+			},
+		};
 		log_debug!(self.logger, "Generating channel_reestablish events for {}", log_pubkey!(counterparty_node_id));
 
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
-
 		{
 			let mut peer_state_lock = self.per_peer_state.write().unwrap();
 			match peer_state_lock.entry(counterparty_node_id.clone()) {
@@ -6201,10 +9636,12 @@ impl<Signer: Sign, M: Deref , T: Deref , K: Deref , F: Deref , L: Deref >
 			} else { true }
 		});
 		//TODO: Also re-broadcast announcement_signatures
+		mem::drop(channel_state_lock);
+		self.enforce_event_queue_limits();
 	}
 
 	fn handle_error(&self, counterparty_node_id: &PublicKey, msg: &msgs::ErrorMessage) {
-		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
+		let _persistence_guard = match self.active_persistence_guard() { Ok(guard) => guard, Err(_) => return };
 
 		if msg.channel_id == [0; 32] {
 			for chan in self.list_channels() {
@@ -6308,8 +9745,8 @@ impl PersistenceNotifier {
 	}
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+pub(crate) const SERIALIZATION_VERSION: u8 = 1;
+pub(crate) const MIN_SERIALIZATION_VERSION: u8 = 1;
 
 impl_writeable_tlv_based!(CounterpartyForwardingInfo, {
 	(2, fee_base_msat, required),
@@ -6352,18 +9789,24 @@ impl_writeable_tlv_based!(ChannelDetails, {
 	(32, is_public, required),
 	(33, inbound_htlc_minimum_msat, option),
 	(35, inbound_htlc_maximum_msat, option),
+	(37, is_on_probation, (default_value, false)),
+	(39, is_shutting_down, (default_value, false)),
 });
 
 impl_writeable_tlv_based!(PhantomRouteHints, {
 	(2, channels, vec_type),
 	(4, phantom_scid, required),
 	(6, real_node_pubkey, required),
+	(7, min_cltv_expiry_delta, (default_value, MIN_CLTV_EXPIRY_DELTA)),
+	(9, min_final_cltv_expiry, (default_value, MIN_FINAL_CLTV_EXPIRY)),
 });
 
 impl_writeable_tlv_based_enum!(PendingHTLCRouting,
 	(0, Forward) => {
 		(0, onion_packet, required),
+		(1, blinded, option),
 		(2, short_channel_id, required),
+		(3, custom_tlvs, vec_type),
 	},
 	(1, Receive) => {
 		(0, payment_data, required),
@@ -6372,15 +9815,40 @@ impl_writeable_tlv_based_enum!(PendingHTLCRouting,
 	},
 	(2, ReceiveKeysend) => {
 		(0, payment_preimage, required),
+		(1, custom_tlvs, vec_type),
+		(2, incoming_cltv_expiry, required),
+	},
+	(3, ReceiveTrampoline) => {
+		(0, payment_data, required),
 		(2, incoming_cltv_expiry, required),
+		(4, forward_info, required),
 	},
 ;);
 
+impl_writeable_tlv_based!(BlindedForward, {
+	(0, next_blinding_point, required),
+	(2, failure, required),
+});
+
+impl_writeable_tlv_based_enum!(BlindedFailure,
+	(0, FromIntroductionNode) => {},
+	(2, FromBlindedNode) => {},
+;);
+
+impl_writeable_tlv_based!(TrampolineForwardInfo, {
+	(0, next_node_id, required),
+	(2, amt_to_forward, required),
+	(4, outgoing_cltv_value, required),
+	(6, payment_data, option),
+	(8, next_packet, (option: LengthReadable)),
+});
+
 impl_writeable_tlv_based!(PendingHTLCInfo, {
 	(0, routing, required),
 	(2, incoming_shared_secret, required),
 	(4, payment_hash, required),
 	(6, amt_to_forward, required),
+	(7, incoming_amt_msat, option),
 	(8, outgoing_cltv_value, required)
 });
 
@@ -6461,6 +9929,7 @@ impl_writeable_tlv_based!(HTLCPreviousHopData, {
 	(0, short_channel_id, required),
 	(1, phantom_shared_secret, option),
 	(2, outpoint, required),
+	(3, blinded_failure, option),
 	(4, htlc_id, required),
 	(6, incoming_packet_shared_secret, required)
 });
@@ -6530,6 +9999,14 @@ impl Readable for ClaimableHTLC {
 	}
 }
 
+impl_writeable_tlv_based!(PendingTrampolineForward, {
+	(0, payment_hash, required),
+	(2, htlcs, vec_type),
+	(4, forward_info, required),
+	(5, outbound_payment_id, option),
+	(7, outbound_amount_msat, option),
+});
+
 impl Readable for HTLCSource {
 	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
 		let id: u8 = Readable::read(reader)?;
@@ -6614,6 +10091,11 @@ impl_writeable_tlv_based_enum!(HTLCForwardInfo,
 		(0, htlc_id, required),
 		(2, err_packet, required),
 	},
+	(2, FailMalformedHTLC) => {
+		(0, htlc_id, required),
+		(2, failure_code, required),
+		(4, sha256_of_onion, required),
+	},
 ;);
 
 impl_writeable_tlv_based!(PendingInboundPayment, {
@@ -6640,6 +10122,7 @@ impl_writeable_tlv_based_enum_upgradable!(PendingOutboundPayment,
 		(6, total_msat, required),
 		(8, pending_amt_msat, required),
 		(10, starting_block_height, required),
+		(11, max_total_routing_fee_msat, option),
 	},
 	(3, Abandoned) => {
 		(0, session_privs, required),
@@ -6656,6 +10139,10 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> Writeable f
 {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
 		let _consistency_lock = self.total_consistency_lock.write().unwrap();
+		if self.standby.load(Ordering::Acquire) {
+			// Our state may be stale, and must never overwrite that of the active instance.
+			return Err(io::Error::new(io::ErrorKind::PermissionDenied, "A standby ChannelManager may not be persisted"));
+		}
 
 		write_ver_prefix!(writer, SERIALIZATION_VERSION, MIN_SERIALIZATION_VERSION);
 
@@ -6680,12 +10167,24 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> Writeable f
 			}
 		}
 
-		(channel_state.forward_htlcs.len() as u64).write(writer)?;
-		for (short_channel_id, pending_forwards) in channel_state.forward_htlcs.iter() {
-			short_channel_id.write(writer)?;
-			(pending_forwards.len() as u64).write(writer)?;
-			for forward in pending_forwards {
-				forward.write(writer)?;
+		// Held forwards are written as pending forwards, to be passed to the forward policy handler
+		// again after reloading.
+		{
+			let held_forwards = self.held_forwards.lock().unwrap();
+			let mut forward_htlcs: HashMap<u64, Vec<&HTLCForwardInfo>> = HashMap::new();
+			for (short_channel_id, pending_forwards) in channel_state.forward_htlcs.iter() {
+				forward_htlcs.entry(*short_channel_id).or_insert_with(Vec::new).extend(pending_forwards.iter());
+			}
+			for held_forward in held_forwards.held.values().chain(held_forwards.decided.iter().map(|(held_forward, _)| held_forward)) {
+				forward_htlcs.entry(held_forward.short_channel_id).or_insert_with(Vec::new).push(&held_forward.forward_info);
+			}
+			(forward_htlcs.len() as u64).write(writer)?;
+			for (short_channel_id, pending_forwards) in forward_htlcs.iter() {
+				short_channel_id.write(writer)?;
+				(pending_forwards.len() as u64).write(writer)?;
+				for forward in pending_forwards {
+					forward.write(writer)?;
+				}
 			}
 		}
 
@@ -6769,6 +10268,15 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> Writeable f
 				_ => {},
 			}
 		}
+		let node_extension_data = self.node_extension_data.lock().unwrap();
+		let node_extension_data = if node_extension_data.is_empty() { None } else { Some(&*node_extension_data) };
+		let payment_abandonment_records: Vec<PaymentAbandonmentRecord> =
+			self.payment_abandonment_records.lock().unwrap().values().cloned().collect();
+		let inbound_payment_limits = self.inbound_payment_limits.lock().unwrap();
+		let inbound_payment_limits: Vec<&RegisteredInboundPaymentLimits> = inbound_payment_limits.values().collect();
+		let trampoline_forwards: Vec<&PendingTrampolineForward> = channel_state.trampoline_forwards.values().collect();
+		let gossip_refresh = self.gossip_refresh.lock().unwrap();
+		let channel_update_refresh_times: Vec<(&[u8; 32], &u32)> = gossip_refresh.channel_update_refresh_times.iter().collect();
 		write_tlv_fields!(writer, {
 			(1, pending_outbound_payments_no_retry, required),
 			(3, pending_outbound_payments, required),
@@ -6776,12 +10284,61 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> Writeable f
 			(7, self.fake_scid_rand_bytes, required),
 			(9, htlc_purposes, vec_type),
 			(11, self.probing_cookie_secret, required),
+			(13, node_extension_data, option),
+			(15, payment_abandonment_records, vec_type),
+			(17, inbound_payment_limits, vec_type),
+			(19, trampoline_forwards, vec_type),
+			(21, channel_update_refresh_times, vec_type),
+			(23, gossip_refresh.node_announcement, option),
 		});
 
 		Ok(())
 	}
 }
 
+/// Ensures we track the outbound payments with HTLCs pending in the given [`ChannelMonitor`],
+/// whose channel we have no authoritative [`Channel`] state for, to avoid "forgetting" payments
+/// which are still in-flight via their on-chain state. Fails if an HTLC has an empty path.
+fn track_monitor_pending_outbound_htlcs<Signer: Sign, L: Deref>(
+	pending_outbound_payments: &mut HashMap<PaymentId, PendingOutboundPayment>, monitor: &ChannelMonitor<Signer>,
+	best_block_height: u32, logger: &L
+) -> Result<(), ()> where L::Target: Logger {
+	for (htlc_source, htlc) in monitor.get_pending_outbound_htlcs() {
+		if let HTLCSource::OutboundRoute { payment_id, session_priv, path, payment_secret, .. } = htlc_source {
+			if path.is_empty() {
+				log_error!(logger, "Got an empty path for a pending payment");
+				return Err(());
+			}
+			let path_amt = path.last().unwrap().fee_msat;
+			let mut session_priv_bytes = [0; 32];
+			session_priv_bytes[..].copy_from_slice(&session_priv[..]);
+			match pending_outbound_payments.entry(payment_id) {
+				hash_map::Entry::Occupied(mut entry) => {
+					let newly_added = entry.get_mut().insert(session_priv_bytes, &path);
+					log_info!(logger, "{} a pending payment path for {} msat for session priv {} on an existing pending payment with payment hash {}",
+						if newly_added { "Added" } else { "Had" }, path_amt, log_bytes!(session_priv_bytes), log_bytes!(htlc.payment_hash.0));
+				},
+				hash_map::Entry::Vacant(entry) => {
+					let path_fee = path.get_path_fees();
+					entry.insert(PendingOutboundPayment::Retryable {
+						session_privs: [session_priv_bytes].iter().map(|a| *a).collect(),
+						payment_hash: htlc.payment_hash,
+						payment_secret,
+						pending_amt_msat: path_amt,
+						pending_fee_msat: Some(path_fee),
+						total_msat: path_amt,
+						starting_block_height: best_block_height,
+						max_total_routing_fee_msat: None,
+					});
+					log_info!(logger, "Added a pending payment for {} msat with payment hash {} for path with session priv {}",
+						path_amt, log_bytes!(htlc.payment_hash.0),  log_bytes!(session_priv_bytes));
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
 /// Arguments for the creation of a ChannelManager that are not deserialized.
 ///
 /// At a high-level, the process for deserializing a ChannelManager and resuming normal operation
@@ -6861,6 +10418,13 @@ pub struct ChannelManagerReadArgs<'a, Signer: 'a + Sign, M: Deref, T: Deref, K:
 	///
 	/// (C-not exported) because we have no HashMap bindings
 	pub channel_monitors: HashMap<OutPoint, &'a mut ChannelMonitor<Signer>>,
+
+	/// The role of the deserialized ChannelManager, see [`NodeRole`].
+	///
+	/// A [`NodeRole::Standby`] is deserialized read-only: its channels are not checked against
+	/// `channel_monitors`, which may thus be empty, and nothing is broadcast or closed. Instead, the
+	/// channels are checked against the monitors passed to [`ChannelManager::promote`].
+	pub role: NodeRole,
 }
 
 impl<'a, Signer: 'a + Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
@@ -6878,7 +10442,8 @@ impl<'a, Signer: 'a + Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			mut channel_monitors: Vec<&'a mut ChannelMonitor<Signer>>) -> Self {
 		Self {
 			keys_manager, fee_estimator, chain_monitor, tx_broadcaster, logger, default_config,
-			channel_monitors: channel_monitors.drain(..).map(|monitor| { (monitor.get_funding_txo().0, monitor) }).collect()
+			channel_monitors: channel_monitors.drain(..).map(|monitor| { (monitor.get_funding_txo().0, monitor) }).collect(),
+			role: NodeRole::Active,
 		}
 	}
 }
@@ -6915,6 +10480,12 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		let best_block_hash: BlockHash = Readable::read(reader)?;
 
 		let mut failed_htlcs = Vec::new();
+		let standby = args.role == NodeRole::Standby;
+		if standby {
+			// A standby doesn't act on any ChannelMonitors until it's promoted, at which point its
+			// channels are checked against the monitors it's given instead.
+			args.channel_monitors.clear();
+		}
 
 		let channel_count: u64 = Readable::read(reader)?;
 		let mut funding_txo_set = HashSet::with_capacity(cmp::min(channel_count as usize, 128));
@@ -6926,7 +10497,16 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			let mut channel: Channel<Signer> = Channel::read(reader, (&args.keys_manager, best_block_height))?;
 			let funding_txo = channel.get_funding_txo().ok_or(DecodeError::InvalidValue)?;
 			funding_txo_set.insert(funding_txo.clone());
-			if let Some(ref mut monitor) = args.channel_monitors.get_mut(&funding_txo) {
+			if standby {
+				log_info!(args.logger, "Loaded channel {} as a standby", log_bytes!(channel.channel_id()));
+				if let Some(short_channel_id) = channel.get_short_channel_id() {
+					short_to_chan_info.insert(short_channel_id, (channel.get_counterparty_node_id(), channel.channel_id()));
+				}
+				if channel.is_funding_initiated() {
+					id_to_peer.insert(channel.channel_id(), channel.get_counterparty_node_id());
+				}
+				by_id.insert(channel.channel_id(), channel);
+			} else if let Some(ref mut monitor) = args.channel_monitors.get_mut(&funding_txo) {
 				if channel.get_cur_holder_commitment_transaction_number() < monitor.get_cur_holder_commitment_number() ||
 						channel.get_revoked_counterparty_commitment_transaction_number() < monitor.get_min_seen_secret() ||
 						channel.get_cur_counterparty_commitment_transaction_number() < monitor.get_cur_counterparty_commitment_number() ||
@@ -7078,6 +10658,12 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		let mut fake_scid_rand_bytes: Option<[u8; 32]> = None;
 		let mut probing_cookie_secret: Option<[u8; 32]> = None;
 		let mut claimable_htlc_purposes = None;
+		let mut node_extension_data: Option<ExtensionData> = None;
+		let mut payment_abandonment_records: Option<Vec<PaymentAbandonmentRecord>> = Some(Vec::new());
+		let mut inbound_payment_limits: Option<Vec<RegisteredInboundPaymentLimits>> = Some(Vec::new());
+		let mut trampoline_forwards: Option<Vec<PendingTrampolineForward>> = Some(Vec::new());
+		let mut channel_update_refresh_times: Option<Vec<([u8; 32], u32)>> = Some(Vec::new());
+		let mut node_announcement: Option<NodeAnnouncementInfo> = None;
 		read_tlv_fields!(reader, {
 			(1, pending_outbound_payments_no_retry, option),
 			(3, pending_outbound_payments, option),
@@ -7085,6 +10671,12 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			(7, fake_scid_rand_bytes, option),
 			(9, claimable_htlc_purposes, vec_type),
 			(11, probing_cookie_secret, option),
+			(13, node_extension_data, option),
+			(15, payment_abandonment_records, vec_type),
+			(17, inbound_payment_limits, vec_type),
+			(19, trampoline_forwards, vec_type),
+			(21, channel_update_refresh_times, vec_type),
+			(23, node_announcement, option),
 		});
 		if fake_scid_rand_bytes.is_none() {
 			fake_scid_rand_bytes = Some(args.keys_manager.get_secure_random_bytes());
@@ -7113,38 +10705,8 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			// 0.0.102+
 			for (_, monitor) in args.channel_monitors.iter() {
 				if by_id.get(&monitor.get_funding_txo().0.to_channel_id()).is_none() {
-					for (htlc_source, htlc) in monitor.get_pending_outbound_htlcs() {
-						if let HTLCSource::OutboundRoute { payment_id, session_priv, path, payment_secret, .. } = htlc_source {
-							if path.is_empty() {
-								log_error!(args.logger, "Got an empty path for a pending payment");
-								return Err(DecodeError::InvalidValue);
-							}
-							let path_amt = path.last().unwrap().fee_msat;
-							let mut session_priv_bytes = [0; 32];
-							session_priv_bytes[..].copy_from_slice(&session_priv[..]);
-							match pending_outbound_payments.as_mut().unwrap().entry(payment_id) {
-								hash_map::Entry::Occupied(mut entry) => {
-									let newly_added = entry.get_mut().insert(session_priv_bytes, &path);
-									log_info!(args.logger, "{} a pending payment path for {} msat for session priv {} on an existing pending payment with payment hash {}",
-										if newly_added { "Added" } else { "Had" }, path_amt, log_bytes!(session_priv_bytes), log_bytes!(htlc.payment_hash.0));
-								},
-								hash_map::Entry::Vacant(entry) => {
-									let path_fee = path.get_path_fees();
-									entry.insert(PendingOutboundPayment::Retryable {
-										session_privs: [session_priv_bytes].iter().map(|a| *a).collect(),
-										payment_hash: htlc.payment_hash,
-										payment_secret,
-										pending_amt_msat: path_amt,
-										pending_fee_msat: Some(path_fee),
-										total_msat: path_amt,
-										starting_block_height: best_block_height,
-									});
-									log_info!(args.logger, "Added a pending payment for {} msat with payment hash {} for path with session priv {}",
-										path_amt, log_bytes!(htlc.payment_hash.0),  log_bytes!(session_priv_bytes));
-								}
-							}
-						}
-					}
+					track_monitor_pending_outbound_htlcs(pending_outbound_payments.as_mut().unwrap(),
+						monitor, best_block_height, &args.logger).map_err(|()| DecodeError::InvalidValue)?;
 				}
 			}
 		}
@@ -7173,7 +10735,7 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 							events::PaymentPurpose::InvoicePayment {
 								payment_preimage: match pending_inbound_payments.get(&payment_hash) {
 									Some(inbound_payment) => inbound_payment.payment_preimage,
-									None => match inbound_payment::verify(payment_hash, &hop_data, 0, &expanded_inbound_key, &args.logger) {
+									None => match inbound_payment::verify(payment_hash, hop_data, 0, &expanded_inbound_key, &OverpaymentPolicy::Any, &args.logger) {
 										Ok(payment_preimage) => payment_preimage,
 										Err(()) => {
 											log_error!(args.logger, "Failed to read claimable payment data for HTLC with payment hash {} - was not a pending inbound payment and didn't match our payment key", log_bytes!(payment_hash.0));
@@ -7279,6 +10841,8 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			}
 		}
 
+		let mut default_config = args.default_config;
+		default_config.cltv_policy = default_config.cltv_policy.sanitized();
 		let channel_manager = ChannelManager {
 			genesis_hash,
 			fee_estimator: bounded_fee_estimator,
@@ -7292,10 +10856,15 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				short_to_chan_info,
 				forward_htlcs,
 				claimable_htlcs,
+				trampoline_forwards: trampoline_forwards.unwrap().drain(..)
+					.map(|forward| (forward.payment_hash, forward)).collect(),
 				pending_msg_events: Vec::new(),
 			}),
 			inbound_payment_key: expanded_inbound_key,
 			pending_inbound_payments: Mutex::new(pending_inbound_payments),
+			inbound_payment_limits: Mutex::new(inbound_payment_limits.unwrap().drain(..)
+				.map(|limits| (limits.payment_hash, limits)).collect()),
+			funding_intents: Mutex::new(HashMap::new()),
 			pending_outbound_payments: Mutex::new(pending_outbound_payments.unwrap()),
 
 			outbound_scid_aliases: Mutex::new(outbound_scid_aliases),
@@ -7310,6 +10879,27 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 			last_node_announcement_serial: AtomicUsize::new(last_node_announcement_serial as usize),
 			highest_seen_timestamp: AtomicUsize::new(highest_seen_timestamp as usize),
+			msg_event_queue_warned: AtomicBool::new(false),
+			event_queue_warned: AtomicBool::new(false),
+			channels_frozen: AtomicBool::new(false),
+			standby: AtomicBool::new(standby),
+			standby_held_events: Mutex::new(if standby { mem::take(&mut pending_events_read) } else { Vec::new() }),
+			inbound_volume: Mutex::new(InboundVolumeWindow::new()),
+			peer_config_overrides: Mutex::new(HashMap::new()),
+			payment_abandonment_records: Mutex::new(payment_abandonment_records.unwrap().drain(..)
+				.map(|record| (record.payment_id, record)).collect()),
+			extension_registry: Mutex::new(ExtensionRegistry::new()),
+			node_extension_data: Mutex::new(node_extension_data.unwrap_or_default()),
+			gossip_refresh: Mutex::new(GossipRefreshState {
+				channel_update_refresh_times: channel_update_refresh_times.unwrap().drain(..).collect(),
+				node_announcement,
+			}),
+			rejected_forwards: Mutex::new(VecDeque::new()),
+			force_close_decision_handler: Mutex::new(None),
+			forward_policy_handler: Mutex::new(None),
+			held_forwards: Mutex::new(HeldForwards::default()),
+			timer_schedule: Mutex::new(TimerSchedule::new()),
+			backgrounded_at: Mutex::new(None),
 
 			per_peer_state: RwLock::new(per_peer_state),
 
@@ -7320,7 +10910,7 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 			keys_manager: args.keys_manager,
 			logger: args.logger,
-			default_configuration: args.default_config,
+			default_configuration: RwLock::new(default_config),
 		};
 
 		for htlc_source in failed_htlcs.drain(..) {
@@ -7336,6 +10926,232 @@ impl<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	}
 }
 
+/// A [`chain::Watch`] used by [`ChannelManagerObserver`], which refuses to watch anything.
+struct ObserverChainWatch;
+impl<Signer: Sign> chain::Watch<Signer> for ObserverChainWatch {
+	fn watch_channel(&self, _funding_txo: OutPoint, _monitor: ChannelMonitor<Signer>) -> Result<(), ChannelMonitorUpdateErr> {
+		Err(ChannelMonitorUpdateErr::PermanentFailure)
+	}
+	fn update_channel(&self, _funding_txo: OutPoint, _update: ChannelMonitorUpdate) -> Result<(), ChannelMonitorUpdateErr> {
+		Err(ChannelMonitorUpdateErr::PermanentFailure)
+	}
+	fn release_pending_monitor_events(&self) -> Vec<(OutPoint, Vec<MonitorEvent>, Option<PublicKey>)> {
+		Vec::new()
+	}
+}
+
+/// A [`BroadcasterInterface`] used by [`ChannelManagerObserver`], which records transactions
+/// instead of broadcasting them.
+struct ObserverBroadcaster {
+	withheld_transactions: Mutex<Vec<Transaction>>,
+}
+impl BroadcasterInterface for ObserverBroadcaster {
+	fn broadcast_transaction(&self, tx: &Transaction) {
+		self.withheld_transactions.lock().unwrap().push(tx.clone());
+	}
+}
+
+/// A [`FeeEstimator`] used by [`ChannelManagerObserver`], which always returns the minimum
+/// feerate.
+struct ObserverFeeEstimator;
+impl FeeEstimator for ObserverFeeEstimator {
+	fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u32 {
+		FEERATE_FLOOR_SATS_PER_KW
+	}
+}
+
+/// An HTLC pending in one of our channels, as seen by a [`ChannelManagerObserver`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObservedHTLC {
+	/// The ID of the channel the HTLC is in.
+	pub channel_id: [u8; 32],
+	/// Whether the HTLC was offered to us by our counterparty, rather than by us to them.
+	pub inbound: bool,
+	/// The ID of the HTLC within the channel.
+	pub htlc_id: u64,
+	/// The payment hash of the HTLC.
+	pub payment_hash: PaymentHash,
+	/// The value of the HTLC.
+	pub amount_msat: u64,
+	/// The block height at which the HTLC expires.
+	pub cltv_expiry: u32,
+}
+
+/// An inbound payment which has been received but not yet claimed, as seen by a
+/// [`ChannelManagerObserver`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObservedClaimablePayment {
+	/// The payment hash of the payment.
+	pub payment_hash: PaymentHash,
+	/// The total value of the payment, as indicated by the sender.
+	pub total_msat: u64,
+	/// The sum of the values of the parts we've received.
+	pub received_msat: u64,
+	/// The number of parts we've received.
+	pub parts: usize,
+}
+
+/// The state of an outbound payment, as seen by a [`ChannelManagerObserver`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObservedPaymentState {
+	/// The payment has parts which are still pending and may be retried.
+	Pending,
+	/// The payment was fulfilled, though some parts may still be pending resolution.
+	Fulfilled,
+	/// The payment was abandoned, though some parts may still be pending resolution.
+	Abandoned,
+}
+
+/// An outbound payment which is still being tracked, as seen by a [`ChannelManagerObserver`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObservedOutboundPayment {
+	/// The ID of the payment.
+	pub payment_id: PaymentId,
+	/// The payment hash of the payment. This is `None` for pending payments which were sent prior
+	/// to LDK 0.0.102 and for some fulfilled payments.
+	pub payment_hash: Option<PaymentHash>,
+	/// The state of the payment.
+	pub state: ObservedPaymentState,
+	/// The total value of the payment, if known.
+	pub total_msat: Option<u64>,
+	/// The number of parts of the payment which are still pending.
+	pub pending_parts: usize,
+}
+
+/// Arguments for deserializing a [`ChannelManagerObserver`]. See [`ChannelManagerReadArgs`],
+/// which these are a subset of.
+pub struct ChannelManagerObserverReadArgs<'a, Signer: 'a + Sign, K: Deref, L: Deref>
+	where K::Target: KeysInterface<Signer = Signer>,
+		L::Target: Logger,
+{
+	/// The keys provider which was used to create the serialized [`ChannelManager`], used to
+	/// deserialize each channel's signer state and determine our node id.
+	///
+	/// Note that no signing operations are performed with the deserialized signers.
+	pub keys_manager: K,
+	/// The logger to which any errors encountered during deserialization are logged.
+	pub logger: L,
+	/// The [`ChannelMonitor`]s of the [`ChannelManager`]'s channels, as required by
+	/// [`ChannelManagerReadArgs::channel_monitors`].
+	///
+	/// These should be copies deserialized solely for inspection, as they may be updated during
+	/// deserialization and must not be persisted afterwards.
+	///
+	/// (C-not exported) because we have no HashMap bindings
+	pub channel_monitors: HashMap<OutPoint, &'a mut ChannelMonitor<Signer>>,
+}
+
+/// A read-only view of a serialized [`ChannelManager`], allowing its channels, pending HTLCs and
+/// payments to be inspected, e.g. to verify a backup or to debug a production node, without any
+/// risk of acting on them.
+///
+/// The [`ChannelManager`] is deserialized as usual, but with chain and broadcast interfaces which
+/// do nothing, and none of its methods which send messages, sign or update channels are exposed.
+/// Any transactions which deserialization would have broadcast, e.g. because a
+/// [`ChannelMonitor`] is ahead of the [`ChannelManager`], are instead available via
+/// [`ChannelManagerObserver::withheld_transactions`].
+pub struct ChannelManagerObserver<Signer: Sign, K: Deref, L: Deref>
+	where K::Target: KeysInterface<Signer = Signer>,
+		L::Target: Logger,
+{
+	best_block_hash: BlockHash,
+	broadcaster: Arc<ObserverBroadcaster>,
+	channel_manager: ChannelManager<Signer, Arc<ObserverChainWatch>, Arc<ObserverBroadcaster>, K, Arc<ObserverFeeEstimator>, L>,
+}
+
+impl<Signer: Sign, K: Deref, L: Deref> ChannelManagerObserver<Signer, K, L>
+	where K::Target: KeysInterface<Signer = Signer>,
+		L::Target: Logger,
+{
+	/// Gets the hash and height of the best block as of the snapshot.
+	pub fn best_block(&self) -> (BlockHash, u32) {
+		(self.best_block_hash, self.channel_manager.best_block.read().unwrap().height())
+	}
+
+	/// Gets our node id.
+	pub fn get_our_node_id(&self) -> PublicKey {
+		self.channel_manager.get_our_node_id()
+	}
+
+	/// Gets the details of all channels in the snapshot. See [`ChannelManager::list_channels`].
+	///
+	/// Note that, as no peers are connected to the observer, all channels are reported as not
+	/// usable.
+	pub fn list_channels(&self) -> Vec<ChannelDetails> {
+		self.channel_manager.list_channels()
+	}
+
+	/// Gets all HTLCs pending in the snapshot's channels.
+	pub fn list_pending_htlcs(&self) -> Vec<ObservedHTLC> {
+		let channel_state = self.channel_manager.channel_state.lock().unwrap();
+		let mut htlcs = Vec::new();
+		for chan in channel_state.by_id.values() {
+			htlcs.append(&mut chan.get_observed_htlcs());
+		}
+		htlcs
+	}
+
+	/// Gets all inbound payments which have been received but not yet claimed.
+	pub fn list_claimable_payments(&self) -> Vec<ObservedClaimablePayment> {
+		let channel_state = self.channel_manager.channel_state.lock().unwrap();
+		channel_state.claimable_htlcs.iter().map(|(payment_hash, (_, htlcs))| ObservedClaimablePayment {
+			payment_hash: *payment_hash,
+			total_msat: htlcs.first().map(|htlc| htlc.total_msat).unwrap_or(0),
+			received_msat: htlcs.iter().map(|htlc| htlc.value).sum(),
+			parts: htlcs.len(),
+		}).collect()
+	}
+
+	/// Gets all outbound payments which are still being tracked.
+	pub fn list_outbound_payments(&self) -> Vec<ObservedOutboundPayment> {
+		let outbounds = self.channel_manager.pending_outbound_payments.lock().unwrap();
+		outbounds.iter().map(|(payment_id, payment)| {
+			let (payment_hash, state, total_msat) = match payment {
+				PendingOutboundPayment::Legacy { .. } => (None, ObservedPaymentState::Pending, None),
+				PendingOutboundPayment::Retryable { payment_hash, total_msat, .. } =>
+					(Some(*payment_hash), ObservedPaymentState::Pending, Some(*total_msat)),
+				PendingOutboundPayment::Fulfilled { payment_hash, .. } =>
+					(*payment_hash, ObservedPaymentState::Fulfilled, None),
+				PendingOutboundPayment::Abandoned { payment_hash, .. } =>
+					(Some(*payment_hash), ObservedPaymentState::Abandoned, None),
+			};
+			ObservedOutboundPayment {
+				payment_id: *payment_id, payment_hash, state, total_msat, pending_parts: payment.remaining_parts(),
+			}
+		}).collect()
+	}
+
+	/// Gets the transactions which deserializing the snapshot would have broadcast.
+	///
+	/// A non-empty list indicates that the [`ChannelManager`] was stale compared to its
+	/// [`ChannelMonitor`]s, or that some [`ChannelMonitor`]s were missing.
+	pub fn withheld_transactions(&self) -> Vec<Transaction> {
+		self.broadcaster.withheld_transactions.lock().unwrap().clone()
+	}
+}
+
+impl<'a, Signer: Sign, K: Deref, L: Deref>
+	ReadableArgs<ChannelManagerObserverReadArgs<'a, Signer, K, L>> for ChannelManagerObserver<Signer, K, L>
+	where K::Target: KeysInterface<Signer = Signer>,
+		L::Target: Logger,
+{
+	fn read<R: io::Read>(reader: &mut R, args: ChannelManagerObserverReadArgs<'a, Signer, K, L>) -> Result<Self, DecodeError> {
+		let broadcaster = Arc::new(ObserverBroadcaster { withheld_transactions: Mutex::new(Vec::new()) });
+		let read_args = ChannelManagerReadArgs {
+			keys_manager: args.keys_manager,
+			fee_estimator: Arc::new(ObserverFeeEstimator),
+			chain_monitor: Arc::new(ObserverChainWatch),
+			tx_broadcaster: Arc::clone(&broadcaster),
+			logger: args.logger,
+			default_config: UserConfig::default(),
+			channel_monitors: args.channel_monitors,
+			role: NodeRole::Active,
+		};
+		let (best_block_hash, channel_manager) = <(BlockHash, ChannelManager<_, _, _, _, _, _>)>::read(reader, read_args)?;
+		Ok(ChannelManagerObserver { best_block_hash, broadcaster, channel_manager })
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use bitcoin::hashes::Hash;
@@ -7343,13 +11159,15 @@ mod tests {
 	use core::time::Duration;
 	use core::sync::atomic::Ordering;
 	use ln::{PaymentPreimage, PaymentHash, PaymentSecret};
-	use ln::channelmanager::{PaymentId, PaymentSendFailure};
+	use ln::channelmanager::{PaymentId, PaymentSendFailure, MIN_FINAL_CLTV_EXPIRY};
 	use ln::channelmanager::inbound_payment;
-	use ln::features::InitFeatures;
+	use ln::features::{InitFeatures, InvoiceFeatures};
 	use ln::functional_test_utils::*;
 	use ln::msgs;
 	use ln::msgs::ChannelMessageHandler;
+	use ln::onion_utils;
 	use routing::router::{PaymentParameters, RouteParameters, find_route};
+	use util::config::{CltvPolicy, OverpaymentPolicy};
 	use util::errors::APIError;
 	use util::events::{Event, HTLCDestination, MessageSendEvent, MessageSendEventsProvider, ClosureReason};
 	use util::test_utils;
@@ -7784,6 +11602,63 @@ mod tests {
 		nodes[1].logger.assert_log_contains("lightning::ln::channelmanager".to_string(), "We don't support MPP keysend payments".to_string(), 1);
 	}
 
+	#[test]
+	fn test_keysend_custom_tlvs() {
+		// Test that custom TLVs in the onion of a keysend payment are provided in the
+		// `PaymentReceived` event, and that such payments are failed if we've set
+		// `UserConfig::accept_keysend_custom_tlvs` to false.
+		let chanmon_cfgs = create_chanmon_cfgs(3);
+		let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+		let mut reject_config = test_default_channel_config();
+		reject_config.accept_keysend_custom_tlvs = false;
+		let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, Some(reject_config)]);
+		let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		create_announced_chan_between_nodes(&nodes, 0, 2, InitFeatures::known(), InitFeatures::known());
+
+		let custom_tlvs = vec![(65537, vec![42; 4]), (7629169, b"podcast".to_vec())];
+		let send_keysend = |dest: &Node, payment_preimage: PaymentPreimage| {
+			let (route, _, _, _) = get_route_and_payment_hash!(nodes[0], dest, 10_000);
+			let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
+			let cur_height = nodes[0].best_block_info().1 + 1;
+			let (mut onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 10_000, &None, cur_height, &Some(payment_preimage)).unwrap();
+			if let msgs::OnionHopDataFormat::FinalNode { custom_tlvs: ref mut tlvs, .. } = onion_payloads.last_mut().unwrap().format {
+				*tlvs = custom_tlvs.clone();
+			} else { panic!(); }
+			nodes[0].node.send_onion_along_path(&route.paths[0], &route.payment_params, &payment_hash, &None, 10_000, PaymentId(payment_hash.0), onion_payloads, htlc_msat, htlc_cltv).unwrap();
+			check_added_monitors!(nodes[0], 1);
+			payment_hash
+		};
+
+		let payment_preimage = PaymentPreimage([42; 32]);
+		let payment_hash = send_keysend(&nodes[1], payment_preimage);
+		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		do_pass_along_path(&nodes[0], &[&nodes[1]], 10_000, payment_hash, None, events.remove(0), true, false, Some(payment_preimage));
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentReceived { payment_hash: ref received_hash, custom_tlvs: ref received_tlvs, .. } => {
+				assert_eq!(*received_hash, payment_hash);
+				assert_eq!(*received_tlvs, custom_tlvs);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+		let payment_preimage = PaymentPreimage([43; 32]);
+		let payment_hash = send_keysend(&nodes[2], payment_preimage);
+		let updates = get_htlc_update_msgs!(nodes[0], nodes[2].node.get_our_node_id());
+		nodes[2].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &updates.update_add_htlcs[0]);
+		commitment_signed_dance!(nodes[2], nodes[0], updates.commitment_signed, false, true);
+		nodes[2].logger.assert_log_contains("lightning::ln::channelmanager".to_string(), "We don't accept keysend payments with custom TLVs".to_string(), 1);
+		let updates = get_htlc_update_msgs!(nodes[2], nodes[0].node.get_our_node_id());
+		assert_eq!(updates.update_fail_htlcs.len(), 1);
+		nodes[0].node.handle_update_fail_htlc(&nodes[2].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+		commitment_signed_dance!(nodes[0], nodes[2], updates.commitment_signed, false);
+		expect_payment_failed!(nodes[0], payment_hash, true);
+	}
+
 	#[test]
 	fn test_multi_hop_missing_secret() {
 		let chanmon_cfgs = create_chanmon_cfgs(4);
@@ -7814,6 +11689,162 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_cltv_policy_sanitized_and_applied() {
+		// Check that a CltvPolicy outside of our safety bounds fails validation and is clamped by
+		// the ChannelManager, and that its forwarding delta floor is applied to our channel_updates.
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let mut config = test_default_channel_config();
+		config.cltv_policy = CltvPolicy {
+			min_cltv_expiry_delta: 200,
+			min_final_cltv_expiry: 1,
+			max_cltv_expiry_from_now: 10,
+			max_total_cltv_expiry_delta: 0,
+		};
+		assert!(config.cltv_policy.validate().is_err());
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(config)]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+		let sanitized_policy = nodes[1].node.get_current_default_configuration().cltv_policy;
+		assert_eq!(sanitized_policy, CltvPolicy {
+			min_cltv_expiry_delta: 200,
+			min_final_cltv_expiry: MIN_FINAL_CLTV_EXPIRY,
+			max_cltv_expiry_from_now: 200,
+			max_total_cltv_expiry_delta: MIN_FINAL_CLTV_EXPIRY + 1,
+		});
+		assert!(sanitized_policy.validate().is_ok());
+		assert!(CltvPolicy::default().validate().is_ok());
+
+		let (as_update, bs_update, _, _) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		assert_eq!(as_update.contents.cltv_expiry_delta, test_default_channel_config().channel_config.cltv_expiry_delta);
+		assert_eq!(bs_update.contents.cltv_expiry_delta, 200);
+	}
+
+	#[test]
+	fn test_cltv_policy_sanitized_on_reload() {
+		// Check that a CltvPolicy outside of our safety bounds is clamped when it's provided on
+		// deserialization, as it is when constructing a new ChannelManager.
+		use bitcoin::BlockHash;
+		use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs};
+		use util::enforcing_trait_impls::EnforcingSigner;
+		use util::ser::{ReadableArgs, Writeable};
+
+		let chanmon_cfgs = create_chanmon_cfgs(1);
+		let node_cfgs = create_node_cfgs(1, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(1, &node_cfgs, &[None]);
+		let nodes = create_network(1, &node_cfgs, &node_chanmgrs);
+
+		let mut config = test_default_channel_config();
+		config.cltv_policy.min_final_cltv_expiry = 1;
+		let serialized = nodes[0].node.encode();
+		let (_, reloaded) = <(BlockHash, ChannelManager<EnforcingSigner, &test_utils::TestChainMonitor, &test_utils::TestBroadcaster, &test_utils::TestKeysInterface, &test_utils::TestFeeEstimator, &test_utils::TestLogger>)>::read(
+			&mut &serialized[..], ChannelManagerReadArgs::new(node_cfgs[0].keys_manager, node_cfgs[0].fee_estimator,
+				nodes[0].chain_monitor, nodes[0].tx_broadcaster, nodes[0].logger, config, Vec::new())).unwrap();
+		let policy = reloaded.get_current_default_configuration().cltv_policy;
+		assert_eq!(policy.min_final_cltv_expiry, MIN_FINAL_CLTV_EXPIRY);
+		assert!(policy.validate().is_ok());
+	}
+
+	#[test]
+	fn test_cltv_policy_min_cltv_expiry_delta_validated() {
+		// Check that the cltv_expiry_delta of our channels is validated against our CltvPolicy's
+		// min_cltv_expiry_delta when it's above MIN_CLTV_EXPIRY_DELTA.
+		use ln::channelmanager::{UserConfigSetting, MIN_CLTV_EXPIRY_DELTA};
+		use util::config::PeerConfigOverrides;
+
+		let min_cltv_expiry_delta = MIN_CLTV_EXPIRY_DELTA + 10;
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let mut config = test_default_channel_config();
+		config.cltv_policy.min_cltv_expiry_delta = min_cltv_expiry_delta;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[Some(config), None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let chan_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+		let node_b_id = nodes[1].node.get_our_node_id();
+
+		let mut channel_config = nodes[0].node.get_current_default_configuration().channel_config;
+		channel_config.cltv_expiry_delta = min_cltv_expiry_delta - 1;
+		match nodes[0].node.update_channel_config(&node_b_id, &[chan_id], &channel_config) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			_ => panic!("Unexpected result"),
+		}
+		let overrides = PeerConfigOverrides { channel_config: Some(channel_config), ..Default::default() };
+		match nodes[0].node.set_peer_config_overrides(node_b_id, overrides) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			_ => panic!("Unexpected result"),
+		}
+		let mut user_config = nodes[0].node.get_current_default_configuration();
+		user_config.channel_config = channel_config;
+		let update = nodes[0].node.apply_user_config(user_config);
+		assert_eq!(update.rejected.iter().map(|(setting, _)| *setting).collect::<Vec<_>>(),
+			vec![UserConfigSetting::ChannelConfig]);
+		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+
+		channel_config.cltv_expiry_delta = min_cltv_expiry_delta;
+		nodes[0].node.update_channel_config(&node_b_id, &[chan_id], &channel_config).unwrap();
+		assert_eq!(nodes[0].node.get_and_clear_pending_msg_events().len(), 1);
+	}
+
+	#[test]
+	fn test_cltv_policy_min_final_cltv_expiry_enforced() {
+		// Check that payments we receive must leave us our CltvPolicy's min_final_cltv_expiry,
+		// which we advertise in our invoices, when it's above MIN_FINAL_CLTV_EXPIRY.
+		let min_final_cltv_expiry = TEST_FINAL_CLTV + 10;
+		assert!(min_final_cltv_expiry > MIN_FINAL_CLTV_EXPIRY);
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let mut config = test_default_channel_config();
+		config.cltv_policy.min_final_cltv_expiry = min_final_cltv_expiry;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(config)]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+		let (route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 100_000);
+		nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &updates.update_add_htlcs[0]);
+		commitment_signed_dance!(nodes[1], nodes[0], updates.commitment_signed, false, true);
+		nodes[1].logger.assert_log_contains("lightning::ln::channelmanager".to_string(), "The final CLTV expiry is too soon to handle".to_string(), 1);
+		let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+		assert_eq!(updates.update_fail_htlcs.len(), 1);
+		nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+		commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+		expect_payment_failed!(nodes[0], payment_hash, false, 17, []);
+
+		let payment_params = PaymentParameters::from_node_id(nodes[1].node.get_our_node_id())
+			.with_features(InvoiceFeatures::known());
+		let (route, payment_hash, payment_preimage, payment_secret) =
+			get_route_and_payment_hash!(nodes[0], nodes[1], payment_params, 100_000, min_final_cltv_expiry);
+		send_along_route_with_secret(&nodes[0], route, &[&[&nodes[1]]], 100_000, payment_hash, payment_secret);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	}
+
+	#[test]
+	fn test_min_final_cltv_expiry_tolerates_block_in_flight() {
+		// Check that a payment using exactly the default min_final_cltv_expiry we advertise is still
+		// accepted if we find a block while it's in flight.
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+		let payment_params = PaymentParameters::from_node_id(nodes[1].node.get_our_node_id())
+			.with_features(InvoiceFeatures::known());
+		let (route, payment_hash, payment_preimage, payment_secret) =
+			get_route_and_payment_hash!(nodes[0], nodes[1], payment_params, 100_000, MIN_FINAL_CLTV_EXPIRY);
+		nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+
+		connect_blocks(&nodes[1], 1);
+		pass_along_path(&nodes[0], &[&nodes[1]], 100_000, payment_hash, Some(payment_secret), events.pop().unwrap(), true, None);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	}
+
 	#[test]
 	fn bad_inbound_payment_hash() {
 		// Add coverage for checking that a user-provided payment hash matches the payment secret.
@@ -7832,7 +11863,7 @@ mod tests {
 		// payment verification fails as expected.
 		let mut bad_payment_hash = payment_hash.clone();
 		bad_payment_hash.0[0] += 1;
-		match inbound_payment::verify(bad_payment_hash, &payment_data, nodes[0].node.highest_seen_timestamp.load(Ordering::Acquire) as u64, &nodes[0].node.inbound_payment_key, &nodes[0].logger) {
+		match inbound_payment::verify(bad_payment_hash, &payment_data, nodes[0].node.highest_seen_timestamp.load(Ordering::Acquire) as u64, &nodes[0].node.inbound_payment_key, &OverpaymentPolicy::Any, &nodes[0].logger) {
 			Ok(_) => panic!("Unexpected ok"),
 			Err(()) => {
 				nodes[0].logger.assert_log_contains("lightning::ln::inbound_payment".to_string(), "Failing HTLC with user-generated payment_hash".to_string(), 1);
@@ -7840,7 +11871,7 @@ mod tests {
 		}
 
 		// Check that using the original payment hash succeeds.
-		assert!(inbound_payment::verify(payment_hash, &payment_data, nodes[0].node.highest_seen_timestamp.load(Ordering::Acquire) as u64, &nodes[0].node.inbound_payment_key, &nodes[0].logger).is_ok());
+		assert!(inbound_payment::verify(payment_hash, &payment_data, nodes[0].node.highest_seen_timestamp.load(Ordering::Acquire) as u64, &nodes[0].node.inbound_payment_key, &OverpaymentPolicy::Any, &nodes[0].logger).is_ok());
 	}
 
 	#[test]
@@ -41,6 +41,8 @@
 //!     (see [BOLT-4](https://github.com/lightning/bolts/blob/master/04-onion-routing.md#basic-multi-part-payments) for more information).
 //! - `ShutdownAnySegwit` - requires/supports that future segwit versions are allowed in `shutdown`
 //!     (see [BOLT-2](https://github.com/lightning/bolts/blob/master/02-peer-protocol.md) for more information).
+//! - `RouteBlinding` - requires/supports forwarding payments within blinded routes
+//!     (see [BOLT-4](https://github.com/lightning/bolts/blob/master/04-onion-routing.md#route-blinding) for more information).
 //! - `ChannelType` - node supports the channel_type field in open/accept
 //!     (see [BOLT-2](https://github.com/lightning/bolts/blob/master/02-peer-protocol.md) for more information).
 //! - `SCIDPrivacy` - supply channel aliases for routing
@@ -170,7 +172,7 @@ mod sealed {
 			// Byte 2
 			BasicMPP | Wumbo,
 			// Byte 3
-			ShutdownAnySegwit,
+			RouteBlinding | ShutdownAnySegwit,
 			// Byte 4
 			,
 			// Byte 5
@@ -204,7 +206,7 @@ mod sealed {
 			// Byte 2
 			BasicMPP | Wumbo,
 			// Byte 3
-			ShutdownAnySegwit,
+			RouteBlinding | ShutdownAnySegwit,
 			// Byte 4
 			,
 			// Byte 5
@@ -430,6 +432,9 @@ mod sealed {
 	define_feature!(19, Wumbo, [InitContext, NodeContext],
 		"Feature flags for `option_support_large_channel` (aka wumbo channels).", set_wumbo_optional, set_wumbo_required,
 		supports_wumbo, requires_wumbo);
+	define_feature!(25, RouteBlinding, [InitContext, NodeContext],
+		"Feature flags for `option_route_blinding`.", set_route_blinding_optional,
+		set_route_blinding_required, supports_route_blinding, requires_route_blinding);
 	define_feature!(27, ShutdownAnySegwit, [InitContext, NodeContext],
 		"Feature flags for `opt_shutdown_anysegwit`.", set_shutdown_any_segwit_optional,
 		set_shutdown_any_segwit_required, supports_shutdown_anysegwit, requires_shutdown_anysegwit);
@@ -733,6 +738,14 @@ impl<T: sealed::Context> Features<T> {
 		})
 	}
 
+	/// Returns true if either bit of the feature with the given required (even) bit is set,
+	/// whether or not we understand the feature.
+	pub(crate) fn supports_feature_bit_pair(&self, required_bit: usize) -> bool {
+		let byte_offset = required_bit / 8;
+		let mask = 0b11 << (required_bit % 8);
+		self.flags.get(byte_offset).map(|byte| (byte & mask) != 0).unwrap_or(false)
+	}
+
 	pub(crate) fn supports_unknown_bits(&self) -> bool {
 		// Bitwise AND-ing with all even and odd bits set except for known features will select
 		// both required and optional unknown features.
@@ -889,6 +902,11 @@ mod tests {
 		assert!(InitFeatures::known().supports_shutdown_anysegwit());
 		assert!(NodeFeatures::known().supports_shutdown_anysegwit());
 
+		assert!(InitFeatures::known().supports_route_blinding());
+		assert!(NodeFeatures::known().supports_route_blinding());
+		assert!(!InitFeatures::known().requires_route_blinding());
+		assert!(!NodeFeatures::known().requires_route_blinding());
+
 		assert!(InitFeatures::known().supports_scid_privacy());
 		assert!(NodeFeatures::known().supports_scid_privacy());
 		assert!(ChannelTypeFeatures::known().supports_scid_privacy());
@@ -944,7 +962,7 @@ mod tests {
 			// - option_data_loss_protect
 			// - var_onion_optin (req) | static_remote_key (req) | payment_secret(req)
 			// - basic_mpp | wumbo
-			// - opt_shutdown_anysegwit
+			// - option_route_blinding | opt_shutdown_anysegwit
 			// -
 			// - option_channel_type | option_scid_alias
 			// - option_zeroconf
@@ -952,7 +970,7 @@ mod tests {
 			assert_eq!(node_features.flags[0], 0b00000010);
 			assert_eq!(node_features.flags[1], 0b01010001);
 			assert_eq!(node_features.flags[2], 0b00001010);
-			assert_eq!(node_features.flags[3], 0b00001000);
+			assert_eq!(node_features.flags[3], 0b00001010);
 			assert_eq!(node_features.flags[4], 0b00000000);
 			assert_eq!(node_features.flags[5], 0b10100000);
 			assert_eq!(node_features.flags[6], 0b00001000);
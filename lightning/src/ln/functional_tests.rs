@@ -20,21 +20,22 @@ use chain::transaction::OutPoint;
 use chain::keysinterface::{BaseSign, KeysInterface};
 use ln::{PaymentPreimage, PaymentSecret, PaymentHash};
 use ln::channel::{commitment_tx_base_weight, COMMITMENT_TX_WEIGHT_PER_HTLC, CONCURRENT_INBOUND_HTLC_FEE_BUFFER, FEE_SPIKE_BUFFER_FEE_INCREASE_MULTIPLE, MIN_AFFORDABLE_HTLC_COUNT};
-use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, PaymentId, RAACommitmentOrder, PaymentSendFailure, BREAKDOWN_TIMEOUT, MIN_CLTV_EXPIRY_DELTA, PAYMENT_EXPIRY_BLOCKS };
+use ln::channelmanager::{ChannelManager, ChannelManagerObserver, ChannelManagerObserverReadArgs, ChannelManagerReadArgs, NodeRole, ObservedPaymentState, PaymentId, RAACommitmentOrder, PaymentSendFailure, PaymentSendFailureCause, BREAKDOWN_TIMEOUT, MIN_CLTV_EXPIRY_DELTA, PAYMENT_EXPIRY_BLOCKS, GOSSIP_REFRESH_INTERVAL_SECS, GOSSIP_REFRESH_JITTER_SECS, ForceCloseDecision, ForceCloseDecisionHandler, ForceCloseReason, ForwardPolicyDecision, ForwardPolicyHandler, ForwardRejectionReason, ForwardRequest, ConfigLimitViolation, UserConfigSetting, PeerCapabilitySupport, ReestablishOverrides };
 use ln::channel::{Channel, ChannelError};
 use ln::{chan_utils, onion_utils};
 use ln::chan_utils::{htlc_success_tx_weight, htlc_timeout_tx_weight, HTLCOutputInCommitment};
 use routing::gossip::NetworkGraph;
 use routing::router::{PaymentParameters, Route, RouteHop, RouteParameters, find_route, get_route};
-use ln::features::{ChannelFeatures, InitFeatures, InvoiceFeatures, NodeFeatures};
+use ln::features::{ChannelFeatures, ChannelTypeFeatures, InitFeatures, InvoiceFeatures, NodeFeatures};
 use ln::msgs;
 use ln::msgs::{ChannelMessageHandler, RoutingMessageHandler, ErrorAction};
 use util::enforcing_trait_impls::EnforcingSigner;
 use util::{byte_utils, test_utils};
-use util::events::{Event, MessageSendEvent, MessageSendEventsProvider, PaymentPurpose, ClosureReason, HTLCDestination};
-use util::errors::APIError;
+use util::events::{Event, MessageSendEvent, MessageSendEventsProvider, PaymentPurpose, ClosureReason, HTLCDestination, PendingEventQueue};
+use util::errors::{APIError, APIErrorCause, ErrorClassification, Retryability};
 use util::ser::{Writeable, ReadableArgs};
-use util::config::UserConfig;
+use util::config::{ClaimFeeBudget, EventQueueOverflowPolicy, PeerConfigOverrides, UserConfig};
+use util::extensions::{extension_type_for_name, MIN_EXTENSION_TYPE};
 
 use bitcoin::hash_types::BlockHash;
 use bitcoin::blockdata::block::{Block, BlockHeader};
@@ -1341,7 +1342,8 @@ fn test_basic_channel_reserve() {
 	// The 2* and +1 are for the fee spike reserve.
 	let commit_tx_fee = 2 * commit_tx_fee_msat(get_feerate!(nodes[0], chan.2), 1 + 1, get_opt_anchors!(nodes[0], chan.2));
 	let max_can_send = 5000000 - channel_reserve - commit_tx_fee;
-	let (route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], max_can_send + 1);
+	let (mut route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], max_can_send);
+	route.paths[0].last_mut().unwrap().fee_msat += 1;
 	let err = nodes[0].node.send_payment(&route, our_payment_hash, &Some(our_payment_secret)).err().unwrap();
 	match err {
 		PaymentSendFailure::AllFailedRetrySafe(ref fails) => {
@@ -1359,6 +1361,32 @@ fn test_basic_channel_reserve() {
 	send_payment(&nodes[0], &vec![&nodes[1]], max_can_send);
 }
 
+#[test]
+fn test_payment_send_failure_classification() {
+	// Check that a payment which fails to send on all paths is classified as retryable later,
+	// with the cause of the underlying path failure.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000, InitFeatures::known(), InitFeatures::known());
+
+	let (mut route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 100000);
+	route.paths[0].last_mut().unwrap().fee_msat = 5000000;
+	let err = nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).err().unwrap();
+	assert_eq!(err.error_code(), 103);
+	assert_eq!(err.retryability(), Retryability::Later);
+	assert_eq!(err.cause(), PaymentSendFailureCause::AllPathsFailed(Some(APIErrorCause::ChannelUnavailable)));
+	match err {
+		PaymentSendFailure::AllFailedRetrySafe(ref fails) => {
+			assert_eq!(fails[0].error_code(), 4);
+			assert_eq!(fails[0].retryability(), Retryability::Later);
+		},
+		_ => panic!("Unexpected error variant"),
+	}
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+}
+
 #[test]
 fn test_fee_spike_violation_fails_htlc() {
 	let chanmon_cfgs = create_chanmon_cfgs(2);
@@ -1367,7 +1395,8 @@ fn test_fee_spike_violation_fails_htlc() {
 	let mut nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000, InitFeatures::known(), InitFeatures::known());
 
-	let (route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 3460001);
+	let (mut route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 3460000);
+	route.paths[0].last_mut().unwrap().fee_msat += 1;
 	// Need to manually create the update_add_htlc message to go around the channel reserve check in send_htlc()
 	let secp_ctx = Secp256k1::new();
 	let session_priv = SecretKey::from_slice(&[42; 32]).expect("RNG is bad!");
@@ -1384,6 +1413,7 @@ fn test_fee_spike_violation_fails_htlc() {
 		payment_hash: payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &msg);
@@ -1562,6 +1592,7 @@ fn test_chan_reserve_violation_inbound_htlc_outbound_channel() {
 		payment_hash: payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[0].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &msg);
@@ -1716,7 +1747,8 @@ fn test_chan_reserve_violation_inbound_htlc_inbound_chan() {
 	let commit_tx_fee_2_htlcs = commit_tx_fee_msat(feerate, 2, opt_anchors);
 	let recv_value_2 = chan_stat.value_to_self_msat - amt_msat_1 - chan_stat.channel_reserve_msat - total_routing_fee_msat - commit_tx_fee_2_htlcs + 1;
 	let amt_msat_2 = recv_value_2 + total_routing_fee_msat;
-	let (route_2, _, _, _) = get_route_and_payment_hash!(nodes[0], nodes[2], amt_msat_2);
+	let mut route_2 = route_1.clone();
+	route_2.paths[0].last_mut().unwrap().fee_msat = amt_msat_2;
 
 	// Need to manually create the update_add_htlc message to go around the channel reserve check in send_htlc()
 	let secp_ctx = Secp256k1::new();
@@ -1732,6 +1764,7 @@ fn test_chan_reserve_violation_inbound_htlc_inbound_chan() {
 		payment_hash: our_payment_hash_1,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &msg);
@@ -1765,6 +1798,54 @@ fn test_inbound_outbound_capacity_is_not_zero() {
 	assert_eq!(channels1[0].inbound_capacity_msat, 100000 * 1000 - 95000000 - reserve*1000);
 }
 
+fn do_test_zero_reserve_channel(lsp_opens: bool) {
+	// Test that an LSP (nodes[0]) which trusts its client (nodes[1]) doesn't require it to keep a
+	// channel reserve, letting the client spend its full balance.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let mut lsp_config = test_default_channel_config();
+	lsp_config.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 100;
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[Some(lsp_config), None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	nodes[0].node.set_peer_config_overrides(nodes[1].node.get_our_node_id(),
+		PeerConfigOverrides { trusted_zero_reserve: true, ..Default::default() }).unwrap();
+
+	let chan = if lsp_opens {
+		create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100_000, 10_000_000, InitFeatures::known(), InitFeatures::known())
+	} else {
+		create_announced_chan_between_nodes_with_value(&nodes, 1, 0, 100_000, 0, InitFeatures::known(), InitFeatures::known())
+	};
+
+	let reserve = Channel::<EnforcingSigner>::get_holder_selected_channel_reserve_satoshis(100_000, &UserConfig::default());
+	let lsp_chan = &nodes[0].node.list_channels()[0];
+	assert_eq!(lsp_chan.counterparty.unspendable_punishment_reserve, 0);
+	assert_eq!(lsp_chan.unspendable_punishment_reserve, Some(reserve));
+	let client_chan = &nodes[1].node.list_channels()[0];
+	assert_eq!(client_chan.unspendable_punishment_reserve, Some(0));
+	assert_eq!(client_chan.counterparty.unspendable_punishment_reserve, reserve);
+	assert_eq!(client_chan.outbound_capacity_msat, client_chan.balance_msat);
+
+	let max_can_send = if lsp_opens {
+		// The LSP pays the commitment transaction fee, so the client can spend its full balance.
+		assert_eq!(client_chan.next_outbound_htlc_limit_msat, 10_000_000);
+		10_000_000
+	} else {
+		// The client pays the commitment transaction fee, including the fee spike buffer.
+		let feerate = get_feerate!(nodes[1], chan.2);
+		let commit_tx_fee = 2 * commit_tx_fee_msat(feerate, 1 + 1, get_opt_anchors!(nodes[1], chan.2));
+		assert_eq!(client_chan.next_outbound_htlc_limit_msat, 100_000_000 - commit_tx_fee);
+		client_chan.next_outbound_htlc_limit_msat
+	};
+	send_payment(&nodes[1], &[&nodes[0]], max_can_send);
+	assert_eq!(nodes[1].node.list_channels()[0].next_outbound_htlc_limit_msat, 0);
+}
+
+#[test]
+fn test_zero_reserve_channel() {
+	do_test_zero_reserve_channel(true);
+	do_test_zero_reserve_channel(false);
+}
+
 fn commit_tx_fee_msat(feerate: u32, num_htlcs: u64, opt_anchors: bool) -> u64 {
 	(commitment_tx_base_weight(opt_anchors) + num_htlcs * COMMITMENT_TX_WEIGHT_PER_HTLC) * feerate as u64 / 1000 * 1000
 }
@@ -1881,7 +1962,10 @@ fn test_channel_reserve_holding_cell_htlcs() {
 	// channel reserve test with htlc pending output > 0
 	let recv_value_2 = stat01.value_to_self_msat - amt_msat_1 - stat01.channel_reserve_msat - total_fee_msat - commit_tx_fee_2_htlcs;
 	{
-		let (route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], recv_value_2 + 1);
+		// The router won't find a route beyond our spendable balance, so reuse the path of route_1.
+		let mut route = route_1.clone();
+		route.paths[0].last_mut().unwrap().fee_msat = recv_value_2 + 1;
+		let (_, our_payment_hash, our_payment_secret) = get_payment_preimage_hash!(nodes[2]);
 		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &Some(our_payment_secret)), true, APIError::ChannelUnavailable { ref err },
 			assert!(regex::Regex::new(r"Cannot send value that would put our balance under counterparty-announced channel reserve value \(\d+\)").unwrap().is_match(err)));
 		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
@@ -1907,7 +1991,9 @@ fn test_channel_reserve_holding_cell_htlcs() {
 
 	// test with outbound holding cell amount > 0
 	{
-		let (route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], recv_value_22+1);
+		let mut route = route_1.clone();
+		route.paths[0].last_mut().unwrap().fee_msat = recv_value_22 + 1;
+		let (_, our_payment_hash, our_payment_secret) = get_payment_preimage_hash!(nodes[2]);
 		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &Some(our_payment_secret)), true, APIError::ChannelUnavailable { ref err },
 			assert!(regex::Regex::new(r"Cannot send value that would put our balance under counterparty-announced channel reserve value \(\d+\)").unwrap().is_match(err)));
 		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
@@ -1967,7 +2053,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 	let events = nodes[2].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 2);
 	match events[0] {
-		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(our_payment_hash_21, *payment_hash);
 			assert_eq!(recv_value_21, amount_msat);
 			match &purpose {
@@ -1981,7 +2067,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 		_ => panic!("Unexpected event"),
 	}
 	match events[1] {
-		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(our_payment_hash_22, *payment_hash);
 			assert_eq!(recv_value_22, amount_msat);
 			match &purpose {
@@ -3366,6 +3452,7 @@ fn fail_backward_pending_htlc_upon_channel_failure() {
 			payment_hash,
 			cltv_expiry,
 			onion_routing_packet,
+			blinding_point: None,
 		};
 		nodes[0].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &update_add_htlc);
 	}
@@ -3630,6 +3717,68 @@ fn test_simple_peer_disconnect() {
 	fail_payment(&nodes[0], &vec!(&nodes[1], &nodes[2]), payment_hash_6);
 }
 
+#[test]
+fn test_reestablish_plan_and_overrides() {
+	// Tests that the retransmissions made on reconnection are exposed via
+	// get_last_reestablish_plan and can be altered via ReestablishOverrides.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+	assert!(nodes[0].node.get_last_reestablish_plan(&chan_id).is_none());
+	// Move past the initial commitment so that channel_ready is not re-sent on reconnection.
+	send_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+
+	let (route, payment_hash, payment_preimage, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 1_000_000);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	// Drop the update_add_htlc and commitment_signed, which nodes[0] should then re-send.
+	let _ = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
+
+	// With the commitment update suppressed, nodes[1] never receives it.
+	let suppress_commitment_update = ReestablishOverrides { suppress_commitment_update: true, ..Default::default() };
+	nodes[0].node.set_reestablish_overrides(&chan_id, &nodes[1].node.get_our_node_id(), Some(suppress_commitment_update)).unwrap();
+	nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+	nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+	reconnect_nodes(&nodes[1], &nodes[0], (false, false), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+	let plan = nodes[0].node.get_last_reestablish_plan(&chan_id).unwrap();
+	assert!(!plan.resend_channel_ready);
+	assert!(!plan.resend_revoke_and_ack);
+	assert!(!plan.resend_commitment_update);
+	assert_eq!(plan.resent_htlc_updates, 0);
+
+	// Once the overrides are cleared, the commitment update is re-sent as usual.
+	nodes[0].node.set_reestablish_overrides(&chan_id, &nodes[1].node.get_our_node_id(), None).unwrap();
+	nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+	nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+	reconnect_nodes(&nodes[1], &nodes[0], (false, false), (1, 0), (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+	let plan = nodes[0].node.get_last_reestablish_plan(&chan_id).unwrap();
+	assert!(plan.resend_commitment_update);
+	assert_eq!(plan.resent_htlc_updates, 1);
+	assert!(!plan.awaiting_monitor_update);
+
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	expect_payment_received!(nodes[1], payment_hash, payment_secret, 1_000_000);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+	// Offsetting our commitment number makes nodes[1] think we're expecting a commitment_signed it
+	// never sent, causing it to close the channel.
+	let commitment_number_mismatch = ReestablishOverrides { next_local_commitment_number_offset: 1, ..Default::default() };
+	nodes[0].node.set_reestablish_overrides(&chan_id, &nodes[1].node.get_our_node_id(), Some(commitment_number_mismatch)).unwrap();
+	nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+	nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+	nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::empty(), remote_network_address: None });
+	let as_reestablish = get_chan_reestablish_msgs!(nodes[0], nodes[1]);
+	nodes[1].node.peer_connected(&nodes[0].node.get_our_node_id(), &msgs::Init { features: InitFeatures::empty(), remote_network_address: None });
+	let bs_reestablish = get_chan_reestablish_msgs!(nodes[1], nodes[0]);
+	assert_eq!(as_reestablish[0].next_local_commitment_number, bs_reestablish[0].next_local_commitment_number + 1);
+	nodes[1].node.handle_channel_reestablish(&nodes[0].node.get_our_node_id(), &as_reestablish[0]);
+	check_added_monitors!(nodes[1], 1);
+	check_closed_broadcast!(nodes[1], true);
+	check_closed_event!(nodes[1], 1, ClosureReason::ProcessingError { err: "Peer attempted to reestablish channel with a very old remote commitment transaction".to_owned() });
+}
+
 fn do_test_drop_messages_peer_disconnect(messages_delivered: u8, simulate_broken_lnd: bool) {
 	// Test that we can reconnect when in-flight HTLC updates get dropped
 	let chanmon_cfgs = create_chanmon_cfgs(2);
@@ -3737,7 +3886,7 @@ fn do_test_drop_messages_peer_disconnect(messages_delivered: u8, simulate_broken
 	let events_2 = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events_2.len(), 1);
 	match events_2[0] {
-		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat } => {
+		Event::PaymentReceived { ref payment_hash, ref purpose, amount_msat, .. } => {
 			assert_eq!(payment_hash_1, *payment_hash);
 			assert_eq!(amount_msat, 1_000_000);
 			match &purpose {
@@ -4028,6 +4177,7 @@ fn test_funding_peer_disconnect() {
 			tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 			logger: nodes[0].logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap()
 	};
 	nodes_0_deserialized = nodes_0_deserialized_tmp;
@@ -4366,6 +4516,79 @@ fn test_holding_cell_htlc_add_timeouts() {
 	do_test_holding_cell_htlc_add_timeouts(true);
 }
 
+#[test]
+fn test_channel_manager_observer() {
+	// Tests that a serialized ChannelManager can be inspected via a ChannelManagerObserver, which
+	// exposes its channels, HTLCs and payments but never broadcasts anything.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let funding_txo = OutPoint { txid: chan.3.txid(), index: 0 };
+
+	let stale_nodes_0_serialized = nodes[0].node.encode();
+	let (payment_preimage, payment_hash, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+
+	macro_rules! observe {
+		($node: expr, $node_serialized: expr, $keys_manager: expr, $logger: expr) => { {
+			let mut monitor_serialized = test_utils::TestVecWriter(Vec::new());
+			get_monitor!($node, chan.2).write(&mut monitor_serialized).unwrap();
+			let (_, mut monitor) = <(BlockHash, ChannelMonitor<EnforcingSigner>)>::read(
+				&mut &monitor_serialized.0[..], $keys_manager).unwrap();
+			let mut channel_monitors = HashMap::new();
+			channel_monitors.insert(funding_txo, &mut monitor);
+			<ChannelManagerObserver<EnforcingSigner, _, _>>::read(&mut &$node_serialized[..], ChannelManagerObserverReadArgs {
+				keys_manager: $keys_manager, logger: $logger, channel_monitors,
+			}).unwrap()
+		} }
+	}
+
+	let logger = test_utils::TestLogger::new();
+	let observer = observe!(nodes[1], nodes[1].node.encode(), &chanmon_cfgs[1].keys_manager, &logger);
+	assert_eq!(observer.get_our_node_id(), nodes[1].node.get_our_node_id());
+	assert_eq!(observer.best_block().1, nodes[1].best_block_info().1);
+	let channels = observer.list_channels();
+	assert_eq!(channels.len(), 1);
+	assert_eq!(channels[0].channel_id, chan.2);
+	assert!(!channels[0].is_usable);
+	let htlcs = observer.list_pending_htlcs();
+	assert_eq!(htlcs.len(), 1);
+	assert!(htlcs[0].inbound);
+	assert_eq!(htlcs[0].payment_hash, payment_hash);
+	assert_eq!(htlcs[0].amount_msat, 1_000_000);
+	let claimable = observer.list_claimable_payments();
+	assert_eq!(claimable.len(), 1);
+	assert_eq!(claimable[0].payment_hash, payment_hash);
+	assert_eq!(claimable[0].received_msat, 1_000_000);
+	assert_eq!(claimable[0].parts, 1);
+	assert!(observer.list_outbound_payments().is_empty());
+	assert!(observer.withheld_transactions().is_empty());
+
+	let observer = observe!(nodes[0], nodes[0].node.encode(), &chanmon_cfgs[0].keys_manager, &logger);
+	let htlcs = observer.list_pending_htlcs();
+	assert_eq!(htlcs.len(), 1);
+	assert!(!htlcs[0].inbound);
+	let payments = observer.list_outbound_payments();
+	assert_eq!(payments.len(), 1);
+	assert_eq!(payments[0].payment_hash, Some(payment_hash));
+	assert_eq!(payments[0].state, ObservedPaymentState::Pending);
+	assert_eq!(payments[0].total_msat, Some(1_000_000));
+	assert_eq!(payments[0].pending_parts, 1);
+
+	// A ChannelManager which is stale compared to its ChannelMonitor would normally be
+	// force-closed, broadcasting the latest commitment transaction, which is instead withheld.
+	nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
+	let observer = observe!(nodes[0], stale_nodes_0_serialized, &chanmon_cfgs[0].keys_manager, &logger);
+	assert!(observer.list_channels().is_empty());
+	let withheld = observer.withheld_transactions();
+	assert_eq!(withheld.len(), 1);
+	check_spends!(withheld[0], chan.3);
+	assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+}
+
 #[test]
 fn test_no_txn_manager_serialize_deserialize() {
 	let chanmon_cfgs = create_chanmon_cfgs(2);
@@ -4411,6 +4634,7 @@ fn test_no_txn_manager_serialize_deserialize() {
 			tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 			logger: &logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap()
 	};
 	nodes_0_deserialized = nodes_0_deserialized_tmp;
@@ -4521,6 +4745,7 @@ fn test_manager_serialize_deserialize_events() {
 			tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 			logger: &logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap()
 	};
 	nodes_0_deserialized = nodes_0_deserialized_tmp;
@@ -4607,6 +4832,7 @@ fn test_simple_manager_serialize_deserialize() {
 			tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 			logger: &logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap()
 	};
 	nodes_0_deserialized = nodes_0_deserialized_tmp;
@@ -4698,6 +4924,7 @@ fn test_manager_serialize_deserialize_inconsistent_monitor() {
 		tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 		logger: &logger,
 		channel_monitors: node_0_stale_monitors.iter_mut().map(|monitor| { (monitor.get_funding_txo().0, monitor) }).collect(),
+		role: NodeRole::Active,
 	}) { } else {
 		panic!("If the monitor(s) are stale, this indicates a bug and we should get an Err return");
 	};
@@ -4712,6 +4939,7 @@ fn test_manager_serialize_deserialize_inconsistent_monitor() {
 		tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 		logger: &logger,
 		channel_monitors: node_0_monitors.iter_mut().map(|monitor| { (monitor.get_funding_txo().0, monitor) }).collect(),
+		role: NodeRole::Active,
 	}).unwrap();
 	nodes_0_deserialized = nodes_0_deserialized_tmp;
 	assert!(nodes_0_read.is_empty());
@@ -6446,7 +6674,11 @@ fn test_fail_holding_cell_htlc_upon_free_multihop() {
 	let feemsat = 239;
 	let total_routing_fee_msat = (nodes.len() - 2) as u64 * feemsat;
 	let max_can_send = 5000000 - channel_reserve - 2*commit_tx_fee_msat(feerate, 1 + 1, opt_anchors) - total_routing_fee_msat;
-	let (route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], max_can_send);
+	// The router leaves some room for fees below our spendable balance, so set the amounts of the
+	// route ourselves.
+	let (mut route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], max_can_send / 2);
+	route.paths[0][0].fee_msat = feemsat;
+	route.paths[0].last_mut().unwrap().fee_msat = max_can_send;
 	let payment_event = {
 		nodes[0].node.send_payment(&route, our_payment_hash, &Some(our_payment_secret)).unwrap();
 		check_added_monitors!(nodes[0], 1);
@@ -6756,11 +6988,11 @@ fn test_update_add_htlc_bolt2_receiver_check_max_htlc_limit() {
 	let mut nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000, InitFeatures::known(), InitFeatures::known());
 
-	let (route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 3999999);
+	let (route, our_payment_hash, _, our_payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 1000);
 	let session_priv = SecretKey::from_slice(&[42; 32]).unwrap();
 	let cur_height = nodes[0].node.best_block.read().unwrap().height() + 1;
 	let onion_keys = onion_utils::construct_onion_keys(&Secp256k1::signing_only(), &route.paths[0], &session_priv).unwrap();
-	let (onion_payloads, _htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 3999999, &Some(our_payment_secret), cur_height, &None).unwrap();
+	let (onion_payloads, _htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 1000, &Some(our_payment_secret), cur_height, &None).unwrap();
 	let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, [0; 32], &our_payment_hash);
 
 	let mut msg = msgs::UpdateAddHTLC {
@@ -6770,6 +7002,7 @@ fn test_update_add_htlc_bolt2_receiver_check_max_htlc_limit() {
 		payment_hash: our_payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet.clone(),
+		blinding_point: None,
 	};
 
 	for i in 0..super::channel::OUR_MAX_HTLCS {
@@ -7478,6 +7711,7 @@ fn do_test_data_loss_protect(reconnect_panicing: bool) {
 			tx_broadcaster: &tx_broadcaster,
 			default_config: UserConfig::default(),
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap().1
 	};
 	nodes[0].node = &node_state_0;
@@ -7825,6 +8059,124 @@ fn test_bump_penalty_txn_on_revoked_commitment() {
 	nodes[1].node.get_and_clear_pending_msg_events();
 }
 
+#[test]
+fn test_claim_fee_budget_stops_bumping() {
+	// Tests that once bumping a claim would exceed the channel's ClaimFeeBudget we stop bumping it
+	// and generate an Event::ClaimFeeBudgetExhausted, resuming once the budget is lifted.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1000000, 59000000, InitFeatures::known(), InitFeatures::known());
+
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3000000).0;
+	let revoked_txn = get_local_commitment_txn!(nodes[0], chan.2);
+	let penalty_sum: u64 = revoked_txn[0].output.iter().filter(|outp| outp.script_pubkey.is_v0_p2wsh()).map(|outp| outp.value).sum();
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+	mine_transaction(&nodes[1], &revoked_txn[0]);
+	check_added_monitors!(nodes[1], 1);
+	check_closed_broadcast!(nodes[1], true);
+	check_closed_event!(nodes[1], 1, ClosureReason::CommitmentTxConfirmed);
+	let fee_1 = {
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 2); // justice tx + local commitment tx
+		check_spends!(node_txn[0], revoked_txn[0]);
+		let fee = penalty_sum - node_txn[0].output[0].value;
+		node_txn.clear();
+		fee
+	};
+
+	// With the budget set to the fee we've already paid, the justice tx is not bumped.
+	let budget = ClaimFeeBudget { max_total_fee_satoshis: Some(fee_1), max_total_fee_percent_of_claimable: Some(50) };
+	get_monitor!(nodes[1], chan.2).set_claim_fee_budget(Some(budget));
+	connect_blocks(&nodes[1], 15);
+	assert!(nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	let events = nodes[1].chain_monitor.chain_monitor.get_and_clear_pending_events();
+	assert_eq!(events.len(), 2);
+	if let Event::SpendableOutputs { .. } = events[0] {} else { panic!("Unexpected event"); }
+	match events[1] {
+		Event::ClaimFeeBudgetExhausted { channel_id, total_fee_satoshis, attempted_total_fee_satoshis, budget_satoshis } => {
+			assert_eq!(channel_id, chan.2);
+			assert_eq!(total_fee_satoshis, fee_1);
+			assert!(attempted_total_fee_satoshis > fee_1);
+			assert_eq!(budget_satoshis, fee_1);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	// Further refused bumps don't generate more events.
+	connect_blocks(&nodes[1], 1);
+	assert!(nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	assert!(nodes[1].chain_monitor.chain_monitor.get_and_clear_pending_events().is_empty());
+
+	// Once the budget is lifted, the justice tx is bumped again.
+	get_monitor!(nodes[1], chan.2).set_claim_fee_budget(None);
+	connect_blocks(&nodes[1], 1);
+	{
+		let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		check_spends!(node_txn[0], revoked_txn[0]);
+		assert!(penalty_sum - node_txn[0].output[0].value > fee_1);
+	}
+	assert!(nodes[1].chain_monitor.chain_monitor.get_and_clear_pending_events().is_empty());
+}
+
+#[test]
+fn test_scheduled_claims() {
+	// Tests that claims waiting on differing HTLC timelocks are exposed via
+	// ChannelMonitor::get_scheduled_claims and broadcast at their own heights using the
+	// pre-computed transactions.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+	connect_blocks(&nodes[0], 5);
+	connect_blocks(&nodes[1], 5);
+	route_payment(&nodes[0], &[&nodes[1]], 2_000_000);
+
+	nodes[0].node.force_close_broadcasting_latest_txn(&chan.2, &nodes[1].node.get_our_node_id()).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	check_closed_broadcast!(nodes[0], true);
+	check_closed_event!(nodes[0], 1, ClosureReason::HolderForceClosed);
+	let commitment_tx = {
+		let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		check_spends!(node_txn[0], chan.3);
+		node_txn.remove(0)
+	};
+	mine_transaction(&nodes[0], &commitment_tx);
+	nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
+
+	let scheduled_claims = get_monitor!(nodes[0], chan.2).get_scheduled_claims();
+	assert_eq!(scheduled_claims.len(), 2);
+	assert_eq!(scheduled_claims[0].broadcast_height + 5, scheduled_claims[1].broadcast_height);
+	for claim in scheduled_claims.iter() {
+		assert_eq!(claim.outpoints.len(), 1);
+		assert_eq!(claim.outpoints[0].txid, commitment_tx.txid());
+		let claim_tx = claim.transaction.as_ref().unwrap();
+		check_spends!(claim_tx, commitment_tx);
+		assert_eq!(claim_tx.lock_time.0, claim.broadcast_height + 1);
+	}
+
+	// Each HTLC-Timeout transaction is broadcast once its own timelock matures.
+	for (i, claim) in scheduled_claims.iter().enumerate() {
+		let claim_tx = claim.transaction.as_ref().unwrap();
+		connect_blocks(&nodes[0], claim.broadcast_height - nodes[0].best_block_info().1 - 1);
+		assert!(!nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().contains(claim_tx));
+		connect_blocks(&nodes[0], 1);
+		{
+			let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+			assert_eq!(node_txn.iter().filter(|tx| *tx == claim_tx).count(), 1);
+			node_txn.clear();
+		}
+		assert_eq!(get_monitor!(nodes[0], chan.2).get_scheduled_claims()[..], scheduled_claims[i + 1..]);
+	}
+}
+
 #[test]
 fn test_bump_penalty_txn_on_revoked_htlcs() {
 	// In case of penalty txn with too low feerates for getting into mempools, RBF-bump them to sure
@@ -8279,111 +8631,417 @@ fn test_channel_conf_timeout() {
 }
 
 #[test]
-fn test_override_channel_config() {
+fn test_outbound_channel_funding_timeout() {
+	// Tests that outbound channels configured with an `outbound_funding_timeout_blocks` are
+	// abandoned without broadcasting a commitment transaction once their funding transaction fails
+	// to confirm in time, generating an `Event::FundingTimedOut` listing the inputs to double-spend.
 	let chanmon_cfgs = create_chanmon_cfgs(2);
 	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
-	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let mut config = test_default_channel_config();
+	config.channel_handshake_config.outbound_funding_timeout_blocks = Some(144);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[Some(config), None]);
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 
-	// Node0 initiates a channel to node1 using the override config.
-	let mut override_config = UserConfig::default();
-	override_config.channel_handshake_config.our_to_self_delay = 200;
+	let funding_tx = create_chan_between_nodes_with_value_init(&nodes[0], &nodes[1], 1_000_000, 100_000, InitFeatures::known(), InitFeatures::known());
 
-	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 16_000_000, 12_000_000, 42, Some(override_config)).unwrap();
+	connect_blocks(&nodes[0], 143);
+	check_added_monitors!(nodes[0], 0);
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+	nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
 
-	// Assert the channel created by node0 is using the override config.
-	let res = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
-	assert_eq!(res.channel_flags, 0);
-	assert_eq!(res.to_self_delay, 200);
+	connect_blocks(&nodes[0], 1);
+	check_added_monitors!(nodes[0], 1);
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 2);
+	match events[0] {
+		Event::FundingTimedOut { user_channel_id, funding_txo, ref funding_inputs, .. } => {
+			assert_eq!(user_channel_id, 42);
+			assert_eq!(funding_txo.unwrap().txid, funding_tx.txid());
+			let expected_inputs: Vec<_> = funding_tx.input.iter().map(|input| input.previous_output).collect();
+			assert_eq!(*funding_inputs, expected_inputs);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	match events[1] {
+		Event::ChannelClosed { reason: ClosureReason::FundingTimedOut, .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+	let close_ev = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(close_ev.len(), 1);
+	match close_ev[0] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { .. }, ref node_id } => {
+			assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	// As the funding transaction never confirmed, we don't bother broadcasting a commitment
+	// transaction spending it.
+	assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
 }
 
 #[test]
-fn test_override_0msat_htlc_minimum() {
-	let mut zero_config = UserConfig::default();
-	zero_config.channel_handshake_config.our_htlc_minimum_msat = 0;
+fn test_channel_funding_intents() {
+	// Tests that channels created with a funding intent can be funded out-of-band, with the intent
+	// tracking the funding output, and are closed if the intent expires before they're funded.
 	let chanmon_cfgs = create_chanmon_cfgs(2);
 	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
-	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(zero_config.clone())]);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let node_b_id = nodes[1].node.get_our_node_id();
 
-	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 16_000_000, 12_000_000, 42, Some(zero_config)).unwrap();
-	let res = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
-	assert_eq!(res.htlc_minimum_msat, 1);
-
-	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &res);
-	let res = get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
-	assert_eq!(res.htlc_minimum_msat, 1);
-}
-
-#[test]
-fn test_channel_update_has_correct_htlc_maximum_msat() {
-	// Tests that the `ChannelUpdate` message has the correct values for `htlc_maximum_msat` set.
-	// Bolt 7 specifies that if present `htlc_maximum_msat`:
-	// 1. MUST be set to less than or equal to the channel capacity. In LDK, this is capped to
-	// 90% of the `channel_value`.
-	// 2. MUST be set to less than or equal to the `max_htlc_value_in_flight_msat` received from the peer.
-
-	let mut config_30_percent = UserConfig::default();
-	config_30_percent.channel_handshake_config.announced_channel = true;
-	config_30_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 30;
-	let mut config_50_percent = UserConfig::default();
-	config_50_percent.channel_handshake_config.announced_channel = true;
-	config_50_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 50;
-	let mut config_95_percent = UserConfig::default();
-	config_95_percent.channel_handshake_config.announced_channel = true;
-	config_95_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 95;
-	let mut config_100_percent = UserConfig::default();
-	config_100_percent.channel_handshake_config.announced_channel = true;
-	config_100_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 100;
-
-	let chanmon_cfgs = create_chanmon_cfgs(4);
-	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
-	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[Some(config_30_percent), Some(config_50_percent), Some(config_95_percent), Some(config_100_percent)]);
-	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+	let intent = nodes[0].node.create_channel_with_funding_intent(node_b_id, 100_000, 0, 42, None, 3600).unwrap();
+	assert_eq!(intent.channel_value_satoshis, 100_000);
+	assert!(intent.output_script.is_none());
+	assert_eq!(nodes[0].node.list_funding_intents(), vec![intent.clone()]);
 
-	let channel_value_satoshis = 100000;
-	let channel_value_msat = channel_value_satoshis * 1000;
-	let channel_value_30_percent_msat = (channel_value_msat as f64 * 0.3) as u64;
-	let channel_value_50_percent_msat = (channel_value_msat as f64 * 0.5) as u64;
-	let channel_value_90_percent_msat = (channel_value_msat as f64 * 0.9) as u64;
+	let open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, node_b_id);
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &open_channel);
+	let accept_channel = get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_accept_channel(&node_b_id, InitFeatures::known(), &accept_channel);
+	let (temporary_channel_id, funding_tx, _) = create_funding_transaction(&nodes[0], &node_b_id, 100_000, 42);
+	assert_eq!(temporary_channel_id, intent.temporary_channel_id);
+	let updated_intent = nodes[0].node.get_funding_intent(&temporary_channel_id).unwrap();
+	assert_eq!(updated_intent.output_script.as_ref(), Some(&funding_tx.output[0].script_pubkey));
+
+	// Once funded, the intent is no longer tracked.
+	nodes[0].node.funding_transaction_generated(&temporary_channel_id, &node_b_id, funding_tx).unwrap();
+	assert!(nodes[0].node.get_funding_intent(&temporary_channel_id).is_none());
+	let funding_created = get_event_msg!(nodes[0], MessageSendEvent::SendFundingCreated, node_b_id);
+	nodes[1].node.handle_funding_created(&nodes[0].node.get_our_node_id(), &funding_created);
+	check_added_monitors!(nodes[1], 1);
+	let funding_signed = get_event_msg!(nodes[1], MessageSendEvent::SendFundingSigned, nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_funding_signed(&node_b_id, &funding_signed);
+	check_added_monitors!(nodes[0], 1);
 
-	let (node_0_chan_update, node_1_chan_update, _, _)  = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, channel_value_satoshis, 10001, InitFeatures::known(), InitFeatures::known());
-	let (node_2_chan_update, node_3_chan_update, _, _)  = create_announced_chan_between_nodes_with_value(&nodes, 2, 3, channel_value_satoshis, 10001, InitFeatures::known(), InitFeatures::known());
+	// A second channel is never funded, and is closed once its intent expires.
+	let intent = nodes[0].node.create_channel_with_funding_intent(node_b_id, 100_000, 0, 43, None, 10).unwrap();
+	let open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, node_b_id);
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &open_channel);
+	let accept_channel = get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_accept_channel(&node_b_id, InitFeatures::known(), &accept_channel);
+	let (temporary_channel_id, _, _) = create_funding_transaction(&nodes[0], &node_b_id, 100_000, 43);
 
-	// Assert that `node[0]`'s `ChannelUpdate` is capped at 50 percent of the `channel_value`, as
-	// that's the value of `node[1]`'s `holder_max_htlc_value_in_flight_msat`.
-	assert_eq!(node_0_chan_update.contents.htlc_maximum_msat, channel_value_50_percent_msat);
-	// Assert that `node[1]`'s `ChannelUpdate` is capped at 30 percent of the `channel_value`, as
-	// that's the value of `node[0]`'s `holder_max_htlc_value_in_flight_msat`.
-	assert_eq!(node_1_chan_update.contents.htlc_maximum_msat, channel_value_30_percent_msat);
+	// Blocks in tests are timestamped with their height.
+	let blocks_to_expiry = (intent.expires_at - nodes[0].best_block_info().1 as u64) as u32;
+	connect_blocks(&nodes[0], blocks_to_expiry - 1);
+	assert!(nodes[0].node.get_funding_intent(&temporary_channel_id).is_some());
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
 
-	// Assert that `node[2]`'s `ChannelUpdate` is capped at 90 percent of the `channel_value`, as
-	// the value of `node[3]`'s `holder_max_htlc_value_in_flight_msat` (100%), exceeds 90% of the
-	// `channel_value`.
-	assert_eq!(node_2_chan_update.contents.htlc_maximum_msat, channel_value_90_percent_msat);
-	// Assert that `node[3]`'s `ChannelUpdate` is capped at 90 percent of the `channel_value`, as
-	// the value of `node[2]`'s `holder_max_htlc_value_in_flight_msat` (95%), exceeds 90% of the
-	// `channel_value`.
-	assert_eq!(node_3_chan_update.contents.htlc_maximum_msat, channel_value_90_percent_msat);
+	connect_blocks(&nodes[0], 1);
+	assert!(nodes[0].node.list_funding_intents().is_empty());
+	check_closed_event!(nodes[0], 1, ClosureReason::FundingIntentExpired);
+	let close_ev = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(close_ev.len(), 1);
+	match close_ev[0] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { ref msg }, ref node_id } => {
+			assert_eq!(*node_id, node_b_id);
+			nodes[1].node.handle_error(&nodes[0].node.get_our_node_id(), msg);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	check_closed_event!(nodes[1], 1, ClosureReason::CounterpartyForceClosed { peer_msg: "Channel funding expired".to_owned() });
+	assert_eq!(nodes[0].node.list_channels().len(), 1);
 }
 
 #[test]
-fn test_manually_accept_inbound_channel_request() {
-	let mut manually_accept_conf = UserConfig::default();
-	manually_accept_conf.manually_accept_inbound_channels = true;
+fn test_outbound_channel_feerate_and_min_depth_config() {
+	// Tests that the initial commitment feerate and the minimum funding depth of outbound channels
+	// can be set at channel creation.
 	let chanmon_cfgs = create_chanmon_cfgs(2);
 	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
-	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(manually_accept_conf.clone())]);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 
-	let temp_channel_id = nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 42, Some(manually_accept_conf)).unwrap();
-	let res = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
-
-	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &res);
-
-	// Assert that `nodes[1]` has no `MessageSendEvent::SendAcceptChannel` in `msg_events` before
-	// accepting the inbound channel request.
-	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	let mut override_config = UserConfig::default();
+	override_config.channel_handshake_config.funding_feerate_sat_per_1000_weight = Some(1000);
+	override_config.channel_handshake_limits.min_minimum_depth = 20;
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 42, Some(override_config)).unwrap();
+
+	let open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	assert_eq!(open_channel.feerate_per_kw, 1000);
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &open_channel);
+	let accept_channel = get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
+	assert!(accept_channel.minimum_depth < 20);
+	nodes[0].node.handle_accept_channel(&nodes[1].node.get_our_node_id(), InitFeatures::known(), &accept_channel);
+	assert_eq!(nodes[0].node.list_channels()[0].confirmations_required, Some(20));
+	match nodes[0].node.get_and_clear_pending_events()[..] {
+		[Event::FundingGenerationReady { .. }] => {},
+		_ => panic!("Unexpected events"),
+	}
+}
+
+#[test]
+fn test_peer_feature_requirements() {
+	// Peers which do not support the features required by our config should not be able to open
+	// channels with us, nor should we open channels to them or, if configured, forward to them.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut requiring_cfg = test_default_channel_config();
+	requiring_cfg.peer_feature_requirements.require_scid_privacy = true;
+	requiring_cfg.peer_feature_requirements.enforce_on_forwards = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(requiring_cfg), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	// All of our test nodes support scid_privacy, so channels can be opened as usual.
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	// Reconnect nodes[2] to nodes[1], this time without advertising any features.
+	nodes[1].node.peer_disconnected(&nodes[2].node.get_our_node_id(), false);
+	nodes[2].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+	nodes[1].node.peer_connected(&nodes[2].node.get_our_node_id(), &msgs::Init { features: InitFeatures::empty(), remote_network_address: None });
+	let reestablish_1 = get_chan_reestablish_msgs!(nodes[1], nodes[2]);
+	nodes[2].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known(), remote_network_address: None });
+	let reestablish_2 = get_chan_reestablish_msgs!(nodes[2], nodes[1]);
+	nodes[1].node.handle_channel_reestablish(&nodes[2].node.get_our_node_id(), &reestablish_2[0]);
+	handle_chan_reestablish_msgs!(nodes[1], nodes[2]);
+	nodes[2].node.handle_channel_reestablish(&nodes[1].node.get_our_node_id(), &reestablish_1[0]);
+	handle_chan_reestablish_msgs!(nodes[2], nodes[1]);
+
+	// We refuse to open a new channel to nodes[2]...
+	match nodes[1].node.create_channel(nodes[2].node.get_our_node_id(), 100_000, 0, 42, None) {
+		Err(APIError::ChannelUnavailable { err }) => assert!(err.contains("option_scid_alias")),
+		_ => panic!("Unexpected result"),
+	}
+
+	// ...to accept one from it...
+	nodes[2].node.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 42, None).unwrap();
+	let open_channel = get_event_msg!(nodes[2], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_open_channel(&nodes[2].node.get_our_node_id(), InitFeatures::empty(), &open_channel);
+	let msg_events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 1);
+	match msg_events[0] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { ref msg }, .. } => {
+			assert_eq!(msg.channel_id, open_channel.temporary_channel_id);
+			assert!(msg.data.contains("option_scid_alias"));
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	// ...and to forward over our existing channel with it.
+	let (route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], 10_000);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let payment_event = SendEvent::from_event(nodes[0].node.get_and_clear_pending_msg_events().remove(0));
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+
+	let htlc_fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert!(htlc_fail_updates.update_add_htlcs.is_empty());
+	assert_eq!(htlc_fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &htlc_fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], htlc_fail_updates.commitment_signed, true, true);
+	expect_payment_failed_with_update!(nodes[0], payment_hash, false, chan_2.0.contents.short_channel_id, true);
+}
+
+#[test]
+fn test_event_queue_limits_block() {
+	// Once our message queue is full, we should refuse to open new channels until it is drained,
+	// warning the user when it gets close.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let mut limited_cfg = test_default_channel_config();
+	limited_cfg.event_queue_limits.max_pending_msg_events = 2;
+	limited_cfg.event_queue_limits.warning_threshold_percent = 50;
+	limited_cfg.event_queue_limits.overflow_policy = EventQueueOverflowPolicy::Block;
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[Some(limited_cfg), None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 42, None).unwrap();
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 43, None).unwrap();
+	match nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 44, None) {
+		Err(APIError::ChannelUnavailable { .. }) => {},
+		_ => panic!("Unexpected result"),
+	}
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PendingEventQueueThresholdCrossed { queue, pending, limit } => {
+			assert_eq!(queue, PendingEventQueue::MessageSendEvents);
+			assert_eq!(pending, 1);
+			assert_eq!(limit, 2);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	// Once the queue is drained, we can open channels again.
+	assert_eq!(nodes[0].node.get_and_clear_pending_msg_events().len(), 2);
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 44, None).unwrap();
+	get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+}
+
+#[test]
+fn test_event_queue_limits_drop_gossip() {
+	// With the default overflow policy, queued gossip is dropped, oldest first, to make room for
+	// other messages once the message queue is full.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let mut limited_cfg = test_default_channel_config();
+	limited_cfg.event_queue_limits.max_pending_msg_events = 4;
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[Some(limited_cfg), None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	// Each call queues both a channel_announcement and a node_announcement.
+	for _ in 0..3 {
+		nodes[0].node.broadcast_node_announcement([0; 3], [0; 32], Vec::new());
+	}
+	nodes[0].node.timer_tick_occurred();
+	nodes[0].node.get_and_clear_pending_events();
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 42, None).unwrap();
+	let msg_events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 4);
+	match msg_events[0] {
+		MessageSendEvent::BroadcastNodeAnnouncement { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+	match msg_events[1] {
+		MessageSendEvent::BroadcastChannelAnnouncement { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+	match msg_events[3] {
+		MessageSendEvent::SendOpenChannel { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+}
+
+#[test]
+fn test_estimate_channel_costs() {
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	*chanmon_cfgs[0].fee_estimator.sat_per_kw.lock().unwrap() = 1000;
+
+	let estimate = nodes[0].node.estimate_channel_costs(1_000_000, &ChannelTypeFeatures::only_static_remote_key()).unwrap();
+	assert_eq!(estimate.feerate_sat_per_1000_weight, 1000);
+	assert_eq!(estimate.funding_tx_fee_satoshis, 610);
+	assert_eq!(estimate.commitment_tx_fee_satoshis, 724);
+	assert_eq!(estimate.commitment_tx_fee_per_htlc_satoshis, 172);
+	assert_eq!(estimate.counterparty_reserve_satoshis, 10_000);
+	assert_eq!(estimate.expected_holder_reserve_satoshis, 10_000);
+	assert_eq!(estimate.cooperative_close_fee_satoshis, 674);
+	assert_eq!(estimate.force_close_fee_satoshis, 724 + 483);
+
+	// The feerate we estimate with is the one we actually propose when opening the channel.
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 1_000_000, 0, 42, None).unwrap();
+	let open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	assert_eq!(open_channel.feerate_per_kw, estimate.feerate_sat_per_1000_weight);
+
+	// Channel types we don't understand can't be estimated.
+	let unknown_type = ChannelTypeFeatures::from_le_bytes(vec![0, 0, 0, 0, 0, 0, 0, 0, 1]);
+	assert!(nodes[0].node.estimate_channel_costs(1_000_000, &unknown_type).is_err());
+}
+
+#[test]
+fn test_override_channel_config() {
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	// Node0 initiates a channel to node1 using the override config.
+	let mut override_config = UserConfig::default();
+	override_config.channel_handshake_config.our_to_self_delay = 200;
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 16_000_000, 12_000_000, 42, Some(override_config)).unwrap();
+
+	// Assert the channel created by node0 is using the override config.
+	let res = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	assert_eq!(res.channel_flags, 0);
+	assert_eq!(res.to_self_delay, 200);
+}
+
+#[test]
+fn test_override_0msat_htlc_minimum() {
+	let mut zero_config = UserConfig::default();
+	zero_config.channel_handshake_config.our_htlc_minimum_msat = 0;
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(zero_config.clone())]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 16_000_000, 12_000_000, 42, Some(zero_config)).unwrap();
+	let res = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	assert_eq!(res.htlc_minimum_msat, 1);
+
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &res);
+	let res = get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
+	assert_eq!(res.htlc_minimum_msat, 1);
+}
+
+#[test]
+fn test_channel_update_has_correct_htlc_maximum_msat() {
+	// Tests that the `ChannelUpdate` message has the correct values for `htlc_maximum_msat` set.
+	// Bolt 7 specifies that if present `htlc_maximum_msat`:
+	// 1. MUST be set to less than or equal to the channel capacity. In LDK, this is capped to
+	// 90% of the `channel_value`.
+	// 2. MUST be set to less than or equal to the `max_htlc_value_in_flight_msat` received from the peer.
+
+	let mut config_30_percent = UserConfig::default();
+	config_30_percent.channel_handshake_config.announced_channel = true;
+	config_30_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 30;
+	let mut config_50_percent = UserConfig::default();
+	config_50_percent.channel_handshake_config.announced_channel = true;
+	config_50_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 50;
+	let mut config_95_percent = UserConfig::default();
+	config_95_percent.channel_handshake_config.announced_channel = true;
+	config_95_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 95;
+	let mut config_100_percent = UserConfig::default();
+	config_100_percent.channel_handshake_config.announced_channel = true;
+	config_100_percent.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 100;
+
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[Some(config_30_percent), Some(config_50_percent), Some(config_95_percent), Some(config_100_percent)]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+
+	let channel_value_satoshis = 100000;
+	let channel_value_msat = channel_value_satoshis * 1000;
+	let channel_value_30_percent_msat = (channel_value_msat as f64 * 0.3) as u64;
+	let channel_value_50_percent_msat = (channel_value_msat as f64 * 0.5) as u64;
+	let channel_value_90_percent_msat = (channel_value_msat as f64 * 0.9) as u64;
+
+	let (node_0_chan_update, node_1_chan_update, _, _)  = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, channel_value_satoshis, 10001, InitFeatures::known(), InitFeatures::known());
+	let (node_2_chan_update, node_3_chan_update, _, _)  = create_announced_chan_between_nodes_with_value(&nodes, 2, 3, channel_value_satoshis, 10001, InitFeatures::known(), InitFeatures::known());
+
+	// Assert that `node[0]`'s `ChannelUpdate` is capped at 50 percent of the `channel_value`, as
+	// that's the value of `node[1]`'s `holder_max_htlc_value_in_flight_msat`.
+	assert_eq!(node_0_chan_update.contents.htlc_maximum_msat, channel_value_50_percent_msat);
+	// Assert that `node[1]`'s `ChannelUpdate` is capped at 30 percent of the `channel_value`, as
+	// that's the value of `node[0]`'s `holder_max_htlc_value_in_flight_msat`.
+	assert_eq!(node_1_chan_update.contents.htlc_maximum_msat, channel_value_30_percent_msat);
+
+	// Assert that `node[2]`'s `ChannelUpdate` is capped at 90 percent of the `channel_value`, as
+	// the value of `node[3]`'s `holder_max_htlc_value_in_flight_msat` (100%), exceeds 90% of the
+	// `channel_value`.
+	assert_eq!(node_2_chan_update.contents.htlc_maximum_msat, channel_value_90_percent_msat);
+	// Assert that `node[3]`'s `ChannelUpdate` is capped at 90 percent of the `channel_value`, as
+	// the value of `node[2]`'s `holder_max_htlc_value_in_flight_msat` (95%), exceeds 90% of the
+	// `channel_value`.
+	assert_eq!(node_3_chan_update.contents.htlc_maximum_msat, channel_value_90_percent_msat);
+}
+
+#[test]
+fn test_manually_accept_inbound_channel_request() {
+	let mut manually_accept_conf = UserConfig::default();
+	manually_accept_conf.manually_accept_inbound_channels = true;
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(manually_accept_conf.clone())]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let temp_channel_id = nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 42, Some(manually_accept_conf)).unwrap();
+	let res = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &res);
+
+	// Assert that `nodes[1]` has no `MessageSendEvent::SendAcceptChannel` in `msg_events` before
+	// accepting the inbound channel request.
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
 
 	let events = nodes[1].node.get_and_clear_pending_events();
 	match events[0] {
@@ -9700,6 +10358,7 @@ fn test_forwardable_regen() {
 			tx_broadcaster: nodes[1].tx_broadcaster.clone(),
 			logger: nodes[1].logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap()
 	};
 	nodes_1_deserialized = nodes_1_deserialized_tmp;
@@ -10163,6 +10822,7 @@ fn do_test_partial_claim_before_restart(persist_both_monitors: bool) {
 			tx_broadcaster: nodes[3].tx_broadcaster.clone(),
 			logger: nodes[3].logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap().1
 	};
 	nodes[3].node = &nodes_3_deserialized;
@@ -10457,3 +11117,799 @@ fn test_non_final_funding_tx() {
 	assert!(nodes[0].node.funding_transaction_generated(&temp_channel_id, &nodes[1].node.get_our_node_id(), tx.clone()).is_ok());
 	get_event_msg!(nodes[0], MessageSendEvent::SendFundingCreated, nodes[1].node.get_our_node_id());
 }
+
+#[test]
+fn test_freeze_all_channels() {
+	// Freezing our channels should disable them, reject new HTLCs and pause fee updates until
+	// they're unfrozen.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	nodes[1].node.freeze_all_channels();
+	assert!(nodes[1].node.channels_frozen());
+	let msg_events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 2);
+	for event in msg_events {
+		match event {
+			MessageSendEvent::BroadcastChannelUpdate { msg } => assert_eq!(msg.contents.flags & 2, 2),
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	// Freezing again is a no-op.
+	nodes[1].node.freeze_all_channels();
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+
+	// We can't send payments...
+	let (route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[1], nodes[2], 10_000);
+	match nodes[1].node.send_payment(&route, payment_hash, &Some(payment_secret)) {
+		Err(PaymentSendFailure::ParameterError(APIError::ChannelUnavailable { .. })) => {},
+		_ => panic!("Unexpected result"),
+	}
+
+	// ...or forward them.
+	let (route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], 10_000);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let payment_event = SendEvent::from_event(nodes[0].node.get_and_clear_pending_msg_events().remove(0));
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+
+	let htlc_fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert!(htlc_fail_updates.update_add_htlcs.is_empty());
+	assert_eq!(htlc_fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &htlc_fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], htlc_fail_updates.commitment_signed, false, true);
+	expect_payment_failed!(nodes[0], payment_hash, false, 0x2000 | 2, [0; 0]);
+
+	// Fee updates are paused and the channels stay disabled across timer ticks.
+	{
+		let mut feerate_lock = chanmon_cfgs[1].fee_estimator.sat_per_kw.lock().unwrap();
+		*feerate_lock *= 4;
+	}
+	nodes[1].node.timer_tick_occurred();
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+
+	// Once unfrozen, our channels are re-enabled and we can forward payments again.
+	nodes[1].node.unfreeze_all_channels();
+	assert!(!nodes[1].node.channels_frozen());
+	let msg_events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 2);
+	for event in msg_events {
+		match event {
+			MessageSendEvent::BroadcastChannelUpdate { msg } => assert_eq!(msg.contents.flags & 2, 0),
+			_ => panic!("Unexpected event"),
+		}
+	}
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], 10_000).0;
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+}
+
+#[test]
+fn test_channel_probation() {
+	// Once a counterparty disconnects from us too often, we should stop forwarding over its
+	// channel until the probation period ends or it's lifted manually.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut probation_config = test_default_channel_config();
+	probation_config.channel_probation.max_disconnects = Some(2);
+	probation_config.channel_probation.probation_ticks = 2;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(probation_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let is_on_probation = |channel_id: [u8; 32]| {
+		nodes[1].node.list_channels().iter().find(|details| details.channel_id == channel_id).unwrap().is_on_probation
+	};
+	// No updates have been made to the channel yet, so channel_ready is retransmitted.
+	for _ in 0..2 {
+		assert!(!is_on_probation(chan_2.2));
+		nodes[1].node.peer_disconnected(&nodes[2].node.get_our_node_id(), false);
+		nodes[2].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+		reconnect_nodes(&nodes[1], &nodes[2], (true, true), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+	}
+	assert!(is_on_probation(chan_2.2));
+
+	// Payments over the channel are refused...
+	let (route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], 10_000);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let payment_event = SendEvent::from_event(nodes[0].node.get_and_clear_pending_msg_events().remove(0));
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+
+	let htlc_fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert!(htlc_fail_updates.update_add_htlcs.is_empty());
+	assert_eq!(htlc_fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &htlc_fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], htlc_fail_updates.commitment_signed, false, true);
+	expect_payment_failed_with_update!(nodes[0], payment_hash, false, chan_2.0.contents.short_channel_id, false);
+
+	// ...until the probation period ends.
+	nodes[1].node.timer_tick_occurred();
+	assert!(is_on_probation(chan_2.2));
+	nodes[1].node.timer_tick_occurred();
+	assert!(!is_on_probation(chan_2.2));
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], 10_000).0;
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+
+	// Probation can also be lifted early.
+	for _ in 0..2 {
+		nodes[1].node.peer_disconnected(&nodes[2].node.get_our_node_id(), false);
+		nodes[2].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+		reconnect_nodes(&nodes[1], &nodes[2], (false, false), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+	}
+	assert!(is_on_probation(chan_2.2));
+	assert!(nodes[1].node.end_channel_probation(&chan_2.2, &nodes[0].node.get_our_node_id()).is_err());
+	nodes[1].node.end_channel_probation(&chan_2.2, &nodes[2].node.get_our_node_id()).unwrap();
+	assert!(!is_on_probation(chan_2.2));
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], 10_000).0;
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+}
+
+#[test]
+fn test_force_close_decision_handler() {
+	// A ForceCloseDecisionHandler should be able to keep a channel open despite a feerate
+	// disagreement, and have it closed as usual once it approves.
+	struct TestForceCloseDecisionHandler {
+		decision: ForceCloseDecision,
+		reasons: Arc<Mutex<Vec<ForceCloseReason>>>,
+	}
+	impl ForceCloseDecisionHandler for TestForceCloseDecisionHandler {
+		fn decide_force_close(&self, _channel_id: &[u8; 32], _counterparty_node_id: &PublicKey, reason: &ForceCloseReason) -> ForceCloseDecision {
+			self.reasons.lock().unwrap().push(reason.clone());
+			self.decision
+		}
+	}
+
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+
+	let reasons = Arc::new(Mutex::new(Vec::new()));
+	nodes[1].node.set_force_close_decision_handler(Box::new(TestForceCloseDecisionHandler {
+		decision: ForceCloseDecision::DisableAndWait, reasons: Arc::clone(&reasons),
+	}));
+
+	// nodes[1] considers anything above 6250 sat/kW absurd.
+	*chanmon_cfgs[0].fee_estimator.sat_per_kw.lock().unwrap() = 10_000;
+	nodes[0].node.timer_tick_occurred();
+	check_added_monitors!(nodes[0], 1);
+	let update = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_update_fee(&nodes[0].node.get_our_node_id(), update.update_fee.as_ref().unwrap());
+	commitment_signed_dance!(nodes[1], nodes[0], update.commitment_signed, false);
+
+	assert_eq!(*reasons.lock().unwrap(), vec![ForceCloseReason::FeerateDisagreement { counterparty_feerate_sat_per_1000_weight: 10_000 }]);
+	let channels = nodes[1].node.list_channels();
+	assert_eq!(channels.len(), 1);
+	assert_eq!(channels[0].channel_id, chan_id);
+	assert!(channels[0].is_on_probation);
+
+	// Once the handler approves, the channel is closed as it would be without one.
+	nodes[1].node.set_force_close_decision_handler(Box::new(TestForceCloseDecisionHandler {
+		decision: ForceCloseDecision::Approve, reasons: Arc::clone(&reasons),
+	}));
+	*chanmon_cfgs[0].fee_estimator.sat_per_kw.lock().unwrap() = 12_000;
+	nodes[0].node.timer_tick_occurred();
+	check_added_monitors!(nodes[0], 1);
+	let update = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_update_fee(&nodes[0].node.get_our_node_id(), update.update_fee.as_ref().unwrap());
+	assert_eq!(reasons.lock().unwrap().len(), 2);
+	check_added_monitors!(nodes[1], 1);
+	check_closed_broadcast!(nodes[1], true);
+	check_closed_event!(nodes[1], 1, ClosureReason::ProcessingError { err: "Peer's feerate much too high. Actual: 12000. Our expected upper limit: 6250".to_string() });
+	assert!(nodes[1].node.list_channels().is_empty());
+}
+
+struct TestForwardPolicyHandler {
+	decision: ForwardPolicyDecision,
+	decision_delay: Option<core::time::Duration>,
+	requests: Arc<Mutex<Vec<ForwardRequest>>>,
+}
+impl ForwardPolicyHandler for TestForwardPolicyHandler {
+	fn decide_forward(&self, request: &ForwardRequest) -> ForwardPolicyDecision {
+		self.requests.lock().unwrap().push(request.clone());
+		if let Some(delay) = self.decision_delay { std::thread::sleep(delay); }
+		self.decision
+	}
+}
+
+/// Sends a payment from `nodes[0]` to `nodes[2]` and has `nodes[1]` attempt to forward it.
+fn send_payment_to_forwarding_node(nodes: &[Node], amt_msat: u64) -> (PaymentPreimage, PaymentHash, PaymentSecret) {
+	let (route, payment_hash, payment_preimage, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], amt_msat);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.remove(0));
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	(payment_preimage, payment_hash, payment_secret)
+}
+
+/// Fails back the payment `nodes[1]` failed to forward to `nodes[2]` over `chan`.
+fn fail_back_unforwarded_payment(nodes: &[Node], payment_hash: PaymentHash, chan: &(msgs::ChannelUpdate, msgs::ChannelUpdate, [u8; 32], Transaction)) {
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[1], vec![HTLCDestination::NextHopChannel { node_id: Some(nodes[2].node.get_our_node_id()), channel_id: chan.2 }]);
+	check_added_monitors!(nodes[1], 1);
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false, true);
+	expect_payment_failed_with_update!(nodes[0], payment_hash, false, chan.0.contents.short_channel_id, false);
+}
+
+#[test]
+fn test_forward_policy_handler() {
+	// A ForwardPolicyHandler should be able to deny forwards, and hold them until they're allowed
+	// asynchronously.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let requests = Arc::new(Mutex::new(Vec::new()));
+	nodes[1].node.set_forward_policy_handler(Box::new(TestForwardPolicyHandler {
+		decision: ForwardPolicyDecision::Deny, decision_delay: None, requests: Arc::clone(&requests),
+	}));
+	let (_, payment_hash, _) = send_payment_to_forwarding_node(&nodes, 100_000);
+	{
+		let requests = requests.lock().unwrap();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].payment_hash, payment_hash);
+		assert_eq!(requests[0].prev_channel_id, chan_1.2);
+		assert_eq!(requests[0].prev_htlc_id, 0);
+		assert_eq!(requests[0].prev_counterparty_node_id, Some(nodes[0].node.get_our_node_id()));
+		assert_eq!(requests[0].next_channel_id, chan_2.2);
+		assert_eq!(requests[0].next_short_channel_id, chan_2.0.contents.short_channel_id);
+		assert_eq!(requests[0].next_counterparty_node_id, nodes[2].node.get_our_node_id());
+		assert_eq!(requests[0].incoming_amount_msat, Some(101_000));
+		assert_eq!(requests[0].outgoing_amount_msat, 100_000);
+	}
+	fail_back_unforwarded_payment(&nodes, payment_hash, &chan_2);
+	let rejected_forwards = nodes[1].node.list_rejected_forwards();
+	assert_eq!(rejected_forwards.len(), 1);
+	assert_eq!(rejected_forwards[0].reason, ForwardRejectionReason::Policy);
+	assert_eq!(rejected_forwards[0].incoming_amount_msat, Some(101_000));
+
+	// A held forward is forwarded once allowed.
+	nodes[1].node.set_forward_policy_handler(Box::new(TestForwardPolicyHandler {
+		decision: ForwardPolicyDecision::Hold, decision_delay: None, requests: Arc::clone(&requests),
+	}));
+	let (payment_preimage, payment_hash, payment_secret) = send_payment_to_forwarding_node(&nodes, 100_000);
+	assert_eq!(requests.lock().unwrap().len(), 2);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	assert!(nodes[1].node.resolve_held_forward(&chan_1.2, 0, true).is_err());
+	nodes[1].node.resolve_held_forward(&chan_1.2, 1, true).unwrap();
+	assert!(nodes[1].node.resolve_held_forward(&chan_1.2, 1, true).is_err());
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	// The handler isn't consulted again once a held forward is decided on.
+	assert_eq!(requests.lock().unwrap().len(), 2);
+	check_added_monitors!(nodes[1], 1);
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.remove(0));
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[2]);
+	expect_payment_received!(nodes[2], payment_hash, payment_secret, 100_000);
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+}
+
+#[test]
+fn test_forward_policy_handler_custom_tlvs() {
+	// Custom TLVs the sender included in the onion for a forwarding node should be passed to its
+	// ForwardPolicyHandler.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let requests = Arc::new(Mutex::new(Vec::new()));
+	nodes[1].node.set_forward_policy_handler(Box::new(TestForwardPolicyHandler {
+		decision: ForwardPolicyDecision::Deny, decision_delay: None, requests: Arc::clone(&requests),
+	}));
+	let session_priv = [3; 32];
+	*nodes[0].keys_manager.override_random_bytes.lock().unwrap() = Some(session_priv);
+	let (route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], 100_000);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let mut payment_event = SendEvent::from_event(events.remove(0));
+
+	// Rebuild the onion with custom TLVs for nodes[1], as we don't support sending them.
+	let custom_tlvs = vec![(65537, vec![0x01, 0x02])];
+	let cur_height = nodes[0].best_block_info().1 + 1;
+	let session_priv = SecretKey::from_slice(&session_priv).unwrap();
+	let onion_keys = onion_utils::construct_onion_keys(&Secp256k1::new(), &route.paths[0], &session_priv).unwrap();
+	let (mut onion_payloads, _, _) = onion_utils::build_onion_payloads(&route.paths[0], 100_000, &Some(payment_secret), cur_height, &None).unwrap();
+	if let msgs::OnionHopDataFormat::NonFinalNode { custom_tlvs: ref mut hop_custom_tlvs, .. } = onion_payloads[0].format {
+		*hop_custom_tlvs = custom_tlvs.clone();
+	} else { panic!(); }
+	payment_event.msgs[0].onion_routing_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, [0; 32], &payment_hash);
+
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	{
+		let requests = requests.lock().unwrap();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].custom_tlvs, custom_tlvs);
+	}
+	fail_back_unforwarded_payment(&nodes, payment_hash, &chan_2);
+}
+
+#[test]
+fn test_forward_policy_time_budgets() {
+	// Forwards which the ForwardPolicyHandler doesn't decide on in time should be failed back or
+	// forwarded as configured.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let requests = Arc::new(Mutex::new(Vec::new()));
+	nodes[1].node.set_forward_policy_handler(Box::new(TestForwardPolicyHandler {
+		decision: ForwardPolicyDecision::Hold, decision_delay: None, requests: Arc::clone(&requests),
+	}));
+	let (_, payment_hash, _) = send_payment_to_forwarding_node(&nodes, 100_000);
+	nodes[1].node.timer_tick_occurred();
+	assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+	nodes[1].node.timer_tick_occurred();
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	fail_back_unforwarded_payment(&nodes, payment_hash, &chan_2);
+	assert!(nodes[1].node.resolve_held_forward(&chan_1.2, 0, true).is_err());
+
+	// A decision which takes too long is discarded for the configured fallback, here forwarding.
+	let mut config = nodes[1].node.get_current_default_configuration();
+	config.forward_policy.max_decision_millis = 1;
+	config.forward_policy.fail_open = true;
+	nodes[1].node.apply_user_config(config);
+	nodes[1].node.set_forward_policy_handler(Box::new(TestForwardPolicyHandler {
+		decision: ForwardPolicyDecision::Deny, decision_delay: Some(core::time::Duration::from_millis(10)),
+		requests: Arc::clone(&requests),
+	}));
+	let (payment_preimage, payment_hash, payment_secret) = send_payment_to_forwarding_node(&nodes, 100_000);
+	assert_eq!(requests.lock().unwrap().len(), 2);
+	check_added_monitors!(nodes[1], 1);
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.remove(0));
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[2]);
+	expect_payment_received!(nodes[2], payment_hash, payment_secret, 100_000);
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+}
+
+#[test]
+fn test_simulate_config_exposure() {
+	// Check that a hypothetical config is evaluated against the HTLCs currently pending on a
+	// channel, including at a higher feerate than the channel's current one.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan_id = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100_000, 0, InitFeatures::known(), InitFeatures::known()).2;
+
+	// One HTLC which is dust at any feerate and one which is only dust at high feerates.
+	let (dust_preimage, ..) = route_payment(&nodes[0], &[&nodes[1]], 10_000);
+	let (payment_preimage, ..) = route_payment(&nodes[0], &[&nodes[1]], 5_000_000);
+
+	let mut config = test_default_channel_config();
+	config.channel_config.max_dust_htlc_exposure_msat = 5_000_000;
+	let exposures = nodes[1].node.simulate_config_exposure(&config, None);
+	assert_eq!(exposures.len(), 1);
+	assert_eq!(exposures[0].channel_id, chan_id);
+	assert_eq!(exposures[0].counterparty_node_id, nodes[0].node.get_our_node_id());
+	assert_eq!(exposures[0].pending_htlcs, 2);
+	assert_eq!(exposures[0].on_holder_tx_dust_exposure_msat, 10_000);
+	assert_eq!(exposures[0].on_counterparty_tx_dust_exposure_msat, 10_000);
+	assert_eq!(exposures[0].inbound_htlc_value_in_flight_msat, 5_010_000);
+	assert!(exposures[0].violations.is_empty());
+
+	// At a high enough feerate both HTLCs become dust, exceeding the dust exposure limit.
+	let exposures = nodes[1].node.simulate_config_exposure(&config, Some(10_000));
+	assert_eq!(exposures[0].on_holder_tx_dust_exposure_msat, 5_010_000);
+	assert_eq!(exposures[0].violations, vec![ConfigLimitViolation::DustHTLCExposure {
+		exposure_msat: 5_010_000, limit_msat: 5_000_000,
+	}]);
+
+	config.channel_config.max_dust_htlc_exposure_msat = 5_000;
+	config.channel_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel = 5;
+	config.channel_handshake_limits.min_max_accepted_htlcs = 100;
+	assert_eq!(nodes[1].node.simulate_config_exposure(&config, None)[0].violations, vec![
+		ConfigLimitViolation::DustHTLCExposure { exposure_msat: 10_000, limit_msat: 5_000 },
+		ConfigLimitViolation::InboundHTLCValueInFlight { value_msat: 5_010_000, limit_msat: 5_000_000 },
+		ConfigLimitViolation::CounterpartyMaxAcceptedHTLCs { counterparty_max_accepted_htlcs: 50, min_max_accepted_htlcs: 100 },
+	]);
+
+	// Outbound HTLCs count towards dust exposure but not inbound value in flight.
+	let exposures = nodes[0].node.simulate_config_exposure(&config, None);
+	assert_eq!(exposures[0].on_holder_tx_dust_exposure_msat, 10_000);
+	assert_eq!(exposures[0].inbound_htlc_value_in_flight_msat, 0);
+
+	claim_payment(&nodes[0], &[&nodes[1]], dust_preimage);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	assert!(nodes[1].node.simulate_config_exposure(&config, Some(10_000))[0].violations.iter()
+		.all(|violation| if let ConfigLimitViolation::CounterpartyMaxAcceptedHTLCs { .. } = violation { true } else { false }));
+}
+
+#[test]
+fn test_apply_user_config() {
+	// Check that a new default config is validated and applied at runtime, updating the config of
+	// existing channels which were using the previous default.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let default_chan_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+	let custom_chan_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+
+	let mut custom_channel_config = nodes[0].node.get_current_default_configuration().channel_config;
+	custom_channel_config.forwarding_fee_base_msat = 42;
+	nodes[0].node.update_channel_config(&nodes[1].node.get_our_node_id(), &[custom_chan_id], &custom_channel_config).unwrap();
+	assert_eq!(nodes[0].node.get_and_clear_pending_msg_events().len(), 1);
+
+	let mut config = nodes[0].node.get_current_default_configuration();
+	config.channel_config.forwarding_fee_base_msat = 1234;
+	config.channel_handshake_config.our_to_self_delay = BREAKDOWN_TIMEOUT * 2;
+	config.accept_inbound_channels = false;
+	let update = nodes[0].node.apply_user_config(config);
+	assert_eq!(update.applied, vec![UserConfigSetting::ChannelConfig, UserConfigSetting::AcceptInboundChannels]);
+	assert_eq!(update.new_channels_only, vec![UserConfigSetting::ChannelHandshakeConfig]);
+	assert!(update.rejected.is_empty());
+
+	let applied_config = nodes[0].node.get_current_default_configuration();
+	assert_eq!(applied_config.channel_config.forwarding_fee_base_msat, 1234);
+	assert_eq!(applied_config.channel_handshake_config.our_to_self_delay, BREAKDOWN_TIMEOUT * 2);
+	assert!(!applied_config.accept_inbound_channels);
+
+	// Only the channel which was using the previous default has its fees updated.
+	let msg_events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 1);
+	match msg_events[0] {
+		MessageSendEvent::BroadcastChannelUpdate { ref msg } => assert_eq!(msg.contents.fee_base_msat, 1234),
+		_ => panic!("Unexpected event"),
+	}
+	for chan in nodes[0].node.list_channels() {
+		let expected_fee_base_msat = if chan.channel_id == default_chan_id { 1234 } else { 42 };
+		assert_eq!(chan.config.unwrap().forwarding_fee_base_msat, expected_fee_base_msat);
+	}
+
+	// Invalid settings are rejected, keeping their previous values, while other changes apply.
+	let mut config = applied_config;
+	config.channel_config.cltv_expiry_delta = MIN_CLTV_EXPIRY_DELTA - 1;
+	config.channel_handshake_config.our_to_self_delay = BREAKDOWN_TIMEOUT - 1;
+	config.accept_inbound_channels = true;
+	let update = nodes[0].node.apply_user_config(config);
+	assert_eq!(update.applied, vec![UserConfigSetting::AcceptInboundChannels]);
+	assert!(update.new_channels_only.is_empty());
+	assert_eq!(update.rejected.iter().map(|(setting, _)| *setting).collect::<Vec<_>>(),
+		vec![UserConfigSetting::ChannelHandshakeConfig, UserConfigSetting::ChannelConfig]);
+	let applied_config = nodes[0].node.get_current_default_configuration();
+	assert_eq!(applied_config.channel_config, nodes[0].node.list_channels().iter()
+		.find(|chan| chan.channel_id == default_chan_id).unwrap().config.unwrap());
+	assert_eq!(applied_config.channel_handshake_config.our_to_self_delay, BREAKDOWN_TIMEOUT * 2);
+	assert!(applied_config.accept_inbound_channels);
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+}
+
+#[test]
+fn test_apply_user_config_reports_all_settings() {
+	// Check that changes to settings which don't need validating are reported as applied.
+	let chanmon_cfgs = create_chanmon_cfgs(1);
+	let node_cfgs = create_node_cfgs(1, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(1, &node_cfgs, &[None]);
+	let nodes = create_network(1, &node_cfgs, &node_chanmgrs);
+
+	let mut config = nodes[0].node.get_current_default_configuration();
+	config.accept_keysend_custom_tlvs = !config.accept_keysend_custom_tlvs;
+	config.trampoline_forwarding.enabled = !config.trampoline_forwarding.enabled;
+	let update = nodes[0].node.apply_user_config(config);
+	assert_eq!(update.applied, vec![UserConfigSetting::AcceptKeysendCustomTlvs, UserConfigSetting::TrampolineForwarding]);
+	assert!(update.new_channels_only.is_empty());
+	assert!(update.rejected.is_empty());
+	let applied_config = nodes[0].node.get_current_default_configuration();
+	assert_eq!(applied_config.accept_keysend_custom_tlvs, config.accept_keysend_custom_tlvs);
+	assert_eq!(applied_config.trampoline_forwarding, config.trampoline_forwarding);
+}
+
+#[test]
+fn test_probe_peer_capabilities() {
+	// Check that a peer's capabilities are reported based on its init features if we're connected
+	// to it, or its node_announcement otherwise.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut config = test_default_channel_config();
+	config.peer_feature_requirements.require_scid_privacy = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[Some(config), None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	let node_b_id = nodes[1].node.get_our_node_id();
+	let node_c_id = nodes[2].node.get_our_node_id();
+
+	// Reconnect to node B claiming only `option_static_remotekey` and
+	// `option_anchors_zero_fee_htlc_tx`.
+	nodes[0].node.peer_disconnected(&node_b_id, false);
+	let features = InitFeatures::from_le_bytes(vec![0, 1 << 5, 1 << 7]);
+	nodes[0].node.peer_connected(&node_b_id, &msgs::Init { features: features.clone(), remote_network_address: None });
+	let capabilities = nodes[0].node.probe_peer_capabilities(&node_b_id, None);
+	assert!(capabilities.is_connected);
+	assert_eq!(capabilities.init_features, Some(features));
+	assert_eq!(capabilities.static_remote_key, PeerCapabilitySupport::Supported);
+	assert_eq!(capabilities.anchors, PeerCapabilitySupport::UnsupportedByUs);
+	assert_eq!(capabilities.dual_funding, PeerCapabilitySupport::Unsupported);
+	assert_eq!(capabilities.zero_conf, PeerCapabilitySupport::UnsupportedByPeer);
+	assert_eq!(capabilities.scid_privacy, PeerCapabilitySupport::UnsupportedByPeer);
+	assert_eq!(capabilities.missing_required_features, vec!["option_scid_alias"]);
+
+	// Once disconnected from node C, we fall back to its node_announcement, if we have one.
+	nodes[0].node.peer_disconnected(&node_c_id, false);
+	nodes[2].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+	let capabilities = nodes[0].node.probe_peer_capabilities(&node_c_id, Some(&nodes[0].network_graph.read_only()));
+	assert!(!capabilities.is_connected);
+	assert_eq!(capabilities.zero_conf, PeerCapabilitySupport::Unknown);
+	assert_eq!(capabilities.taproot, PeerCapabilitySupport::Unknown);
+
+	// Announcing a channel between B and C also has C's node_announcement reach us.
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let capabilities = nodes[0].node.probe_peer_capabilities(&node_c_id, Some(&nodes[0].network_graph.read_only()));
+	assert!(!capabilities.is_connected);
+	assert_eq!(capabilities.announced_features, Some(NodeFeatures::known()));
+	assert_eq!(capabilities.static_remote_key, PeerCapabilitySupport::Supported);
+	assert_eq!(capabilities.zero_conf, PeerCapabilitySupport::Supported);
+	assert_eq!(capabilities.anchors, PeerCapabilitySupport::Unsupported);
+	assert!(capabilities.missing_required_features.is_empty());
+}
+
+#[test]
+fn test_inbound_volume_threshold_events() {
+	// Check that we generate an event for each configured threshold the value of payments claimed
+	// within a window crosses, and that the value is reset once the window elapses.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let mut config = test_default_channel_config();
+	config.inbound_volume.thresholds_msat = [Some(1_500_000), Some(2_500_000), Some(2_000_000), None];
+	config.inbound_volume.window_ticks = 2;
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(config)]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let pay_and_claim = |amount_msat: u64| -> Vec<(u64, u64, u64)> {
+		let (payment_preimage, payment_hash, _) = route_payment(&nodes[0], &[&nodes[1]], amount_msat);
+		nodes[1].node.claim_funds(payment_preimage);
+		check_added_monitors!(nodes[1], 1);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		match events[0] {
+			Event::PaymentClaimed { payment_hash: ref claimed_hash, .. } => assert_eq!(*claimed_hash, payment_hash),
+			_ => panic!("Unexpected event"),
+		}
+		let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+		nodes[0].node.handle_update_fulfill_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fulfill_htlcs[0]);
+		commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+		expect_payment_sent!(nodes[0], payment_preimage);
+		events[1..].iter().map(|event| match event {
+			&Event::InboundVolumeThresholdReached { threshold_msat, received_msat, payment_count, window_ticks } => {
+				assert_eq!(window_ticks, 2);
+				(threshold_msat, received_msat, payment_count)
+			},
+			_ => panic!("Unexpected event"),
+		}).collect()
+	};
+
+	assert!(pay_and_claim(1_000_000).is_empty());
+	// A single payment crossing several thresholds generates an event for each, in order.
+	assert_eq!(pay_and_claim(1_000_000), vec![(1_500_000, 2_000_000, 2), (2_000_000, 2_000_000, 2)]);
+	assert_eq!(pay_and_claim(1_000_000), vec![(2_500_000, 3_000_000, 3)]);
+	assert!(pay_and_claim(1_000_000).is_empty());
+
+	// Once the window elapses, the received value is reset and thresholds may be reached again.
+	nodes[1].node.timer_tick_occurred();
+	nodes[1].node.timer_tick_occurred();
+	assert_eq!(pay_and_claim(2_000_000), vec![(1_500_000, 2_000_000, 1), (2_000_000, 2_000_000, 1)]);
+}
+
+#[test]
+fn test_extension_data_persisted() {
+	// Check that extensions may store opaque data alongside channels and the node once they've
+	// registered their type, and that the data survives a reload even without re-registering.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let channel_id = chan.2;
+	let node_b_id = nodes[1].node.get_our_node_id();
+
+	let extension_type = extension_type_for_name("test-extension");
+	assert!(nodes[0].node.set_node_extension_data(extension_type, Some(vec![1])).is_err());
+	assert!(nodes[0].node.register_extension(MIN_EXTENSION_TYPE - 1, "reserved".to_owned()).is_err());
+	nodes[0].node.register_extension(extension_type, "test-extension".to_owned()).unwrap();
+	assert!(nodes[0].node.register_extension(extension_type, "other-extension".to_owned()).is_err());
+
+	nodes[0].node.set_node_extension_data(extension_type, Some(vec![1, 2, 3])).unwrap();
+	nodes[0].node.set_channel_extension_data(&channel_id, &node_b_id, extension_type, Some(vec![4; 100])).unwrap();
+	match nodes[0].node.set_channel_extension_data(&channel_id, &nodes[0].node.get_our_node_id(), extension_type, None) {
+		Err(APIError::APIMisuseError { .. }) => {},
+		_ => panic!(),
+	}
+	match nodes[0].node.set_channel_extension_data(&[42; 32], &node_b_id, extension_type, None) {
+		Err(APIError::ChannelUnavailable { .. }) => {},
+		_ => panic!(),
+	}
+	assert_eq!(nodes[0].node.get_node_extension_data(extension_type), Some(vec![1, 2, 3]));
+	assert_eq!(nodes[0].node.get_channel_extension_data(&channel_id, extension_type), Some(vec![4; 100]));
+	assert_eq!(nodes[0].node.get_channel_extension_data(&channel_id, extension_type + 1), None);
+
+	let logger = test_utils::TestLogger::new();
+	let fee_estimator = test_utils::TestFeeEstimator { sat_per_kw: Mutex::new(253) };
+	let keys_manager = &chanmon_cfgs[0].keys_manager;
+	let (_, mut monitor) = <(BlockHash, ChannelMonitor<EnforcingSigner>)>::read(
+		&mut io::Cursor::new(&get_monitor!(nodes[0], channel_id).encode()), keys_manager).unwrap();
+	let mut channel_monitors = HashMap::new();
+	channel_monitors.insert(monitor.get_funding_txo().0, &mut monitor);
+	let (_, reloaded_manager) = <(BlockHash, ChannelManager<EnforcingSigner, &test_utils::TestChainMonitor, &test_utils::TestBroadcaster, &test_utils::TestKeysInterface, &test_utils::TestFeeEstimator, &test_utils::TestLogger>)>::read(
+		&mut io::Cursor::new(&nodes[0].node.encode()), ChannelManagerReadArgs {
+			default_config: UserConfig::default(),
+			keys_manager,
+			fee_estimator: &fee_estimator,
+			chain_monitor: nodes[0].chain_monitor,
+			tx_broadcaster: nodes[0].tx_broadcaster.clone(),
+			logger: &logger,
+			channel_monitors,
+			role: NodeRole::Active,
+		}).unwrap();
+	assert_eq!(reloaded_manager.get_node_extension_data(extension_type), Some(vec![1, 2, 3]));
+	assert_eq!(reloaded_manager.get_channel_extension_data(&channel_id, extension_type), Some(vec![4; 100]));
+	// Registrations aren't persisted.
+	assert!(reloaded_manager.set_node_extension_data(extension_type, None).is_err());
+
+	nodes[0].node.set_node_extension_data(extension_type, None).unwrap();
+	assert_eq!(nodes[0].node.get_node_extension_data(extension_type), None);
+}
+
+fn do_test_nonstrict_forwarding(nonstrict_forwarding: bool) {
+	// Check that, if enabled, an HTLC is forwarded over another channel with the requested peer if
+	// the requested channel can't carry it, and that it's otherwise failed back.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut forwarding_config = test_default_channel_config();
+	forwarding_config.nonstrict_forwarding = nonstrict_forwarding;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(forwarding_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 0, InitFeatures::known(), InitFeatures::known());
+	// Our counterparty only lets us have 10% of each channel's value in flight, so only the
+	// second channel can carry the payment.
+	let small_chan = create_announced_chan_between_nodes_with_value(&nodes, 1, 2, 100_000, 0, InitFeatures::known(), InitFeatures::known());
+	let large_chan = create_announced_chan_between_nodes_with_value(&nodes, 1, 2, 1_000_000, 0, InitFeatures::known(), InitFeatures::known());
+
+	let (mut route, payment_hash, payment_preimage, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[2], 20_000_000);
+	route.paths[0][1].short_channel_id = small_chan.0.contents.short_channel_id;
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	if !nonstrict_forwarding {
+		expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[1],
+			vec![HTLCDestination::NextHopChannel { node_id: Some(nodes[2].node.get_our_node_id()), channel_id: small_chan.2 }]);
+		check_added_monitors!(nodes[1], 1);
+		let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+		nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+		commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false);
+		expect_payment_failed_conditions(&nodes[0], payment_hash, false,
+			PaymentFailedConditions::new().blamed_scid(small_chan.0.contents.short_channel_id).blamed_chan_closed(false));
+		return;
+	}
+
+	check_added_monitors!(nodes[1], 1);
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let forward_event = SendEvent::from_event(events.pop().unwrap());
+	assert_eq!(forward_event.msgs[0].channel_id, large_chan.2);
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &forward_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], forward_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[2]);
+	expect_payment_received!(nodes[2], payment_hash, payment_secret, 20_000_000);
+
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+}
+
+#[test]
+fn test_nonstrict_forwarding() {
+	do_test_nonstrict_forwarding(true);
+	do_test_nonstrict_forwarding(false);
+}
+
+#[test]
+fn test_gossip_refresh() {
+	// Test that we re-broadcast our channel_updates and node_announcement before other nodes would
+	// consider them stale, both from timer_tick_occurred and when forced to.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let scid = chan.0.contents.short_channel_id;
+
+	// Block header times in tests are their heights, so the current height is the current time.
+	let start_time = nodes[0].best_block_info().1;
+	let connect_block_at_time = |time: u32| {
+		let block = Block {
+			header: BlockHeader { version: 0x2000000, prev_blockhash: nodes[0].best_block_hash(), merkle_root: TxMerkleNode::all_zeros(), time, bits: 42, nonce: 42 },
+			txdata: vec![],
+		};
+		connect_block(&nodes[0], &block);
+	};
+	let check_refreshed_gossip = |min_timestamp: u32| -> u32 {
+		let msg_events = nodes[0].node.get_and_clear_pending_msg_events();
+		assert_eq!(msg_events.len(), 3);
+		let update_timestamp = match msg_events[0] {
+			MessageSendEvent::BroadcastChannelUpdate { ref msg } => {
+				assert_eq!(msg.contents.short_channel_id, scid);
+				assert_eq!(msg.contents.flags & (1<<1), 0);
+				assert!(msg.contents.timestamp >= min_timestamp);
+				msg.contents.timestamp
+			},
+			_ => panic!("Unexpected event"),
+		};
+		match msg_events[1] {
+			MessageSendEvent::BroadcastChannelAnnouncement { ref msg, ref update_msg } => {
+				assert_eq!(msg.contents.short_channel_id, scid);
+				assert_eq!(update_msg.contents.timestamp, update_timestamp);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		match msg_events[2] {
+			MessageSendEvent::BroadcastNodeAnnouncement { ref msg } => {
+				assert_eq!(msg.contents.node_id, nodes[0].node.get_our_node_id());
+				assert_eq!(msg.contents.alias, [0; 32]);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		update_timestamp
+	};
+
+	nodes[0].node.timer_tick_occurred();
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+
+	// Even with the maximum jitter, nothing is due for a refresh yet...
+	connect_block_at_time(start_time + GOSSIP_REFRESH_INTERVAL_SECS - GOSSIP_REFRESH_JITTER_SECS);
+	nodes[0].node.timer_tick_occurred();
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+
+	// ...but once the full refresh interval has passed, all of our gossip is.
+	let refresh_time = start_time + GOSSIP_REFRESH_INTERVAL_SECS;
+	connect_block_at_time(refresh_time);
+	nodes[0].node.timer_tick_occurred();
+	let refreshed_timestamp = check_refreshed_gossip(refresh_time);
+
+	nodes[0].node.timer_tick_occurred();
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+
+	// Forcing a refresh re-broadcasts everything immediately, with a newer channel_update.
+	nodes[0].node.force_gossip_refresh();
+	check_refreshed_gossip(refreshed_timestamp + 1);
+
+	nodes[0].node.timer_tick_occurred();
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+}
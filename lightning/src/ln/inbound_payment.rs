@@ -19,6 +19,7 @@ use ln::{PaymentHash, PaymentPreimage, PaymentSecret};
 use ln::msgs;
 use ln::msgs::MAX_VALUE_MSAT;
 use util::chacha20::ChaCha20;
+use util::config::OverpaymentPolicy;
 use util::crypto::hkdf_extract_expand_thrice;
 use util::errors::APIError;
 use util::logger::Logger;
@@ -200,7 +201,7 @@ fn construct_payment_secret(iv_bytes: &[u8; IV_LEN], metadata_bytes: &[u8; METAD
 /// [`KeysInterface::get_inbound_payment_key_material`]: crate::chain::keysinterface::KeysInterface::get_inbound_payment_key_material
 /// [`create_inbound_payment`]: crate::ln::channelmanager::ChannelManager::create_inbound_payment
 /// [`create_inbound_payment_for_hash`]: crate::ln::channelmanager::ChannelManager::create_inbound_payment_for_hash
-pub(super) fn verify<L: Deref>(payment_hash: PaymentHash, payment_data: &msgs::FinalOnionHopData, highest_seen_timestamp: u64, keys: &ExpandedKey, logger: &L) -> Result<Option<PaymentPreimage>, ()>
+pub(super) fn verify<L: Deref>(payment_hash: PaymentHash, payment_data: &msgs::FinalOnionHopData, highest_seen_timestamp: u64, keys: &ExpandedKey, overpayment_policy: &OverpaymentPolicy, logger: &L) -> Result<Option<PaymentPreimage>, ()>
 	where L::Target: Logger
 {
 	let (iv_bytes, metadata_bytes) = decrypt_metadata(payment_data.payment_secret, keys);
@@ -246,6 +247,11 @@ pub(super) fn verify<L: Deref>(payment_hash: PaymentHash, payment_data: &msgs::F
 		return Err(())
 	}
 
+	if min_amt_msat != 0 && !overpayment_policy.allows(payment_data.total_msat, min_amt_msat) {
+		log_trace!(logger, "Failing HTLC with payment_hash {} due to total_msat {} overpaying the expected amount of {} msat", log_bytes!(payment_hash.0), payment_data.total_msat, min_amt_msat);
+		return Err(())
+	}
+
 	if expiry < highest_seen_timestamp {
 		log_trace!(logger, "Failing HTLC with payment_hash {}: expired payment", log_bytes!(payment_hash.0));
 		return Err(())
@@ -32,6 +32,8 @@ pub mod peer_handler;
 pub mod chan_utils;
 pub mod features;
 pub mod script;
+pub mod swap;
+pub mod channel_request;
 
 #[cfg(fuzzing)]
 pub mod peer_channel_encryptor;
@@ -44,6 +46,7 @@ pub mod channel;
 pub(crate) mod channel;
 
 pub(crate) mod onion_utils;
+pub(crate) mod blinded_payment;
 pub mod wire;
 
 // Older rustc (which we support) refuses to let us call the get_payment_preimage_hash!() macro
@@ -9,21 +9,33 @@
 
 //! Further functional tests which test blockchain reorganizations.
 
-use chain::channelmonitor::{ANTI_REORG_DELAY, Balance};
+use chain::{BlockProvider, Filter, WatchedOutput};
+use chain::channelmonitor::{ANTI_REORG_DELAY, Balance, ChannelMonitor};
+use chain::chainmonitor::{MonitorUpdateId, Persist, TimelockedBalance, TimelockedBalanceSource};
+use chain::replication::{ClaimAuthority, MonitorReplicationMessage, MonitorReplicationTransport, ReplicatingPersister, ReplicationError, WatchingService};
 use chain::transaction::OutPoint;
-use chain::chaininterface::LowerBoundedFeeEstimator;
+use chain::keysinterface::SpendableOutputDescriptor;
+use chain::chaininterface::{BroadcasterInterface, BroadcastRejectReason, BroadcastResult, LowerBoundedFeeEstimator};
 use ln::channel;
-use ln::channelmanager::BREAKDOWN_TIMEOUT;
+use ln::channelmanager::{BREAKDOWN_TIMEOUT, ChannelManager, ChannelManagerReadArgs, NodeRole};
 use ln::features::InitFeatures;
+use ln::msgs;
 use ln::msgs::ChannelMessageHandler;
+use util::config::UserConfig;
+use util::enforcing_trait_impls::EnforcingSigner;
+use util::errors::APIError;
 use util::events::{Event, MessageSendEvent, MessageSendEventsProvider, ClosureReason, HTLCDestination};
+use util::ser::{Readable, ReadableArgs, Writeable};
+use util::test_utils;
 
 use bitcoin::blockdata::script::Builder;
 use bitcoin::blockdata::opcodes;
 use bitcoin::secp256k1::Secp256k1;
-use bitcoin::Transaction;
+use bitcoin::{Block, BlockHash, BlockHeader, Transaction, TxMerkleNode};
+use bitcoin::hashes::Hash;
 
 use prelude::*;
+use sync::{Arc, Mutex};
 
 use ln::functional_test_utils::*;
 
@@ -1630,3 +1642,627 @@ fn test_revoked_counterparty_aggregated_claims() {
 	test_spendable_output(&nodes[1], &claim_txn_2[0]);
 	assert!(nodes[1].chain_monitor.chain_monitor.get_monitor(funding_outpoint).unwrap().get_claimable_balances().is_empty());
 }
+
+#[test]
+fn test_commitment_transactions_snapshot() {
+	// Check that the snapshot of commitment transactions returned by a ChannelMonitor matches the
+	// transactions which would actually be broadcast.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3_000_000).0;
+
+	let snapshot = get_monitor!(nodes[0], chan_id).get_commitment_transactions_snapshot(true);
+	let holder_txn = get_local_commitment_txn!(nodes[0], chan_id);
+	assert_eq!(holder_txn.len(), 2);
+	assert_eq!(snapshot.holder_commitment_tx.txid(), holder_txn[0].txid());
+	assert!(snapshot.holder_commitment_tx.input[0].witness.is_empty());
+	assert!(snapshot.holder_commitment_counterparty_sig.is_some());
+	assert_eq!(snapshot.holder_htlc_txn.len(), 1);
+	assert!(snapshot.holder_htlc_txn[0].htlc.offered);
+	assert_eq!(snapshot.holder_htlc_txn[0].transaction.txid(), holder_txn[1].txid());
+	assert!(snapshot.holder_htlc_txn[0].counterparty_sig.is_some());
+
+	let counterparty_txn = get_local_commitment_txn!(nodes[1], chan_id);
+	assert_eq!(snapshot.counterparty_commitment_txid, Some(counterparty_txn[0].txid()));
+	assert_eq!(snapshot.counterparty_htlcs.len(), 1);
+	assert!(!snapshot.counterparty_htlcs[0].offered);
+
+	// Signatures are redacted on request.
+	let redacted_snapshot = get_monitor!(nodes[0], chan_id).get_commitment_transactions_snapshot(false);
+	assert!(redacted_snapshot.holder_commitment_counterparty_sig.is_none());
+	assert!(redacted_snapshot.holder_htlc_txn[0].counterparty_sig.is_none());
+	assert_eq!(redacted_snapshot.holder_commitment_tx, snapshot.holder_commitment_tx);
+
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+}
+
+#[test]
+fn test_emergency_close_bundle() {
+	// Check that an exported emergency close bundle contains valid transactions closing the
+	// channel and sweeping our funds to the requested destination, without preventing further
+	// updates to the channel.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let (_, _, chan_id, funding_tx) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let funding_outpoint = OutPoint { txid: funding_tx.txid(), index: 0 };
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3_000_000).0;
+
+	let secp_ctx = Secp256k1::new();
+	let cold_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	let sign_sweep = |descriptor: &SpendableOutputDescriptor| {
+		nodes[0].keys_manager.backing.spend_spendable_outputs(&[descriptor], Vec::new(), cold_script.clone(), 253, &secp_ctx)
+	};
+	let mut bundles = nodes[0].chain_monitor.chain_monitor.get_emergency_close_bundles(sign_sweep);
+	assert_eq!(bundles.len(), 1);
+	let (bundle_funding_outpoint, bundle) = bundles.pop().unwrap();
+	assert_eq!(bundle_funding_outpoint, funding_outpoint);
+
+	// The bundle matches what we'd broadcast ourselves, with our delayed outputs on both the
+	// commitment and HTLC-Timeout transactions swept to the cold destination.
+	let holder_txn = get_local_commitment_txn!(nodes[0], chan_id);
+	assert_eq!(bundle.commitment_tx, holder_txn[0]);
+	check_spends!(bundle.commitment_tx, funding_tx);
+	assert_eq!(bundle.htlc_txn.len(), 1);
+	check_spends!(bundle.htlc_txn[0], bundle.commitment_tx);
+	assert_eq!(bundle.sweep_txn.len(), 2);
+	check_spends!(bundle.sweep_txn[0], bundle.commitment_tx);
+	check_spends!(bundle.sweep_txn[1], bundle.htlc_txn[0]);
+	for sweep_tx in bundle.sweep_txn.iter() {
+		assert_eq!(sweep_tx.output.len(), 1);
+		assert_eq!(sweep_tx.output[0].script_pubkey, cold_script);
+	}
+
+	// Sweeps the signer refuses are omitted.
+	let refusing_bundle = get_monitor!(nodes[0], chan_id).get_emergency_close_bundle(|_| Err(()), &nodes[0].logger).unwrap();
+	assert_eq!(refusing_bundle.commitment_tx, bundle.commitment_tx);
+	assert!(refusing_bundle.sweep_txn.is_empty());
+
+	// Unlike signing our latest commitment transaction for broadcast, exporting a bundle doesn't
+	// prevent the channel from moving forward, after which a new bundle must be exported.
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	let new_bundle = get_monitor!(nodes[0], chan_id).get_emergency_close_bundle(sign_sweep, &nodes[0].logger).unwrap();
+	assert!(new_bundle.commitment_number < bundle.commitment_number);
+	assert_ne!(new_bundle.commitment_tx, bundle.commitment_tx);
+	assert!(new_bundle.htlc_txn.is_empty());
+	assert_eq!(new_bundle.sweep_txn.len(), 1);
+
+	// Once the funding output has been spent, no bundle is exported.
+	mine_transaction(&nodes[0], &new_bundle.commitment_tx);
+	check_closed_broadcast!(nodes[0], true);
+	check_added_monitors!(nodes[0], 1);
+	check_closed_event!(nodes[0], 1, ClosureReason::CommitmentTxConfirmed);
+	assert!(nodes[0].chain_monitor.chain_monitor.get_emergency_close_bundles(sign_sweep).is_empty());
+}
+
+#[test]
+fn test_timelocked_balances() {
+	// Check that the timeline of when closed-channel funds become spendable is built from the
+	// monitors' claimable balances, skipping channels which are open and not closing.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let (_, _, chan_id, funding_tx) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let funding_txo = OutPoint { txid: funding_tx.txid(), index: 0 };
+	route_payment(&nodes[0], &[&nodes[1]], 3_000_000);
+	let htlc_cltv_timeout = nodes[0].best_block_info().1 + TEST_FINAL_CLTV + 1;
+	let best_height = nodes[0].best_block_info().1;
+
+	let chain_monitor = &nodes[0].chain_monitor.chain_monitor;
+	let channels = nodes[0].node.list_channels();
+	assert!(!channels[0].is_shutting_down);
+	assert!(chain_monitor.get_timelocked_balances(&channels.iter().collect::<Vec<_>>()).is_empty());
+
+	// Without the channel in the list of open channels, we assume it has been closed and our own
+	// commitment transaction will confirm.
+	let to_self_amount = chain_monitor.get_monitor(funding_txo).unwrap().get_claimable_balances().iter()
+		.find_map(|balance| if let Balance::ClaimableOnChannelClose { claimable_amount_satoshis } = balance { Some(*claimable_amount_satoshis) } else { None })
+		.unwrap();
+	assert_eq!(chain_monitor.get_timelocked_balances(&[]), vec![TimelockedBalance {
+		spendable_height: htlc_cltv_timeout, amount_satoshis: 3_000,
+		source: TimelockedBalanceSource::HTLCTimeoutClaim, funding_txo,
+	}, TimelockedBalance {
+		spendable_height: best_height + ANTI_REORG_DELAY + BREAKDOWN_TIMEOUT as u32, amount_satoshis: to_self_amount,
+		source: TimelockedBalanceSource::UnconfirmedClose, funding_txo,
+	}]);
+
+	// Once we start a cooperative close, our balance becomes spendable once the closing
+	// transaction confirms.
+	nodes[0].node.close_channel(&chan_id, &nodes[1].node.get_our_node_id()).unwrap();
+	get_event_msg!(nodes[0], MessageSendEvent::SendShutdown, nodes[1].node.get_our_node_id());
+	let channels = nodes[0].node.list_channels();
+	assert!(channels[0].is_shutting_down);
+	assert_eq!(chain_monitor.get_timelocked_balances(&channels.iter().collect::<Vec<_>>()), vec![TimelockedBalance {
+		spendable_height: best_height + ANTI_REORG_DELAY, amount_satoshis: to_self_amount,
+		source: TimelockedBalanceSource::CooperativeClose, funding_txo,
+	}, TimelockedBalance {
+		spendable_height: htlc_cltv_timeout, amount_satoshis: 3_000,
+		source: TimelockedBalanceSource::HTLCTimeoutClaim, funding_txo,
+	}]);
+
+	// After our commitment transaction confirms, our balance is spendable once its CSV expires.
+	let commitment_tx = get_local_commitment_txn!(nodes[0], chan_id).swap_remove(0);
+	mine_transaction(&nodes[0], &commitment_tx);
+	check_closed_broadcast!(nodes[0], true);
+	check_added_monitors!(nodes[0], 1);
+	check_closed_event!(nodes[0], 1, ClosureReason::CommitmentTxConfirmed);
+	assert!(nodes[0].node.list_channels().is_empty());
+	assert_eq!(chain_monitor.get_timelocked_balances(&[]), vec![TimelockedBalance {
+		spendable_height: htlc_cltv_timeout, amount_satoshis: 3_000,
+		source: TimelockedBalanceSource::HTLCTimeoutClaim, funding_txo,
+	}, TimelockedBalance {
+		spendable_height: best_height + BREAKDOWN_TIMEOUT as u32, amount_satoshis: to_self_amount,
+		source: TimelockedBalanceSource::ConfirmedCloseOutput, funding_txo,
+	}]);
+}
+
+struct TestBlockProvider {
+	start_height: u32,
+	blocks: Vec<Block>,
+}
+
+impl BlockProvider for TestBlockProvider {
+	fn get_block(&self, height: u32) -> Option<Block> {
+		if height < self.start_height { return None; }
+		self.blocks.get((height - self.start_height) as usize).cloned()
+	}
+}
+
+#[test]
+fn test_replay_from_height() {
+	// Check that replaying correct chain data into a ChannelMonitor which was fed blocks missing a
+	// counterparty commitment transaction results in it seeing the commitment transaction.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+	let commitment_tx = get_local_commitment_txn!(nodes[1], chan_id).swap_remove(0);
+
+	// A buggy chain source feeds nodes[0] two blocks without the commitment transaction.
+	let (fork_point_hash, fork_point_height) = nodes[0].best_block_info();
+	connect_blocks(&nodes[0], 2);
+	let replay_height = fork_point_height + 1;
+	assert!(!get_monitor!(nodes[0], chan_id).get_relevant_txids().contains(&commitment_tx.txid()));
+
+	let mut blocks = Vec::new();
+	let mut prev_blockhash = fork_point_hash;
+	for (height, txdata) in vec![(replay_height, vec![commitment_tx.clone()]), (replay_height + 1, Vec::new())] {
+		let block = Block {
+			header: BlockHeader { version: 0x20000000, prev_blockhash, merkle_root: TxMerkleNode::all_zeros(), time: height, bits: 42, nonce: 0xdeadbeef },
+			txdata,
+		};
+		prev_blockhash = block.header.block_hash();
+		blocks.push(block);
+	}
+	let block_provider = TestBlockProvider { start_height: replay_height, blocks };
+
+	let fee_estimator = &chanmon_cfgs[0].fee_estimator;
+	let monitor = get_monitor!(nodes[0], chan_id);
+	assert!(monitor.replay_from_height(0, &block_provider, nodes[0].tx_broadcaster, fee_estimator, nodes[0].logger).is_err());
+	assert!(monitor.replay_from_height(replay_height + 2, &block_provider, nodes[0].tx_broadcaster, fee_estimator, nodes[0].logger).is_err());
+	let watch_outputs = monitor.replay_from_height(replay_height, &block_provider, nodes[0].tx_broadcaster, fee_estimator, nodes[0].logger).unwrap();
+	for (txid, outputs) in watch_outputs {
+		assert_eq!(txid, commitment_tx.txid());
+		for (idx, output) in outputs {
+			nodes[0].chain_source.register_output(WatchedOutput {
+				block_hash: None, outpoint: OutPoint { txid, index: idx as u16 }, script_pubkey: output.script_pubkey,
+			});
+		}
+	}
+	assert_eq!(monitor.current_best_block().block_hash(), prev_blockhash);
+	assert_eq!(monitor.current_best_block().height(), replay_height + 1);
+	assert!(monitor.get_relevant_txids().contains(&commitment_tx.txid()));
+	// Our balance is now awaiting confirmations of the counterparty's commitment transaction.
+	let balances = monitor.get_claimable_balances();
+	assert_eq!(balances.len(), 1);
+	if let Balance::ClaimableAwaitingConfirmations { confirmation_height, .. } = balances[0] {
+		assert_eq!(confirmation_height, replay_height + ANTI_REORG_DELAY - 1);
+	} else { panic!("Unexpected balance {:?}", balances[0]); }
+}
+
+struct RejectingBroadcaster {
+	txn_broadcasted: Mutex<Vec<Transaction>>,
+}
+
+impl BroadcasterInterface for RejectingBroadcaster {
+	fn broadcast_transaction(&self, tx: &Transaction) {
+		self.txn_broadcasted.lock().unwrap().push(tx.clone());
+	}
+	fn broadcast_transaction_with_result(&self, tx: &Transaction) -> Option<BroadcastResult> {
+		self.broadcast_transaction(tx);
+		Some(BroadcastResult::Rejected(BroadcastRejectReason::InsufficientFee))
+	}
+}
+
+#[test]
+fn test_claim_bumped_on_broadcast_rejection() {
+	// Check that a claim transaction which our broadcaster reports was rejected for insufficient
+	// fee is immediately bumped, whether the result is provided synchronously or not.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan_id = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 500_000_000, InitFeatures::known(), InitFeatures::known()).2;
+
+	let revoked_local_txn = get_local_commitment_txn!(nodes[1], chan_id);
+	assert_eq!(revoked_local_txn.len(), 1);
+	send_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+
+	mine_transaction(&nodes[0], &revoked_local_txn[0]);
+	check_closed_broadcast!(nodes[0], true);
+	check_added_monitors!(nodes[0], 1);
+	check_closed_event!(nodes[0], 1, ClosureReason::CommitmentTxConfirmed);
+	let justice_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0).into_iter()
+		.find(|tx| tx.input[0].previous_output.txid == revoked_local_txn[0].txid()).unwrap();
+
+	// Results for unknown transactions and results other than an insufficient fee rejection are
+	// ignored.
+	let chain_monitor = &nodes[0].chain_monitor.chain_monitor;
+	chain_monitor.provide_broadcast_result(&revoked_local_txn[0].txid(), BroadcastResult::Rejected(BroadcastRejectReason::InsufficientFee));
+	chain_monitor.provide_broadcast_result(&justice_tx.txid(), BroadcastResult::Accepted);
+	chain_monitor.provide_broadcast_result(&justice_tx.txid(), BroadcastResult::Rejected(BroadcastRejectReason::Other("non-final".to_owned())));
+	assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+	chain_monitor.provide_broadcast_result(&justice_tx.txid(), BroadcastResult::Rejected(BroadcastRejectReason::InsufficientFee));
+	let bumped_tx = {
+		let mut txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(txn.len(), 1);
+		txn.pop().unwrap()
+	};
+	check_spends!(bumped_tx, revoked_local_txn[0]);
+	assert!(bumped_tx.output[0].value < justice_tx.output[0].value);
+
+	// A broadcaster which keeps rejecting our claims synchronously only sees a bounded number of
+	// bumps.
+	let rejecting_broadcaster = RejectingBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+	get_monitor!(nodes[0], chan_id).provide_broadcast_result(&bumped_tx.txid(),
+		BroadcastResult::Rejected(BroadcastRejectReason::InsufficientFee), &rejecting_broadcaster, &chanmon_cfgs[0].fee_estimator, nodes[0].logger);
+	let rebumped_txn = rejecting_broadcaster.txn_broadcasted.lock().unwrap().split_off(0);
+	assert_eq!(rebumped_txn.len(), 3);
+	let mut prev_value = bumped_tx.output[0].value;
+	for tx in rebumped_txn {
+		check_spends!(tx, revoked_local_txn[0]);
+		assert!(tx.output[0].value < prev_value);
+		prev_value = tx.output[0].value;
+	}
+}
+
+#[test]
+fn test_claim_rebroadcast_on_mempool_eviction() {
+	// Check that claim transactions missing from the mempool are rebroadcast, and bumped if they
+	// are evicted repeatedly.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan_id = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 500_000_000, InitFeatures::known(), InitFeatures::known()).2;
+
+	let revoked_local_txn = get_local_commitment_txn!(nodes[1], chan_id);
+	send_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+
+	let chain_monitor = &nodes[0].chain_monitor.chain_monitor;
+	assert!(chain_monitor.get_unconfirmed_claim_txids().is_empty());
+	mine_transaction(&nodes[0], &revoked_local_txn[0]);
+	check_closed_broadcast!(nodes[0], true);
+	check_added_monitors!(nodes[0], 1);
+	check_closed_event!(nodes[0], 1, ClosureReason::CommitmentTxConfirmed);
+	let justice_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0).into_iter()
+		.find(|tx| tx.input[0].previous_output.txid == revoked_local_txn[0].txid()).unwrap();
+	assert!(chain_monitor.get_unconfirmed_claim_txids().contains(&justice_tx.txid()));
+
+	// While the justice transaction is in the mempool we don't rebroadcast it.
+	let unconfirmed_txids = chain_monitor.get_unconfirmed_claim_txids();
+	chain_monitor.update_mempool_status(&unconfirmed_txids);
+	assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+	// Once it's evicted we rebroadcast it as-is, then bump it if it's evicted again.
+	let mempool_txids: Vec<_> = unconfirmed_txids.into_iter().filter(|txid| *txid != justice_tx.txid()).collect();
+	chain_monitor.update_mempool_status(&mempool_txids);
+	assert_eq!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0), vec![justice_tx.clone()]);
+	chain_monitor.update_mempool_status(&mempool_txids);
+	let bumped_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().pop().unwrap();
+	check_spends!(bumped_tx, revoked_local_txn[0]);
+	assert!(bumped_tx.output[0].value < justice_tx.output[0].value);
+	assert!(chain_monitor.get_unconfirmed_claim_txids().contains(&bumped_tx.txid()));
+	assert!(!chain_monitor.get_unconfirmed_claim_txids().contains(&justice_tx.txid()));
+
+	// Seeing the bumped transaction in the mempool resets its eviction count.
+	let mut unconfirmed_txids = chain_monitor.get_unconfirmed_claim_txids();
+	chain_monitor.update_mempool_status(&unconfirmed_txids);
+	unconfirmed_txids.retain(|txid| *txid != bumped_tx.txid());
+	chain_monitor.update_mempool_status(&unconfirmed_txids);
+	assert_eq!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0), vec![bumped_tx.clone()]);
+
+	// Once the claim confirms we no longer consider it.
+	mine_transaction(&nodes[0], &bumped_tx);
+	assert!(!chain_monitor.get_unconfirmed_claim_txids().contains(&bumped_tx.txid()));
+	nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
+	chain_monitor.update_mempool_status(&[]);
+	assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+}
+
+struct TestReplicationTransport {
+	messages: Mutex<Vec<Vec<u8>>>,
+	fail: Mutex<bool>,
+}
+
+impl MonitorReplicationTransport for TestReplicationTransport {
+	fn send_message(&self, message: &MonitorReplicationMessage) -> Result<(), ()> {
+		if *self.fail.lock().unwrap() { return Err(()); }
+		self.messages.lock().unwrap().push(message.encode());
+		Ok(())
+	}
+}
+
+#[test]
+fn test_monitor_replication_to_watching_service() {
+	// Tests that a WatchingService running only a ChainMonitor is kept current by a
+	// ReplicatingPersister, detects divergence, can be resynced and honors its ClaimAuthority.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let funding_txo = OutPoint { txid: chan.3.txid(), index: 0 };
+
+	let node_persister = test_utils::TestPersister::new();
+	let transport = TestReplicationTransport { messages: Mutex::new(Vec::new()), fail: Mutex::new(false) };
+	let replicating_persister = ReplicatingPersister::new(&node_persister, &transport);
+
+	let service_persister = test_utils::TestPersister::new();
+	let service_broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()), blocks: Arc::new(Mutex::new(Vec::new())) };
+	let logger = test_utils::TestLogger::new();
+	let fee_estimator = test_utils::TestFeeEstimator { sat_per_kw: Mutex::new(253) };
+	let service = WatchingService::new(None::<&test_utils::TestChainSource>, &service_broadcaster, &logger,
+		&fee_estimator, &service_persister, &chanmon_cfgs[0].keys_manager, ClaimAuthority::WatchOnly);
+
+	macro_rules! deliver_messages {
+		() => { {
+			let messages: Vec<Vec<u8>> = transport.messages.lock().unwrap().drain(..).collect();
+			messages.iter().map(|message| {
+				service.handle_message(MonitorReplicationMessage::read(&mut &message[..]).unwrap())
+			}).collect::<Vec<_>>()
+		} }
+	}
+	macro_rules! replicate_updates {
+		($skip: expr) => { {
+			let updates = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).unwrap().clone();
+			let monitor = get_monitor!(nodes[0], chan.2);
+			for update in updates.into_iter().skip($skip) {
+				let update_id = MonitorUpdateId::from_monitor_update(&update);
+				replicating_persister.update_persisted_channel(funding_txo, &Some(update), &monitor, update_id).unwrap();
+			}
+		} }
+	}
+	macro_rules! node_update_id {
+		() => { get_monitor!(nodes[0], chan.2).get_latest_update_id() }
+	}
+
+	// Updates for an unknown channel are rejected, after which the initial monitor is accepted and
+	// updates it already includes are ignored.
+	let (payment_preimage, _, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	replicate_updates!(0);
+	assert!(deliver_messages!().iter().all(|res| *res == Err(ReplicationError::UnknownChannel { funding_txo })));
+	{
+		let monitor = get_monitor!(nodes[0], chan.2);
+		replicating_persister.persist_new_channel(funding_txo, &monitor, MonitorUpdateId::from_new_monitor(&monitor)).unwrap();
+	}
+	assert_eq!(deliver_messages!(), vec![Ok(())]);
+	replicate_updates!(0);
+	assert!(deliver_messages!().iter().all(|res| res.is_ok()));
+	assert_eq!(service.list_monitor_update_ids(), vec![(funding_txo, node_update_id!())]);
+
+	// Updates from a new payment are applied.
+	let update_count = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).unwrap().len();
+	let (payment_preimage, _, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	replicate_updates!(update_count);
+	assert!(deliver_messages!().iter().all(|res| res.is_ok()));
+	assert_eq!(service.list_monitor_update_ids(), vec![(funding_txo, node_update_id!())]);
+
+	// A lost message is detected as a sequence gap, which is resolved by catching up.
+	let local_update_id = node_update_id!();
+	let update_count = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).unwrap().len();
+	let (payment_preimage, _, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	replicate_updates!(update_count);
+	let lost_sequence_number = replicating_persister.next_sequence_number() - transport.messages.lock().unwrap().len() as u64;
+	transport.messages.lock().unwrap().remove(0);
+	let results = deliver_messages!();
+	assert_eq!(results[0], Err(ReplicationError::SequenceGap {
+		expected_sequence_number: lost_sequence_number, received_sequence_number: lost_sequence_number + 1,
+	}));
+	// Later updates are still applied where possible while catching up, but diverge here.
+	assert_eq!(results[1], Err(ReplicationError::Diverged {
+		funding_txo, local_update_id, remote_update_id: local_update_id + 3,
+	}));
+	assert!(service.is_catching_up());
+	replicating_persister.catch_up(&[(funding_txo, &*get_monitor!(nodes[0], chan.2))]).unwrap();
+	assert_eq!(deliver_messages!(), vec![Ok(()), Ok(())]);
+	assert!(!service.is_catching_up());
+	assert_eq!(service.list_monitor_update_ids(), vec![(funding_txo, node_update_id!())]);
+
+	// A divergence within a single channel is resolved by resyncing the monitor.
+	let local_update_id = node_update_id!();
+	let update_count = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).unwrap().len();
+	let (payment_preimage, _, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	replicate_updates!(update_count + 1);
+	assert_eq!(deliver_messages!()[0], Err(ReplicationError::Diverged {
+		funding_txo, local_update_id, remote_update_id: local_update_id + 2,
+	}));
+	replicating_persister.resync_monitor(funding_txo, &*get_monitor!(nodes[0], chan.2)).unwrap();
+	assert_eq!(deliver_messages!(), vec![Ok(())]);
+	assert_eq!(service.list_monitor_update_ids(), vec![(funding_txo, node_update_id!())]);
+
+	// If sending fails, the full monitor is sent with the next update.
+	*transport.fail.lock().unwrap() = true;
+	replicate_updates!(0);
+	assert!(transport.messages.lock().unwrap().is_empty());
+	*transport.fail.lock().unwrap() = false;
+	{
+		let monitor = get_monitor!(nodes[0], chan.2);
+		replicating_persister.update_persisted_channel(funding_txo, &None, &monitor, MonitorUpdateId::from_new_monitor(&monitor)).unwrap();
+	}
+	assert_eq!(transport.messages.lock().unwrap().len(), 1);
+	assert_eq!(deliver_messages!(), vec![Ok(())]);
+
+	// When the node force-closes, the service withholds the commitment transaction its monitor
+	// generates as it has no authority to claim.
+	let update_count = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).unwrap().len();
+	nodes[0].node.force_close_broadcasting_latest_txn(&chan.2, &nodes[1].node.get_our_node_id()).unwrap();
+	check_closed_broadcast!(nodes[0], true);
+	check_added_monitors!(nodes[0], 1);
+	check_closed_event!(nodes[0], 1, ClosureReason::HolderForceClosed);
+	replicate_updates!(update_count);
+	assert_eq!(deliver_messages!(), vec![Ok(())]);
+	let withheld = service.take_withheld_transactions();
+	assert_eq!(withheld.len(), 1);
+	check_spends!(withheld[0], chan.3);
+	assert!(service_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+	service.set_claim_authority(ClaimAuthority::Full);
+	assert_eq!(service.claim_authority(), ClaimAuthority::Full);
+
+	// Redelivered messages are ignored, while a restarted stream is detected as a gap.
+	let sequence_number = replicating_persister.next_sequence_number() - 1;
+	let redelivered = MonitorReplicationMessage::CatchUpComplete { sequence_number };
+	assert_eq!(service.handle_message(redelivered), Ok(()));
+	assert!(!service.is_catching_up());
+	let restarted_persister = ReplicatingPersister::new(&node_persister, &transport);
+	restarted_persister.catch_up(&[(funding_txo, &*get_monitor!(nodes[0], chan.2))]).unwrap();
+	assert_eq!(deliver_messages!(), vec![Err(ReplicationError::SequenceGap {
+		expected_sequence_number: sequence_number + 1, received_sequence_number: 0,
+	}), Ok(())]);
+	assert!(!service.is_catching_up());
+}
+
+#[test]
+fn test_standby_promotion_with_fencing() {
+	// Tests that a standby ChannelManager stays inert until promoted, and that it may only be
+	// promoted once the previously active instance has been fenced and its state matches the
+	// ChannelMonitors replicated to a WatchingService.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let funding_txo = OutPoint { txid: chan.3.txid(), index: 0 };
+
+	let node_persister = test_utils::TestPersister::new();
+	let transport = TestReplicationTransport { messages: Mutex::new(Vec::new()), fail: Mutex::new(false) };
+	let replicating_persister = ReplicatingPersister::new(&node_persister, &transport);
+
+	let service_persister = test_utils::TestPersister::new();
+	let service_broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()), blocks: Arc::new(Mutex::new(Vec::new())) };
+	let logger = test_utils::TestLogger::new();
+	let fee_estimator = test_utils::TestFeeEstimator { sat_per_kw: Mutex::new(253) };
+	let service = WatchingService::new(None::<&test_utils::TestChainSource>, &service_broadcaster, &logger,
+		&fee_estimator, &service_persister, &chanmon_cfgs[0].keys_manager, ClaimAuthority::WatchOnly);
+
+	macro_rules! deliver_messages {
+		() => { {
+			let messages: Vec<Vec<u8>> = transport.messages.lock().unwrap().drain(..).collect();
+			messages.iter().map(|message| {
+				service.handle_message(MonitorReplicationMessage::read(&mut &message[..]).unwrap())
+			}).collect::<Vec<_>>()
+		} }
+	}
+	macro_rules! replicate_updates {
+		($skip: expr) => { {
+			let updates = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).unwrap().clone();
+			let monitor = get_monitor!(nodes[0], chan.2);
+			for update in updates.into_iter().skip($skip) {
+				let update_id = MonitorUpdateId::from_monitor_update(&update);
+				replicating_persister.update_persisted_channel(funding_txo, &Some(update), &monitor, update_id).unwrap();
+			}
+		} }
+	}
+	let standby_broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()), blocks: Arc::new(Mutex::new(Vec::new())) };
+	macro_rules! read_standby {
+		() => { {
+			let serialized = nodes[0].node.encode();
+			<(BlockHash, ChannelManager<EnforcingSigner, _, _, _, _, _>)>::read(&mut &serialized[..], ChannelManagerReadArgs {
+				default_config: UserConfig::default(),
+				keys_manager: &chanmon_cfgs[0].keys_manager,
+				fee_estimator: node_cfgs[0].fee_estimator,
+				chain_monitor: service.chain_monitor(),
+				tx_broadcaster: &standby_broadcaster,
+				logger: &logger,
+				channel_monitors: HashMap::new(),
+				role: NodeRole::Standby,
+			}).unwrap().1
+		} }
+	}
+	macro_rules! read_service_monitor {
+		() => { {
+			let serialized = service.chain_monitor().get_monitor(funding_txo).unwrap().encode();
+			<(BlockHash, ChannelMonitor<EnforcingSigner>)>::read(&mut &serialized[..], &chanmon_cfgs[0].keys_manager).unwrap().1
+		} }
+	}
+
+	{
+		let monitor = get_monitor!(nodes[0], chan.2);
+		replicating_persister.persist_new_channel(funding_txo, &monitor, MonitorUpdateId::from_new_monitor(&monitor)).unwrap();
+	}
+	assert_eq!(deliver_messages!(), vec![Ok(())]);
+
+	// A standby neither accepts API calls nor talks to peers, and may not be persisted.
+	let standby = read_standby!();
+	assert_eq!(standby.role(), NodeRole::Standby);
+	assert!(matches!(standby.create_channel(nodes[1].node.get_our_node_id(), 100_000, 0, 42, None),
+		Err(APIError::APIMisuseError { .. })));
+	assert!(standby.write(&mut Vec::new()).is_err());
+	standby.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known(), remote_network_address: None });
+	let msg_events = standby.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 1);
+	assert!(matches!(msg_events[0], MessageSendEvent::HandleError { action: msgs::ErrorAction::DisconnectPeer { .. }, .. }));
+	assert!(standby.get_and_clear_pending_events().is_empty());
+
+	// Promotion is refused while the active instance may still be operating...
+	let monitor = read_service_monitor!();
+	assert!(matches!(standby.promote(&service, vec![&monitor]), Err(APIError::APIMisuseError { .. })));
+	assert_eq!(standby.role(), NodeRole::Standby);
+
+	// ...and once the replicated monitors are ahead of the standby's state.
+	let update_count = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).map_or(0, |updates| updates.len());
+	let (payment_preimage, _, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	replicate_updates!(update_count);
+	assert!(deliver_messages!().iter().all(|res| res.is_ok()));
+	replicating_persister.step_down().unwrap();
+	assert_eq!(deliver_messages!(), vec![Ok(())]);
+	let monitor = read_service_monitor!();
+	match standby.promote(&service, vec![&monitor]) {
+		Err(APIError::APIMisuseError { err }) => assert!(err.contains("is at update_id")),
+		_ => panic!(),
+	}
+
+	// A standby read from the final state of the stepped-down instance can be promoted, after
+	// which the service stops accepting replication from the previous instance.
+	let standby = read_standby!();
+	standby.promote(&service, vec![&monitor]).unwrap();
+	assert_eq!(standby.role(), NodeRole::Active);
+	let events = standby.get_and_clear_pending_events();
+	assert!(matches!(events.last(), Some(Event::RoleChanged { role: NodeRole::Active })));
+	assert!(matches!(standby.promote(&service, Vec::new()), Err(APIError::APIMisuseError { .. })));
+	replicate_updates!(0);
+	assert!(deliver_messages!().iter().all(|res| *res == Err(ReplicationError::Fenced)));
+	assert_eq!(service.claim_authority(), ClaimAuthority::Full);
+
+	// The promoted instance can in turn step down.
+	standby.step_down();
+	assert_eq!(standby.role(), NodeRole::Standby);
+	let events = standby.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	assert!(matches!(events[0], Event::RoleChanged { role: NodeRole::Standby }));
+	standby.step_down();
+	assert!(standby.get_and_clear_pending_events().is_empty());
+}
@@ -40,9 +40,10 @@ use core::fmt::Debug;
 use io::{self, Read};
 use io_extras::read_to_end;
 
+use util::errors::{ErrorClassification, Retryability};
 use util::events::{MessageSendEventsProvider, OnionMessageProvider};
 use util::logger;
-use util::ser::{BigSize, LengthReadable, Readable, ReadableArgs, Writeable, Writer, FixedLengthReader, HighZeroBytesDroppedBigSize, Hostname};
+use util::ser::{BigSize, LengthRead, LengthReadable, Readable, ReadableArgs, Writeable, Writer, FixedLengthReader, HighZeroBytesDroppedBigSize, Hostname};
 
 use ln::{PaymentPreimage, PaymentHash, PaymentSecret};
 
@@ -304,6 +305,9 @@ pub struct UpdateAddHTLC {
 	/// The expiry height of the HTLC
 	pub cltv_expiry: u32,
 	pub(crate) onion_routing_packet: OnionPacket,
+	/// The blinding point of an HTLC forwarded within a blinded route, which the recipient of the
+	/// message needs to decrypt its onion.
+	pub blinding_point: Option<PublicKey>,
 }
 
  /// An onion message to be sent or received from a peer
@@ -803,6 +807,62 @@ pub struct LightningError {
 	pub action: ErrorAction,
 }
 
+impl ErrorClassification for LightningError {
+	type Cause = LightningErrorCause;
+
+	/// Returns a code depending on the [`ErrorAction`]:
+	///  * 201: [`ErrorAction::DisconnectPeer`]
+	///  * 202: [`ErrorAction::IgnoreError`]
+	///  * 203: [`ErrorAction::IgnoreAndLog`]
+	///  * 204: [`ErrorAction::IgnoreDuplicateGossip`]
+	///  * 205: [`ErrorAction::SendErrorMessage`]
+	///  * 206: [`ErrorAction::SendWarningMessage`]
+	fn error_code(&self) -> u16 {
+		match self.action {
+			ErrorAction::DisconnectPeer { .. } => 201,
+			ErrorAction::IgnoreError => 202,
+			ErrorAction::IgnoreAndLog(_) => 203,
+			ErrorAction::IgnoreDuplicateGossip => 204,
+			ErrorAction::SendErrorMessage { .. } => 205,
+			ErrorAction::SendWarningMessage { .. } => 206,
+		}
+	}
+
+	/// Errors which are merely ignored, such as a failure to find a route, are
+	/// [`Retryability::Later`], while all others are [`Retryability::Never`].
+	fn retryability(&self) -> Retryability {
+		match self.action {
+			ErrorAction::IgnoreError|ErrorAction::IgnoreAndLog(_) => Retryability::Later,
+			_ => Retryability::Never,
+		}
+	}
+
+	fn cause(&self) -> LightningErrorCause {
+		match self.action {
+			ErrorAction::DisconnectPeer { .. } => LightningErrorCause::PeerMisbehaved,
+			ErrorAction::SendErrorMessage { .. } => LightningErrorCause::PeerMisbehaved,
+			ErrorAction::SendWarningMessage { .. } => LightningErrorCause::PeerWarned,
+			ErrorAction::IgnoreError|ErrorAction::IgnoreAndLog(_) => LightningErrorCause::Ignored,
+			ErrorAction::IgnoreDuplicateGossip => LightningErrorCause::DuplicateGossip,
+		}
+	}
+}
+
+/// The cause of a [`LightningError`], as returned by [`ErrorClassification::cause`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LightningErrorCause {
+	/// The peer did something incorrect, and we will send it an error message or disconnect it.
+	PeerMisbehaved,
+	/// The peer did something we will warn it about, without closing any channels.
+	PeerWarned,
+	/// We were unable to process a request or message, which was ignored. This includes failures
+	/// to find a route.
+	Ignored,
+	/// The peer provided us with a gossip message which we'd already seen.
+	DuplicateGossip,
+}
+
 /// Struct used to return values from revoke_and_ack messages, containing a bunch of commitment
 /// transaction updates if they were pending.
 #[derive(Clone, Debug, PartialEq)]
@@ -952,12 +1012,13 @@ pub trait OnionMessageHandler : OnionMessageProvider {
 }
 
 mod fuzzy_internal_msgs {
+	use bitcoin::secp256k1::PublicKey;
 	use prelude::*;
 	use ln::{PaymentPreimage, PaymentSecret};
 
 	// These types aren't intended to be pub, but are exposed for direct fuzzing (as we deserialize
 	// them from untrusted input):
-	#[derive(Clone)]
+	#[derive(Clone, PartialEq)]
 	pub(crate) struct FinalOnionHopData {
 		pub(crate) payment_secret: PaymentSecret,
 		/// The total value, in msat, of the payment as received by the ultimate recipient.
@@ -971,10 +1032,41 @@ mod fuzzy_internal_msgs {
 		},
 		NonFinalNode {
 			short_channel_id: u64,
+			/// Any TLVs of an odd type in the custom range (at or above 2^16), sorted by type, which
+			/// the sender included for us as a forwarding node.
+			custom_tlvs: Vec<(u64, Vec<u8>)>,
 		},
 		FinalNode {
 			payment_data: Option<FinalOnionHopData>,
 			keysend_preimage: Option<PaymentPreimage>,
+			/// Any TLVs of an odd type in the custom range (at or above 2^16) other than
+			/// `keysend_preimage`, sorted by type, such as the metadata senders attach to keysend
+			/// payments.
+			custom_tlvs: Vec<(u64, Vec<u8>)>,
+		},
+		/// The final hop of the outer onion of a trampoline payment, where the recipient is a
+		/// trampoline node which should route the payment as instructed by `trampoline_packet`.
+		TrampolineEntrypoint {
+			payment_data: FinalOnionHopData,
+			trampoline_packet: TrampolineOnionPacket,
+		},
+		/// A hop within a trampoline onion, instructing a trampoline node to route the payment to
+		/// `outgoing_node_id`. If `payment_data` is set, `outgoing_node_id` is the final recipient,
+		/// which does not support trampoline payments and should be paid via a regular onion.
+		TrampolineForward {
+			outgoing_node_id: PublicKey,
+			payment_data: Option<FinalOnionHopData>,
+		},
+		/// A hop within a blinded route, whose forwarding instructions were encrypted for it by
+		/// the route's recipient in `encrypted_tlvs`. The introduction node of the route learns the
+		/// route's blinding point from `intro_node_blinding_point`, while later hops receive it in
+		/// [`super::UpdateAddHTLC::blinding_point`].
+		///
+		/// The amount and CLTV expiry to forward are derived from the encrypted forwarding
+		/// parameters, so `amt_to_forward` and `outgoing_cltv_value` are not included.
+		Blinded {
+			encrypted_tlvs: Vec<u8>,
+			intro_node_blinding_point: Option<PublicKey>,
 		},
 	}
 
@@ -987,6 +1079,17 @@ mod fuzzy_internal_msgs {
 		// 12 bytes of 0-padding for Legacy format
 	}
 
+	/// An onion packet carried within the final hop payload of a payment's onion, which is peeled
+	/// by each trampoline node the payment is routed through. Unlike [`super::OnionPacket`]s,
+	/// trampoline onion packets are of variable length.
+	#[derive(Clone, Debug, PartialEq)]
+	pub struct TrampolineOnionPacket {
+		pub(crate) version: u8,
+		pub(crate) public_key: PublicKey,
+		pub(crate) hop_data: Vec<u8>,
+		pub(crate) hmac: [u8; 32],
+	}
+
 	pub struct DecodedOnionErrorPacket {
 		pub(crate) hmac: [u8; 32],
 		pub(crate) failuremsg: Vec<u8>,
@@ -1021,6 +1124,18 @@ impl onion_utils::Packet for OnionPacket {
 	}
 }
 
+impl onion_utils::Packet for TrampolineOnionPacket {
+	type Data = Vec<u8>;
+	fn new(public_key: PublicKey, hop_data: Vec<u8>, hmac: [u8; 32]) -> Self {
+		Self {
+			version: 0,
+			public_key,
+			hop_data,
+			hmac,
+		}
+	}
+}
+
 impl PartialEq for OnionPacket {
 	fn eq(&self, other: &OnionPacket) -> bool {
 		for (i, j) in self.hop_data.iter().zip(other.hop_data.iter()) {
@@ -1353,7 +1468,9 @@ impl_writeable_msg!(UpdateAddHTLC, {
 	payment_hash,
 	cltv_expiry,
 	onion_routing_packet
-}, {});
+}, {
+	(0, blinding_point, option),
+});
 
 impl Readable for OnionMessage {
 	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
@@ -1378,6 +1495,28 @@ impl Writeable for OnionMessage {
 	}
 }
 
+impl Writeable for TrampolineOnionPacket {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		self.version.write(w)?;
+		self.public_key.write(w)?;
+		w.write_all(&self.hop_data)?;
+		self.hmac.write(w)
+	}
+}
+
+impl LengthReadable for TrampolineOnionPacket {
+	fn read<R: LengthRead>(r: &mut R) -> Result<Self, DecodeError> {
+		let version = Readable::read(r)?;
+		let public_key = Readable::read(r)?;
+		// 1 (version) + 33 (pubkey) + 32 (HMAC) = 66
+		let hop_data_len = r.total_bytes().checked_sub(66).ok_or(DecodeError::ShortRead)? as usize;
+		let mut hop_data = vec![0; hop_data_len];
+		r.read_exact(&mut hop_data)?;
+		let hmac = Readable::read(r)?;
+		Ok(TrampolineOnionPacket { version, public_key, hop_data, hmac })
+	}
+}
+
 impl Writeable for FinalOnionHopData {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
 		self.payment_secret.0.write(w)?;
@@ -1403,19 +1542,46 @@ impl Writeable for OnionHopData {
 				self.outgoing_cltv_value.write(w)?;
 				w.write_all(&[0;12])?;
 			},
-			OnionHopDataFormat::NonFinalNode { short_channel_id } => {
+			OnionHopDataFormat::NonFinalNode { short_channel_id, ref custom_tlvs } => {
+				let custom_tlvs: Vec<&(u64, Vec<u8>)> = custom_tlvs.iter().collect();
 				encode_varint_length_prefixed_tlv!(w, {
 					(2, HighZeroBytesDroppedBigSize(self.amt_to_forward), required),
 					(4, HighZeroBytesDroppedBigSize(self.outgoing_cltv_value), required),
 					(6, short_channel_id, required)
+				}, custom_tlvs);
+			},
+			OnionHopDataFormat::FinalNode { ref payment_data, ref keysend_preimage, ref custom_tlvs } => {
+				// The keysend preimage is itself in the custom range, so has to be written in order
+				// with the custom TLVs.
+				let keysend_tlv = keysend_preimage.map(|preimage| (5482373484, preimage.encode()));
+				let mut custom_tlvs: Vec<&(u64, Vec<u8>)> = custom_tlvs.iter().chain(keysend_tlv.iter()).collect();
+				custom_tlvs.sort_unstable_by_key(|tlv| tlv.0);
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedBigSize(self.amt_to_forward), required),
+					(4, HighZeroBytesDroppedBigSize(self.outgoing_cltv_value), required),
+					(8, payment_data, option)
+				}, custom_tlvs);
+			},
+			OnionHopDataFormat::TrampolineEntrypoint { ref payment_data, ref trampoline_packet } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedBigSize(self.amt_to_forward), required),
+					(4, HighZeroBytesDroppedBigSize(self.outgoing_cltv_value), required),
+					(8, payment_data, required),
+					(66100, trampoline_packet, required)
 				});
 			},
-			OnionHopDataFormat::FinalNode { ref payment_data, ref keysend_preimage } => {
+			OnionHopDataFormat::TrampolineForward { ref outgoing_node_id, ref payment_data } => {
 				encode_varint_length_prefixed_tlv!(w, {
 					(2, HighZeroBytesDroppedBigSize(self.amt_to_forward), required),
 					(4, HighZeroBytesDroppedBigSize(self.outgoing_cltv_value), required),
 					(8, payment_data, option),
-					(5482373484, keysend_preimage, option)
+					(14, outgoing_node_id, required)
+				});
+			},
+			OnionHopDataFormat::Blinded { ref encrypted_tlvs, ref intro_node_blinding_point } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(10, *encrypted_tlvs, vec_type),
+					(12, intro_node_blinding_point, option)
 				});
 			},
 		}
@@ -1429,37 +1595,83 @@ impl Readable for OnionHopData {
 		const LEGACY_ONION_HOP_FLAG: u64 = 0;
 		let (format, amt, cltv_value) = if b.0 != LEGACY_ONION_HOP_FLAG {
 			let mut rd = FixedLengthReader::new(r, b.0);
-			let mut amt = HighZeroBytesDroppedBigSize(0u64);
-			let mut cltv_value = HighZeroBytesDroppedBigSize(0u32);
+			let mut amt: Option<HighZeroBytesDroppedBigSize<u64>> = None;
+			let mut cltv_value: Option<HighZeroBytesDroppedBigSize<u32>> = None;
 			let mut short_id: Option<u64> = None;
 			let mut payment_data: Option<FinalOnionHopData> = None;
+			let mut encrypted_tlvs: Option<Vec<u8>> = None;
+			let mut intro_node_blinding_point: Option<PublicKey> = None;
+			let mut outgoing_node_id: Option<PublicKey> = None;
+			let mut trampoline_packet: Option<TrampolineOnionPacket> = None;
 			let mut keysend_preimage: Option<PaymentPreimage> = None;
+			let mut custom_tlvs = Vec::new();
 			decode_tlv_stream!(&mut rd, {
-				(2, amt, required),
-				(4, cltv_value, required),
+				(2, amt, option),
+				(4, cltv_value, option),
 				(6, short_id, option),
 				(8, payment_data, option),
+				(10, encrypted_tlvs, vec_type),
+				(12, intro_node_blinding_point, option),
+				(14, outgoing_node_id, option),
+				(66100, trampoline_packet, (option: LengthReadable)),
 				// See https://github.com/lightning/blips/blob/master/blip-0003.md
 				(5482373484, keysend_preimage, option)
+			}, |typ: u64, reader: &mut FixedLengthReader<_>| -> Result<bool, DecodeError> {
+				if typ < 1 << 16 || typ % 2 == 0 { return Ok(false); }
+				custom_tlvs.push((typ, read_to_end(reader)?));
+				Ok(true)
 			});
 			rd.eat_remaining().map_err(|_| DecodeError::ShortRead)?;
-			let format = if let Some(short_channel_id) = short_id {
-				if payment_data.is_some() { return Err(DecodeError::InvalidValue); }
-				OnionHopDataFormat::NonFinalNode {
-					short_channel_id,
+			if let &Some(ref data) = &payment_data {
+				if data.total_msat > MAX_VALUE_MSAT {
+					return Err(DecodeError::InvalidValue);
 				}
+			}
+			if (outgoing_node_id.is_some() || trampoline_packet.is_some()) &&
+				(short_id.is_some() || keysend_preimage.is_some())
+			{
+				return Err(DecodeError::InvalidValue);
+			}
+			if let Some(encrypted_tlvs) = encrypted_tlvs {
+				// Within a blinded route, everything but the amount and CLTV expiry received by the
+				// final hop comes from the encrypted TLVs.
+				if short_id.is_some() || payment_data.is_some() || outgoing_node_id.is_some() ||
+					trampoline_packet.is_some() || keysend_preimage.is_some()
+				{
+					return Err(DecodeError::InvalidValue);
+				}
+				let format = OnionHopDataFormat::Blinded { encrypted_tlvs, intro_node_blinding_point };
+				(format, amt.map(|amt| amt.0).unwrap_or(0), cltv_value.map(|cltv| cltv.0).unwrap_or(0))
 			} else {
-				if let &Some(ref data) = &payment_data {
-					if data.total_msat > MAX_VALUE_MSAT {
-						return Err(DecodeError::InvalidValue);
+				if intro_node_blinding_point.is_some() { return Err(DecodeError::InvalidValue); }
+				let amt = amt.ok_or(DecodeError::InvalidValue)?;
+				let cltv_value = cltv_value.ok_or(DecodeError::InvalidValue)?;
+				let format = if let Some(outgoing_node_id) = outgoing_node_id {
+					if trampoline_packet.is_some() { return Err(DecodeError::InvalidValue); }
+					OnionHopDataFormat::TrampolineForward {
+						outgoing_node_id,
+						payment_data,
 					}
-				}
-				OnionHopDataFormat::FinalNode {
-					payment_data,
-					keysend_preimage,
-				}
-			};
-			(format, amt.0, cltv_value.0)
+				} else if let Some(trampoline_packet) = trampoline_packet {
+					OnionHopDataFormat::TrampolineEntrypoint {
+						payment_data: payment_data.ok_or(DecodeError::InvalidValue)?,
+						trampoline_packet,
+					}
+				} else if let Some(short_channel_id) = short_id {
+					if payment_data.is_some() { return Err(DecodeError::InvalidValue); }
+					OnionHopDataFormat::NonFinalNode {
+						short_channel_id,
+						custom_tlvs,
+					}
+				} else {
+					OnionHopDataFormat::FinalNode {
+						payment_data,
+						keysend_preimage,
+						custom_tlvs,
+					}
+				};
+				(format, amt.0, cltv_value.0)
+			}
 		} else {
 			let format = OnionHopDataFormat::Legacy {
 				short_channel_id: Readable::read(r)?,
@@ -2433,7 +2645,8 @@ mod tests {
 			amount_msat: 3608586615801332854,
 			payment_hash: PaymentHash([1; 32]),
 			cltv_expiry: 821716,
-			onion_routing_packet
+			onion_routing_packet,
+			blinding_point: None,
 		};
 		let encoded_value = update_add_htlc.encode();
 		let target_value = hex::decode("020202020202020202020202020202020202020202020202020202020202020200083a840000034d32144668701144760101010101010101010101010101010101010101010101010101010101010101000c89d4ff031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010202020202020202020202020202020202020202020202020202020202020202").unwrap();
@@ -2625,6 +2838,7 @@ mod tests {
 		let mut msg = msgs::OnionHopData {
 			format: OnionHopDataFormat::NonFinalNode {
 				short_channel_id: 0xdeadbeef1bad1dea,
+				custom_tlvs: Vec::new(),
 			},
 			amt_to_forward: 0x0badf00d01020304,
 			outgoing_cltv_value: 0xffffffff,
@@ -2633,19 +2847,42 @@ mod tests {
 		let target_value = hex::decode("1a02080badf00d010203040404ffffffff0608deadbeef1bad1dea").unwrap();
 		assert_eq!(encoded_value, target_value);
 		msg = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
-		if let OnionHopDataFormat::NonFinalNode { short_channel_id } = msg.format {
+		if let OnionHopDataFormat::NonFinalNode { short_channel_id, custom_tlvs } = msg.format {
 			assert_eq!(short_channel_id, 0xdeadbeef1bad1dea);
+			assert!(custom_tlvs.is_empty());
 		} else { panic!(); }
 		assert_eq!(msg.amt_to_forward, 0x0badf00d01020304);
 		assert_eq!(msg.outgoing_cltv_value, 0xffffffff);
 	}
 
+	#[test]
+	fn encoding_nonfinal_onion_hop_data_with_custom_tlvs() {
+		let custom_tlvs = vec![(65537, vec![0x01])];
+		let mut msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::NonFinalNode {
+				short_channel_id: 0xdeadbeef1bad1dea,
+				custom_tlvs: custom_tlvs.clone(),
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let encoded_value = msg.encode();
+		let target_value = hex::decode("2102080badf00d010203040404ffffffff0608deadbeef1bad1deafe000100010101").unwrap();
+		assert_eq!(encoded_value, target_value);
+		msg = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		if let OnionHopDataFormat::NonFinalNode { short_channel_id, custom_tlvs: read_tlvs } = msg.format {
+			assert_eq!(short_channel_id, 0xdeadbeef1bad1dea);
+			assert_eq!(read_tlvs, custom_tlvs);
+		} else { panic!(); }
+	}
+
 	#[test]
 	fn encoding_final_onion_hop_data() {
 		let mut msg = msgs::OnionHopData {
 			format: OnionHopDataFormat::FinalNode {
 				payment_data: None,
 				keysend_preimage: None,
+				custom_tlvs: Vec::new(),
 			},
 			amt_to_forward: 0x0badf00d01020304,
 			outgoing_cltv_value: 0xffffffff,
@@ -2669,6 +2906,7 @@ mod tests {
 					total_msat: 0x1badca1f
 				}),
 				keysend_preimage: None,
+				custom_tlvs: Vec::new(),
 			},
 			amt_to_forward: 0x0badf00d01020304,
 			outgoing_cltv_value: 0xffffffff,
@@ -2683,6 +2921,7 @@ mod tests {
 				total_msat: 0x1badca1f
 			}),
 			keysend_preimage: None,
+			custom_tlvs: _,
 		} = msg.format {
 			assert_eq!(payment_secret, expected_payment_secret);
 		} else { panic!(); }
@@ -2690,6 +2929,65 @@ mod tests {
 		assert_eq!(msg.outgoing_cltv_value, 0xffffffff);
 	}
 
+	#[test]
+	fn encoding_final_onion_hop_data_with_custom_tlvs() {
+		let keysend_preimage = PaymentPreimage([0x42u8; 32]);
+		let custom_tlvs = vec![(65537, vec![0x01]), (7629169, vec![0x02; 3]), (5482373485, vec![0x03])];
+		let mut msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::FinalNode {
+				payment_data: None,
+				keysend_preimage: Some(keysend_preimage),
+				custom_tlvs: custom_tlvs.clone(),
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let encoded_value = msg.encode();
+		let target_value = hex::decode("5502080badf00d010203040404ffffffff fe00010001 0101 fe00746971 03 020202 ff0000000146c6616c 20 4242424242424242424242424242424242424242424242424242424242424242 ff0000000146c6616d 01 03".replace(" ", "")).unwrap();
+		assert_eq!(encoded_value, target_value);
+		msg = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		if let OnionHopDataFormat::FinalNode { payment_data: None, keysend_preimage: Some(preimage), custom_tlvs: read_tlvs } = msg.format {
+			assert_eq!(preimage, keysend_preimage);
+			assert_eq!(read_tlvs, custom_tlvs);
+		} else { panic!(); }
+
+		// Unknown odd TLVs below the custom range are ignored, while even ones are rejected.
+		let ignored_value = hex::decode("1302080badf00d010203040404ffffffff0d0100").unwrap();
+		msg = Readable::read(&mut Cursor::new(&ignored_value[..])).unwrap();
+		if let OnionHopDataFormat::FinalNode { custom_tlvs, .. } = msg.format {
+			assert!(custom_tlvs.is_empty());
+		} else { panic!(); }
+		let even_value = hex::decode("1702080badf00d010203040404fffffffffe000100000100").unwrap();
+		let res: Result<msgs::OnionHopData, _> = Readable::read(&mut Cursor::new(&even_value[..]));
+		assert_eq!(res.err().unwrap(), msgs::DecodeError::UnknownRequiredFeature);
+	}
+
+	#[test]
+	fn encoding_blinded_onion_hop_data() {
+		let secp_ctx = Secp256k1::new();
+		let (_, blinding_point) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::Blinded {
+				encrypted_tlvs: vec![0x42; 3],
+				intro_node_blinding_point: Some(blinding_point),
+			},
+			amt_to_forward: 0,
+			outgoing_cltv_value: 0,
+		};
+		let encoded_value = msg.encode();
+		let target_value = hex::decode("280a034242420c21031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f").unwrap();
+		assert_eq!(encoded_value, target_value);
+		let msg: msgs::OnionHopData = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		if let OnionHopDataFormat::Blinded { encrypted_tlvs, intro_node_blinding_point: Some(point) } = msg.format {
+			assert_eq!(encrypted_tlvs, vec![0x42; 3]);
+			assert_eq!(point, blinding_point);
+		} else { panic!(); }
+
+		// Blinded hops may not also carry unblinded forwarding instructions.
+		let with_short_channel_id = hex::decode("0f0608deadbeef1bad1dea0a03424242").unwrap();
+		assert!(<msgs::OnionHopData as Readable>::read(&mut Cursor::new(&with_short_channel_id[..])).is_err());
+	}
+
 	#[test]
 	fn query_channel_range_end_blocknum() {
 		let tests: Vec<(u32, u32, u32)> = vec![
@@ -2845,13 +3143,14 @@ mod tests {
 		let payload = msgs::OnionHopData {
 			format: OnionHopDataFormat::NonFinalNode {
 				short_channel_id: 0xdeadbeef1bad1dea,
+				custom_tlvs: Vec::new(),
 			},
 			amt_to_forward: 1000,
 			outgoing_cltv_value: 0xffffffff,
 		};
 		let mut encoded_payload = Vec::new();
 		let test_bytes = vec![42u8; 1000];
-		if let OnionHopDataFormat::NonFinalNode { short_channel_id } = payload.format {
+		if let OnionHopDataFormat::NonFinalNode { short_channel_id, .. } = payload.format {
 			encode_varint_length_prefixed_tlv!(&mut encoded_payload, {
 				(1, test_bytes, vec_type),
 				(2, HighZeroBytesDroppedBigSize(payload.amt_to_forward), required),
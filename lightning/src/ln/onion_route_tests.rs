@@ -15,7 +15,7 @@ use chain::channelmonitor::{ChannelMonitor, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PER
 use chain::keysinterface::{KeysInterface, Recipient};
 use ln::{PaymentHash, PaymentSecret};
 use ln::channel::EXPIRE_PREV_CONFIG_TICKS;
-use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, HTLCForwardInfo, CLTV_FAR_FAR_AWAY, MIN_CLTV_EXPIRY_DELTA, PendingHTLCInfo, PendingHTLCRouting};
+use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, ForwardRejectionReason, NodeRole, HTLCForwardInfo, CLTV_FAR_FAR_AWAY, MIN_CLTV_EXPIRY_DELTA, PendingHTLCInfo, PendingHTLCRouting};
 use ln::onion_utils;
 use routing::gossip::{NetworkUpdate, RoutingFees, NodeId};
 use routing::router::{get_route, PaymentParameters, Route, RouteHint, RouteHintHop};
@@ -768,13 +768,14 @@ fn do_test_onion_failure_stale_channel_update(announced_channel: bool) {
 
 		let chanmgr = <(_, ChannelManager<_, _, _, _, _, _>)>::read(
 			&mut &nodes[1].node.encode()[..], ChannelManagerReadArgs {
-				default_config: *nodes[1].node.get_current_default_configuration(),
+				default_config: nodes[1].node.get_current_default_configuration(),
 				keys_manager: nodes[1].keys_manager,
 				fee_estimator: node_cfgs[1].fee_estimator,
 				chain_monitor: &chain_monitor,
 				tx_broadcaster: nodes[1].tx_broadcaster.clone(),
 				logger: nodes[1].logger,
 				channel_monitors: channel_monitors,
+				role: NodeRole::Active,
 			},
 		).unwrap().1;
 		chanmgr.list_channels().iter()
@@ -1372,3 +1373,54 @@ fn test_phantom_failure_reject_payment() {
 		.expected_htlc_error_data(0x4000 | 15, &error_data);
 	expect_payment_failed_conditions(&nodes[0], payment_hash, true, fail_conditions);
 }
+
+#[test]
+fn test_rejected_forwards_recorded() {
+	// Tests that HTLCs we reject forwarding are recorded with the reason we rejected them.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	let channels = [create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()), create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known())];
+
+	// Tampering with the HTLC amount must not change its value in whole satoshis, as that would
+	// change the commitment transaction.
+	let (route, _, _, _) = get_route_and_payment_hash!(nodes[0], nodes[2], 40_500);
+	let (_, payment_hash, payment_secret) = get_payment_preimage_hash!(nodes[2]);
+	assert!(nodes[1].node.list_rejected_forwards().is_empty());
+
+	let short_channel_id = channels[0].0.contents.short_channel_id;
+	run_onion_failure_test("fee_insufficient", 0, &nodes, &route, &payment_hash, &payment_secret, |msg| {
+		msg.amount_msat -= 1;
+	}, || {}, true, Some(UPDATE|12), Some(NetworkUpdate::ChannelFailure { short_channel_id, is_permanent: true}), Some(short_channel_id));
+
+	let rejected_forwards = nodes[1].node.list_rejected_forwards();
+	assert_eq!(rejected_forwards.len(), 1);
+	let rejection = &rejected_forwards[0];
+	assert_eq!(rejection.payment_hash, payment_hash);
+	assert_eq!(rejection.prev_channel_id, channels[0].2);
+	assert_eq!(rejection.next_short_channel_id, channels[1].0.contents.short_channel_id);
+	assert_eq!(rejection.incoming_amount_msat, Some(40_500 + route.paths[0][0].fee_msat - 1));
+	assert_eq!(rejection.outgoing_amount_msat, 40_500);
+	assert_eq!(rejection.incoming_cltv_expiry.unwrap(), rejection.outgoing_cltv_expiry + route.paths[0][0].cltv_expiry_delta);
+	assert_eq!(rejection.reason, ForwardRejectionReason::FeeInsufficient);
+	assert_eq!(rejection.failure_code, UPDATE|12);
+	assert_eq!(rejection.rejected_at_height, nodes[1].best_block_info().1);
+
+	let short_channel_id = channels[1].0.contents.short_channel_id;
+	run_onion_failure_test("channel_disabled", 0, &nodes, &route, &payment_hash, &payment_secret, |_| {}, || {
+		nodes[1].node.peer_disconnected(&nodes[2].node.get_our_node_id(), false);
+		nodes[2].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+	}, true, Some(UPDATE|20), Some(NetworkUpdate::ChannelUpdateMessage{msg: ChannelUpdate::dummy(short_channel_id)}), Some(short_channel_id));
+	reconnect_nodes(&nodes[1], &nodes[2], (true, true), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+
+	let rejected_forwards = nodes[1].node.list_rejected_forwards();
+	assert_eq!(rejected_forwards.len(), 2);
+	assert_eq!(rejected_forwards[1].payment_hash, payment_hash);
+	assert_eq!(rejected_forwards[1].next_short_channel_id, short_channel_id);
+	assert_eq!(rejected_forwards[1].reason, ForwardRejectionReason::PeerDisconnected);
+	assert_eq!(rejected_forwards[1].failure_code, UPDATE|20);
+
+	nodes[1].node.clear_rejected_forwards();
+	assert!(nodes[1].node.list_rejected_forwards().is_empty());
+}
@@ -12,7 +12,7 @@ use ln::channelmanager::HTLCSource;
 use ln::msgs;
 use ln::wire::Encode;
 use routing::gossip::NetworkUpdate;
-use routing::router::RouteHop;
+use routing::router::{RouteHop, TrampolineHop};
 use util::chacha20::{ChaCha20, ChaChaReader};
 use util::errors::{self, APIError};
 use util::ser::{Readable, ReadableArgs, Writeable, LengthCalculatingWriter};
@@ -95,12 +95,17 @@ pub(crate) fn next_hop_packet_pubkey<T: secp256k1::Signing + secp256k1::Verifica
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
 #[inline]
-pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop, usize)> (secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
+pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop, usize)> (secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey, callback: FType) -> Result<(), secp256k1::Error> {
+	construct_onion_keys_generic_callback(secp_ctx, path, |hop| &hop.pubkey, session_priv, callback)
+}
+
+#[inline]
+fn construct_onion_keys_generic_callback<T: secp256k1::Signing, H, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &H, usize)> (secp_ctx: &Secp256k1<T>, hops: &[H], hop_pubkey: fn(&H) -> &PublicKey, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
 	let mut blinded_priv = session_priv.clone();
 	let mut blinded_pub = PublicKey::from_secret_key(secp_ctx, &blinded_priv);
 
-	for (idx, hop) in path.iter().enumerate() {
-		let shared_secret = SharedSecret::new(&hop.pubkey, &blinded_priv);
+	for (idx, hop) in hops.iter().enumerate() {
+		let shared_secret = SharedSecret::new(hop_pubkey(hop), &blinded_priv);
 
 		let mut sha = Sha256::engine();
 		sha.input(&blinded_pub.serialize()[..]);
@@ -120,9 +125,18 @@ pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
 pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
-	let mut res = Vec::with_capacity(path.len());
+	construct_onion_keys_generic(secp_ctx, path, |hop| &hop.pubkey, session_priv)
+}
+
+// can only fail if a trampoline hop has an invalid public key or session_priv is invalid
+pub(super) fn construct_trampoline_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, trampoline_hops: &[TrampolineHop], session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	construct_onion_keys_generic(secp_ctx, trampoline_hops, |hop| &hop.pubkey, session_priv)
+}
+
+fn construct_onion_keys_generic<T: secp256k1::Signing, H>(secp_ctx: &Secp256k1<T>, hops: &[H], hop_pubkey: fn(&H) -> &PublicKey, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	let mut res = Vec::with_capacity(hops.len());
 
-	construct_onion_keys_callback(secp_ctx, path, session_priv, |shared_secret, _blinding_factor, ephemeral_pubkey, _, _| {
+	construct_onion_keys_generic_callback(secp_ctx, hops, hop_pubkey, session_priv, |shared_secret, _blinding_factor, ephemeral_pubkey, _, _| {
 		let (rho, mu) = gen_rho_mu_from_shared_secret(shared_secret.as_ref());
 
 		res.push(OnionKeys {
@@ -151,7 +165,7 @@ pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, paymen
 		// exactly as it should be (and the next hop isn't trying to probe to find out if we're
 		// the intended recipient).
 		let value_msat = if cur_value_msat == 0 { hop.fee_msat } else { cur_value_msat };
-		let cltv = if cur_cltv == starting_htlc_offset { hop.cltv_expiry_delta + starting_htlc_offset } else { cur_cltv };
+		let cltv = if idx == 0 { hop.cltv_expiry_delta + starting_htlc_offset } else { cur_cltv };
 		res.insert(0, msgs::OnionHopData {
 			format: if hop.node_features.supports_variable_length_onion() {
 				if idx == 0 {
@@ -163,10 +177,12 @@ pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, paymen
 							})
 						} else { None },
 						keysend_preimage: *keysend_preimage,
+						custom_tlvs: Vec::new(),
 					}
 				} else {
 					msgs::OnionHopDataFormat::NonFinalNode {
 						short_channel_id: last_short_channel_id,
+						custom_tlvs: Vec::new(),
 					}
 				}
 			} else {
@@ -190,10 +206,108 @@ pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, paymen
 	Ok((res, cur_value_msat, cur_cltv))
 }
 
+/// Builds the payloads for a payment along `path` to the first of `trampoline_hops`, whose final
+/// payload carries a trampoline onion instructing each trampoline node to route the payment on to
+/// the next, with the last paying `payee_pubkey`.
+///
+/// `total_msat` is the total value delivered to the first trampoline node across all paths, out
+/// of which the fees of `trampoline_hops` are paid. Returns the hop data, as well as the first-hop
+/// value_msat and CLTV value we should send.
+pub(super) fn build_trampoline_onion_payloads<T: secp256k1::Signing>(
+	secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, trampoline_hops: &[TrampolineHop], payee_pubkey: PublicKey,
+	total_msat: u64, payment_secret: &PaymentSecret, starting_htlc_offset: u32, payment_hash: &PaymentHash,
+	trampoline_session_priv: &SecretKey, trampoline_prng_seed: [u8; 32]
+) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
+	// The first trampoline node learns the payment secret it must see across all parts, so we
+	// give it one derived from the payee's rather than the payee's itself.
+	let trampoline_payment_secret = {
+		let mut sha = Sha256::engine();
+		sha.input(b"LDK trampoline payment secret");
+		sha.input(&payment_secret.0);
+		PaymentSecret(Sha256::from_engine(sha).into_inner())
+	};
+	let (mut payloads, htlc_msat, htlc_cltv) = build_onion_payloads(path, total_msat,
+		&Some(trampoline_payment_secret), starting_htlc_offset, &None)?;
+
+	let trampoline_fee_msat: u64 = trampoline_hops.iter().map(|hop| hop.fee_msat).sum();
+	let trampoline_cltv_expiry_delta: u32 = trampoline_hops.iter().map(|hop| hop.cltv_expiry_delta).sum();
+	let entrypoint_payload = payloads.last_mut().unwrap();
+	let payee_value_msat = total_msat.checked_sub(trampoline_fee_msat)
+		.ok_or(APIError::RouteError{err: "Trampoline fees exceed the payment value"})?;
+	let payee_cltv = entrypoint_payload.outgoing_cltv_value.checked_sub(trampoline_cltv_expiry_delta)
+		.ok_or(APIError::RouteError{err: "Trampoline CLTV deltas exceed the route's CLTV"})?;
+
+	let mut trampoline_payloads = Vec::with_capacity(trampoline_hops.len());
+	let mut cur_value_msat = payee_value_msat;
+	let mut cur_cltv = payee_cltv;
+	let mut outgoing_node_id = payee_pubkey;
+	for hop in trampoline_hops.iter().rev() {
+		trampoline_payloads.insert(0, msgs::OnionHopData {
+			format: msgs::OnionHopDataFormat::TrampolineForward {
+				outgoing_node_id,
+				payment_data: if outgoing_node_id == payee_pubkey {
+					Some(msgs::FinalOnionHopData { payment_secret: *payment_secret, total_msat: payee_value_msat })
+				} else { None },
+			},
+			amt_to_forward: cur_value_msat,
+			outgoing_cltv_value: cur_cltv,
+		});
+		cur_value_msat += hop.fee_msat;
+		cur_cltv += hop.cltv_expiry_delta;
+		outgoing_node_id = hop.pubkey;
+	}
+	if payloads_serialized_length(&trampoline_payloads) > TRAMPOLINE_ONION_DATA_LEN {
+		return Err(APIError::RouteError{err: "Trampoline route size too large considering onion data"});
+	}
+	let trampoline_onion_keys = construct_trampoline_onion_keys(secp_ctx, trampoline_hops, trampoline_session_priv)
+		.map_err(|_| APIError::RouteError{err: "Pubkey along trampoline hop was maliciously selected"})?;
+	let trampoline_packet = construct_trampoline_onion_packet(trampoline_payloads, trampoline_onion_keys,
+		trampoline_prng_seed, payment_hash);
+
+	entrypoint_payload.format = match entrypoint_payload.format {
+		msgs::OnionHopDataFormat::FinalNode { payment_data: Some(ref payment_data), .. } => {
+			msgs::OnionHopDataFormat::TrampolineEntrypoint { payment_data: payment_data.clone(), trampoline_packet }
+		},
+		_ => return Err(APIError::RouteError{err: "First trampoline node does not support variable-length onions"}),
+	};
+	Ok((payloads, htlc_msat, htlc_cltv))
+}
+
+/// Builds the payloads for relaying a trampoline payment along `path` to the next node, such that
+/// the next node receives exactly `outgoing_cltv_value` as instructed by the sender.
+///
+/// If `next_trampoline_packet` is set, the next node is another trampoline node, whose payload
+/// carries the trampoline onion for it to peel. Otherwise, it's the payment's final recipient.
+/// Returns the hop data, as well as the first-hop value_msat and CLTV value we should send.
+pub(super) fn build_trampoline_forward_onion_payloads(
+	path: &Vec<RouteHop>, total_msat: u64, payment_secret: &PaymentSecret, outgoing_cltv_value: u32,
+	next_trampoline_packet: Option<msgs::TrampolineOnionPacket>
+) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
+	let final_cltv_expiry_delta = path.last().ok_or(APIError::RouteError{err: "Path didn't go anywhere"})?.cltv_expiry_delta;
+	let starting_htlc_offset = outgoing_cltv_value.checked_sub(final_cltv_expiry_delta)
+		.ok_or(APIError::RouteError{err: "Route's final CLTV delta exceeds the trampoline payment's CLTV"})?;
+	let (mut payloads, htlc_msat, htlc_cltv) = build_onion_payloads(path, total_msat,
+		&Some(*payment_secret), starting_htlc_offset, &None)?;
+
+	if let Some(trampoline_packet) = next_trampoline_packet {
+		let final_payload = payloads.last_mut().unwrap();
+		final_payload.format = match final_payload.format {
+			msgs::OnionHopDataFormat::FinalNode { payment_data: Some(ref payment_data), .. } => {
+				msgs::OnionHopDataFormat::TrampolineEntrypoint { payment_data: payment_data.clone(), trampoline_packet }
+			},
+			_ => return Err(APIError::RouteError{err: "Next trampoline node does not support variable-length onions"}),
+		};
+	}
+	Ok((payloads, htlc_msat, htlc_cltv))
+}
+
 /// Length of the onion data packet. Before TLV-based onions this was 20 65-byte hops, though now
 /// the hops can be of variable length.
 pub(crate) const ONION_DATA_LEN: usize = 20*65;
 
+/// Length of the data packet of the trampoline onions we construct.
+pub(crate) const TRAMPOLINE_ONION_DATA_LEN: usize = 400;
+
 #[inline]
 fn shift_slice_right(arr: &mut [u8], amt: usize) {
 	for i in (amt..arr.len()).rev() {
@@ -229,6 +343,16 @@ pub(super) fn construct_onion_packet(payloads: Vec<msgs::OnionHopData>, onion_ke
 		payloads, onion_keys, FixedSizeOnionPacket(packet_data), Some(associated_data))
 }
 
+/// panics if payloads_serialized_length(payloads) > TRAMPOLINE_ONION_DATA_LEN
+pub(super) fn construct_trampoline_onion_packet(payloads: Vec<msgs::OnionHopData>, onion_keys: Vec<OnionKeys>, prng_seed: [u8; 32], associated_data: &PaymentHash) -> msgs::TrampolineOnionPacket {
+	let mut packet_data = vec![0; TRAMPOLINE_ONION_DATA_LEN];
+
+	let mut chacha = ChaCha20::new(&prng_seed, &[0; 8]);
+	chacha.process_in_place(&mut packet_data);
+
+	construct_onion_packet_with_init_noise::<_, _>(payloads, onion_keys, packet_data, Some(associated_data))
+}
+
 #[cfg(test)]
 // Used in testing to write bogus OnionHopDatas, which is otherwise not representable in
 // msgs::OnionHopData.
@@ -320,7 +444,8 @@ fn construct_onion_packet_with_init_noise<HD: Writeable, P: Packet>(
 		chacha.process_in_place(packet_data);
 
 		if i == 0 {
-			packet_data[ONION_DATA_LEN - filler.len()..ONION_DATA_LEN].copy_from_slice(&filler[..]);
+			let packet_len = packet_data.len();
+			packet_data[packet_len - filler.len()..packet_len].copy_from_slice(&filler[..]);
 		}
 
 		let mut hmac = HmacEngine::<Sha256>::new(&keys.mu);
@@ -749,9 +874,9 @@ pub(crate) fn decode_next_hop<D: DecodeInput, R: ReadableArgs<D::Arg>, N: NextPa
 mod tests {
 	use io;
 	use prelude::*;
-	use ln::PaymentHash;
+	use ln::{PaymentHash, PaymentSecret};
 	use ln::features::{ChannelFeatures, NodeFeatures};
-	use routing::router::{Route, RouteHop};
+	use routing::router::{Route, RouteHop, TrampolineHop};
 	use ln::msgs;
 	use util::ser::{Writeable, Writer};
 
@@ -759,6 +884,7 @@ mod tests {
 
 	use bitcoin::secp256k1::Secp256k1;
 	use bitcoin::secp256k1::{PublicKey,SecretKey};
+	use bitcoin::secp256k1::ecdh::SharedSecret;
 
 	use super::OnionKeys;
 
@@ -962,4 +1088,77 @@ mod tests {
 		// anyway...
 		assert_eq!(packet.encode(), hex::decode("0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619e5f14350c2a76fc232b5e46d421e9615471ab9e0bc887beff8c95fdb878f7b3a71a060daf367132b378b3a3883c0e2c0e026b8900b2b5cdbc784e1a3bb913f88a9c50f7d61ab590531cf08000178a333a347f8b4072ed056f820f77774345e183a342ec4729f3d84accf515e88adddb85ecc08daba68404bae9a8e8d7178977d7094a1ae549f89338c0777551f874159eb42d3a59fb9285ad4e24883f27de23942ec966611e99bee1cee503455be9e8e642cef6cef7b9864130f692283f8a973d47a8f1c1726b6e59969385975c766e35737c8d76388b64f748ee7943ffb0e2ee45c57a1abc40762ae598723d21bd184e2b338f68ebff47219357bd19cd7e01e2337b806ef4d717888e129e59cd3dc31e6201ccb2fd6d7499836f37a993262468bcb3a4dcd03a22818aca49c6b7b9b8e9e870045631d8e039b066ff86e0d1b7291f71cefa7264c70404a8e538b566c17ccc5feab231401e6c08a01bd5edfc1aa8e3e533b96e82d1f91118d508924b923531929aea889fcdf050597c681185f336b1da63b0939aa2b7c50b21b5eb7b6ad66c81fab98a3cdf73f658149e7e9ced4edde5d38c9b8f92e16f6b4ab13d7fca6a0e4ecc9f9de611a90da6e99c39551094c56e3196f282c5dffd9fc4b2fc12f3bca8e6fe47eb45fbdd3be21a8a8d200797eae3c9a0497132f92410d804977408494dff49dd3d8bce248e0b74fd9e6f0f7102c25ddfa02bd9ad9f746abbfa337ef811d5345a9e16b60de1767b209645ba40bd1f9a5f75bc04feca9b27c5554be4fe83fac2cb83aa447a817bb85ae966c68b420063833fada375e2f515965e687a45699632902672c654d1d18d7bcbf55e8fa57f63f2da449f8e1e606e8722df081e5f193fc4179feb99ad22819afdeef211f7c54afdba92aeef0c00b7bc2b65a4813c01f907a8377585708f2d4c940a25328e585714c8ded0a9a4d7a6de1027c1cb7a0198cd3db68b58c0704dfd0cfbe624e9cd18cc0ae5d96697bb476708b9ee0403d211e64e0d5a7683a7a9a140c02f0ff1c6e67a302941b4052bdea8a63e70a3ad62c5b89c698f1fd3c7685cb49705096cad702d02d93bcb1c27a409f4c9bddec001205ca4a2740f19b50900be81c7e847f1a863deea8d35701f1355cad8db57b1d4eb2ab4e29587734785abfb46ddede71928213d7d089dfdeda052827f459f1688cc0935bd47e7bcec27427c8376dcce7e22699567c0d145f8a7db33f6758815f1f15f9f7a9760dec4f34ae095edda4c64e9735bdd029c4e32c2ee31ba47ec5e6bdb97813d52dbd15b4e0b7a2c7f790ae64104d99f38c127f0a093288fa34144adb16b8968d4fa7656fcec99de8503dd46d3b03620a71c7cd085364abd30dccf7fbda25a1cdc102600149c9af1c97aa0372cd2e1909f28ac5c686f432b310e79528c9b8b9e8f314c1e74621ce6308ad2278b81d460892e0d9dd38b7c76d58be6dfd10ae7583ee1e7ef5b3f6f78dc60af0950df1b00cc55b6d178ba2e476bea0eaeef49323b83f05804159e7aef4eed4cc60dd07be76f067dfd0bcfb0b806b69ba921336a20c43c832d0cab8fa3ddeb29e3bf07b0d98a112eb07802756235a49d44a8b82a950d84e95e01971f0e106ccb337f07384e21620e0ad39e16ed9edca123226cf55ac44f449eeb53e38a7f27d101806e4823e4efcc887414240ee6826c4a5cb1c6443ad36ebf905a435c1d9054e54173911b17b5b40f60b3d9fd5f12eac54ca1e20191f5f18544d5fd3d665e9bcef96fb44b76110aa64d9db4c86c9513cbdad546538e8aec521fbe83ceac5e74a15629f1ed0b870a1d0d1e5680b6d6100d1bd3f3b9043bd35b8919c4088f1949b8be89e4701eb870f8ed64fafa446c78df3ea").unwrap());
 	}
+
+	#[test]
+	fn trampoline_onion_payloads() {
+		// Tests that the trampoline onion carried in the final payload of a payment's onion can be
+		// peeled by each trampoline node in turn, with the last one instructed to pay the payee.
+		type NextTrampolinePacket = ([u8; 32], Vec<u8>);
+		let secp_ctx = Secp256k1::new();
+		let trampoline_keys = [SecretKey::from_slice(&[42; 32]).unwrap(), SecretKey::from_slice(&[43; 32]).unwrap()];
+		let trampoline_pubkeys = [PublicKey::from_secret_key(&secp_ctx, &trampoline_keys[0]), PublicKey::from_secret_key(&secp_ctx, &trampoline_keys[1])];
+		let payee_pubkey = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[44; 32]).unwrap());
+		let payment_hash = PaymentHash([0x42; 32]);
+		let payment_secret = PaymentSecret([0x43; 32]);
+
+		let path = vec![RouteHop {
+			pubkey: trampoline_pubkeys[0], node_features: NodeFeatures::known(), short_channel_id: 1,
+			channel_features: ChannelFeatures::known(), fee_msat: 103_000, cltv_expiry_delta: 18 + 60 + 40,
+		}];
+		let trampoline_hops = vec![
+			TrampolineHop { pubkey: trampoline_pubkeys[0], fee_msat: 2_000, cltv_expiry_delta: 60 },
+			TrampolineHop { pubkey: trampoline_pubkeys[1], fee_msat: 1_000, cltv_expiry_delta: 40 },
+		];
+		let (payloads, htlc_msat, htlc_cltv) = super::build_trampoline_onion_payloads(&secp_ctx, &path,
+			&trampoline_hops, payee_pubkey, 103_000, &payment_secret, 100, &payment_hash,
+			&SecretKey::from_slice(&[45; 32]).unwrap(), [46; 32]).unwrap();
+		assert_eq!((htlc_msat, htlc_cltv), (103_000, 218));
+		let session_priv = SecretKey::from_slice(&[47; 32]).unwrap();
+		let onion_keys = super::construct_onion_keys(&secp_ctx, &path, &session_priv).unwrap();
+		let packet = super::construct_onion_packet(payloads, onion_keys, [48; 32], &payment_hash);
+
+		// The first trampoline node receives the outer onion, learning only a derived payment secret.
+		let shared_secret = SharedSecret::new(&packet.public_key.unwrap(), &trampoline_keys[0]).secret_bytes();
+		let trampoline_packet = match super::decode_next_payment_hop(shared_secret, &packet.hop_data, packet.hmac, payment_hash) {
+			Ok(super::Hop::Receive(msgs::OnionHopData {
+				format: msgs::OnionHopDataFormat::TrampolineEntrypoint { payment_data, trampoline_packet },
+				amt_to_forward: 103_000, outgoing_cltv_value: 218,
+			})) => {
+				assert_eq!(payment_data.total_msat, 103_000);
+				assert_ne!(payment_data.payment_secret, payment_secret);
+				trampoline_packet
+			},
+			_ => panic!(),
+		};
+		assert_eq!(trampoline_packet.hop_data.len(), super::TRAMPOLINE_ONION_DATA_LEN);
+
+		// It then peels the trampoline onion, learning the next trampoline node...
+		let shared_secret = SharedSecret::new(&trampoline_packet.public_key, &trampoline_keys[0]).secret_bytes();
+		let (hop_data, next_packet): (msgs::OnionHopData, Option<NextTrampolinePacket>) = super::decode_next_hop(
+			shared_secret, &trampoline_packet.hop_data, trampoline_packet.hmac, payment_hash).unwrap();
+		match hop_data.format {
+			msgs::OnionHopDataFormat::TrampolineForward { outgoing_node_id, payment_data: None } => {
+				assert_eq!(outgoing_node_id, trampoline_pubkeys[1]);
+			},
+			_ => panic!(),
+		}
+		assert_eq!((hop_data.amt_to_forward, hop_data.outgoing_cltv_value), (101_000, 158));
+
+		// ...which learns that it's the last and should pay the payee.
+		let (next_hmac, next_hop_data) = next_packet.unwrap();
+		let next_pubkey = super::next_hop_packet_pubkey(&secp_ctx, trampoline_packet.public_key, &shared_secret).unwrap();
+		let shared_secret = SharedSecret::new(&next_pubkey, &trampoline_keys[1]).secret_bytes();
+		let (hop_data, next_packet): (msgs::OnionHopData, Option<NextTrampolinePacket>) = super::decode_next_hop(
+			shared_secret, &next_hop_data, next_hmac, payment_hash).unwrap();
+		assert!(next_packet.is_none());
+		match hop_data.format {
+			msgs::OnionHopDataFormat::TrampolineForward { outgoing_node_id, payment_data: Some(payment_data) } => {
+				assert_eq!(outgoing_node_id, payee_pubkey);
+				assert_eq!(payment_data.payment_secret, payment_secret);
+				assert_eq!(payment_data.total_msat, 100_000);
+			},
+			_ => panic!(),
+		}
+		assert_eq!((hop_data.amt_to_forward, hop_data.outgoing_cltv_value), (100_000, 118));
+	}
 }
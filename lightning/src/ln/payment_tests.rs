@@ -15,24 +15,29 @@ use chain::{ChannelMonitorUpdateErr, Confirm, Listen, Watch};
 use chain::channelmonitor::{ANTI_REORG_DELAY, ChannelMonitor, LATENCY_GRACE_PERIOD_BLOCKS};
 use chain::transaction::OutPoint;
 use chain::keysinterface::KeysInterface;
+use ln::blinded_payment::{self, ForwardTlvs, INVALID_ONION_BLINDING, PaymentConstraints, PaymentRelay};
 use ln::channel::EXPIRE_PREV_CONFIG_TICKS;
-use ln::channelmanager::{BREAKDOWN_TIMEOUT, ChannelManager, ChannelManagerReadArgs, MPP_TIMEOUT_TICKS, PaymentId, PaymentSendFailure};
+use ln::channelmanager::{AbandonedShard, AbandonedShardState, BackgroundResumeHint, BREAKDOWN_TIMEOUT, ChannelManager, ChannelManagerReadArgs, NodeRole, InboundPaymentLimits, MPP_TIMEOUT_TICKS, PaymentAbandonmentRecord, PaymentId, PaymentSendFailure, TIMER_TASK_INTERVAL};
 use ln::features::{InitFeatures, InvoiceFeatures};
+use ln::{PaymentHash, PaymentSecret};
 use ln::msgs;
 use ln::msgs::ChannelMessageHandler;
-use routing::router::{PaymentParameters, get_route};
+use routing::router::{PaymentParameters, RoutingFeeLimit, TrampolineHop, get_route};
 use util::events::{ClosureReason, Event, HTLCDestination, MessageSendEvent, MessageSendEventsProvider};
 use util::test_utils;
+use util::config::OverpaymentPolicy;
 use util::errors::APIError;
 use util::enforcing_trait_impls::EnforcingSigner;
-use util::ser::{ReadableArgs, Writeable};
+use util::ser::{Readable, ReadableArgs, Writeable};
 use io;
 
 use bitcoin::{Block, BlockHeader, BlockHash, TxMerkleNode};
 use bitcoin::hashes::Hash;
 use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
 
 use prelude::*;
+use core::time::Duration;
 
 use ln::functional_test_utils::*;
 
@@ -201,6 +206,87 @@ fn mpp_retry() {
 	claim_payment_along_route(&nodes[0], &[&[&nodes[1], &nodes[3]], &[&nodes[2], &nodes[3]]], false, payment_preimage);
 }
 
+#[test]
+fn mpp_retry_fee_limit() {
+	// Check that the fees of retries are limited to what remains of the payment's fee limit after
+	// the fees of its other paths.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+
+	let (chan_1_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let (chan_2_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 0, 2, InitFeatures::known(), InitFeatures::known());
+	let (chan_3_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 1, 3, InitFeatures::known(), InitFeatures::known());
+	let (chan_4_update, _, chan_4_id, _) = create_announced_chan_between_nodes(&nodes, 3, 2, InitFeatures::known(), InitFeatures::known());
+	send_payment(&nodes[3], &[&nodes[2]], 1_500_000);
+
+	let (mut route, payment_hash, payment_preimage, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[3], 1_000_000);
+	let path = route.paths[0].clone();
+	route.paths.push(path);
+	route.paths[0][0].pubkey = nodes[1].node.get_our_node_id();
+	route.paths[0][0].short_channel_id = chan_1_update.contents.short_channel_id;
+	route.paths[0][1].short_channel_id = chan_3_update.contents.short_channel_id;
+	route.paths[1][0].pubkey = nodes[2].node.get_our_node_id();
+	route.paths[1][0].short_channel_id = chan_2_update.contents.short_channel_id;
+	route.paths[1][1].short_channel_id = chan_4_update.contents.short_channel_id;
+	// Allow 1,000 ppm of the total 2,000,000 msat, just enough for both paths' fees.
+	let path_fee_msat = route.paths[0][0].fee_msat;
+	assert_eq!(route.get_total_fees(), 2 * path_fee_msat);
+	route.payment_params.as_mut().unwrap().max_total_routing_fee =
+		Some(RoutingFeeLimit { base_msat: path_fee_msat, proportional_millionths: 1_000 });
+	assert_eq!(2_000_000 * 1_000 / 1_000_000, 2 * path_fee_msat);
+
+	let payment_id = nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 2);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	pass_along_path(&nodes[0], &[&nodes[1], &nodes[3]], 2_000_000, payment_hash, Some(payment_secret), events.remove(0), false, None);
+
+	// Fail the second path at nodes[2].
+	let (update_add, commitment_signed) = match events.remove(0) {
+		MessageSendEvent::UpdateHTLCs { updates: msgs::CommitmentUpdate { ref update_add_htlcs, ref commitment_signed, .. }, .. } =>
+			(update_add_htlcs[0].clone(), commitment_signed.clone()),
+		_ => panic!("Unexpected event"),
+	};
+	nodes[2].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &update_add);
+	commitment_signed_dance!(nodes[2], nodes[0], commitment_signed, false);
+	expect_pending_htlcs_forwardable!(&nodes[2]);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(&nodes[2], vec![HTLCDestination::NextHopChannel { node_id: Some(nodes[3].node.get_our_node_id()), channel_id: chan_4_id }]);
+	let htlc_updates = get_htlc_update_msgs!(nodes[2], nodes[0].node.get_our_node_id());
+	check_added_monitors!(nodes[2], 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[2].node.get_our_node_id(), &htlc_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[2], htlc_updates.commitment_signed, false);
+
+	// The retry may only pay the fees left over by the first path.
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentPathFailed { ref retry, all_paths_failed: false, .. } => {
+			let retry = retry.as_ref().unwrap();
+			assert_eq!(retry.final_value_msat, 1_000_000);
+			assert_eq!(retry.payment_params.max_total_routing_fee, Some(RoutingFeeLimit::absolute(path_fee_msat)));
+		},
+		_ => panic!("Unexpected event"),
+	}
+	send_payment(&nodes[3], &[&nodes[2]], 1_500_000);
+
+	let mut retry_route = route.clone();
+	retry_route.paths.remove(0);
+	retry_route.paths[0][0].fee_msat += 1;
+	if let Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err })) = nodes[0].node.retry_payment(&retry_route, payment_id) {
+		assert!(err.contains("over the payment's max_total_routing_fee_msat"));
+	} else { panic!("Unexpected error"); }
+
+	retry_route.paths[0][0].fee_msat -= 1;
+	nodes[0].node.retry_payment(&retry_route, payment_id).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	pass_along_path(&nodes[0], &[&nodes[2], &nodes[3]], 2_000_000, payment_hash, Some(payment_secret), events.pop().unwrap(), true, None);
+	claim_payment_along_route(&nodes[0], &[&[&nodes[1], &nodes[3]], &[&nodes[2], &nodes[3]]], false, payment_preimage);
+}
+
 fn do_mpp_receive_timeout(send_partial_mpp: bool) {
 	let chanmon_cfgs = create_chanmon_cfgs(4);
 	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
@@ -273,6 +359,107 @@ fn mpp_receive_timeout() {
 	do_mpp_receive_timeout(false);
 }
 
+#[test]
+fn mpp_receive_timeout_ignores_tick_bursts() {
+	// Test that a burst of timer ticks, e.g. after a mobile app returns from the background, only
+	// counts once towards timing out a partial MPP, as timer tasks run at most once per interval.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+
+	let (chan_1_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let (chan_2_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 0, 2, InitFeatures::known(), InitFeatures::known());
+	let (chan_3_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 1, 3, InitFeatures::known(), InitFeatures::known());
+	let (chan_4_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+
+	let (mut route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[3], 100_000);
+	let path = route.paths[0].clone();
+	route.paths.push(path);
+	route.paths[0][0].pubkey = nodes[1].node.get_our_node_id();
+	route.paths[0][0].short_channel_id = chan_1_update.contents.short_channel_id;
+	route.paths[0][1].short_channel_id = chan_3_update.contents.short_channel_id;
+	route.paths[1][0].pubkey = nodes[2].node.get_our_node_id();
+	route.paths[1][0].short_channel_id = chan_2_update.contents.short_channel_id;
+	route.paths[1][1].short_channel_id = chan_4_update.contents.short_channel_id;
+
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 2);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	pass_along_path(&nodes[0], &[&nodes[1], &nodes[3]], 200_000, payment_hash, Some(payment_secret), events.remove(0), false, None);
+
+	// However often we tick, each task only runs once per interval.
+	let start = Duration::from_secs(1_000);
+	for _ in 0..MPP_TIMEOUT_TICKS * 2 {
+		nodes[3].node.process_timer_tasks(start);
+	}
+	nodes[3].node.process_timer_tasks(start + TIMER_TASK_INTERVAL / 2);
+	assert_eq!(nodes[3].node.next_timer_task_deadline(), start + TIMER_TASK_INTERVAL);
+	assert!(nodes[3].node.get_and_clear_pending_events().is_empty());
+
+	// Once enough intervals have passed, the partial MPP times out.
+	for i in 1..MPP_TIMEOUT_TICKS as u32 {
+		nodes[3].node.process_timer_tasks(start + TIMER_TASK_INTERVAL * i);
+	}
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[3], vec![HTLCDestination::FailedPayment { payment_hash }]);
+	check_added_monitors!(nodes[3], 1);
+	let htlc_fail_updates = get_htlc_update_msgs!(nodes[3], nodes[1].node.get_our_node_id());
+	assert_eq!(htlc_fail_updates.update_fail_htlcs.len(), 1);
+}
+
+#[test]
+fn mpp_receive_times_out_on_resume_from_background() {
+	// Test that timer ticks are ignored while in the background, and that the time spent there
+	// is caught up on resume, timing out a partial MPP.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+
+	let (chan_1_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let (chan_2_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 0, 2, InitFeatures::known(), InitFeatures::known());
+	let (chan_3_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 1, 3, InitFeatures::known(), InitFeatures::known());
+	let (chan_4_update, _, _, _) = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+
+	let (mut route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[3], 100_000);
+	let path = route.paths[0].clone();
+	route.paths.push(path);
+	route.paths[0][0].pubkey = nodes[1].node.get_our_node_id();
+	route.paths[0][0].short_channel_id = chan_1_update.contents.short_channel_id;
+	route.paths[0][1].short_channel_id = chan_3_update.contents.short_channel_id;
+	route.paths[1][0].pubkey = nodes[2].node.get_our_node_id();
+	route.paths[1][0].short_channel_id = chan_2_update.contents.short_channel_id;
+	route.paths[1][1].short_channel_id = chan_4_update.contents.short_channel_id;
+
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 2);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	pass_along_path(&nodes[0], &[&nodes[1], &nodes[3]], 200_000, payment_hash, Some(payment_secret), events.remove(0), false, None);
+
+	let hint = nodes[3].node.prepare_for_background();
+	assert_eq!(hint.best_block_height, nodes[3].best_block_info().1);
+	assert!(hint.earliest_htlc_expiry.unwrap() > hint.best_block_height);
+	assert_eq!(BackgroundResumeHint::read(&mut io::Cursor::new(hint.encode())).unwrap(), hint);
+
+	// Timers firing in the background don't count towards the timeout.
+	for _ in 0..MPP_TIMEOUT_TICKS {
+		nodes[3].node.timer_tick_occurred();
+	}
+	assert!(nodes[3].node.get_and_clear_pending_events().is_empty());
+
+	nodes[3].node.resume_from_background(TIMER_TASK_INTERVAL * MPP_TIMEOUT_TICKS as u32);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[3], vec![HTLCDestination::FailedPayment { payment_hash }]);
+	check_added_monitors!(nodes[3], 1);
+	let htlc_fail_updates = get_htlc_update_msgs!(nodes[3], nodes[1].node.get_our_node_id());
+	assert_eq!(htlc_fail_updates.update_fail_htlcs.len(), 1);
+
+	// Resuming again does nothing.
+	nodes[3].node.resume_from_background(TIMER_TASK_INTERVAL * MPP_TIMEOUT_TICKS as u32);
+	assert!(nodes[3].node.get_and_clear_pending_events().is_empty());
+}
+
 #[test]
 fn retry_expired_payment() {
 	let chanmon_cfgs = create_chanmon_cfgs(3);
@@ -432,6 +619,7 @@ fn do_retry_with_no_persist(confirm_before_reload: bool) {
 			tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 			logger: nodes[0].logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap()
 	};
 	nodes_0_deserialized = nodes_0_deserialized_tmp;
@@ -702,6 +890,7 @@ fn do_test_dup_htlc_onchain_fails_on_reload(persist_manager_post_event: bool, co
 				tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 				logger: nodes[0].logger,
 				channel_monitors,
+				role: NodeRole::Active,
 			}).unwrap()
 	};
 	nodes_0_deserialized = nodes_0_deserialized_tmp;
@@ -792,6 +981,7 @@ fn test_fulfill_restart_failure() {
 				tx_broadcaster: nodes[1].tx_broadcaster.clone(),
 				logger: nodes[1].logger,
 				channel_monitors,
+				role: NodeRole::Active,
 			}).unwrap()
 	};
 	nodes_1_deserialized = nodes_1_deserialized_tmp;
@@ -975,3 +1165,500 @@ fn failed_probe_yields_event() {
 		_ => panic!(),
 	};
 }
+
+fn do_test_inbound_invoice_overpayment(overpayment_policy: OverpaymentPolicy, overpayment_accepted: bool) {
+	// Check that we fail payments which overpay an invoice by more than our configured policy allows.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let mut recipient_config = test_default_channel_config();
+	recipient_config.payment_amount_policy.inbound_invoice_overpayment = overpayment_policy;
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, Some(recipient_config)]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	// Pay 10% more than the invoice asked for.
+	let (payment_hash, payment_secret) = nodes[1].node.create_inbound_payment(Some(100_000), 7200).unwrap();
+	let payment_preimage = nodes[1].node.get_payment_preimage(payment_hash, payment_secret).unwrap();
+	let (route, _, _, _) = get_route_and_payment_hash!(nodes[0], nodes[1], 110_000);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+
+	if overpayment_accepted {
+		pass_along_path(&nodes[0], &[&nodes[1]], 110_000, payment_hash, Some(payment_secret), events.pop().unwrap(), true, Some(payment_preimage));
+		claim_payment_along_route(&nodes[0], &[&[&nodes[1]]], false, payment_preimage);
+	} else {
+		do_pass_along_path(&nodes[0], &[&nodes[1]], 110_000, payment_hash, Some(payment_secret), events.pop().unwrap(), false, false, None);
+		expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[1], vec![HTLCDestination::FailedPayment { payment_hash }]);
+		check_added_monitors!(nodes[1], 1);
+		let htlc_fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+		assert_eq!(htlc_fail_updates.update_fail_htlcs.len(), 1);
+		nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &htlc_fail_updates.update_fail_htlcs[0]);
+		commitment_signed_dance!(nodes[0], nodes[1], htlc_fail_updates.commitment_signed, false);
+		expect_payment_failed_conditions(&nodes[0], payment_hash, true, PaymentFailedConditions::new());
+	}
+}
+
+#[test]
+fn test_inbound_invoice_overpayment() {
+	do_test_inbound_invoice_overpayment(OverpaymentPolicy::Any, true);
+	do_test_inbound_invoice_overpayment(OverpaymentPolicy::UpToPercent(10), true);
+	do_test_inbound_invoice_overpayment(OverpaymentPolicy::UpToPercent(9), false);
+	do_test_inbound_invoice_overpayment(OverpaymentPolicy::Exact, false);
+}
+
+#[test]
+fn test_outbound_mpp_overshoot() {
+	// Check that we refuse retries which would overshoot the payment's total amount by more than
+	// our configured policy allows.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let mut sender_config = test_default_channel_config();
+	sender_config.payment_amount_policy.outbound_mpp_overshoot = OverpaymentPolicy::Exact;
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[Some(sender_config), None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let (mut route, payment_hash, payment_preimage, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[1], 1_000_000);
+	let payment_id = nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+
+	// The default policy would allow retrying another 5% of the payment, but we only allow paying
+	// exactly the payment's amount.
+	route.paths[0].last_mut().unwrap().fee_msat = 50_000;
+	if let Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err })) = nodes[0].node.retry_payment(&route, payment_id) {
+		assert!(err.contains("over total_payment_amt_msat"));
+	} else { panic!("Unexpected result"); }
+
+	pass_along_path(&nodes[0], &[&nodes[1]], 1_000_000, payment_hash, Some(payment_secret), events.pop().unwrap(), true, None);
+	claim_payment_along_route(&nodes[0], &[&[&nodes[1]]], false, payment_preimage);
+}
+
+#[test]
+fn abandon_payment_force_records_shards() {
+	// Tests that force-abandoning a payment records the state of its HTLCs and that they are not
+	// retried once they fail back.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let (route, _, _, _) = get_route_and_payment_hash!(nodes[0], nodes[1], 100_000);
+	let (_, payment_hash, _, payment_id) = send_along_route(&nodes[0], route.clone(), &[&nodes[1]], 100_000);
+	assert!(nodes[0].node.abandon_payment_force(PaymentId([42; 32])).is_none());
+
+	let record = nodes[0].node.abandon_payment_force(payment_id).unwrap();
+	assert_eq!(record.payment_hash, payment_hash);
+	assert_eq!(record.abandoned_at_height, nodes[0].best_block_info().1);
+	assert_eq!(record.shards, vec![AbandonedShard {
+		channel_id: Some(chan.2),
+		path: route.paths[0].clone(),
+		state: AbandonedShardState::Committed,
+	}]);
+	assert_eq!(nodes[0].node.get_payment_abandonment_record(payment_id), Some(record.clone()));
+	assert_eq!(nodes[0].node.list_payment_abandonment_records(), vec![record.clone()]);
+	assert_eq!(PaymentAbandonmentRecord::read(&mut io::Cursor::new(&record.encode())).unwrap(), record);
+	match nodes[0].node.retry_payment(&route, payment_id) {
+		Err(PaymentSendFailure::ParameterError(_)) => {},
+		_ => panic!(),
+	}
+
+	nodes[1].node.fail_htlc_backwards(&payment_hash);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[1], vec![HTLCDestination::FailedPayment { payment_hash }]);
+	check_added_monitors!(nodes[1], 1);
+	let htlc_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &htlc_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], htlc_updates.commitment_signed, false);
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 2);
+	match events[0] {
+		Event::PaymentPathFailed { payment_hash: ev_payment_hash, ref retry, all_paths_failed, .. } => {
+			assert_eq!(ev_payment_hash, payment_hash);
+			assert!(retry.is_none());
+			assert!(all_paths_failed);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	match events[1] {
+		Event::PaymentFailed { payment_id: ev_payment_id, .. } => assert_eq!(ev_payment_id, payment_id),
+		_ => panic!("Unexpected event"),
+	}
+
+	// The record outlives the payment itself until it is removed.
+	assert_eq!(nodes[0].node.remove_payment_abandonment_record(payment_id), Some(record));
+	assert!(nodes[0].node.list_payment_abandonment_records().is_empty());
+}
+
+#[test]
+fn inbound_payment_limits() {
+	// Tests that HTLCs which would take a payment beyond the limits registered for it are failed.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+
+	let chan_1_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).0.contents.short_channel_id;
+	let chan_2_id = create_announced_chan_between_nodes(&nodes, 0, 2, InitFeatures::known(), InitFeatures::known()).0.contents.short_channel_id;
+	let chan_3_id = create_announced_chan_between_nodes(&nodes, 1, 3, InitFeatures::known(), InitFeatures::known()).0.contents.short_channel_id;
+	let chan_4 = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+	let chan_4_id = chan_4.0.contents.short_channel_id;
+
+	assert!(nodes[3].node.create_inbound_payment_with_limits(Some(200_000), 7200,
+		InboundPaymentLimits { max_parts: Some(0), ..Default::default() }).is_err());
+	assert!(nodes[3].node.create_inbound_payment_with_limits(Some(200_000), 7200,
+		InboundPaymentLimits { max_total_msat: Some(100_000), ..Default::default() }).is_err());
+	let (payment_hash, payment_secret) = nodes[3].node.create_inbound_payment_with_limits(Some(200_000), 7200,
+		InboundPaymentLimits { max_total_msat: Some(250_000), max_parts: Some(1) }).unwrap();
+
+	let (mut route, _, _, _) = get_route_and_payment_hash!(&nodes[0], nodes[3], 100_000);
+	let path = route.paths[0].clone();
+	route.paths.push(path);
+	route.paths[0][0].pubkey = nodes[1].node.get_our_node_id();
+	route.paths[0][0].short_channel_id = chan_1_id;
+	route.paths[0][1].short_channel_id = chan_3_id;
+	route.paths[1][0].pubkey = nodes[2].node.get_our_node_id();
+	route.paths[1][0].short_channel_id = chan_2_id;
+	route.paths[1][1].short_channel_id = chan_4_id;
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 2);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	if SendEvent::from_event(events[0].clone()).node_id != nodes[1].node.get_our_node_id() {
+		events.swap(0, 1);
+	}
+
+	// The first part is accepted, but the second would exceed the limit on the number of parts.
+	pass_along_path(&nodes[0], &[&nodes[1], &nodes[3]], 200_000, payment_hash, Some(payment_secret), events.remove(0), false, None);
+	do_pass_along_path(&nodes[0], &[&nodes[2], &nodes[3]], 200_000, payment_hash, Some(payment_secret), events.remove(0), false, false, None);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[3], vec![HTLCDestination::FailedPayment { payment_hash }]);
+	check_added_monitors!(nodes[3], 1);
+	let fail_updates = get_htlc_update_msgs!(nodes[3], nodes[2].node.get_our_node_id());
+	nodes[2].node.handle_update_fail_htlc(&nodes[3].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[2], nodes[3], fail_updates.commitment_signed, false);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[2], vec![HTLCDestination::NextHopChannel { node_id: Some(nodes[3].node.get_our_node_id()), channel_id: chan_4.2 }]);
+	check_added_monitors!(nodes[2], 1);
+	let fail_updates = get_htlc_update_msgs!(nodes[2], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fail_htlc(&nodes[2].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[2], fail_updates.commitment_signed, false);
+	expect_payment_failed_conditions(&nodes[0], payment_hash, true, PaymentFailedConditions::new().mpp_parts_remain());
+}
+
+/// Sends a payment from `nodes[0]` to `nodes[2]` via `nodes[1]` as its trampoline node, passing
+/// it to `nodes[1]` and returning its `TrampolineForwardRequested` event.
+fn send_trampoline_payment<'a, 'b, 'c>(nodes: &[Node<'a, 'b, 'c>], trampoline_fee_msat: u64, payment_hash: PaymentHash, payment_secret: PaymentSecret) -> Event {
+	let payment_params = PaymentParameters::from_node_id(nodes[2].node.get_our_node_id())
+		.with_features(InvoiceFeatures::known())
+		.with_trampoline_hops(vec![TrampolineHop {
+			pubkey: nodes[1].node.get_our_node_id(), fee_msat: trampoline_fee_msat, cltv_expiry_delta: 144 + 72,
+		}]);
+	let route = get_route!(nodes[0], payment_params, 100_000, TEST_FINAL_CLTV).unwrap();
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	let mut events = nodes[1].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	events.pop().unwrap()
+}
+
+/// Fails back the HTLC `nodes[1]` failed back to `nodes[0]`, checking its failure code.
+fn fail_trampoline_payment_back<'a, 'b, 'c>(nodes: &[Node<'a, 'b, 'c>], payment_hash: PaymentHash, failure_code: u16) {
+	check_added_monitors!(nodes[1], 1);
+	let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false);
+	expect_trampoline_payment_failed(&nodes[0], payment_hash, failure_code);
+}
+
+/// Checks that `node` saw its trampoline payment fail at its trampoline node with `failure_code`.
+fn expect_trampoline_payment_failed<'a, 'b, 'c>(node: &Node<'a, 'b, 'c>, payment_hash: PaymentHash, failure_code: u16) {
+	let events = node.node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentPathFailed { payment_hash: ev_hash, all_paths_failed: true, error_code, ref error_data, .. } => {
+			assert_eq!(ev_hash, payment_hash);
+			assert_eq!(error_code, Some(failure_code));
+			assert_eq!(error_data.as_ref().unwrap(), &Vec::new());
+		},
+		_ => panic!("Unexpected event"),
+	}
+}
+
+#[test]
+fn trampoline_payment_relay() {
+	// Tests that a node with trampoline forwarding enabled relays trampoline payments along a route
+	// within the payment's budget, claiming the inbound HTLC once the outbound one is claimed.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut relay_config = test_default_channel_config();
+	relay_config.trampoline_forwarding.enabled = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(relay_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let (payment_preimage, payment_hash, payment_secret) = get_payment_preimage_hash!(nodes[2], Some(100_000));
+	let route_params = match send_trampoline_payment(&nodes, 2_000, payment_hash, payment_secret) {
+		Event::TrampolineForwardRequested { payment_hash: ev_hash, next_node_id, route_params } => {
+			assert_eq!(ev_hash, payment_hash);
+			assert_eq!(next_node_id, nodes[2].node.get_our_node_id());
+			assert_eq!(route_params.final_value_msat, 100_000);
+			// We received 102_000 msat, and keep 1_000 msat plus 1_000 ppm for ourselves.
+			assert_eq!(route_params.payment_params.max_total_routing_fee, Some(RoutingFeeLimit::absolute(900)));
+			route_params
+		},
+		_ => panic!("Unexpected event"),
+	};
+	let route = get_route!(nodes[1], route_params.payment_params, route_params.final_value_msat, route_params.final_cltv_expiry_delta).unwrap();
+
+	// A route delivering the wrong amount is rejected, but we may still try again.
+	let mut bogus_route = route.clone();
+	bogus_route.paths[0].last_mut().unwrap().fee_msat = 99_000;
+	match nodes[1].node.forward_trampoline_payment(payment_hash, &bogus_route) {
+		Err(PaymentSendFailure::ParameterError(APIError::RouteError { .. })) => {},
+		_ => panic!("Unexpected result"),
+	}
+	nodes[1].node.forward_trampoline_payment(payment_hash, &route).unwrap();
+	check_added_monitors!(nodes[1], 1);
+	// The payment may only be forwarded once.
+	assert!(nodes[1].node.forward_trampoline_payment(payment_hash, &route).is_err());
+	assert!(nodes[1].node.fail_trampoline_forward(&payment_hash).is_err());
+
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	pass_along_path(&nodes[1], &[&nodes[2]], 100_000, payment_hash, Some(payment_secret), events.pop().unwrap(), true, None);
+
+	nodes[2].node.claim_funds(payment_preimage);
+	expect_payment_claimed!(nodes[2], payment_hash, 100_000);
+	check_added_monitors!(nodes[2], 1);
+	let fulfill_updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_update_fulfill_htlc(&nodes[2].node.get_our_node_id(), &fulfill_updates.update_fulfill_htlcs[0]);
+	check_added_monitors!(nodes[1], 1);
+	let events = nodes[1].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::TrampolinePaymentForwarded { payment_hash: ev_hash, fee_earned_msat } => {
+			assert_eq!(ev_hash, payment_hash);
+			assert_eq!(fee_earned_msat, 2_000);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	let claim_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	commitment_signed_dance!(nodes[1], nodes[2], fulfill_updates.commitment_signed, false);
+	let events = nodes[1].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentPathSuccessful { payment_hash: Some(ev_hash), .. } => assert_eq!(ev_hash, payment_hash),
+		_ => panic!("Unexpected event"),
+	}
+	nodes[0].node.handle_update_fulfill_htlc(&nodes[1].node.get_our_node_id(), &claim_updates.update_fulfill_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], claim_updates.commitment_signed, false);
+	expect_payment_sent!(nodes[0], payment_preimage);
+}
+
+#[test]
+fn trampoline_payment_relay_failures() {
+	// Tests the ways in which a trampoline payment we're to relay is failed back.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut relay_config = test_default_channel_config();
+	relay_config.trampoline_forwarding.enabled = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(relay_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	// A payment which doesn't leave us our fee is failed as soon as it's received.
+	let (_, payment_hash, payment_secret) = get_payment_preimage_hash!(nodes[2], Some(100_000));
+	let payment_params = PaymentParameters::from_node_id(nodes[2].node.get_our_node_id())
+		.with_trampoline_hops(vec![TrampolineHop {
+			pubkey: nodes[1].node.get_our_node_id(), fee_msat: 1_000, cltv_expiry_delta: 144 + 72,
+		}]);
+	let route = get_route!(nodes[0], payment_params, 100_000, TEST_FINAL_CLTV).unwrap();
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, true, true);
+	let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false);
+	expect_trampoline_payment_failed(&nodes[0], payment_hash, 0x2000 | 51);
+
+	// A payment for which no route within its budget can be found may be failed by the user.
+	let (_, payment_hash, payment_secret) = get_payment_preimage_hash!(nodes[2], Some(100_000));
+	let route_params = match send_trampoline_payment(&nodes, 1_100, payment_hash, payment_secret) {
+		Event::TrampolineForwardRequested { route_params, .. } => {
+			assert_eq!(route_params.payment_params.max_total_routing_fee, Some(RoutingFeeLimit::absolute(0)));
+			route_params
+		},
+		_ => panic!("Unexpected event"),
+	};
+	let mut route = get_route!(nodes[1], route_params.payment_params, route_params.final_value_msat, route_params.final_cltv_expiry_delta).unwrap();
+	route.paths[0][0].fee_msat += 1;
+	match nodes[1].node.forward_trampoline_payment(payment_hash, &route) {
+		Err(PaymentSendFailure::ParameterError(APIError::RouteError { .. })) => {},
+		_ => panic!("Unexpected result"),
+	}
+	nodes[1].node.fail_trampoline_forward(&payment_hash).unwrap();
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[1], vec![HTLCDestination::FailedPayment { payment_hash }]);
+	fail_trampoline_payment_back(&nodes, payment_hash, 0x2000 | 25);
+
+	// A payment which fails downstream is failed back once all of its outbound parts have failed.
+	let (_, payment_hash, payment_secret) = get_payment_preimage_hash!(nodes[2], Some(100_000));
+	let route_params = match send_trampoline_payment(&nodes, 2_000, payment_hash, payment_secret) {
+		Event::TrampolineForwardRequested { route_params, .. } => route_params,
+		_ => panic!("Unexpected event"),
+	};
+	let route = get_route!(nodes[1], route_params.payment_params, route_params.final_value_msat, route_params.final_cltv_expiry_delta).unwrap();
+	nodes[1].node.forward_trampoline_payment(payment_hash, &route).unwrap();
+	check_added_monitors!(nodes[1], 1);
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	pass_along_path(&nodes[1], &[&nodes[2]], 100_000, payment_hash, Some(payment_secret), events.pop().unwrap(), true, None);
+	nodes[2].node.fail_htlc_backwards(&payment_hash);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[2], vec![HTLCDestination::FailedPayment { payment_hash }]);
+	check_added_monitors!(nodes[2], 1);
+	let fail_updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_update_fail_htlc(&nodes[2].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[1], nodes[2], fail_updates.commitment_signed, false);
+	let events = nodes[1].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 3);
+	match events[0] {
+		Event::PaymentPathFailed { payment_hash: ev_hash, all_paths_failed: true, retry: None, .. } => assert_eq!(ev_hash, payment_hash),
+		_ => panic!("Unexpected event"),
+	}
+	expect_htlc_handling_failed_destinations!(&events[1..], vec![HTLCDestination::FailedPayment { payment_hash }]);
+	nodes[1].node.process_pending_htlc_forwards();
+	fail_trampoline_payment_back(&nodes, payment_hash, 0x2000 | 25);
+}
+
+#[test]
+fn blinded_forward_failure_returned_as_invalid_onion_blinding() {
+	// Send a payment to D over a blinded route B -> C -> D, with B as the introduction node. B and
+	// C forward it per the relay parameters D encrypted to them, but D fails it as we don't
+	// support receiving over blinded routes. C must then fail it back malformed, and B must turn
+	// that into an invalid_onion_blinding failure from itself.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_bc = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let chan_cd = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+
+	let (mut route, payment_hash, _, payment_secret) = get_route_and_payment_hash!(nodes[0], nodes[3], 100_000);
+	assert_eq!(route.paths[0].len(), 3);
+	let cur_height = nodes[0].best_block_info().1;
+
+	let payment_relay = PaymentRelay { cltv_expiry_delta: 144, fee_proportional_millionths: 1_000, fee_base_msat: 1_000 };
+	let payment_constraints = PaymentConstraints { max_cltv_expiry: cur_height + 1_000, htlc_minimum_msat: 1_000 };
+	let forward_tlvs = |short_channel_id| ForwardTlvs {
+		short_channel_id, payment_relay: payment_relay.clone(), payment_constraints: payment_constraints.clone(),
+		next_blinding_override: None,
+	}.encode();
+	// D's TLVs would carry a path_id, which we don't support receiving to.
+	let receive_tlvs = vec![6, 32].into_iter().chain([42; 32].iter().cloned()).collect();
+	let secp_ctx = Secp256k1::new();
+	let session_priv = SecretKey::from_slice(&[42; 32]).unwrap();
+	let (blinding_point, blinded_hops) = blinded_payment::blind_hops(&secp_ctx, &session_priv, &[
+		(nodes[1].node.get_our_node_id(), forward_tlvs(chan_bc.0.contents.short_channel_id)),
+		(nodes[2].node.get_our_node_id(), forward_tlvs(chan_cd.0.contents.short_channel_id)),
+		(nodes[3].node.get_our_node_id(), receive_tlvs),
+	]);
+
+	// The introduction node is addressed by its real node id, while later hops' onions are
+	// encrypted to their blinded node ids.
+	route.paths[0][1].pubkey = blinded_hops[1].0;
+	route.paths[0][2].pubkey = blinded_hops[2].0;
+	route.payment_params = Some(PaymentParameters::from_node_id(blinded_hops[2].0));
+	let onion_payloads = blinded_hops.iter().enumerate().map(|(idx, (_, encrypted_tlvs))| msgs::OnionHopData {
+		format: msgs::OnionHopDataFormat::Blinded {
+			encrypted_tlvs: encrypted_tlvs.clone(),
+			intro_node_blinding_point: if idx == 0 { Some(blinding_point) } else { None },
+		},
+		amt_to_forward: 0,
+		outgoing_cltv_value: 0,
+	}).collect();
+	// C forwards 100_000 msat to D, taking 1_100 msat in fees, and B takes another 1_101 msat.
+	let htlc_msat = 102_201;
+	let htlc_cltv = cur_height + 400;
+	nodes[0].node.send_onion_along_path(&route.paths[0], &route.payment_params, &payment_hash, &Some(payment_secret),
+		100_000, PaymentId(payment_hash.0), onion_payloads, htlc_msat, htlc_cltv).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.remove(0));
+	assert!(payment_event.msgs[0].blinding_point.is_none());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.remove(0));
+	assert_eq!(payment_event.msgs[0].amount_msat, 101_100);
+	assert_eq!(payment_event.msgs[0].cltv_expiry, htlc_cltv - 144);
+	assert!(payment_event.msgs[0].blinding_point.is_some());
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[2]);
+	check_added_monitors!(nodes[2], 1);
+
+	let mut events = nodes[2].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.remove(0));
+	assert_eq!(payment_event.msgs[0].amount_msat, 100_000);
+	assert_eq!(payment_event.msgs[0].cltv_expiry, htlc_cltv - 288);
+	assert!(payment_event.msgs[0].blinding_point.is_some());
+	nodes[3].node.handle_update_add_htlc(&nodes[2].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[3], nodes[2], payment_event.commitment_msg, false, true);
+
+	// D fails the HTLC back malformed, as it was received within a blinded route.
+	let updates = get_htlc_update_msgs!(nodes[3], nodes[2].node.get_our_node_id());
+	assert!(updates.update_fail_htlcs.is_empty());
+	assert_eq!(updates.update_fail_malformed_htlcs.len(), 1);
+	assert_eq!(updates.update_fail_malformed_htlcs[0].failure_code, INVALID_ONION_BLINDING);
+	assert_eq!(updates.update_fail_malformed_htlcs[0].sha256_of_onion, [0; 32]);
+	nodes[2].node.handle_update_fail_malformed_htlc(&nodes[3].node.get_our_node_id(), &updates.update_fail_malformed_htlcs[0]);
+	commitment_signed_dance!(nodes[2], nodes[3], updates.commitment_signed, false, true);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[2],
+		vec![HTLCDestination::NextHopChannel { node_id: Some(nodes[3].node.get_our_node_id()), channel_id: chan_cd.2 }]);
+	check_added_monitors!(nodes[2], 1);
+
+	// C, being within the blinded route, fails it back malformed too.
+	let updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	assert!(updates.update_fail_htlcs.is_empty());
+	assert_eq!(updates.update_fail_malformed_htlcs.len(), 1);
+	assert_eq!(updates.update_fail_malformed_htlcs[0].failure_code, INVALID_ONION_BLINDING);
+	assert_eq!(updates.update_fail_malformed_htlcs[0].sha256_of_onion, [0; 32]);
+	nodes[1].node.handle_update_fail_malformed_htlc(&nodes[2].node.get_our_node_id(), &updates.update_fail_malformed_htlcs[0]);
+	commitment_signed_dance!(nodes[1], nodes[2], updates.commitment_signed, false, true);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[1],
+		vec![HTLCDestination::NextHopChannel { node_id: Some(nodes[2].node.get_our_node_id()), channel_id: chan_bc.2 }]);
+	check_added_monitors!(nodes[1], 1);
+
+	// B, the introduction node, fails it back as invalid_onion_blinding from itself.
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	assert!(updates.update_fail_malformed_htlcs.is_empty());
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+	expect_payment_failed_conditions(&nodes[0], payment_hash, false,
+		PaymentFailedConditions::new().expected_htlc_error_data(INVALID_ONION_BLINDING, &[0; 32]));
+}
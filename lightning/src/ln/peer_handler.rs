@@ -296,6 +296,89 @@ impl error::Error for PeerHandleError {
 	}
 }
 
+/// The reason a message received from a peer was considered invalid, as counted in
+/// [`PeerMessageStats::invalid_received`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum InvalidMessageCategory {
+	/// The message was of an unknown even type, or required an unknown feature or TLV.
+	UnknownRequired,
+	/// The message had a version we don't understand.
+	UnknownVersion,
+	/// The message contained a value which was invalid.
+	InvalidValue,
+	/// The message was shorter than its contents required.
+	ShortRead,
+	/// A length descriptor in the message was inconsistent with its contents.
+	BadLengthDescriptor,
+	/// The message used a compression scheme we don't support.
+	UnsupportedCompression,
+	/// Reading the message failed with an I/O error.
+	Io,
+	/// The message was read successfully but was rejected by one of our message handlers.
+	RejectedByHandler,
+}
+
+impl<'a> From<&'a msgs::DecodeError> for InvalidMessageCategory {
+	fn from(error: &'a msgs::DecodeError) -> Self {
+		match error {
+			msgs::DecodeError::UnknownVersion => InvalidMessageCategory::UnknownVersion,
+			msgs::DecodeError::UnknownRequiredFeature => InvalidMessageCategory::UnknownRequired,
+			msgs::DecodeError::InvalidValue => InvalidMessageCategory::InvalidValue,
+			msgs::DecodeError::ShortRead => InvalidMessageCategory::ShortRead,
+			msgs::DecodeError::BadLengthDescriptor => InvalidMessageCategory::BadLengthDescriptor,
+			msgs::DecodeError::Io(_) => InvalidMessageCategory::Io,
+			msgs::DecodeError::UnsupportedCompression => InvalidMessageCategory::UnsupportedCompression,
+		}
+	}
+}
+
+/// Counts of the messages exchanged with a peer over its current connection, as returned by
+/// [`PeerManager::get_message_stats`].
+///
+/// These allow interoperability problems with specific peers (and thus, often, specific
+/// implementations) to be identified from the field.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerMessageStats {
+	/// The number of messages we've sent the peer, by message type.
+	pub sent: HashMap<u16, u64>,
+	/// The number of messages we've received from the peer and successfully read, by message
+	/// type. Messages which were later rejected by one of our message handlers are included.
+	pub received: HashMap<u16, u64>,
+	/// The number of invalid messages we've received from the peer, by the reason they were
+	/// invalid.
+	pub invalid_received: HashMap<InvalidMessageCategory, u64>,
+}
+
+impl PeerMessageStats {
+	fn record_sent(&mut self, type_id: u16) {
+		*self.sent.entry(type_id).or_insert(0) += 1;
+	}
+
+	fn record_received(&mut self, type_id: u16) {
+		*self.received.entry(type_id).or_insert(0) += 1;
+	}
+
+	fn record_invalid(&mut self, category: InvalidMessageCategory) {
+		*self.invalid_received.entry(category).or_insert(0) += 1;
+	}
+}
+
+/// A handler which is periodically given the [`PeerMessageStats`] of all connected peers.
+///
+/// Set via [`PeerManager::set_message_stats_handler`]. The handler is called from
+/// [`PeerManager::timer_tick_occurred`] without any peer locks held, but must not call
+/// [`PeerManager::set_message_stats_handler`] itself.
+pub trait PeerMessageStatsHandler {
+	/// Handles the current [`PeerMessageStats`] of each connected peer.
+	fn handle_message_stats(&self, stats: Vec<(PublicKey, PeerMessageStats)>);
+}
+
+struct MessageStatsReporter {
+	handler: Box<dyn PeerMessageStatsHandler + Send>,
+	interval_ticks: u32,
+	ticks_elapsed: u32,
+}
+
 enum InitSyncTracker{
 	NoSyncRequested,
 	ChannelsSyncing(u64),
@@ -341,6 +424,27 @@ const MAX_BUFFER_DRAIN_TICK_INTERVALS_PER_PEER: i8 = 4;
 /// [`FORWARD_INIT_SYNC_BUFFER_LIMIT_RATIO`]) than a hard limit.
 const BUFFER_DRAIN_MSGS_PER_TICK: usize = 32;
 
+/// How we request gossip from a peer, set via [`PeerManager::set_gossip_sync_strategy`] or
+/// [`PeerManager::set_default_gossip_sync_strategy`].
+///
+/// Peers supporting `gossip_queries` only send us gossip once we send them a
+/// `gossip_timestamp_filter`. Absent a strategy, the filter generated by the
+/// [`RoutingMessageHandler`] is sent unmodified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GossipSyncStrategy {
+	/// Request all gossip the peer has, followed by any new gossip as it is received.
+	Full,
+	/// Request only gossip with a timestamp at or after `first_timestamp`, followed by any new
+	/// gossip as it is received.
+	FilterByTimestamp {
+		/// The earliest gossip timestamp to request, in seconds since the UNIX epoch.
+		first_timestamp: u32,
+	},
+	/// Don't request any gossip from the peer, e.g. for nodes which only sync the network graph via
+	/// rapid gossip sync.
+	Disabled,
+}
+
 struct Peer {
 	channel_encryptor: PeerChannelEncryptor,
 	their_node_id: Option<PublicKey>,
@@ -364,6 +468,8 @@ struct Peer {
 	awaiting_pong_timer_tick_intervals: i8,
 	received_message_since_timer_tick: bool,
 	sent_gossip_timestamp_filter: bool,
+
+	message_stats: PeerMessageStats,
 }
 
 impl Peer {
@@ -509,6 +615,15 @@ pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: D
 
 	peer_counter: AtomicCounter,
 
+	/// The handler periodically given our peers' [`PeerMessageStats`], if any, see
+	/// [`PeerManager::set_message_stats_handler`].
+	message_stats_reporter: Mutex<Option<MessageStatsReporter>>,
+
+	/// The [`GossipSyncStrategy`] for specific peers, see [`PeerManager::set_gossip_sync_strategy`].
+	gossip_sync_strategies: Mutex<HashMap<PublicKey, GossipSyncStrategy>>,
+	/// The [`GossipSyncStrategy`] for peers not in `gossip_sync_strategies`, if any.
+	default_gossip_sync_strategy: Mutex<Option<GossipSyncStrategy>>,
+
 	logger: L,
 	secp_ctx: Secp256k1<secp256k1::SignOnly>
 }
@@ -647,6 +762,9 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 			our_node_secret,
 			ephemeral_key_midstate,
 			peer_counter: AtomicCounter::new(),
+			message_stats_reporter: Mutex::new(None),
+			gossip_sync_strategies: Mutex::new(HashMap::new()),
+			default_gossip_sync_strategy: Mutex::new(None),
 			logger,
 			custom_message_handler,
 			secp_ctx,
@@ -669,6 +787,63 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 		}).collect()
 	}
 
+	/// Gets the [`PeerMessageStats`] for the current connection to each peer which has completed
+	/// the initial handshake.
+	///
+	/// Note that stats are tracked per connection and thus reset whenever a peer reconnects.
+	pub fn get_message_stats(&self) -> Vec<(PublicKey, PeerMessageStats)> {
+		let peers = self.peers.read().unwrap();
+		peers.values().filter_map(|peer_mutex| {
+			let p = peer_mutex.lock().unwrap();
+			if !p.channel_encryptor.is_ready_for_encryption() || p.their_features.is_none() {
+				return None;
+			}
+			p.their_node_id.map(|node_id| (node_id, p.message_stats.clone()))
+		}).collect()
+	}
+
+	/// Gets the [`PeerMessageStats`] for the current connection to the peer with the given
+	/// `node_id`, if we're connected to it.
+	pub fn get_peer_message_stats(&self, node_id: &PublicKey) -> Option<PeerMessageStats> {
+		let peers = self.peers.read().unwrap();
+		let descriptor = self.node_id_to_descriptor.lock().unwrap().get(node_id).cloned();
+		descriptor.and_then(|descriptor| peers.get(&descriptor))
+			.map(|peer_mutex| peer_mutex.lock().unwrap().message_stats.clone())
+	}
+
+	/// Sets a [`PeerMessageStatsHandler`] which is given the [`PeerMessageStats`] of all connected
+	/// peers every `interval_ticks` calls to [`PeerManager::timer_tick_occurred`], replacing any
+	/// previously set handler.
+	pub fn set_message_stats_handler(&self, handler: Box<dyn PeerMessageStatsHandler + Send>, interval_ticks: u32) {
+		*self.message_stats_reporter.lock().unwrap() = Some(MessageStatsReporter {
+			handler, interval_ticks, ticks_elapsed: 0,
+		});
+	}
+
+	/// Sets the [`GossipSyncStrategy`] used for the peer with the given `node_id` the next time it
+	/// connects, overriding any default set via [`PeerManager::set_default_gossip_sync_strategy`].
+	/// Passing `None` reverts the peer to the default.
+	pub fn set_gossip_sync_strategy(&self, node_id: PublicKey, strategy: Option<GossipSyncStrategy>) {
+		let mut strategies = self.gossip_sync_strategies.lock().unwrap();
+		match strategy {
+			Some(strategy) => { strategies.insert(node_id, strategy); },
+			None => { strategies.remove(&node_id); },
+		}
+	}
+
+	/// Sets the [`GossipSyncStrategy`] used for peers without one set via
+	/// [`PeerManager::set_gossip_sync_strategy`]. Passing `None` (the default) sends the
+	/// `gossip_timestamp_filter` generated by the [`RoutingMessageHandler`] unmodified.
+	pub fn set_default_gossip_sync_strategy(&self, strategy: Option<GossipSyncStrategy>) {
+		*self.default_gossip_sync_strategy.lock().unwrap() = strategy;
+	}
+
+	/// Gets the [`GossipSyncStrategy`] which applies to the peer with the given `node_id`, if any.
+	pub fn get_gossip_sync_strategy(&self, node_id: &PublicKey) -> Option<GossipSyncStrategy> {
+		self.gossip_sync_strategies.lock().unwrap().get(node_id).cloned()
+			.or(*self.default_gossip_sync_strategy.lock().unwrap())
+	}
+
 	fn get_ephemeral_key(&self) -> SecretKey {
 		let mut ephemeral_hash = self.ephemeral_key_midstate.clone();
 		let counter = self.peer_counter.get_increment();
@@ -718,6 +893,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 			awaiting_pong_timer_tick_intervals: 0,
 			received_message_since_timer_tick: false,
 			sent_gossip_timestamp_filter: false,
+
+			message_stats: PeerMessageStats::default(),
 		})).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
@@ -765,6 +942,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 			awaiting_pong_timer_tick_intervals: 0,
 			received_message_since_timer_tick: false,
 			sent_gossip_timestamp_filter: false,
+
+			message_stats: PeerMessageStats::default(),
 		})).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
@@ -911,12 +1090,16 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 			log_trace!(self.logger, "Enqueueing message {:?} to {}", message, log_pubkey!(peer.their_node_id.unwrap()))
 		}
 		peer.msgs_sent_since_pong += 1;
+		peer.message_stats.record_sent(message.type_id());
 		peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&buffer.0[..]));
 	}
 
 	/// Append a message to a peer's pending outbound/write gossip broadcast buffer
 	fn enqueue_encoded_gossip_broadcast(&self, peer: &mut Peer, encoded_message: &Vec<u8>) {
 		peer.msgs_sent_since_pong += 1;
+		if encoded_message.len() >= 2 {
+			peer.message_stats.record_sent(u16::from_be_bytes([encoded_message[0], encoded_message[1]]));
+		}
 		peer.gossip_broadcast_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_message[..]));
 	}
 
@@ -1066,8 +1249,12 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 									let mut reader = io::Cursor::new(&msg_data[..]);
 									let message_result = wire::read(&mut reader, &*self.custom_message_handler);
 									let message = match message_result {
-										Ok(x) => x,
+										Ok(x) => {
+											peer.message_stats.record_received(x.type_id());
+											x
+										},
 										Err(e) => {
+											peer.message_stats.record_invalid(InvalidMessageCategory::from(&e.0));
 											match e {
 												// Note that to avoid recursion we never call
 												// `do_attempt_write_data` from here, causing
@@ -1119,7 +1306,11 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 							Err(handling_error) => match handling_error {
 								MessageHandlingError::PeerHandleError(e) => { return Err(e) },
 								MessageHandlingError::LightningError(e) => {
-									try_potential_handleerror!(&mut peer_mutex.lock().unwrap(), Err(e));
+									let mut peer = peer_mutex.lock().unwrap();
+									if let msgs::ErrorAction::IgnoreDuplicateGossip = e.action {} else {
+										peer.message_stats.record_invalid(InvalidMessageCategory::RejectedByHandler);
+									}
+									try_potential_handleerror!(&mut peer, Err(e));
 								},
 							},
 							Ok(Some(msg)) => {
@@ -1689,7 +1880,17 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 						self.enqueue_message(&mut *get_peer_for_forwarding!(node_id), msg);
 					}
 					MessageSendEvent::SendGossipTimestampFilter { ref node_id, ref msg } => {
-						self.enqueue_message(&mut *get_peer_for_forwarding!(node_id), msg);
+						let first_timestamp = match self.get_gossip_sync_strategy(node_id) {
+							None => msg.first_timestamp,
+							Some(GossipSyncStrategy::Full) => 0,
+							Some(GossipSyncStrategy::FilterByTimestamp { first_timestamp }) => first_timestamp,
+							Some(GossipSyncStrategy::Disabled) => {
+								log_debug!(self.logger, "Not sending gossip_timestamp_filter to {} as gossip sync is disabled", log_pubkey!(node_id));
+								continue;
+							},
+						};
+						let msg = msgs::GossipTimestampFilter { first_timestamp, ..msg.clone() };
+						self.enqueue_message(&mut *get_peer_for_forwarding!(node_id), &msg);
 					}
 				}
 			}
@@ -1890,6 +2091,15 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, OM: Deref, L: Deref, CM
 				descriptor.disconnect_socket();
 			}
 		}
+
+		let mut reporter_lock = self.message_stats_reporter.lock().unwrap();
+		if let Some(reporter) = reporter_lock.as_mut() {
+			reporter.ticks_elapsed += 1;
+			if reporter.ticks_elapsed >= reporter.interval_ticks {
+				reporter.ticks_elapsed = 0;
+				reporter.handler.handle_message_stats(self.get_message_stats());
+			}
+		}
 	}
 }
 
@@ -1908,12 +2118,16 @@ fn is_gossip_msg(type_id: u16) -> bool {
 
 #[cfg(test)]
 mod tests {
-	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor, IgnoringMessageHandler, filter_addresses};
+	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor, IgnoringMessageHandler, InvalidMessageCategory, PeerMessageStats, PeerMessageStatsHandler, GossipSyncStrategy, filter_addresses};
 	use ln::{msgs, wire};
+	use ln::wire::Encode;
 	use ln::msgs::NetAddress;
 	use util::events;
+	use util::ser::Readable;
 	use util::test_utils;
 
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
 	use bitcoin::secp256k1::Secp256k1;
 	use bitcoin::secp256k1::{SecretKey, PublicKey};
 
@@ -2132,6 +2346,118 @@ mod tests {
 		assert_eq!(cfgs[1].routing_handler.chan_anns_recvd.load(Ordering::Acquire), 54);
 	}
 
+	struct TestStatsHandler {
+		stats: Arc<Mutex<Vec<Vec<(PublicKey, PeerMessageStats)>>>>,
+	}
+	impl PeerMessageStatsHandler for TestStatsHandler {
+		fn handle_message_stats(&self, stats: Vec<(PublicKey, PeerMessageStats)>) {
+			self.stats.lock().unwrap().push(stats);
+		}
+	}
+
+	#[test]
+	fn test_message_stats() {
+		// Check that we count the messages exchanged with each peer, including invalid messages,
+		// and periodically hand the counts to the stats handler.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (mut fd_a, mut fd_b) = establish_connection(&peers[0], &peers[1]);
+
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		let stats = peers[0].get_peer_message_stats(&b_id).unwrap();
+		assert_eq!(stats.sent.get(&msgs::Init::TYPE), Some(&1));
+		assert_eq!(stats.received.get(&msgs::Init::TYPE), Some(&1));
+		assert!(stats.invalid_received.is_empty());
+		assert_eq!(peers[0].get_message_stats(), vec![(b_id, stats)]);
+
+		// A ping is answered with a pong.
+		peers[0].timer_tick_occurred();
+		let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+		assert_eq!(peers[1].read_event(&mut fd_b, &a_data).unwrap(), false);
+		peers[1].process_events();
+		let b_data = fd_b.outbound_data.lock().unwrap().split_off(0);
+		assert_eq!(peers[0].read_event(&mut fd_a, &b_data).unwrap(), false);
+		assert_eq!(peers[0].get_peer_message_stats(&b_id).unwrap().sent.get(&msgs::Ping::TYPE), Some(&1));
+		assert_eq!(peers[0].get_peer_message_stats(&b_id).unwrap().received.get(&msgs::Pong::TYPE), Some(&1));
+		assert_eq!(peers[1].get_peer_message_stats(&a_id).unwrap().received.get(&msgs::Ping::TYPE), Some(&1));
+		assert_eq!(peers[1].get_peer_message_stats(&a_id).unwrap().sent.get(&msgs::Pong::TYPE), Some(&1));
+
+		// A truncated channel_update is ignored, but counted as invalid.
+		let prev_stats = peers[0].get_peer_message_stats(&b_id).unwrap();
+		let bogus_update = {
+			let peers_lock = peers[1].peers.read().unwrap();
+			let mut peer = peers_lock.get(&fd_b).unwrap().lock().unwrap();
+			peer.channel_encryptor.encrypt_message(&msgs::ChannelUpdate::TYPE.to_be_bytes())
+		};
+		assert_eq!(peers[0].read_event(&mut fd_a, &bogus_update).unwrap(), false);
+		let stats = peers[0].get_peer_message_stats(&b_id).unwrap();
+		assert_eq!(stats.invalid_received.get(&InvalidMessageCategory::ShortRead), Some(&1));
+		assert_eq!(stats.received, prev_stats.received);
+
+		let handled_stats = Arc::new(Mutex::new(Vec::new()));
+		peers[1].set_message_stats_handler(Box::new(TestStatsHandler { stats: Arc::clone(&handled_stats) }), 1);
+		peers[1].timer_tick_occurred();
+		let handled_stats = handled_stats.lock().unwrap();
+		assert_eq!(handled_stats.len(), 1);
+		assert_eq!(handled_stats[0], vec![(a_id, peers[1].get_peer_message_stats(&a_id).unwrap())]);
+	}
+
+	#[test]
+	fn test_gossip_sync_strategy() {
+		// Check that the gossip_timestamp_filter we send each peer follows its GossipSyncStrategy,
+		// falling back to the default strategy and then the routing handler's filter.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		let other_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+
+		assert_eq!(peers[0].get_gossip_sync_strategy(&b_id), None);
+		peers[0].set_default_gossip_sync_strategy(Some(GossipSyncStrategy::Full));
+		peers[0].set_gossip_sync_strategy(b_id, Some(GossipSyncStrategy::FilterByTimestamp { first_timestamp: 42 }));
+		assert_eq!(peers[0].get_gossip_sync_strategy(&b_id), Some(GossipSyncStrategy::FilterByTimestamp { first_timestamp: 42 }));
+		assert_eq!(peers[0].get_gossip_sync_strategy(&other_id), Some(GossipSyncStrategy::Full));
+		peers[1].set_gossip_sync_strategy(a_id, Some(GossipSyncStrategy::Disabled));
+
+		let (fd_a, fd_b) = establish_connection(&peers[0], &peers[1]);
+		let sent_filters = |peer: &PeerManager<_, _, _, _, _, _>, node_id| {
+			peer.get_peer_message_stats(node_id).unwrap().sent.get(&msgs::GossipTimestampFilter::TYPE).cloned()
+		};
+		assert_eq!(sent_filters(&peers[0], &b_id), Some(1));
+		assert_eq!(peers[1].get_peer_message_stats(&a_id).unwrap().received.get(&msgs::GossipTimestampFilter::TYPE), Some(&1));
+
+		// Peer B's filter is dropped as it has disabled gossip sync with peer A.
+		peers[1].process_events();
+		assert_eq!(sent_filters(&peers[1], &a_id), None);
+
+		// A further filter from peer A's routing handler is rewritten according to its strategy.
+		let mut sent_timestamps = Vec::new();
+		for strategy in [None, Some(GossipSyncStrategy::Full)].iter() {
+			peers[0].set_gossip_sync_strategy(b_id, *strategy);
+			peers[0].set_default_gossip_sync_strategy(None);
+			cfgs[0].routing_handler.pending_events.lock().unwrap().push(events::MessageSendEvent::SendGossipTimestampFilter {
+				node_id: b_id,
+				msg: msgs::GossipTimestampFilter { chain_hash: genesis_block(Network::Testnet).header.block_hash(), first_timestamp: 7, timestamp_range: u32::max_value() },
+			});
+			peers[0].process_events();
+
+			let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+			let peers_lock = peers[1].peers.read().unwrap();
+			let mut peer = peers_lock.get(&fd_b).unwrap().lock().unwrap();
+			let msg_len = peer.channel_encryptor.decrypt_length_header(&a_data[..18]).unwrap() as usize;
+			assert_eq!(a_data.len(), 18 + msg_len + 16);
+			let msg = peer.channel_encryptor.decrypt_message(&a_data[18..]).unwrap();
+			assert_eq!(msg[..2], msgs::GossipTimestampFilter::TYPE.to_be_bytes());
+			let filter: msgs::GossipTimestampFilter = Readable::read(&mut &msg[2..]).unwrap();
+			sent_timestamps.push(filter.first_timestamp);
+		}
+		assert_eq!(sent_timestamps, vec![7, 0]);
+	}
+
 	#[test]
 	fn test_handshake_timeout() {
 		// Tests that we time out a peer still waiting on handshake completion after a full timer
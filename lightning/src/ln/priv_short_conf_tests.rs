@@ -14,7 +14,7 @@
 use chain::{ChannelMonitorUpdateErr, Watch};
 use chain::channelmonitor::ChannelMonitor;
 use chain::keysinterface::{Recipient, KeysInterface};
-use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, MIN_CLTV_EXPIRY_DELTA};
+use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, NodeRole, MIN_CLTV_EXPIRY_DELTA};
 use routing::gossip::RoutingFees;
 use routing::router::{PaymentParameters, RouteHint, RouteHintHop};
 use ln::features::{InitFeatures, InvoiceFeatures, ChannelTypeFeatures};
@@ -23,7 +23,8 @@ use ln::msgs::{ChannelMessageHandler, RoutingMessageHandler, ChannelUpdate, Erro
 use ln::wire::Encode;
 use util::enforcing_trait_impls::EnforcingSigner;
 use util::events::{ClosureReason, Event, HTLCDestination, MessageSendEvent, MessageSendEventsProvider};
-use util::config::UserConfig;
+use util::config::{PeerConfigOverrides, UserConfig};
+use util::errors::APIError;
 use util::ser::{Writeable, ReadableArgs};
 use util::test_utils;
 
@@ -132,6 +133,7 @@ fn test_priv_forwarding_rejection() {
 			tx_broadcaster: nodes[1].tx_broadcaster.clone(),
 			logger: nodes[1].logger,
 			channel_monitors,
+			role: NodeRole::Active,
 		}).unwrap()
 	};
 	assert!(nodes_1_read.is_empty());
@@ -1021,3 +1023,72 @@ fn test_zero_conf_accept_reject() {
 		_ => panic!(),
 	}
 }
+
+#[test]
+fn test_peer_config_overrides() {
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let default_channel_config = nodes[1].node.get_current_default_configuration().channel_config;
+	let mut channel_config = default_channel_config;
+	channel_config.forwarding_fee_base_msat = 1234;
+	let overrides = PeerConfigOverrides {
+		channel_config: Some(channel_config),
+		trusted_zero_conf: true,
+		max_pending_channels: Some(1),
+		..Default::default()
+	};
+	channel_config.cltv_expiry_delta = MIN_CLTV_EXPIRY_DELTA - 1;
+	assert!(nodes[1].node.set_peer_config_overrides(nodes[0].node.get_our_node_id(),
+		PeerConfigOverrides { channel_config: Some(channel_config), ..Default::default() }).is_err());
+	nodes[1].node.set_peer_config_overrides(nodes[0].node.get_our_node_id(), overrides).unwrap();
+	assert_eq!(nodes[1].node.get_peer_config_overrides(&nodes[0].node.get_our_node_id()), Some(overrides));
+
+	// Zero-conf channels from the trusted peer are accepted automatically and use the overridden
+	// channel config.
+	let mut channel_type_features = ChannelTypeFeatures::only_static_remote_key();
+	channel_type_features.set_zero_conf_required();
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 42, None).unwrap();
+	let mut open_channel_msg = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	open_channel_msg.channel_type = Some(channel_type_features);
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &open_channel_msg);
+	let accept_channel_msg = get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
+	assert_eq!(accept_channel_msg.minimum_depth, 0);
+	assert_eq!(nodes[1].node.list_channels()[0].config.unwrap().forwarding_fee_base_msat, 1234);
+
+	// Further channels are rejected while the first is pending, whoever opens them.
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 43, None).unwrap();
+	let open_channel_msg = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &open_channel_msg);
+	let msg_events = nodes[1].node.get_and_clear_pending_msg_events();
+	match msg_events[0] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { ref msg, .. }, .. } => {
+			assert!(msg.data.starts_with("Already have 1 pending channels"));
+		},
+		_ => panic!(),
+	}
+	match nodes[1].node.create_channel(nodes[0].node.get_our_node_id(), 100000, 10001, 44, None) {
+		Err(APIError::ChannelUnavailable { .. }) => {},
+		_ => panic!(),
+	}
+
+	// Removing the overrides reverts the channel to our default config.
+	assert_eq!(nodes[1].node.remove_peer_config_overrides(&nodes[0].node.get_our_node_id()), Some(overrides));
+	assert_eq!(nodes[1].node.list_channels()[0].config.unwrap(), default_channel_config);
+
+	// Inbound channels from the peer may be rejected despite our default config accepting them.
+	nodes[1].node.set_peer_config_overrides(nodes[0].node.get_our_node_id(), PeerConfigOverrides {
+		accept_inbound_channels: Some(false),
+		..Default::default()
+	}).unwrap();
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &open_channel_msg);
+	let msg_events = nodes[1].node.get_and_clear_pending_msg_events();
+	match msg_events[0] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { ref msg, .. }, .. } => {
+			assert_eq!(msg.data, "No inbound channels accepted");
+		},
+		_ => panic!(),
+	}
+}
@@ -12,7 +12,7 @@
 use chain::channelmonitor::{ANTI_REORG_DELAY, ChannelMonitor};
 use chain::transaction::OutPoint;
 use chain::{Confirm, Watch};
-use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs};
+use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, NodeRole};
 use ln::features::InitFeatures;
 use ln::msgs::ChannelMessageHandler;
 use util::enforcing_trait_impls::EnforcingSigner;
@@ -325,13 +325,14 @@ fn do_test_unconf_chan(reload_node: bool, reorg_after_reload: bool, use_funding_
 			<(BlockHash, ChannelManager<EnforcingSigner, &test_utils::TestChainMonitor, &test_utils::TestBroadcaster,
 			  &test_utils::TestKeysInterface, &test_utils::TestFeeEstimator, &test_utils::TestLogger>)>::read(
 				&mut nodes_0_read, ChannelManagerReadArgs {
-					default_config: *nodes[0].node.get_current_default_configuration(),
+					default_config: nodes[0].node.get_current_default_configuration(),
 					keys_manager,
 					fee_estimator: node_cfgs[0].fee_estimator,
 					chain_monitor: nodes[0].chain_monitor,
 					tx_broadcaster: nodes[0].tx_broadcaster.clone(),
 					logger: nodes[0].logger,
 					channel_monitors,
+					role: NodeRole::Active,
 			}).unwrap().1
 		};
 		nodes[0].node = &nodes_0_deserialized;
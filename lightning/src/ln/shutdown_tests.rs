@@ -27,6 +27,8 @@ use bitcoin::blockdata::script::Builder;
 use bitcoin::blockdata::opcodes;
 use bitcoin::network::constants::Network;
 use bitcoin::util::address::WitnessVersion;
+use bitcoin::hashes::Hash;
+use bitcoin::WPubkeyHash;
 
 use regex;
 
@@ -914,3 +916,52 @@ fn simple_target_feerate_shutdown() {
 	check_closed_event!(nodes[0], 1, ClosureReason::CooperativeClosure);
 	check_closed_event!(nodes[1], 1, ClosureReason::CooperativeClosure);
 }
+
+#[test]
+fn test_close_channel_with_shutdown_script() {
+	// Test that `close_channel_with_shutdown_script` pays our balance out to the given script, and
+	// that it refuses to do so if we've already committed to an upfront shutdown script.
+	let mut config = UserConfig::default();
+	config.channel_handshake_config.announced_channel = true;
+	config.channel_handshake_limits.force_announced_channel_preference = false;
+	config.channel_handshake_config.commit_upfront_shutdown_pubkey = false;
+	let user_cfgs = [Some(config), None];
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &user_cfgs);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_id = OutPoint { txid: chan.3.txid(), index: 0 }.to_channel_id();
+	let shutdown_script = ShutdownScript::new_p2wpkh(&WPubkeyHash::hash(&[42; 33]));
+
+	// nodes[1] committed to an upfront shutdown script when opening the channel.
+	match nodes[1].node.close_channel_with_shutdown_script(&chan_id, &nodes[0].node.get_our_node_id(), None, shutdown_script.clone()) {
+		Err(APIError::APIMisuseError { err }) =>
+			assert_eq!(err, "Cannot override shutdown script for a channel with one already set"),
+		Err(e) => panic!("Unexpected error: {:?}", e),
+		Ok(_) => panic!("Expected error"),
+	}
+
+	nodes[0].node.close_channel_with_shutdown_script(&chan_id, &nodes[1].node.get_our_node_id(), None, shutdown_script.clone()).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let node_0_shutdown = get_event_msg!(nodes[0], MessageSendEvent::SendShutdown, nodes[1].node.get_our_node_id());
+	assert_eq!(node_0_shutdown.scriptpubkey, shutdown_script.clone().into_inner());
+	nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &InitFeatures::known(), &node_0_shutdown);
+	let node_1_shutdown = get_event_msg!(nodes[1], MessageSendEvent::SendShutdown, nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &InitFeatures::known(), &node_1_shutdown);
+
+	let node_0_closing_signed = get_event_msg!(nodes[0], MessageSendEvent::SendClosingSigned, nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &node_0_closing_signed);
+	let node_1_closing_signed = get_event_msg!(nodes[1], MessageSendEvent::SendClosingSigned, nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_closing_signed(&nodes[1].node.get_our_node_id(), &node_1_closing_signed);
+	let (_, node_0_closing_signed) = get_closing_signed_broadcast!(nodes[0].node, nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &node_0_closing_signed.unwrap());
+	let (_, node_1_none) = get_closing_signed_broadcast!(nodes[1].node, nodes[0].node.get_our_node_id());
+	assert!(node_1_none.is_none());
+	let node_0_closing_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0);
+	assert_eq!(node_0_closing_tx.len(), 1);
+	assert!(node_0_closing_tx[0].output.iter().any(|output| output.script_pubkey == shutdown_script.clone().into_inner()));
+	check_closed_event!(nodes[0], 1, ClosureReason::CooperativeClosure);
+	check_closed_event!(nodes[1], 1, ClosureReason::CooperativeClosure);
+}
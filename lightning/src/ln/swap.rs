@@ -0,0 +1,660 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Primitives for submarine swaps, exchanging on-chain funds for off-chain funds (loop-in) or
+//! vice versa (loop-out) atomically, as commonly used to rebalance channels.
+//!
+//! Both directions lock on-chain funds in a [`SwapContract`] output, which the receiving party
+//! may claim by revealing the preimage of an off-chain payment's hash, or which the sending party
+//! may refund to itself once a block height has been reached:
+//!  * In a loop-out, we pay an invoice for the swap's payment hash, which our counterparty is
+//!    unable to claim until we claim the on-chain output they create, revealing the preimage.
+//!  * In a loop-in, we create the on-chain output for an invoice we issue for the swap's payment
+//!    hash. Once our counterparty pays it and we claim the payment, they learn the preimage and
+//!    can claim the on-chain output.
+//!
+//! A [`SwapTracker`] follows each swap's on-chain output via the scripts and outpoints watched
+//! by a [`ChainMonitor`], correlates it with the swap's off-chain payment, and tells the
+//! application when to claim or refund the output.
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::transaction::{EcdsaSighashType, Transaction, TxIn};
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::ripemd160::Hash as Ripemd160;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, Signing};
+use bitcoin::util::sighash;
+use bitcoin::{PackedLockTime, Sequence, Witness};
+
+use chain;
+use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use chain::chainmonitor::{ChainMonitor, Persist};
+use chain::keysinterface::Sign;
+use chain::transaction::OutPoint;
+use ln::{PaymentHash, PaymentPreimage};
+use ln::msgs::DecodeError;
+use util::crypto::sign;
+use util::events::Event;
+use util::logger::Logger;
+use util::transaction_utils;
+
+use prelude::*;
+use core::ops::Deref;
+
+/// The number of blocks before a [`SwapContract`]'s refund height at which
+/// [`SwapEvent::ClaimDeadlineApproaching`] is generated for swaps we are to claim.
+pub const CLAIM_DEADLINE_SAFETY_MARGIN: u32 = 6;
+
+/// The maximum length of a DER-encoded signature, plus its sighash flag.
+const MAX_SIG_LEN: usize = 73;
+
+/// An on-chain output which may be claimed by revealing the preimage of a payment hash, or
+/// refunded once a block height has been reached.
+///
+/// The output pays to the P2WSH of the following script:
+/// ```text
+/// OP_SIZE 32 OP_EQUAL
+/// OP_IF
+///     OP_HASH160 <RIPEMD160(payment_hash)> OP_EQUALVERIFY <claim_pubkey>
+/// OP_ELSE
+///     OP_DROP <refund_height> OP_CHECKLOCKTIMEVERIFY OP_DROP <refund_pubkey>
+/// OP_ENDIF
+/// OP_CHECKSIG
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SwapContract {
+	/// The hash of the payment whose preimage allows claiming the output.
+	pub payment_hash: PaymentHash,
+	/// The key of the party which may claim the output with the preimage.
+	pub claim_pubkey: PublicKey,
+	/// The key of the party which may refund the output once [`Self::refund_height`] is reached.
+	pub refund_pubkey: PublicKey,
+	/// The block height from which the output may be refunded.
+	pub refund_height: u32,
+}
+
+impl_writeable_tlv_based!(SwapContract, {
+	(0, payment_hash, required),
+	(2, claim_pubkey, required),
+	(4, refund_pubkey, required),
+	(6, refund_height, required),
+});
+
+impl SwapContract {
+	/// Gets the witness script of the swap output.
+	pub fn redeemscript(&self) -> Script {
+		Builder::new().push_opcode(opcodes::all::OP_SIZE)
+			.push_int(32)
+			.push_opcode(opcodes::all::OP_EQUAL)
+			.push_opcode(opcodes::all::OP_IF)
+			.push_opcode(opcodes::all::OP_HASH160)
+			.push_slice(&Ripemd160::hash(&self.payment_hash.0[..]).into_inner())
+			.push_opcode(opcodes::all::OP_EQUALVERIFY)
+			.push_slice(&self.claim_pubkey.serialize())
+			.push_opcode(opcodes::all::OP_ELSE)
+			.push_opcode(opcodes::all::OP_DROP)
+			.push_int(self.refund_height as i64)
+			.push_opcode(opcodes::all::OP_CLTV)
+			.push_opcode(opcodes::all::OP_DROP)
+			.push_slice(&self.refund_pubkey.serialize())
+			.push_opcode(opcodes::all::OP_ENDIF)
+			.push_opcode(opcodes::all::OP_CHECKSIG)
+			.into_script()
+	}
+
+	/// Gets the script_pubkey the swap output pays to.
+	pub fn script_pubkey(&self) -> Script {
+		self.redeemscript().to_v0_p2wsh()
+	}
+
+	/// Builds a transaction claiming the swap output at `funding_outpoint` to
+	/// `destination_script` by revealing `payment_preimage`.
+	///
+	/// Fails if `payment_preimage` or `claim_key` do not match the contract, or if the output's
+	/// value cannot pay for the transaction at the given feerate.
+	pub fn build_claim_transaction<C: Signing>(&self, funding_outpoint: OutPoint, funding_value_satoshis: u64,
+		payment_preimage: &PaymentPreimage, destination_script: Script, feerate_sat_per_1000_weight: u32,
+		claim_key: &SecretKey, secp_ctx: &Secp256k1<C>
+	) -> Result<Transaction, ()> {
+		if Sha256::hash(&payment_preimage.0[..]).into_inner() != self.payment_hash.0 { return Err(()); }
+		if PublicKey::from_secret_key(secp_ctx, claim_key) != self.claim_pubkey { return Err(()); }
+		self.build_spend(funding_outpoint, funding_value_satoshis, PackedLockTime::ZERO, payment_preimage.0.to_vec(),
+			destination_script, feerate_sat_per_1000_weight, claim_key, secp_ctx)
+	}
+
+	/// Builds a transaction refunding the swap output at `funding_outpoint` to
+	/// `destination_script`, which may be broadcast once the best block height reaches
+	/// [`Self::refund_height`].
+	///
+	/// Fails if `refund_key` does not match the contract, or if the output's value cannot pay for
+	/// the transaction at the given feerate.
+	pub fn build_refund_transaction<C: Signing>(&self, funding_outpoint: OutPoint, funding_value_satoshis: u64,
+		destination_script: Script, feerate_sat_per_1000_weight: u32, refund_key: &SecretKey, secp_ctx: &Secp256k1<C>
+	) -> Result<Transaction, ()> {
+		if PublicKey::from_secret_key(secp_ctx, refund_key) != self.refund_pubkey { return Err(()); }
+		self.build_spend(funding_outpoint, funding_value_satoshis, PackedLockTime(self.refund_height), Vec::new(),
+			destination_script, feerate_sat_per_1000_weight, refund_key, secp_ctx)
+	}
+
+	fn build_spend<C: Signing>(&self, funding_outpoint: OutPoint, funding_value_satoshis: u64, lock_time: PackedLockTime,
+		path_selector: Vec<u8>, destination_script: Script, feerate_sat_per_1000_weight: u32, key: &SecretKey,
+		secp_ctx: &Secp256k1<C>
+	) -> Result<Transaction, ()> {
+		let redeemscript = self.redeemscript();
+		let mut tx = Transaction {
+			version: 2,
+			lock_time,
+			input: vec![TxIn {
+				previous_output: funding_outpoint.into_bitcoin_outpoint(),
+				script_sig: Script::new(),
+				// A non-final sequence is required for the refund's locktime to be enforced.
+				sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
+				witness: Witness::new(),
+			}],
+			output: Vec::new(),
+		};
+		let witness_weight = 1 + 1 + MAX_SIG_LEN + 1 + path_selector.len() + 1 + redeemscript.len();
+		transaction_utils::maybe_add_change_output(&mut tx, funding_value_satoshis, witness_weight,
+			feerate_sat_per_1000_weight, destination_script)?;
+		if tx.output.is_empty() { return Err(()); }
+
+		let sighash = hash_to_message!(&sighash::SighashCache::new(&tx)
+			.segwit_signature_hash(0, &redeemscript, funding_value_satoshis, EcdsaSighashType::All).unwrap()[..]);
+		let mut sig = sign(secp_ctx, &sighash, key).serialize_der().to_vec();
+		sig.push(EcdsaSighashType::All as u8);
+		tx.input[0].witness = Witness::from_vec(vec![sig, path_selector, redeemscript.into_bytes()]);
+		Ok(tx)
+	}
+
+	/// Gets the payment preimage revealed by `tx` if it claims the swap output at
+	/// `funding_outpoint`.
+	pub fn extract_preimage(&self, tx: &Transaction, funding_outpoint: &OutPoint) -> Option<PaymentPreimage> {
+		let funding_outpoint = funding_outpoint.into_bitcoin_outpoint();
+		let input = tx.input.iter().find(|input| input.previous_output == funding_outpoint)?;
+		let witness = input.witness.to_vec();
+		if witness.len() != 3 || witness[1].len() != 32 { return None; }
+		if Sha256::hash(&witness[1]).into_inner() != self.payment_hash.0 { return None; }
+		let mut preimage = [0; 32];
+		preimage.copy_from_slice(&witness[1]);
+		Some(PaymentPreimage(preimage))
+	}
+}
+
+/// Which side of a swap we are on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapRole {
+	/// We may claim the swap output with the payment preimage, i.e. we are the client of a
+	/// loop-out or the server of a loop-in.
+	Claimer,
+	/// We may refund the swap output after the refund height, i.e. we are the client of a loop-in
+	/// or the server of a loop-out.
+	Refunder,
+}
+
+impl_writeable_tlv_based_enum!(SwapRole,
+	(0, Claimer) => {},
+	(2, Refunder) => {};
+);
+
+/// A notable change in the state of a swap tracked by a [`SwapTracker`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SwapEvent {
+	/// The swap output was confirmed on chain. Before proceeding with the off-chain part of the
+	/// swap, you should check that `value_satoshis` matches the agreed amount and wait for
+	/// sufficient confirmations.
+	FundingConfirmed {
+		/// The swap's payment hash.
+		payment_hash: PaymentHash,
+		/// The swap output.
+		outpoint: OutPoint,
+		/// The value of the swap output.
+		value_satoshis: u64,
+		/// The height of the block the output was confirmed in.
+		height: u32,
+	},
+	/// We learned the swap's payment preimage, either from an on-chain claim of the swap output
+	/// or from an off-chain payment we sent. Claimers should now claim the swap output with
+	/// [`SwapContract::build_claim_transaction`], while refunders should claim any pending
+	/// off-chain payment via [`ChannelManager::claim_funds`].
+	///
+	/// [`ChannelManager::claim_funds`]: crate::ln::channelmanager::ChannelManager::claim_funds
+	PreimageLearned {
+		/// The swap's payment hash.
+		payment_hash: PaymentHash,
+		/// The swap's payment preimage.
+		payment_preimage: PaymentPreimage,
+	},
+	/// We received the swap's off-chain payment. If the preimage is known (i.e. we are a loop-in
+	/// client), it is included and the payment may be claimed once the swap output has
+	/// sufficient confirmations.
+	PaymentReceived {
+		/// The swap's payment hash.
+		payment_hash: PaymentHash,
+		/// The value of the payment received.
+		amount_msat: u64,
+		/// The swap's payment preimage, if known.
+		payment_preimage: Option<PaymentPreimage>,
+	},
+	/// The swap output was claimed with the payment preimage on chain.
+	Claimed {
+		/// The swap's payment hash.
+		payment_hash: PaymentHash,
+		/// The claiming transaction.
+		txid: Txid,
+	},
+	/// The swap output was refunded on chain.
+	Refunded {
+		/// The swap's payment hash.
+		payment_hash: PaymentHash,
+		/// The refunding transaction.
+		txid: Txid,
+	},
+	/// The swap output's refund height has been reached while it remains unspent, and it should
+	/// be refunded with [`SwapContract::build_refund_transaction`]. Only generated for swaps we
+	/// are the [`SwapRole::Refunder`] of.
+	RefundAvailable {
+		/// The swap's payment hash.
+		payment_hash: PaymentHash,
+		/// The swap output.
+		outpoint: OutPoint,
+		/// The value of the swap output.
+		value_satoshis: u64,
+	},
+	/// The swap output's refund height is within [`CLAIM_DEADLINE_SAFETY_MARGIN`] blocks while it
+	/// remains unspent. If the preimage is known, the swap output must be claimed (e.g. with a
+	/// higher feerate) before the refunder can race us. Only generated for swaps we are the
+	/// [`SwapRole::Claimer`] of.
+	ClaimDeadlineApproaching {
+		/// The swap's payment hash.
+		payment_hash: PaymentHash,
+		/// The number of blocks until the refund height.
+		blocks_remaining: u32,
+	},
+}
+
+struct SwapFunding {
+	outpoint: OutPoint,
+	value_satoshis: u64,
+}
+
+impl_writeable_tlv_based!(SwapFunding, {
+	(0, outpoint, required),
+	(2, value_satoshis, required),
+});
+
+struct TrackedSwap {
+	contract: SwapContract,
+	role: SwapRole,
+	payment_preimage: Option<PaymentPreimage>,
+	funding: Option<SwapFunding>,
+	resolving_txid: Option<Txid>,
+	/// Whether a [`SwapEvent::RefundAvailable`] or [`SwapEvent::ClaimDeadlineApproaching`] has
+	/// been generated for the current funding.
+	deadline_notified: bool,
+}
+
+impl_writeable_tlv_based!(TrackedSwap, {
+	(0, contract, required),
+	(2, role, required),
+	(4, payment_preimage, option),
+	(6, funding, option),
+	(8, resolving_txid, option),
+	(10, deadline_notified, required),
+});
+
+/// Tracks the state of swaps by their payment hash, generating [`SwapEvent`]s as their on-chain
+/// outputs are confirmed and spent and their off-chain payments complete.
+///
+/// The tracker must be given each [`Event`] generated by the [`ChainMonitor`] watching the swaps'
+/// outputs and by the [`ChannelManager`] sending or receiving the swaps' payments via
+/// [`Self::handle_event`], as well as each new best block height via
+/// [`Self::best_block_updated`].
+///
+/// As a [`ChainMonitor`] does not persist the scripts and outpoints it watches, the tracker
+/// should be persisted and [`Self::register_watches`] called after reloading, before syncing the
+/// chain.
+///
+/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+pub struct SwapTracker {
+	swaps: HashMap<PaymentHash, TrackedSwap>,
+}
+
+impl_writeable_tlv_based!(SwapTracker, {
+	(0, swaps, required),
+});
+
+impl SwapTracker {
+	/// Creates a new tracker with no swaps.
+	pub fn new() -> Self {
+		SwapTracker { swaps: HashMap::new() }
+	}
+
+	/// Begins tracking a swap, watching for its output via `chain_monitor`.
+	///
+	/// If we generated the swap's payment preimage (i.e. as a loop-out client or loop-in server),
+	/// it should be provided as `payment_preimage`.
+	///
+	/// Fails if a swap with the same payment hash is already tracked.
+	pub fn track_swap<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref>(
+		&mut self, contract: SwapContract, role: SwapRole, payment_preimage: Option<PaymentPreimage>,
+		chain_monitor: &ChainMonitor<ChannelSigner, C, T, F, L, P>
+	) -> Result<(), ()>
+	where C::Target: chain::Filter, T::Target: BroadcasterInterface, F::Target: FeeEstimator,
+	      L::Target: Logger, P::Target: Persist<ChannelSigner>,
+	{
+		if self.swaps.contains_key(&contract.payment_hash) { return Err(()); }
+		if let Some(preimage) = payment_preimage {
+			if Sha256::hash(&preimage.0[..]).into_inner() != contract.payment_hash.0 { return Err(()); }
+		}
+		chain_monitor.watch_script(contract.script_pubkey());
+		self.swaps.insert(contract.payment_hash, TrackedSwap {
+			contract, role, payment_preimage, funding: None, resolving_txid: None, deadline_notified: false,
+		});
+		Ok(())
+	}
+
+	/// Stops tracking the swap with the given payment hash, e.g. once it has been resolved with
+	/// sufficient confirmations, and stops watching for its output.
+	pub fn remove_swap<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref>(
+		&mut self, payment_hash: &PaymentHash, chain_monitor: &ChainMonitor<ChannelSigner, C, T, F, L, P>
+	) -> Option<SwapContract>
+	where C::Target: chain::Filter, T::Target: BroadcasterInterface, F::Target: FeeEstimator,
+	      L::Target: Logger, P::Target: Persist<ChannelSigner>,
+	{
+		let swap = self.swaps.remove(payment_hash)?;
+		chain_monitor.unwatch_script(&swap.contract.script_pubkey());
+		if let Some(funding) = swap.funding {
+			chain_monitor.unwatch_outpoint(&funding.outpoint);
+		}
+		Some(swap.contract)
+	}
+
+	/// Registers the scripts and outpoints of all tracked swaps with `chain_monitor`, which must be
+	/// done after reloading the tracker.
+	pub fn register_watches<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref>(
+		&self, chain_monitor: &ChainMonitor<ChannelSigner, C, T, F, L, P>
+	)
+	where C::Target: chain::Filter, T::Target: BroadcasterInterface, F::Target: FeeEstimator,
+	      L::Target: Logger, P::Target: Persist<ChannelSigner>,
+	{
+		for swap in self.swaps.values() {
+			let script_pubkey = swap.contract.script_pubkey();
+			if let Some(ref funding) = swap.funding {
+				chain_monitor.watch_outpoint(funding.outpoint, script_pubkey.clone());
+			}
+			chain_monitor.watch_script(script_pubkey);
+		}
+	}
+
+	/// Gets the contract, funding outpoint and funding value of the swap with the given payment
+	/// hash, if tracked.
+	pub fn get_swap(&self, payment_hash: &PaymentHash) -> Option<(&SwapContract, Option<(OutPoint, u64)>)> {
+		self.swaps.get(payment_hash).map(|swap| {
+			(&swap.contract, swap.funding.as_ref().map(|funding| (funding.outpoint, funding.value_satoshis)))
+		})
+	}
+
+	/// Updates the tracked swaps based on an [`Event`], returning any resulting [`SwapEvent`]s.
+	/// Events unrelated to tracked swaps are ignored.
+	pub fn handle_event(&mut self, event: &Event) -> Vec<SwapEvent> {
+		let mut swap_events = Vec::new();
+		match event {
+			Event::WatchedOutputConfirmed { outpoint, output, height, .. } => {
+				// Only the first output paying to a swap's script is tracked.
+				if let Some(swap) = self.swaps.values_mut().find(|swap|
+					swap.funding.is_none() && swap.contract.script_pubkey() == output.script_pubkey
+				) {
+					swap.funding = Some(SwapFunding { outpoint: *outpoint, value_satoshis: output.value });
+					swap.deadline_notified = false;
+					swap_events.push(SwapEvent::FundingConfirmed {
+						payment_hash: swap.contract.payment_hash, outpoint: *outpoint, value_satoshis: output.value,
+						height: *height,
+					});
+				}
+			},
+			Event::WatchedOutputSpent { outpoint, spending_tx, .. } => {
+				if let Some(swap) = self.swaps.values_mut().find(|swap|
+					swap.funding.as_ref().map(|funding| funding.outpoint == *outpoint).unwrap_or(false)
+				) {
+					let payment_hash = swap.contract.payment_hash;
+					let txid = spending_tx.txid();
+					swap.resolving_txid = Some(txid);
+					if let Some(payment_preimage) = swap.contract.extract_preimage(spending_tx, outpoint) {
+						if swap.payment_preimage.is_none() {
+							swap.payment_preimage = Some(payment_preimage);
+							swap_events.push(SwapEvent::PreimageLearned { payment_hash, payment_preimage });
+						}
+						swap_events.push(SwapEvent::Claimed { payment_hash, txid });
+					} else {
+						swap_events.push(SwapEvent::Refunded { payment_hash, txid });
+					}
+				}
+			},
+			Event::WatchedTransactionUnconfirmed { txid } => {
+				// Roll back any state derived from the transaction, which will be regenerated
+				// should it be confirmed again.
+				for swap in self.swaps.values_mut() {
+					if swap.funding.as_ref().map(|funding| funding.outpoint.txid == *txid).unwrap_or(false) {
+						swap.funding = None;
+						swap.resolving_txid = None;
+					} else if swap.resolving_txid == Some(*txid) {
+						swap.resolving_txid = None;
+					}
+				}
+			},
+			Event::PaymentSent { payment_preimage, payment_hash, .. } => {
+				if let Some(swap) = self.swaps.get_mut(payment_hash) {
+					if swap.payment_preimage.is_none() {
+						swap.payment_preimage = Some(*payment_preimage);
+						swap_events.push(SwapEvent::PreimageLearned {
+							payment_hash: *payment_hash, payment_preimage: *payment_preimage,
+						});
+					}
+				}
+			},
+			Event::PaymentReceived { payment_hash, amount_msat, .. } => {
+				if let Some(swap) = self.swaps.get(payment_hash) {
+					swap_events.push(SwapEvent::PaymentReceived {
+						payment_hash: *payment_hash, amount_msat: *amount_msat, payment_preimage: swap.payment_preimage,
+					});
+				}
+			},
+			_ => {},
+		}
+		swap_events
+	}
+
+	/// Checks the tracked swaps against the new best block height, returning
+	/// [`SwapEvent::RefundAvailable`] and [`SwapEvent::ClaimDeadlineApproaching`] events for any
+	/// funded, unresolved swaps whose refund height is near.
+	pub fn best_block_updated(&mut self, height: u32) -> Vec<SwapEvent> {
+		let mut swap_events = Vec::new();
+		for swap in self.swaps.values_mut() {
+			if swap.deadline_notified || swap.resolving_txid.is_some() { continue; }
+			let funding = match swap.funding { Some(ref funding) => funding, None => continue };
+			let payment_hash = swap.contract.payment_hash;
+			match swap.role {
+				SwapRole::Refunder if height >= swap.contract.refund_height => {
+					swap.deadline_notified = true;
+					swap_events.push(SwapEvent::RefundAvailable {
+						payment_hash, outpoint: funding.outpoint, value_satoshis: funding.value_satoshis,
+					});
+				},
+				SwapRole::Claimer if height + CLAIM_DEADLINE_SAFETY_MARGIN >= swap.contract.refund_height => {
+					swap.deadline_notified = true;
+					swap_events.push(SwapEvent::ClaimDeadlineApproaching {
+						payment_hash, blocks_remaining: swap.contract.refund_height.saturating_sub(height),
+					});
+				},
+				_ => {},
+			}
+		}
+		swap_events
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{SwapContract, SwapEvent, SwapRole, SwapTracker, CLAIM_DEADLINE_SAFETY_MARGIN};
+
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+	use bitcoin::hashes::Hash;
+	use bitcoin::hashes::sha256::Hash as Sha256;
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+	use bitcoin::{PackedLockTime, Sequence, Witness};
+
+	use chain::transaction::OutPoint;
+	use ln::{PaymentHash, PaymentPreimage};
+	use ln::functional_test_utils::*;
+	use util::events::Event;
+	use util::ser::{Readable, Writeable};
+
+	use prelude::*;
+	use io;
+
+	fn test_contract(secp_ctx: &Secp256k1<bitcoin::secp256k1::All>, refund_height: u32) -> (SwapContract, PaymentPreimage, SecretKey, SecretKey) {
+		let payment_preimage = PaymentPreimage([42; 32]);
+		let claim_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let refund_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		(SwapContract {
+			payment_hash: PaymentHash(Sha256::hash(&payment_preimage.0).into_inner()),
+			claim_pubkey: PublicKey::from_secret_key(secp_ctx, &claim_key),
+			refund_pubkey: PublicKey::from_secret_key(secp_ctx, &refund_key),
+			refund_height,
+		}, payment_preimage, claim_key, refund_key)
+	}
+
+	fn build_funding_tx(contract: &SwapContract, value: u64) -> Transaction {
+		Transaction {
+			version: 2, lock_time: PackedLockTime::ZERO,
+			input: vec![TxIn { previous_output: bitcoin::OutPoint::null(), script_sig: Script::new(), sequence: Sequence::MAX, witness: Witness::new() }],
+			output: vec![TxOut { value, script_pubkey: contract.script_pubkey() }],
+		}
+	}
+
+	#[test]
+	fn claim_and_refund_transactions() {
+		let secp_ctx = Secp256k1::new();
+		let (contract, payment_preimage, claim_key, refund_key) = test_contract(&secp_ctx, 500);
+		let funding_tx = build_funding_tx(&contract, 100_000);
+		let funding_outpoint = OutPoint { txid: funding_tx.txid(), index: 0 };
+		let destination = Script::new_v0_p2wpkh(&bitcoin::WPubkeyHash::hash(&[3; 33]));
+
+		let claim_tx = contract.build_claim_transaction(funding_outpoint, 100_000, &payment_preimage,
+			destination.clone(), 253, &claim_key, &secp_ctx).unwrap();
+		claim_tx.verify(|outpoint| {
+			if *outpoint == funding_outpoint.into_bitcoin_outpoint() { Some(funding_tx.output[0].clone()) } else { None }
+		}).unwrap();
+		assert_eq!(claim_tx.output[0].script_pubkey, destination);
+		assert!(claim_tx.output[0].value < 100_000);
+		assert_eq!(contract.extract_preimage(&claim_tx, &funding_outpoint), Some(payment_preimage));
+
+		let refund_tx = contract.build_refund_transaction(funding_outpoint, 100_000, destination.clone(),
+			253, &refund_key, &secp_ctx).unwrap();
+		refund_tx.verify(|outpoint| {
+			if *outpoint == funding_outpoint.into_bitcoin_outpoint() { Some(funding_tx.output[0].clone()) } else { None }
+		}).unwrap();
+		assert_eq!(refund_tx.lock_time.0, 500);
+		assert_eq!(contract.extract_preimage(&refund_tx, &funding_outpoint), None);
+
+		// A refund with an earlier locktime fails the script's OP_CHECKLOCKTIMEVERIFY.
+		let early_refund_tx = contract.build_spend(funding_outpoint, 100_000, PackedLockTime(499), Vec::new(),
+			destination.clone(), 253, &refund_key, &secp_ctx).unwrap();
+		assert!(early_refund_tx.verify(|_| Some(funding_tx.output[0].clone())).is_err());
+
+		// Mismatched keys or preimages are rejected, as are outputs too small to pay the fee.
+		assert!(contract.build_claim_transaction(funding_outpoint, 100_000, &PaymentPreimage([0; 32]),
+			destination.clone(), 253, &claim_key, &secp_ctx).is_err());
+		assert!(contract.build_claim_transaction(funding_outpoint, 100_000, &payment_preimage,
+			destination.clone(), 253, &refund_key, &secp_ctx).is_err());
+		assert!(contract.build_refund_transaction(funding_outpoint, 400, destination, 253, &refund_key, &secp_ctx).is_err());
+	}
+
+	#[test]
+	fn tracks_swap_lifecycle() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let chain_monitor = &nodes[0].chain_monitor.chain_monitor;
+
+		// As the refunder of a swap, we learn the preimage from an on-chain claim.
+		let secp_ctx = Secp256k1::new();
+		let refund_height = nodes[0].best_block_info().1 + 10;
+		let (contract, payment_preimage, claim_key, _) = test_contract(&secp_ctx, refund_height);
+		let payment_hash = contract.payment_hash;
+		let mut tracker = SwapTracker::new();
+		tracker.track_swap(contract.clone(), SwapRole::Refunder, None, chain_monitor).unwrap();
+		assert!(tracker.track_swap(contract.clone(), SwapRole::Refunder, None, chain_monitor).is_err());
+
+		let funding_tx = build_funding_tx(&contract, 100_000);
+		let funding_outpoint = OutPoint { txid: funding_tx.txid(), index: 0 };
+		mine_transaction(&nodes[0], &funding_tx);
+		let mut swap_events = Vec::new();
+		for event in chain_monitor.get_and_clear_pending_events() {
+			swap_events.append(&mut tracker.handle_event(&event));
+		}
+		assert_eq!(swap_events, vec![SwapEvent::FundingConfirmed {
+			payment_hash, outpoint: funding_outpoint, value_satoshis: 100_000, height: nodes[0].best_block_info().1,
+		}]);
+		assert_eq!(tracker.get_swap(&payment_hash).unwrap().1, Some((funding_outpoint, 100_000)));
+
+		// The tracker round-trips through serialization.
+		let mut tracker = SwapTracker::read(&mut io::Cursor::new(&tracker.encode())).unwrap();
+		assert!(tracker.best_block_updated(refund_height - 1).is_empty());
+
+		let claim_tx = contract.build_claim_transaction(funding_outpoint, 100_000, &payment_preimage,
+			Script::new(), 253, &claim_key, &secp_ctx).unwrap();
+		mine_transaction(&nodes[0], &claim_tx);
+		let mut swap_events = Vec::new();
+		for event in chain_monitor.get_and_clear_pending_events() {
+			swap_events.append(&mut tracker.handle_event(&event));
+		}
+		assert_eq!(swap_events, vec![
+			SwapEvent::PreimageLearned { payment_hash, payment_preimage },
+			SwapEvent::Claimed { payment_hash, txid: claim_tx.txid() },
+		]);
+		// Resolved swaps don't generate refund events.
+		assert!(tracker.best_block_updated(refund_height).is_empty());
+
+		// As a claimer, we're warned as the refund height approaches, and correlate off-chain
+		// payment events with the swap.
+		let (mut claimer_contract, _, _, _) = test_contract(&secp_ctx, refund_height);
+		claimer_contract.payment_hash = PaymentHash([7; 32]);
+		tracker.track_swap(claimer_contract.clone(), SwapRole::Claimer, None, chain_monitor).unwrap();
+		let claimer_funding_tx = build_funding_tx(&claimer_contract, 50_000);
+		mine_transaction(&nodes[0], &claimer_funding_tx);
+		for event in chain_monitor.get_and_clear_pending_events() {
+			tracker.handle_event(&event);
+		}
+		assert!(tracker.best_block_updated(refund_height - CLAIM_DEADLINE_SAFETY_MARGIN - 1).is_empty());
+		assert_eq!(tracker.best_block_updated(refund_height - CLAIM_DEADLINE_SAFETY_MARGIN),
+			vec![SwapEvent::ClaimDeadlineApproaching { payment_hash: PaymentHash([7; 32]), blocks_remaining: CLAIM_DEADLINE_SAFETY_MARGIN }]);
+		assert!(tracker.best_block_updated(refund_height - CLAIM_DEADLINE_SAFETY_MARGIN + 1).is_empty());
+
+		let sent_preimage = PaymentPreimage([8; 32]);
+		assert_eq!(tracker.handle_event(&Event::PaymentSent {
+			payment_id: None, payment_preimage: sent_preimage, payment_hash: PaymentHash([7; 32]), fee_paid_msat: None,
+		}), vec![SwapEvent::PreimageLearned { payment_hash: PaymentHash([7; 32]), payment_preimage: sent_preimage }]);
+
+		// Once removed, the swaps' scripts and outputs are no longer watched.
+		assert_eq!(tracker.remove_swap(&payment_hash, chain_monitor), Some(contract.clone()));
+		assert_eq!(tracker.remove_swap(&PaymentHash([7; 32]), chain_monitor), Some(claimer_contract));
+		mine_transaction(&nodes[0], &build_funding_tx(&contract, 1_000));
+		assert!(chain_monitor.get_and_clear_pending_events().is_empty());
+		assert!(tracker.get_swap(&payment_hash).is_none());
+
+		// Application-watched outputs are not persisted, so aren't registered by a reloaded
+		// ChainMonitor, which the test framework checks for on drop.
+		chanmon_cfgs[0].chain_source.watched_outputs.lock().unwrap().clear();
+	}
+}
@@ -14,14 +14,17 @@ use bitcoin::secp256k1::{self, PublicKey, Secp256k1, SecretKey};
 use chain::keysinterface::{KeysInterface, Sign};
 use super::utils;
 use ln::msgs::DecodeError;
+use routing::gossip::{NodeId, ReadOnlyNetworkGraph};
 use util::chacha20poly1305rfc::ChaChaPolyWriteAdapter;
-use util::ser::{Readable, VecWriter, Writeable, Writer};
+use util::ser::{BigSize, Readable, VecWriter, Writeable, Writer};
 
+use core::cmp;
 use io;
 use prelude::*;
 
 /// Onion messages can be sent and received to blinded routes, which serve to hide the identity of
 /// the recipient.
+#[derive(Clone, Debug, PartialEq)]
 pub struct BlindedRoute {
 	/// To send to a blinded route, the sender first finds a route to the unblinded
 	/// `introduction_node_id`, which can unblind its [`encrypted_payload`] to find out the onion
@@ -40,6 +43,7 @@ pub struct BlindedRoute {
 
 /// Used to construct the blinded hops portion of a blinded route. These hops cannot be identified
 /// by outside observers and thus can be used to hide the identity of the recipient.
+#[derive(Clone, Debug, PartialEq)]
 pub struct BlindedHop {
 	/// The blinded node id of this hop in a blinded route.
 	pub(super) blinded_node_id: PublicKey,
@@ -49,33 +53,137 @@ pub struct BlindedHop {
 	pub(super) encrypted_payload: Vec<u8>,
 }
 
+/// Parameters for constructing a [`BlindedRoute`] via [`BlindedRoute::new_with_params`] or
+/// [`BlindedRoute::new_for_recipient`], making it harder for the sender and the nodes along the
+/// route to learn about the recipient.
+///
+/// Default::default() adds no dummy hops and no padding, as [`BlindedRoute::new`] does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlindedRouteParams {
+	/// The number of dummy hops added after the recipient, which the recipient peels itself. These
+	/// hide the recipient's distance from the introduction node.
+	pub num_dummy_hops: u8,
+	/// Whether each hop's encrypted payload is padded to the size of the largest, so that the
+	/// payload sizes don't reveal which hop is the recipient. The padding is deterministic, i.e.
+	/// the same hops always result in the same payload sizes.
+	pub pad_payloads: bool,
+}
+
 impl BlindedRoute {
 	/// Create a blinded route to be forwarded along `node_pks`. The last node pubkey in `node_pks`
 	/// will be the destination node.
 	///
 	/// Errors if less than two hops are provided or if `node_pk`(s) are invalid.
-	//  TODO: make all payloads the same size with padding + add dummy hops
 	pub fn new<Signer: Sign, K: KeysInterface, T: secp256k1::Signing + secp256k1::Verification>
 		(node_pks: &[PublicKey], keys_manager: &K, secp_ctx: &Secp256k1<T>) -> Result<Self, ()>
+	{
+		Self::new_with_params::<Signer, _, _>(node_pks, &BlindedRouteParams::default(), keys_manager, secp_ctx)
+	}
+
+	/// Create a blinded route to be forwarded along `node_pks` as in [`BlindedRoute::new`], with
+	/// the dummy hops and padding set in `params`.
+	///
+	/// Errors if less than two hops are provided, if the route would have more than 255 hops
+	/// including dummy hops or if `node_pk`(s) are invalid.
+	pub fn new_with_params<Signer: Sign, K: KeysInterface, T: secp256k1::Signing + secp256k1::Verification>
+		(node_pks: &[PublicKey], params: &BlindedRouteParams, keys_manager: &K, secp_ctx: &Secp256k1<T>) -> Result<Self, ()>
 	{
 		if node_pks.len() < 2 { return Err(()) }
+		if node_pks.len() + params.num_dummy_hops as usize > u8::max_value() as usize { return Err(()) }
+		Self::new_inner(node_pks, None, params, keys_manager, secp_ctx).map_err(|_| ())
+	}
+
+	/// Create a blinded route to `recipient` as in [`BlindedRoute::new_with_params`], through one
+	/// of its channel counterparties in `network_graph` as introduction node.
+	///
+	/// The introduction node is selected among the counterparties whose channels with the
+	/// recipient are enabled in both directions, i.e. which appear to be online, preferring the
+	/// counterparty which can relay the most to the recipient and, among equals, whose channel was
+	/// updated most recently.
+	///
+	/// Errors if no suitable introduction node is found or if `recipient` is invalid.
+	pub fn new_for_recipient<Signer: Sign, K: KeysInterface, T: secp256k1::Signing + secp256k1::Verification>
+		(recipient: PublicKey, network_graph: &ReadOnlyNetworkGraph, params: &BlindedRouteParams,
+		 keys_manager: &K, secp_ctx: &Secp256k1<T>) -> Result<Self, ()>
+	{
+		let introduction_node_id = select_introduction_node(&recipient, network_graph).ok_or(())?;
+		Self::new_with_params::<Signer, _, _>(&[introduction_node_id, recipient], params, keys_manager, secp_ctx)
+	}
+
+	/// Create a blinded route as in [`BlindedRoute::new`], which provides the given `path_id` to
+	/// the destination node along with any onion message sent to it. At least two hops must be
+	/// provided.
+	pub(super) fn new_with_path_id<K: KeysInterface + ?Sized, T: secp256k1::Signing + secp256k1::Verification>
+		(node_pks: &[PublicKey], path_id: Option<[u8; 32]>, keys_manager: &K, secp_ctx: &Secp256k1<T>) -> Result<Self, secp256k1::Error>
+	{
+		Self::new_inner(node_pks, path_id, &BlindedRouteParams::default(), keys_manager, secp_ctx)
+	}
+
+	fn new_inner<K: KeysInterface + ?Sized, T: secp256k1::Signing + secp256k1::Verification>
+		(node_pks: &[PublicKey], path_id: Option<[u8; 32]>, params: &BlindedRouteParams, keys_manager: &K,
+		 secp_ctx: &Secp256k1<T>) -> Result<Self, secp256k1::Error>
+	{
+		debug_assert!(node_pks.len() >= 2);
 		let blinding_secret_bytes = keys_manager.get_secure_random_bytes();
 		let blinding_secret = SecretKey::from_slice(&blinding_secret_bytes[..]).expect("RNG is busted");
 		let introduction_node_id = node_pks[0];
 
+		// Dummy hops are the recipient forwarding to itself.
+		let mut unblinded_path = node_pks.to_vec();
+		let recipient = node_pks[node_pks.len() - 1];
+		unblinded_path.extend((0..params.num_dummy_hops).map(|_| recipient));
+
 		Ok(BlindedRoute {
 			introduction_node_id,
 			blinding_point: PublicKey::from_secret_key(secp_ctx, &blinding_secret),
-			blinded_hops: blinded_hops(secp_ctx, node_pks, &blinding_secret).map_err(|_| ())?,
+			blinded_hops: blinded_hops(secp_ctx, &unblinded_path, path_id, params.pad_payloads, &blinding_secret)?,
 		})
 	}
 }
 
-/// Construct blinded hops for the given `unblinded_path`.
+/// Selects the introduction node for a blinded route to `recipient` from its channel
+/// counterparties in `network_graph`, see [`BlindedRoute::new_for_recipient`].
+fn select_introduction_node(recipient: &PublicKey, network_graph: &ReadOnlyNetworkGraph) -> Option<PublicKey> {
+	let recipient_node_id = NodeId::from_pubkey(recipient);
+	let recipient_info = network_graph.node(&recipient_node_id)?;
+	// Candidates are scored by the amount they can relay to the recipient, then by how recently
+	// their channel with the recipient was updated.
+	let mut best_candidate: Option<(NodeId, (u64, u32))> = None;
+	for short_channel_id in recipient_info.channels.iter() {
+		let channel = match network_graph.channel(*short_channel_id) {
+			Some(channel) => channel,
+			None => continue,
+		};
+		let (counterparty, to_recipient, from_recipient) = if channel.node_one == recipient_node_id {
+			(channel.node_two, &channel.two_to_one, &channel.one_to_two)
+		} else {
+			(channel.node_one, &channel.one_to_two, &channel.two_to_one)
+		};
+		let (to_recipient, from_recipient) = match (to_recipient, from_recipient) {
+			(Some(to_recipient), Some(from_recipient)) if to_recipient.enabled && from_recipient.enabled =>
+				(to_recipient, from_recipient),
+			_ => continue,
+		};
+		let relayable_msat = match channel.capacity_sats {
+			Some(capacity_sats) => cmp::min(capacity_sats.saturating_mul(1000), to_recipient.htlc_maximum_msat),
+			None => to_recipient.htlc_maximum_msat,
+		};
+		let score = (relayable_msat, cmp::max(to_recipient.last_update, from_recipient.last_update));
+		if best_candidate.as_ref().map_or(true, |(_, best_score)| score > *best_score) {
+			best_candidate = Some((counterparty, score));
+		}
+	}
+	best_candidate.and_then(|(node_id, _)| PublicKey::from_slice(node_id.as_slice()).ok())
+}
+
+/// Construct blinded hops for the given `unblinded_path`, padding their payloads to a uniform size
+/// if `pad_payloads` is set.
 fn blinded_hops<T: secp256k1::Signing + secp256k1::Verification>(
-	secp_ctx: &Secp256k1<T>, unblinded_path: &[PublicKey], session_priv: &SecretKey
+	secp_ctx: &Secp256k1<T>, unblinded_path: &[PublicKey], path_id: Option<[u8; 32]>,
+	pad_payloads: bool, session_priv: &SecretKey
 ) -> Result<Vec<BlindedHop>, secp256k1::Error> {
-	let mut blinded_hops = Vec::with_capacity(unblinded_path.len());
+	// The serialized payloads, which are only encrypted once we know how much to pad them.
+	let mut hop_payloads = Vec::with_capacity(unblinded_path.len());
 
 	let mut prev_ss_and_blinded_node_id = None;
 	utils::construct_keys_callback(secp_ctx, unblinded_path, None, session_priv, |blinded_node_id, _, _, encrypted_payload_ss, unblinded_pk, _| {
@@ -85,24 +193,27 @@ fn blinded_hops<T: secp256k1::Signing + secp256k1::Verification>(
 					next_node_id: pk,
 					next_blinding_override: None,
 				};
-				blinded_hops.push(BlindedHop {
-					blinded_node_id: prev_blinded_node_id,
-					encrypted_payload: encrypt_payload(payload, prev_ss),
-				});
+				hop_payloads.push((prev_blinded_node_id, prev_ss, payload.encode()));
 			} else { debug_assert!(false); }
 		}
 		prev_ss_and_blinded_node_id = Some((encrypted_payload_ss, blinded_node_id));
 	})?;
 
 	if let Some((final_ss, final_blinded_node_id)) = prev_ss_and_blinded_node_id {
-		let final_payload = ReceiveTlvs { path_id: None };
-		blinded_hops.push(BlindedHop {
-			blinded_node_id: final_blinded_node_id,
-			encrypted_payload: encrypt_payload(final_payload, final_ss),
-		});
+		let final_payload = ReceiveTlvs { path_id };
+		hop_payloads.push((final_blinded_node_id, final_ss, final_payload.encode()));
 	} else { debug_assert!(false) }
 
-	Ok(blinded_hops)
+	let padded_len = if pad_payloads {
+		hop_payloads.iter().map(|(_, _, tlvs)| tlvs.len()).max()
+	} else { None };
+	Ok(hop_payloads.into_iter().map(|(blinded_node_id, ss, tlvs)| {
+		let padding_len = padded_len.map(|padded_len| padded_len - tlvs.len());
+		BlindedHop {
+			blinded_node_id,
+			encrypted_payload: encrypt_payload(PaddedTlvs { padding_len, tlvs }, ss),
+		}
+	}).collect())
 }
 
 /// Encrypt TLV payload to be used as a [`BlindedHop::encrypted_payload`].
@@ -148,6 +259,24 @@ impl_writeable!(BlindedHop, {
 	encrypted_payload
 });
 
+/// A serialized TLV stream, preceded by a padding TLV if `padding_len` is set.
+struct PaddedTlvs {
+	padding_len: Option<usize>,
+	tlvs: Vec<u8>,
+}
+
+impl Writeable for PaddedTlvs {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		if let Some(padding_len) = self.padding_len {
+			// The padding TLV has the lowest type, and thus comes first.
+			BigSize(1).write(writer)?;
+			BigSize(padding_len as u64).write(writer)?;
+			writer.write_all(&vec![0; padding_len])?;
+		}
+		writer.write_all(&self.tlvs)
+	}
+}
+
 /// TLVs to encode in an intermediate onion message packet's hop data. When provided in a blinded
 /// route, they are encoded into [`BlindedHop::encrypted_payload`].
 pub(crate) struct ForwardTlvs {
@@ -168,7 +297,6 @@ pub(crate) struct ReceiveTlvs {
 
 impl Writeable for ForwardTlvs {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
-		// TODO: write padding
 		encode_tlv_stream!(writer, {
 			(4, self.next_node_id, required),
 			(8, self.next_blinding_override, option)
@@ -179,7 +307,6 @@ impl Writeable for ForwardTlvs {
 
 impl Writeable for ReceiveTlvs {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
-		// TODO: write padding
 		encode_tlv_stream!(writer, {
 			(6, self.path_id, option),
 		});
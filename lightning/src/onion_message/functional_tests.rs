@@ -10,15 +10,24 @@
 //! Onion message testing and test utilities live here.
 
 use chain::keysinterface::{KeysInterface, Recipient};
-use ln::msgs::OnionMessageHandler;
-use super::{BlindedRoute, Destination, OnionMessenger, SendError};
+use ln::features::ChannelFeatures;
+use ln::msgs::{DecodeError, OnionMessageHandler, UnsignedChannelUpdate};
+use routing::gossip::NetworkGraph;
+use super::{BlindedRoute, BlindedRouteParams, CustomOnionMessageContents, CustomOnionMessageHandler, Destination, OnionMessageForwardingStats, OnionMessenger, SendError};
+use util::config::OnionMessageForwardingConfig;
 use util::enforcing_trait_impls::EnforcingSigner;
+use util::events::{Event, EventsProvider};
+use util::ser::{Writeable, Writer};
 use util::test_utils;
 
+use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
 
-use sync::Arc;
+use core::cell::RefCell;
+use io;
+use sync::{Arc, Mutex};
+use prelude::*;
 
 struct MessengerNode {
 	keys_manager: Arc<test_utils::TestKeysInterface>,
@@ -69,6 +78,20 @@ fn pass_along_path(path: &Vec<MessengerNode>, expected_path_id: Option<[u8; 32]>
 	}
 }
 
+fn forward_onion_message(from: &MessengerNode, to: &MessengerNode) {
+	let mut msgs = from.messenger.release_pending_msgs();
+	assert_eq!(msgs.len(), 1);
+	let onion_msgs = msgs.remove(&to.get_node_pk()).unwrap();
+	assert_eq!(onion_msgs.len(), 1);
+	to.messenger.handle_onion_message(&from.get_node_pk(), &onion_msgs[0]);
+}
+
+fn get_messenger_events(node: &MessengerNode) -> Vec<Event> {
+	let events = RefCell::new(Vec::new());
+	node.messenger.process_pending_events(&|event: &Event| events.borrow_mut().push(event.clone()));
+	events.into_inner()
+}
+
 #[test]
 fn one_hop() {
 	let nodes = create_nodes(2);
@@ -107,6 +130,88 @@ fn three_blinded_hops() {
 	pass_along_path(&nodes, None);
 }
 
+#[test]
+fn blinded_route_with_dummy_hops_and_padding() {
+	let nodes = create_nodes(3);
+
+	let secp_ctx = Secp256k1::new();
+	let params = BlindedRouteParams { num_dummy_hops: 2, pad_payloads: true };
+	let blinded_route = BlindedRoute::new_with_params::<EnforcingSigner, _, _>(&[nodes[1].get_node_pk(), nodes[2].get_node_pk()], &params, &*nodes[2].keys_manager, &secp_ctx).unwrap();
+	assert_eq!(blinded_route.blinded_hops.len(), 4);
+	let payload_len = blinded_route.blinded_hops[0].encrypted_payload.len();
+	assert!(blinded_route.blinded_hops.iter().all(|hop| hop.encrypted_payload.len() == payload_len));
+
+	// The dummy hops are peeled by the recipient itself, without forwarding.
+	nodes[0].messenger.send_onion_message(&[], Destination::BlindedRoute(blinded_route), None).unwrap();
+	pass_along_path(&nodes, None);
+	assert!(nodes[2].messenger.release_pending_msgs().is_empty());
+	nodes[2].logger.assert_log_contains("lightning::onion_message::messenger".to_string(),
+		"Peeling a dummy hop of an onion message".to_string(), 2);
+
+	// Without padding, the recipient's payload is smaller than the others.
+	let params = BlindedRouteParams { num_dummy_hops: 1, pad_payloads: false };
+	let blinded_route = BlindedRoute::new_with_params::<EnforcingSigner, _, _>(&[nodes[1].get_node_pk(), nodes[2].get_node_pk()], &params, &*nodes[2].keys_manager, &secp_ctx).unwrap();
+	assert_eq!(blinded_route.blinded_hops.len(), 3);
+	assert!(blinded_route.blinded_hops[2].encrypted_payload.len() < blinded_route.blinded_hops[1].encrypted_payload.len());
+
+	// A route may not exceed 255 hops including dummy hops.
+	let params = BlindedRouteParams { num_dummy_hops: 254, pad_payloads: false };
+	assert!(BlindedRoute::new_with_params::<EnforcingSigner, _, _>(&[nodes[1].get_node_pk(), nodes[2].get_node_pk()], &params, &*nodes[2].keys_manager, &secp_ctx).is_err());
+}
+
+#[test]
+fn blinded_route_introduction_node_selection() {
+	let nodes = create_nodes(4);
+	let logger = test_utils::TestLogger::new();
+	let network_graph = NetworkGraph::new(genesis_block(Network::Testnet).header.block_hash(), &logger);
+
+	let update_channel = |short_channel_id: u64, flags: u8, htlc_maximum_msat: u64, timestamp: u32| {
+		network_graph.update_channel_unsigned(&UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			timestamp,
+			flags,
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat,
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+			excess_data: Vec::new(),
+		}).unwrap();
+	};
+	// Adds a channel between nodes[0] and `counterparty`, relaying up to `htlc_maximum_msat` to
+	// nodes[0] and enabled in both directions unless `disabled`.
+	let add_channel = |short_channel_id: u64, counterparty: &MessengerNode, htlc_maximum_msat: u64, disabled: bool| {
+		let (recipient_pk, counterparty_pk) = (nodes[0].get_node_pk(), counterparty.get_node_pk());
+		let recipient_is_one = recipient_pk.serialize() < counterparty_pk.serialize();
+		let (node_1, node_2) = if recipient_is_one { (recipient_pk, counterparty_pk) } else { (counterparty_pk, recipient_pk) };
+		network_graph.add_channel_from_partial_announcement(short_channel_id, 1_000_000_000, ChannelFeatures::known(), node_1, node_2).unwrap();
+		let (to_recipient_dir, from_recipient_dir) = if recipient_is_one { (1, 0) } else { (0, 1) };
+		let disable_flag = if disabled { 2 } else { 0 };
+		update_channel(short_channel_id, to_recipient_dir, htlc_maximum_msat, 1_000_000_000);
+		update_channel(short_channel_id, from_recipient_dir | disable_flag, 1_000_000_000, 1_000_000_000);
+	};
+
+	let secp_ctx = Secp256k1::new();
+	let params = BlindedRouteParams::default();
+	let new_route = || BlindedRoute::new_for_recipient::<EnforcingSigner, _, _>(
+		nodes[0].get_node_pk(), &network_graph.read_only(), &params, &*nodes[0].keys_manager, &secp_ctx);
+
+	// Without any channels, no introduction node can be selected.
+	assert!(new_route().is_err());
+
+	// The counterparty which can relay the most to the recipient is selected, unless its channel
+	// is disabled.
+	add_channel(1, &nodes[1], 100_000, false);
+	add_channel(2, &nodes[2], 500_000, false);
+	add_channel(3, &nodes[3], 900_000, true);
+	assert_eq!(new_route().unwrap().introduction_node_id, nodes[2].get_node_pk());
+
+	// Among equals, the most recently updated channel wins.
+	update_channel(1, if nodes[0].get_node_pk().serialize() < nodes[1].get_node_pk().serialize() { 1 } else { 0 }, 500_000, 1_000_000_001);
+	assert_eq!(new_route().unwrap().introduction_node_id, nodes[1].get_node_pk());
+}
+
 #[test]
 fn too_big_packet_error() {
 	// Make sure we error as expected if a packet is too big to send.
@@ -165,3 +270,260 @@ fn reply_path() {
 		"lightning::onion_message::messenger".to_string(),
 		format!("Received an onion message with path_id: None and reply_path").to_string(), 2);
 }
+
+#[test]
+fn reply_received() {
+	// Check that a reply over a reply path we generated is matched to the request it was sent with.
+	let nodes = create_nodes(3);
+
+	let (request_id, reply_path) = nodes[0].messenger.create_reply_path(&[nodes[1].get_node_pk()], 3).unwrap();
+	nodes[0].messenger.send_onion_message(&[nodes[1].get_node_pk()], Destination::Node(nodes[2].get_node_pk()), Some(reply_path.clone())).unwrap();
+	forward_onion_message(&nodes[0], &nodes[1]);
+	forward_onion_message(&nodes[1], &nodes[2]);
+	nodes[2].logger.assert_log_contains(
+		"lightning::onion_message::messenger".to_string(),
+		"Received an onion message with path_id: None and reply_path".to_string(), 1);
+	assert!(get_messenger_events(&nodes[0]).is_empty());
+
+	nodes[2].messenger.send_onion_message(&[], Destination::BlindedRoute(reply_path.clone()), None).unwrap();
+	forward_onion_message(&nodes[2], &nodes[1]);
+	forward_onion_message(&nodes[1], &nodes[0]);
+	let events = get_messenger_events(&nodes[0]);
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::OnionMessageReplyReceived { request_id: id } => assert_eq!(id, request_id),
+		_ => panic!("Unexpected event"),
+	}
+
+	// Further replies over the same reply path are ignored, and the request no longer times out.
+	nodes[2].messenger.send_onion_message(&[], Destination::BlindedRoute(reply_path), None).unwrap();
+	forward_onion_message(&nodes[2], &nodes[1]);
+	forward_onion_message(&nodes[1], &nodes[0]);
+	for _ in 0..3 { nodes[0].messenger.timer_tick_occurred(); }
+	assert!(get_messenger_events(&nodes[0]).is_empty());
+}
+
+#[test]
+fn reply_timed_out() {
+	let nodes = create_nodes(2);
+
+	let err = nodes[0].messenger.send_onion_message_expecting_reply(&[], Destination::Node(nodes[1].get_node_pk()), &[], 2).unwrap_err();
+	assert_eq!(err, SendError::TooFewBlindedHops);
+
+	let request_id = nodes[0].messenger.send_onion_message_expecting_reply(
+		&[], Destination::Node(nodes[1].get_node_pk()), &[nodes[1].get_node_pk()], 2).unwrap();
+	forward_onion_message(&nodes[0], &nodes[1]);
+
+	nodes[0].messenger.timer_tick_occurred();
+	assert!(get_messenger_events(&nodes[0]).is_empty());
+	nodes[0].messenger.timer_tick_occurred();
+	let events = get_messenger_events(&nodes[0]);
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::OnionMessageReplyTimedOut { request_id: id } => assert_eq!(id, request_id),
+		_ => panic!("Unexpected event"),
+	}
+	nodes[0].messenger.timer_tick_occurred();
+	assert!(get_messenger_events(&nodes[0]).is_empty());
+}
+
+/// Has `nodes[1]` handle `num_msgs` onion messages sent by `nodes[0]` to `nodes[2]`.
+fn send_to_forwarding_node(nodes: &[MessengerNode], num_msgs: usize) {
+	for _ in 0..num_msgs {
+		nodes[0].messenger.send_onion_message(&[nodes[1].get_node_pk()], Destination::Node(nodes[2].get_node_pk()), None).unwrap();
+	}
+	let mut msgs = nodes[0].messenger.release_pending_msgs();
+	for onion_msg in msgs.remove(&nodes[1].get_node_pk()).unwrap() {
+		nodes[1].messenger.handle_onion_message(&nodes[0].get_node_pk(), &onion_msg);
+	}
+}
+
+#[test]
+fn forwarding_rate_limit() {
+	// Check that we only forward as many onion messages from a peer as its rate limit allows.
+	let mut nodes = create_nodes(3);
+	let config = OnionMessageForwardingConfig { max_forwards_per_tick_per_peer: 1, max_burst_per_peer: 2, ..Default::default() };
+	nodes[1].messenger = OnionMessenger::new_with_config(nodes[1].keys_manager.clone(), nodes[1].logger.clone(), config);
+
+	send_to_forwarding_node(&nodes, 3);
+	assert_eq!(nodes[1].messenger.release_pending_msgs().remove(&nodes[2].get_node_pk()).unwrap().len(), 2);
+	assert_eq!(nodes[1].messenger.forwarding_stats(),
+		OnionMessageForwardingStats { forwarded: 2, dropped_rate_limited: 1, dropped_queue_full: 0 });
+
+	// Each timer tick allows another message, up to the burst limit.
+	nodes[1].messenger.timer_tick_occurred();
+	send_to_forwarding_node(&nodes, 2);
+	assert_eq!(nodes[1].messenger.forwarding_stats(),
+		OnionMessageForwardingStats { forwarded: 3, dropped_rate_limited: 2, dropped_queue_full: 0 });
+	for _ in 0..5 { nodes[1].messenger.timer_tick_occurred(); }
+	send_to_forwarding_node(&nodes, 3);
+	assert_eq!(nodes[1].messenger.forwarding_stats(),
+		OnionMessageForwardingStats { forwarded: 5, dropped_rate_limited: 3, dropped_queue_full: 0 });
+}
+
+#[test]
+fn forwarding_queue_limit() {
+	// Check that we drop onion messages to forward once our queue of messages to send is full.
+	let mut nodes = create_nodes(3);
+	let config = OnionMessageForwardingConfig { max_queued_per_peer: 2, max_queued_total: 2, ..Default::default() };
+	nodes[1].messenger = OnionMessenger::new_with_config(nodes[1].keys_manager.clone(), nodes[1].logger.clone(), config);
+
+	// Our own messages count towards the total limit.
+	nodes[1].messenger.send_onion_message(&[], Destination::Node(nodes[0].get_node_pk()), None).unwrap();
+	send_to_forwarding_node(&nodes, 3);
+	assert_eq!(nodes[1].messenger.forwarding_stats(),
+		OnionMessageForwardingStats { forwarded: 1, dropped_rate_limited: 0, dropped_queue_full: 2 });
+
+	// Once the queues drain, we forward up to the per-peer limit.
+	let msgs = nodes[1].messenger.release_pending_msgs();
+	assert_eq!(msgs.get(&nodes[2].get_node_pk()).unwrap().len(), 1);
+	send_to_forwarding_node(&nodes, 3);
+	assert_eq!(nodes[1].messenger.release_pending_msgs().remove(&nodes[2].get_node_pk()).unwrap().len(), 2);
+	assert_eq!(nodes[1].messenger.forwarding_stats(),
+		OnionMessageForwardingStats { forwarded: 3, dropped_rate_limited: 0, dropped_queue_full: 3 });
+}
+
+const PING_TLV_TYPE: u64 = 65;
+const PONG_TLV_TYPE: u64 = 67;
+
+#[derive(Clone, Debug, PartialEq)]
+enum TestCustomMessage {
+	Ping(Vec<u8>),
+	Pong(Vec<u8>),
+}
+
+impl CustomOnionMessageContents for TestCustomMessage {
+	fn tlv_type(&self) -> u64 {
+		match self {
+			TestCustomMessage::Ping(_) => PING_TLV_TYPE,
+			TestCustomMessage::Pong(_) => PONG_TLV_TYPE,
+		}
+	}
+}
+
+impl Writeable for TestCustomMessage {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		match self {
+			TestCustomMessage::Ping(data) | TestCustomMessage::Pong(data) => w.write_all(data),
+		}
+	}
+}
+
+/// Responds to pings with a pong echoing their data, recording all messages it handles.
+struct TestCustomMessageHandler {
+	received: Mutex<Vec<TestCustomMessage>>,
+}
+
+impl TestCustomMessageHandler {
+	fn new() -> Arc<Self> {
+		Arc::new(Self { received: Mutex::new(Vec::new()) })
+	}
+}
+
+impl CustomOnionMessageHandler for TestCustomMessageHandler {
+	type CustomMessage = TestCustomMessage;
+
+	fn read_custom_message<R: io::Read>(&self, message_type: u64, buffer: &mut R) -> Result<Option<Self::CustomMessage>, DecodeError> {
+		let mut data = Vec::new();
+		buffer.read_to_end(&mut data)?;
+		match message_type {
+			PING_TLV_TYPE => Ok(Some(TestCustomMessage::Ping(data))),
+			PONG_TLV_TYPE => Ok(Some(TestCustomMessage::Pong(data))),
+			_ => Ok(None),
+		}
+	}
+
+	fn handle_custom_message(&self, msg: Self::CustomMessage) -> Option<Self::CustomMessage> {
+		self.received.lock().unwrap().push(msg.clone());
+		match msg {
+			TestCustomMessage::Ping(data) => Some(TestCustomMessage::Pong(data)),
+			TestCustomMessage::Pong(_) => None,
+		}
+	}
+}
+
+#[test]
+fn custom_message_reply() {
+	// Check that custom messages are delivered to the handler registered for their type and that
+	// its response is sent back over the reply path.
+	let nodes = create_nodes(3);
+	let handlers = [TestCustomMessageHandler::new(), TestCustomMessageHandler::new(), TestCustomMessageHandler::new()];
+	for (node, handler) in nodes.iter().zip(handlers.iter()) {
+		node.messenger.register_custom_message_handler(&[PING_TLV_TYPE, PONG_TLV_TYPE], handler.clone()).unwrap();
+	}
+
+	// Use enough data that it's read in several chunks.
+	let ping = TestCustomMessage::Ping(vec![42; 5000]);
+	let (request_id, reply_path) = nodes[0].messenger.create_reply_path(&[nodes[1].get_node_pk()], 3).unwrap();
+	nodes[0].messenger.send_custom_onion_message(&[nodes[1].get_node_pk()], Destination::Node(nodes[2].get_node_pk()), &ping, Some(reply_path)).unwrap();
+	forward_onion_message(&nodes[0], &nodes[1]);
+	forward_onion_message(&nodes[1], &nodes[2]);
+	assert_eq!(*handlers[2].received.lock().unwrap(), vec![ping]);
+
+	forward_onion_message(&nodes[2], &nodes[1]);
+	forward_onion_message(&nodes[1], &nodes[0]);
+	assert_eq!(*handlers[0].received.lock().unwrap(), vec![TestCustomMessage::Pong(vec![42; 5000])]);
+	assert!(handlers[1].received.lock().unwrap().is_empty());
+	let events = get_messenger_events(&nodes[0]);
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::OnionMessageReplyReceived { request_id: id } => assert_eq!(id, request_id),
+		_ => panic!("Unexpected event"),
+	}
+
+	// Without a reply path, the response is dropped.
+	nodes[0].messenger.send_custom_onion_message(&[], Destination::Node(nodes[1].get_node_pk()), &TestCustomMessage::Ping(vec![1]), None).unwrap();
+	forward_onion_message(&nodes[0], &nodes[1]);
+	assert_eq!(*handlers[1].received.lock().unwrap(), vec![TestCustomMessage::Ping(vec![1])]);
+	assert!(nodes[1].messenger.release_pending_msgs().is_empty());
+	nodes[1].logger.assert_log_contains(
+		"lightning::onion_message::messenger".to_string(),
+		"Dropping response of type 67 to custom onion message as it had no reply path".to_string(), 1);
+}
+
+#[test]
+fn unknown_custom_message_types() {
+	// Check that onion messages with unknown even custom message types are dropped while unknown
+	// odd types are ignored.
+	let nodes = create_nodes(2);
+	let handler = TestCustomMessageHandler::new();
+	nodes[1].messenger.register_custom_message_handler(&[PONG_TLV_TYPE, 68], handler.clone()).unwrap();
+
+	struct UnknownMessage(u64);
+	impl CustomOnionMessageContents for UnknownMessage {
+		fn tlv_type(&self) -> u64 { self.0 }
+	}
+	impl Writeable for UnknownMessage {
+		fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> { w.write_all(&[1, 2, 3]) }
+	}
+
+	// Type 68 is registered, but the handler doesn't understand it.
+	for tlv_type in [66, 68, 69].iter() {
+		nodes[0].messenger.send_custom_onion_message(&[], Destination::Node(nodes[1].get_node_pk()), &UnknownMessage(*tlv_type), None).unwrap();
+		forward_onion_message(&nodes[0], &nodes[1]);
+	}
+	assert!(handler.received.lock().unwrap().is_empty());
+	let logger = &nodes[1].logger;
+	logger.assert_log_contains("lightning::onion_message::messenger".to_string(),
+		"Dropping onion message with unknown even custom message type 66".to_string(), 1);
+	logger.assert_log_contains("lightning::onion_message::messenger".to_string(),
+		"Dropping onion message with unknown even custom message type 68".to_string(), 1);
+	logger.assert_log_contains("lightning::onion_message::messenger".to_string(),
+		"Ignoring unknown odd custom message type 69 in onion message".to_string(), 1);
+
+	let err = nodes[0].messenger.send_custom_onion_message(&[], Destination::Node(nodes[1].get_node_pk()), &UnknownMessage(63), None).unwrap_err();
+	assert_eq!(err, SendError::InvalidMessage);
+}
+
+#[test]
+fn custom_message_handler_registration() {
+	let nodes = create_nodes(1);
+	let handler = TestCustomMessageHandler::new();
+
+	// Types below 64 are reserved for control TLVs.
+	assert!(nodes[0].messenger.register_custom_message_handler(&[PING_TLV_TYPE, 63], handler.clone()).is_err());
+	nodes[0].messenger.register_custom_message_handler(&[PING_TLV_TYPE], handler.clone()).unwrap();
+	// None of the types are registered if any is already registered.
+	assert!(nodes[0].messenger.register_custom_message_handler(&[PONG_TLV_TYPE, PING_TLV_TYPE], TestCustomMessageHandler::new()).is_err());
+	nodes[0].messenger.register_custom_message_handler(&[PONG_TLV_TYPE], handler).unwrap();
+}
@@ -16,16 +16,19 @@ use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::secp256k1::{self, PublicKey, Scalar, Secp256k1, SecretKey};
 
 use chain::keysinterface::{InMemorySigner, KeysInterface, KeysManager, Recipient, Sign};
-use ln::msgs::{self, OnionMessageHandler};
+use ln::msgs::{self, DecodeError, OnionMessageHandler};
 use ln::onion_utils;
 use super::blinded_route::{BlindedRoute, ForwardTlvs, ReceiveTlvs};
-use super::packet::{BIG_PACKET_HOP_DATA_LEN, ForwardControlTlvs, Packet, Payload, ReceiveControlTlvs, SMALL_PACKET_HOP_DATA_LEN};
+use super::packet::{BIG_PACKET_HOP_DATA_LEN, ForwardControlTlvs, MIN_MESSAGE_TLV_TYPE, Packet, Payload, RawCustomMessage, ReceiveControlTlvs, SMALL_PACKET_HOP_DATA_LEN};
 use super::utils;
-use util::events::OnionMessageProvider;
+use util::config::OnionMessageForwardingConfig;
+use util::events::{Event, EventHandler, EventsProvider, OnionMessageProvider};
 use util::logger::Logger;
+use util::ser::Writeable;
 
-use core::mem;
+use core::{cmp, mem};
 use core::ops::Deref;
+use io;
 use sync::{Arc, Mutex};
 use prelude::*;
 
@@ -33,6 +36,14 @@ use prelude::*;
 /// used to retrieve invoices and fulfill invoice requests from [offers]. Currently, only sending
 /// and receiving empty onion messages is supported.
 ///
+/// Onion messages forwarded on behalf of peers are rate-limited per peer and bounded in number as
+/// set in the [`OnionMessageForwardingConfig`], see [`OnionMessenger::new_with_config`].
+///
+/// Onion messages with custom contents may be sent via
+/// [`OnionMessenger::send_custom_onion_message`] and are delivered to the
+/// [`CustomOnionMessageHandler`] registered for their TLV type via
+/// [`OnionMessenger::register_custom_message_handler`].
+///
 /// # Example
 ///
 //  Needs to be `ignore` until the `onion_message` module is made public, otherwise this is a test
@@ -87,10 +98,101 @@ pub struct OnionMessenger<Signer: Sign, K: Deref, L: Deref>
 	keys_manager: K,
 	logger: L,
 	pending_messages: Mutex<HashMap<PublicKey, VecDeque<msgs::OnionMessage>>>,
+	/// The requests we're awaiting a reply to, by the `path_id` of the reply path we sent with
+	/// them, which also serves as the request's id.
+	pending_replies: Mutex<HashMap<[u8; 32], PendingReply>>,
+	pending_events: Mutex<Vec<Event>>,
+	config: OnionMessageForwardingConfig,
+	forwarding_state: Mutex<ForwardingState>,
+	/// The handlers of custom onion messages, by the TLV types they registered for.
+	custom_handlers: Mutex<HashMap<u64, Arc<dyn RegisteredCustomHandler + Send + Sync>>>,
 	secp_ctx: Secp256k1<secp256k1::All>,
 	// Coming soon:
 	// invoice_handler: InvoiceHandler,
-	// custom_handler: CustomHandler, // handles custom onion messages
+}
+
+/// The contents of a custom onion message, written as the value of a TLV of type
+/// [`CustomOnionMessageContents::tlv_type`].
+pub trait CustomOnionMessageContents: Writeable {
+	/// Returns the TLV type identifying the message contents. Must be at least 64, as lower types
+	/// are reserved for an onion message's control TLVs.
+	fn tlv_type(&self) -> u64;
+}
+
+/// Handler for custom onion messages, registered with an [`OnionMessenger`] for the TLV types it
+/// understands via [`OnionMessenger::register_custom_message_handler`].
+///
+/// Much like a [`CustomMessageHandler`] for peer messages, this allows users to send and receive
+/// onion messages which LDK doesn't know about itself. Custom messages of even TLV types which no
+/// handler understands cause the onion message to be dropped, while those of odd types are
+/// ignored.
+///
+/// [`CustomMessageHandler`]: crate::ln::peer_handler::CustomMessageHandler
+pub trait CustomOnionMessageHandler {
+	/// The type of the custom messages this handler reads and handles.
+	type CustomMessage: CustomOnionMessageContents;
+
+	/// Reads a custom message of the given `message_type` from `buffer`, returning `Ok(None)` if
+	/// the type isn't understood.
+	fn read_custom_message<R: io::Read>(&self, message_type: u64, buffer: &mut R) -> Result<Option<Self::CustomMessage>, DecodeError>;
+
+	/// Handles a custom message received in an onion message, returning a response to send back
+	/// over the onion message's reply path, if any.
+	///
+	/// If the onion message was sent without a reply path, any response is dropped.
+	fn handle_custom_message(&self, msg: Self::CustomMessage) -> Option<Self::CustomMessage>;
+}
+
+/// A type-erased [`CustomOnionMessageHandler`], allowing handlers of different message types to be
+/// registered with a single [`OnionMessenger`].
+trait RegisteredCustomHandler {
+	/// Reads and handles the raw `message`, returning the raw response, if any, or `Ok(None)` if the
+	/// message wasn't understood.
+	fn handle_raw_message(&self, message: &RawCustomMessage) -> Result<Option<Option<RawCustomMessage>>, DecodeError>;
+}
+
+impl<H: CustomOnionMessageHandler> RegisteredCustomHandler for H {
+	fn handle_raw_message(&self, message: &RawCustomMessage) -> Result<Option<Option<RawCustomMessage>>, DecodeError> {
+		let msg = match self.read_custom_message(message.tlv_type, &mut &message.contents[..])? {
+			Some(msg) => msg,
+			None => return Ok(None),
+		};
+		Ok(Some(self.handle_custom_message(msg).map(|response| RawCustomMessage {
+			tlv_type: response.tlv_type(),
+			contents: response.encode(),
+		})))
+	}
+}
+
+/// A request sent via [`OnionMessenger::send_onion_message_expecting_reply`] which we're awaiting
+/// a reply to.
+struct PendingReply {
+	/// The number of calls to [`OnionMessenger::timer_tick_occurred`] since the request was sent.
+	ticks_elapsed: u8,
+	/// The number of ticks after which we give up on receiving a reply.
+	timeout_ticks: u8,
+}
+
+/// The state of our rate limiting of the onion messages we forward.
+struct ForwardingState {
+	/// The number of onion messages each peer may currently have forwarded, i.e. the tokens in
+	/// their token bucket. Peers without an entry have a full bucket.
+	peer_tokens: HashMap<PublicKey, u32>,
+	stats: OnionMessageForwardingStats,
+}
+
+/// Counts of the onion messages an [`OnionMessenger`] has forwarded or dropped, see
+/// [`OnionMessenger::forwarding_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OnionMessageForwardingStats {
+	/// The number of onion messages we've queued for forwarding.
+	pub forwarded: u64,
+	/// The number of onion messages we've dropped as the peer which sent them exceeded its rate
+	/// limit.
+	pub dropped_rate_limited: u64,
+	/// The number of onion messages we've dropped as our queue of messages to send to the next
+	/// peer, or to all peers, was full.
+	pub dropped_queue_full: u64,
 }
 
 /// The destination of an onion message.
@@ -123,6 +225,12 @@ pub enum SendError {
 	/// The provided [`Destination`] was an invalid [`BlindedRoute`], due to having fewer than two
 	/// blinded hops.
 	TooFewBlindedHops,
+	/// Our [`KeysInterface`] failed to provide our node secret, which we need to construct a
+	/// reply path to ourselves.
+	GetNodeIdFailed,
+	/// The custom message's [`CustomOnionMessageContents::tlv_type`] was below 64, i.e. one of the
+	/// types reserved for an onion message's control TLVs.
+	InvalidMessage,
 }
 
 impl<Signer: Sign, K: Deref, L: Deref> OnionMessenger<Signer, K, L>
@@ -130,13 +238,28 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessenger<Signer, K, L>
 	      L::Target: Logger,
 {
 	/// Constructs a new `OnionMessenger` to send, forward, and delegate received onion messages to
-	/// their respective handlers.
+	/// their respective handlers, limiting the messages it forwards as in the default
+	/// [`OnionMessageForwardingConfig`].
 	pub fn new(keys_manager: K, logger: L) -> Self {
+		Self::new_with_config(keys_manager, logger, OnionMessageForwardingConfig::default())
+	}
+
+	/// Constructs a new `OnionMessenger` as in [`OnionMessenger::new`], limiting the messages it
+	/// forwards as in the given `config`.
+	pub fn new_with_config(keys_manager: K, logger: L, config: OnionMessageForwardingConfig) -> Self {
 		let mut secp_ctx = Secp256k1::new();
 		secp_ctx.seeded_randomize(&keys_manager.get_secure_random_bytes());
 		OnionMessenger {
 			keys_manager,
 			pending_messages: Mutex::new(HashMap::new()),
+			pending_replies: Mutex::new(HashMap::new()),
+			pending_events: Mutex::new(Vec::new()),
+			config,
+			forwarding_state: Mutex::new(ForwardingState {
+				peer_tokens: HashMap::new(),
+				stats: OnionMessageForwardingStats::default(),
+			}),
+			custom_handlers: Mutex::new(HashMap::new()),
 			secp_ctx,
 			logger,
 		}
@@ -145,6 +268,48 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessenger<Signer, K, L>
 	/// Send an empty onion message to `destination`, routing it through `intermediate_nodes`.
 	/// See [`OnionMessenger`] for example usage.
 	pub fn send_onion_message(&self, intermediate_nodes: &[PublicKey], destination: Destination, reply_path: Option<BlindedRoute>) -> Result<(), SendError> {
+		self.send_onion_message_internal(intermediate_nodes, destination, reply_path, None)
+	}
+
+	/// Send an onion message containing the custom `contents` to `destination`, routing it through
+	/// `intermediate_nodes`. The recipient's [`CustomOnionMessageHandler`] may respond over the
+	/// `reply_path`, if any, which should generally be created via
+	/// [`OnionMessenger::create_reply_path`] so that its response is delivered to the handler we
+	/// registered for its type.
+	pub fn send_custom_onion_message<T: CustomOnionMessageContents>(
+		&self, intermediate_nodes: &[PublicKey], destination: Destination, contents: &T,
+		reply_path: Option<BlindedRoute>
+	) -> Result<(), SendError> {
+		let tlv_type = contents.tlv_type();
+		if tlv_type < MIN_MESSAGE_TLV_TYPE {
+			return Err(SendError::InvalidMessage);
+		}
+		let message = RawCustomMessage { tlv_type, contents: contents.encode() };
+		self.send_onion_message_internal(intermediate_nodes, destination, reply_path, Some(message))
+	}
+
+	/// Registers `handler` to read and handle received custom onion messages of any of the given
+	/// `tlv_types`.
+	///
+	/// Errors without registering any of the types if one is below 64 or already has a handler
+	/// registered.
+	pub fn register_custom_message_handler<H: CustomOnionMessageHandler + Send + Sync + 'static>(
+		&self, tlv_types: &[u64], handler: Arc<H>
+	) -> Result<(), ()> {
+		let mut custom_handlers = self.custom_handlers.lock().unwrap();
+		if tlv_types.iter().any(|tlv_type| *tlv_type < MIN_MESSAGE_TLV_TYPE || custom_handlers.contains_key(tlv_type)) {
+			return Err(());
+		}
+		for tlv_type in tlv_types {
+			custom_handlers.insert(*tlv_type, handler.clone());
+		}
+		Ok(())
+	}
+
+	fn send_onion_message_internal(
+		&self, intermediate_nodes: &[PublicKey], destination: Destination,
+		reply_path: Option<BlindedRoute>, message: Option<RawCustomMessage>
+	) -> Result<(), SendError> {
 		if let Destination::BlindedRoute(BlindedRoute { ref blinded_hops, .. }) = destination {
 			if blinded_hops.len() < 2 {
 				return Err(SendError::TooFewBlindedHops);
@@ -162,8 +327,8 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessenger<Signer, K, L>
 			}
 		};
 		let (packet_payloads, packet_keys) = packet_payloads_and_keys(
-			&self.secp_ctx, intermediate_nodes, destination, reply_path, &blinding_secret)
-			.map_err(|e| SendError::Secp256k1(e))?;
+			&self.secp_ctx, intermediate_nodes, destination, reply_path, message, &blinding_secret)
+			.map_err(SendError::Secp256k1)?;
 
 		let prng_seed = self.keys_manager.get_secure_random_bytes();
 		let onion_packet = construct_onion_message_packet(
@@ -180,6 +345,131 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessenger<Signer, K, L>
 		Ok(())
 	}
 
+	/// Send an empty onion message to `destination` as in [`OnionMessenger::send_onion_message`],
+	/// along with a reply path to us through `reply_path_intermediate_nodes`, returning the id of
+	/// the request.
+	///
+	/// Once an onion message is received over the reply path, an
+	/// [`Event::OnionMessageReplyReceived`] is generated with the request's id. If none is received
+	/// within `timeout_ticks` calls to [`OnionMessenger::timer_tick_occurred`], an
+	/// [`Event::OnionMessageReplyTimedOut`] is generated instead and any later reply is ignored.
+	///
+	/// As a reply path is a [`BlindedRoute`], at least one `reply_path_intermediate_nodes` is
+	/// required.
+	pub fn send_onion_message_expecting_reply(
+		&self, intermediate_nodes: &[PublicKey], destination: Destination,
+		reply_path_intermediate_nodes: &[PublicKey], timeout_ticks: u8
+	) -> Result<[u8; 32], SendError> {
+		let (request_id, reply_path) = self.create_reply_path(reply_path_intermediate_nodes, timeout_ticks)?;
+		if let Err(e) = self.send_onion_message(intermediate_nodes, destination, Some(reply_path)) {
+			self.pending_replies.lock().unwrap().remove(&request_id);
+			return Err(e);
+		}
+		Ok(request_id)
+	}
+
+	/// Creates a reply path to us through `intermediate_nodes`, for use in a message sent via
+	/// [`OnionMessenger::send_onion_message`], returning it along with the id of the request it
+	/// belongs to. Replies over it generate events as described in
+	/// [`OnionMessenger::send_onion_message_expecting_reply`], which should generally be used
+	/// instead.
+	pub fn create_reply_path(&self, intermediate_nodes: &[PublicKey], timeout_ticks: u8) -> Result<([u8; 32], BlindedRoute), SendError> {
+		if intermediate_nodes.is_empty() {
+			return Err(SendError::TooFewBlindedHops);
+		}
+		let our_node_secret = self.keys_manager.get_node_secret(Recipient::Node)
+			.map_err(|()| SendError::GetNodeIdFailed)?;
+		let mut hops = intermediate_nodes.to_vec();
+		hops.push(PublicKey::from_secret_key(&self.secp_ctx, &our_node_secret));
+
+		// The path_id is encrypted to us by the reply path, so only the recipient of the reply
+		// path can know it and reply over it.
+		let request_id = self.keys_manager.get_secure_random_bytes();
+		let reply_path = BlindedRoute::new_with_path_id(&hops, Some(request_id), &*self.keys_manager, &self.secp_ctx)
+			.map_err(SendError::Secp256k1)?;
+		self.pending_replies.lock().unwrap().insert(request_id, PendingReply { ticks_elapsed: 0, timeout_ticks });
+		Ok((request_id, reply_path))
+	}
+
+	/// Gets counts of the onion messages we've forwarded, or dropped due to the limits in our
+	/// [`OnionMessageForwardingConfig`].
+	pub fn forwarding_stats(&self) -> OnionMessageForwardingStats {
+		self.forwarding_state.lock().unwrap().stats
+	}
+
+	/// Times out requests sent via [`OnionMessenger::send_onion_message_expecting_reply`] which
+	/// haven't been replied to in time and replenishes our peers' onion message forwarding rate
+	/// limits. Should be called roughly once per minute.
+	pub fn timer_tick_occurred(&self) {
+		let max_burst = self.config.max_burst_per_peer;
+		let refill = self.config.max_forwards_per_tick_per_peer;
+		self.forwarding_state.lock().unwrap().peer_tokens.retain(|_, tokens| {
+			*tokens = cmp::min(tokens.saturating_add(refill), max_burst);
+			// Full buckets are equivalent to no entry.
+			*tokens < max_burst
+		});
+
+		let mut pending_events = self.pending_events.lock().unwrap();
+		self.pending_replies.lock().unwrap().retain(|request_id, pending_reply| {
+			pending_reply.ticks_elapsed = pending_reply.ticks_elapsed.saturating_add(1);
+			if pending_reply.ticks_elapsed < pending_reply.timeout_ticks { return true; }
+			log_debug!(self.logger, "Timed out waiting for a reply to onion message request {}", log_bytes!(request_id[..]));
+			pending_events.push(Event::OnionMessageReplyTimedOut { request_id: *request_id });
+			false
+		});
+	}
+
+	/// Takes a token from `peer_node_id`'s token bucket to forward a message it sent us, returning
+	/// whether it had one left.
+	fn take_forwarding_token(&self, peer_node_id: &PublicKey) -> bool {
+		let mut forwarding_state = self.forwarding_state.lock().unwrap();
+		let max_burst = self.config.max_burst_per_peer;
+		let tokens = forwarding_state.peer_tokens.entry(*peer_node_id).or_insert(max_burst);
+		if *tokens == 0 {
+			forwarding_state.stats.dropped_rate_limited += 1;
+			return false;
+		}
+		*tokens -= 1;
+		true
+	}
+
+	/// Delegates a received custom `message` to the handler registered for its type, sending its
+	/// response, if any, over the `reply_path`. Returns false if the onion message carrying it
+	/// should be dropped, i.e. if the message was invalid or of an unknown even type.
+	fn handle_custom_message(&self, message: RawCustomMessage, reply_path: Option<BlindedRoute>) -> bool {
+		// Don't hold the lock while calling into the handler, which may send onion messages itself.
+		let handler = self.custom_handlers.lock().unwrap().get(&message.tlv_type).cloned();
+		let response = match handler.map(|handler| handler.handle_raw_message(&message)) {
+			Some(Ok(Some(response))) => response,
+			Some(Err(e)) => {
+				log_trace!(self.logger, "Failed to read custom onion message of type {}: {:?}", message.tlv_type, e);
+				return false
+			},
+			None|Some(Ok(None)) => {
+				if message.tlv_type % 2 == 0 {
+					log_trace!(self.logger, "Dropping onion message with unknown even custom message type {}", message.tlv_type);
+					return false
+				}
+				log_trace!(self.logger, "Ignoring unknown odd custom message type {} in onion message", message.tlv_type);
+				return true
+			},
+		};
+		match (response, reply_path) {
+			(Some(response), Some(reply_path)) => {
+				if response.tlv_type < MIN_MESSAGE_TLV_TYPE {
+					log_error!(self.logger, "Not sending custom onion message response with reserved type {}", response.tlv_type);
+				} else if let Err(e) = self.send_onion_message_internal(&[], Destination::BlindedRoute(reply_path), None, Some(response)) {
+					log_trace!(self.logger, "Failed to send custom onion message response: {:?}", e);
+				}
+			},
+			(Some(response), None) => {
+				log_trace!(self.logger, "Dropping response of type {} to custom onion message as it had no reply path", response.tlv_type);
+			},
+			(None, _) => {},
+		}
+		true
+	}
+
 	#[cfg(test)]
 	pub(super) fn release_pending_msgs(&self) -> HashMap<PublicKey, VecDeque<msgs::OnionMessage>> {
 		let mut pending_msgs = self.pending_messages.lock().unwrap();
@@ -193,10 +483,11 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessageHandler for OnionMessenger<Si
 	where K::Target: KeysInterface<Signer = Signer>,
 	      L::Target: Logger,
 {
-	/// Handle an incoming onion message. Currently, if a message was destined for us we will log, but
-	/// soon we'll delegate the onion message to a handler that can generate invoices or send
-	/// payments.
-	fn handle_onion_message(&self, _peer_node_id: &PublicKey, msg: &msgs::OnionMessage) {
+	/// Handle an incoming onion message. If a message was destined for us, its custom contents, if
+	/// any, are delegated to the registered [`CustomOnionMessageHandler`], whose response is sent
+	/// back over the message's reply path. Soon we'll also delegate onion messages to a handler
+	/// that can generate invoices or send payments.
+	fn handle_onion_message(&self, peer_node_id: &PublicKey, msg: &msgs::OnionMessage) {
 		let control_tlvs_ss = match self.keys_manager.ecdh(Recipient::Node, &msg.blinding_point, None) {
 			Ok(ss) => ss,
 			Err(e) =>  {
@@ -224,20 +515,23 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessageHandler for OnionMessenger<Si
 			msg.onion_routing_packet.hmac, control_tlvs_ss)
 		{
 			Ok((Payload::Receive {
-				control_tlvs: ReceiveControlTlvs::Unblinded(ReceiveTlvs { path_id }), reply_path,
+				control_tlvs: ReceiveControlTlvs::Unblinded(ReceiveTlvs { path_id }), reply_path, message,
 			}, None)) => {
 				log_info!(self.logger,
 					"Received an onion message with path_id: {:02x?} and {}reply_path",
 						path_id, if reply_path.is_some() { "" } else { "no " });
+				if let Some(message) = message {
+					if !self.handle_custom_message(message, reply_path) { return }
+				}
+				if let Some(request_id) = path_id {
+					if self.pending_replies.lock().unwrap().remove(&request_id).is_some() {
+						self.pending_events.lock().unwrap().push(Event::OnionMessageReplyReceived { request_id });
+					}
+				}
 			},
 			Ok((Payload::Forward(ForwardControlTlvs::Unblinded(ForwardTlvs {
 				next_node_id, next_blinding_override
 			})), Some((next_hop_hmac, new_packet_bytes)))) => {
-				// TODO: we need to check whether `next_node_id` is our node, in which case this is a dummy
-				// blinded hop and this onion message is destined for us. In this situation, we should keep
-				// unwrapping the onion layers to get to the final payload. Since we don't have the option
-				// of creating blinded routes with dummy hops currently, we should be ok to not handle this
-				// for now.
 				let new_pubkey = match onion_utils::next_hop_packet_pubkey(&self.secp_ctx, msg.onion_routing_packet.public_key, &onion_decode_ss) {
 					Ok(pk) => pk,
 					Err(e) => {
@@ -251,33 +545,58 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessageHandler for OnionMessenger<Si
 					hop_data: new_packet_bytes,
 					hmac: next_hop_hmac,
 				};
+				let next_blinding_point = match next_blinding_override {
+					Some(blinding_point) => blinding_point,
+					None => {
+						let blinding_factor = {
+							let mut sha = Sha256::engine();
+							sha.input(&msg.blinding_point.serialize()[..]);
+							sha.input(control_tlvs_ss.as_ref());
+							Sha256::from_engine(sha).into_inner()
+						};
+						let next_blinding_point = msg.blinding_point;
+						match next_blinding_point.mul_tweak(&self.secp_ctx, &Scalar::from_be_bytes(blinding_factor).unwrap()) {
+							Ok(bp) => bp,
+							Err(e) => {
+								log_trace!(self.logger, "Failed to compute next blinding point: {}", e);
+								return
+							}
+						}
+					},
+				};
+				let onion_message = msgs::OnionMessage {
+					blinding_point: next_blinding_point,
+					onion_routing_packet: outgoing_packet,
+				};
+
+				// If the next hop is us, this is a dummy hop and we keep peeling the onion ourselves.
+				// Each dummy hop peels a layer, so this recurses at most once per hop.
+				if let Ok(our_node_secret) = self.keys_manager.get_node_secret(Recipient::Node) {
+					if next_node_id == PublicKey::from_secret_key(&self.secp_ctx, &our_node_secret) {
+						log_trace!(self.logger, "Peeling a dummy hop of an onion message");
+						self.handle_onion_message(peer_node_id, &onion_message);
+						return
+					}
+				}
+
+				if !self.take_forwarding_token(peer_node_id) {
+					log_trace!(self.logger, "Dropping onion message to forward to peer {} as peer {} exceeded its rate limit",
+						next_node_id, peer_node_id);
+					return
+				}
 
 				let mut pending_per_peer_msgs = self.pending_messages.lock().unwrap();
+				let total_queued: usize = pending_per_peer_msgs.values().map(|msgs| msgs.len()).sum();
+				let peer_queued = pending_per_peer_msgs.get(&next_node_id).map_or(0, |msgs| msgs.len());
+				if total_queued >= self.config.max_queued_total || peer_queued >= self.config.max_queued_per_peer {
+					log_trace!(self.logger, "Dropping onion message to forward to peer {} as our queue of messages to send is full",
+						next_node_id);
+					self.forwarding_state.lock().unwrap().stats.dropped_queue_full += 1;
+					return
+				}
 				let pending_msgs = pending_per_peer_msgs.entry(next_node_id).or_insert_with(VecDeque::new);
-				pending_msgs.push_back(
-					msgs::OnionMessage {
-						blinding_point: match next_blinding_override {
-							Some(blinding_point) => blinding_point,
-							None => {
-								let blinding_factor = {
-									let mut sha = Sha256::engine();
-									sha.input(&msg.blinding_point.serialize()[..]);
-									sha.input(control_tlvs_ss.as_ref());
-									Sha256::from_engine(sha).into_inner()
-								};
-								let next_blinding_point = msg.blinding_point;
-								match next_blinding_point.mul_tweak(&self.secp_ctx, &Scalar::from_be_bytes(blinding_factor).unwrap()) {
-									Ok(bp) => bp,
-									Err(e) => {
-										log_trace!(self.logger, "Failed to compute next blinding point: {}", e);
-										return
-									}
-								}
-							},
-						},
-						onion_routing_packet: outgoing_packet,
-					},
-				);
+				pending_msgs.push_back(onion_message);
+				self.forwarding_state.lock().unwrap().stats.forwarded += 1;
 				log_trace!(self.logger, "Forwarding an onion message to peer {}", next_node_id);
 			},
 			Err(e) => {
@@ -303,6 +622,20 @@ impl<Signer: Sign, K: Deref, L: Deref> OnionMessageProvider for OnionMessenger<S
 	}
 }
 
+impl<Signer: Sign, K: Deref, L: Deref> EventsProvider for OnionMessenger<Signer, K, L>
+	where K::Target: KeysInterface<Signer = Signer>,
+	      L::Target: Logger,
+{
+	/// Processes [`Event::OnionMessageReplyReceived`] and [`Event::OnionMessageReplyTimedOut`]
+	/// events for requests sent via [`OnionMessenger::send_onion_message_expecting_reply`].
+	fn process_pending_events<H: Deref>(&self, handler: H) where H::Target: EventHandler {
+		let pending_events = mem::take(&mut *self.pending_events.lock().unwrap());
+		for event in pending_events {
+			handler.handle_event(&event);
+		}
+	}
+}
+
 // TODO: parameterize the below Simple* types with OnionMessenger and handle the messages it
 // produces
 /// Useful for simplifying the parameters of [`SimpleArcChannelManager`] and
@@ -322,7 +655,7 @@ pub type SimpleRefOnionMessenger<'a, 'b, L> = OnionMessenger<InMemorySigner, &'a
 /// `unblinded_path` to the given `destination`.
 fn packet_payloads_and_keys<T: secp256k1::Signing + secp256k1::Verification>(
 	secp_ctx: &Secp256k1<T>, unblinded_path: &[PublicKey], destination: Destination, mut reply_path:
-	Option<BlindedRoute>, session_priv: &SecretKey
+	Option<BlindedRoute>, mut message: Option<RawCustomMessage>, session_priv: &SecretKey
 ) -> Result<(Vec<(Payload, [u8; 32])>, Vec<onion_utils::OnionKeys>), secp256k1::Error> {
 	let num_hops = unblinded_path.len() + destination.num_hops();
 	let mut payloads = Vec::with_capacity(num_hops);
@@ -368,6 +701,7 @@ fn packet_payloads_and_keys<T: secp256k1::Signing + secp256k1::Verification>(
 			payloads.push((Payload::Receive {
 				control_tlvs: ReceiveControlTlvs::Blinded(encrypted_payload),
 				reply_path: reply_path.take(),
+				message: message.take(),
 			}, control_tlvs_ss));
 		}
 
@@ -387,6 +721,7 @@ fn packet_payloads_and_keys<T: secp256k1::Signing + secp256k1::Verification>(
 		payloads.push((Payload::Receive {
 			control_tlvs: ReceiveControlTlvs::Unblinded(ReceiveTlvs { path_id: None, }),
 			reply_path: reply_path.take(),
+			message: message.take(),
 		}, control_tlvs_ss));
 	}
 
@@ -16,7 +16,7 @@ use ln::msgs::DecodeError;
 use ln::onion_utils;
 use super::blinded_route::{BlindedRoute, ForwardTlvs, ReceiveTlvs};
 use util::chacha20poly1305rfc::{ChaChaPolyReadAdapter, ChaChaPolyWriteAdapter};
-use util::ser::{BigSize, FixedLengthReader, LengthRead, LengthReadable, LengthReadableArgs, Readable, ReadableArgs, Writeable, Writer};
+use util::ser::{BigSize, FixedLengthReader, LengthRead, LengthReadable, LengthReadableArgs, Readable, ReadableArgs, VecWriter, Writeable, Writer};
 
 use core::cmp;
 use io::{self, Read};
@@ -27,6 +27,10 @@ use prelude::*;
 pub(super) const SMALL_PACKET_HOP_DATA_LEN: usize = 1300;
 pub(super) const BIG_PACKET_HOP_DATA_LEN: usize = 32768;
 
+/// TLV types below this are reserved for an onion message's control TLVs, while its contents
+/// use types at or above it.
+pub(super) const MIN_MESSAGE_TLV_TYPE: u64 = 64;
+
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct Packet {
 	pub(super) version: u8,
@@ -99,8 +103,7 @@ pub(super) enum Payload {
 	Receive {
 		control_tlvs: ReceiveControlTlvs,
 		reply_path: Option<BlindedRoute>,
-		// Coming soon:
-		// message: Message,
+		message: Option<RawCustomMessage>,
 	}
 }
 
@@ -112,6 +115,24 @@ pub(super) enum Payload {
 //	CustomMessage<T>,
 // }
 
+/// The contents of an onion message as a TLV of a type of at least [`MIN_MESSAGE_TLV_TYPE`], left
+/// for the [`CustomOnionMessageHandler`] registered for its type to read.
+///
+/// [`CustomOnionMessageHandler`]: super::messenger::CustomOnionMessageHandler
+pub(super) struct RawCustomMessage {
+	pub(super) tlv_type: u64,
+	pub(super) contents: Vec<u8>,
+}
+
+impl RawCustomMessage {
+	/// Writes the message as the final TLV of a TLV stream.
+	fn write_tlv<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		BigSize(self.tlv_type).write(w)?;
+		BigSize(self.contents.len() as u64).write(w)?;
+		w.write_all(&self.contents)
+	}
+}
+
 /// Forward control TLVs in their blinded and unblinded form.
 pub(super) enum ForwardControlTlvs {
 	/// If we're sending to a blinded route, the node that constructed the blinded route has provided
@@ -141,12 +162,16 @@ impl Writeable for (Payload, [u8; 32]) {
 				})
 			},
 			Payload::Receive {
-				control_tlvs: ReceiveControlTlvs::Blinded(encrypted_bytes), reply_path
+				control_tlvs: ReceiveControlTlvs::Blinded(encrypted_bytes), reply_path, message
 			} => {
-				encode_varint_length_prefixed_tlv!(w, {
+				let mut tlv_stream = VecWriter(Vec::new());
+				encode_tlv_stream!(&mut tlv_stream, {
 					(2, reply_path, option),
 					(4, encrypted_bytes, vec_type)
-				})
+				});
+				if let Some(message) = message { message.write_tlv(&mut tlv_stream)?; }
+				BigSize(tlv_stream.0.len() as u64).write(w)?;
+				w.write_all(&tlv_stream.0)?;
 			},
 			Payload::Forward(ForwardControlTlvs::Unblinded(control_tlvs)) => {
 				let write_adapter = ChaChaPolyWriteAdapter::new(self.1, &control_tlvs);
@@ -155,13 +180,17 @@ impl Writeable for (Payload, [u8; 32]) {
 				})
 			},
 			Payload::Receive {
-				control_tlvs: ReceiveControlTlvs::Unblinded(control_tlvs), reply_path,
+				control_tlvs: ReceiveControlTlvs::Unblinded(control_tlvs), reply_path, message,
 			} => {
 				let write_adapter = ChaChaPolyWriteAdapter::new(self.1, &control_tlvs);
-				encode_varint_length_prefixed_tlv!(w, {
+				let mut tlv_stream = VecWriter(Vec::new());
+				encode_tlv_stream!(&mut tlv_stream, {
 					(2, reply_path, option),
 					(4, write_adapter, required)
-				})
+				});
+				if let Some(message) = message { message.write_tlv(&mut tlv_stream)?; }
+				BigSize(tlv_stream.0.len() as u64).write(w)?;
+				w.write_all(&tlv_stream.0)?;
 			},
 		}
 		Ok(())
@@ -175,10 +204,25 @@ impl ReadableArgs<SharedSecret> for Payload {
 		let mut rd = FixedLengthReader::new(r, v.0);
 		let mut reply_path: Option<BlindedRoute> = None;
 		let mut read_adapter: Option<ChaChaPolyReadAdapter<ControlTlvs>> = None;
+		let mut message: Option<RawCustomMessage> = None;
 		let rho = onion_utils::gen_rho_from_shared_secret(&encrypted_tlvs_ss.secret_bytes());
 		decode_tlv_stream!(&mut rd, {
 			(2, reply_path, option),
 			(4, read_adapter, (option: LengthReadableArgs, rho))
+		}, |tlv_type: u64, msg_reader: &mut FixedLengthReader<_>| -> Result<bool, DecodeError> {
+			if tlv_type < MIN_MESSAGE_TLV_TYPE { return Ok(false); }
+			// An onion message has at most one message TLV.
+			if message.is_some() { return Err(DecodeError::InvalidValue); }
+			// Read in chunks rather than trusting the TLV's length to size a buffer.
+			let mut contents = Vec::new();
+			loop {
+				let mut buf = [0; 4096];
+				let read_len = msg_reader.read(&mut buf[..])?;
+				if read_len == 0 { break; }
+				contents.extend_from_slice(&buf[..read_len]);
+			}
+			message = Some(RawCustomMessage { tlv_type, contents });
+			Ok(true)
 		});
 		rd.eat_remaining().map_err(|_| DecodeError::ShortRead)?;
 
@@ -188,7 +232,7 @@ impl ReadableArgs<SharedSecret> for Payload {
 				Ok(Payload::Forward(ForwardControlTlvs::Unblinded(tlvs)))
 			},
 			Some(ChaChaPolyReadAdapter { readable: ControlTlvs::Receive(tlvs)}) => {
-				Ok(Payload::Receive { control_tlvs: ReceiveControlTlvs::Unblinded(tlvs), reply_path })
+				Ok(Payload::Receive { control_tlvs: ReceiveControlTlvs::Unblinded(tlvs), reply_path, message })
 			},
 		}
 	}
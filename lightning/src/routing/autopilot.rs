@@ -0,0 +1,434 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Analysis of the [`NetworkGraph`] to find good nodes to open channels with, as a foundation for
+//! "autopilot" features which open channels on a user's behalf.
+//!
+//! A [`ChannelSuggester`] scores each announced node based on:
+//!  * its centrality, approximated by its number of channels and their total capacity,
+//!  * the stability of its fee policies, as observed across calls to
+//!    [`ChannelSuggester::observe_fee_policies`], and
+//!  * its uptime, approximated by how many of its channels it has recently sent updates for.
+//!
+//! The best-scoring nodes may then be turned into ranked [`ChannelSuggestion`]s with proposed
+//! channel sizes given a total budget via [`ChannelSuggester::suggest_channels`].
+//!
+//! Note that, as the scores are based solely on public gossip, they are easily gamed by nodes
+//! wishing to attract channels. Suggestions should thus be combined with other information, e.g.
+//! a curated list of well-known nodes, before being acted upon.
+
+use ln::msgs::NetAddress;
+use routing::gossip::{ChannelInfo, ChannelUpdateInfo, NetworkGraph, NodeId, RoutingFees};
+use util::logger::Logger;
+
+use prelude::*;
+use core::cmp;
+use core::ops::Deref;
+
+/// The value of a component score of a node which is as good as possible. Scores are always
+/// between zero and this value.
+pub const MAX_SCORE: u64 = 1_000_000;
+
+/// Parameters for scoring nodes and sizing suggested channels in a [`ChannelSuggester`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AutopilotParameters {
+	/// The weight of [`NodeScore::centrality`] in [`NodeScore::score`].
+	///
+	/// Default value: 2
+	pub centrality_weight: u64,
+	/// The weight of [`NodeScore::fee_stability`] in [`NodeScore::score`].
+	///
+	/// Default value: 1
+	pub fee_stability_weight: u64,
+	/// The weight of [`NodeScore::uptime`] in [`NodeScore::score`].
+	///
+	/// Default value: 1
+	pub uptime_weight: u64,
+	/// Channel updates older than this many seconds are considered stale when approximating a
+	/// node's uptime.
+	///
+	/// Default value: 3 days
+	pub recent_update_window_secs: u64,
+	/// Nodes with fewer channels than this are not scored.
+	///
+	/// Default value: 3
+	pub min_channels: usize,
+	/// The smallest channel we suggest opening.
+	///
+	/// Default value: 100,000 satoshis
+	pub min_channel_value_satoshis: u64,
+	/// The largest channel we suggest opening.
+	///
+	/// Default value: 5,000,000 satoshis
+	pub max_channel_value_satoshis: u64,
+	/// The maximum number of channels we suggest opening at once.
+	///
+	/// Default value: 5
+	pub max_suggestions: usize,
+}
+
+impl Default for AutopilotParameters {
+	fn default() -> Self {
+		AutopilotParameters {
+			centrality_weight: 2,
+			fee_stability_weight: 1,
+			uptime_weight: 1,
+			recent_update_window_secs: 60 * 60 * 24 * 3,
+			min_channels: 3,
+			min_channel_value_satoshis: 100_000,
+			max_channel_value_satoshis: 5_000_000,
+			max_suggestions: 5,
+		}
+	}
+}
+
+/// The score of a potential channel partner, as computed by [`ChannelSuggester::score_nodes`].
+///
+/// Each component is between zero and [`MAX_SCORE`], with higher values being better.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeScore {
+	/// The node being scored.
+	pub node_id: NodeId,
+	/// How central the node is, relative to the most central node in the graph.
+	pub centrality: u64,
+	/// How rarely the node has changed the fees of its channels.
+	pub fee_stability: u64,
+	/// The fraction of the node's channels which it has recently sent an update enabling.
+	pub uptime: u64,
+	/// The weighted average of the other components.
+	pub score: u64,
+}
+
+/// A suggestion to open a channel, as returned by [`ChannelSuggester::suggest_channels`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelSuggestion {
+	/// The node to open a channel with, and why it was chosen.
+	pub node_score: NodeScore,
+	/// The proposed channel value.
+	pub channel_value_satoshis: u64,
+	/// The addresses the node has announced, at which it may be connected to.
+	pub addresses: Vec<NetAddress>,
+}
+
+/// Scores nodes in a [`NetworkGraph`] as potential channel partners and suggests channels to
+/// open. See the [module-level documentation] for details.
+///
+/// [module-level documentation]: crate::routing::autopilot
+pub struct ChannelSuggester<G: Deref<Target = NetworkGraph<L>>, L: Deref> where L::Target: Logger {
+	network_graph: G,
+	params: AutopilotParameters,
+	/// The last fees we observed for each channel direction, keyed by short channel id and
+	/// whether the direction is from `node_one`.
+	observed_fees: HashMap<(u64, bool), RoutingFees>,
+	/// The number of fee changes we've observed for each node's channels.
+	fee_changes: HashMap<NodeId, u64>,
+}
+
+impl<G: Deref<Target = NetworkGraph<L>>, L: Deref> ChannelSuggester<G, L> where L::Target: Logger {
+	/// Creates a new suggester for the given graph.
+	pub fn new(network_graph: G, params: AutopilotParameters) -> Self {
+		ChannelSuggester {
+			network_graph,
+			params,
+			observed_fees: HashMap::new(),
+			fee_changes: HashMap::new(),
+		}
+	}
+
+	/// Records the current fees of each channel in the graph, counting changes since the
+	/// previous call against the node which set them.
+	///
+	/// This should be called periodically, e.g. after each gossip sync, for
+	/// [`NodeScore::fee_stability`] to be meaningful. Until it is called more than once, all
+	/// nodes are considered perfectly stable.
+	pub fn observe_fee_policies(&mut self) {
+		let graph = self.network_graph.read_only();
+		let mut observed_fees = HashMap::with_capacity(self.observed_fees.len());
+		for (scid, channel) in graph.channels().iter() {
+			for &(from_node_one, update, node_id) in [
+				(true, &channel.one_to_two, &channel.node_one),
+				(false, &channel.two_to_one, &channel.node_two),
+			].iter() {
+				if let Some(update) = update {
+					if let Some(prev_fees) = self.observed_fees.get(&(*scid, from_node_one)) {
+						if *prev_fees != update.fees {
+							*self.fee_changes.entry(*node_id).or_insert(0) += 1;
+						}
+					}
+					observed_fees.insert((*scid, from_node_one), update.fees);
+				}
+			}
+		}
+		self.observed_fees = observed_fees;
+	}
+
+	/// Scores every node in the graph with at least [`AutopilotParameters::min_channels`]
+	/// channels, returning them ordered by descending [`NodeScore::score`].
+	///
+	/// `now_unix_secs` is the current time, against which channel update timestamps are compared.
+	pub fn score_nodes(&self, now_unix_secs: u64) -> Vec<NodeScore> {
+		let graph = self.network_graph.read_only();
+		let channels = graph.channels();
+		let recent_update_cutoff = now_unix_secs.saturating_sub(self.params.recent_update_window_secs);
+
+		// (node_id, channel count, total capacity, recently updated channel count)
+		let mut candidates = Vec::new();
+		for (node_id, node) in graph.nodes().iter() {
+			if node.channels.is_empty() || node.channels.len() < self.params.min_channels { continue; }
+			let mut capacity_sats = 0u64;
+			let mut recently_updated = 0u64;
+			for scid in node.channels.iter() {
+				if let Some(channel) = channels.get(scid) {
+					capacity_sats = capacity_sats.saturating_add(channel_capacity_sats(channel));
+					let update = if channel.node_one == *node_id { &channel.one_to_two } else { &channel.two_to_one };
+					if let Some(update) = update {
+						if update.enabled && update.last_update as u64 >= recent_update_cutoff {
+							recently_updated += 1;
+						}
+					}
+				}
+			}
+			candidates.push((*node_id, node.channels.len() as u64, capacity_sats, recently_updated));
+		}
+
+		let max_channels = candidates.iter().map(|c| c.1).max().unwrap_or(0);
+		let max_capacity_sats = candidates.iter().map(|c| c.2).max().unwrap_or(0);
+		let total_weight = self.params.centrality_weight + self.params.fee_stability_weight + self.params.uptime_weight;
+		let mut scores: Vec<NodeScore> = candidates.drain(..).map(|(node_id, channel_count, capacity_sats, recently_updated)| {
+			let capacity_score = (capacity_sats as u128 * MAX_SCORE as u128)
+				.checked_div(max_capacity_sats as u128).unwrap_or(0) as u64;
+			let centrality = (channel_count * MAX_SCORE / max_channels + capacity_score) / 2;
+			let fee_changes = self.fee_changes.get(&node_id).cloned().unwrap_or(0);
+			let fee_stability = channel_count * MAX_SCORE / (channel_count + fee_changes);
+			let uptime = recently_updated * MAX_SCORE / channel_count;
+			let score = (self.params.centrality_weight * centrality + self.params.fee_stability_weight * fee_stability +
+				self.params.uptime_weight * uptime).checked_div(total_weight).unwrap_or(0);
+			NodeScore { node_id, centrality, fee_stability, uptime, score }
+		}).collect();
+		scores.sort_unstable_by(|a, b| b.score.cmp(&a.score).then_with(|| a.node_id.cmp(&b.node_id)));
+		scores
+	}
+
+	/// Suggests up to [`AutopilotParameters::max_suggestions`] channels to open with the
+	/// best-scoring nodes, ordered by descending score.
+	///
+	/// `budget_satoshis` is split between the suggestions in proportion to their scores, within
+	/// [`AutopilotParameters::min_channel_value_satoshis`] and
+	/// [`AutopilotParameters::max_channel_value_satoshis`]. Fewer channels are suggested if the
+	/// budget does not allow for more.
+	///
+	/// We never suggest channels with ourselves, nodes in `existing_peers` (e.g. those we already
+	/// have channels with), or nodes which have not announced any addresses to connect to.
+	pub fn suggest_channels(
+		&self, our_node_id: &NodeId, existing_peers: &[NodeId], budget_satoshis: u64, now_unix_secs: u64
+	) -> Vec<ChannelSuggestion> {
+		if self.params.min_channel_value_satoshis == 0 { return Vec::new(); }
+		let max_count = cmp::min(self.params.max_suggestions as u64,
+			budget_satoshis / self.params.min_channel_value_satoshis) as usize;
+		let graph = self.network_graph.read_only();
+		let mut chosen = Vec::new();
+		for node_score in self.score_nodes(now_unix_secs).drain(..) {
+			if chosen.len() >= max_count { break; }
+			if node_score.score == 0 || node_score.node_id == *our_node_id ||
+				existing_peers.contains(&node_score.node_id)
+			{
+				continue;
+			}
+			let addresses = match graph.node(&node_score.node_id).and_then(|node| node.announcement_info.as_ref()) {
+				Some(info) if !info.addresses.is_empty() => info.addresses.clone(),
+				_ => continue,
+			};
+			chosen.push((node_score, addresses));
+		}
+
+		let mut remaining_budget = budget_satoshis;
+		let mut remaining_score: u64 = chosen.iter().map(|(node_score, _)| node_score.score).sum();
+		let mut suggestions = Vec::with_capacity(chosen.len());
+		for (node_score, addresses) in chosen.drain(..) {
+			let share = (remaining_budget as u128 * node_score.score as u128 / remaining_score as u128) as u64;
+			let channel_value_satoshis = cmp::min(remaining_budget, cmp::min(self.params.max_channel_value_satoshis,
+				cmp::max(self.params.min_channel_value_satoshis, share)));
+			if channel_value_satoshis < self.params.min_channel_value_satoshis { break; }
+			remaining_budget -= channel_value_satoshis;
+			remaining_score -= node_score.score;
+			suggestions.push(ChannelSuggestion { node_score, channel_value_satoshis, addresses });
+		}
+		suggestions
+	}
+}
+
+/// Gets the capacity of a channel, falling back to the largest HTLC it allows if the capacity is
+/// unknown.
+fn channel_capacity_sats(channel: &ChannelInfo) -> u64 {
+	match channel.capacity_sats {
+		Some(capacity_sats) => capacity_sats,
+		None => {
+			let htlc_maximum_msat = |update: &Option<ChannelUpdateInfo>|
+				update.as_ref().map(|update| update.htlc_maximum_msat).unwrap_or(0);
+			cmp::max(htlc_maximum_msat(&channel.one_to_two), htlc_maximum_msat(&channel.two_to_one)) / 1000
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{AutopilotParameters, ChannelSuggester, MAX_SCORE};
+	use ln::features::{ChannelFeatures, NodeFeatures};
+	use ln::msgs::{NetAddress, UnsignedChannelUpdate, UnsignedNodeAnnouncement};
+	use routing::gossip::{NetworkGraph, NodeId};
+	use util::test_utils::TestLogger;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	use prelude::*;
+
+	const NOW: u64 = 1_000_000_000;
+
+	fn pubkey(n: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[n; 32]).unwrap())
+	}
+
+	fn add_channel(network_graph: &NetworkGraph<&TestLogger>, short_channel_id: u64, node_1: u8, node_2: u8) {
+		let (node_1, node_2) = if pubkey(node_1).serialize() < pubkey(node_2).serialize() { (node_1, node_2) } else { (node_2, node_1) };
+		network_graph.add_channel_from_partial_announcement(
+			short_channel_id, NOW, ChannelFeatures::known(), pubkey(node_1), pubkey(node_2)).unwrap();
+		update_channel(network_graph, short_channel_id, 0, 10, NOW as u32);
+		update_channel(network_graph, short_channel_id, 1, 10, NOW as u32);
+	}
+
+	fn update_channel(network_graph: &NetworkGraph<&TestLogger>, short_channel_id: u64, flags: u8, fee_base_msat: u32, timestamp: u32) {
+		network_graph.update_channel_unsigned(&UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			timestamp,
+			flags,
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: 1_000_000_000,
+			fee_base_msat,
+			fee_proportional_millionths: 0,
+			excess_data: Vec::new(),
+		}).unwrap();
+	}
+
+	fn announce_node(network_graph: &NetworkGraph<&TestLogger>, node: u8) {
+		network_graph.update_node_from_unsigned_announcement(&UnsignedNodeAnnouncement {
+			features: NodeFeatures::known(),
+			timestamp: NOW as u32,
+			node_id: pubkey(node),
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: vec![NetAddress::IPv4 { addr: [127, 0, 0, node], port: 9735 }],
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		}).unwrap();
+	}
+
+	fn node_id(n: u8) -> NodeId {
+		NodeId::from_pubkey(&pubkey(n))
+	}
+
+	// Builds a graph in which node 1 is a hub connected to nodes 2 through 6, and nodes 2 through
+	// 4 are also connected in a ring.
+	fn network_graph(logger: &TestLogger) -> NetworkGraph<&TestLogger> {
+		let network_graph = NetworkGraph::new(genesis_block(Network::Testnet).header.block_hash(), logger);
+		for node in 2..7 {
+			add_channel(&network_graph, node as u64, 1, node);
+		}
+		add_channel(&network_graph, 10, 2, 3);
+		add_channel(&network_graph, 11, 3, 4);
+		add_channel(&network_graph, 12, 4, 2);
+		for node in 1..7 {
+			announce_node(&network_graph, node);
+		}
+		network_graph
+	}
+
+	fn flags_from(network_graph: &NetworkGraph<&TestLogger>, short_channel_id: u64, node: u8) -> u8 {
+		if network_graph.read_only().channel(short_channel_id).unwrap().node_one == node_id(node) { 0 } else { 1 }
+	}
+
+	#[test]
+	fn scores_central_nodes_highest() {
+		let logger = TestLogger::new();
+		let network_graph = network_graph(&logger);
+		let suggester = ChannelSuggester::new(&network_graph, AutopilotParameters::default());
+
+		let scores = suggester.score_nodes(NOW);
+		// Nodes 5 and 6 only have a single channel and are not scored.
+		assert_eq!(scores.len(), 4);
+		assert_eq!(scores[0].node_id, node_id(1));
+		assert_eq!(scores[0].centrality, MAX_SCORE);
+		assert_eq!(scores[0].fee_stability, MAX_SCORE);
+		assert_eq!(scores[0].uptime, MAX_SCORE);
+		assert_eq!(scores[0].score, MAX_SCORE);
+		for score in scores[1..].iter() {
+			assert_eq!(score.centrality, 3 * MAX_SCORE / 5);
+			assert!(score.score < MAX_SCORE);
+		}
+	}
+
+	#[test]
+	fn penalizes_unstable_fees_and_stale_updates() {
+		let logger = TestLogger::new();
+		let network_graph = network_graph(&logger);
+		let mut suggester = ChannelSuggester::new(&network_graph, AutopilotParameters::default());
+		suggester.observe_fee_policies();
+
+		// Node 2 changes the fees on two of its three channels.
+		update_channel(&network_graph, 2, flags_from(&network_graph, 2, 2), 20, NOW as u32 + 1);
+		update_channel(&network_graph, 10, flags_from(&network_graph, 10, 2), 20, NOW as u32 + 1);
+		suggester.observe_fee_policies();
+		let scores = suggester.score_nodes(NOW);
+		let score_2 = scores.iter().find(|score| score.node_id == node_id(2)).unwrap();
+		assert_eq!(score_2.fee_stability, 3 * MAX_SCORE / 5);
+		let score_3 = scores.iter().find(|score| score.node_id == node_id(3)).unwrap();
+		assert_eq!(score_3.fee_stability, MAX_SCORE);
+
+		// Node 3's updates are no longer recent a week later, except for the one it refreshes.
+		let later = NOW + 60 * 60 * 24 * 7;
+		update_channel(&network_graph, 3, flags_from(&network_graph, 3, 3), 10, later as u32);
+		let scores = suggester.score_nodes(later);
+		let score_3 = scores.iter().find(|score| score.node_id == node_id(3)).unwrap();
+		assert_eq!(score_3.uptime, MAX_SCORE / 3);
+		let score_4 = scores.iter().find(|score| score.node_id == node_id(4)).unwrap();
+		assert_eq!(score_4.uptime, 0);
+	}
+
+	#[test]
+	fn suggests_channels_within_budget() {
+		let logger = TestLogger::new();
+		let network_graph = network_graph(&logger);
+		let params = AutopilotParameters::default();
+		let suggester = ChannelSuggester::new(&network_graph, params);
+
+		// We never suggest ourselves or existing peers.
+		let suggestions = suggester.suggest_channels(&node_id(2), &[node_id(3)], 1_000_000, NOW);
+		assert_eq!(suggestions.len(), 2);
+		assert_eq!(suggestions[0].node_score.node_id, node_id(1));
+		assert_eq!(suggestions[1].node_score.node_id, node_id(4));
+		assert_eq!(suggestions[0].addresses, vec![NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }]);
+		assert!(suggestions[0].channel_value_satoshis > suggestions[1].channel_value_satoshis);
+		assert_eq!(suggestions.iter().map(|s| s.channel_value_satoshis).sum::<u64>(), 1_000_000);
+
+		// Channel sizes are capped, leaving part of a large budget unspent.
+		let suggestions = suggester.suggest_channels(&node_id(5), &[], 100_000_000, NOW);
+		assert_eq!(suggestions.len(), 4);
+		assert!(suggestions.iter().all(|s| s.channel_value_satoshis == params.max_channel_value_satoshis));
+
+		// A small budget only allows for as many minimum-sized channels as it covers.
+		let suggestions = suggester.suggest_channels(&node_id(5), &[], 250_000, NOW);
+		assert_eq!(suggestions.len(), 2);
+		assert!(suggestions.iter().all(|s| s.channel_value_satoshis >= params.min_channel_value_satoshis));
+		assert!(suggestions.iter().map(|s| s.channel_value_satoshis).sum::<u64>() <= 250_000);
+		assert!(suggester.suggest_channels(&node_id(5), &[], 50_000, NOW).is_empty());
+	}
+}
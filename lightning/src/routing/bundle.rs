@@ -0,0 +1,294 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A single serializable container for all of our routing intelligence.
+//!
+//! Restoring a [`NetworkGraph`] and scorer separately after a reinstall (or when moving a wallet
+//! between devices) is error-prone, as both must be kept in sync with each other and with any
+//! routing preferences the application layers on top. A [`RoutingStateBundle`] carries all of
+//! them in one versioned blob, and applies those preferences when used to find routes.
+
+use bitcoin::secp256k1::PublicKey;
+
+use ln::msgs::DecodeError;
+use routing::gossip::{NetworkGraph, NodeId};
+use routing::router::{PaymentParameters, RouteHint, RouteHop};
+use routing::scoring::{ChannelUsage, Score};
+use util::logger::Logger;
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use io;
+use prelude::*;
+use sync::Arc;
+use core::ops::Deref;
+
+const SERIALIZATION_VERSION: u8 = 1;
+const MIN_SERIALIZATION_VERSION: u8 = 1;
+
+/// Route hints we've previously seen for a given payee, cached so that they can be reused when
+/// paying the same payee again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedRouteHints {
+	/// The node id of the payee the hints lead to.
+	pub payee_pubkey: PublicKey,
+	/// The hints for routing to the payee.
+	pub route_hints: Vec<RouteHint>,
+}
+
+impl_writeable_tlv_based!(CachedRouteHints, {
+	(0, payee_pubkey, required),
+	(2, route_hints, vec_type),
+});
+
+/// A [`NetworkGraph`], scorer, and the application's routing preferences, serialized together.
+///
+/// The bundle is itself a [`Score`] which never routes through its blacklisted channels or
+/// nodes, deferring to its scorer otherwise, and so should be given to [`find_route`] in place of
+/// the scorer. Routes to payees with cached hints should be found using the [`PaymentParameters`]
+/// from [`RoutingStateBundle::payment_params_for`].
+///
+/// Write one out via its [`Writeable`] implementation. When reading, the graph is read first and
+/// handed to a closure which builds the scorer's read arguments, as scorers such as
+/// [`ProbabilisticScorer`] need a reference to the graph they score:
+///
+/// ```
+/// # extern crate bitcoin;
+/// # use lightning::routing::bundle::RoutingStateBundle;
+/// # use lightning::routing::scoring::{ProbabilisticScorer, ProbabilisticScoringParameters};
+/// # use lightning::util::logger::{Logger, Record};
+/// # use lightning::util::ser::ReadableArgs;
+/// # use std::sync::Arc;
+/// #
+/// # struct FakeLogger {}
+/// # impl Logger for FakeLogger {
+/// #     fn log(&self, record: &Record) { unimplemented!() }
+/// # }
+/// # fn read(bytes: &[u8], logger: &FakeLogger) {
+/// type Bundle<'a> = RoutingStateBundle<
+/// 	Arc<lightning::routing::gossip::NetworkGraph<&'a FakeLogger>>, &'a FakeLogger,
+/// 	ProbabilisticScorer<Arc<lightning::routing::gossip::NetworkGraph<&'a FakeLogger>>, &'a FakeLogger>>;
+/// let bundle = Bundle::read(&mut &bytes[..], (logger, |graph| {
+/// 	(ProbabilisticScoringParameters::default(), graph, logger)
+/// }));
+/// # }
+/// ```
+///
+/// [`find_route`]: crate::routing::router::find_route
+/// [`ProbabilisticScorer`]: crate::routing::scoring::ProbabilisticScorer
+pub struct RoutingStateBundle<G: Deref<Target = NetworkGraph<L>>, L: Deref, S> where L::Target: Logger {
+	/// The network graph.
+	pub network_graph: G,
+	/// The scorer, which is generally a [`Score`] or [`WriteableScore`].
+	///
+	/// [`WriteableScore`]: crate::routing::scoring::WriteableScore
+	pub scorer: S,
+	/// Short channel ids of channels the application never wants to route through.
+	pub blacklisted_channels: Vec<u64>,
+	/// Nodes the application never wants to route through.
+	pub blacklisted_nodes: Vec<NodeId>,
+	/// Route hints cached from previously paid invoices.
+	pub route_hint_cache: Vec<CachedRouteHints>,
+}
+
+impl<G: Deref<Target = NetworkGraph<L>>, L: Deref, S> RoutingStateBundle<G, L, S> where L::Target: Logger {
+	/// Returns the [`PaymentParameters`] for paying `payee_pubkey`, including any route hints we
+	/// have cached for it.
+	pub fn payment_params_for(&self, payee_pubkey: PublicKey) -> PaymentParameters {
+		let route_hints = self.route_hint_cache.iter()
+			.find(|cached| cached.payee_pubkey == payee_pubkey)
+			.map_or(Vec::new(), |cached| cached.route_hints.clone());
+		PaymentParameters::from_node_id(payee_pubkey).with_route_hints(route_hints)
+	}
+}
+
+impl<G: Deref<Target = NetworkGraph<L>>, L: Deref, S: Score> Score for RoutingStateBundle<G, L, S> where L::Target: Logger {
+	fn channel_penalty_msat(
+		&self, short_channel_id: u64, source: &NodeId, target: &NodeId, usage: ChannelUsage
+	) -> u64 {
+		if self.blacklisted_channels.contains(&short_channel_id) ||
+			self.blacklisted_nodes.contains(source) || self.blacklisted_nodes.contains(target)
+		{
+			return u64::max_value();
+		}
+		self.scorer.channel_penalty_msat(short_channel_id, source, target, usage)
+	}
+
+	fn payment_path_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.scorer.payment_path_failed(path, short_channel_id)
+	}
+
+	fn payment_path_successful(&mut self, path: &[&RouteHop]) {
+		self.scorer.payment_path_successful(path)
+	}
+
+	fn probe_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.scorer.probe_failed(path, short_channel_id)
+	}
+
+	fn probe_successful(&mut self, path: &[&RouteHop]) {
+		self.scorer.probe_successful(path)
+	}
+}
+
+impl<G: Deref<Target = NetworkGraph<L>>, L: Deref, S: Writeable> Writeable for RoutingStateBundle<G, L, S> where L::Target: Logger {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_ver_prefix!(writer, SERIALIZATION_VERSION, MIN_SERIALIZATION_VERSION);
+
+		self.network_graph.write(writer)?;
+		self.scorer.write(writer)?;
+		write_tlv_fields!(writer, {
+			(0, self.blacklisted_channels, vec_type),
+			(2, self.blacklisted_nodes, vec_type),
+			(4, self.route_hint_cache, vec_type),
+		});
+		Ok(())
+	}
+}
+
+impl<L: Deref, S, A, F> ReadableArgs<(L, F)> for RoutingStateBundle<Arc<NetworkGraph<L>>, L, S>
+where
+	L::Target: Logger,
+	S: ReadableArgs<A>,
+	F: FnOnce(Arc<NetworkGraph<L>>) -> A,
+{
+	fn read<R: io::Read>(reader: &mut R, args: (L, F)) -> Result<Self, DecodeError> {
+		let (logger, scorer_args) = args;
+		let _ver = read_ver_prefix!(reader, SERIALIZATION_VERSION);
+
+		let network_graph = Arc::new(NetworkGraph::read(reader, logger)?);
+		let scorer = S::read(reader, scorer_args(Arc::clone(&network_graph)))?;
+
+		init_tlv_field_var!(blacklisted_channels, vec_type);
+		init_tlv_field_var!(blacklisted_nodes, vec_type);
+		init_tlv_field_var!(route_hint_cache, vec_type);
+		read_tlv_fields!(reader, {
+			(0, blacklisted_channels, vec_type),
+			(2, blacklisted_nodes, vec_type),
+			(4, route_hint_cache, vec_type),
+		});
+
+		Ok(Self {
+			network_graph,
+			scorer,
+			blacklisted_channels: init_tlv_based_struct_field!(blacklisted_channels, vec_type),
+			blacklisted_nodes: init_tlv_based_struct_field!(blacklisted_nodes, vec_type),
+			route_hint_cache: init_tlv_based_struct_field!(route_hint_cache, vec_type),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CachedRouteHints, RoutingStateBundle};
+
+	use ln::features::{InitFeatures, InvoiceFeatures};
+	use ln::functional_test_utils::*;
+	use routing::gossip::{NetworkGraph, NodeId};
+	use routing::router::{RouteHint, RouteHintHop, RouteParameters, find_route};
+	use routing::gossip::RoutingFees;
+	use routing::scoring::{FixedPenaltyScorer, ProbabilisticScorer, ProbabilisticScoringParameters};
+	use util::ser::{ReadableArgs, Writeable};
+	use util::test_utils::TestLogger;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	use io;
+	use sync::Arc;
+
+	#[test]
+	fn routing_state_bundle_round_trips() {
+		let secp_ctx = Secp256k1::new();
+		let logger = TestLogger::new();
+		let genesis_hash = genesis_block(Network::Testnet).header.block_hash();
+		let network_graph = Arc::new(NetworkGraph::new(genesis_hash, &logger));
+		let scorer = ProbabilisticScorer::new(ProbabilisticScoringParameters::default(), Arc::clone(&network_graph), &logger);
+		let payee_pubkey = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+
+		let bundle = RoutingStateBundle {
+			network_graph: Arc::clone(&network_graph),
+			scorer,
+			blacklisted_channels: vec![42],
+			blacklisted_nodes: vec![NodeId::from_pubkey(&payee_pubkey)],
+			route_hint_cache: vec![CachedRouteHints {
+				payee_pubkey,
+				route_hints: vec![RouteHint(vec![RouteHintHop {
+					src_node_id: payee_pubkey,
+					short_channel_id: 43,
+					fees: RoutingFees { base_msat: 1, proportional_millionths: 2 },
+					cltv_expiry_delta: 40,
+					htlc_minimum_msat: None,
+					htlc_maximum_msat: Some(1000),
+				}])],
+			}],
+		};
+
+		let encoded = bundle.encode();
+		let read_bundle: RoutingStateBundle<_, _, ProbabilisticScorer<_, _>> =
+			RoutingStateBundle::read(&mut io::Cursor::new(&encoded), (&logger, |graph| {
+				(ProbabilisticScoringParameters::default(), graph, &logger)
+			})).unwrap();
+		assert!(*read_bundle.network_graph == *network_graph);
+		assert_eq!(read_bundle.blacklisted_channels, bundle.blacklisted_channels);
+		assert_eq!(read_bundle.blacklisted_nodes, bundle.blacklisted_nodes);
+		assert_eq!(read_bundle.route_hint_cache, bundle.route_hint_cache);
+		assert_eq!(read_bundle.encode(), encoded);
+		assert_eq!(read_bundle.payment_params_for(payee_pubkey).route_hints, bundle.route_hint_cache[0].route_hints);
+
+		// Scorers which don't need the graph to be read work too.
+		let fixed_bundle = RoutingStateBundle {
+			network_graph: Arc::clone(&network_graph),
+			scorer: FixedPenaltyScorer::with_penalty(1234),
+			blacklisted_channels: Vec::new(),
+			blacklisted_nodes: Vec::new(),
+			route_hint_cache: Vec::new(),
+		};
+		let read_bundle: RoutingStateBundle<_, _, FixedPenaltyScorer> =
+			RoutingStateBundle::read(&mut io::Cursor::new(&fixed_bundle.encode()), (&logger, |_| 0)).unwrap();
+		assert_eq!(read_bundle.encode(), fixed_bundle.encode());
+	}
+
+	#[test]
+	fn restored_bundle_avoids_blacklisted_channels_and_nodes() {
+		let chanmon_cfgs = create_chanmon_cfgs(4);
+		let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+		let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+		let chan_0_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		create_announced_chan_between_nodes(&nodes, 1, 3, InitFeatures::known(), InitFeatures::known());
+		let chan_0_2 = create_announced_chan_between_nodes(&nodes, 0, 2, InitFeatures::known(), InitFeatures::known());
+		create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+
+		let bundle = RoutingStateBundle {
+			network_graph: nodes[0].network_graph,
+			scorer: FixedPenaltyScorer::with_penalty(0),
+			blacklisted_channels: vec![chan_0_1.0.contents.short_channel_id],
+			blacklisted_nodes: Vec::new(),
+			route_hint_cache: Vec::new(),
+		};
+		let mut read_bundle: RoutingStateBundle<_, _, FixedPenaltyScorer> =
+			RoutingStateBundle::read(&mut io::Cursor::new(&bundle.encode()), (nodes[0].logger, |_| 0)).unwrap();
+
+		let payee_pubkey = nodes[3].node.get_our_node_id();
+		let route_params = RouteParameters {
+			payment_params: read_bundle.payment_params_for(payee_pubkey).with_features(InvoiceFeatures::known()),
+			final_value_msat: 10_000,
+			final_cltv_expiry_delta: TEST_FINAL_CLTV,
+		};
+		let our_node_id = nodes[0].node.get_our_node_id();
+		let route = find_route(&our_node_id, &route_params, &read_bundle.network_graph, None,
+			nodes[0].logger, &read_bundle, &[42; 32]).unwrap();
+		assert_eq!(route.paths[0][0].short_channel_id, chan_0_2.0.contents.short_channel_id);
+
+		read_bundle.blacklisted_nodes.push(NodeId::from_pubkey(&nodes[2].node.get_our_node_id()));
+		assert!(find_route(&our_node_id, &route_params, &read_bundle.network_graph, None,
+			nodes[0].logger, &read_bundle, &[42; 32]).is_err());
+	}
+}
@@ -35,7 +35,7 @@ use util::scid_utils::{block_from_scid, scid_from_parts, MAX_SCID_BLOCK};
 use io;
 use io_extras::{copy, sink};
 use prelude::*;
-use alloc::collections::{BTreeMap, btree_map::Entry as BtreeEntry};
+use alloc::collections::{BTreeMap, BTreeSet, btree_map::Entry as BtreeEntry};
 use core::{cmp, fmt};
 use sync::{RwLock, RwLockReadGuard};
 use core::sync::atomic::{AtomicUsize, Ordering};
@@ -130,12 +130,15 @@ pub struct NetworkGraph<L: Deref> where L::Target: Logger {
 	// Lock order: channels -> nodes
 	channels: RwLock<BTreeMap<u64, ChannelInfo>>,
 	nodes: RwLock<BTreeMap<NodeId, NodeInfo>>,
+	// Lock order: channels -> nodes -> channel_update_spam
+	channel_update_spam: RwLock<HashMap<NodeId, ChannelUpdateSpamTracker>>,
 }
 
 /// A read-only view of [`NetworkGraph`].
 pub struct ReadOnlyNetworkGraph<'a> {
 	channels: RwLockReadGuard<'a, BTreeMap<u64, ChannelInfo>>,
 	nodes: RwLockReadGuard<'a, BTreeMap<NodeId, NodeInfo>>,
+	channel_update_spam: RwLockReadGuard<'a, HashMap<NodeId, ChannelUpdateSpamTracker>>,
 }
 
 /// Update to the [`NetworkGraph`] based on payment failure information conveyed via the Onion
@@ -184,6 +187,49 @@ impl_writeable_tlv_based_enum_upgradable!(NetworkUpdate,
 	},
 );
 
+/// A policy used by [`P2PGossipSync`] to dampen `channel_update` churn from nodes which flap their
+/// channels constantly.
+///
+/// Updates which arrive more frequently than the policy allows are ignored (and not relayed) and
+/// count towards the spam score of the node which signed them, see
+/// [`ReadOnlyNetworkGraph::channel_update_spam_score`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelUpdateRateLimit {
+	/// The minimum number of seconds, as measured by the `timestamp` field of the updates, which
+	/// must separate two accepted `channel_update`s for the same channel direction.
+	pub min_update_interval_secs: u32,
+	/// The number of seconds, as measured by the `timestamp` field of the updates, after which a
+	/// node's spam score is halved.
+	pub spam_score_half_life_secs: u32,
+}
+
+impl Default for ChannelUpdateRateLimit {
+	fn default() -> Self {
+		Self {
+			min_update_interval_secs: 60 * 5,
+			spam_score_half_life_secs: 60 * 60 * 24,
+		}
+	}
+}
+
+/// Tracks how often a node has sent us `channel_update`s which were dampened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ChannelUpdateSpamTracker {
+	spam_score: u32,
+	last_dampened_timestamp: u32,
+}
+
+impl ChannelUpdateSpamTracker {
+	fn record_dampened_update(&mut self, timestamp: u32, rate_limit: &ChannelUpdateRateLimit) {
+		let elapsed = timestamp.saturating_sub(self.last_dampened_timestamp);
+		if let Some(half_lives) = elapsed.checked_div(rate_limit.spam_score_half_life_secs) {
+			self.spam_score = self.spam_score.checked_shr(half_lives).unwrap_or(0);
+		}
+		self.spam_score = self.spam_score.saturating_add(1);
+		self.last_dampened_timestamp = cmp::max(self.last_dampened_timestamp, timestamp);
+	}
+}
+
 /// Receives and validates network updates from peers,
 /// stores authentic and relevant data as a network graph.
 /// This network graph is then used for routing payments.
@@ -199,6 +245,7 @@ where C::Target: chain::Access, L::Target: Logger
 	chain_access: Option<C>,
 	full_syncs_requested: AtomicUsize,
 	pending_events: Mutex<Vec<MessageSendEvent>>,
+	channel_update_rate_limit: Option<ChannelUpdateRateLimit>,
 	logger: L,
 }
 
@@ -216,6 +263,7 @@ where C::Target: chain::Access, L::Target: Logger
 			full_syncs_requested: AtomicUsize::new(0),
 			chain_access,
 			pending_events: Mutex::new(vec![]),
+			channel_update_rate_limit: None,
 			logger,
 		}
 	}
@@ -227,6 +275,12 @@ where C::Target: chain::Access, L::Target: Logger
 		self.chain_access = chain_access;
 	}
 
+	/// Sets the [`ChannelUpdateRateLimit`] applied to `channel_update`s received from peers, or
+	/// disables dampening entirely if `None` (the default).
+	pub fn set_channel_update_rate_limit(&mut self, rate_limit: Option<ChannelUpdateRateLimit>) {
+		self.channel_update_rate_limit = rate_limit;
+	}
+
 	/// Gets a reference to the underlying [`NetworkGraph`] which was provided in
 	/// [`P2PGossipSync::new`].
 	///
@@ -313,7 +367,7 @@ where C::Target: chain::Access, L::Target: Logger
 	}
 
 	fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<bool, LightningError> {
-		self.network_graph.update_channel(msg)?;
+		self.network_graph.update_channel_intern(&msg.contents, Some(msg), Some(&msg.signature), self.channel_update_rate_limit.as_ref())?;
 		Ok(msg.contents.excess_data.len() <= MAX_EXCESS_BYTES_FOR_RELAY)
 	}
 
@@ -939,6 +993,12 @@ pub enum EffectiveCapacity {
 	/// A capacity sufficient to route any payment, typically used for private channels provided by
 	/// an invoice.
 	Infinite,
+	/// The maximum HTLC amount as provided by an invoice route hint, typically the recipient's
+	/// inbound liquidity in the hinted channel.
+	HintMaxHTLC {
+		/// The maximum HTLC amount denominated in millisatoshi.
+		amount_msat: u64,
+	},
 	/// A capacity that is unknown possibly because either the chain state is unavailable to know
 	/// the total capacity or the `htlc_maximum_msat` was not advertised on the gossip network.
 	Unknown,
@@ -956,6 +1016,7 @@ impl EffectiveCapacity {
 			EffectiveCapacity::MaximumHTLC { amount_msat } => *amount_msat,
 			EffectiveCapacity::Total { capacity_msat, .. } => *capacity_msat,
 			EffectiveCapacity::Infinite => u64::max_value(),
+			EffectiveCapacity::HintMaxHTLC { amount_msat } => *amount_msat,
 			EffectiveCapacity::Unknown => UNKNOWN_CHANNEL_CAPACITY_MSAT,
 		}
 	}
@@ -1125,8 +1186,8 @@ impl Readable for NodeInfo {
 	}
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+pub(crate) const SERIALIZATION_VERSION: u8 = 1;
+pub(crate) const MIN_SERIALIZATION_VERSION: u8 = 1;
 
 impl<L: Deref> Writeable for NetworkGraph<L> where L::Target: Logger {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
@@ -1185,6 +1246,7 @@ impl<L: Deref> ReadableArgs<L> for NetworkGraph<L> where L::Target: Logger {
 			logger,
 			channels: RwLock::new(channels),
 			nodes: RwLock::new(nodes),
+			channel_update_spam: RwLock::new(HashMap::new()),
 			last_rapid_gossip_sync_timestamp: Mutex::new(last_rapid_gossip_sync_timestamp),
 		})
 	}
@@ -1221,6 +1283,7 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 			logger,
 			channels: RwLock::new(BTreeMap::new()),
 			nodes: RwLock::new(BTreeMap::new()),
+			channel_update_spam: RwLock::new(HashMap::new()),
 			last_rapid_gossip_sync_timestamp: Mutex::new(None),
 		}
 	}
@@ -1229,9 +1292,11 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 	pub fn read_only(&'_ self) -> ReadOnlyNetworkGraph<'_> {
 		let channels = self.channels.read().unwrap();
 		let nodes = self.nodes.read().unwrap();
+		let channel_update_spam = self.channel_update_spam.read().unwrap();
 		ReadOnlyNetworkGraph {
 			channels,
 			nodes,
+			channel_update_spam,
 		}
 	}
 
@@ -1608,6 +1673,14 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 				Self::remove_channel_in_nodes(&mut nodes, &info, scid);
 			}
 		}
+
+		// Similarly forget the spam scores of nodes we haven't dampened an update from in as long,
+		// or which are no longer in the graph at all.
+		let nodes = self.nodes.read().unwrap();
+		let mut channel_update_spam = self.channel_update_spam.write().unwrap();
+		channel_update_spam.retain(|node_id, tracker| {
+			tracker.last_dampened_timestamp >= min_time_unix && nodes.contains_key(node_id)
+		});
 	}
 
 	/// For an already known (from announcement) channel, update info about one of the directions
@@ -1620,7 +1693,7 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 	/// If built with `no-std`, any updates with a timestamp more than two weeks in the past or
 	/// materially in the future will be rejected.
 	pub fn update_channel(&self, msg: &msgs::ChannelUpdate) -> Result<(), LightningError> {
-		self.update_channel_intern(&msg.contents, Some(&msg), Some(&msg.signature))
+		self.update_channel_intern(&msg.contents, Some(&msg), Some(&msg.signature), None)
 	}
 
 	/// For an already known (from announcement) channel, update info about one of the directions
@@ -1630,13 +1703,14 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 	/// If built with `no-std`, any updates with a timestamp more than two weeks in the past or
 	/// materially in the future will be rejected.
 	pub fn update_channel_unsigned(&self, msg: &msgs::UnsignedChannelUpdate) -> Result<(), LightningError> {
-		self.update_channel_intern(msg, None, None)
+		self.update_channel_intern(msg, None, None, None)
 	}
 
-	fn update_channel_intern(&self, msg: &msgs::UnsignedChannelUpdate, full_msg: Option<&msgs::ChannelUpdate>, sig: Option<&secp256k1::ecdsa::Signature>) -> Result<(), LightningError> {
+	fn update_channel_intern(&self, msg: &msgs::UnsignedChannelUpdate, full_msg: Option<&msgs::ChannelUpdate>, sig: Option<&secp256k1::ecdsa::Signature>, rate_limit: Option<&ChannelUpdateRateLimit>) -> Result<(), LightningError> {
 		let dest_node_id;
 		let chan_enabled = msg.flags & (1 << 1) != (1 << 1);
 		let chan_was_enabled;
+		let mut dampened = false;
 
 		#[cfg(all(feature = "std", not(test), not(feature = "_test_utils")))]
 		{
@@ -1685,6 +1759,9 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 								return Err(LightningError{err: "Update had same timestamp as last processed update".to_owned(), action: ErrorAction::IgnoreDuplicateGossip});
 							}
 							chan_was_enabled = existing_chan_info.enabled;
+							if let Some(rate_limit) = rate_limit {
+								dampened = msg.timestamp - existing_chan_info.last_update < rate_limit.min_update_interval_secs;
+							}
 						} else {
 							chan_was_enabled = false;
 						}
@@ -1712,6 +1789,20 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 					} }
 				}
 
+				// Only updates with a valid signature count towards a node's spam score, lest anyone
+				// be able to make a node look spammy.
+				macro_rules! check_dampened {
+					($source_node_id: expr) => {
+						if dampened {
+							let mut channel_update_spam = self.channel_update_spam.write().unwrap();
+							channel_update_spam.entry($source_node_id)
+								.or_insert(ChannelUpdateSpamTracker { spam_score: 0, last_dampened_timestamp: msg.timestamp })
+								.record_dampened_update(msg.timestamp, rate_limit.unwrap());
+							return Err(LightningError{err: "channel_update received more frequently than our rate limit allows".to_owned(), action: ErrorAction::IgnoreAndLog(Level::Gossip)});
+						}
+					}
+				}
+
 				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.encode()[..])[..]);
 				if msg.flags & 1 == 1 {
 					dest_node_id = channel.node_one.clone();
@@ -1722,6 +1813,7 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 							action: ErrorAction::IgnoreAndLog(Level::Debug)
 						})?, "channel_update");
 					}
+					check_dampened!(channel.node_two);
 					channel.two_to_one = get_new_channel_info!();
 				} else {
 					dest_node_id = channel.node_two.clone();
@@ -1732,6 +1824,7 @@ impl<L: Deref> NetworkGraph<L> where L::Target: Logger {
 							action: ErrorAction::IgnoreAndLog(Level::Debug)
 						})?, "channel_update");
 					}
+					check_dampened!(channel.node_one);
 					channel.one_to_two = get_new_channel_info!();
 				}
 			}
@@ -1836,6 +1929,29 @@ impl ReadOnlyNetworkGraph<'_> {
 		self.nodes.keys().map(|n| *n).collect()
 	}
 
+	/// Returns the `channel_update` spam score of the node with the given id.
+	///
+	/// The score is incremented each time a validly-signed `channel_update` from the node is
+	/// dampened by a [`P2PGossipSync`] configured with a [`ChannelUpdateRateLimit`], and decays
+	/// per the rate limit's half-life. Nodes we've never dampened an update from have a score of 0.
+	pub fn channel_update_spam_score(&self, node_id: &NodeId) -> u32 {
+		self.channel_update_spam.get(node_id).map_or(0, |tracker| tracker.spam_score)
+	}
+
+	/// Takes a [`NetworkGraphSnapshot`] of the current contents of the graph.
+	pub fn snapshot(&self) -> NetworkGraphSnapshot {
+		let channels = self.channels.iter().map(|(short_channel_id, chan)| ChannelSnapshot {
+			short_channel_id: *short_channel_id,
+			node_one: chan.node_one,
+			node_two: chan.node_two,
+			capacity_sats: chan.capacity_sats,
+			one_to_two: chan.one_to_two.as_ref().map(|info| info.into()),
+			two_to_one: chan.two_to_one.as_ref().map(|info| info.into()),
+		}).collect();
+		let nodes = self.nodes.keys().cloned().collect();
+		NetworkGraphSnapshot { channels, nodes }
+	}
+
 	/// Get network addresses by node id.
 	/// Returns None if the requested node is completely unknown,
 	/// or if node announcement for the node was never received.
@@ -1849,18 +1965,228 @@ impl ReadOnlyNetworkGraph<'_> {
 	}
 }
 
+/// The forwarding policy of one direction of a channel, as captured in a
+/// [`NetworkGraphSnapshot`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelPolicySnapshot {
+	/// Whether the channel can be currently used for payments (in this one direction).
+	pub enabled: bool,
+	/// The difference in CLTV values that you must have when routing through this channel.
+	pub cltv_expiry_delta: u16,
+	/// The minimum value, which must be relayed to the next hop via the channel
+	pub htlc_minimum_msat: u64,
+	/// The maximum value which may be relayed to the next hop via the channel.
+	pub htlc_maximum_msat: u64,
+	/// Fees charged when the channel is used for routing
+	pub fees: RoutingFees,
+}
+
+impl From<&ChannelUpdateInfo> for ChannelPolicySnapshot {
+	fn from(info: &ChannelUpdateInfo) -> Self {
+		Self {
+			enabled: info.enabled,
+			cltv_expiry_delta: info.cltv_expiry_delta,
+			htlc_minimum_msat: info.htlc_minimum_msat,
+			htlc_maximum_msat: info.htlc_maximum_msat,
+			fees: info.fees,
+		}
+	}
+}
+
+impl_writeable_tlv_based!(ChannelPolicySnapshot, {
+	(0, enabled, required),
+	(2, cltv_expiry_delta, required),
+	(4, htlc_minimum_msat, required),
+	(6, htlc_maximum_msat, required),
+	(8, fees, required),
+});
+
+/// A channel as captured in a [`NetworkGraphSnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelSnapshot {
+	/// The short channel id of the channel.
+	pub short_channel_id: u64,
+	/// Source node of the first direction of the channel
+	pub node_one: NodeId,
+	/// Source node of the second direction of the channel
+	pub node_two: NodeId,
+	/// The channel capacity as seen on-chain, if chain lookup was available.
+	pub capacity_sats: Option<u64>,
+	/// The policy of the first direction of the channel, if known.
+	pub one_to_two: Option<ChannelPolicySnapshot>,
+	/// The policy of the second direction of the channel, if known.
+	pub two_to_one: Option<ChannelPolicySnapshot>,
+}
+
+impl_writeable_tlv_based!(ChannelSnapshot, {
+	(0, short_channel_id, required),
+	(2, node_one, required),
+	(4, node_two, required),
+	(6, capacity_sats, option),
+	(8, one_to_two, option),
+	(10, two_to_one, option),
+});
+
+/// A compact, owned copy of the routing-relevant contents of a [`NetworkGraph`] at a point in
+/// time.
+///
+/// Unlike the [`NetworkGraph`] itself, snapshots hold no locks and carry none of the original
+/// gossip messages, making them cheap to keep around and compare via [`Self::diff`]. They may be
+/// exported via their [`Writeable`] implementation for consumption by analytics tools.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkGraphSnapshot {
+	/// All channels in the graph. Snapshots taken via [`ReadOnlyNetworkGraph::snapshot`] are
+	/// sorted by short channel id.
+	pub channels: Vec<ChannelSnapshot>,
+	/// All nodes in the graph. Snapshots taken via [`ReadOnlyNetworkGraph::snapshot`] are sorted
+	/// by node id.
+	pub nodes: Vec<NodeId>,
+}
+
+impl_writeable_tlv_based!(NetworkGraphSnapshot, {
+	(0, channels, vec_type),
+	(2, nodes, vec_type),
+});
+
+/// A change in the forwarding policy of one direction of a channel between two
+/// [`NetworkGraphSnapshot`]s.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelPolicyChange {
+	/// The short channel id of the channel.
+	pub short_channel_id: u64,
+	/// The node which sets the policy for this direction of the channel.
+	pub source_node: NodeId,
+	/// The policy in the older snapshot, if any.
+	pub old_policy: Option<ChannelPolicySnapshot>,
+	/// The policy in the newer snapshot, if any.
+	pub new_policy: Option<ChannelPolicySnapshot>,
+}
+
+impl_writeable_tlv_based!(ChannelPolicyChange, {
+	(0, short_channel_id, required),
+	(2, source_node, required),
+	(4, old_policy, option),
+	(6, new_policy, option),
+});
+
+/// A change in the on-chain capacity of a channel between two [`NetworkGraphSnapshot`]s.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelCapacityChange {
+	/// The short channel id of the channel.
+	pub short_channel_id: u64,
+	/// The capacity in the older snapshot, if known.
+	pub old_capacity_sats: Option<u64>,
+	/// The capacity in the newer snapshot, if known.
+	pub new_capacity_sats: Option<u64>,
+}
+
+impl_writeable_tlv_based!(ChannelCapacityChange, {
+	(0, short_channel_id, required),
+	(2, old_capacity_sats, option),
+	(4, new_capacity_sats, option),
+});
+
+/// The differences between two [`NetworkGraphSnapshot`]s, as returned by
+/// [`NetworkGraphSnapshot::diff`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetworkGraphDiff {
+	/// Channels present only in the newer snapshot.
+	pub channels_added: Vec<ChannelSnapshot>,
+	/// Short channel ids of channels present only in the older snapshot.
+	pub channels_removed: Vec<u64>,
+	/// Nodes present only in the newer snapshot.
+	pub nodes_added: Vec<NodeId>,
+	/// Nodes present only in the older snapshot.
+	pub nodes_removed: Vec<NodeId>,
+	/// Policy changes on channels present in both snapshots.
+	pub policy_changes: Vec<ChannelPolicyChange>,
+	/// Capacity changes on channels present in both snapshots.
+	pub capacity_changes: Vec<ChannelCapacityChange>,
+}
+
+impl_writeable_tlv_based!(NetworkGraphDiff, {
+	(0, channels_added, vec_type),
+	(2, channels_removed, vec_type),
+	(4, nodes_added, vec_type),
+	(6, nodes_removed, vec_type),
+	(8, policy_changes, vec_type),
+	(9, capacity_changes, vec_type),
+});
+
+impl NetworkGraphDiff {
+	/// Returns true if the two snapshots were identical.
+	pub fn is_empty(&self) -> bool {
+		self.channels_added.is_empty() && self.channels_removed.is_empty() &&
+			self.nodes_added.is_empty() && self.nodes_removed.is_empty() &&
+			self.policy_changes.is_empty() && self.capacity_changes.is_empty()
+	}
+}
+
+impl NetworkGraphSnapshot {
+	/// Computes the changes needed to go from `self` to the `newer` snapshot.
+	///
+	/// Snapshots need not be sorted, though each channel and node should only appear once. The
+	/// changes are returned sorted by short channel id or node id.
+	pub fn diff(&self, newer: &NetworkGraphSnapshot) -> NetworkGraphDiff {
+		let mut diff = NetworkGraphDiff::default();
+
+		let old_chans: BTreeMap<u64, &ChannelSnapshot> =
+			self.channels.iter().map(|chan| (chan.short_channel_id, chan)).collect();
+		let new_chans: BTreeMap<u64, &ChannelSnapshot> =
+			newer.channels.iter().map(|chan| (chan.short_channel_id, chan)).collect();
+		for (short_channel_id, old) in old_chans.iter() {
+			let new = match new_chans.get(short_channel_id) {
+				Some(new) => new,
+				None => {
+					diff.channels_removed.push(*short_channel_id);
+					continue;
+				},
+			};
+			if old.capacity_sats != new.capacity_sats {
+				diff.capacity_changes.push(ChannelCapacityChange {
+					short_channel_id: *short_channel_id,
+					old_capacity_sats: old.capacity_sats, new_capacity_sats: new.capacity_sats,
+				});
+			}
+			if old.one_to_two != new.one_to_two {
+				diff.policy_changes.push(ChannelPolicyChange {
+					short_channel_id: *short_channel_id, source_node: new.node_one,
+					old_policy: old.one_to_two, new_policy: new.one_to_two,
+				});
+			}
+			if old.two_to_one != new.two_to_one {
+				diff.policy_changes.push(ChannelPolicyChange {
+					short_channel_id: *short_channel_id, source_node: new.node_two,
+					old_policy: old.two_to_one, new_policy: new.two_to_one,
+				});
+			}
+		}
+		diff.channels_added = new_chans.iter()
+			.filter(|(short_channel_id, _)| !old_chans.contains_key(*short_channel_id))
+			.map(|(_, new)| (*new).clone())
+			.collect();
+
+		let old_nodes: BTreeSet<&NodeId> = self.nodes.iter().collect();
+		let new_nodes: BTreeSet<&NodeId> = newer.nodes.iter().collect();
+		diff.nodes_removed = old_nodes.difference(&new_nodes).map(|node_id| **node_id).collect();
+		diff.nodes_added = new_nodes.difference(&old_nodes).map(|node_id| **node_id).collect();
+
+		diff
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use chain;
 	use ln::chan_utils::make_funding_redeemscript;
 	use ln::PaymentHash;
 	use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
-	use routing::gossip::{P2PGossipSync, NetworkGraph, NetworkUpdate, NodeAlias, MAX_EXCESS_BYTES_FOR_RELAY, NodeId, RoutingFees, ChannelUpdateInfo, ChannelInfo, NodeAnnouncementInfo, NodeInfo};
+	use routing::gossip::{P2PGossipSync, NetworkGraph, NetworkUpdate, NodeAlias, MAX_EXCESS_BYTES_FOR_RELAY, NodeId, RoutingFees, ChannelUpdateInfo, ChannelInfo, NodeAnnouncementInfo, NodeInfo, ChannelUpdateRateLimit, NetworkGraphSnapshot, NetworkGraphDiff, ChannelSnapshot, ChannelCapacityChange};
 	use ln::msgs::{Init, RoutingMessageHandler, UnsignedNodeAnnouncement, NodeAnnouncement,
 		UnsignedChannelAnnouncement, ChannelAnnouncement, UnsignedChannelUpdate, ChannelUpdate,
 		ReplyChannelRange, QueryChannelRange, QueryShortChannelIds, MAX_VALUE_MSAT};
 	use util::test_utils;
-	use util::ser::{ReadableArgs, Writeable};
+	use util::ser::{Readable, ReadableArgs, Writeable};
 	use util::events::{Event, EventHandler, MessageSendEvent, MessageSendEventsProvider};
 	use util::scid_utils::scid_from_parts;
 
@@ -2249,6 +2575,198 @@ mod tests {
 		};
 	}
 
+	#[test]
+	fn dampens_frequent_channel_updates() {
+		let secp_ctx = Secp256k1::new();
+		let logger = test_utils::TestLogger::new();
+		let genesis_hash = genesis_block(Network::Testnet).header.block_hash();
+		let network_graph = NetworkGraph::new(genesis_hash, &logger);
+		let chain_source: Option<&test_utils::TestChainSource> = None;
+		let mut gossip_sync = P2PGossipSync::new(&network_graph, chain_source, &logger);
+		gossip_sync.set_channel_update_rate_limit(Some(ChannelUpdateRateLimit {
+			min_update_interval_secs: 100, spam_score_half_life_secs: 1000,
+		}));
+
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
+		let node_1_id = NodeId::from_pubkey(&PublicKey::from_secret_key(&secp_ctx, node_1_privkey));
+
+		let valid_channel_announcement = get_signed_channel_announcement(|_| {}, node_1_privkey, node_2_privkey, &secp_ctx);
+		let short_channel_id = valid_channel_announcement.contents.short_channel_id;
+		assert!(gossip_sync.handle_channel_announcement(&valid_channel_announcement).is_ok());
+
+		let channel_update = get_signed_channel_update(|_| {}, node_1_privkey, &secp_ctx);
+		let base_timestamp = channel_update.contents.timestamp;
+		assert!(gossip_sync.handle_channel_update(&channel_update).is_ok());
+
+		// An update arriving sooner than the rate limit allows is ignored and counted as spam.
+		let channel_update = get_signed_channel_update(|unsigned_channel_update| {
+			unsigned_channel_update.timestamp += 50;
+			unsigned_channel_update.cltv_expiry_delta = 42;
+		}, node_1_privkey, &secp_ctx);
+		match gossip_sync.handle_channel_update(&channel_update) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "channel_update received more frequently than our rate limit allows")
+		};
+		assert_eq!(network_graph.read_only().channel(short_channel_id).unwrap().one_to_two.as_ref().unwrap().last_update, base_timestamp);
+		assert_eq!(network_graph.read_only().channel_update_spam_score(&node_1_id), 1);
+
+		// An update which fails signature verification does not count towards the spam score.
+		let mut invalid_sig_channel_update = get_signed_channel_update(|unsigned_channel_update| {
+			unsigned_channel_update.timestamp += 60;
+		}, node_1_privkey, &secp_ctx);
+		invalid_sig_channel_update.signature = secp_ctx.sign_ecdsa(&hash_to_message!(&Sha256dHash::hash(&[0; 32])), node_2_privkey);
+		assert!(gossip_sync.handle_channel_update(&invalid_sig_channel_update).is_err());
+		assert_eq!(network_graph.read_only().channel_update_spam_score(&node_1_id), 1);
+
+		// Updates are accepted again once the interval has passed.
+		let channel_update = get_signed_channel_update(|unsigned_channel_update| {
+			unsigned_channel_update.timestamp += 100;
+		}, node_1_privkey, &secp_ctx);
+		assert!(gossip_sync.handle_channel_update(&channel_update).is_ok());
+
+		// The spam score decays over time.
+		let channel_update = get_signed_channel_update(|unsigned_channel_update| {
+			unsigned_channel_update.timestamp += 150;
+		}, node_1_privkey, &secp_ctx);
+		assert!(gossip_sync.handle_channel_update(&channel_update).is_err());
+		assert_eq!(network_graph.read_only().channel_update_spam_score(&node_1_id), 2);
+		let channel_update = get_signed_channel_update(|unsigned_channel_update| {
+			unsigned_channel_update.timestamp += 2100;
+		}, node_1_privkey, &secp_ctx);
+		assert!(gossip_sync.handle_channel_update(&channel_update).is_ok());
+		let channel_update = get_signed_channel_update(|unsigned_channel_update| {
+			unsigned_channel_update.timestamp += 2150;
+		}, node_1_privkey, &secp_ctx);
+		assert!(gossip_sync.handle_channel_update(&channel_update).is_err());
+		assert_eq!(network_graph.read_only().channel_update_spam_score(&node_1_id), 1);
+
+		// Spam scores are pruned along with stale channels.
+		network_graph.remove_stale_channels_with_time(base_timestamp as u64 + 2100 + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS);
+		assert_eq!(network_graph.read_only().channel_update_spam_score(&node_1_id), 1);
+		network_graph.remove_stale_channels_with_time(base_timestamp as u64 + 2150 + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS + 1);
+		assert_eq!(network_graph.read_only().channel_update_spam_score(&node_1_id), 0);
+		assert!(network_graph.channel_update_spam.read().unwrap().is_empty());
+	}
+
+	#[test]
+	fn network_graph_snapshot_diff() {
+		let secp_ctx = Secp256k1::new();
+		let logger = test_utils::TestLogger::new();
+		let genesis_hash = genesis_block(Network::Testnet).header.block_hash();
+		let network_graph = NetworkGraph::new(genesis_hash, &logger);
+		let chain_source: Option<&test_utils::TestChainSource> = None;
+		let gossip_sync = P2PGossipSync::new(&network_graph, chain_source, &logger);
+
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
+		let node_3_privkey = &SecretKey::from_slice(&[40; 32]).unwrap();
+		let node_3_id = NodeId::from_pubkey(&PublicKey::from_secret_key(&secp_ctx, node_3_privkey));
+
+		let first_announcement = get_signed_channel_announcement(|_| {}, node_1_privkey, node_2_privkey, &secp_ctx);
+		let first_scid = first_announcement.contents.short_channel_id;
+		assert!(gossip_sync.handle_channel_announcement(&first_announcement).is_ok());
+		assert!(gossip_sync.handle_channel_update(&get_signed_channel_update(|_| {}, node_1_privkey, &secp_ctx)).is_ok());
+
+		let old_snapshot = network_graph.read_only().snapshot();
+		assert!(old_snapshot.diff(&old_snapshot).is_empty());
+
+		// Change the fees on the first channel and add a second channel to a new node.
+		assert!(gossip_sync.handle_channel_update(&get_signed_channel_update(|unsigned_channel_update| {
+			unsigned_channel_update.timestamp += 10;
+			unsigned_channel_update.fee_base_msat = 20_000;
+		}, node_1_privkey, &secp_ctx)).is_ok());
+		let second_announcement = get_signed_channel_announcement(|unsigned_announcement| {
+			unsigned_announcement.short_channel_id += 1;
+		}, node_2_privkey, node_3_privkey, &secp_ctx);
+		assert!(gossip_sync.handle_channel_announcement(&second_announcement).is_ok());
+
+		let new_snapshot = network_graph.read_only().snapshot();
+		let diff = old_snapshot.diff(&new_snapshot);
+		assert_eq!(diff.channels_added.len(), 1);
+		assert_eq!(diff.channels_added[0].short_channel_id, first_scid + 1);
+		assert!(diff.channels_removed.is_empty());
+		assert_eq!(diff.nodes_added, vec![node_3_id]);
+		assert!(diff.nodes_removed.is_empty());
+		assert_eq!(diff.policy_changes.len(), 1);
+		assert_eq!(diff.policy_changes[0].short_channel_id, first_scid);
+		assert_eq!(diff.policy_changes[0].old_policy.unwrap().fees.base_msat, 10_000);
+		assert_eq!(diff.policy_changes[0].new_policy.unwrap().fees.base_msat, 20_000);
+
+		// The reverse diff removes what was added.
+		let reverse_diff = new_snapshot.diff(&old_snapshot);
+		assert_eq!(reverse_diff.channels_removed, vec![first_scid + 1]);
+		assert_eq!(reverse_diff.nodes_removed, vec![node_3_id]);
+
+		// Snapshots and diffs round-trip through serialization.
+		assert_eq!(NetworkGraphSnapshot::read(&mut io::Cursor::new(new_snapshot.encode())).unwrap(), new_snapshot);
+		assert_eq!(NetworkGraphDiff::read(&mut io::Cursor::new(diff.encode())).unwrap(), diff);
+	}
+
+	fn snapshot_node_ids(secp_ctx: &Secp256k1<All>) -> Vec<NodeId> {
+		(40..44u8).map(|i| {
+			NodeId::from_pubkey(&PublicKey::from_secret_key(secp_ctx, &SecretKey::from_slice(&[i; 32]).unwrap()))
+		}).collect()
+	}
+
+	fn snapshot_channel(short_channel_id: u64, node_ids: &[NodeId], capacity_sats: Option<u64>) -> ChannelSnapshot {
+		ChannelSnapshot {
+			short_channel_id, node_one: node_ids[0], node_two: node_ids[1], capacity_sats,
+			one_to_two: None, two_to_one: None,
+		}
+	}
+
+	#[test]
+	fn network_graph_snapshot_diff_unsorted() {
+		// Snapshots built by users or read from elsewhere may not be sorted, which mustn't affect
+		// the diff.
+		let secp_ctx = Secp256k1::new();
+		let node_ids = snapshot_node_ids(&secp_ctx);
+		let old_snapshot = NetworkGraphSnapshot {
+			channels: vec![snapshot_channel(3, &node_ids, None), snapshot_channel(1, &node_ids, None)],
+			nodes: vec![node_ids[2], node_ids[0], node_ids[1]],
+		};
+		let new_snapshot = NetworkGraphSnapshot {
+			channels: vec![snapshot_channel(4, &node_ids, None), snapshot_channel(1, &node_ids, None), snapshot_channel(2, &node_ids, None)],
+			nodes: vec![node_ids[3], node_ids[1], node_ids[0]],
+		};
+		let diff = old_snapshot.diff(&new_snapshot);
+		assert_eq!(diff.channels_added, vec![snapshot_channel(2, &node_ids, None), snapshot_channel(4, &node_ids, None)]);
+		assert_eq!(diff.channels_removed, vec![3]);
+		assert_eq!(diff.nodes_added, vec![node_ids[3]]);
+		assert_eq!(diff.nodes_removed, vec![node_ids[2]]);
+		assert!(diff.policy_changes.is_empty());
+		assert!(diff.capacity_changes.is_empty());
+
+		let mut reordered_snapshot = new_snapshot.clone();
+		reordered_snapshot.channels.reverse();
+		reordered_snapshot.nodes.reverse();
+		assert!(new_snapshot.diff(&reordered_snapshot).is_empty());
+	}
+
+	#[test]
+	fn network_graph_snapshot_diff_capacity_changes() {
+		let secp_ctx = Secp256k1::new();
+		let node_ids = snapshot_node_ids(&secp_ctx);
+		let old_snapshot = NetworkGraphSnapshot {
+			channels: vec![snapshot_channel(1, &node_ids, None), snapshot_channel(2, &node_ids, Some(500))],
+			nodes: vec![node_ids[0], node_ids[1]],
+		};
+		let new_snapshot = NetworkGraphSnapshot {
+			channels: vec![snapshot_channel(1, &node_ids, Some(1000)), snapshot_channel(2, &node_ids, Some(600))],
+			nodes: vec![node_ids[0], node_ids[1]],
+		};
+		let diff = old_snapshot.diff(&new_snapshot);
+		assert!(!diff.is_empty());
+		assert!(diff.channels_added.is_empty() && diff.channels_removed.is_empty());
+		assert!(diff.policy_changes.is_empty());
+		assert_eq!(diff.capacity_changes, vec![
+			ChannelCapacityChange { short_channel_id: 1, old_capacity_sats: None, new_capacity_sats: Some(1000) },
+			ChannelCapacityChange { short_channel_id: 2, old_capacity_sats: Some(500), new_capacity_sats: Some(600) },
+		]);
+		assert_eq!(NetworkGraphDiff::read(&mut io::Cursor::new(diff.encode())).unwrap(), diff);
+	}
+
 	#[test]
 	fn handling_network_update() {
 		let logger = test_utils::TestLogger::new();
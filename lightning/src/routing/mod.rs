@@ -9,6 +9,8 @@
 
 //! Structs and impls for receiving messages about the network and storing the topology live here.
 
+pub mod autopilot;
+pub mod bundle;
 pub mod gossip;
 pub mod router;
 pub mod scoring;
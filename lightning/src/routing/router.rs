@@ -13,6 +13,8 @@
 //! interrogate it to get routes for your own payments.
 
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
 
 use ln::channelmanager::ChannelDetails;
 use ln::features::{ChannelFeatures, InvoiceFeatures, NodeFeatures};
@@ -28,6 +30,9 @@ use prelude::*;
 use alloc::collections::BinaryHeap;
 use core::cmp;
 use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use sync::Arc;
 
 /// A hop in a route
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -181,6 +186,10 @@ pub const DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA: u32 = 1008;
 // limits, but for now more than 10 paths likely carries too much one-path failure.
 pub const DEFAULT_MAX_PATH_COUNT: u8 = 10;
 
+/// Maximum 'shadow' CLTV expiry delta we add to the final hop of each path by default, see
+/// [`PaymentParameters::max_shadow_cltv_expiry_delta_offset`].
+pub const DEFAULT_MAX_SHADOW_CLTV_EXPIRY_DELTA_OFFSET: u32 = 3*144;
+
 // The median hop CLTV expiry delta currently seen in the network.
 const MEDIAN_HOP_CLTV_EXPIRY_DELTA: u32 = 40;
 
@@ -245,6 +254,53 @@ pub struct PaymentParameters {
 	/// payment to fail. Future attempts for the same payment shouldn't be relayed through any of
 	/// these SCIDs.
 	pub previously_failed_channels: Vec<u64>,
+
+	/// The maximum [`channel_update` spam score] of a node whose channel fees and CLTV deltas we
+	/// will trust. Public channels whose forwarding policy was last set by a node with a higher
+	/// score are not considered during routing. If `None`, spam scores are ignored.
+	///
+	/// [`channel_update` spam score]: crate::routing::gossip::ReadOnlyNetworkGraph::channel_update_spam_score
+	pub max_channel_update_spam_score: Option<u32>,
+
+	/// Trampoline nodes to route the payment through, in order, allowing pathfinding beyond them
+	/// to be outsourced to the trampoline nodes, e.g. for clients without a full network graph.
+	///
+	/// If non-empty, we only find a route to the first trampoline node, paying it enough to cover
+	/// the fees and CLTV deltas of all trampoline hops. The last trampoline node then pays the
+	/// payee as a regular payment, so the payee need not support trampoline payments. Note that
+	/// this requires a payment secret, and thus can't be used for keysend payments.
+	pub trampoline_hops: Vec<TrampolineHop>,
+
+	/// The maximum 'shadow' CLTV expiry delta added to the final hop of each path, hiding from
+	/// the payee that it's the final hop and from intermediate hops how far away the payee is.
+	///
+	/// The offset of each path is that of a short random walk from the payee through the network
+	/// graph, and never exceeds [`max_total_cltv_expiry_delta`]. A value of 0 disables shadow
+	/// offsets.
+	///
+	/// Default value: [`DEFAULT_MAX_SHADOW_CLTV_EXPIRY_DELTA_OFFSET`]
+	///
+	/// [`max_total_cltv_expiry_delta`]: Self::max_total_cltv_expiry_delta
+	pub max_shadow_cltv_expiry_delta_offset: u32,
+
+	/// The maximum amount, in millionths of the payment amount, by which we randomly overpay the
+	/// payee, preventing intermediate hops from inferring the payment amount from round values.
+	///
+	/// The overpayment is added before the route is found, and thus is reflected in the route's
+	/// final hop. Note that the payee must accept overpayments for this to be used, which not all
+	/// implementations do for spontaneous payments.
+	///
+	/// Default value: 0
+	pub max_random_overpayment_ppm: u32,
+
+	/// The maximum total fee the route may pay, including any [`trampoline_hops`]' fees. If a
+	/// payment's failed paths are retried, this also limits the fees of the retry to what remains
+	/// after the fees of the payment's other paths.
+	///
+	/// Default value: `None`, i.e. no limit
+	///
+	/// [`trampoline_hops`]: Self::trampoline_hops
+	pub max_total_routing_fee: Option<RoutingFeeLimit>,
 }
 
 impl_writeable_tlv_based!(PaymentParameters, {
@@ -256,6 +312,11 @@ impl_writeable_tlv_based!(PaymentParameters, {
 	(5, max_channel_saturation_power_of_half, (default_value, 2)),
 	(6, expiry_time, option),
 	(7, previously_failed_channels, vec_type),
+	(9, max_channel_update_spam_score, option),
+	(11, trampoline_hops, vec_type),
+	(13, max_shadow_cltv_expiry_delta_offset, (default_value, DEFAULT_MAX_SHADOW_CLTV_EXPIRY_DELTA_OFFSET)),
+	(15, max_random_overpayment_ppm, (default_value, 0)),
+	(17, max_total_routing_fee, option),
 });
 
 impl PaymentParameters {
@@ -270,6 +331,11 @@ impl PaymentParameters {
 			max_path_count: DEFAULT_MAX_PATH_COUNT,
 			max_channel_saturation_power_of_half: 2,
 			previously_failed_channels: Vec::new(),
+			max_channel_update_spam_score: None,
+			trampoline_hops: Vec::new(),
+			max_shadow_cltv_expiry_delta_offset: DEFAULT_MAX_SHADOW_CLTV_EXPIRY_DELTA_OFFSET,
+			max_random_overpayment_ppm: 0,
+			max_total_routing_fee: None,
 		}
 	}
 
@@ -319,8 +385,96 @@ impl PaymentParameters {
 	pub fn with_max_channel_saturation_power_of_half(self, max_channel_saturation_power_of_half: u8) -> Self {
 		Self { max_channel_saturation_power_of_half, ..self }
 	}
+
+	/// Includes a limit for the `channel_update` spam score of nodes whose channels may be used.
+	///
+	/// (C-not exported) since bindings don't support move semantics
+	pub fn with_max_channel_update_spam_score(self, max_channel_update_spam_score: u32) -> Self {
+		Self { max_channel_update_spam_score: Some(max_channel_update_spam_score), ..self }
+	}
+
+	/// Includes trampoline nodes to route the payment through.
+	///
+	/// (C-not exported) since bindings don't support move semantics
+	pub fn with_trampoline_hops(self, trampoline_hops: Vec<TrampolineHop>) -> Self {
+		Self { trampoline_hops, ..self }
+	}
+
+	/// Includes a limit for the 'shadow' CLTV expiry delta added to the final hop of each path.
+	///
+	/// (C-not exported) since bindings don't support move semantics
+	pub fn with_max_shadow_cltv_expiry_delta_offset(self, max_shadow_cltv_expiry_delta_offset: u32) -> Self {
+		Self { max_shadow_cltv_expiry_delta_offset, ..self }
+	}
+
+	/// Includes a limit for the random overpayment of the payee, in millionths of the amount.
+	///
+	/// (C-not exported) since bindings don't support move semantics
+	pub fn with_max_random_overpayment_ppm(self, max_random_overpayment_ppm: u32) -> Self {
+		Self { max_random_overpayment_ppm, ..self }
+	}
+
+	/// Includes a limit for the total fee the route may pay.
+	///
+	/// (C-not exported) since bindings don't support move semantics
+	pub fn with_max_total_routing_fee(self, max_total_routing_fee: RoutingFeeLimit) -> Self {
+		Self { max_total_routing_fee: Some(max_total_routing_fee), ..self }
+	}
+}
+
+/// A limit on the total fee paid to route a payment, see
+/// [`PaymentParameters::max_total_routing_fee`].
+///
+/// The limit is the greater of a fixed amount and a share of the payment amount, allowing the
+/// fees of large payments to be bounded relative to their amount without preventing small
+/// payments, whose fees are dominated by the base fees of the channels used, from being routed.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct RoutingFeeLimit {
+	/// The maximum total fee, in millisatoshis, regardless of the payment amount.
+	pub base_msat: u64,
+	/// The maximum total fee, in millionths of the payment amount, where it's greater than
+	/// `base_msat`.
+	pub proportional_millionths: u32,
+}
+
+impl_writeable_tlv_based!(RoutingFeeLimit, {
+	(0, base_msat, required),
+	(2, proportional_millionths, required),
+});
+
+impl RoutingFeeLimit {
+	/// Creates a limit of `max_fee_msat`, regardless of the payment amount.
+	pub fn absolute(max_fee_msat: u64) -> Self {
+		Self { base_msat: max_fee_msat, proportional_millionths: 0 }
+	}
+
+	/// Gets the maximum total fee, in millisatoshis, to pay for routing `amount_msat`.
+	pub fn max_fee_msat(&self, amount_msat: u64) -> u64 {
+		let proportional_msat = amount_msat as u128 * self.proportional_millionths as u128 / 1_000_000;
+		cmp::max(self.base_msat, cmp::min(proportional_msat, u64::max_value() as u128) as u64)
+	}
 }
 
+/// A trampoline node which a payment is routed through, see
+/// [`PaymentParameters::trampoline_hops`].
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct TrampolineHop {
+	/// The node_id of the trampoline node.
+	pub pubkey: PublicKey,
+	/// The fee we pay the trampoline node to route the payment on to the next trampoline node or
+	/// the payee, covering the fees of any channels it uses.
+	pub fee_msat: u64,
+	/// The CLTV delta the trampoline node requires to route the payment on to the next trampoline
+	/// node or the payee, covering the CLTV deltas of any channels it uses.
+	pub cltv_expiry_delta: u32,
+}
+
+impl_writeable_tlv_based!(TrampolineHop, {
+	(0, pubkey, required),
+	(2, fee_msat, required),
+	(4, cltv_expiry_delta, required),
+});
+
 /// A list of hops along a payment path terminating with a channel to the recipient.
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct RouteHint(pub Vec<RouteHintHop>);
@@ -458,7 +612,7 @@ impl<'a> CandidateRouteHop<'a> {
 
 	fn htlc_minimum_msat(&self) -> u64 {
 		match self {
-			CandidateRouteHop::FirstHop { .. } => 0,
+			CandidateRouteHop::FirstHop { details } => details.counterparty.outbound_htlc_minimum_msat.unwrap_or(0),
 			CandidateRouteHop::PublicHop { info, .. } => info.direction().htlc_minimum_msat,
 			CandidateRouteHop::PrivateHop { hint } => hint.htlc_minimum_msat.unwrap_or(0),
 		}
@@ -480,7 +634,8 @@ impl<'a> CandidateRouteHop<'a> {
 				liquidity_msat: details.next_outbound_htlc_limit_msat,
 			},
 			CandidateRouteHop::PublicHop { info, .. } => info.effective_capacity(),
-			CandidateRouteHop::PrivateHop { .. } => EffectiveCapacity::Infinite,
+			CandidateRouteHop::PrivateHop { hint } => hint.htlc_maximum_msat
+				.map_or(EffectiveCapacity::Infinite, |amount_msat| EffectiveCapacity::HintMaxHTLC { amount_msat }),
 		}
 	}
 }
@@ -491,6 +646,7 @@ fn max_htlc_from_capacity(capacity: EffectiveCapacity, max_channel_saturation_po
 	match capacity {
 		EffectiveCapacity::ExactLiquidity { liquidity_msat } => liquidity_msat,
 		EffectiveCapacity::Infinite => u64::max_value(),
+		EffectiveCapacity::HintMaxHTLC { amount_msat } => amount_msat,
 		EffectiveCapacity::Unknown => EffectiveCapacity::Unknown.as_msat(),
 		EffectiveCapacity::MaximumHTLC { amount_msat } =>
 			amount_msat.checked_shr(saturation_shift).unwrap_or(0),
@@ -729,6 +885,8 @@ fn default_node_features() -> NodeFeatures {
 /// adjustments to the [`NetworkGraph`] and channel scores should be made prior to calling this
 /// function.
 ///
+/// To bound the time spent searching for a route, use [`find_route_with_budget`] instead.
+///
 /// # Panics
 ///
 /// Panics if first_hops contains channels without short_channel_ids;
@@ -751,12 +909,150 @@ where L::Target: Logger, GL::Target: Logger {
 	Ok(route)
 }
 
+#[cfg(not(feature = "no-std"))]
+type ConfiguredTime = std::time::Instant;
+#[cfg(feature = "no-std")]
+use util::time::{Eternity, Time};
+#[cfg(feature = "no-std")]
+type ConfiguredTime = Eternity;
+
+/// The error string returned by [`find_route_with_budget`] if its [`RouteSearchBudget`] ran out
+/// before a sufficient route was found.
+pub const ROUTE_SEARCH_BUDGET_EXHAUSTED_ERR: &str = "Route search budget exhausted before a sufficient route was found";
+
+/// How many nodes we visit between checks of a [`RouteSearchBudget`], as checking the time is
+/// relatively expensive.
+const ROUTE_SEARCH_BUDGET_CHECK_INTERVAL: u32 = 256;
+
+/// Limits on how long [`find_route_with_budget`] may spend searching for a route, allowing
+/// interactive applications to bound route-finding latency on large graphs.
+///
+/// Default::default() provides an unlimited budget.
+#[derive(Clone, Debug, Default)]
+pub struct RouteSearchBudget {
+	/// If set, the search is aborted once the flag is set, e.g. from another thread.
+	pub cancellation_flag: Option<Arc<AtomicBool>>,
+	/// If set, the search is aborted once it has been running for this long.
+	///
+	/// Ignored when built with the `no-std` feature, as we have no access to a clock.
+	pub max_duration: Option<Duration>,
+}
+
+impl RouteSearchBudget {
+	fn is_exhausted(&self, search_started_at: &ConfiguredTime) -> bool {
+		if let Some(flag) = &self.cancellation_flag {
+			if flag.load(Ordering::Acquire) { return true; }
+		}
+		match self.max_duration {
+			Some(max_duration) => search_started_at.elapsed() >= max_duration,
+			None => false,
+		}
+	}
+}
+
+/// Finds a route from us (payer) to the given target node (payee), as [`find_route`] does, but
+/// giving up once the given [`RouteSearchBudget`] runs out.
+///
+/// If the budget runs out after we've found paths which can carry the full payment amount, the
+/// best route made up of the paths found so far is returned, even though a cheaper one may exist.
+/// Otherwise, an error with [`ROUTE_SEARCH_BUDGET_EXHAUSTED_ERR`] as its `err` is returned.
+pub fn find_route_with_budget<L: Deref, GL: Deref, S: Score>(
+	our_node_pubkey: &PublicKey, route_params: &RouteParameters,
+	network_graph: &NetworkGraph<GL>, first_hops: Option<&[&ChannelDetails]>, logger: L,
+	scorer: &S, random_seed_bytes: &[u8; 32], budget: &RouteSearchBudget
+) -> Result<Route, LightningError>
+where L::Target: Logger, GL::Target: Logger {
+	let graph_lock = network_graph.read_only();
+	let mut route = get_route_with_budget(our_node_pubkey, &route_params.payment_params, &graph_lock,
+		first_hops, route_params.final_value_msat, route_params.final_cltv_expiry_delta, logger, scorer,
+		random_seed_bytes, budget)?;
+	add_random_cltv_offset(&mut route, &route_params.payment_params, &graph_lock, random_seed_bytes);
+	Ok(route)
+}
+
+/// Finds routes from us (payer) to each of the given target nodes (payees), as [`find_route`]
+/// does, but taking the [`NetworkGraph`]'s read lock only once for the whole batch.
+///
+/// This is significantly cheaper than calling [`find_route`] for each destination when computing
+/// many routes at once, e.g. for probing campaigns or batch payouts, as the graph can't change (and
+/// other threads needn't contend for the lock) between searches.
+///
+/// A distinct randomness seed is derived from `random_seed_bytes` for each route so that the
+/// routes' shadow CLTV offsets are uncorrelated.
+///
+/// Returns one result per entry in `route_params`, in the same order.
+pub fn find_routes_batch<L: Deref, GL: Deref, S: Score>(
+	our_node_pubkey: &PublicKey, route_params: &[&RouteParameters],
+	network_graph: &NetworkGraph<GL>, first_hops: Option<&[&ChannelDetails]>, logger: L,
+	scorer: &S, random_seed_bytes: &[u8; 32]
+) -> Vec<Result<Route, LightningError>>
+where L::Target: Logger, GL::Target: Logger {
+	let graph_lock = network_graph.read_only();
+	route_params.iter().enumerate().map(|(idx, params)| {
+		let mut seed_preimage = random_seed_bytes.to_vec();
+		seed_preimage.extend_from_slice(&(idx as u64).to_be_bytes());
+		let route_seed_bytes = Sha256::hash(&seed_preimage).into_inner();
+		let mut route = get_route(our_node_pubkey, &params.payment_params, &graph_lock, first_hops,
+			params.final_value_msat, params.final_cltv_expiry_delta, &*logger, scorer, &route_seed_bytes)?;
+		add_random_cltv_offset(&mut route, &params.payment_params, &graph_lock, &route_seed_bytes);
+		Ok(route)
+	}).collect()
+}
+
 pub(crate) fn get_route<L: Deref, S: Score>(
 	our_node_pubkey: &PublicKey, payment_params: &PaymentParameters, network_graph: &ReadOnlyNetworkGraph,
 	first_hops: Option<&[&ChannelDetails]>, final_value_msat: u64, final_cltv_expiry_delta: u32,
-	logger: L, scorer: &S, _random_seed_bytes: &[u8; 32]
+	logger: L, scorer: &S, random_seed_bytes: &[u8; 32]
 ) -> Result<Route, LightningError>
 where L::Target: Logger {
+	get_route_with_budget(our_node_pubkey, payment_params, network_graph, first_hops, final_value_msat,
+		final_cltv_expiry_delta, logger, scorer, random_seed_bytes, &RouteSearchBudget::default())
+}
+
+fn get_route_with_budget<L: Deref, S: Score>(
+	our_node_pubkey: &PublicKey, payment_params: &PaymentParameters, network_graph: &ReadOnlyNetworkGraph,
+	first_hops: Option<&[&ChannelDetails]>, final_value_msat: u64, final_cltv_expiry_delta: u32,
+	logger: L, scorer: &S, random_seed_bytes: &[u8; 32], budget: &RouteSearchBudget
+) -> Result<Route, LightningError>
+where L::Target: Logger {
+	if let Some(first_trampoline_hop) = payment_params.trampoline_hops.first() {
+		// Route to the first trampoline node, delivering enough to pay all trampoline nodes.
+		let mut trampoline_fee_msat = 0u64;
+		let mut trampoline_cltv_expiry_delta = 0u32;
+		for hop in payment_params.trampoline_hops.iter() {
+			trampoline_fee_msat = trampoline_fee_msat.checked_add(hop.fee_msat)
+				.ok_or_else(|| LightningError{err: "Trampoline fees overflowed".to_owned(), action: ErrorAction::IgnoreError})?;
+			trampoline_cltv_expiry_delta = trampoline_cltv_expiry_delta.checked_add(hop.cltv_expiry_delta)
+				.ok_or_else(|| LightningError{err: "Trampoline CLTV deltas overflowed".to_owned(), action: ErrorAction::IgnoreError})?;
+		}
+		if payment_params.trampoline_hops.iter().any(|hop| hop.pubkey == payment_params.payee_pubkey) {
+			return Err(LightningError{err: "Trampoline hops cannot include the payee".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		// The trampoline fees count towards the total fee limit, leaving the rest for the route to
+		// the first trampoline node.
+		let max_total_routing_fee = match payment_params.max_total_routing_fee {
+			Some(limit) => Some(RoutingFeeLimit::absolute(limit.max_fee_msat(final_value_msat)
+				.checked_sub(trampoline_fee_msat)
+				.ok_or_else(|| LightningError{err: "Trampoline fees exceed the maximum total routing fee".to_owned(), action: ErrorAction::IgnoreError})?)),
+			None => None,
+		};
+		let trampoline_params = PaymentParameters {
+			payee_pubkey: first_trampoline_hop.pubkey,
+			features: None,
+			route_hints: Vec::new(),
+			trampoline_hops: Vec::new(),
+			max_total_routing_fee,
+			..payment_params.clone()
+		};
+		let mut route = get_route_with_budget(our_node_pubkey, &trampoline_params, network_graph, first_hops,
+			final_value_msat.saturating_add(trampoline_fee_msat),
+			final_cltv_expiry_delta.saturating_add(trampoline_cltv_expiry_delta), logger, scorer,
+			random_seed_bytes, budget)?;
+		route.payment_params = Some(payment_params.clone());
+		return Ok(route);
+	}
+
+	let search_started_at = ConfiguredTime::now();
 	let payee_node_id = NodeId::from_pubkey(&payment_params.payee_pubkey);
 	let our_node_id = NodeId::from_pubkey(&our_node_pubkey);
 
@@ -772,6 +1068,16 @@ where L::Target: Logger {
 		return Err(LightningError{err: "Cannot send a payment of 0 msat".to_owned(), action: ErrorAction::IgnoreError});
 	}
 
+	// The fee limit applies to the amount we were asked to pay, ignoring any random overpayment.
+	let max_total_routing_fee_msat = payment_params.max_total_routing_fee
+		.map_or(u64::max_value(), |limit| limit.max_fee_msat(final_value_msat));
+
+	let final_value_msat = final_value_msat.saturating_add(
+		random_overpayment_msat(payment_params, final_value_msat, random_seed_bytes));
+	if final_value_msat > MAX_VALUE_MSAT {
+		return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
 	for route in payment_params.route_hints.iter() {
 		for hop in &route.0 {
 			if hop.src_node_id == payment_params.payee_pubkey {
@@ -1148,6 +1454,10 @@ where L::Target: Logger {
 							);
 							let path_penalty_msat = $next_hops_path_penalty_msat
 								.saturating_add(channel_penalty_msat);
+							// Do not consider candidates that would put the fees of this path alone
+							// over the total fee limit.
+							let exceeds_fee_limit = hop_use_fee_msat
+								.saturating_add($next_hops_fee_msat) > max_total_routing_fee_msat;
 							let new_graph_node = RouteGraphNode {
 								node_id: $src_node_id,
 								lowest_fee_to_peer_through_node: total_fee_msat,
@@ -1180,7 +1490,9 @@ where L::Target: Logger {
 							let new_cost = cmp::max(total_fee_msat, path_htlc_minimum_msat)
 								.saturating_add(path_penalty_msat);
 
-							if !old_entry.was_processed && new_cost < old_cost {
+							if exceeds_fee_limit {
+								// Path is too expensive, ignore it and move on.
+							} else if !old_entry.was_processed && new_cost < old_cost {
 								targets.push(new_graph_node);
 								old_entry.next_hops_fee_msat = $next_hops_fee_msat;
 								old_entry.hop_use_fee_msat = hop_use_fee_msat;
@@ -1278,7 +1590,9 @@ where L::Target: Logger {
 						if !chan.features.requires_unknown_bits() {
 							let (directed_channel, source) =
 								chan.as_directed_to(&$node_id).expect("inconsistent NetworkGraph");
-							if first_hops.is_none() || *source != our_node_id {
+							let source_is_spammy = payment_params.max_channel_update_spam_score
+								.map_or(false, |max_score| network_graph.channel_update_spam_score(source) > max_score);
+							if (first_hops.is_none() || *source != our_node_id) && !source_is_spammy {
 								if let Some(direction) = directed_channel.direction() {
 									if direction.enabled {
 										let candidate = CandidateRouteHop::PublicHop {
@@ -1302,9 +1616,17 @@ where L::Target: Logger {
 	}
 
 	let mut payment_paths = Vec::<PaymentPath>::new();
+	let mut nodes_visited_since_budget_check = 0;
+	let mut budget_exhausted = false;
 
 	// TODO: diversify by nodes (so that all paths aren't doomed if one node is offline).
 	'paths_collection: loop {
+		if budget.is_exhausted(&search_started_at) {
+			log_trace!(logger, "Route search budget exhausted with {} msat collected in paths.", already_collected_value_msat);
+			budget_exhausted = true;
+			break 'paths_collection;
+		}
+
 		// For every new path, start from scratch, except for used_channel_liquidities, which
 		// helps to avoid reusing previously selected paths in future iterations.
 		targets.clear();
@@ -1477,6 +1799,15 @@ where L::Target: Logger {
 		// paths_collection will be stopped because found_new_path==false.
 		// This is not necessarily a routing failure.
 		'path_construction: while let Some(RouteGraphNode { node_id, lowest_fee_to_node, total_cltv_delta, mut value_contribution_msat, path_htlc_minimum_msat, path_penalty_msat, path_length_to_node, .. }) = targets.pop() {
+			nodes_visited_since_budget_check += 1;
+			if nodes_visited_since_budget_check >= ROUTE_SEARCH_BUDGET_CHECK_INTERVAL {
+				nodes_visited_since_budget_check = 0;
+				if budget.is_exhausted(&search_started_at) {
+					log_trace!(logger, "Route search budget exhausted with {} msat collected in paths.", already_collected_value_msat);
+					budget_exhausted = true;
+					break 'paths_collection;
+				}
+			}
 
 			// Since we're going payee-to-payer, hitting our node as a target means we should stop
 			// traversing the graph and arrange the path out of what we found.
@@ -1647,6 +1978,10 @@ where L::Target: Logger {
 	}
 
 	// Step (5).
+	if budget_exhausted && already_collected_value_msat < final_value_msat {
+		return Err(LightningError{err: ROUTE_SEARCH_BUDGET_EXHAUSTED_ERR.to_owned(), action: ErrorAction::IgnoreError});
+	}
+
 	if payment_paths.len() == 0 {
 		return Err(LightningError{err: "Failed to find a path to the given destination".to_owned(), action: ErrorAction::IgnoreError});
 	}
@@ -1760,6 +2095,9 @@ where L::Target: Logger {
 		paths: selected_paths.into_iter().map(|path| path.into_iter().collect()).collect::<Result<Vec<_>, _>>()?,
 		payment_params: Some(payment_params.clone()),
 	};
+	if route.get_total_fees() > max_total_routing_fee_msat {
+		return Err(LightningError{err: format!("Failed to find a route with total fees within the limit of {} msat", max_total_routing_fee_msat), action: ErrorAction::IgnoreError});
+	}
 	log_info!(logger, "Got route to {}: {}", payment_params.payee_pubkey, log_route!(route));
 	Ok(route)
 }
@@ -1768,6 +2106,21 @@ where L::Target: Logger {
 // destination, if the remaining CLTV expiry delta exactly matches a feasible path in the network
 // graph. In order to improve privacy, this method obfuscates the CLTV expiry deltas along the
 // payment path by adding a randomized 'shadow route' offset to the final hop.
+/// Picks a random amount to overpay the payee by, up to
+/// [`PaymentParameters::max_random_overpayment_ppm`] of `final_value_msat`.
+fn random_overpayment_msat(payment_params: &PaymentParameters, final_value_msat: u64, random_seed_bytes: &[u8; 32]) -> u64 {
+	let max_overpayment_msat = (final_value_msat as u128 * payment_params.max_random_overpayment_ppm as u128 / 1_000_000) as u64;
+	if max_overpayment_msat == 0 { return 0; }
+
+	// Use a nonce distinct from the ones the shadow route's random walk starts from.
+	let mut nonce = [0u8; 12];
+	nonce.copy_from_slice(&payment_params.payee_pubkey.serialize()[1..13]);
+	let mut prng = ChaCha20::new(random_seed_bytes, &nonce);
+	let mut random_bytes = [0u8; 8];
+	prng.process_in_place(&mut random_bytes);
+	u64::from_be_bytes(random_bytes) % (max_overpayment_msat + 1)
+}
+
 fn add_random_cltv_offset(route: &mut Route, payment_params: &PaymentParameters,
 	network_graph: &ReadOnlyNetworkGraph, random_seed_bytes: &[u8; 32]
 ) {
@@ -1832,8 +2185,7 @@ fn add_random_cltv_offset(route: &mut Route, payment_params: &PaymentParameters,
 		}
 
 		// Limit the total offset to reduce the worst-case locked liquidity timevalue
-		const MAX_SHADOW_CLTV_EXPIRY_DELTA_OFFSET: u32 = 3*144;
-		shadow_ctlv_expiry_delta_offset = cmp::min(shadow_ctlv_expiry_delta_offset, MAX_SHADOW_CLTV_EXPIRY_DELTA_OFFSET);
+		shadow_ctlv_expiry_delta_offset = cmp::min(shadow_ctlv_expiry_delta_offset, payment_params.max_shadow_cltv_expiry_delta_offset);
 
 		// Limit the offset so we never exceed the max_total_cltv_expiry_delta. To improve plausibility,
 		// we choose the limit to be the largest possible multiple of MEDIAN_HOP_CLTV_EXPIRY_DELTA.
@@ -1933,9 +2285,9 @@ fn build_route_from_hops_internal<L: Deref>(
 #[cfg(test)]
 mod tests {
 	use routing::gossip::{NetworkGraph, P2PGossipSync, NodeId, EffectiveCapacity};
-	use routing::router::{get_route, build_route_from_hops_internal, add_random_cltv_offset, default_node_features,
-		PaymentParameters, Route, RouteHint, RouteHintHop, RouteHop, RoutingFees,
-		DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA, MAX_PATH_LENGTH_ESTIMATE};
+	use routing::router::{find_route, find_routes_batch, get_route, get_route_with_budget, build_route_from_hops_internal, add_random_cltv_offset,
+		default_node_features, PaymentParameters, Route, RouteHint, RouteHintHop, RouteHop, RouteParameters, RouteSearchBudget,
+		RoutingFeeLimit, RoutingFees, TrampolineHop, DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA, MAX_PATH_LENGTH_ESTIMATE, ROUTE_SEARCH_BUDGET_EXHAUSTED_ERR};
 	use routing::scoring::{ChannelUsage, Score, ProbabilisticScorer, ProbabilisticScoringParameters};
 	use chain::transaction::OutPoint;
 	use chain::keysinterface::KeysInterface;
@@ -1966,6 +2318,8 @@ mod tests {
 	use sync::{self, Arc};
 
 	use core::convert::TryInto;
+	use core::sync::atomic::{AtomicBool, Ordering};
+	use core::time::Duration;
 
 	fn get_channel_details(short_channel_id: Option<u64>, node_id: PublicKey,
 			features: InitFeatures, outbound_capacity_msat: u64) -> channelmanager::ChannelDetails {
@@ -1998,6 +2352,8 @@ mod tests {
 			inbound_htlc_minimum_msat: None,
 			inbound_htlc_maximum_msat: None,
 			config: None,
+			is_on_probation: false,
+			is_shutting_down: false,
 		}
 	}
 
@@ -2503,6 +2859,152 @@ mod tests {
 		assert_eq!(route.paths[0][1].channel_features.le_flags(), &id_to_feature_flags(4));
 	}
 
+	#[test]
+	fn trampoline_route_test() {
+		let (secp_ctx, network_graph, _, _, logger) = build_graph();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let trampoline_hops = vec![TrampolineHop { pubkey: nodes[2], fee_msat: 50, cltv_expiry_delta: 100 }];
+		let payment_params = PaymentParameters::from_node_id(nodes[5]).with_trampoline_hops(trampoline_hops);
+		let scorer = test_utils::TestScorer::with_penalty(0);
+		let keys_manager = test_utils::TestKeysInterface::new(&[0u8; 32], Network::Testnet);
+		let random_seed_bytes = keys_manager.get_secure_random_bytes();
+
+		// We only route to the trampoline node, paying it its fee and CLTV delta on top of the
+		// payee's.
+		let route = get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).unwrap();
+		assert_eq!(route.paths.len(), 1);
+		assert_eq!(route.paths[0].len(), 2);
+		assert_eq!(route.paths[0][1].pubkey, nodes[2]);
+		assert_eq!(route.paths[0][1].fee_msat, 150);
+		assert_eq!(route.paths[0][1].cltv_expiry_delta, 142);
+		assert_eq!(route.payment_params, Some(payment_params));
+
+		// The payee can't also be a trampoline node.
+		let payment_params = PaymentParameters::from_node_id(nodes[2])
+			.with_trampoline_hops(vec![TrampolineHop { pubkey: nodes[2], fee_msat: 50, cltv_expiry_delta: 100 }]);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes) {
+			assert_eq!(err, "Trampoline hops cannot include the payee");
+		} else { panic!(); };
+	}
+
+	#[test]
+	fn max_total_routing_fee_test() {
+		let (secp_ctx, network_graph, _, _, logger) = build_graph();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let scorer = test_utils::TestScorer::with_penalty(0);
+		let keys_manager = test_utils::TestKeysInterface::new(&[0u8; 32], Network::Testnet);
+		let random_seed_bytes = keys_manager.get_secure_random_bytes();
+
+		// The limit is the greater of its base and proportional parts.
+		let limit = RoutingFeeLimit { base_msat: 1_000, proportional_millionths: 10_000 };
+		assert_eq!(limit.max_fee_msat(10_000), 1_000);
+		assert_eq!(limit.max_fee_msat(1_000_000), 10_000);
+		assert_eq!(RoutingFeeLimit { base_msat: 0, proportional_millionths: u32::max_value() }.max_fee_msat(u64::max_value()), u64::max_value());
+
+		// The route to 2 via 1 pays 100 msat in fees, which is only allowed by limits of at least
+		// that much.
+		let payment_params = PaymentParameters::from_node_id(nodes[2]);
+		let route = get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).unwrap();
+		assert_eq!(route.get_total_fees(), 100);
+		for limit in [RoutingFeeLimit::absolute(100), RoutingFeeLimit { base_msat: 0, proportional_millionths: 1_000_000 },
+			RoutingFeeLimit { base_msat: 100, proportional_millionths: 1 }].iter()
+		{
+			let payment_params = payment_params.clone().with_max_total_routing_fee(*limit);
+			let limited_route = get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).unwrap();
+			assert_eq!(limited_route.paths, route.paths);
+		}
+		for limit in [RoutingFeeLimit::absolute(99), RoutingFeeLimit { base_msat: 99, proportional_millionths: 990_000 }].iter() {
+			let payment_params = payment_params.clone().with_max_total_routing_fee(*limit);
+			assert!(get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).is_err());
+		}
+
+		// Trampoline fees count towards the limit.
+		let payment_params = PaymentParameters::from_node_id(nodes[5])
+			.with_trampoline_hops(vec![TrampolineHop { pubkey: nodes[2], fee_msat: 50, cltv_expiry_delta: 100 }]);
+		let route = get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).unwrap();
+		let max_fee_msat = route.get_total_fees() + 50;
+		let limited_params = payment_params.clone().with_max_total_routing_fee(RoutingFeeLimit::absolute(max_fee_msat));
+		assert!(get_route(&our_id, &limited_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).is_ok());
+		let limited_params = payment_params.clone().with_max_total_routing_fee(RoutingFeeLimit::absolute(max_fee_msat - 1));
+		assert!(get_route(&our_id, &limited_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).is_err());
+		let limited_params = payment_params.with_max_total_routing_fee(RoutingFeeLimit::absolute(49));
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_route(&our_id, &limited_params, &network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes) {
+			assert_eq!(err, "Trampoline fees exceed the maximum total routing fee");
+		} else { panic!(); };
+	}
+
+	#[test]
+	fn route_search_budget_test() {
+		let (secp_ctx, network_graph, _, _, logger) = build_graph();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let payment_params = PaymentParameters::from_node_id(nodes[2]);
+		let scorer = test_utils::TestScorer::with_penalty(0);
+		let keys_manager = test_utils::TestKeysInterface::new(&[0u8; 32], Network::Testnet);
+		let random_seed_bytes = keys_manager.get_secure_random_bytes();
+
+		// An unlimited budget finds the same route as get_route.
+		let route = get_route_with_budget(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42,
+			Arc::clone(&logger), &scorer, &random_seed_bytes, &RouteSearchBudget::default()).unwrap();
+		assert!(route == get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42,
+			Arc::clone(&logger), &scorer, &random_seed_bytes).unwrap());
+
+		// A cancelled search returns a distinct error.
+		let cancellation_flag = Arc::new(AtomicBool::new(false));
+		let budget = RouteSearchBudget { cancellation_flag: Some(Arc::clone(&cancellation_flag)), max_duration: None };
+		assert!(get_route_with_budget(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42,
+			Arc::clone(&logger), &scorer, &random_seed_bytes, &budget).is_ok());
+		cancellation_flag.store(true, Ordering::Release);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_route_with_budget(&our_id, &payment_params,
+			&network_graph.read_only(), None, 100, 42, Arc::clone(&logger), &scorer, &random_seed_bytes, &budget) {
+			assert_eq!(err, ROUTE_SEARCH_BUDGET_EXHAUSTED_ERR);
+		} else { panic!(); }
+
+		// As does one which runs out of time.
+		#[cfg(not(feature = "no-std"))] {
+			let budget = RouteSearchBudget { cancellation_flag: None, max_duration: Some(Duration::from_secs(0)) };
+			let res = get_route_with_budget(&our_id, &payment_params, &network_graph.read_only(), None, 100, 42,
+				Arc::clone(&logger), &scorer, &random_seed_bytes, &budget);
+			if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = res {
+				assert_eq!(err, ROUTE_SEARCH_BUDGET_EXHAUSTED_ERR);
+			} else { panic!(); }
+		}
+	}
+
+	#[test]
+	fn batch_route_test() {
+		let (secp_ctx, network_graph, _, _, logger) = build_graph();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let scorer = test_utils::TestScorer::with_penalty(0);
+		let keys_manager = test_utils::TestKeysInterface::new(&[0u8; 32], Network::Testnet);
+		let random_seed_bytes = keys_manager.get_secure_random_bytes();
+
+		let unknown_node = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		let route_params = [nodes[2], nodes[4], unknown_node].iter().map(|node_id| RouteParameters {
+			payment_params: PaymentParameters::from_node_id(*node_id),
+			final_value_msat: 100,
+			final_cltv_expiry_delta: 42,
+		}).collect::<Vec<_>>();
+		let route_params_refs = route_params.iter().collect::<Vec<_>>();
+		let routes = find_routes_batch(&our_id, &route_params_refs, &network_graph, None,
+			Arc::clone(&logger), &scorer, &random_seed_bytes);
+		assert_eq!(routes.len(), 3);
+
+		// Each destination gets the same path it would have gotten on its own.
+		for (params, route) in route_params.iter().zip(routes.iter()).take(2) {
+			let route = route.as_ref().unwrap();
+			let single_route = find_route(&our_id, params, &network_graph, None, Arc::clone(&logger),
+				&scorer, &random_seed_bytes).unwrap();
+			assert_eq!(route.paths.len(), single_route.paths.len());
+			for (path, single_path) in route.paths.iter().zip(single_route.paths.iter()) {
+				assert_eq!(path.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(),
+					single_path.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>());
+			}
+		}
+
+		// An unreachable destination fails without affecting the rest of the batch.
+		assert!(routes[2].is_err());
+	}
+
 	#[test]
 	fn invalid_first_hop_test() {
 		let (secp_ctx, network_graph, _, _, logger) = build_graph();
@@ -3620,6 +4122,39 @@ mod tests {
 		assert!(do_unannounced_path_test(Some(21_000_000_0000_0000_000), 50000, 21_000_000_0000_0000_000, 21_000_000_0000_0000_000).is_err());
 	}
 
+	#[test]
+	fn unannounced_path_hint_htlc_maximum_test() {
+		// A last-hop hint's htlc_maximum_msat reflects the payee's inbound liquidity and thus must
+		// bound the amount we route over it, even if our first hop could carry more.
+		assert!(do_unannounced_path_test(Some(1_000_000), 0, 2_000_000, 1_000_001).is_err());
+		let route = do_unannounced_path_test(Some(1_000_000), 0, 2_000_000, 1_000_000).unwrap();
+		assert_eq!(route.get_total_amount(), 1_000_000);
+	}
+
+	#[test]
+	fn first_hop_htlc_minimum_test() {
+		// Our counterparty's htlc_minimum_msat is known exactly for our own channels, so we
+		// shouldn't build a path which sends less than it over a first hop.
+		let secp_ctx = Secp256k1::new();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let genesis_hash = genesis_block(Network::Testnet).header.block_hash();
+		let logger = test_utils::TestLogger::new();
+		let network_graph = NetworkGraph::new(genesis_hash, &logger);
+		let scorer = test_utils::TestScorer::with_penalty(0);
+		let keys_manager = test_utils::TestKeysInterface::new(&[0u8; 32], Network::Testnet);
+		let random_seed_bytes = keys_manager.get_secure_random_bytes();
+		let payment_params = PaymentParameters::from_node_id(nodes[0]);
+
+		let mut first_hop = get_channel_details(Some(42), nodes[0], InitFeatures::known(), 1_000_000);
+		first_hop.counterparty.outbound_htlc_minimum_msat = Some(10_000);
+		let first_hops = vec![&first_hop];
+		assert!(get_route(&our_id, &payment_params, &network_graph.read_only(), Some(&first_hops),
+			9_999, 42, &logger, &scorer, &random_seed_bytes).is_err());
+		let route = get_route(&our_id, &payment_params, &network_graph.read_only(), Some(&first_hops),
+			10_000, 42, &logger, &scorer, &random_seed_bytes).unwrap();
+		assert_eq!(route.paths[0][0].short_channel_id, 42);
+	}
+
 	#[test]
 	fn available_amount_while_routing_test() {
 		// Tests whether we choose the correct available channel amount while routing.
@@ -5631,10 +6166,50 @@ mod tests {
 		// Check that no offset is added when we restrict the max_total_cltv_expiry_delta
 		let mut route_limited = route.clone();
 		let limited_max_total_cltv_expiry_delta = cltv_expiry_deltas_before.iter().sum();
-		let limited_payment_params = payment_params.with_max_total_cltv_expiry_delta(limited_max_total_cltv_expiry_delta);
+		let limited_payment_params = payment_params.clone().with_max_total_cltv_expiry_delta(limited_max_total_cltv_expiry_delta);
 		add_random_cltv_offset(&mut route_limited, &limited_payment_params, &network_graph.read_only(), &random_seed_bytes);
 		let cltv_expiry_deltas_limited = route_limited.paths[0].iter().map(|h| h.cltv_expiry_delta).collect::<Vec<u32>>();
 		assert_eq!(cltv_expiry_deltas_before, cltv_expiry_deltas_limited);
+
+		// Check that the offset respects the payment's own limit, and that it may be disabled
+		let mut route_small_offset = route.clone();
+		let small_offset_payment_params = payment_params.clone().with_max_shadow_cltv_expiry_delta_offset(10);
+		add_random_cltv_offset(&mut route_small_offset, &small_offset_payment_params, &network_graph.read_only(), &random_seed_bytes);
+		let cltv_expiry_deltas_small_offset = route_small_offset.paths[0].iter().map(|h| h.cltv_expiry_delta).collect::<Vec<u32>>();
+		assert_eq!(*cltv_expiry_deltas_small_offset.last().unwrap(), cltv_expiry_deltas_before.last().unwrap() + 10);
+
+		let mut route_no_offset = route.clone();
+		let no_offset_payment_params = payment_params.with_max_shadow_cltv_expiry_delta_offset(0);
+		add_random_cltv_offset(&mut route_no_offset, &no_offset_payment_params, &network_graph.read_only(), &random_seed_bytes);
+		let cltv_expiry_deltas_no_offset = route_no_offset.paths[0].iter().map(|h| h.cltv_expiry_delta).collect::<Vec<u32>>();
+		assert_eq!(cltv_expiry_deltas_before, cltv_expiry_deltas_no_offset);
+	}
+
+	#[test]
+	fn adds_limited_random_overpayment() {
+		let (secp_ctx, network_graph, _, _, logger) = build_graph();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let scorer = test_utils::TestScorer::with_penalty(0);
+
+		// By default, we deliver exactly the requested amount.
+		let payment_params = PaymentParameters::from_node_id(nodes[2]);
+		let keys_manager = test_utils::TestKeysInterface::new(&[0u8; 32], Network::Testnet);
+		let random_seed_bytes = keys_manager.get_secure_random_bytes();
+		let route = get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100_000, 42, Arc::clone(&logger), &scorer, &random_seed_bytes).unwrap();
+		assert_eq!(route.paths[0].last().unwrap().fee_msat, 100_000);
+
+		// With randomization enabled, we overpay by up to the given share of the amount, picking a
+		// different overpayment for different random seeds.
+		let payment_params = payment_params.with_max_random_overpayment_ppm(10_000);
+		let mut overpayments = HashSet::new();
+		for seed in 0..8u8 {
+			let route = get_route(&our_id, &payment_params, &network_graph.read_only(), None, 100_000, 42, Arc::clone(&logger), &scorer, &[seed; 32]).unwrap();
+			assert_eq!(route.paths.len(), 1);
+			let delivered_msat = route.paths[0].last().unwrap().fee_msat;
+			assert!((100_000..=101_000).contains(&delivered_msat));
+			overpayments.insert(delivered_msat);
+		}
+		assert!(overpayments.len() > 1);
 	}
 
 	#[test]
@@ -5985,6 +6560,8 @@ mod benches {
 			inbound_htlc_minimum_msat: None,
 			inbound_htlc_maximum_msat: None,
 			config: None,
+			is_on_probation: false,
+			is_shutting_down: false,
 		}
 	}
 
@@ -12,6 +12,9 @@
 //! [`ProbabilisticScorer`] may be given to [`find_route`] to score payment channels during path
 //! finding when a custom [`Score`] implementation is not needed.
 //!
+//! Existing scorers may be combined using [`WeightedScorer`], customized with manual per-channel
+//! overrides using [`OverrideScorer`], or compared against an alternative using [`ShadowScorer`].
+//!
 //! # Example
 //!
 //! ```
@@ -277,6 +280,331 @@ impl ReadableArgs<u64> for FixedPenaltyScorer {
 	}
 }
 
+/// [`Score`] implementation which sums the penalties of two other [`Score`]s, each scaled by a
+/// weight.
+///
+/// Updates are passed through to both scorers. Weights are expressed in thousandths, so a weight
+/// of 1000 leaves the scorer's penalty unchanged. To combine more than two scorers, nest
+/// `WeightedScorer`s.
+pub struct WeightedScorer<A: Score, B: Score> {
+	first: A,
+	first_weight: u64,
+	second: B,
+	second_weight: u64,
+}
+
+impl<A: Score, B: Score> WeightedScorer<A, B> {
+	/// Creates a new scorer returning `first`'s penalty times `first_weight / 1000` plus
+	/// `second`'s penalty times `second_weight / 1000`.
+	pub fn new(first: A, first_weight: u64, second: B, second_weight: u64) -> Self {
+		Self { first, first_weight, second, second_weight }
+	}
+
+	/// Gets the first of the combined scorers.
+	pub fn first(&self) -> &A { &self.first }
+
+	/// Gets the second of the combined scorers.
+	pub fn second(&self) -> &B { &self.second }
+}
+
+impl<A: Score, B: Score> Score for WeightedScorer<A, B> {
+	fn channel_penalty_msat(
+		&self, short_channel_id: u64, source: &NodeId, target: &NodeId, usage: ChannelUsage
+	) -> u64 {
+		let first_penalty_msat = self.first.channel_penalty_msat(short_channel_id, source, target, usage);
+		let second_penalty_msat = self.second.channel_penalty_msat(short_channel_id, source, target, usage);
+		(first_penalty_msat.saturating_mul(self.first_weight) / 1000)
+			.saturating_add(second_penalty_msat.saturating_mul(self.second_weight) / 1000)
+	}
+
+	fn payment_path_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.first.payment_path_failed(path, short_channel_id);
+		self.second.payment_path_failed(path, short_channel_id);
+	}
+
+	fn payment_path_successful(&mut self, path: &[&RouteHop]) {
+		self.first.payment_path_successful(path);
+		self.second.payment_path_successful(path);
+	}
+
+	fn probe_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.first.probe_failed(path, short_channel_id);
+		self.second.probe_failed(path, short_channel_id);
+	}
+
+	fn probe_successful(&mut self, path: &[&RouteHop]) {
+		self.first.probe_successful(path);
+		self.second.probe_successful(path);
+	}
+}
+
+impl<A: Score + Writeable, B: Score + Writeable> Writeable for WeightedScorer<A, B> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		self.first.write(w)?;
+		self.second.write(w)?;
+		write_tlv_fields!(w, {
+			(0, self.first_weight, required),
+			(2, self.second_weight, required),
+		});
+		Ok(())
+	}
+}
+
+impl<A: Score, B: Score, AA, BA> ReadableArgs<(AA, BA)> for WeightedScorer<A, B>
+where A: ReadableArgs<AA>, B: ReadableArgs<BA> {
+	fn read<R: Read>(r: &mut R, args: (AA, BA)) -> Result<Self, DecodeError> {
+		let first = A::read(r, args.0)?;
+		let second = B::read(r, args.1)?;
+		let mut first_weight = 0;
+		let mut second_weight = 0;
+		read_tlv_fields!(r, {
+			(0, first_weight, required),
+			(2, second_weight, required),
+		});
+		Ok(Self { first, first_weight, second, second_weight })
+	}
+}
+
+/// A manual override of a channel's penalty in an [`OverrideScorer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelPenaltyOverride {
+	/// Always use the given penalty for the channel, ignoring the base scorer. A penalty of 0 pins
+	/// the channel as preferred.
+	Pinned(u64),
+	/// Add the given penalty to the one given by the base scorer.
+	Additional(u64),
+}
+
+impl_writeable_tlv_based_enum!(ChannelPenaltyOverride,
+	;
+	(0, Pinned),
+	(1, Additional),
+);
+
+/// [`Score`] implementation which applies manual per-channel overrides on top of a base
+/// [`Score`].
+///
+/// Updates are always passed through to the base scorer, so that it keeps learning about
+/// overridden channels and is up-to-date if the override is removed.
+pub struct OverrideScorer<S: Score> {
+	base: S,
+	overrides: HashMap<u64, ChannelPenaltyOverride>,
+}
+
+impl<S: Score> OverrideScorer<S> {
+	/// Creates a new scorer with no overrides on top of `base`.
+	pub fn new(base: S) -> Self {
+		Self { base, overrides: HashMap::new() }
+	}
+
+	/// Gets the base scorer.
+	pub fn base(&self) -> &S { &self.base }
+
+	/// Overrides the penalty of the channel with the given short channel id, replacing any
+	/// previous override for it.
+	pub fn set_override(&mut self, short_channel_id: u64, penalty_override: ChannelPenaltyOverride) {
+		self.overrides.insert(short_channel_id, penalty_override);
+	}
+
+	/// Removes any override for the channel with the given short channel id, returning it.
+	pub fn remove_override(&mut self, short_channel_id: u64) -> Option<ChannelPenaltyOverride> {
+		self.overrides.remove(&short_channel_id)
+	}
+
+	/// Gets the override for the channel with the given short channel id, if any.
+	pub fn get_override(&self, short_channel_id: u64) -> Option<ChannelPenaltyOverride> {
+		self.overrides.get(&short_channel_id).cloned()
+	}
+}
+
+impl<S: Score> Score for OverrideScorer<S> {
+	fn channel_penalty_msat(
+		&self, short_channel_id: u64, source: &NodeId, target: &NodeId, usage: ChannelUsage
+	) -> u64 {
+		match self.overrides.get(&short_channel_id) {
+			Some(ChannelPenaltyOverride::Pinned(penalty_msat)) => *penalty_msat,
+			Some(ChannelPenaltyOverride::Additional(penalty_msat)) => self.base
+				.channel_penalty_msat(short_channel_id, source, target, usage)
+				.saturating_add(*penalty_msat),
+			None => self.base.channel_penalty_msat(short_channel_id, source, target, usage),
+		}
+	}
+
+	fn payment_path_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.base.payment_path_failed(path, short_channel_id)
+	}
+
+	fn payment_path_successful(&mut self, path: &[&RouteHop]) {
+		self.base.payment_path_successful(path)
+	}
+
+	fn probe_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.base.probe_failed(path, short_channel_id)
+	}
+
+	fn probe_successful(&mut self, path: &[&RouteHop]) {
+		self.base.probe_successful(path)
+	}
+}
+
+impl<S: Score + Writeable> Writeable for OverrideScorer<S> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		self.base.write(w)?;
+		write_tlv_fields!(w, {
+			(0, self.overrides, required),
+		});
+		Ok(())
+	}
+}
+
+impl<S: Score + ReadableArgs<A>, A> ReadableArgs<A> for OverrideScorer<S> {
+	fn read<R: Read>(r: &mut R, args: A) -> Result<Self, DecodeError> {
+		let base = S::read(r, args)?;
+		let mut overrides = HashMap::new();
+		read_tlv_fields!(r, {
+			(0, overrides, required),
+		});
+		Ok(Self { base, overrides })
+	}
+}
+
+/// The maximum number of [`ShadowPathComparison`]s kept by a [`ShadowScorer`].
+pub const MAX_SHADOW_PATH_COMPARISONS: usize = 1000;
+
+/// How a [`ShadowScorer`]'s primary and shadow scorers scored a path whose outcome we learned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShadowPathComparison {
+	/// The short channel ids of the scored channels along the path.
+	pub short_channel_ids: Vec<u64>,
+	/// Whether the payment or probe along the path succeeded.
+	pub succeeded: bool,
+	/// The sum of the primary scorer's penalties for the path.
+	pub primary_penalty_msat: u64,
+	/// The sum of the shadow scorer's penalties for the path.
+	pub shadow_penalty_msat: u64,
+}
+
+/// [`Score`] implementation which routes using a primary [`Score`] while keeping an alternative,
+/// shadow [`Score`] up-to-date alongside it, allowing the two to be compared before switching.
+///
+/// The shadow scorer receives the same updates as the primary, but never affects routing. For
+/// each path we learn the outcome of, a [`ShadowPathComparison`] is recorded with both scorers'
+/// penalties for the path, as they stood before the update. To see which route the shadow scorer
+/// would have chosen, pass [`ShadowScorer::shadow`] to [`find_route`].
+///
+/// As paths don't include our own node, the first hop of each path is not scored. Channel
+/// capacities are also unknown at that point, so [`EffectiveCapacity::Unknown`] is used.
+///
+/// [`find_route`]: crate::routing::router::find_route
+pub struct ShadowScorer<P: Score, S: Score> {
+	primary: P,
+	shadow: S,
+	comparisons: VecDeque<ShadowPathComparison>,
+}
+
+impl<P: Score, S: Score> ShadowScorer<P, S> {
+	/// Creates a new scorer routing with `primary` and shadowing it with `shadow`.
+	pub fn new(primary: P, shadow: S) -> Self {
+		Self { primary, shadow, comparisons: VecDeque::new() }
+	}
+
+	/// Gets the primary scorer.
+	pub fn primary(&self) -> &P { &self.primary }
+
+	/// Gets the shadow scorer.
+	pub fn shadow(&self) -> &S { &self.shadow }
+
+	/// Gets the recorded comparisons, oldest first. At most [`MAX_SHADOW_PATH_COMPARISONS`] are
+	/// kept.
+	pub fn comparisons(&self) -> &VecDeque<ShadowPathComparison> { &self.comparisons }
+
+	/// Clears the recorded comparisons.
+	pub fn clear_comparisons(&mut self) {
+		self.comparisons.clear();
+	}
+
+	fn record_comparison(&mut self, path: &[&RouteHop], succeeded: bool) {
+		let mut comparison = ShadowPathComparison {
+			short_channel_ids: Vec::with_capacity(path.len()),
+			succeeded,
+			primary_penalty_msat: 0,
+			shadow_penalty_msat: 0,
+		};
+		let mut amount_msat: u64 = path.iter().map(|hop| hop.fee_msat).sum();
+		for (prev_hop, hop) in path.iter().zip(path.iter().skip(1)) {
+			amount_msat = amount_msat.saturating_sub(prev_hop.fee_msat);
+			let source = NodeId::from_pubkey(&prev_hop.pubkey);
+			let target = NodeId::from_pubkey(&hop.pubkey);
+			let usage = ChannelUsage {
+				amount_msat,
+				inflight_htlc_msat: 0,
+				effective_capacity: EffectiveCapacity::Unknown,
+			};
+			comparison.short_channel_ids.push(hop.short_channel_id);
+			comparison.primary_penalty_msat = comparison.primary_penalty_msat.saturating_add(
+				self.primary.channel_penalty_msat(hop.short_channel_id, &source, &target, usage));
+			comparison.shadow_penalty_msat = comparison.shadow_penalty_msat.saturating_add(
+				self.shadow.channel_penalty_msat(hop.short_channel_id, &source, &target, usage));
+		}
+		if self.comparisons.len() >= MAX_SHADOW_PATH_COMPARISONS {
+			self.comparisons.pop_front();
+		}
+		self.comparisons.push_back(comparison);
+	}
+}
+
+impl<P: Score, S: Score> Score for ShadowScorer<P, S> {
+	fn channel_penalty_msat(
+		&self, short_channel_id: u64, source: &NodeId, target: &NodeId, usage: ChannelUsage
+	) -> u64 {
+		self.primary.channel_penalty_msat(short_channel_id, source, target, usage)
+	}
+
+	fn payment_path_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.record_comparison(path, false);
+		self.primary.payment_path_failed(path, short_channel_id);
+		self.shadow.payment_path_failed(path, short_channel_id);
+	}
+
+	fn payment_path_successful(&mut self, path: &[&RouteHop]) {
+		self.record_comparison(path, true);
+		self.primary.payment_path_successful(path);
+		self.shadow.payment_path_successful(path);
+	}
+
+	fn probe_failed(&mut self, path: &[&RouteHop], short_channel_id: u64) {
+		self.record_comparison(path, false);
+		self.primary.probe_failed(path, short_channel_id);
+		self.shadow.probe_failed(path, short_channel_id);
+	}
+
+	fn probe_successful(&mut self, path: &[&RouteHop]) {
+		self.record_comparison(path, true);
+		self.primary.probe_successful(path);
+		self.shadow.probe_successful(path);
+	}
+}
+
+impl<P: Score + Writeable, S: Score + Writeable> Writeable for ShadowScorer<P, S> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		// The recorded comparisons are only meant for live analysis and aren't persisted.
+		self.primary.write(w)?;
+		self.shadow.write(w)?;
+		write_tlv_fields!(w, {});
+		Ok(())
+	}
+}
+
+impl<P: Score, S: Score, PA, SA> ReadableArgs<(PA, SA)> for ShadowScorer<P, S>
+where P: ReadableArgs<PA>, S: ReadableArgs<SA> {
+	fn read<R: Read>(r: &mut R, args: (PA, SA)) -> Result<Self, DecodeError> {
+		let primary = P::read(r, args.0)?;
+		let shadow = S::read(r, args.1)?;
+		read_tlv_fields!(r, {});
+		Ok(Self::new(primary, shadow))
+	}
+}
+
 #[cfg(not(feature = "no-std"))]
 type ConfiguredTime = std::time::Instant;
 #[cfg(feature = "no-std")]
@@ -546,6 +874,26 @@ impl<G: Deref<Target = NetworkGraph<L>>, L: Deref, T: Time> ProbabilisticScorerU
 	pub fn clear_manual_penalties(&mut self) {
 		self.params.manual_node_penalties = HashMap::new();
 	}
+
+	/// Decays our knowledge of channel liquidity by `elapsed`, for time which passed without `T`
+	/// advancing, e.g. while a mobile app was suspended in the background, or at all, e.g. with
+	/// [`Eternity`].
+	///
+	/// Only whole [`liquidity_offset_half_life`]s are decayed, so this should be called with the
+	/// entire time passed rather than in small increments.
+	///
+	/// [`Eternity`]: crate::util::time::Eternity
+	/// [`liquidity_offset_half_life`]: ProbabilisticScoringParameters::liquidity_offset_half_life
+	pub fn time_passed(&mut self, elapsed: Duration) {
+		let decays = elapsed.as_secs().checked_div(self.params.liquidity_offset_half_life.as_secs())
+			.map(|decays| core::cmp::min(decays, 64) as u32)
+			.unwrap_or(0);
+		if decays == 0 { return; }
+		for liquidity in self.channel_liquidities.values_mut() {
+			liquidity.min_liquidity_offset_msat = liquidity.min_liquidity_offset_msat.checked_shr(decays).unwrap_or(0);
+			liquidity.max_liquidity_offset_msat = liquidity.max_liquidity_offset_msat.checked_shr(decays).unwrap_or(0);
+		}
+	}
 }
 
 impl ProbabilisticScoringParameters {
@@ -1276,7 +1624,9 @@ impl<T: Time> Readable for ChannelLiquidity<T> {
 
 #[cfg(test)]
 mod tests {
-	use super::{ChannelLiquidity, ProbabilisticScoringParameters, ProbabilisticScorerUsingTime};
+	use super::{ChannelLiquidity, ChannelPenaltyOverride, FixedPenaltyScorer, MAX_SHADOW_PATH_COMPARISONS,
+		OverrideScorer, ProbabilisticScoringParameters, ProbabilisticScorerUsingTime, ShadowPathComparison,
+		ShadowScorer, WeightedScorer};
 	use util::time::Time;
 	use util::time::tests::SinceEpoch;
 
@@ -1956,6 +2306,38 @@ mod tests {
 		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage), 125);
 	}
 
+	#[test]
+	fn decays_liquidity_bounds_by_time_passed() {
+		let logger = TestLogger::new();
+		let network_graph = network_graph(&logger);
+		let params = ProbabilisticScoringParameters {
+			liquidity_penalty_multiplier_msat: 1_000,
+			liquidity_offset_half_life: Duration::from_secs(10),
+			..ProbabilisticScoringParameters::zero_penalty()
+		};
+		let mut scorer = ProbabilisticScorer::new(params, &network_graph, &logger);
+		let source = source_node_id();
+		let target = target_node_id();
+		let usage = ChannelUsage {
+			amount_msat: 256,
+			inflight_htlc_msat: 0,
+			effective_capacity: EffectiveCapacity::Total { capacity_msat: 1_024, htlc_maximum_msat: Some(1_000) },
+		};
+		scorer.payment_path_failed(&payment_path_for_amount(512).iter().collect::<Vec<_>>(), 42);
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage), 281);
+
+		// Less than a half life passing doesn't decay anything.
+		scorer.time_passed(Duration::from_secs(9));
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage), 281);
+
+		// A half life passing decays as much as the clock advancing by one would have.
+		scorer.time_passed(Duration::from_secs(10));
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage), 173);
+
+		scorer.time_passed(Duration::from_secs(10 * 64));
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage), 125);
+	}
+
 	#[test]
 	fn restricts_liquidity_bounds_after_decay() {
 		let logger = TestLogger::new();
@@ -2311,4 +2693,76 @@ mod tests {
 		};
 		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage), 0);
 	}
+
+	// Scorer combinator tests
+
+	fn usage_for_amount(amount_msat: u64) -> ChannelUsage {
+		ChannelUsage { amount_msat, inflight_htlc_msat: 0, effective_capacity: EffectiveCapacity::Unknown }
+	}
+
+	#[test]
+	fn weighted_scorer_sums_weighted_penalties() {
+		let scorer = WeightedScorer::new(
+			FixedPenaltyScorer::with_penalty(100), 500, FixedPenaltyScorer::with_penalty(1_000), 2_000);
+		let source = source_node_id();
+		let target = target_node_id();
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage_for_amount(1_000)), 2_050);
+
+		let read_scorer: WeightedScorer<FixedPenaltyScorer, FixedPenaltyScorer> =
+			ReadableArgs::read(&mut io::Cursor::new(&scorer.encode()), (100, 1_000)).unwrap();
+		assert_eq!(read_scorer.channel_penalty_msat(42, &source, &target, usage_for_amount(1_000)), 2_050);
+
+		let saturating = WeightedScorer::new(
+			FixedPenaltyScorer::with_penalty(u64::max_value()), 1_000, FixedPenaltyScorer::with_penalty(1), 1_000);
+		assert_eq!(saturating.channel_penalty_msat(42, &source, &target, usage_for_amount(1_000)), u64::max_value() / 1_000 + 1);
+	}
+
+	#[test]
+	fn override_scorer_applies_overrides() {
+		let mut scorer = OverrideScorer::new(FixedPenaltyScorer::with_penalty(100));
+		let source = source_node_id();
+		let target = target_node_id();
+		scorer.set_override(42, ChannelPenaltyOverride::Pinned(0));
+		scorer.set_override(43, ChannelPenaltyOverride::Additional(50));
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage_for_amount(1_000)), 0);
+		assert_eq!(scorer.channel_penalty_msat(43, &source, &target, usage_for_amount(1_000)), 150);
+		assert_eq!(scorer.channel_penalty_msat(44, &source, &target, usage_for_amount(1_000)), 100);
+
+		let read_scorer: OverrideScorer<FixedPenaltyScorer> =
+			ReadableArgs::read(&mut io::Cursor::new(&scorer.encode()), 100).unwrap();
+		assert_eq!(read_scorer.get_override(42), Some(ChannelPenaltyOverride::Pinned(0)));
+		assert_eq!(read_scorer.get_override(43), Some(ChannelPenaltyOverride::Additional(50)));
+
+		assert_eq!(scorer.remove_override(42), Some(ChannelPenaltyOverride::Pinned(0)));
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage_for_amount(1_000)), 100);
+	}
+
+	#[test]
+	fn shadow_scorer_records_comparisons() {
+		let mut scorer = ShadowScorer::new(FixedPenaltyScorer::with_penalty(100), FixedPenaltyScorer::with_penalty(1_000));
+		let source = source_node_id();
+		let target = target_node_id();
+		assert_eq!(scorer.channel_penalty_msat(42, &source, &target, usage_for_amount(1_000)), 100);
+
+		let path = payment_path_for_amount(500);
+		scorer.payment_path_failed(&path.iter().collect::<Vec<_>>(), 43);
+		scorer.probe_successful(&path.iter().collect::<Vec<_>>());
+		assert_eq!(scorer.comparisons().len(), 2);
+		assert_eq!(scorer.comparisons()[0], ShadowPathComparison {
+			short_channel_ids: vec![42, 43],
+			succeeded: false,
+			primary_penalty_msat: 200,
+			shadow_penalty_msat: 2_000,
+		});
+		assert!(scorer.comparisons()[1].succeeded);
+
+		for _ in 0..MAX_SHADOW_PATH_COMPARISONS {
+			scorer.payment_path_successful(&path.iter().collect::<Vec<_>>());
+		}
+		assert_eq!(scorer.comparisons().len(), MAX_SHADOW_PATH_COMPARISONS);
+		assert!(scorer.comparisons()[0].succeeded);
+
+		scorer.clear_comparisons();
+		assert!(scorer.comparisons().is_empty());
+	}
 }
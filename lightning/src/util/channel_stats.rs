@@ -0,0 +1,373 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Long-term performance statistics for each of our channels.
+//!
+//! A [`ChannelPerformanceTracker`] records how often each channel's peer is online, how many of
+//! the HTLCs we forward over the channel succeed, and how long HTLCs take to resolve. Operators can
+//! query it via [`ChannelPerformanceTracker::list_channel_stats`] when deciding which channels to
+//! close or rebalance, and persist it via its [`Writeable`] implementation (e.g. with a
+//! [`KVStorePersister`]) so that statistics accumulate across restarts.
+//!
+//! [`KVStorePersister`]: crate::util::persist::KVStorePersister
+
+use bitcoin::secp256k1::PublicKey;
+
+use ln::channelmanager::ChannelDetails;
+use ln::msgs::DecodeError;
+use util::events::{Event, HTLCDestination};
+use util::ser::{Readable, Writeable, Writer};
+
+use io;
+use prelude::*;
+use sync::Mutex;
+use core::time::Duration;
+
+/// The number of most recent [`DowntimeWindow`]s kept for each channel. The total downtime over
+/// the lifetime of the channel is kept regardless.
+pub const MAX_DOWNTIME_WINDOWS: usize = 64;
+
+/// A period during which a channel's peer was disconnected from us, in seconds since the UNIX
+/// epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DowntimeWindow {
+	/// When the peer disconnected.
+	pub start: u64,
+	/// When the peer reconnected, or `None` if it is still disconnected.
+	pub end: Option<u64>,
+}
+
+impl_writeable_tlv_based!(DowntimeWindow, {
+	(0, start, required),
+	(2, end, option),
+});
+
+/// The statistics tracked for a single channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelPerformanceStats {
+	/// The channel's id.
+	pub channel_id: [u8; 32],
+	/// The node id of the channel's counterparty.
+	pub counterparty_node_id: PublicKey,
+	/// When we started tracking the channel, in seconds since the UNIX epoch.
+	pub tracked_since: u64,
+	/// The total number of seconds the peer has been disconnected over all completed downtime
+	/// windows, including those which have since been dropped from
+	/// [`ChannelPerformanceStats::downtime_windows`].
+	pub total_downtime_secs: u64,
+	/// Up to [`MAX_DOWNTIME_WINDOWS`] of the most recent periods the peer was disconnected, oldest
+	/// first.
+	pub downtime_windows: Vec<DowntimeWindow>,
+	/// The number of HTLCs we successfully forwarded over this channel.
+	pub forwards_succeeded: u64,
+	/// The number of HTLCs we failed to forward over this channel.
+	pub forwards_failed: u64,
+	/// The number of HTLC resolutions reported via
+	/// [`ChannelPerformanceTracker::record_htlc_resolution`].
+	pub resolved_htlcs: u64,
+	/// The sum of all HTLC resolution latencies reported, in milliseconds.
+	pub total_resolution_latency_ms: u64,
+}
+
+impl_writeable_tlv_based!(ChannelPerformanceStats, {
+	(0, channel_id, required),
+	(2, counterparty_node_id, required),
+	(4, tracked_since, required),
+	(6, total_downtime_secs, required),
+	(8, downtime_windows, vec_type),
+	(10, forwards_succeeded, required),
+	(12, forwards_failed, required),
+	(14, resolved_htlcs, required),
+	(16, total_resolution_latency_ms, required),
+});
+
+impl ChannelPerformanceStats {
+	fn new(channel_id: [u8; 32], counterparty_node_id: PublicKey, peer_connected: bool, now: u64) -> Self {
+		let mut stats = ChannelPerformanceStats {
+			channel_id,
+			counterparty_node_id,
+			tracked_since: now,
+			total_downtime_secs: 0,
+			downtime_windows: Vec::new(),
+			forwards_succeeded: 0,
+			forwards_failed: 0,
+			resolved_htlcs: 0,
+			total_resolution_latency_ms: 0,
+		};
+		if !peer_connected {
+			stats.peer_disconnected(now);
+		}
+		stats
+	}
+
+	fn peer_disconnected(&mut self, now: u64) {
+		if let Some(DowntimeWindow { end: None, .. }) = self.downtime_windows.last() {
+			return;
+		}
+		if self.downtime_windows.len() >= MAX_DOWNTIME_WINDOWS {
+			self.downtime_windows.remove(0);
+		}
+		self.downtime_windows.push(DowntimeWindow { start: now, end: None });
+	}
+
+	fn peer_connected(&mut self, now: u64) {
+		if let Some(window) = self.downtime_windows.last_mut() {
+			if window.end.is_none() {
+				window.end = Some(now);
+				self.total_downtime_secs += now.saturating_sub(window.start);
+			}
+		}
+	}
+
+	/// The total number of seconds the peer has been disconnected since we started tracking the
+	/// channel, including any ongoing downtime.
+	pub fn downtime_secs(&self, now: u64) -> u64 {
+		let ongoing_secs = match self.downtime_windows.last() {
+			Some(DowntimeWindow { start, end: None }) => now.saturating_sub(*start),
+			_ => 0,
+		};
+		self.total_downtime_secs + ongoing_secs
+	}
+
+	/// The proportion of time, in millionths, the peer has been connected to us since we started
+	/// tracking the channel.
+	pub fn uptime_millionths(&self, now: u64) -> u32 {
+		let tracked_secs = now.saturating_sub(self.tracked_since);
+		if tracked_secs == 0 {
+			return if self.downtime_secs(now) == 0 { 1_000_000 } else { 0 };
+		}
+		let uptime_secs = tracked_secs.saturating_sub(self.downtime_secs(now));
+		(uptime_secs as u128 * 1_000_000 / tracked_secs as u128) as u32
+	}
+
+	/// The proportion, in millionths, of HTLCs forwarded over the channel which succeeded, or
+	/// `None` if we haven't forwarded any.
+	pub fn forwarding_success_millionths(&self) -> Option<u32> {
+		let attempts = self.forwards_succeeded + self.forwards_failed;
+		if attempts == 0 { return None; }
+		Some((self.forwards_succeeded as u128 * 1_000_000 / attempts as u128) as u32)
+	}
+
+	/// The average time taken to resolve an HTLC on the channel, or `None` if no resolutions have
+	/// been reported.
+	pub fn average_resolution_latency(&self) -> Option<Duration> {
+		if self.resolved_htlcs == 0 { return None; }
+		Some(Duration::from_millis(self.total_resolution_latency_ms / self.resolved_htlcs))
+	}
+}
+
+/// Tracks [`ChannelPerformanceStats`] for each of our channels.
+///
+/// The tracker must be told about channels via [`Self::track_channels`], about peer connections
+/// via [`Self::peer_connected`] and [`Self::peer_disconnected`], and should be handed each
+/// [`Event`] via [`Self::handle_event`]. All times are given in seconds since the UNIX epoch.
+pub struct ChannelPerformanceTracker {
+	channels: Mutex<HashMap<[u8; 32], ChannelPerformanceStats>>,
+	connected_peers: Mutex<HashSet<PublicKey>>,
+}
+
+impl ChannelPerformanceTracker {
+	/// Creates a new tracker with no channels.
+	pub fn new() -> Self {
+		Self {
+			channels: Mutex::new(HashMap::new()),
+			connected_peers: Mutex::new(HashSet::new()),
+		}
+	}
+
+	/// Starts tracking any channels in `channels` which we aren't tracking yet. Should be called
+	/// with the result of [`ChannelManager::list_channels`] on startup and whenever a channel is
+	/// opened.
+	///
+	/// [`ChannelManager::list_channels`]: crate::ln::channelmanager::ChannelManager::list_channels
+	pub fn track_channels(&self, channels: &[ChannelDetails], now: u64) {
+		let connected_peers = self.connected_peers.lock().unwrap();
+		let mut tracked_channels = self.channels.lock().unwrap();
+		for channel in channels {
+			let counterparty_node_id = channel.counterparty.node_id;
+			tracked_channels.entry(channel.channel_id).or_insert_with(|| ChannelPerformanceStats::new(
+				channel.channel_id, counterparty_node_id, connected_peers.contains(&counterparty_node_id), now
+			));
+		}
+	}
+
+	/// Notes that `counterparty_node_id` connected to us, closing the downtime window of each of
+	/// its channels.
+	pub fn peer_connected(&self, counterparty_node_id: &PublicKey, now: u64) {
+		self.connected_peers.lock().unwrap().insert(*counterparty_node_id);
+		for stats in self.channels.lock().unwrap().values_mut() {
+			if stats.counterparty_node_id == *counterparty_node_id {
+				stats.peer_connected(now);
+			}
+		}
+	}
+
+	/// Notes that `counterparty_node_id` disconnected from us, opening a downtime window for each
+	/// of its channels.
+	pub fn peer_disconnected(&self, counterparty_node_id: &PublicKey, now: u64) {
+		self.connected_peers.lock().unwrap().remove(counterparty_node_id);
+		for stats in self.channels.lock().unwrap().values_mut() {
+			if stats.counterparty_node_id == *counterparty_node_id {
+				stats.peer_disconnected(now);
+			}
+		}
+	}
+
+	/// Updates forwarding statistics from [`Event::PaymentForwarded`] and
+	/// [`Event::HTLCHandlingFailed`], and stops tracking channels on [`Event::ChannelClosed`].
+	pub fn handle_event(&self, event: &Event) {
+		let mut channels = self.channels.lock().unwrap();
+		match event {
+			Event::PaymentForwarded { next_channel_id: Some(channel_id), .. } => {
+				if let Some(stats) = channels.get_mut(channel_id) {
+					stats.forwards_succeeded += 1;
+				}
+			},
+			Event::HTLCHandlingFailed {
+				failed_next_destination: HTLCDestination::NextHopChannel { channel_id, .. }, ..
+			} => {
+				if let Some(stats) = channels.get_mut(channel_id) {
+					stats.forwards_failed += 1;
+				}
+			},
+			Event::ChannelClosed { channel_id, .. } => {
+				channels.remove(channel_id);
+			},
+			_ => {},
+		}
+	}
+
+	/// Records that an HTLC on the given channel was resolved `latency` after it was added.
+	///
+	/// [`Event`]s do not carry the time at which HTLCs were added, so this must be reported by the
+	/// user, e.g. by comparing the time an [`Event::PaymentForwarded`] was seen to the time the
+	/// corresponding [`Event::PendingHTLCsForwardable`] was handled.
+	pub fn record_htlc_resolution(&self, channel_id: &[u8; 32], latency: Duration) {
+		if let Some(stats) = self.channels.lock().unwrap().get_mut(channel_id) {
+			stats.resolved_htlcs += 1;
+			stats.total_resolution_latency_ms += latency.as_millis() as u64;
+		}
+	}
+
+	/// Returns the statistics for the given channel, if we're tracking it.
+	pub fn channel_stats(&self, channel_id: &[u8; 32]) -> Option<ChannelPerformanceStats> {
+		self.channels.lock().unwrap().get(channel_id).cloned()
+	}
+
+	/// Returns the statistics for all tracked channels.
+	pub fn list_channel_stats(&self) -> Vec<ChannelPerformanceStats> {
+		self.channels.lock().unwrap().values().cloned().collect()
+	}
+}
+
+impl Default for ChannelPerformanceTracker {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Writeable for ChannelPerformanceTracker {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let mut channels: Vec<ChannelPerformanceStats> = self.channels.lock().unwrap().values().cloned().collect();
+		channels.sort_unstable_by_key(|stats| stats.channel_id);
+		write_tlv_fields!(writer, {
+			(0, channels, vec_type),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for ChannelPerformanceTracker {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		init_tlv_field_var!(channels, vec_type);
+		read_tlv_fields!(reader, {
+			(0, channels, vec_type),
+		});
+		let channels: Vec<ChannelPerformanceStats> = init_tlv_based_struct_field!(channels, vec_type);
+		Ok(Self {
+			channels: Mutex::new(channels.into_iter().map(|stats| (stats.channel_id, stats)).collect()),
+			// We don't know which peers are connected until we're told, so treat them all as
+			// disconnected until `peer_connected` is called.
+			connected_peers: Mutex::new(HashSet::new()),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ChannelPerformanceTracker, DowntimeWindow, MAX_DOWNTIME_WINDOWS};
+
+	use ln::features::InitFeatures;
+	use ln::functional_test_utils::*;
+	use util::events::{ClosureReason, Event, HTLCDestination};
+	use util::ser::{Readable, Writeable};
+
+	use io;
+	use core::time::Duration;
+
+	#[test]
+	fn tracks_channel_performance() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let channel_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
+		let counterparty_node_id = nodes[1].node.get_our_node_id();
+
+		let tracker = ChannelPerformanceTracker::new();
+		tracker.peer_connected(&counterparty_node_id, 0);
+		tracker.track_channels(&nodes[0].node.list_channels(), 0);
+		let stats = tracker.channel_stats(&channel_id).unwrap();
+		assert_eq!(stats.counterparty_node_id, counterparty_node_id);
+		assert_eq!(stats.uptime_millionths(0), 1_000_000);
+		assert_eq!(stats.forwarding_success_millionths(), None);
+
+		// The peer is offline for a quarter of the time we've tracked the channel.
+		tracker.peer_disconnected(&counterparty_node_id, 100);
+		assert_eq!(tracker.channel_stats(&channel_id).unwrap().downtime_secs(150), 50);
+		tracker.peer_connected(&counterparty_node_id, 200);
+		let stats = tracker.channel_stats(&channel_id).unwrap();
+		assert_eq!(stats.downtime_windows, vec![DowntimeWindow { start: 100, end: Some(200) }]);
+		assert_eq!(stats.uptime_millionths(400), 750_000);
+
+		for _ in 0..3 {
+			tracker.handle_event(&Event::PaymentForwarded {
+				fee_earned_msat: Some(1000), prev_channel_id: None, next_channel_id: Some(channel_id),
+				claim_from_onchain_tx: false,
+			});
+		}
+		tracker.handle_event(&Event::HTLCHandlingFailed {
+			prev_channel_id: [0; 32],
+			failed_next_destination: HTLCDestination::NextHopChannel { node_id: Some(counterparty_node_id), channel_id },
+		});
+		tracker.record_htlc_resolution(&channel_id, Duration::from_millis(100));
+		tracker.record_htlc_resolution(&channel_id, Duration::from_millis(300));
+		let stats = tracker.channel_stats(&channel_id).unwrap();
+		assert_eq!(stats.forwarding_success_millionths(), Some(750_000));
+		assert_eq!(stats.average_resolution_latency(), Some(Duration::from_millis(200)));
+
+		// Only the most recent downtime windows are kept, but the total downtime is not lost.
+		for i in 0..MAX_DOWNTIME_WINDOWS as u64 {
+			tracker.peer_disconnected(&counterparty_node_id, 1000 + i * 10);
+			tracker.peer_connected(&counterparty_node_id, 1000 + i * 10 + 1);
+		}
+		let stats = tracker.channel_stats(&channel_id).unwrap();
+		assert_eq!(stats.downtime_windows.len(), MAX_DOWNTIME_WINDOWS);
+		assert_eq!(stats.downtime_windows[0].start, 1000);
+		assert_eq!(stats.total_downtime_secs, 100 + MAX_DOWNTIME_WINDOWS as u64);
+
+		let read_tracker = ChannelPerformanceTracker::read(&mut io::Cursor::new(&tracker.encode())).unwrap();
+		assert_eq!(read_tracker.list_channel_stats(), tracker.list_channel_stats());
+
+		tracker.handle_event(&Event::ChannelClosed {
+			channel_id, user_channel_id: 0, reason: ClosureReason::CooperativeClosure,
+		});
+		assert!(tracker.channel_stats(&channel_id).is_none());
+	}
+}
@@ -0,0 +1,399 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities to check whether persisted state can be loaded by this version of LDK before
+//! actually attempting to load it.
+//!
+//! [`check_compat`] inspects serialized objects without needing any of the arguments their
+//! readers require (such as a [`KeysInterface`] or the set of [`ChannelMonitor`]s) and reports
+//! whether this version would accept them. Where the object's layout allows it, the report also
+//! lists the fields which this version does not understand: unknown odd TLV fields are silently
+//! dropped on load (and thus lost the next time the object is written), whereas unknown even
+//! fields cause the load to fail.
+//!
+//! Serialized objects from previous releases are kept as fixtures in this module's tests, so
+//! that any change which would break loading state written by those releases is caught here.
+//!
+//! [`KeysInterface`]: crate::chain::keysinterface::KeysInterface
+//! [`ChannelMonitor`]: crate::chain::channelmonitor::ChannelMonitor
+
+use chain::channelmonitor::SERIALIZATION_VERSION as MONITOR_SERIALIZATION_VERSION;
+use ln::channelmanager::SERIALIZATION_VERSION as MANAGER_SERIALIZATION_VERSION;
+use ln::msgs::DecodeError;
+use routing::gossip::NodeId;
+use routing::gossip::SERIALIZATION_VERSION as GRAPH_SERIALIZATION_VERSION;
+use util::compression::{self, SerializedForm, COMPRESSED_SERIALIZATION_VERSION};
+use util::ser::{BigSize, FixedLengthReader, Readable};
+
+use bitcoin::hash_types::BlockHash;
+
+use prelude::*;
+use io::{self, Read};
+use io_extras::read_to_end;
+
+/// The kinds of persisted objects which [`check_compat`] can inspect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PersistedObject {
+	/// A serialized [`ChannelManager`].
+	///
+	/// Only the version prefix of a [`ChannelManager`] is checked.
+	///
+	/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+	ChannelManager,
+	/// A serialized [`ChannelMonitor`], as written by a [`Persist`] implementation. Compressed
+	/// monitors are accepted.
+	///
+	/// Only the version prefix of a [`ChannelMonitor`] is checked.
+	///
+	/// [`ChannelMonitor`]: crate::chain::channelmonitor::ChannelMonitor
+	/// [`Persist`]: crate::chain::chainmonitor::Persist
+	ChannelMonitor,
+	/// A serialized [`NetworkGraph`].
+	///
+	/// [`NetworkGraph`]: crate::routing::gossip::NetworkGraph
+	NetworkGraph,
+	/// A serialized [`ProbabilisticScorer`].
+	///
+	/// [`ProbabilisticScorer`]: crate::routing::scoring::ProbabilisticScorer
+	ProbabilisticScorer,
+}
+
+/// A TLV field in a persisted object which this version of LDK does not know about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownField {
+	/// The (sub-)object the field was found in, e.g. `"ChannelInfo"`.
+	pub context: &'static str,
+	/// The TLV type of the field.
+	pub tlv_type: u64,
+}
+
+/// The result of [`check_compat`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompatReport {
+	/// The kind of object which was checked.
+	pub object: PersistedObject,
+	/// The version the object was written with, if its version prefix could be read.
+	pub written_version: Option<u8>,
+	/// The minimum version required to read the object, as declared by the version of LDK which
+	/// wrote it, if its version prefix could be read.
+	pub min_reader_version: Option<u8>,
+	/// Whether the object was written compressed.
+	pub compressed: bool,
+	/// Whether this version of LDK is expected to load the object.
+	///
+	/// If [`Self::fields_checked`] is false, this only reflects the object's version prefix.
+	pub loadable: bool,
+	/// Unknown odd TLV fields, which are ignored on load and will not be written back out.
+	pub dropped_fields: Vec<UnknownField>,
+	/// Unknown even TLV fields, any of which cause the load to fail.
+	pub unknown_required_fields: Vec<UnknownField>,
+	/// Whether the object's TLV fields were inspected. If false, [`Self::dropped_fields`] and
+	/// [`Self::unknown_required_fields`] are always empty.
+	pub fields_checked: bool,
+	/// The error encountered while inspecting the object, if any.
+	pub decode_error: Option<DecodeError>,
+}
+
+impl CompatReport {
+	fn new(object: PersistedObject) -> Self {
+		Self {
+			object,
+			written_version: None,
+			min_reader_version: None,
+			compressed: false,
+			loadable: false,
+			dropped_fields: Vec::new(),
+			unknown_required_fields: Vec::new(),
+			fields_checked: false,
+			decode_error: None,
+		}
+	}
+}
+
+// The TLV types each (sub-)object we inspect knows about. These must be kept in sync with the
+// types read by each object's reader.
+const CHANNEL_INFO_TYPES: &[u64] = &[0, 1, 2, 4, 6, 8, 10, 12];
+const NODE_INFO_TYPES: &[u64] = &[0, 2, 4];
+const NETWORK_GRAPH_TYPES: &[u64] = &[1];
+const SCORER_TYPES: &[u64] = &[0];
+const CHANNEL_LIQUIDITY_TYPES: &[u64] = &[0, 2, 4];
+
+/// Checks whether `bytes`, a serialized object of the given kind, can be loaded by this version
+/// of LDK, and which of its fields (if any) would be dropped in doing so.
+///
+/// This does not fully deserialize the object, so a report indicating it is loadable is not a
+/// guarantee that loading it succeeds, e.g. if the data is corrupted in a way which is only
+/// detected when reading the contents of its fields.
+pub fn check_compat(object: PersistedObject, bytes: &[u8]) -> CompatReport {
+	let mut report = CompatReport::new(object);
+	let res = check_object(&mut report, &mut io::Cursor::new(bytes));
+	match res {
+		Ok(()) => report.loadable = report.unknown_required_fields.is_empty(),
+		Err(e) => report.decode_error = Some(e),
+	}
+	report
+}
+
+fn check_object<R: Read>(report: &mut CompatReport, r: &mut R) -> Result<(), DecodeError> {
+	match report.object {
+		PersistedObject::ChannelManager => check_ver_prefix(report, r, MANAGER_SERIALIZATION_VERSION),
+		PersistedObject::ChannelMonitor => check_ver_prefix(report, r, MONITOR_SERIALIZATION_VERSION),
+		PersistedObject::NetworkGraph => {
+			check_ver_prefix(report, r, GRAPH_SERIALIZATION_VERSION)?;
+			report.fields_checked = true;
+			let _: BlockHash = Readable::read(r)?;
+			let channels_count: u64 = Readable::read(r)?;
+			for _ in 0..channels_count {
+				let _scid: u64 = Readable::read(r)?;
+				check_tlv_stream(report, r, "ChannelInfo", CHANNEL_INFO_TYPES)?;
+			}
+			let nodes_count: u64 = Readable::read(r)?;
+			for _ in 0..nodes_count {
+				let _: NodeId = Readable::read(r)?;
+				check_tlv_stream(report, r, "NodeInfo", NODE_INFO_TYPES)?;
+			}
+			check_tlv_stream(report, r, "NetworkGraph", NETWORK_GRAPH_TYPES)?;
+			Ok(())
+		},
+		PersistedObject::ProbabilisticScorer => {
+			report.fields_checked = true;
+			let records = check_tlv_stream(report, r, "ProbabilisticScorer", SCORER_TYPES)?;
+			let liquidities = match records.into_iter().find(|&(typ, _)| typ == 0) {
+				Some((_, value)) => value,
+				None => return Err(DecodeError::InvalidValue),
+			};
+			let mut liquidities_reader = io::Cursor::new(&liquidities);
+			let liquidities_count: u16 = Readable::read(&mut liquidities_reader)?;
+			for _ in 0..liquidities_count {
+				let _scid: u64 = Readable::read(&mut liquidities_reader)?;
+				check_tlv_stream(report, &mut liquidities_reader, "ChannelLiquidity", CHANNEL_LIQUIDITY_TYPES)?;
+			}
+			Ok(())
+		},
+	}
+}
+
+/// Reads a version prefix, failing as the object's reader would if the object requires a newer
+/// version than `this_version`.
+///
+/// Monitors are the only objects we write compressed, but as the compressed version prefix is
+/// never a valid uncompressed one, it is simply treated as an unknown version for other objects.
+fn check_ver_prefix<R: Read>(report: &mut CompatReport, r: &mut R, this_version: u8) -> Result<(), DecodeError> {
+	let ver: u8 = Readable::read(r)?;
+	let min_ver: u8 = Readable::read(r)?;
+	if report.object == PersistedObject::ChannelMonitor && !report.compressed &&
+		ver == COMPRESSED_SERIALIZATION_VERSION && min_ver == COMPRESSED_SERIALIZATION_VERSION
+	{
+		report.compressed = true;
+		let mut prefixed_reader = io::Cursor::new([ver, min_ver]).chain(r);
+		return match compression::read_ver_prefix_or_decompress(&mut prefixed_reader, this_version)? {
+			SerializedForm::Compressed(data) => check_ver_prefix(report, &mut io::Cursor::new(&data), this_version),
+			SerializedForm::Uncompressed(_) => Err(DecodeError::InvalidValue),
+		};
+	}
+	report.written_version = Some(ver);
+	report.min_reader_version = Some(min_ver);
+	if min_ver > this_version {
+		return Err(DecodeError::UnknownVersion);
+	}
+	Ok(())
+}
+
+/// Reads a length-prefixed TLV stream, recording any types not in `known_types` and returning
+/// the records which were read.
+fn check_tlv_stream<R: Read>(
+	report: &mut CompatReport, r: &mut R, context: &'static str, known_types: &[u64]
+) -> Result<Vec<(u64, Vec<u8>)>, DecodeError> {
+	let stream_len: BigSize = Readable::read(r)?;
+	let mut stream_reader = FixedLengthReader::new(r, stream_len.0);
+	let mut records = Vec::new();
+	let mut last_type: Option<u64> = None;
+	while stream_reader.bytes_remain() {
+		let typ: BigSize = Readable::read(&mut stream_reader)?;
+		if let Some(last) = last_type {
+			if typ.0 <= last {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+		last_type = Some(typ.0);
+		let len: BigSize = Readable::read(&mut stream_reader)?;
+		let mut value_reader = FixedLengthReader::new(&mut stream_reader, len.0);
+		let value = read_to_end(&mut value_reader)?;
+		if value_reader.bytes_remain() {
+			return Err(DecodeError::ShortRead);
+		}
+		if !known_types.contains(&typ.0) {
+			let field = UnknownField { context, tlv_type: typ.0 };
+			if typ.0 & 1 == 1 {
+				report.dropped_fields.push(field);
+			} else {
+				report.unknown_required_fields.push(field);
+			}
+		}
+		records.push((typ.0, value));
+	}
+	Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{check_compat, PersistedObject, UnknownField};
+
+	use ln::msgs::DecodeError;
+	use routing::gossip::NetworkGraph;
+	use routing::scoring::{ProbabilisticScorer, ProbabilisticScoringParameters};
+	use util::compression;
+	use util::ser::ReadableArgs;
+	use util::test_utils::TestLogger;
+
+	use hex;
+	use prelude::*;
+	use io;
+
+	/// Objects as serialized by previous releases, which must remain loadable.
+	///
+	/// When cutting a release whose serialization differs from the previous one, append the
+	/// objects it writes here.
+	struct Fixture {
+		release: &'static str,
+		network_graph: &'static [&'static str],
+		scorer: &'static [&'static str],
+	}
+
+	const FIXTURES: &[Fixture] = &[
+		Fixture {
+			release: "0.0.110",
+			network_graph: &[
+			"010143497fd7f826957108f4a30fd9cec3aeba79972084e90ead01ea3309000000000000000000000001000000000000",
+			"002a9500020000010800000000625900800221035be5e9478209674a96e60f1f037f6176540fd001fa1d64694770c56a",
+			"7709c42c0436363400046259008002010104020090060800000000000003e80809090000000005f5e1000a0d0c000400",
+			"0003e80204000000640c0100062102bb58b5feca505c74edc000d8282fc556e51a1024fc8e7d7e56c6f887c5c8d5f208",
+			"01000a01000c0100000000000000000202bb58b5feca505c74edc000d8282fc556e51a1024fc8e7d7e56c6f887c5c8d5",
+			"f219000d0c0004000003e80204000000640408000000000000002a035be5e9478209674a96e60f1f037f6176540fd001",
+			"fa1d64694770c56a7709c42c400234330002000002046259008004030000000620000000000000000000000000000000",
+			"00000000000000000000000000000000000a000408000000000000002a06010462590080",
+			],
+			scorer: &[
+			"2f002d0001000000000000002a2200080000000002faf08002080000000000000000040c000000006ad11c7c1f6e303c",
+			],
+		},
+	];
+
+	fn decode(hex_chunks: &[&str]) -> Vec<u8> {
+		hex::decode(hex_chunks.concat()).unwrap()
+	}
+
+	#[test]
+	fn loads_fixtures_from_previous_releases() {
+		let logger = TestLogger::new();
+		for fixture in FIXTURES {
+			let graph_bytes = decode(fixture.network_graph);
+			let report = check_compat(PersistedObject::NetworkGraph, &graph_bytes);
+			assert!(report.loadable, "{}: {:?}", fixture.release, report);
+			assert!(report.fields_checked);
+			assert_eq!(report.written_version, Some(1));
+			assert!(report.dropped_fields.is_empty());
+			let graph = NetworkGraph::read(&mut io::Cursor::new(&graph_bytes), &logger).unwrap();
+			assert_eq!(graph.read_only().channels().len(), 1);
+			assert_eq!(graph.read_only().nodes().len(), 2);
+
+			let scorer_bytes = decode(fixture.scorer);
+			let report = check_compat(PersistedObject::ProbabilisticScorer, &scorer_bytes);
+			assert!(report.loadable, "{}: {:?}", fixture.release, report);
+			assert!(report.fields_checked);
+			assert!(report.dropped_fields.is_empty());
+			let params = ProbabilisticScoringParameters::default();
+			ProbabilisticScorer::read(&mut io::Cursor::new(&scorer_bytes), (params, &graph, &logger)).unwrap();
+		}
+	}
+
+	#[test]
+	fn reports_unknown_fields() {
+		let logger = TestLogger::new();
+		let graph_bytes = decode(FIXTURES[0].network_graph);
+		// The graph ends with its trailing TLV stream, holding only the rapid gossip sync timestamp.
+		let trailing_stream = hex::decode("06010462590080").unwrap();
+		assert!(graph_bytes.ends_with(&trailing_stream));
+		let graph_prefix = &graph_bytes[..graph_bytes.len() - trailing_stream.len()];
+
+		// Unknown odd fields are dropped, but the graph still loads.
+		let mut odd_graph_bytes = graph_prefix.to_vec();
+		odd_graph_bytes.extend_from_slice(&hex::decode("09010462590080050100").unwrap());
+		let report = check_compat(PersistedObject::NetworkGraph, &odd_graph_bytes);
+		assert!(report.loadable);
+		assert_eq!(report.dropped_fields, vec![UnknownField { context: "NetworkGraph", tlv_type: 5 }]);
+		assert!(report.unknown_required_fields.is_empty());
+		assert!(NetworkGraph::read(&mut io::Cursor::new(&odd_graph_bytes), &logger).is_ok());
+
+		// Unknown even fields cause the load to fail.
+		let mut even_graph_bytes = graph_prefix.to_vec();
+		even_graph_bytes.extend_from_slice(&hex::decode("09010462590080040100").unwrap());
+		let report = check_compat(PersistedObject::NetworkGraph, &even_graph_bytes);
+		assert!(!report.loadable);
+		assert!(report.decode_error.is_none());
+		assert_eq!(report.unknown_required_fields, vec![UnknownField { context: "NetworkGraph", tlv_type: 4 }]);
+		match NetworkGraph::read(&mut io::Cursor::new(&even_graph_bytes), &logger) {
+			Err(DecodeError::UnknownRequiredFeature) => {},
+			_ => panic!(),
+		}
+
+		// Fields are also checked in nested objects, here in the single `ChannelLiquidity`.
+		let scorer_bytes = decode(FIXTURES[0].scorer);
+		let mut odd_scorer_bytes = vec![0x31, 0x00, 0x2f];
+		odd_scorer_bytes.extend_from_slice(&scorer_bytes[3..13]);
+		odd_scorer_bytes.push(0x24);
+		odd_scorer_bytes.extend_from_slice(&scorer_bytes[14..]);
+		odd_scorer_bytes.extend_from_slice(&[0x07, 0x00]);
+		let report = check_compat(PersistedObject::ProbabilisticScorer, &odd_scorer_bytes);
+		assert!(report.loadable);
+		assert_eq!(report.dropped_fields, vec![UnknownField { context: "ChannelLiquidity", tlv_type: 7 }]);
+		let graph = NetworkGraph::read(&mut io::Cursor::new(&graph_bytes), &logger).unwrap();
+		let params = ProbabilisticScoringParameters::default();
+		assert!(ProbabilisticScorer::read(&mut io::Cursor::new(&odd_scorer_bytes), (params, &graph, &logger)).is_ok());
+	}
+
+	#[test]
+	fn reports_version_prefixes() {
+		let report = check_compat(PersistedObject::ChannelManager, &[1, 1, 0, 0]);
+		assert!(report.loadable);
+		assert!(!report.fields_checked);
+		assert_eq!(report.written_version, Some(1));
+
+		// Objects written by a newer version which remain readable by older ones are loadable.
+		let report = check_compat(PersistedObject::ChannelManager, &[2, 1, 0, 0]);
+		assert!(report.loadable);
+		assert_eq!(report.written_version, Some(2));
+		assert_eq!(report.min_reader_version, Some(1));
+
+		let report = check_compat(PersistedObject::NetworkGraph, &[3, 2, 0, 0]);
+		assert!(!report.loadable);
+		assert_eq!(report.written_version, Some(3));
+		assert_eq!(report.min_reader_version, Some(2));
+		assert_eq!(report.decode_error, Some(DecodeError::UnknownVersion));
+
+		let report = check_compat(PersistedObject::ChannelMonitor, &[1]);
+		assert!(!report.loadable);
+		assert_eq!(report.decode_error, Some(DecodeError::ShortRead));
+
+		// Compressed monitors are checked by the version prefix of their decompressed data.
+		let mut monitor_bytes = [0; 32];
+		monitor_bytes[0] = 1;
+		monitor_bytes[1] = 1;
+		let report = check_compat(PersistedObject::ChannelMonitor, &compression::encode_compressed(&monitor_bytes));
+		assert!(report.loadable);
+		assert!(report.compressed);
+		assert_eq!(report.written_version, Some(1));
+
+		// Only monitors may be written compressed.
+		let report = check_compat(PersistedObject::ChannelManager, &compression::encode_compressed(&monitor_bytes));
+		assert!(!report.loadable);
+		assert!(!report.compressed);
+		assert_eq!(report.decode_error, Some(DecodeError::UnknownVersion));
+	}
+}
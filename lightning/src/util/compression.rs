@@ -0,0 +1,231 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A simple LZ77-style compression of serialized objects, used to optionally shrink large
+//! [`ChannelMonitor`]s and [`ChannelMonitorUpdate`]s, which tend to repeat the same points,
+//! scripts and hashes many times over.
+//!
+//! Compressed objects are written with a version prefix which no version of LDK would write for
+//! an uncompressed object, allowing readers to transparently accept both forms while older
+//! readers fail with [`DecodeError::UnknownVersion`].
+//!
+//! [`ChannelMonitor`]: crate::chain::channelmonitor::ChannelMonitor
+//! [`ChannelMonitorUpdate`]: crate::chain::channelmonitor::ChannelMonitorUpdate
+
+use ln::msgs::DecodeError;
+use util::ser::{BigSize, FixedLengthReader, Readable, VecWriter, Writeable};
+
+use prelude::*;
+use io::{self, Read};
+use io_extras::read_to_end;
+use core::cmp;
+
+/// The version (and minimum version) written in place of an object's version prefix when it is
+/// written compressed.
+pub(crate) const COMPRESSED_SERIALIZATION_VERSION: u8 = 0xfe;
+
+/// Matches shorter than this cost more to encode than the literals they would replace.
+const MIN_MATCH_LEN: usize = 8;
+const HASH_BITS: u32 = 14;
+const MAX_ALLOC_SIZE: usize = 64 * 1024;
+
+/// The form of a serialized object, as read by [`read_ver_prefix_or_decompress`].
+pub(crate) enum SerializedForm {
+	/// The object was written uncompressed with the given version, which the remaining data
+	/// should be read as.
+	Uncompressed(u8),
+	/// The object was written compressed, with the given data being its uncompressed
+	/// serialization, including its version prefix.
+	Compressed(Vec<u8>),
+}
+
+/// Encodes `obj` compressed, prefixed by the compressed version prefix and the length of the
+/// compressed data.
+pub(crate) fn encode_compressed<T: Writeable>(obj: &T) -> Vec<u8> {
+	let compressed = compress(&obj.encode());
+	let mut w = VecWriter(Vec::with_capacity(compressed.len() + 11));
+	w.0.push(COMPRESSED_SERIALIZATION_VERSION);
+	w.0.push(COMPRESSED_SERIALIZATION_VERSION);
+	BigSize(compressed.len() as u64).write(&mut w).expect("No in-memory data may fail to serialize");
+	w.0.extend_from_slice(&compressed);
+	w.0
+}
+
+/// Reads the version prefix of an object written either as-is by `write_ver_prefix` or by
+/// [`encode_compressed`], in which case the rest of the compressed object is read and
+/// decompressed.
+pub(crate) fn read_ver_prefix_or_decompress<R: Read>(r: &mut R, this_version: u8) -> Result<SerializedForm, DecodeError> {
+	let ver: u8 = Readable::read(r)?;
+	let min_ver: u8 = Readable::read(r)?;
+	if ver == COMPRESSED_SERIALIZATION_VERSION && min_ver == COMPRESSED_SERIALIZATION_VERSION {
+		let compressed_len: BigSize = Readable::read(r)?;
+		let mut compressed_reader = FixedLengthReader::new(r, compressed_len.0);
+		let compressed = read_to_end(&mut compressed_reader)?;
+		if compressed_reader.bytes_remain() {
+			return Err(DecodeError::ShortRead);
+		}
+		let decompressed = decompress(&compressed)?;
+		// Compressed objects never contain another compressed object.
+		if decompressed.len() < 2 || decompressed[0] == COMPRESSED_SERIALIZATION_VERSION {
+			return Err(DecodeError::InvalidValue);
+		}
+		return Ok(SerializedForm::Compressed(decompressed));
+	}
+	if min_ver > this_version {
+		return Err(DecodeError::UnknownVersion);
+	}
+	Ok(SerializedForm::Uncompressed(ver))
+}
+
+fn hash(data: &[u8]) -> usize {
+	let word = (data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24;
+	(word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_token(out: &mut VecWriter, literals: &[u8], match_len: usize, distance: usize) {
+	BigSize(literals.len() as u64).write(out).unwrap();
+	out.0.extend_from_slice(literals);
+	BigSize(match_len as u64).write(out).unwrap();
+	if match_len != 0 {
+		BigSize(distance as u64).write(out).unwrap();
+	}
+}
+
+/// Compresses `data` as its length followed by a series of tokens, each made up of a run of
+/// literal bytes and a back-reference to earlier data (or zero to mark the final token).
+fn compress(data: &[u8]) -> Vec<u8> {
+	let mut out = VecWriter(Vec::with_capacity(data.len() / 2));
+	BigSize(data.len() as u64).write(&mut out).unwrap();
+
+	// Maps the hash of four bytes to one more than the last position they were seen at, or zero.
+	let mut table = vec![0usize; 1 << HASH_BITS];
+	let mut literal_start = 0;
+	let mut pos = 0;
+	while pos + MIN_MATCH_LEN <= data.len() {
+		let candidate = table[hash(&data[pos..])].wrapping_sub(1);
+		table[hash(&data[pos..])] = pos + 1;
+		if candidate < pos && data[candidate..candidate + MIN_MATCH_LEN] == data[pos..pos + MIN_MATCH_LEN] {
+			let mut match_len = MIN_MATCH_LEN;
+			while pos + match_len < data.len() && data[candidate + match_len] == data[pos + match_len] {
+				match_len += 1;
+			}
+			write_token(&mut out, &data[literal_start..pos], match_len, pos - candidate);
+			for matched_pos in pos + 1..cmp::min(pos + match_len, data.len() - 3) {
+				table[hash(&data[matched_pos..])] = matched_pos + 1;
+			}
+			pos += match_len;
+			literal_start = pos;
+		} else {
+			pos += 1;
+		}
+	}
+	write_token(&mut out, &data[literal_start..], 0, 0);
+	out.0
+}
+
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>, DecodeError> {
+	let mut reader = io::Cursor::new(compressed);
+	let len: BigSize = Readable::read(&mut reader)?;
+	let mut out = Vec::with_capacity(cmp::min(len.0, MAX_ALLOC_SIZE as u64) as usize);
+	loop {
+		let literal_len: BigSize = Readable::read(&mut reader)?;
+		if literal_len.0 > len.0 - out.len() as u64 || literal_len.0 > compressed.len() as u64 - reader.position() {
+			return Err(DecodeError::InvalidValue);
+		}
+		let literal_start = out.len();
+		out.resize(literal_start + literal_len.0 as usize, 0);
+		reader.read_exact(&mut out[literal_start..])?;
+
+		let match_len: BigSize = Readable::read(&mut reader)?;
+		if match_len.0 == 0 {
+			break;
+		}
+		let distance: BigSize = Readable::read(&mut reader)?;
+		if match_len.0 > len.0 - out.len() as u64 || distance.0 == 0 || distance.0 > out.len() as u64 {
+			return Err(DecodeError::InvalidValue);
+		}
+		// Matches may overlap the data they produce, so copy byte-by-byte.
+		let match_start = out.len() - distance.0 as usize;
+		for i in 0..match_len.0 as usize {
+			let byte = out[match_start + i];
+			out.push(byte);
+		}
+	}
+	if out.len() as u64 != len.0 || (reader.position() as usize) != compressed.len() {
+		return Err(DecodeError::InvalidValue);
+	}
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{compress, decompress, encode_compressed, read_ver_prefix_or_decompress, SerializedForm};
+	use ln::msgs::DecodeError;
+	use util::ser::Writeable;
+
+	use prelude::*;
+	use io;
+
+	#[test]
+	fn compression_round_trips() {
+		let mut repetitive = Vec::new();
+		for i in 0..100u8 {
+			repetitive.extend_from_slice(&[2; 33]);
+			repetitive.push(i);
+			repetitive.extend_from_slice(&[0xab; 32]);
+		}
+		let inputs: Vec<Vec<u8>> = vec![Vec::new(), vec![42], vec![7; 1000], (0..=255u8).collect(), repetitive.clone()];
+		for input in inputs.iter() {
+			assert_eq!(&decompress(&compress(input)).unwrap(), input);
+		}
+		assert!(compress(&repetitive).len() < repetitive.len() / 5);
+
+		// Truncated or corrupted data is rejected rather than misread.
+		let compressed = compress(&repetitive);
+		assert!(decompress(&compressed[..compressed.len() - 1]).is_err());
+		let mut trailing = compressed.clone();
+		trailing.push(0);
+		assert!(decompress(&trailing).is_err());
+		// A back-reference to before the start of the data.
+		assert!(decompress(&[10, 1, 42, 9, 5]).is_err());
+	}
+
+	#[test]
+	fn reads_both_forms() {
+		let mut uncompressed = Vec::new();
+		uncompressed.extend_from_slice(&[1, 1]);
+		uncompressed.extend_from_slice(&[3; 100]);
+
+		match read_ver_prefix_or_decompress(&mut io::Cursor::new(&uncompressed), 1) {
+			Ok(SerializedForm::Uncompressed(1)) => {},
+			_ => panic!(),
+		}
+		match read_ver_prefix_or_decompress(&mut io::Cursor::new(&[2, 2]), 1) {
+			Err(DecodeError::UnknownVersion) => {},
+			_ => panic!(),
+		}
+
+		struct Raw(Vec<u8>);
+		impl Writeable for Raw {
+			fn write<W: ::util::ser::Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+				w.write_all(&self.0)
+			}
+		}
+		let compressed = encode_compressed(&Raw(uncompressed.clone()));
+		assert!(compressed.len() < uncompressed.len());
+		match read_ver_prefix_or_decompress(&mut io::Cursor::new(&compressed), 1) {
+			Ok(SerializedForm::Compressed(data)) => assert_eq!(data, uncompressed),
+			_ => panic!(),
+		}
+
+		// A compressed object may not contain another compressed object.
+		let nested = encode_compressed(&Raw(compressed));
+		assert!(read_ver_prefix_or_decompress(&mut io::Cursor::new(&nested), 1).is_err());
+	}
+}
@@ -10,13 +10,20 @@
 //! Various user-configurable channel limits and settings which ChannelManager
 //! applies for you.
 
+use chain::chaininterface::ConfirmationTarget;
 use ln::channel::MAX_FUNDING_SATOSHIS_NO_WUMBO;
-use ln::channelmanager::{BREAKDOWN_TIMEOUT, MAX_LOCAL_BREAKDOWN_TIMEOUT};
+use ln::features::InitFeatures;
+use ln::channelmanager::{BREAKDOWN_TIMEOUT, CLTV_FAR_FAR_AWAY, MAX_LOCAL_BREAKDOWN_TIMEOUT, MIN_CLTV_EXPIRY_DELTA, MIN_FINAL_CLTV_EXPIRY};
+use routing::router::DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA;
+use util::errors::APIError;
+
+use prelude::*;
+use core::cmp;
 
 /// Configuration we set when applicable.
 ///
 /// Default::default() provides sane defaults.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ChannelHandshakeConfig {
 	/// Confirmations we will wait for before considering the channel locked in.
 	/// Applied only for inbound channels (see ChannelHandshakeLimits::max_minimum_depth for the
@@ -149,7 +156,52 @@ pub struct ChannelHandshakeConfig {
 	///                as 1000 sats instead, which is a safe implementation-specific lower bound.
 	/// Maximum value: 1,000,000, any values larger than 1 Million will be treated as 1 Million (or 100%)
 	///                instead, although channel negotiations will fail in that case.
-	pub their_channel_reserve_proportional_millionths: u32
+	pub their_channel_reserve_proportional_millionths: u32,
+	/// If this is set to true, we do not require our counterparty to maintain any channel reserve,
+	/// ignoring [`ChannelHandshakeConfig::their_channel_reserve_proportional_millionths`]. This
+	/// lets the counterparty spend its full balance, but leaves us unable to punish it for
+	/// broadcasting a revoked state once it has spent its balance.
+	///
+	/// This should only be enabled for trusted peers, generally via
+	/// [`PeerConfigOverrides::trusted_zero_reserve`] rather than for all peers.
+	///
+	/// Note that channel reserves below the dust limit are not accepted by all implementations,
+	/// so channels with untrusted peers may fail to open with this set.
+	///
+	/// Default value: false.
+	pub their_channel_reserve_zero: bool,
+	/// The [`ConfirmationTarget`] we query our [`FeeEstimator`] with to pick the feerate of the
+	/// initial commitment transaction for outbound channels, which is also the feerate the
+	/// channel's fee affordability checks are made against until the first `update_fee`.
+	///
+	/// Ignored if [`ChannelHandshakeConfig::funding_feerate_sat_per_1000_weight`] is set.
+	///
+	/// Default value: [`ConfirmationTarget::Normal`].
+	///
+	/// [`FeeEstimator`]: crate::chain::chaininterface::FeeEstimator
+	pub funding_feerate_confirmation_target: ConfirmationTarget,
+	/// An explicit feerate to use for the initial commitment transaction of outbound channels, in
+	/// place of querying our [`FeeEstimator`].
+	///
+	/// Values below the minimum relay feerate of 253 sat/kW will be treated as 253 sat/kW.
+	///
+	/// Default value: None.
+	///
+	/// [`FeeEstimator`]: crate::chain::chaininterface::FeeEstimator
+	pub funding_feerate_sat_per_1000_weight: Option<u32>,
+	/// The number of blocks after creating an outbound channel we'll wait for its funding
+	/// transaction to confirm before giving up on it. Once reached, the channel is closed with
+	/// [`ClosureReason::FundingTimedOut`] and an [`Event::FundingTimedOut`] is generated so that
+	/// the funding transaction's inputs can be reused.
+	///
+	/// Only applies to outbound channels - inbound channels are always given up on after 2016
+	/// blocks, as recommended by BOLT 2.
+	///
+	/// Default value: None, i.e. we wait for the funding transaction indefinitely.
+	///
+	/// [`ClosureReason::FundingTimedOut`]: crate::util::events::ClosureReason::FundingTimedOut
+	/// [`Event::FundingTimedOut`]: crate::util::events::Event::FundingTimedOut
+	pub outbound_funding_timeout_blocks: Option<u32>,
 }
 
 impl Default for ChannelHandshakeConfig {
@@ -163,6 +215,10 @@ impl Default for ChannelHandshakeConfig {
 			announced_channel: false,
 			commit_upfront_shutdown_pubkey: true,
 			their_channel_reserve_proportional_millionths: 10_000,
+			their_channel_reserve_zero: false,
+			funding_feerate_confirmation_target: ConfirmationTarget::Normal,
+			funding_feerate_sat_per_1000_weight: None,
+			outbound_funding_timeout_blocks: None,
 		}
 	}
 }
@@ -178,7 +234,7 @@ impl Default for ChannelHandshakeConfig {
 /// Most additional limits are disabled except those with which specify a default in individual
 /// field documentation. Note that this may result in barely-usable channels, but since they
 /// are applied mostly only to incoming channels that's not much of a problem.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ChannelHandshakeLimits {
 	/// Minimum allowed satoshis when a channel is funded. This is supplied by the sender and so
 	/// only applies to inbound channels.
@@ -218,6 +274,12 @@ pub struct ChannelHandshakeLimits {
 	///
 	/// Default value: 144, or roughly one day and only applies to outbound channels.
 	pub max_minimum_depth: u32,
+	/// The minimum number of confirmations we require on the funding transaction of our outbound
+	/// channels before considering them ready, even if our counterparty asked for fewer.
+	///
+	/// Default value: 0, i.e. we use the number our counterparty asked for (subject to
+	/// [`ChannelHandshakeLimits::trust_own_funding_0conf`]).
+	pub min_minimum_depth: u32,
 	/// Whether we implicitly trust funding transactions generated by us for our own outbound
 	/// channels to not be double-spent.
 	///
@@ -266,6 +328,7 @@ impl Default for ChannelHandshakeLimits {
 			min_max_accepted_htlcs: 0,
 			trust_own_funding_0conf: true,
 			max_minimum_depth: 144,
+			min_minimum_depth: 0,
 			force_announced_channel_preference: true,
 			their_to_self_delay: MAX_LOCAL_BREAKDOWN_TIMEOUT,
 		}
@@ -311,10 +374,8 @@ pub struct ChannelConfig {
 	/// the spending transaction).
 	///
 	/// Default value: 72 (12 hours at an average of 6 blocks/hour).
-	/// Minimum value: [`MIN_CLTV_EXPIRY_DELTA`], any values less than this will be treated as
-	///                [`MIN_CLTV_EXPIRY_DELTA`] instead.
-	///
-	/// [`MIN_CLTV_EXPIRY_DELTA`]: crate::ln::channelmanager::MIN_CLTV_EXPIRY_DELTA
+	/// Minimum value: [`CltvPolicy::min_cltv_expiry_delta`], any values less than this will be
+	///                treated as [`CltvPolicy::min_cltv_expiry_delta`] instead.
 	pub cltv_expiry_delta: u16,
 	/// Limit our total exposure to in-flight HTLCs which are burned to fees as they are too
 	/// small to claim on-chain.
@@ -453,6 +514,568 @@ impl ::util::ser::Readable for LegacyChannelConfig {
 	}
 }
 
+/// The CLTV limits we apply when creating invoices, forwarding HTLCs and building routes.
+///
+/// Each limit has a safety bound below which (or, for the maximums, above which) we can no longer
+/// reliably claim or fail back HTLCs on-chain. Use [`CltvPolicy::validate`] to check a policy
+/// against those bounds. A [`ChannelManager`] will clamp any out-of-bounds values to the nearest
+/// safe one.
+///
+/// Default::default() provides the limits LDK has historically used.
+///
+/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CltvPolicy {
+	/// The minimum difference between an HTLC's inbound and outbound CLTV expiry we require when
+	/// forwarding it. This is the floor applied to [`ChannelConfig::cltv_expiry_delta`] when
+	/// announcing our channels, and the delta we require for HTLCs forwarded to phantom nodes.
+	///
+	/// Default value: [`MIN_CLTV_EXPIRY_DELTA`], which is also the minimum.
+	///
+	/// [`MIN_CLTV_EXPIRY_DELTA`]: crate::ln::channelmanager::MIN_CLTV_EXPIRY_DELTA
+	pub min_cltv_expiry_delta: u16,
+	/// The `min_final_cltv_expiry` we set in invoices we generate for payment to us. Payments we
+	/// receive must leave us this many blocks, less one to allow for a block being found while the
+	/// payment is in flight.
+	///
+	/// Default value: [`MIN_FINAL_CLTV_EXPIRY`], which is also the minimum.
+	///
+	/// [`MIN_FINAL_CLTV_EXPIRY`]: crate::ln::channelmanager::MIN_FINAL_CLTV_EXPIRY
+	pub min_final_cltv_expiry: u32,
+	/// The maximum number of blocks from the current height an HTLC we forward may expire in.
+	/// HTLCs expiring further out are failed back, as they would lock up our liquidity for too long.
+	///
+	/// Must be at least [`CltvPolicy::min_cltv_expiry_delta`] and
+	/// [`CltvPolicy::min_final_cltv_expiry`].
+	///
+	/// Default value: 2016 (roughly two weeks of blocks).
+	pub max_cltv_expiry_from_now: u32,
+	/// The maximum total CLTV delta we accept on routes for payments we send.
+	///
+	/// Must be greater than [`CltvPolicy::min_final_cltv_expiry`].
+	///
+	/// Default value: [`DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA`].
+	///
+	/// [`DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA`]: crate::routing::router::DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA
+	pub max_total_cltv_expiry_delta: u32,
+}
+
+impl Default for CltvPolicy {
+	fn default() -> Self {
+		CltvPolicy {
+			min_cltv_expiry_delta: MIN_CLTV_EXPIRY_DELTA,
+			min_final_cltv_expiry: MIN_FINAL_CLTV_EXPIRY,
+			max_cltv_expiry_from_now: CLTV_FAR_FAR_AWAY,
+			max_total_cltv_expiry_delta: DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA,
+		}
+	}
+}
+
+impl CltvPolicy {
+	/// Checks that this policy is within the safety bounds documented on each field, returning an
+	/// [`APIError::APIMisuseError`] describing the first violation if not.
+	pub fn validate(&self) -> Result<(), APIError> {
+		if self.min_cltv_expiry_delta < MIN_CLTV_EXPIRY_DELTA {
+			return Err(APIError::APIMisuseError {
+				err: format!("min_cltv_expiry_delta is below the minimum of {}", MIN_CLTV_EXPIRY_DELTA),
+			});
+		}
+		if self.min_final_cltv_expiry < MIN_FINAL_CLTV_EXPIRY {
+			return Err(APIError::APIMisuseError {
+				err: format!("min_final_cltv_expiry is below the minimum of {}", MIN_FINAL_CLTV_EXPIRY),
+			});
+		}
+		if self.max_cltv_expiry_from_now < self.min_cltv_expiry_delta as u32 ||
+			self.max_cltv_expiry_from_now < self.min_final_cltv_expiry
+		{
+			return Err(APIError::APIMisuseError {
+				err: "max_cltv_expiry_from_now is below min_cltv_expiry_delta or min_final_cltv_expiry".to_owned(),
+			});
+		}
+		if self.max_total_cltv_expiry_delta <= self.min_final_cltv_expiry {
+			return Err(APIError::APIMisuseError {
+				err: "max_total_cltv_expiry_delta must be greater than min_final_cltv_expiry".to_owned(),
+			});
+		}
+		Ok(())
+	}
+
+	/// Returns a copy of this policy with any values outside of the safety bounds clamped to the
+	/// nearest safe value.
+	pub(crate) fn sanitized(&self) -> Self {
+		let min_cltv_expiry_delta = cmp::max(self.min_cltv_expiry_delta, MIN_CLTV_EXPIRY_DELTA);
+		let min_final_cltv_expiry = cmp::max(self.min_final_cltv_expiry, MIN_FINAL_CLTV_EXPIRY);
+		let max_cltv_expiry_from_now = cmp::max(self.max_cltv_expiry_from_now,
+			cmp::max(min_cltv_expiry_delta as u32, min_final_cltv_expiry));
+		let max_total_cltv_expiry_delta = cmp::max(self.max_total_cltv_expiry_delta, min_final_cltv_expiry + 1);
+		CltvPolicy {
+			min_cltv_expiry_delta, min_final_cltv_expiry, max_cltv_expiry_from_now, max_total_cltv_expiry_delta,
+		}
+	}
+}
+
+/// What a [`ChannelManager`] does once one of its pending event queues reaches its limit.
+///
+/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EventQueueOverflowPolicy {
+	/// Stop starting new work which would generate further events, i.e. refuse to open channels,
+	/// accept inbound channels or send payments, until the queues drain below their limits.
+	/// Nothing which is already queued is dropped.
+	Block,
+	/// Drop queued gossip broadcasts (channel and node announcements and channel updates), oldest
+	/// first, until the [`MessageSendEvent`] queue is below its limit. Gossip is periodically
+	/// re-broadcast, so dropping it is harmless. If the queues are still full afterwards (e.g.
+	/// because the [`Event`] queue is full), we behave as in [`EventQueueOverflowPolicy::Block`].
+	///
+	/// [`MessageSendEvent`]: crate::util::events::MessageSendEvent
+	/// [`Event`]: crate::util::events::Event
+	DropGossipFirst,
+}
+
+/// Limits on the number of pending [`MessageSendEvent`]s and [`Event`]s a [`ChannelManager`] will
+/// queue up, ensuring a stalled event consumer can't cause memory usage to grow without bound.
+///
+/// Default::default() provides limits far beyond what a healthy node should ever queue.
+///
+/// [`MessageSendEvent`]: crate::util::events::MessageSendEvent
+/// [`Event`]: crate::util::events::Event
+/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EventQueueLimits {
+	/// The maximum number of [`MessageSendEvent`]s we queue before applying the
+	/// [`EventQueueLimits::overflow_policy`].
+	///
+	/// Default value: 10,000.
+	///
+	/// [`MessageSendEvent`]: crate::util::events::MessageSendEvent
+	pub max_pending_msg_events: usize,
+	/// The maximum number of [`Event`]s we queue before applying the
+	/// [`EventQueueLimits::overflow_policy`]. [`Event`]s are never dropped.
+	///
+	/// Default value: 10,000.
+	///
+	/// [`Event`]: crate::util::events::Event
+	pub max_pending_events: usize,
+	/// The percentage of a queue's limit at which we generate an
+	/// [`Event::PendingEventQueueThresholdCrossed`]. Another such event is only generated for the
+	/// same queue once it has dropped back below the threshold.
+	///
+	/// Default value: 80.
+	///
+	/// [`Event::PendingEventQueueThresholdCrossed`]: crate::util::events::Event::PendingEventQueueThresholdCrossed
+	pub warning_threshold_percent: u8,
+	/// What we do once a queue reaches its limit.
+	///
+	/// Default value: [`EventQueueOverflowPolicy::DropGossipFirst`].
+	pub overflow_policy: EventQueueOverflowPolicy,
+}
+
+impl Default for EventQueueLimits {
+	fn default() -> Self {
+		EventQueueLimits {
+			max_pending_msg_events: 10_000,
+			max_pending_events: 10_000,
+			warning_threshold_percent: 80,
+			overflow_policy: EventQueueOverflowPolicy::DropGossipFirst,
+		}
+	}
+}
+
+impl EventQueueLimits {
+	/// The queue length at or above which we warn for a queue with the given limit.
+	pub(crate) fn warning_threshold(&self, limit: usize) -> usize {
+		(limit as u64 * cmp::min(self.warning_threshold_percent, 100) as u64 / 100) as usize
+	}
+}
+
+/// Features we require a peer to support (as advertised in their `init` message) before we open
+/// channels to them, accept channels from them and, optionally, forward HTLCs to them.
+///
+/// Note that `option_anchors` is not yet supported by LDK, and thus cannot be required here.
+///
+/// Default::default() requires nothing beyond what LDK itself requires.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerFeatureRequirements {
+	/// If set, we require peers to support `option_static_remotekey`.
+	///
+	/// Default value: false.
+	pub require_static_remote_key: bool,
+	/// If set, we require peers to support `option_scid_alias` (referred to in LDK as
+	/// `scid_privacy`).
+	///
+	/// Default value: false.
+	pub require_scid_privacy: bool,
+	/// If set, we also refuse to forward HTLCs over channels with peers which do not support the
+	/// required features. Otherwise, the requirements only apply to new channels.
+	///
+	/// Default value: false.
+	pub enforce_on_forwards: bool,
+}
+
+impl PeerFeatureRequirements {
+	/// Returns the names of the features we require which `their_features` does not support, or
+	/// an empty `Vec` if the peer meets all of our requirements.
+	pub fn missing_features(&self, their_features: &InitFeatures) -> Vec<&'static str> {
+		let mut missing = Vec::new();
+		if self.require_static_remote_key && !their_features.supports_static_remote_key() {
+			missing.push("option_static_remotekey");
+		}
+		if self.require_scid_privacy && !their_features.supports_scid_privacy() {
+			missing.push("option_scid_alias");
+		}
+		missing
+	}
+}
+
+/// Thresholds at which a [`ChannelManager`] automatically places a channel on probation, refusing
+/// to forward any new HTLCs through it while still resolving the HTLCs already pending on it.
+///
+/// Strikes against a channel are counted over a window of [`ChannelProbationConfig::window_ticks`]
+/// calls to [`ChannelManager::timer_tick_occurred`], and the channel is placed on probation as
+/// soon as any one count reaches its threshold within a window. Probation can be lifted early via
+/// [`ChannelManager::end_channel_probation`].
+///
+/// Default::default() never places channels on probation.
+///
+/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+/// [`ChannelManager::timer_tick_occurred`]: crate::ln::channelmanager::ChannelManager::timer_tick_occurred
+/// [`ChannelManager::end_channel_probation`]: crate::ln::channelmanager::ChannelManager::end_channel_probation
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChannelProbationConfig {
+	/// The number of HTLCs we offer over a channel which the counterparty may fail within a window
+	/// before the channel is placed on probation, or `None` to not count HTLC failures.
+	///
+	/// Default value: None.
+	pub max_htlc_failures: Option<u32>,
+	/// The number of times the counterparty may disconnect from us within a window before the
+	/// channel is placed on probation, or `None` to not count disconnections.
+	///
+	/// Default value: None.
+	pub max_disconnects: Option<u32>,
+	/// The number of timer ticks within a window at which a channel our counterparty funded may
+	/// have a feerate below half of our [`Normal`] feerate estimate before the channel is placed
+	/// on probation, or `None` to not count stale feerates.
+	///
+	/// Default value: None.
+	///
+	/// [`Normal`]: crate::chain::chaininterface::ConfirmationTarget::Normal
+	pub max_stale_feerate_ticks: Option<u32>,
+	/// The number of timer ticks over which strikes against a channel are counted before being
+	/// reset.
+	///
+	/// Default value: 60 (roughly an hour).
+	pub window_ticks: u32,
+	/// The number of timer ticks a channel remains on probation for.
+	///
+	/// Default value: 1440 (roughly a day).
+	pub probation_ticks: u32,
+}
+
+impl Default for ChannelProbationConfig {
+	fn default() -> Self {
+		ChannelProbationConfig {
+			max_htlc_failures: None,
+			max_disconnects: None,
+			max_stale_feerate_ticks: None,
+			window_ticks: 60,
+			probation_ticks: 60 * 24,
+		}
+	}
+}
+
+/// The maximum number of thresholds which may be set in [`InboundVolumeConfig::thresholds_msat`].
+pub const MAX_INBOUND_VOLUME_THRESHOLDS: usize = 4;
+
+/// Thresholds on the total value of payments we claim within a window of timer ticks, at which
+/// we generate an [`Event::InboundVolumeThresholdReached`], allowing risk systems to react to
+/// unusual inbound volume without summing every [`Event::PaymentClaimed`] themselves.
+///
+/// The received value is only tracked in memory and starts from zero on restart.
+///
+/// Default::default() sets no thresholds, generating no events.
+///
+/// [`Event::InboundVolumeThresholdReached`]: crate::util::events::Event::InboundVolumeThresholdReached
+/// [`Event::PaymentClaimed`]: crate::util::events::Event::PaymentClaimed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InboundVolumeConfig {
+	/// The total values, in millisatoshis, received within a window at which we generate an
+	/// [`Event::InboundVolumeThresholdReached`]. Each threshold generates at most one event per
+	/// window. `None` entries are ignored.
+	///
+	/// Default value: no thresholds.
+	///
+	/// [`Event::InboundVolumeThresholdReached`]: crate::util::events::Event::InboundVolumeThresholdReached
+	pub thresholds_msat: [Option<u64>; MAX_INBOUND_VOLUME_THRESHOLDS],
+	/// The number of timer ticks over which received value is summed before being reset.
+	///
+	/// Default value: 60 (roughly an hour).
+	pub window_ticks: u32,
+}
+
+impl Default for InboundVolumeConfig {
+	fn default() -> Self {
+		InboundVolumeConfig {
+			thresholds_msat: [None; MAX_INBOUND_VOLUME_THRESHOLDS],
+			window_ticks: 60,
+		}
+	}
+}
+
+/// How far a payment amount may exceed the amount it was expected to be.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverpaymentPolicy {
+	/// The amount may not exceed the expected amount at all.
+	Exact,
+	/// The amount may exceed the expected amount by up to the given percentage of it.
+	UpToPercent(u16),
+	/// The amount may exceed the expected amount by any value.
+	Any,
+}
+
+impl OverpaymentPolicy {
+	/// Returns true if this policy allows `amount_msat` to be paid where `expected_amount_msat`
+	/// was expected. Amounts below `expected_amount_msat` are always allowed.
+	pub fn allows(&self, amount_msat: u64, expected_amount_msat: u64) -> bool {
+		match self {
+			OverpaymentPolicy::Exact => amount_msat <= expected_amount_msat,
+			OverpaymentPolicy::UpToPercent(percent) =>
+				amount_msat as u128 * 100 <= expected_amount_msat as u128 * (100 + *percent as u128),
+			OverpaymentPolicy::Any => true,
+		}
+	}
+}
+
+/// How much more than expected we accept when receiving payments, and how far we let our own
+/// payments overshoot their amount when split across multiple paths.
+///
+/// Default::default() accepts any overpayment of an invoice's amount, requires the parts of a
+/// multi-path payment to add up to exactly the total the sender declared, and lets retries of our
+/// own payments overshoot their amount by up to 10%.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PaymentAmountPolicy {
+	/// How far the total amount of an inbound payment may exceed the amount requested in the
+	/// invoice it pays. Payments for invoices without an amount are always accepted.
+	///
+	/// Note that this applies to all invoice payments we receive, as BOLT 12 offers are not yet
+	/// supported by LDK.
+	///
+	/// Default value: [`OverpaymentPolicy::Any`].
+	pub inbound_invoice_overpayment: OverpaymentPolicy,
+	/// How far the HTLCs of an inbound multi-path payment may add up to more than the total amount
+	/// the sender declared in its onion. Once the parts reach the declared total, any further
+	/// parts are failed back regardless of this setting.
+	///
+	/// Default value: [`OverpaymentPolicy::Exact`].
+	pub inbound_mpp_overshoot: OverpaymentPolicy,
+	/// How far the parts of an outbound multi-path payment, including any retries, may add up to
+	/// more than the payment's total amount. Retries via [`ChannelManager::retry_payment`] which
+	/// would exceed this are refused.
+	///
+	/// Default value: [`OverpaymentPolicy::UpToPercent`]`(10)`.
+	///
+	/// [`ChannelManager::retry_payment`]: crate::ln::channelmanager::ChannelManager::retry_payment
+	pub outbound_mpp_overshoot: OverpaymentPolicy,
+}
+
+impl Default for PaymentAmountPolicy {
+	fn default() -> Self {
+		PaymentAmountPolicy {
+			inbound_invoice_overpayment: OverpaymentPolicy::Any,
+			inbound_mpp_overshoot: OverpaymentPolicy::Exact,
+			outbound_mpp_overshoot: OverpaymentPolicy::UpToPercent(10),
+		}
+	}
+}
+
+/// Whether and at what cost we relay trampoline payments, which senders route through us to a
+/// node we find a route to ourselves, see [`Event::TrampolineForwardRequested`].
+///
+/// Default::default() does not relay trampoline payments.
+///
+/// [`Event::TrampolineForwardRequested`]: crate::util::events::Event::TrampolineForwardRequested
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrampolineForwardingConfig {
+	/// Whether we relay trampoline payments. If false, we fail them back as if we didn't understand
+	/// trampoline onions.
+	///
+	/// Default value: false.
+	pub enabled: bool,
+	/// The base fee, in millisatoshis, we charge for relaying a trampoline payment, on top of the
+	/// fees of the route we find to the next node.
+	///
+	/// Default value: 1000.
+	pub fee_base_msat: u32,
+	/// The fee we charge for relaying a trampoline payment, in millionths of the amount we relay,
+	/// on top of [`TrampolineForwardingConfig::fee_base_msat`].
+	///
+	/// Default value: 1000.
+	pub fee_proportional_millionths: u32,
+	/// The difference in CLTV value we require between an incoming trampoline payment and our
+	/// outgoing payment, on top of the CLTV deltas of the route we find to the next node.
+	///
+	/// Default value: 144 (roughly one day).
+	pub cltv_expiry_delta: u16,
+}
+
+impl TrampolineForwardingConfig {
+	/// Gets the fee, in millisatoshis, we charge for relaying `amount_msat` to the next node.
+	pub(crate) fn fee_msat(&self, amount_msat: u64) -> u64 {
+		(self.fee_base_msat as u64).saturating_add(
+			(amount_msat as u128 * self.fee_proportional_millionths as u128 / 1_000_000) as u64)
+	}
+}
+
+impl Default for TrampolineForwardingConfig {
+	fn default() -> Self {
+		TrampolineForwardingConfig {
+			enabled: false,
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 1000,
+			cltv_expiry_delta: 144,
+		}
+	}
+}
+
+/// Limits on the total fee we'll pay to claim a channel's outputs on-chain once it has been
+/// closed, set per channel via [`ChannelMonitor::set_claim_fee_budget`].
+///
+/// Fees are counted across all of our claim transactions for the channel, including those which
+/// have already confirmed, using the fee of the latest (i.e. most bumped) version of each. Our
+/// first claim transaction for a set of outputs is always broadcast, but once bumping a claim
+/// would take the total over the budget we leave it at its current feerate and generate an
+/// [`Event::ClaimFeeBudgetExhausted`] for manual intervention instead.
+///
+/// Only the fees of claims whose feerate we pick are counted, i.e. not those of pre-signed HTLC
+/// transactions on our own commitment transaction.
+///
+/// Default::default() sets no limits.
+///
+/// [`ChannelMonitor::set_claim_fee_budget`]: crate::chain::channelmonitor::ChannelMonitor::set_claim_fee_budget
+/// [`Event::ClaimFeeBudgetExhausted`]: crate::util::events::Event::ClaimFeeBudgetExhausted
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClaimFeeBudget {
+	/// The maximum total fee, in satoshis, or `None` for no absolute limit.
+	///
+	/// Default value: None.
+	pub max_total_fee_satoshis: Option<u64>,
+	/// The maximum total fee, as a percentage of the total value of the outputs being claimed, or
+	/// `None` for no relative limit.
+	///
+	/// If both this and [`ClaimFeeBudget::max_total_fee_satoshis`] are set, the lower of the two
+	/// applies.
+	///
+	/// Default value: None.
+	pub max_total_fee_percent_of_claimable: Option<u8>,
+}
+
+impl ClaimFeeBudget {
+	/// Gets the budget, in satoshis, when claiming outputs worth `claimable_satoshis` in total.
+	pub(crate) fn budget_satoshis(&self, claimable_satoshis: u64) -> u64 {
+		let relative_budget = self.max_total_fee_percent_of_claimable
+			.map(|percent| claimable_satoshis.saturating_mul(percent as u64) / 100);
+		match (self.max_total_fee_satoshis, relative_budget) {
+			(Some(absolute), Some(relative)) => cmp::min(absolute, relative),
+			(Some(absolute), None) => absolute,
+			(None, Some(relative)) => relative,
+			(None, None) => u64::max_value(),
+		}
+	}
+}
+
+impl_writeable_tlv_based!(ClaimFeeBudget, {
+	(1, max_total_fee_satoshis, option),
+	(3, max_total_fee_percent_of_claimable, option),
+});
+
+/// Limits on the onion messages an `OnionMessenger` forwards on behalf of its peers, bounding the
+/// bandwidth and memory onion message spam can consume.
+///
+/// Each peer may have messages forwarded at a rate replenished on each call to
+/// `OnionMessenger::timer_tick_occurred`, with bursts of up to
+/// [`OnionMessageForwardingConfig::max_burst_per_peer`] messages. Messages from peers exceeding
+/// their rate, or which would overflow our queues of messages to send, are dropped.
+///
+/// Default::default() allows on average one message per second from each peer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OnionMessageForwardingConfig {
+	/// The number of onion messages each peer may have forwarded per timer tick (i.e. roughly per
+	/// minute) on average.
+	///
+	/// Default value: 60.
+	pub max_forwards_per_tick_per_peer: u32,
+	/// The number of onion messages each peer may have forwarded at once after a period of
+	/// inactivity.
+	///
+	/// Default value: 120.
+	pub max_burst_per_peer: u32,
+	/// The maximum number of onion messages we queue to send to any one peer. Note that onion
+	/// messages are up to ~32KiB in size.
+	///
+	/// Default value: 32.
+	pub max_queued_per_peer: usize,
+	/// The maximum number of onion messages we queue to send across all peers, including those we
+	/// originate.
+	///
+	/// Default value: 256.
+	pub max_queued_total: usize,
+}
+
+impl Default for OnionMessageForwardingConfig {
+	fn default() -> Self {
+		OnionMessageForwardingConfig {
+			max_forwards_per_tick_per_peer: 60,
+			max_burst_per_peer: 120,
+			max_queued_per_peer: 32,
+			max_queued_total: 256,
+		}
+	}
+}
+
+/// The time budgets of a [`ForwardPolicyHandler`] deciding whether we forward HTLCs, and what we
+/// do with HTLCs it doesn't decide on in time.
+///
+/// Default::default() fails back HTLCs whose forward isn't decided on within one to two timer
+/// ticks.
+///
+/// [`ForwardPolicyHandler`]: crate::ln::channelmanager::ForwardPolicyHandler
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ForwardPolicyConfig {
+	/// The time, in milliseconds, a [`ForwardPolicyHandler`] may take to decide on a forward
+	/// synchronously. Decisions which take longer are discarded, and the HTLC is forwarded or failed
+	/// back as set by [`ForwardPolicyConfig::fail_open`].
+	///
+	/// Only enforced with the `std` feature, as we can't otherwise measure time.
+	///
+	/// Default value: 50.
+	///
+	/// [`ForwardPolicyHandler`]: crate::ln::channelmanager::ForwardPolicyHandler
+	pub max_decision_millis: u32,
+	/// The number of timer ticks a forward held by a [`ForwardPolicyHandler`] may remain held for
+	/// before being forwarded or failed back as set by [`ForwardPolicyConfig::fail_open`]. Note
+	/// that the previous hop may force-close our channel if we hold its HTLC for too long.
+	///
+	/// Default value: 2.
+	///
+	/// [`ForwardPolicyHandler`]: crate::ln::channelmanager::ForwardPolicyHandler
+	pub max_hold_ticks: u8,
+	/// Whether HTLCs whose forward isn't decided on in time are forwarded (fail-open) rather than
+	/// failed back (fail-closed).
+	///
+	/// Default value: false.
+	pub fail_open: bool,
+}
+
+impl Default for ForwardPolicyConfig {
+	fn default() -> Self {
+		ForwardPolicyConfig {
+			max_decision_millis: 50,
+			max_hold_ticks: 2,
+			fail_open: false,
+		}
+	}
+}
+
 /// Top-level config which holds ChannelHandshakeLimits and ChannelConfig.
 ///
 /// Default::default() provides sane defaults for most configurations
@@ -482,6 +1105,17 @@ pub struct UserConfig {
 	///
 	/// Default value: false.
 	pub accept_forwards_to_priv_channels: bool,
+	/// If this is set to true, an HTLC we're to forward over a channel which can't currently
+	/// carry it may instead be forwarded over another channel with the same peer which can, as
+	/// BOLT 4 permits. The channel with the most outbound liquidity available is picked.
+	///
+	/// Failures and [`Event::PaymentForwarded`] events for such HTLCs refer to the channel which
+	/// actually carried them rather than the one the sender requested.
+	///
+	/// Default value: false.
+	///
+	/// [`Event::PaymentForwarded`]: crate::util::events::Event::PaymentForwarded
+	pub nonstrict_forwarding: bool,
 	/// If this is set to false, we do not accept inbound requests to open a new channel.
 	/// Default value: true.
 	pub accept_inbound_channels: bool,
@@ -499,6 +1133,40 @@ pub struct UserConfig {
 	/// [`msgs::OpenChannel`]: crate::ln::msgs::OpenChannel
 	/// [`msgs::AcceptChannel`]: crate::ln::msgs::AcceptChannel
 	pub manually_accept_inbound_channels: bool,
+	/// If this is set to false, we fail back any keysend payments whose onion carries custom TLVs
+	/// (odd TLVs of a type at or above 2^16, such as podcast metadata) rather than accepting them.
+	///
+	/// Otherwise, such TLVs are provided in [`Event::PaymentReceived`]'s `custom_tlvs`, allowing
+	/// you to decide whether to accept each payment, claiming it with
+	/// [`ChannelManager::claim_funds`] or rejecting it with
+	/// [`ChannelManager::fail_htlc_backwards`].
+	///
+	/// Default value: true.
+	///
+	/// [`Event::PaymentReceived`]: crate::util::events::Event::PaymentReceived
+	/// [`ChannelManager::claim_funds`]: crate::ln::channelmanager::ChannelManager::claim_funds
+	/// [`ChannelManager::fail_htlc_backwards`]: crate::ln::channelmanager::ChannelManager::fail_htlc_backwards
+	pub accept_keysend_custom_tlvs: bool,
+	/// The CLTV limits applied when creating invoices, forwarding HTLCs and building routes.
+	pub cltv_policy: CltvPolicy,
+	/// Features we require our peers to support before opening or accepting channels with them, and
+	/// optionally before forwarding HTLCs to them.
+	pub peer_feature_requirements: PeerFeatureRequirements,
+	/// Limits on the number of events we queue up before applying backpressure.
+	pub event_queue_limits: EventQueueLimits,
+	/// When to automatically stop forwarding over channels whose counterparty misbehaves.
+	pub channel_probation: ChannelProbationConfig,
+	/// How far we let payment amounts exceed what was expected, both when receiving and sending.
+	pub payment_amount_policy: PaymentAmountPolicy,
+	/// When to notify us that the total value of payments received within a window is unusually
+	/// high.
+	pub inbound_volume: InboundVolumeConfig,
+	/// Whether and at what cost we relay trampoline payments.
+	pub trampoline_forwarding: TrampolineForwardingConfig,
+	/// The time budgets of our [`ForwardPolicyHandler`], if any.
+	///
+	/// [`ForwardPolicyHandler`]: crate::ln::channelmanager::ForwardPolicyHandler
+	pub forward_policy: ForwardPolicyConfig,
 }
 
 impl Default for UserConfig {
@@ -508,8 +1176,85 @@ impl Default for UserConfig {
 			channel_handshake_limits: ChannelHandshakeLimits::default(),
 			channel_config: ChannelConfig::default(),
 			accept_forwards_to_priv_channels: false,
+			nonstrict_forwarding: false,
 			accept_inbound_channels: true,
 			manually_accept_inbound_channels: false,
+			accept_keysend_custom_tlvs: true,
+			cltv_policy: CltvPolicy::default(),
+			peer_feature_requirements: PeerFeatureRequirements::default(),
+			event_queue_limits: EventQueueLimits::default(),
+			channel_probation: ChannelProbationConfig::default(),
+			payment_amount_policy: PaymentAmountPolicy::default(),
+			inbound_volume: InboundVolumeConfig::default(),
+			trampoline_forwarding: TrampolineForwardingConfig::default(),
+			forward_policy: ForwardPolicyConfig::default(),
+		}
+	}
+}
+
+/// Settings which override the [`UserConfig`] for channels with, and HTLCs forwarded to, a single
+/// peer.
+///
+/// Overrides are registered via [`ChannelManager::set_peer_config_overrides`] and are not
+/// persisted by the [`ChannelManager`], and thus must be registered again when deserializing it.
+///
+/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+/// [`ChannelManager::set_peer_config_overrides`]: crate::ln::channelmanager::ChannelManager::set_peer_config_overrides
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PeerConfigOverrides {
+	/// If set, the [`ChannelConfig`] used for channels with the peer in place of
+	/// [`UserConfig::channel_config`], including the fees we charge to forward HTLCs to the peer.
+	///
+	/// This is applied to new channels as well as to existing channels when the overrides are
+	/// registered.
+	///
+	/// Default value: None.
+	pub channel_config: Option<ChannelConfig>,
+	/// If set, overrides [`UserConfig::accept_inbound_channels`] for channels opened by the peer.
+	///
+	/// Default value: None.
+	pub accept_inbound_channels: Option<bool>,
+	/// If this is set to true, we trust the peer not to double-spend the funding transactions of
+	/// channels it opens with us, treating them as confirmed immediately.
+	///
+	/// Inbound channels from the peer are accepted as with
+	/// [`ChannelManager::accept_inbound_channel_from_trusted_peer_0conf`], including when they are
+	/// accepted automatically or via [`ChannelManager::accept_inbound_channel`].
+	///
+	/// Default value: false.
+	///
+	/// [`ChannelManager::accept_inbound_channel_from_trusted_peer_0conf`]: crate::ln::channelmanager::ChannelManager::accept_inbound_channel_from_trusted_peer_0conf
+	/// [`ChannelManager::accept_inbound_channel`]: crate::ln::channelmanager::ChannelManager::accept_inbound_channel
+	pub trusted_zero_conf: bool,
+	/// If set, the maximum number of channels with the peer whose funding has not yet been
+	/// initiated. New channels beyond this limit, whether opened by us or by the peer, are
+	/// rejected.
+	///
+	/// Default value: None.
+	pub max_pending_channels: Option<usize>,
+	/// If this is set to true, we trust the peer not to broadcast revoked states, and do not
+	/// require it to maintain a channel reserve in channels with it, allowing it to spend its full
+	/// balance. This is commonly used by LSPs for channels with their clients.
+	///
+	/// This is applied to new channels only, see
+	/// [`ChannelHandshakeConfig::their_channel_reserve_zero`].
+	///
+	/// Default value: false.
+	pub trusted_zero_reserve: bool,
+}
+
+impl PeerConfigOverrides {
+	/// Gets `config` with these overrides applied.
+	pub(crate) fn apply_to(&self, mut config: UserConfig) -> UserConfig {
+		if let Some(channel_config) = self.channel_config {
+			config.channel_config = channel_config;
+		}
+		if let Some(accept_inbound_channels) = self.accept_inbound_channels {
+			config.accept_inbound_channels = accept_inbound_channels;
+		}
+		if self.trusted_zero_reserve {
+			config.channel_handshake_config.their_channel_reserve_zero = true;
 		}
+		config
 	}
 }
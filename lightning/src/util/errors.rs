@@ -78,6 +78,107 @@ impl fmt::Debug for APIError {
 	}
 }
 
+impl ErrorClassification for APIError {
+	type Cause = APIErrorCause;
+
+	/// Returns one of the following codes:
+	///  * 1: [`APIError::APIMisuseError`]
+	///  * 2: [`APIError::FeeRateTooHigh`]
+	///  * 3: [`APIError::RouteError`]
+	///  * 4: [`APIError::ChannelUnavailable`]
+	///  * 5: [`APIError::MonitorUpdateFailed`]
+	///  * 6: [`APIError::IncompatibleShutdownScript`]
+	fn error_code(&self) -> u16 {
+		match *self {
+			APIError::APIMisuseError { .. } => 1,
+			APIError::FeeRateTooHigh { .. } => 2,
+			APIError::RouteError { .. } => 3,
+			APIError::ChannelUnavailable { .. } => 4,
+			APIError::MonitorUpdateFailed => 5,
+			APIError::IncompatibleShutdownScript { .. } => 6,
+		}
+	}
+
+	fn retryability(&self) -> Retryability {
+		match *self {
+			APIError::ChannelUnavailable { .. } => Retryability::Later,
+			APIError::MonitorUpdateFailed => Retryability::InProgress,
+			_ => Retryability::Never,
+		}
+	}
+
+	fn cause(&self) -> APIErrorCause {
+		match *self {
+			APIError::APIMisuseError { .. } => APIErrorCause::APIMisuse,
+			APIError::FeeRateTooHigh { feerate, .. } => APIErrorCause::FeeRateTooHigh { feerate },
+			APIError::RouteError { .. } => APIErrorCause::InvalidRoute,
+			APIError::ChannelUnavailable { .. } => APIErrorCause::ChannelUnavailable,
+			APIError::MonitorUpdateFailed => APIErrorCause::MonitorUpdateFailed,
+			APIError::IncompatibleShutdownScript { .. } => APIErrorCause::IncompatibleShutdownScript,
+		}
+	}
+}
+
+/// The cause of an [`APIError`], as returned by [`ErrorClassification::cause`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum APIErrorCause {
+	/// See [`APIError::APIMisuseError`].
+	APIMisuse,
+	/// See [`APIError::FeeRateTooHigh`].
+	FeeRateTooHigh {
+		/// The feerate which was too high.
+		feerate: u32,
+	},
+	/// See [`APIError::RouteError`].
+	InvalidRoute,
+	/// See [`APIError::ChannelUnavailable`].
+	ChannelUnavailable,
+	/// See [`APIError::MonitorUpdateFailed`].
+	MonitorUpdateFailed,
+	/// See [`APIError::IncompatibleShutdownScript`].
+	IncompatibleShutdownScript,
+}
+
+/// Whether a failed call may be retried, as returned by [`ErrorClassification::retryability`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Retryability {
+	/// Retrying the call with the same arguments will fail in the same way. The arguments or our
+	/// configuration must be changed first.
+	Never,
+	/// Retrying the call with the same arguments may succeed once the state of a channel, a peer
+	/// or the network graph has changed, e.g. after a peer reconnects.
+	Later,
+	/// The requested action has been at least partially taken and will complete without any
+	/// further calls. Retrying the call in full may duplicate it, e.g. resulting in an overpayment.
+	InProgress,
+}
+
+/// A common classification of the error types returned by our public APIs, allowing errors to be
+/// handled programmatically without matching on human-readable messages.
+///
+/// Implemented by [`APIError`], [`PaymentSendFailure`] and [`LightningError`], as well as the
+/// error types of the `lightning-invoice` crate.
+///
+/// [`PaymentSendFailure`]: crate::ln::channelmanager::PaymentSendFailure
+/// [`LightningError`]: crate::ln::msgs::LightningError
+pub trait ErrorClassification {
+	/// The typed cause of the error, see [`ErrorClassification::cause`].
+	type Cause;
+
+	/// A stable code identifying the kind of error, which is unique across all implementations.
+	///
+	/// Codes are grouped by type, and are documented on each implementation.
+	fn error_code(&self) -> u16;
+
+	/// Whether, and when, the call which returned this error may be retried.
+	fn retryability(&self) -> Retryability;
+
+	/// The cause of the error. For errors which wrap another, this includes the cause of the
+	/// wrapped error, allowing it to be matched on without unwrapping each layer in turn.
+	fn cause(&self) -> Self::Cause;
+}
+
 #[inline]
 pub(crate) fn get_onion_debug_field(error_code: u16) -> (&'static str, usize) {
 	match error_code & 0xff {
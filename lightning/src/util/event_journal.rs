@@ -0,0 +1,451 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! An append-only journal of the [`Event`]s our node has generated, for audit trails and for
+//! debugging incidents after the fact.
+//!
+//! An [`EventJournal`] is fed each [`Event`] as it is handled and writes it, along with a stable
+//! [`EventId`] and the time it was seen, to a [`KVStorePersister`]. Entries are grouped into
+//! [`JournalSegment`]s of bounded size which are rotated out according to the journal's
+//! [`EventJournalConfig`]. Events which are handled more than once (e.g. because we restarted
+//! before the [`ChannelManager`] was persisted) are only journaled once.
+//!
+//! The journal is laid out in the store as follows:
+//!  * "event_journal/manifest" holds an [`EventJournalManifest`] listing the live segments,
+//!  * "event_journal/{index}" holds the [`JournalSegment`] with the given index.
+//!
+//! Segments which are no longer listed in the manifest have been rotated out and may be deleted.
+//!
+//! [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+use ln::msgs::DecodeError;
+use util::events::{Event, EventHandler};
+use util::persist::KVStorePersister;
+use util::ser::{MaybeReadable, Readable, Writeable, Writer};
+
+use io::{self, Cursor};
+use prelude::*;
+use sync::Mutex;
+use core::ops::Deref;
+
+/// The key under which the [`EventJournalManifest`] is stored.
+pub const EVENT_JOURNAL_MANIFEST_KEY: &str = "event_journal/manifest";
+
+/// Configuration for an [`EventJournal`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventJournalConfig {
+	/// The number of entries after which a new segment is started.
+	///
+	/// As the whole of the current segment is rewritten each time an event is journaled, this
+	/// bounds the cost of each write.
+	///
+	/// Default value: 256.
+	pub max_entries_per_segment: usize,
+	/// The number of segments to retain, after which the oldest is rotated out.
+	///
+	/// Default value: 64.
+	pub max_segments: usize,
+	/// If set, segments whose newest entry is older than this many seconds are rotated out. The
+	/// segment currently being written to is never rotated out.
+	///
+	/// Default value: None.
+	pub retention_secs: Option<u64>,
+}
+
+impl Default for EventJournalConfig {
+	fn default() -> Self {
+		EventJournalConfig {
+			max_entries_per_segment: 256,
+			max_segments: 64,
+			retention_secs: None,
+		}
+	}
+}
+
+/// A stable identifier for an [`Event`], the SHA-256 of its serialization.
+///
+/// Two events with the same contents will have the same id, and are thus only journaled once.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct EventId(pub [u8; 32]);
+
+impl EventId {
+	fn from_encoded_event(encoded_event: &[u8]) -> Self {
+		EventId(Sha256::hash(encoded_event).into_inner())
+	}
+}
+
+impl Writeable for EventId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.0.write(writer)
+	}
+}
+
+impl Readable for EventId {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(EventId(Readable::read(reader)?))
+	}
+}
+
+/// A single journaled [`Event`].
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+	/// The id of the event.
+	pub id: EventId,
+	/// The position of the entry in the journal, increasing by one for each entry.
+	pub sequence_number: u64,
+	/// The time at which the event was journaled, in seconds since the UNIX epoch.
+	pub timestamp: u64,
+	/// The journaled event.
+	pub event: Event,
+}
+
+impl Writeable for JournalEntry {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let encoded_event = self.event.encode();
+		write_tlv_fields!(writer, {
+			(0, self.id, required),
+			(2, self.sequence_number, required),
+			(4, self.timestamp, required),
+			(6, encoded_event, vec_type),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for JournalEntry {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut id = EventId([0; 32]);
+		let mut sequence_number = 0;
+		let mut timestamp = 0;
+		let mut encoded_event: Option<Vec<u8>> = Some(Vec::new());
+		read_tlv_fields!(reader, {
+			(0, id, required),
+			(2, sequence_number, required),
+			(4, timestamp, required),
+			(6, encoded_event, vec_type),
+		});
+		let event = match MaybeReadable::read(&mut Cursor::new(&encoded_event.unwrap()))? {
+			Some(event) => event,
+			None => return Err(DecodeError::InvalidValue),
+		};
+		Ok(JournalEntry { id, sequence_number, timestamp, event })
+	}
+}
+
+/// A group of consecutive [`JournalEntry`]s, stored under "event_journal/{index}".
+#[derive(Clone, Debug)]
+pub struct JournalSegment {
+	/// The index of this segment, increasing by one for each new segment.
+	pub index: u64,
+	/// The entries in this segment, in the order they were journaled.
+	pub entries: Vec<JournalEntry>,
+}
+
+impl JournalSegment {
+	/// The key this segment is stored under.
+	pub fn key(&self) -> String {
+		format!("event_journal/{}", self.index)
+	}
+}
+
+impl_writeable_tlv_based!(JournalSegment, {
+	(0, index, required),
+	(2, entries, vec_type),
+});
+
+/// The list of segments currently making up an [`EventJournal`], stored under
+/// [`EVENT_JOURNAL_MANIFEST_KEY`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventJournalManifest {
+	/// The indices of the live segments, oldest first.
+	pub segment_indices: Vec<u64>,
+}
+
+impl EventJournalManifest {
+	/// The keys of the live segments, oldest first.
+	pub fn segment_keys(&self) -> Vec<String> {
+		self.segment_indices.iter().map(|index| format!("event_journal/{}", index)).collect()
+	}
+}
+
+impl_writeable_tlv_based!(EventJournalManifest, {
+	(0, segment_indices, vec_type),
+});
+
+struct JournalState {
+	segments: VecDeque<JournalSegment>,
+	journaled_ids: HashSet<EventId>,
+	next_sequence_number: u64,
+}
+
+impl JournalState {
+	fn manifest(&self) -> EventJournalManifest {
+		EventJournalManifest { segment_indices: self.segments.iter().map(|segment| segment.index).collect() }
+	}
+
+	/// Rotates out segments as required by `config`, returning whether any were removed.
+	fn apply_retention(&mut self, config: &EventJournalConfig, now: u64) -> bool {
+		let mut rotated = false;
+		while self.segments.len() > 1 {
+			let front = self.segments.front().unwrap();
+			let expired = match (config.retention_secs, front.entries.last()) {
+				(Some(retention_secs), Some(newest)) => newest.timestamp.saturating_add(retention_secs) < now,
+				(_, None) => true,
+				_ => false,
+			};
+			if !expired && self.segments.len() <= config.max_segments { break; }
+			let segment = self.segments.pop_front().unwrap();
+			for entry in segment.entries.iter() {
+				self.journaled_ids.remove(&entry.id);
+			}
+			rotated = true;
+		}
+		rotated
+	}
+}
+
+/// Journals [`Event`]s to a [`KVStorePersister`].
+///
+/// [`EventJournal::record_event`] should be called from within your [`EventHandler`] for each
+/// event, or the journal may wrap another handler via [`EventJournal::handler`].
+pub struct EventJournal<P: Deref> where P::Target: KVStorePersister {
+	persister: P,
+	config: EventJournalConfig,
+	state: Mutex<JournalState>,
+}
+
+impl<P: Deref> EventJournal<P> where P::Target: KVStorePersister {
+	/// Creates a new, empty, journal.
+	pub fn new(persister: P, config: EventJournalConfig) -> Self {
+		Self::from_segments(persister, config, Vec::new())
+	}
+
+	/// Restores a journal from the segments listed in its persisted [`EventJournalManifest`], in
+	/// the order listed there.
+	pub fn from_segments(persister: P, config: EventJournalConfig, segments: Vec<JournalSegment>) -> Self {
+		let mut journaled_ids = HashSet::new();
+		let mut next_sequence_number = 0;
+		for segment in segments.iter() {
+			for entry in segment.entries.iter() {
+				journaled_ids.insert(entry.id);
+				next_sequence_number = core::cmp::max(next_sequence_number, entry.sequence_number + 1);
+			}
+		}
+		Self {
+			persister,
+			config,
+			state: Mutex::new(JournalState { segments: segments.into_iter().collect(), journaled_ids, next_sequence_number }),
+		}
+	}
+
+	/// Journals `event` as having been seen at `timestamp` (in seconds since the UNIX epoch),
+	/// returning its [`EventId`].
+	///
+	/// Returns `Ok(None)` if the event has already been journaled or is one which is never
+	/// serialized (e.g. [`Event::OpenChannelRequest`]) and thus cannot be journaled. If writing to
+	/// the store fails, the event is not journaled and the error is returned.
+	pub fn record_event(&self, event: &Event, timestamp: u64) -> Result<Option<EventId>, io::Error> {
+		let encoded_event = event.encode();
+		let event: Event = match MaybeReadable::read(&mut Cursor::new(&encoded_event)) {
+			Ok(Some(event)) => event,
+			_ => return Ok(None),
+		};
+		let id = EventId::from_encoded_event(&encoded_event);
+
+		let mut state = self.state.lock().unwrap();
+		if state.journaled_ids.contains(&id) { return Ok(None); }
+
+		let start_segment = match state.segments.back() {
+			Some(segment) => segment.entries.len() >= self.config.max_entries_per_segment,
+			None => true,
+		};
+		if start_segment {
+			let index = state.segments.back().map(|segment| segment.index + 1).unwrap_or(0);
+			state.segments.push_back(JournalSegment { index, entries: Vec::new() });
+		}
+		let sequence_number = state.next_sequence_number;
+		state.segments.back_mut().unwrap().entries.push(JournalEntry { id, sequence_number, timestamp, event });
+
+		let segment = state.segments.back().unwrap();
+		if let Err(e) = self.persister.persist(&segment.key(), segment) {
+			if start_segment {
+				state.segments.pop_back();
+			} else {
+				state.segments.back_mut().unwrap().entries.pop();
+			}
+			return Err(e);
+		}
+		state.journaled_ids.insert(id);
+		state.next_sequence_number += 1;
+
+		let rotated = state.apply_retention(&self.config, timestamp);
+		if start_segment || rotated {
+			self.persister.persist(EVENT_JOURNAL_MANIFEST_KEY, &state.manifest())?;
+		}
+		Ok(Some(id))
+	}
+
+	/// Returns the manifest listing the journal's live segments.
+	pub fn manifest(&self) -> EventJournalManifest {
+		self.state.lock().unwrap().manifest()
+	}
+
+	/// Returns all journaled entries with a timestamp in the range `[from, to)` (in seconds since
+	/// the UNIX epoch), oldest first.
+	pub fn entries_in_range(&self, from: u64, to: u64) -> Vec<JournalEntry> {
+		let state = self.state.lock().unwrap();
+		state.segments.iter().flat_map(|segment| segment.entries.iter())
+			.filter(|entry| entry.timestamp >= from && entry.timestamp < to)
+			.cloned().collect()
+	}
+
+	/// Returns the journaled entry with the given id, if it hasn't been rotated out.
+	pub fn get_entry(&self, id: &EventId) -> Option<JournalEntry> {
+		let state = self.state.lock().unwrap();
+		if !state.journaled_ids.contains(id) { return None; }
+		state.segments.iter().flat_map(|segment| segment.entries.iter())
+			.find(|entry| entry.id == *id).cloned()
+	}
+
+	/// Passes each journaled event with a sequence number of at least `from_sequence_number` to
+	/// `handler`, oldest first.
+	///
+	/// Note that the events are handed to `handler` exactly as they were originally generated, so
+	/// this should generally only be used with handlers built for debugging.
+	pub fn replay<H: EventHandler>(&self, from_sequence_number: u64, handler: &H) {
+		let entries: Vec<JournalEntry> = {
+			let state = self.state.lock().unwrap();
+			state.segments.iter().flat_map(|segment| segment.entries.iter())
+				.filter(|entry| entry.sequence_number >= from_sequence_number)
+				.cloned().collect()
+		};
+		for entry in entries {
+			handler.handle_event(&entry.event);
+		}
+	}
+
+	/// Returns an [`EventHandler`] which journals each event using the timestamp returned by
+	/// `now` before passing it to `handler`.
+	///
+	/// Failures to write to the store are ignored so that event handling is never blocked on the
+	/// journal.
+	pub fn handler<'a, H: EventHandler, T: Fn() -> u64>(&'a self, handler: H, now: T) -> JournalingEventHandler<'a, P, H, T> {
+		JournalingEventHandler { journal: self, handler, now }
+	}
+}
+
+/// An [`EventHandler`] which journals events before passing them on, see
+/// [`EventJournal::handler`].
+pub struct JournalingEventHandler<'a, P: Deref, H: EventHandler, T: Fn() -> u64> where P::Target: KVStorePersister {
+	journal: &'a EventJournal<P>,
+	handler: H,
+	now: T,
+}
+
+impl<'a, P: Deref, H: EventHandler, T: Fn() -> u64> EventHandler for JournalingEventHandler<'a, P, H, T> where P::Target: KVStorePersister {
+	fn handle_event(&self, event: &Event) {
+		let _ = self.journal.record_event(event, (self.now)());
+		self.handler.handle_event(event);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{EventJournal, EventJournalConfig, EventJournalManifest, JournalSegment, EVENT_JOURNAL_MANIFEST_KEY};
+
+	use ln::PaymentHash;
+	use ln::channelmanager::PaymentId;
+	use util::events::{ClosureReason, Event, EventHandler};
+	use util::persist::KVStorePersister;
+	use util::ser::{Readable, Writeable};
+
+	use io::{self, Cursor};
+	use prelude::*;
+	use sync::Mutex;
+	use core::time::Duration;
+
+	struct TestStore {
+		entries: Mutex<HashMap<String, Vec<u8>>>,
+		fail_writes: Mutex<bool>,
+	}
+
+	impl KVStorePersister for TestStore {
+		fn persist<W: Writeable>(&self, key: &str, object: &W) -> io::Result<()> {
+			if *self.fail_writes.lock().unwrap() {
+				return Err(io::Error::new(io::ErrorKind::Other, "write failed"));
+			}
+			self.entries.lock().unwrap().insert(key.to_string(), object.encode());
+			Ok(())
+		}
+	}
+
+	impl TestStore {
+		fn read<R: Readable>(&self, key: &str) -> R {
+			let entries = self.entries.lock().unwrap();
+			Readable::read(&mut Cursor::new(entries.get(key).unwrap())).unwrap()
+		}
+	}
+
+	fn closed_event(id: u8) -> Event {
+		Event::ChannelClosed { channel_id: [id; 32], user_channel_id: 0, reason: ClosureReason::CooperativeClosure }
+	}
+
+	#[test]
+	fn journals_rotates_and_restores_events() {
+		let store = TestStore { entries: Mutex::new(HashMap::new()), fail_writes: Mutex::new(false) };
+		let config = EventJournalConfig { max_entries_per_segment: 2, max_segments: 2, retention_secs: Some(1000) };
+		let journal = EventJournal::new(&store, config);
+
+		let first_id = journal.record_event(&closed_event(0), 100).unwrap().unwrap();
+		// Duplicates and events which are never serialized aren't journaled.
+		assert!(journal.record_event(&closed_event(0), 101).unwrap().is_none());
+		assert!(journal.record_event(&Event::PendingHTLCsForwardable { time_forwardable: Duration::from_secs(1) }, 101).unwrap().is_none());
+		assert_eq!(journal.get_entry(&first_id).unwrap().timestamp, 100);
+
+		journal.record_event(&closed_event(1), 110).unwrap().unwrap();
+		journal.record_event(&Event::PaymentFailed { payment_id: PaymentId([2; 32]), payment_hash: PaymentHash([2; 32]) }, 120).unwrap().unwrap();
+		assert_eq!(journal.manifest().segment_indices, vec![0, 1]);
+
+		// A failed write leaves the journal untouched.
+		*store.fail_writes.lock().unwrap() = true;
+		assert!(journal.record_event(&closed_event(3), 130).is_err());
+		*store.fail_writes.lock().unwrap() = false;
+		assert_eq!(journal.entries_in_range(0, 1000).len(), 3);
+
+		// Filling a third segment rotates the first out, allowing its events to be journaled again.
+		journal.record_event(&closed_event(3), 130).unwrap().unwrap();
+		journal.record_event(&closed_event(4), 140).unwrap().unwrap();
+		assert_eq!(journal.manifest().segment_indices, vec![1, 2]);
+		assert!(journal.get_entry(&first_id).is_none());
+		assert_eq!(journal.entries_in_range(115, 135).len(), 2);
+
+		// Restoring from the store gives the same journal, continuing its sequence numbers.
+		let manifest: EventJournalManifest = store.read(EVENT_JOURNAL_MANIFEST_KEY);
+		assert_eq!(manifest, journal.manifest());
+		let segments: Vec<JournalSegment> = manifest.segment_keys().iter().map(|key| store.read(key)).collect();
+		let restored = EventJournal::from_segments(&store, config, segments);
+		assert!(restored.record_event(&closed_event(4), 150).unwrap().is_none());
+		let replayed = Mutex::new(Vec::new());
+		restored.replay(3, &|event: &Event| replayed.lock().unwrap().push(event.encode()));
+		assert_eq!(*replayed.lock().unwrap(), vec![closed_event(3).encode(), closed_event(4).encode()]);
+
+		// Segments older than the retention period are rotated out once a new event comes in, other
+		// than the one still being written to.
+		let handled = Mutex::new(0);
+		let handler = restored.handler(|_: &Event| *handled.lock().unwrap() += 1, || 2000);
+		handler.handle_event(&closed_event(5));
+		assert_eq!(*handled.lock().unwrap(), 1);
+		let entries = restored.entries_in_range(0, u64::max_value());
+		assert_eq!(entries.iter().map(|entry| entry.sequence_number).collect::<Vec<_>>(), vec![4, 5]);
+		assert_eq!(restored.manifest().segment_indices, vec![2]);
+	}
+}
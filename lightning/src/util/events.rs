@@ -15,17 +15,20 @@
 //! few other things.
 
 use chain::keysinterface::SpendableOutputDescriptor;
-use ln::channelmanager::PaymentId;
+use chain::transaction::OutPoint;
+use ln::channelmanager::{NodeRole, PaymentId};
 use ln::channel::FUNDING_CONF_DEADLINE_BLOCKS;
 use ln::features::ChannelTypeFeatures;
 use ln::msgs;
 use ln::msgs::DecodeError;
 use ln::{PaymentPreimage, PaymentHash, PaymentSecret};
 use routing::gossip::NetworkUpdate;
-use util::ser::{BigSize, FixedLengthReader, Writeable, Writer, MaybeReadable, Readable, VecReadWrapper, VecWriteWrapper};
+use util::ser::{BigSize, FixedLengthReader, Writeable, Writer, MaybeReadable, OptionDeserWrapper, Readable, VecReadWrapper, VecWriteWrapper};
 use routing::router::{RouteHop, RouteParameters};
 
-use bitcoin::{PackedLockTime, Transaction};
+use bitcoin::{PackedLockTime, Transaction, TxOut};
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
 use bitcoin::blockdata::script::Script;
 use bitcoin::hashes::Hash;
 use bitcoin::hashes::sha256::Hash as Sha256;
@@ -116,7 +119,11 @@ pub enum ClosureReason {
 	DisconnectedPeer,
 	/// Closure generated from `ChannelManager::read` if the ChannelMonitor is newer than
 	/// the ChannelManager deserialized.
-	OutdatedChannelManager
+	OutdatedChannelManager,
+	/// The [`FundingIntent`] of an outbound channel expired before the channel was funded.
+	///
+	/// [`FundingIntent`]: crate::ln::channelmanager::FundingIntent
+	FundingIntentExpired,
 }
 
 impl core::fmt::Display for ClosureReason {
@@ -137,6 +144,7 @@ impl core::fmt::Display for ClosureReason {
 			},
 			ClosureReason::DisconnectedPeer => f.write_str("the peer disconnected prior to the channel being funded"),
 			ClosureReason::OutdatedChannelManager => f.write_str("the ChannelManager read from disk was stale compared to ChannelMonitor(s)"),
+			ClosureReason::FundingIntentExpired => f.write_str("the channel was not funded before its funding intent expired"),
 		}
 	}
 }
@@ -150,6 +158,7 @@ impl_writeable_tlv_based_enum_upgradable!(ClosureReason,
 	(8, ProcessingError) => { (1, err, required) },
 	(10, DisconnectedPeer) => {},
 	(12, OutdatedChannelManager) => {},
+	(13, FundingIntentExpired) => {},
 );
 
 /// Intended destination of a failed HTLC as indicated in [`Event::HTLCHandlingFailed`].
@@ -196,6 +205,18 @@ impl_writeable_tlv_based_enum_upgradable!(HTLCDestination,
 	}
 );
 
+/// Identifies one of the pending event queues of a [`ChannelManager`].
+///
+/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PendingEventQueue {
+	/// The queue of [`MessageSendEvent`]s, drained via
+	/// [`MessageSendEventsProvider::get_and_clear_pending_msg_events`].
+	MessageSendEvents,
+	/// The queue of [`Event`]s, drained via [`EventsProvider::process_pending_events`].
+	Events,
+}
+
 /// An Event which you should probably take some action in response to.
 ///
 /// Note that while Writeable and Readable are implemented for Event, you probably shouldn't use
@@ -257,6 +278,15 @@ pub enum Event {
 		/// Information for claiming this received payment, based on whether the purpose of the
 		/// payment is to pay an invoice or to send a spontaneous payment.
 		purpose: PaymentPurpose,
+		/// Custom TLVs the payer included in the onion of a spontaneous payment, sorted by type.
+		///
+		/// These are odd TLVs of a type at or above 2^16, such as the metadata included by
+		/// podcasting apps, which you may use to decide whether to claim the payment. They are
+		/// always empty for invoice payments, and spontaneous payments including them are
+		/// rejected unless [`UserConfig::accept_keysend_custom_tlvs`] is set.
+		///
+		/// [`UserConfig::accept_keysend_custom_tlvs`]: crate::util::config::UserConfig::accept_keysend_custom_tlvs
+		custom_tlvs: Vec<(u64, Vec<u8>)>,
 	},
 	/// Indicates a payment has been claimed and we've received money!
 	///
@@ -532,6 +562,39 @@ pub enum Event {
 		/// The full transaction received from the user
 		transaction: Transaction
 	},
+	/// Indicates that the funding transaction of one of our outbound channels failed to confirm
+	/// within [`ChannelHandshakeConfig::outbound_funding_timeout_blocks`] and the channel has been
+	/// abandoned.
+	///
+	/// As the funding transaction never confirmed, no commitment transaction was broadcast when
+	/// closing the channel. Instead, the wallet should double-spend at least one of the
+	/// [`Event::FundingTimedOut::funding_inputs`] (e.g. back to itself), after which the funding
+	/// transaction can never confirm and the remaining inputs are free to be reused.
+	///
+	/// If the funding transaction does confirm before it is double-spent, the funds in the channel
+	/// may only be recovered by broadcasting our latest commitment transaction, as returned by
+	/// [`ChannelMonitor::get_latest_holder_commitment_txn`], or by the counterparty closing the
+	/// channel. Thus, it is important to double-spend the funding transaction promptly.
+	///
+	/// This event is always followed by an [`Event::ChannelClosed`] with a reason of
+	/// [`ClosureReason::FundingTimedOut`].
+	///
+	/// [`ChannelHandshakeConfig::outbound_funding_timeout_blocks`]: crate::util::config::ChannelHandshakeConfig::outbound_funding_timeout_blocks
+	/// [`ChannelMonitor::get_latest_holder_commitment_txn`]: crate::chain::channelmonitor::ChannelMonitor::get_latest_holder_commitment_txn
+	FundingTimedOut {
+		/// The channel_id of the channel which timed out.
+		channel_id: [u8; 32],
+		/// The `user_channel_id` value passed in to [`ChannelManager::create_channel`].
+		///
+		/// [`ChannelManager::create_channel`]: crate::ln::channelmanager::ChannelManager::create_channel
+		user_channel_id: u64,
+		/// The outpoint of the channel's funding output, if the funding transaction was created.
+		funding_txo: Option<OutPoint>,
+		/// The outpoints spent by the funding transaction, which are safe to double-spend. Empty
+		/// if we no longer have a copy of the funding transaction, in which case the wallet must
+		/// look its inputs up using the txid in [`Event::FundingTimedOut::funding_txo`].
+		funding_inputs: Vec<BitcoinOutPoint>,
+	},
 	/// Indicates a request to open a new channel by a peer.
 	///
 	/// To accept the request, call [`ChannelManager::accept_inbound_channel`]. To reject the
@@ -602,6 +665,172 @@ pub enum Event {
 		/// Destination of the HTLC that failed to be processed.
 		failed_next_destination: HTLCDestination,
 	},
+	/// Indicates that one of our pending event queues has grown past the warning threshold set in
+	/// [`EventQueueLimits::warning_threshold_percent`], likely because events are not being
+	/// processed quickly enough.
+	///
+	/// Once the queue reaches its limit, the [`EventQueueLimits::overflow_policy`] is applied.
+	///
+	/// [`EventQueueLimits::warning_threshold_percent`]: crate::util::config::EventQueueLimits::warning_threshold_percent
+	/// [`EventQueueLimits::overflow_policy`]: crate::util::config::EventQueueLimits::overflow_policy
+	PendingEventQueueThresholdCrossed {
+		/// The queue which crossed the threshold.
+		queue: PendingEventQueue,
+		/// The number of entries pending in the queue when the threshold was crossed.
+		pending: usize,
+		/// The configured limit for the queue.
+		limit: usize,
+	},
+	/// Indicates that the total value of payments we've claimed within the current window has
+	/// reached one of the thresholds set in [`InboundVolumeConfig::thresholds_msat`].
+	///
+	/// If a single payment crosses several thresholds, one event is generated for each of them.
+	///
+	/// [`InboundVolumeConfig::thresholds_msat`]: crate::util::config::InboundVolumeConfig::thresholds_msat
+	InboundVolumeThresholdReached {
+		/// The threshold which was reached, in millisatoshis.
+		threshold_msat: u64,
+		/// The total value of payments claimed within the current window, in millisatoshis,
+		/// including the payment which caused the threshold to be reached.
+		received_msat: u64,
+		/// The number of payments claimed within the current window.
+		payment_count: u64,
+		/// The length of the window, in timer ticks, as set in
+		/// [`InboundVolumeConfig::window_ticks`].
+		///
+		/// [`InboundVolumeConfig::window_ticks`]: crate::util::config::InboundVolumeConfig::window_ticks
+		window_ticks: u32,
+	},
+	/// Indicates that an output paying to a script registered via [`ChainMonitor::watch_script`]
+	/// was confirmed on chain.
+	///
+	/// Spends of the output are watched for from then on, resulting in a
+	/// [`Event::WatchedOutputSpent`] once it is spent.
+	///
+	/// [`ChainMonitor::watch_script`]: crate::chain::chainmonitor::ChainMonitor::watch_script
+	WatchedOutputConfirmed {
+		/// The confirmed output.
+		outpoint: OutPoint,
+		/// The value and script of the output.
+		output: TxOut,
+		/// The hash of the block the output was confirmed in.
+		block_hash: BlockHash,
+		/// The height of the block the output was confirmed in.
+		height: u32,
+	},
+	/// Indicates that a transaction spending an output registered via
+	/// [`ChainMonitor::watch_outpoint`] (or reported in a [`Event::WatchedOutputConfirmed`]) was
+	/// confirmed on chain.
+	///
+	/// [`ChainMonitor::watch_outpoint`]: crate::chain::chainmonitor::ChainMonitor::watch_outpoint
+	WatchedOutputSpent {
+		/// The spent output.
+		outpoint: OutPoint,
+		/// The transaction spending the output, e.g. to extract a payment preimage from its
+		/// witness.
+		spending_tx: Transaction,
+		/// The hash of the block the spending transaction was confirmed in.
+		block_hash: BlockHash,
+		/// The height of the block the spending transaction was confirmed in.
+		height: u32,
+	},
+	/// Indicates that a transaction previously reported in a [`Event::WatchedOutputConfirmed`] or
+	/// [`Event::WatchedOutputSpent`] was reorganized out of the chain.
+	///
+	/// Should the transaction be confirmed again, a new event will be generated for it.
+	WatchedTransactionUnconfirmed {
+		/// The txid of the unconfirmed transaction.
+		txid: Txid,
+	},
+	/// Indicates that we've stopped bumping the feerates of our on-chain claim transactions for a
+	/// closed channel, as doing so would exceed the [`ClaimFeeBudget`] set for it via
+	/// [`ChannelMonitor::set_claim_fee_budget`].
+	///
+	/// Our claim transactions remain broadcast at their current feerates, but may not confirm
+	/// before our counterparty can contest them. Manual intervention, such as raising the budget
+	/// or fee-bumping the claims via CPFP, may be required.
+	///
+	/// Only one such event is generated until the budget is changed or we restart.
+	///
+	/// [`ClaimFeeBudget`]: crate::util::config::ClaimFeeBudget
+	/// [`ChannelMonitor::set_claim_fee_budget`]: crate::chain::channelmonitor::ChannelMonitor::set_claim_fee_budget
+	ClaimFeeBudgetExhausted {
+		/// The channel whose outputs are being claimed.
+		channel_id: [u8; 32],
+		/// The total fee, in satoshis, of our claim transactions for the channel so far.
+		total_fee_satoshis: u64,
+		/// The fee, in satoshis, which the refused fee bump would have brought the total to.
+		attempted_total_fee_satoshis: u64,
+		/// The channel's claim fee budget, in satoshis.
+		budget_satoshis: u64,
+	},
+	/// Indicates that the [`ChannelManager`]'s [`NodeRole`] has changed, i.e. that it has been
+	/// promoted to be the node's active instance via [`ChannelManager::promote`] or has stepped
+	/// down to be a standby via [`ChannelManager::step_down`].
+	///
+	/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+	/// [`ChannelManager::promote`]: crate::ln::channelmanager::ChannelManager::promote
+	/// [`ChannelManager::step_down`]: crate::ln::channelmanager::ChannelManager::step_down
+	RoleChanged {
+		/// Our new role.
+		role: NodeRole,
+	},
+	/// Indicates that we've received a trampoline payment to relay, for which we must find a route
+	/// to `next_node_id`, e.g. via [`find_route`] with the given `route_params`, before relaying it
+	/// via [`ChannelManager::forward_trampoline_payment`].
+	///
+	/// Alternatively, the payment may be failed back via
+	/// [`ChannelManager::fail_trampoline_forward`], which happens automatically if it isn't
+	/// forwarded before it nears its expiry.
+	///
+	/// This is only generated if [`TrampolineForwardingConfig::enabled`] is set.
+	///
+	/// [`find_route`]: crate::routing::router::find_route
+	/// [`ChannelManager::forward_trampoline_payment`]: crate::ln::channelmanager::ChannelManager::forward_trampoline_payment
+	/// [`ChannelManager::fail_trampoline_forward`]: crate::ln::channelmanager::ChannelManager::fail_trampoline_forward
+	/// [`TrampolineForwardingConfig::enabled`]: crate::util::config::TrampolineForwardingConfig::enabled
+	TrampolineForwardRequested {
+		/// The hash of the payment to relay.
+		payment_hash: PaymentHash,
+		/// The node to relay the payment to, which is either the next trampoline node or the
+		/// payment's final recipient.
+		next_node_id: PublicKey,
+		/// The parameters for finding a route to `next_node_id`, delivering the amount the sender
+		/// instructed us to and limiting the route's total fee and CLTV delta to what remains of
+		/// the sender's fee and CLTV budget for us after our own fee and
+		/// [`TrampolineForwardingConfig::cltv_expiry_delta`].
+		///
+		/// [`TrampolineForwardingConfig::cltv_expiry_delta`]: crate::util::config::TrampolineForwardingConfig::cltv_expiry_delta
+		route_params: RouteParameters,
+	},
+	/// Indicates that a trampoline payment we relayed via
+	/// [`ChannelManager::forward_trampoline_payment`] has been claimed by the next node, and that
+	/// we've claimed the HTLCs we received for it.
+	///
+	/// [`ChannelManager::forward_trampoline_payment`]: crate::ln::channelmanager::ChannelManager::forward_trampoline_payment
+	TrampolinePaymentForwarded {
+		/// The hash of the relayed payment.
+		payment_hash: PaymentHash,
+		/// The fee, in millisatoshis, we earned, i.e. the total value we received less the total
+		/// value, including routing fees, we sent.
+		fee_earned_msat: u64,
+	},
+	/// Indicates that we've received a reply to an onion message sent via
+	/// `OnionMessenger::send_onion_message_expecting_reply`, i.e. an onion message over the reply
+	/// path we included with it.
+	///
+	/// Onion messages don't carry any contents yet, so only the id of the request is provided.
+	OnionMessageReplyReceived {
+		/// The id of the request, as returned when sending it.
+		request_id: [u8; 32],
+	},
+	/// Indicates that we haven't received a reply to an onion message sent via
+	/// `OnionMessenger::send_onion_message_expecting_reply` within its timeout. Any reply received
+	/// later is ignored.
+	OnionMessageReplyTimedOut {
+		/// The id of the request, as returned when sending it.
+		request_id: [u8; 32],
+	},
 }
 
 impl Writeable for Event {
@@ -612,7 +841,7 @@ impl Writeable for Event {
 				// We never write out FundingGenerationReady events as, upon disconnection, peers
 				// drop any channels which have not yet exchanged funding_signed.
 			},
-			&Event::PaymentReceived { ref payment_hash, ref amount_msat, ref purpose } => {
+			&Event::PaymentReceived { ref payment_hash, ref amount_msat, ref purpose, ref custom_tlvs } => {
 				1u8.write(writer)?;
 				let mut payment_secret = None;
 				let payment_preimage;
@@ -625,12 +854,14 @@ impl Writeable for Event {
 						payment_preimage = Some(*preimage);
 					}
 				}
+				let custom_tlvs = if custom_tlvs.is_empty() { None } else { Some(VecWriteWrapper(custom_tlvs)) };
 				write_tlv_fields!(writer, {
 					(0, payment_hash, required),
 					(2, payment_secret, option),
 					(4, amount_msat, required),
 					(6, 0u64, required), // user_payment_id required for compatibility with 0.0.103 and earlier
 					(8, payment_preimage, option),
+					(11, custom_tlvs, option),
 				});
 			},
 			&Event::PaymentSent { ref payment_id, ref payment_preimage, ref payment_hash, ref fee_paid_msat } => {
@@ -753,6 +984,95 @@ impl Writeable for Event {
 					(2, failed_next_destination, required),
 				})
 			},
+			&Event::FundingTimedOut { ref channel_id, ref user_channel_id, ref funding_txo, ref funding_inputs } => {
+				27u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, channel_id, required),
+					(2, user_channel_id, required),
+					(4, funding_txo, option),
+					(6, funding_inputs, vec_type),
+				})
+			},
+			&Event::PendingEventQueueThresholdCrossed { .. } => {
+				29u8.write(writer)?;
+				// We never write out the queue state as it is stale by the time we're reloaded.
+				write_tlv_fields!(writer, {});
+			},
+			&Event::InboundVolumeThresholdReached { ref threshold_msat, ref received_msat, ref payment_count, ref window_ticks } => {
+				31u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, threshold_msat, required),
+					(2, received_msat, required),
+					(4, payment_count, required),
+					(6, window_ticks, required),
+				})
+			},
+			&Event::WatchedOutputConfirmed { ref outpoint, ref output, ref block_hash, ref height } => {
+				33u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, outpoint, required),
+					(2, output, required),
+					(4, block_hash, required),
+					(6, height, required),
+				})
+			},
+			&Event::WatchedOutputSpent { ref outpoint, ref spending_tx, ref block_hash, ref height } => {
+				35u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, outpoint, required),
+					(2, spending_tx, required),
+					(4, block_hash, required),
+					(6, height, required),
+				})
+			},
+			&Event::WatchedTransactionUnconfirmed { ref txid } => {
+				37u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, txid, required),
+				})
+			},
+			&Event::ClaimFeeBudgetExhausted { ref channel_id, ref total_fee_satoshis, ref attempted_total_fee_satoshis, ref budget_satoshis } => {
+				39u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, channel_id, required),
+					(2, total_fee_satoshis, required),
+					(4, attempted_total_fee_satoshis, required),
+					(6, budget_satoshis, required),
+				})
+			},
+			&Event::RoleChanged { ref role } => {
+				41u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, role, required),
+				})
+			},
+			&Event::TrampolineForwardRequested { ref payment_hash, ref next_node_id, ref route_params } => {
+				43u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, payment_hash, required),
+					(2, next_node_id, required),
+					(4, route_params, required),
+				})
+			},
+			&Event::TrampolinePaymentForwarded { ref payment_hash, ref fee_earned_msat } => {
+				45u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, payment_hash, required),
+					(2, fee_earned_msat, required),
+				})
+			},
+			&Event::OnionMessageReplyReceived { .. } => {
+				47u8.write(writer)?;
+				// We never write out onion message reply events as the requests they're for aren't
+				// persisted.
+				write_tlv_fields!(writer, {});
+			},
+			&Event::OnionMessageReplyTimedOut { .. } => {
+				49u8.write(writer)?;
+				// We never write out onion message reply events as the requests they're for aren't
+				// persisted.
+				write_tlv_fields!(writer, {});
+			},
 			// Note that, going forward, all new events must only write data inside of
 			// `write_tlv_fields`. Versions 0.0.101+ will ignore odd-numbered events that write
 			// data via `write_tlv_fields`.
@@ -773,12 +1093,14 @@ impl MaybeReadable for Event {
 					let mut payment_secret = None;
 					let mut amount_msat = 0;
 					let mut _user_payment_id = None::<u64>; // For compatibility with 0.0.103 and earlier
+					let mut custom_tlvs = Some(Vec::new());
 					read_tlv_fields!(reader, {
 						(0, payment_hash, required),
 						(2, payment_secret, option),
 						(4, amount_msat, required),
 						(6, _user_payment_id, option),
 						(8, payment_preimage, option),
+						(11, custom_tlvs, vec_type),
 					});
 					let purpose = match payment_secret {
 						Some(secret) => PaymentPurpose::InvoicePayment {
@@ -792,6 +1114,7 @@ impl MaybeReadable for Event {
 						payment_hash,
 						amount_msat,
 						purpose,
+						custom_tlvs: custom_tlvs.unwrap(),
 					}))
 				};
 				f()
@@ -1011,6 +1334,151 @@ impl MaybeReadable for Event {
 				};
 				f()
 			},
+			27u8 => {
+				let f = || {
+					let mut channel_id = [0; 32];
+					let mut user_channel_id = 0;
+					let mut funding_txo = None;
+					let mut funding_inputs = Some(Vec::new());
+					read_tlv_fields!(reader, {
+						(0, channel_id, required),
+						(2, user_channel_id, required),
+						(4, funding_txo, option),
+						(6, funding_inputs, vec_type),
+					});
+					Ok(Some(Event::FundingTimedOut {
+						channel_id, user_channel_id, funding_txo, funding_inputs: funding_inputs.unwrap(),
+					}))
+				};
+				f()
+			},
+			29u8 => {
+				// Value 29 is used for `Event::PendingEventQueueThresholdCrossed`.
+				read_tlv_fields!(reader, {});
+				Ok(None)
+			},
+			31u8 => {
+				let f = || {
+					let mut threshold_msat = 0;
+					let mut received_msat = 0;
+					let mut payment_count = 0;
+					let mut window_ticks = 0;
+					read_tlv_fields!(reader, {
+						(0, threshold_msat, required),
+						(2, received_msat, required),
+						(4, payment_count, required),
+						(6, window_ticks, required),
+					});
+					Ok(Some(Event::InboundVolumeThresholdReached {
+						threshold_msat, received_msat, payment_count, window_ticks,
+					}))
+				};
+				f()
+			},
+			33u8 => {
+				let f = || {
+					let mut outpoint = OptionDeserWrapper(None);
+					let mut output = OptionDeserWrapper(None);
+					let mut block_hash = OptionDeserWrapper(None);
+					let mut height = 0;
+					read_tlv_fields!(reader, {
+						(0, outpoint, required),
+						(2, output, required),
+						(4, block_hash, required),
+						(6, height, required),
+					});
+					Ok(Some(Event::WatchedOutputConfirmed {
+						outpoint: outpoint.0.unwrap(), output: output.0.unwrap(), block_hash: block_hash.0.unwrap(), height,
+					}))
+				};
+				f()
+			},
+			35u8 => {
+				let f = || {
+					let mut outpoint = OptionDeserWrapper(None);
+					let mut spending_tx = OptionDeserWrapper(None);
+					let mut block_hash = OptionDeserWrapper(None);
+					let mut height = 0;
+					read_tlv_fields!(reader, {
+						(0, outpoint, required),
+						(2, spending_tx, required),
+						(4, block_hash, required),
+						(6, height, required),
+					});
+					Ok(Some(Event::WatchedOutputSpent {
+						outpoint: outpoint.0.unwrap(), spending_tx: spending_tx.0.unwrap(), block_hash: block_hash.0.unwrap(), height,
+					}))
+				};
+				f()
+			},
+			37u8 => {
+				let f = || {
+					let mut txid = OptionDeserWrapper(None);
+					read_tlv_fields!(reader, {
+						(0, txid, required),
+					});
+					Ok(Some(Event::WatchedTransactionUnconfirmed { txid: txid.0.unwrap() }))
+				};
+				f()
+			},
+			39u8 => {
+				let f = || {
+					let mut channel_id = [0; 32];
+					let mut total_fee_satoshis = 0;
+					let mut attempted_total_fee_satoshis = 0;
+					let mut budget_satoshis = 0;
+					read_tlv_fields!(reader, {
+						(0, channel_id, required),
+						(2, total_fee_satoshis, required),
+						(4, attempted_total_fee_satoshis, required),
+						(6, budget_satoshis, required),
+					});
+					Ok(Some(Event::ClaimFeeBudgetExhausted {
+						channel_id, total_fee_satoshis, attempted_total_fee_satoshis, budget_satoshis,
+					}))
+				};
+				f()
+			},
+			41u8 => {
+				let f = || {
+					let mut role = OptionDeserWrapper(None);
+					read_tlv_fields!(reader, {
+						(0, role, required),
+					});
+					Ok(Some(Event::RoleChanged { role: role.0.unwrap() }))
+				};
+				f()
+			},
+			43u8 => {
+				let f = || {
+					let mut payment_hash = PaymentHash([0; 32]);
+					let mut next_node_id = OptionDeserWrapper(None);
+					let mut route_params = OptionDeserWrapper(None);
+					read_tlv_fields!(reader, {
+						(0, payment_hash, required),
+						(2, next_node_id, required),
+						(4, route_params, required),
+					});
+					Ok(Some(Event::TrampolineForwardRequested {
+						payment_hash,
+						next_node_id: next_node_id.0.unwrap(),
+						route_params: route_params.0.unwrap(),
+					}))
+				};
+				f()
+			},
+			45u8 => {
+				let f = || {
+					let mut payment_hash = PaymentHash([0; 32]);
+					let mut fee_earned_msat = 0;
+					read_tlv_fields!(reader, {
+						(0, payment_hash, required),
+						(2, fee_earned_msat, required),
+					});
+					Ok(Some(Event::TrampolinePaymentForwarded { payment_hash, fee_earned_msat }))
+				};
+				f()
+			},
 			// Versions prior to 0.0.100 did not ignore odd types, instead returning InvalidValue.
 			// Version 0.0.100 failed to properly ignore odd types, possibly resulting in corrupt
 			// reads.
@@ -1253,3 +1721,56 @@ impl<T: EventHandler> EventHandler for Arc<T> {
 		self.deref().handle_event(event)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use ln::{PaymentHash, PaymentPreimage, PaymentSecret};
+	use ln::msgs::DecodeError;
+	use util::ser::{MaybeReadable, Readable, Writeable};
+	use super::{Event, PaymentPurpose};
+	use prelude::*;
+
+	// Reads a `PaymentReceived` event as versions prior to the addition of `custom_tlvs` did.
+	fn read_legacy_payment_received(mut reader: &[u8]) -> Result<(PaymentHash, u64, Option<PaymentPreimage>), DecodeError> {
+		let event_type: u8 = Readable::read(&mut reader)?;
+		assert_eq!(event_type, 1);
+		let mut payment_hash = PaymentHash([0; 32]);
+		let mut payment_preimage = None;
+		let mut _payment_secret: Option<PaymentSecret> = None;
+		let mut amount_msat = 0;
+		let mut _user_payment_id = None::<u64>;
+		read_tlv_fields!(&mut reader, {
+			(0, payment_hash, required),
+			(2, _payment_secret, option),
+			(4, amount_msat, required),
+			(6, _user_payment_id, option),
+			(8, payment_preimage, option),
+		});
+		Ok((payment_hash, amount_msat, payment_preimage))
+	}
+
+	#[test]
+	fn payment_received_custom_tlvs_downgrade() {
+		// Custom TLVs are written in an odd TLV, and only if there are any, so that versions
+		// which don't know about them can still read `PaymentReceived` events.
+		let payment_preimage = PaymentPreimage([1; 32]);
+		for custom_tlvs in vec![Vec::new(), vec![(65537, vec![42; 3]), (65539, Vec::new())]] {
+			let event = Event::PaymentReceived {
+				payment_hash: PaymentHash([2; 32]),
+				amount_msat: 1000,
+				purpose: PaymentPurpose::SpontaneousPayment(payment_preimage),
+				custom_tlvs: custom_tlvs.clone(),
+			};
+			let encoded = event.encode();
+
+			assert_eq!(read_legacy_payment_received(&encoded).unwrap(),
+				(PaymentHash([2; 32]), 1000, Some(payment_preimage)));
+
+			match <Event as MaybeReadable>::read(&mut &encoded[..]).unwrap() {
+				Some(Event::PaymentReceived { custom_tlvs: read_custom_tlvs, .. }) =>
+					assert_eq!(read_custom_tlvs, custom_tlvs),
+				_ => panic!("Unexpected event"),
+			}
+		}
+	}
+}
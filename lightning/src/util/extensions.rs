@@ -0,0 +1,202 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Opaque state which downstream code may persist alongside a [`ChannelManager`] and its
+//! channels.
+//!
+//! Rather than adding their own odd TLV types to our serialization (which may collide with types
+//! we add later), extensions store their data under an extension type via
+//! [`ChannelManager::set_channel_extension_data`] or
+//! [`ChannelManager::set_node_extension_data`]. The data is written as part of the
+//! [`ChannelManager`] and is never interpreted by LDK, surviving as-is across upgrades.
+//!
+//! To avoid collisions, the following rules apply:
+//!  * Extension types below [`MIN_EXTENSION_TYPE`] are reserved for LDK itself.
+//!  * Each extension must register its type with a unique name via
+//!    [`ChannelManager::register_extension`] before setting data. Registering a type already
+//!    registered under a different name fails.
+//!  * Independently developed extensions should derive their type from their name via
+//!    [`extension_type_for_name`] rather than picking small numbers which others may also pick.
+//!
+//! Data stored under types which are not registered (e.g. because an extension was removed) is
+//! still preserved, so that it is not lost if the extension is later re-enabled.
+//!
+//! [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+//! [`ChannelManager::set_channel_extension_data`]: crate::ln::channelmanager::ChannelManager::set_channel_extension_data
+//! [`ChannelManager::set_node_extension_data`]: crate::ln::channelmanager::ChannelManager::set_node_extension_data
+//! [`ChannelManager::register_extension`]: crate::ln::channelmanager::ChannelManager::register_extension
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+use ln::msgs::DecodeError;
+use util::byte_utils;
+use util::errors::APIError;
+use util::ser::{BigSize, Readable, Writeable, Writer};
+
+use prelude::*;
+use io::{self, Read};
+use alloc::collections::BTreeMap;
+use core::cmp;
+
+/// The lowest extension type which extensions may use. Lower types are reserved for LDK.
+pub const MIN_EXTENSION_TYPE: u64 = 1 << 16;
+
+/// Derives an extension type from an extension's name, such that independently developed
+/// extensions are very unlikely to collide.
+pub fn extension_type_for_name(name: &str) -> u64 {
+	let hash = Sha256::hash(name.as_bytes()).into_inner();
+	byte_utils::slice_to_be64(&hash[..8]) | MIN_EXTENSION_TYPE
+}
+
+/// The extensions registered with a `ChannelManager`, by type.
+pub(crate) struct ExtensionRegistry {
+	names: HashMap<u64, String>,
+}
+
+impl ExtensionRegistry {
+	pub(crate) fn new() -> Self {
+		ExtensionRegistry { names: HashMap::new() }
+	}
+
+	/// Registers `extension_type` under `name`. Registering the same type under the same name
+	/// again is allowed.
+	pub(crate) fn register(&mut self, extension_type: u64, name: String) -> Result<(), APIError> {
+		if extension_type < MIN_EXTENSION_TYPE {
+			return Err(APIError::APIMisuseError { err: format!("Extension type {} is reserved", extension_type) });
+		}
+		match self.names.get(&extension_type) {
+			Some(existing) if *existing != name => Err(APIError::APIMisuseError {
+				err: format!("Extension type {} is already registered by {}", extension_type, existing)
+			}),
+			Some(_) => Ok(()),
+			None => {
+				self.names.insert(extension_type, name);
+				Ok(())
+			},
+		}
+	}
+
+	/// Fails if `extension_type` has not been registered.
+	pub(crate) fn check_registered(&self, extension_type: u64) -> Result<(), APIError> {
+		if self.names.contains_key(&extension_type) {
+			Ok(())
+		} else {
+			Err(APIError::APIMisuseError { err: format!("Extension type {} has not been registered", extension_type) })
+		}
+	}
+}
+
+/// Opaque data stored by extensions, by extension type.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ExtensionData {
+	entries: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ExtensionData {
+	pub(crate) fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	pub(crate) fn get(&self, extension_type: u64) -> Option<&Vec<u8>> {
+		self.entries.get(&extension_type)
+	}
+
+	/// Sets the data for `extension_type`, or removes it if `data` is `None`.
+	pub(crate) fn set(&mut self, extension_type: u64, data: Option<Vec<u8>>) {
+		match data {
+			Some(data) => { self.entries.insert(extension_type, data); },
+			None => { self.entries.remove(&extension_type); },
+		}
+	}
+}
+
+impl Writeable for ExtensionData {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		BigSize(self.entries.len() as u64).write(w)?;
+		for (extension_type, data) in self.entries.iter() {
+			BigSize(*extension_type).write(w)?;
+			BigSize(data.len() as u64).write(w)?;
+			w.write_all(data)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for ExtensionData {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let count: BigSize = Readable::read(r)?;
+		let mut entries = BTreeMap::new();
+		let mut last_type = None;
+		for _ in 0..count.0 {
+			let extension_type: BigSize = Readable::read(r)?;
+			// Entries are written in strictly increasing type order.
+			if last_type.map(|last| extension_type.0 <= last).unwrap_or(false) {
+				return Err(DecodeError::InvalidValue);
+			}
+			last_type = Some(extension_type.0);
+			let len: BigSize = Readable::read(r)?;
+			// Read in chunks so that a bogus length doesn't cause a huge allocation.
+			let mut data = Vec::new();
+			while (data.len() as u64) < len.0 {
+				let chunk_start = data.len();
+				data.resize(chunk_start + cmp::min(len.0 - chunk_start as u64, 1024) as usize, 0);
+				r.read_exact(&mut data[chunk_start..])?;
+			}
+			entries.insert(extension_type.0, data);
+		}
+		Ok(ExtensionData { entries })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{extension_type_for_name, ExtensionData, ExtensionRegistry, MIN_EXTENSION_TYPE};
+	use ln::msgs::DecodeError;
+	use util::ser::{Readable, Writeable};
+
+	use prelude::*;
+	use io;
+
+	#[test]
+	fn registers_extensions_without_collisions() {
+		let mut registry = ExtensionRegistry::new();
+		let extension_type = extension_type_for_name("watchtower");
+		assert!(extension_type >= MIN_EXTENSION_TYPE);
+		assert_ne!(extension_type, extension_type_for_name("accounting"));
+
+		assert!(registry.register(MIN_EXTENSION_TYPE - 1, "reserved".to_owned()).is_err());
+		assert!(registry.check_registered(extension_type).is_err());
+		registry.register(extension_type, "watchtower".to_owned()).unwrap();
+		registry.register(extension_type, "watchtower".to_owned()).unwrap();
+		assert!(registry.register(extension_type, "accounting".to_owned()).is_err());
+		registry.check_registered(extension_type).unwrap();
+	}
+
+	#[test]
+	fn extension_data_round_trips() {
+		let mut data = ExtensionData::default();
+		data.set(MIN_EXTENSION_TYPE + 1, Some(vec![1; 2000]));
+		data.set(MIN_EXTENSION_TYPE, Some(Vec::new()));
+		data.set(::core::u64::MAX, Some(vec![3]));
+		data.set(MIN_EXTENSION_TYPE + 2, Some(vec![4]));
+		data.set(MIN_EXTENSION_TYPE + 2, None);
+		let encoded = data.encode();
+		assert_eq!(ExtensionData::read(&mut io::Cursor::new(&encoded)).unwrap(), data);
+		assert!(ExtensionData::read(&mut io::Cursor::new(&encoded[..encoded.len() - 1])).is_err());
+
+		// Entries out of order are rejected.
+		let mut unordered = Vec::new();
+		unordered.extend_from_slice(&[2, 5, 0, 4, 0]);
+		match ExtensionData::read(&mut io::Cursor::new(&unordered)) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!(),
+		}
+	}
+}
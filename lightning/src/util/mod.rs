@@ -21,9 +21,16 @@ pub mod ser;
 pub mod message_signing;
 pub mod invoice;
 pub mod persist;
+pub mod payment_evidence;
+pub mod channel_stats;
+pub mod telemetry;
+pub mod event_journal;
+pub mod extensions;
+pub mod compat;
 
 pub(crate) mod atomic_counter;
 pub(crate) mod byte_utils;
+pub(crate) mod compression;
 pub(crate) mod chacha20;
 #[cfg(all(not(test), feature = "std"))]
 pub(crate) mod fairrwlock;
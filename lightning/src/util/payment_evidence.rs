@@ -0,0 +1,360 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Signed records of settled payments, for businesses which must be able to produce evidence of
+//! the payments they've made and received.
+//!
+//! A [`PaymentEvidenceStore`] is fed the [`Event`]s generated by the [`ChannelManager`] and
+//! remembers each settled payment along with the time it settled. Records can then be filled in
+//! with details only our [`ChannelMonitor`]s know (see [`PaymentEvidenceStore::backfill_from_monitor`])
+//! and exported for a given time range, with any fields the recipient shouldn't see redacted, each
+//! signed by our node key so that it can be checked with [`SignedPaymentRecord::verify`].
+//!
+//! [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+
+use bitcoin::secp256k1::{self, PublicKey, SecretKey};
+
+use chain::channelmonitor::ChannelMonitor;
+use chain::keysinterface::Sign;
+use ln::{PaymentHash, PaymentPreimage};
+use ln::msgs::DecodeError;
+use util::events::{Event, PaymentPurpose};
+use util::message_signing;
+use util::ser::{Readable, Writeable, Writer};
+
+use io;
+use prelude::*;
+use sync::Mutex;
+
+/// Whether a settled payment was sent or received by us.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaymentDirection {
+	/// We received the payment.
+	Inbound,
+	/// We sent the payment.
+	Outbound,
+}
+
+impl_writeable_tlv_based_enum!(PaymentDirection,
+	(0, Inbound) => {},
+	(2, Outbound) => {};
+);
+
+/// A single settled payment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SettledPaymentRecord {
+	/// The hash of the payment.
+	pub payment_hash: PaymentHash,
+	/// The preimage which settled the payment, if known and not redacted.
+	pub payment_preimage: Option<PaymentPreimage>,
+	/// The amount received, or the amount delivered to the recipient for outbound payments, not
+	/// including any fees paid. `None` if not known or redacted.
+	pub amount_msat: Option<u64>,
+	/// Whether we sent or received the payment.
+	pub direction: PaymentDirection,
+	/// The channel counterparty over which the payment settled, if known and not redacted.
+	pub counterparty_node_id: Option<PublicKey>,
+	/// The time at which the payment settled, in seconds since the UNIX epoch.
+	pub settled_at: u64,
+}
+
+impl_writeable_tlv_based!(SettledPaymentRecord, {
+	(0, payment_hash, required),
+	(2, payment_preimage, option),
+	(4, amount_msat, option),
+	(6, direction, required),
+	(8, counterparty_node_id, option),
+	(10, settled_at, required),
+});
+
+/// Which fields of a [`SettledPaymentRecord`] to strip before it is exported.
+///
+/// Default::default() redacts nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordRedaction {
+	/// Whether to remove [`SettledPaymentRecord::payment_preimage`].
+	pub preimage: bool,
+	/// Whether to remove [`SettledPaymentRecord::amount_msat`].
+	pub amount: bool,
+	/// Whether to remove [`SettledPaymentRecord::counterparty_node_id`].
+	pub counterparty: bool,
+}
+
+impl SettledPaymentRecord {
+	/// Returns a copy of this record with the fields selected in `redaction` removed.
+	pub fn redacted(&self, redaction: &RecordRedaction) -> Self {
+		SettledPaymentRecord {
+			payment_hash: self.payment_hash,
+			payment_preimage: if redaction.preimage { None } else { self.payment_preimage },
+			amount_msat: if redaction.amount { None } else { self.amount_msat },
+			direction: self.direction,
+			counterparty_node_id: if redaction.counterparty { None } else { self.counterparty_node_id },
+			settled_at: self.settled_at,
+		}
+	}
+}
+
+/// A [`SettledPaymentRecord`] along with a signature over its serialization, as produced by
+/// [`message_signing::sign`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedPaymentRecord {
+	/// The (possibly redacted) record.
+	pub record: SettledPaymentRecord,
+	/// The zbase32-encoded signature over the record's serialization.
+	pub signature: String,
+}
+
+impl SignedPaymentRecord {
+	/// Checks that the record was signed by `node_id`.
+	pub fn verify(&self, node_id: &PublicKey) -> bool {
+		message_signing::verify(&self.record.encode(), &self.signature, node_id)
+	}
+}
+
+/// Collects [`SettledPaymentRecord`]s from [`Event`]s and our [`ChannelMonitor`]s.
+///
+/// The store may be persisted via its [`Writeable`] implementation so that records survive
+/// restarts.
+pub struct PaymentEvidenceStore {
+	records: Mutex<Vec<SettledPaymentRecord>>,
+}
+
+impl PaymentEvidenceStore {
+	/// Creates a new, empty, store.
+	pub fn new() -> Self {
+		Self { records: Mutex::new(Vec::new()) }
+	}
+
+	/// Records the payment settled by `event`, if any, as having settled at `settled_at` (in
+	/// seconds since the UNIX epoch).
+	///
+	/// [`Event::PaymentClaimed`] and [`Event::PaymentSent`] each create a record. The amounts
+	/// delivered on each path of an outbound payment are only known once the corresponding
+	/// [`Event::PaymentPathSuccessful`]s are seen, which are summed into the existing record.
+	pub fn record_event(&self, event: &Event, settled_at: u64) {
+		let mut records = self.records.lock().unwrap();
+		match event {
+			Event::PaymentClaimed { payment_hash, amount_msat, purpose } => {
+				let payment_preimage = match purpose {
+					PaymentPurpose::InvoicePayment { payment_preimage, .. } => *payment_preimage,
+					PaymentPurpose::SpontaneousPayment(preimage) => Some(*preimage),
+				};
+				records.push(SettledPaymentRecord {
+					payment_hash: *payment_hash,
+					payment_preimage,
+					amount_msat: Some(*amount_msat),
+					direction: PaymentDirection::Inbound,
+					counterparty_node_id: None,
+					settled_at,
+				});
+			},
+			Event::PaymentSent { payment_hash, payment_preimage, .. } => {
+				records.push(SettledPaymentRecord {
+					payment_hash: *payment_hash,
+					payment_preimage: Some(*payment_preimage),
+					amount_msat: None,
+					direction: PaymentDirection::Outbound,
+					counterparty_node_id: None,
+					settled_at,
+				});
+			},
+			Event::PaymentPathSuccessful { payment_hash: Some(payment_hash), path, .. } => {
+				let delivered_msat = match path.last() { Some(hop) => hop.fee_msat, None => return };
+				let counterparty_node_id = path.first().map(|hop| hop.pubkey);
+				if let Some(record) = records.iter_mut().rev().find(|record|
+					record.direction == PaymentDirection::Outbound && record.payment_hash == *payment_hash
+				) {
+					record.amount_msat = Some(record.amount_msat.unwrap_or(0) + delivered_msat);
+					if record.counterparty_node_id.is_none() {
+						record.counterparty_node_id = counterparty_node_id;
+					}
+				}
+			},
+			_ => {},
+		}
+	}
+
+	/// Fills in any preimages and counterparties missing from inbound records using the data held
+	/// by the given [`ChannelMonitor`].
+	///
+	/// Monitors only retain preimages for HTLCs which may still need to be claimed on-chain, so
+	/// this should be called for each monitor shortly after payments settle.
+	pub fn backfill_from_monitor<Signer: Sign>(&self, monitor: &ChannelMonitor<Signer>) {
+		let preimages = monitor.get_stored_preimages();
+		let counterparty_node_id = monitor.get_counterparty_node_id();
+		let mut records = self.records.lock().unwrap();
+		for record in records.iter_mut() {
+			if let Some(preimage) = preimages.get(&record.payment_hash) {
+				if record.payment_preimage.is_none() {
+					record.payment_preimage = Some(*preimage);
+				}
+				if record.direction == PaymentDirection::Inbound && record.counterparty_node_id.is_none() {
+					record.counterparty_node_id = counterparty_node_id;
+				}
+			}
+		}
+	}
+
+	/// Returns signed copies of all records which settled in the range `[from, to)` (in seconds
+	/// since the UNIX epoch), with the fields selected by `redaction` removed before signing.
+	///
+	/// `node_secret` should generally be our node's secret key, as returned by
+	/// [`KeysInterface::get_node_secret`].
+	///
+	/// [`KeysInterface::get_node_secret`]: crate::chain::keysinterface::KeysInterface::get_node_secret
+	pub fn export(
+		&self, from: u64, to: u64, redaction: &RecordRedaction, node_secret: &SecretKey
+	) -> Result<Vec<SignedPaymentRecord>, secp256k1::Error> {
+		let records = self.records.lock().unwrap();
+		let mut signed_records = Vec::new();
+		for record in records.iter().filter(|record| record.settled_at >= from && record.settled_at < to) {
+			let record = record.redacted(redaction);
+			let signature = message_signing::sign(&record.encode(), node_secret)?;
+			signed_records.push(SignedPaymentRecord { record, signature });
+		}
+		Ok(signed_records)
+	}
+}
+
+impl Default for PaymentEvidenceStore {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Writeable for PaymentEvidenceStore {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let records = self.records.lock().unwrap();
+		write_tlv_fields!(writer, {
+			(0, *records, vec_type),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for PaymentEvidenceStore {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		init_tlv_field_var!(records, vec_type);
+		read_tlv_fields!(reader, {
+			(0, records, vec_type),
+		});
+		Ok(Self { records: Mutex::new(init_tlv_based_struct_field!(records, vec_type)) })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{PaymentDirection, PaymentEvidenceStore, RecordRedaction};
+
+	use ::{check_added_monitors, get_monitor};
+
+	use ln::{PaymentHash, PaymentPreimage, PaymentSecret};
+	use ln::channelmanager::PaymentId;
+	use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
+	use ln::functional_test_utils::*;
+	use routing::router::RouteHop;
+	use util::events::{Event, MessageSendEventsProvider, PaymentPurpose};
+	use util::ser::{Readable, Writeable};
+
+	use bitcoin::hashes::Hash;
+	use bitcoin::hashes::sha256::Hash as Sha256;
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	use io;
+
+	#[test]
+	fn exports_signed_redacted_records() {
+		let secp_ctx = Secp256k1::new();
+		let node_secret = SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &node_secret);
+		let peer_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[43; 32]).unwrap());
+
+		let inbound_preimage = PaymentPreimage([1; 32]);
+		let inbound_hash = PaymentHash(Sha256::hash(&inbound_preimage.0).into_inner());
+		let outbound_preimage = PaymentPreimage([2; 32]);
+		let outbound_hash = PaymentHash(Sha256::hash(&outbound_preimage.0).into_inner());
+
+		let store = PaymentEvidenceStore::new();
+		store.record_event(&Event::PaymentClaimed {
+			payment_hash: inbound_hash, amount_msat: 10_000,
+			purpose: PaymentPurpose::InvoicePayment { payment_preimage: Some(inbound_preimage), payment_secret: PaymentSecret([0; 32]) },
+		}, 100);
+		store.record_event(&Event::PaymentSent {
+			payment_id: None, payment_preimage: outbound_preimage, payment_hash: outbound_hash, fee_paid_msat: Some(10),
+		}, 200);
+		for _ in 0..2 {
+			store.record_event(&Event::PaymentPathSuccessful {
+				payment_id: PaymentId([0; 32]), payment_hash: Some(outbound_hash),
+				path: vec![RouteHop {
+					pubkey: peer_id, node_features: NodeFeatures::known(), short_channel_id: 42,
+					channel_features: ChannelFeatures::known(), fee_msat: 5_000, cltv_expiry_delta: 40,
+				}],
+			}, 200);
+		}
+
+		let all = store.export(0, 300, &RecordRedaction::default(), &node_secret).unwrap();
+		assert_eq!(all.len(), 2);
+		assert_eq!(all[0].record.direction, PaymentDirection::Inbound);
+		assert_eq!(all[0].record.payment_preimage, Some(inbound_preimage));
+		assert_eq!(all[0].record.amount_msat, Some(10_000));
+		assert_eq!(all[1].record.direction, PaymentDirection::Outbound);
+		assert_eq!(all[1].record.amount_msat, Some(10_000));
+		assert_eq!(all[1].record.counterparty_node_id, Some(peer_id));
+		assert!(all.iter().all(|signed| signed.verify(&node_id)));
+		assert!(!all[0].verify(&peer_id));
+
+		// Only records in the requested range are exported, and redacted fields are stripped
+		// before signing.
+		let redaction = RecordRedaction { preimage: true, amount: false, counterparty: true };
+		let outbound = store.export(150, 300, &redaction, &node_secret).unwrap();
+		assert_eq!(outbound.len(), 1);
+		assert_eq!(outbound[0].record.payment_hash, outbound_hash);
+		assert_eq!(outbound[0].record.payment_preimage, None);
+		assert_eq!(outbound[0].record.counterparty_node_id, None);
+		assert_eq!(outbound[0].record.amount_msat, Some(10_000));
+		assert!(outbound[0].verify(&node_id));
+
+		let mut tampered = outbound[0].clone();
+		tampered.record.amount_msat = Some(1);
+		assert!(!tampered.verify(&node_id));
+
+		let read_store = PaymentEvidenceStore::read(&mut io::Cursor::new(&store.encode())).unwrap();
+		assert_eq!(read_store.encode(), store.encode());
+	}
+
+	#[test]
+	fn backfills_inbound_records_from_monitors() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+		let (payment_preimage, payment_hash, payment_secret) = route_payment(&nodes[0], &[&nodes[1]], 100_000);
+		nodes[1].node.claim_funds(payment_preimage);
+		check_added_monitors!(nodes[1], 1);
+		nodes[1].node.get_and_clear_pending_events();
+		nodes[1].node.get_and_clear_pending_msg_events();
+
+		// Records built from a claim which didn't carry the preimage pick it, and the channel
+		// counterparty, up from the monitor.
+		let store = PaymentEvidenceStore::new();
+		store.record_event(&Event::PaymentClaimed {
+			payment_hash, amount_msat: 100_000,
+			purpose: PaymentPurpose::InvoicePayment { payment_preimage: None, payment_secret },
+		}, 100);
+		store.backfill_from_monitor(&*get_monitor!(nodes[1], chan.2));
+
+		let node_secret = SecretKey::from_slice(&[42; 32]).unwrap();
+		let records = store.export(0, u64::max_value(), &RecordRedaction::default(), &node_secret).unwrap();
+		assert_eq!(records.len(), 1);
+		assert_eq!(records[0].record.payment_preimage, Some(payment_preimage));
+		assert_eq!(records[0].record.counterparty_node_id, Some(nodes[0].node.get_our_node_id()));
+	}
+}
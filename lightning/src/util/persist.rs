@@ -7,13 +7,17 @@
 //! This module contains a simple key-value store trait KVStorePersister that
 //! allows one to implement the persistence for [`ChannelManager`], [`NetworkGraph`],
 //! and [`ChannelMonitor`] all in one place.
+//!
+//! It also contains [`FencedPersister`], which wraps a store implementing [`FencingTokenStore`]
+//! to prevent a stale duplicate instance of a node sharing the same storage from overwriting
+//! the state of a newer one.
 
 use core::ops::Deref;
 use bitcoin::hashes::hex::ToHex;
 use io::{self};
 use routing::scoring::WriteableScore;
 
-use crate::{chain::{keysinterface::{Sign, KeysInterface}, self, transaction::{OutPoint}, chaininterface::{BroadcasterInterface, FeeEstimator}, chainmonitor::{Persist, MonitorUpdateId}, channelmonitor::{ChannelMonitor, ChannelMonitorUpdate}}, ln::channelmanager::ChannelManager, routing::gossip::NetworkGraph};
+use crate::{chain::{keysinterface::{Sign, KeysInterface}, self, transaction::{OutPoint}, chaininterface::{BroadcasterInterface, FeeEstimator}, chainmonitor::{Persist, MonitorUpdateId}, channelmonitor::{ChannelMonitor, ChannelMonitorUpdate}}, ln::channelmanager::{ChannelManager, LeadershipFence}, routing::gossip::NetworkGraph};
 use super::{logger::Logger, ser::Writeable};
 
 /// Trait for a key-value store for persisting some writeable object at some key
@@ -86,3 +90,117 @@ impl<ChannelSigner: Sign, K: KVStorePersister> Persist<ChannelSigner> for K {
 			.map_err(|_| chain::ChannelMonitorUpdateErr::PermanentFailure)
 	}
 }
+
+/// A [`KVStorePersister`] which can also store a single fencing token, as used by
+/// [`FencedPersister`].
+pub trait FencingTokenStore: KVStorePersister {
+	/// Reads the fencing token last written via [`Self::persist_fencing_token`], or `None` if no
+	/// token has been written yet.
+	fn read_fencing_token(&self) -> io::Result<Option<u64>>;
+
+	/// Durably persists the given fencing token, replacing any previous token.
+	fn persist_fencing_token(&self, token: u64) -> io::Result<()>;
+}
+
+/// Wraps a [`KVStorePersister`] such that its writes are refused once another instance has
+/// started with the same storage, protecting against split-brain deployments where two
+/// instances of a node run concurrently, e.g. during a botched failover.
+///
+/// On creation, each instance claims a fencing token one greater than the last token stored and
+/// persists it. Before each write, the stored token is read back and the write refused if it no
+/// longer matches our own, i.e. if a newer instance has since started. Note that a write racing
+/// with a newer instance's startup may still land, but all writes after it will be refused.
+///
+/// Refused [`ChannelMonitor`] writes return [`ChannelMonitorUpdateErr::TemporaryFailure`], freezing
+/// the affected channels without broadcasting our (possibly stale) state, while refused
+/// [`Persister`] writes return an error, which stops a `lightning-background-processor`
+/// `BackgroundProcessor`. In either case, the stale instance should be shut down.
+///
+/// [`ChannelMonitorUpdateErr::TemporaryFailure`]: chain::ChannelMonitorUpdateErr::TemporaryFailure
+pub struct FencedPersister<K: FencingTokenStore> {
+	store: K,
+	fencing_token: u64,
+}
+
+impl<K: FencingTokenStore> FencedPersister<K> {
+	/// Claims a new fencing token in `store`, fencing off any instances which started before us.
+	pub fn new(store: K) -> io::Result<Self> {
+		let fencing_token = store.read_fencing_token()?.unwrap_or(0).checked_add(1)
+			.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Fencing token overflowed"))?;
+		store.persist_fencing_token(fencing_token)?;
+		Ok(Self { store, fencing_token })
+	}
+
+	/// Gets the fencing token claimed by this instance.
+	pub fn fencing_token(&self) -> u64 {
+		self.fencing_token
+	}
+
+	/// Gets the wrapped store, e.g. to read data back on startup.
+	pub fn get_store(&self) -> &K {
+		&self.store
+	}
+
+	fn check_fencing_token(&self) -> io::Result<()> {
+		match self.store.read_fencing_token()? {
+			Some(token) if token == self.fencing_token => Ok(()),
+			_ => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Storage has been claimed by a newer instance")),
+		}
+	}
+
+	/// Persists the given writeable using the provided key, unless another instance has started
+	/// with the same storage since we were created.
+	pub fn persist<W: Writeable>(&self, key: &str, object: &W) -> io::Result<()> {
+		self.check_fencing_token()?;
+		self.store.persist(key, object)
+	}
+
+	fn persist_monitor<ChannelSigner: Sign>(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		if self.check_fencing_token().is_err() {
+			return Err(chain::ChannelMonitorUpdateErr::TemporaryFailure);
+		}
+		let key = format!("monitors/{}_{}", funding_txo.txid.to_hex(), funding_txo.index);
+		self.store.persist(&key, monitor)
+			.map_err(|_| chain::ChannelMonitorUpdateErr::PermanentFailure)
+	}
+}
+
+impl<K: FencingTokenStore> LeadershipFence for FencedPersister<K> {
+	/// Returns true if we still hold the latest fencing token, in which case instances which
+	/// started before us can no longer persist, and thus can't sign, new channel states. A
+	/// standby should thus create its [`FencedPersister`] before being promoted.
+	fn fence_previous_active(&self) -> bool {
+		self.check_fencing_token().is_ok()
+	}
+}
+
+impl<'a, A: FencingTokenStore, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref, S> Persister<'a, Signer, M, T, K, F, L, S> for FencedPersister<A>
+	where M::Target: 'static + chain::Watch<Signer>,
+		T::Target: 'static + BroadcasterInterface,
+		K::Target: 'static + KeysInterface<Signer = Signer>,
+		F::Target: 'static + FeeEstimator,
+		L::Target: 'static + Logger,
+		S: WriteableScore<'a>,
+{
+	fn persist_manager(&self, channel_manager: &ChannelManager<Signer, M, T, K, F, L>) -> Result<(), io::Error> {
+		self.persist("manager", channel_manager)
+	}
+
+	fn persist_graph(&self, network_graph: &NetworkGraph<L>) -> Result<(), io::Error> {
+		self.persist("network_graph", network_graph)
+	}
+
+	fn persist_scorer(&self, scorer: &S) -> Result<(), io::Error> {
+		self.persist("scorer", &scorer)
+	}
+}
+
+impl<ChannelSigner: Sign, K: FencingTokenStore> Persist<ChannelSigner> for FencedPersister<K> {
+	fn persist_new_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>, _update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		self.persist_monitor(funding_txo, monitor)
+	}
+
+	fn update_persisted_channel(&self, funding_txo: OutPoint, _update: &Option<ChannelMonitorUpdate>, monitor: &ChannelMonitor<ChannelSigner>, _update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		self.persist_monitor(funding_txo, monitor)
+	}
+}
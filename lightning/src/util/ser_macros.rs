@@ -26,6 +26,9 @@ macro_rules! encode_tlv {
 			field.write($stream)?;
 		}
 	};
+	($stream: expr, $optional_type: expr, $optional_field: expr, (option: $trait: ident $(, $read_arg: expr)?)) => {
+		encode_tlv!($stream, $optional_type, $optional_field, option);
+	};
 }
 
 macro_rules! encode_tlv_stream {
@@ -76,10 +79,17 @@ macro_rules! get_varint_length_prefixed_tlv_length {
 			$len.0 += field_len;
 		}
 	};
+	($len: expr, $optional_type: expr, $optional_field: expr, (option: $trait: ident $(, $read_arg: expr)?)) => {
+		get_varint_length_prefixed_tlv_length!($len, $optional_type, $optional_field, option);
+	};
 }
 
+/// Encodes a length-prefixed TLV stream of the given fields.
+///
+/// If `$extra_tlvs` is given, it's a `Vec<&(u64, Vec<u8>)>` of raw TLVs written after the given
+/// fields, which must be sorted by type and have types above those of all the given fields.
 macro_rules! encode_varint_length_prefixed_tlv {
-	($stream: expr, {$(($type: expr, $field: expr, $fieldty: tt)),*}) => { {
+	($stream: expr, {$(($type: expr, $field: expr, $fieldty: tt)),*} $(, $extra_tlvs: expr)?) => { {
 		use util::ser::BigSize;
 		let len = {
 			#[allow(unused_mut)]
@@ -87,10 +97,24 @@ macro_rules! encode_varint_length_prefixed_tlv {
 			$(
 				get_varint_length_prefixed_tlv_length!(len, $type, $field, $fieldty);
 			)*
+			$(
+				for &&(typ, ref value) in $extra_tlvs.iter() {
+					BigSize(typ).write(&mut len).expect("No in-memory data may fail to serialize");
+					BigSize(value.len() as u64).write(&mut len).expect("No in-memory data may fail to serialize");
+					len.0 += value.len();
+				}
+			)?
 			len.0
 		};
 		BigSize(len as u64).write($stream)?;
 		encode_tlv_stream!($stream, { $(($type, $field, $fieldty)),* });
+		$(
+			for &&(typ, ref value) in $extra_tlvs.iter() {
+				BigSize(typ).write($stream)?;
+				BigSize(value.len() as u64).write($stream)?;
+				$stream.write_all(value)?;
+			}
+		)?
 	} }
 }
 
@@ -174,8 +198,14 @@ macro_rules! decode_tlv {
 	}};
 }
 
+/// Decodes a TLV stream into the given fields, erroring on unknown even types.
+///
+/// If a `$decode_custom_tlv` closure is given, it's called as `(type, reader) -> Result<bool,
+/// DecodeError>` with any TLV of a type not listed, and returns whether it read the TLV. Types it
+/// doesn't read are handled as unknown.
 macro_rules! decode_tlv_stream {
-	($stream: expr, {$(($type: expr, $field: ident, $fieldty: tt)),* $(,)*}) => { {
+	($stream: expr, {$(($type: expr, $field: ident, $fieldty: tt)),* $(,)*}
+	 $(, $decode_custom_tlv: expr)?) => { {
 		use ln::msgs::DecodeError;
 		let mut last_seen_type: Option<u64> = None;
 		let mut stream_ref = $stream;
@@ -226,10 +256,13 @@ macro_rules! decode_tlv_stream {
 						return Err(DecodeError::InvalidValue);
 					}
 				},)*
-				x if x % 2 == 0 => {
-					return Err(DecodeError::UnknownRequiredFeature);
+				#[allow(unused_variables)] // x is unused if no $decode_custom_tlv is given
+				x => {
+					let custom_tlv_read = false $( || $decode_custom_tlv(x, &mut s)? )?;
+					if !custom_tlv_read && x % 2 == 0 {
+						return Err(DecodeError::UnknownRequiredFeature);
+					}
 				},
-				_ => {},
 			}
 			s.eat_remaining()?;
 		}
@@ -360,6 +393,9 @@ macro_rules! init_tlv_based_struct_field {
 	($field: ident, vec_type) => {
 		$field.unwrap()
 	};
+	($field: ident, (option: $trait: ident $(, $read_arg: expr)?)) => {
+		$field
+	};
 }
 
 macro_rules! init_tlv_field_var {
@@ -375,6 +411,9 @@ macro_rules! init_tlv_field_var {
 	($field: ident, option) => {
 		let mut $field = None;
 	};
+	($field: ident, (option: $trait: ident $(, $read_arg: expr)?)) => {
+		let mut $field = None;
+	};
 }
 
 /// Implements Readable/Writeable for a struct storing it as a set of TLVs
@@ -382,6 +421,8 @@ macro_rules! init_tlv_field_var {
 /// If $fieldty is `option`, then $field is optional field.
 /// if $fieldty is `vec_type`, then $field is a Vec, which needs to have its individual elements
 /// serialized.
+/// If $fieldty is `(option: $trait)`, then $field is an optional field which is read via $trait,
+/// e.g. `LengthReadable` for fields which consume the remainder of their TLV record.
 macro_rules! impl_writeable_tlv_based {
 	($st: ident, {$(($type: expr, $field: ident, $fieldty: tt)),* $(,)*}) => {
 		impl ::util::ser::Writeable for $st {
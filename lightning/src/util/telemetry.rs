@@ -0,0 +1,261 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Signed health beacons which operators may publish to their own monitoring endpoints.
+//!
+//! A [`HealthBeaconGenerator`] periodically summarizes the state of our node in a
+//! [`HealthBeacon`], signed by our node key so that the monitoring endpoint can check it came
+//! from us with [`SignedHealthBeacon::verify`]. Beacons are never sent anywhere by LDK itself and
+//! are only generated if enabled via [`TelemetryConfig::enabled`].
+//!
+//! To avoid leaking more about our node than necessary, beacons never include any information
+//! about individual channels or peers, only reporting channel counts as coarse
+//! [`ChannelCountBucket`]s, and each field may be left out entirely via the [`TelemetryConfig`].
+
+use bitcoin::secp256k1::{self, PublicKey, Secp256k1, SecretKey};
+
+use ln::channelmanager::ChannelDetails;
+use ln::msgs::DecodeError;
+use util::message_signing;
+use util::ser::Writeable;
+
+use prelude::*;
+use sync::Mutex;
+
+/// Configuration for a [`HealthBeaconGenerator`].
+///
+/// Default::default() disables beacons entirely.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TelemetryConfig {
+	/// Whether beacons should be generated at all.
+	///
+	/// Default value: false.
+	pub enabled: bool,
+	/// The minimum number of seconds between two beacons.
+	///
+	/// Default value: 3600 (one hour).
+	pub interval_secs: u64,
+	/// Whether to include our current best block height.
+	///
+	/// Default value: true.
+	pub include_block_height: bool,
+	/// Whether to include the (bucketed) number of channels we have open.
+	///
+	/// Default value: true.
+	pub include_channel_counts: bool,
+	/// Whether to include the version of LDK we're running.
+	///
+	/// Default value: true.
+	pub include_version: bool,
+}
+
+impl Default for TelemetryConfig {
+	fn default() -> Self {
+		TelemetryConfig {
+			enabled: false,
+			interval_secs: 60 * 60,
+			include_block_height: true,
+			include_channel_counts: true,
+			include_version: true,
+		}
+	}
+}
+
+/// A coarse range a number of channels falls in, reported in place of exact counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelCountBucket {
+	/// No channels.
+	None,
+	/// Between 1 and 5 channels.
+	Few,
+	/// Between 6 and 20 channels.
+	Several,
+	/// Between 21 and 100 channels.
+	Many,
+	/// More than 100 channels.
+	Lots,
+}
+
+impl_writeable_tlv_based_enum!(ChannelCountBucket,
+	(0, None) => {},
+	(2, Few) => {},
+	(4, Several) => {},
+	(6, Many) => {},
+	(8, Lots) => {};
+);
+
+impl ChannelCountBucket {
+	/// Gets the bucket `count` channels falls in.
+	pub fn from_count(count: usize) -> Self {
+		match count {
+			0 => ChannelCountBucket::None,
+			1..=5 => ChannelCountBucket::Few,
+			6..=20 => ChannelCountBucket::Several,
+			21..=100 => ChannelCountBucket::Many,
+			_ => ChannelCountBucket::Lots,
+		}
+	}
+}
+
+/// A summary of our node's health at a point in time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthBeacon {
+	/// Our node id.
+	pub node_id: PublicKey,
+	/// The time at which the beacon was generated, in seconds since the UNIX epoch.
+	pub timestamp: u64,
+	/// Our current best block height, if included.
+	pub block_height: Option<u32>,
+	/// The number of channels we have, if included.
+	pub channel_count: Option<ChannelCountBucket>,
+	/// The number of channels we have which are currently usable, if included.
+	pub usable_channel_count: Option<ChannelCountBucket>,
+	/// The version of LDK we're running, if included.
+	pub version: Option<String>,
+}
+
+impl_writeable_tlv_based!(HealthBeacon, {
+	(0, node_id, required),
+	(2, timestamp, required),
+	(4, block_height, option),
+	(6, channel_count, option),
+	(8, usable_channel_count, option),
+	(10, version, option),
+});
+
+/// A [`HealthBeacon`] along with a signature over its serialization, as produced by
+/// [`message_signing::sign`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedHealthBeacon {
+	/// The beacon.
+	pub beacon: HealthBeacon,
+	/// The zbase32-encoded signature over the beacon's serialization.
+	pub signature: String,
+}
+
+impl SignedHealthBeacon {
+	/// Checks that the beacon was signed by the node it claims to be from.
+	pub fn verify(&self) -> bool {
+		message_signing::verify(&self.beacon.encode(), &self.signature, &self.beacon.node_id)
+	}
+}
+
+/// Generates [`SignedHealthBeacon`]s at most once every [`TelemetryConfig::interval_secs`].
+pub struct HealthBeaconGenerator {
+	config: TelemetryConfig,
+	last_beacon_timestamp: Mutex<Option<u64>>,
+}
+
+impl HealthBeaconGenerator {
+	/// Creates a new generator with the given config.
+	pub fn new(config: TelemetryConfig) -> Self {
+		Self { config, last_beacon_timestamp: Mutex::new(None) }
+	}
+
+	/// Generates a new beacon if beacons are enabled and at least
+	/// [`TelemetryConfig::interval_secs`] have passed since the last one, returning `None`
+	/// otherwise.
+	///
+	/// `channels` should be the result of [`ChannelManager::list_channels`], `best_block_height`
+	/// the height of [`ChannelManager::current_best_block`] and `now` the current time in seconds
+	/// since the UNIX epoch. `node_secret` should generally be our node's secret key, as returned
+	/// by [`KeysInterface::get_node_secret`].
+	///
+	/// [`ChannelManager::list_channels`]: crate::ln::channelmanager::ChannelManager::list_channels
+	/// [`ChannelManager::current_best_block`]: crate::ln::channelmanager::ChannelManager::current_best_block
+	/// [`KeysInterface::get_node_secret`]: crate::chain::keysinterface::KeysInterface::get_node_secret
+	pub fn maybe_generate(
+		&self, channels: &[ChannelDetails], best_block_height: u32, now: u64, node_secret: &SecretKey
+	) -> Result<Option<SignedHealthBeacon>, secp256k1::Error> {
+		if !self.config.enabled { return Ok(None); }
+
+		let mut last_beacon_timestamp = self.last_beacon_timestamp.lock().unwrap();
+		if let Some(last_timestamp) = *last_beacon_timestamp {
+			if now < last_timestamp.saturating_add(self.config.interval_secs) { return Ok(None); }
+		}
+
+		let (channel_count, usable_channel_count) = if self.config.include_channel_counts {
+			let usable_channels = channels.iter().filter(|channel| channel.is_usable).count();
+			(Some(ChannelCountBucket::from_count(channels.len())), Some(ChannelCountBucket::from_count(usable_channels)))
+		} else { (None, None) };
+		let beacon = HealthBeacon {
+			node_id: PublicKey::from_secret_key(&Secp256k1::signing_only(), node_secret),
+			timestamp: now,
+			block_height: if self.config.include_block_height { Some(best_block_height) } else { None },
+			channel_count,
+			usable_channel_count,
+			version: if self.config.include_version { Some(env!("CARGO_PKG_VERSION").to_owned()) } else { None },
+		};
+		let signature = message_signing::sign(&beacon.encode(), node_secret)?;
+		*last_beacon_timestamp = Some(now);
+		Ok(Some(SignedHealthBeacon { beacon, signature }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ChannelCountBucket, HealthBeacon, HealthBeaconGenerator, TelemetryConfig};
+
+	use ln::features::InitFeatures;
+	use ln::functional_test_utils::*;
+	use util::ser::{Readable, Writeable};
+
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	use io;
+
+	#[test]
+	fn generates_signed_beacons() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+		let node_secret = SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_id = PublicKey::from_secret_key(&Secp256k1::new(), &node_secret);
+		let channels = nodes[0].node.list_channels();
+		let height = nodes[0].node.current_best_block().height();
+
+		// Beacons are off by default.
+		let disabled = HealthBeaconGenerator::new(TelemetryConfig::default());
+		assert!(disabled.maybe_generate(&channels, height, 1000, &node_secret).unwrap().is_none());
+
+		let config = TelemetryConfig { enabled: true, include_version: false, ..TelemetryConfig::default() };
+		let generator = HealthBeaconGenerator::new(config);
+		let signed = generator.maybe_generate(&channels, height, 1000, &node_secret).unwrap().unwrap();
+		assert_eq!(signed.beacon.node_id, node_id);
+		assert_eq!(signed.beacon.timestamp, 1000);
+		assert_eq!(signed.beacon.block_height, Some(height));
+		assert_eq!(signed.beacon.channel_count, Some(ChannelCountBucket::Few));
+		assert_eq!(signed.beacon.usable_channel_count, Some(ChannelCountBucket::Few));
+		assert_eq!(signed.beacon.version, None);
+		assert!(signed.verify());
+
+		let mut tampered = signed.clone();
+		tampered.beacon.block_height = Some(height + 1);
+		assert!(!tampered.verify());
+
+		// No new beacon is generated until the interval has passed.
+		assert!(generator.maybe_generate(&channels, height, 1000 + 3599, &node_secret).unwrap().is_none());
+		assert!(generator.maybe_generate(&channels, height, 1000 + 3600, &node_secret).unwrap().is_some());
+
+		let read_beacon = HealthBeacon::read(&mut io::Cursor::new(&signed.beacon.encode())).unwrap();
+		assert_eq!(read_beacon, signed.beacon);
+	}
+
+	#[test]
+	fn buckets_channel_counts() {
+		assert_eq!(ChannelCountBucket::from_count(0), ChannelCountBucket::None);
+		assert_eq!(ChannelCountBucket::from_count(5), ChannelCountBucket::Few);
+		assert_eq!(ChannelCountBucket::from_count(6), ChannelCountBucket::Several);
+		assert_eq!(ChannelCountBucket::from_count(100), ChannelCountBucket::Many);
+		assert_eq!(ChannelCountBucket::from_count(101), ChannelCountBucket::Lots);
+	}
+}
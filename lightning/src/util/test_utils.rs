@@ -133,6 +133,9 @@ impl<'a> chain::Watch<EnforcingSigner> for TestChainMonitor<'a> {
 		let new_monitor = <(BlockHash, channelmonitor::ChannelMonitor<EnforcingSigner>)>::read(
 			&mut io::Cursor::new(&w.0), self.keys_manager).unwrap().1;
 		assert!(new_monitor == monitor);
+		let decompressed_monitor = <(BlockHash, channelmonitor::ChannelMonitor<EnforcingSigner>)>::read(
+			&mut io::Cursor::new(&monitor.encode_compressed()), self.keys_manager).unwrap().1;
+		assert!(decompressed_monitor == monitor);
 		self.latest_monitor_update_id.lock().unwrap().insert(funding_txo.to_channel_id(),
 			(funding_txo, monitor.get_latest_update_id(), MonitorUpdateId::from_new_monitor(&monitor)));
 		self.added_monitors.lock().unwrap().push((funding_txo, monitor));
@@ -145,6 +148,8 @@ impl<'a> chain::Watch<EnforcingSigner> for TestChainMonitor<'a> {
 		update.write(&mut w).unwrap();
 		assert!(channelmonitor::ChannelMonitorUpdate::read(
 				&mut io::Cursor::new(&w.0)).unwrap() == update);
+		assert!(channelmonitor::ChannelMonitorUpdate::read(
+				&mut io::Cursor::new(&update.encode_compressed())).unwrap() == update);
 
 		self.monitor_updates.lock().unwrap().entry(funding_txo.to_channel_id()).or_insert(Vec::new()).push(update.clone());
 
@@ -723,6 +728,7 @@ pub struct TestChainSource {
 	pub utxo_ret: Mutex<Result<TxOut, chain::AccessError>>,
 	pub watched_txn: Mutex<HashSet<(Txid, Script)>>,
 	pub watched_outputs: Mutex<HashSet<(OutPoint, Script)>>,
+	pub watched_scripts: Mutex<HashSet<Script>>,
 }
 
 impl TestChainSource {
@@ -733,6 +739,7 @@ impl TestChainSource {
 			utxo_ret: Mutex::new(Ok(TxOut { value: u64::max_value(), script_pubkey })),
 			watched_txn: Mutex::new(HashSet::new()),
 			watched_outputs: Mutex::new(HashSet::new()),
+			watched_scripts: Mutex::new(HashSet::new()),
 		}
 	}
 }
@@ -755,6 +762,10 @@ impl chain::Filter for TestChainSource {
 	fn register_output(&self, output: WatchedOutput) {
 		self.watched_outputs.lock().unwrap().insert((output.outpoint, output.script_pubkey));
 	}
+
+	fn register_script(&self, script_pubkey: &Script) {
+		self.watched_scripts.lock().unwrap().insert(script_pubkey.clone());
+	}
 }
 
 impl Drop for TestChainSource {